@@ -0,0 +1,37 @@
+//! Arbitrary bytes as a layout, in each format a mount reads, resolved into
+//! a tree as `lhttpfs mount` would, walked as `lhttpfs tree` does and
+//! compiled, and as a compiled layout. Any of it may fail, but none of it
+//! may panic.
+
+#![no_main]
+
+use lhttpfs::{fetch::HostPolicy, fs::LazyHTTPFS, layout};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = LazyHTTPFS::read_compiled(&mut &data[..]);
+    let parsed = [
+        layout::parse(data),
+        layout::parse_table(data, b','),
+        layout::parse_table(data, b'\t'),
+    ];
+    for files in parsed.into_iter().flatten() {
+        // Refuses every URL, with a host or without, so nothing is fetched.
+        let nowhere = HostPolicy::new(&["*"], &["*"]).unwrap();
+        let built = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .hosts(nowhere)
+            .build(files);
+        let Ok(fs) = built else {
+            continue;
+        };
+        for entry in fs.entries() {
+            fs.remote_parts(entry.attr.ino);
+            fs.cached(entry.attr.ino);
+        }
+        let mut compiled = Vec::new();
+        if fs.compile(&mut compiled).is_ok() {
+            LazyHTTPFS::read_compiled(&mut &compiled[..]).unwrap();
+        }
+    }
+});
@@ -0,0 +1,97 @@
+//! Arbitrary operations, as the kernel would ask for them, on the tree of
+//! a small layout with the control files: lookups, listings, opens, reads
+//! and writes, including entries written to `.lhttpfs/add` that change the
+//! tree the operations after them see. They may fail, but never panic.
+
+#![no_main]
+
+use std::sync::Arc;
+
+use arbitrary::Arbitrary;
+use lhttpfs::{
+    fetch::MemoryFetcher,
+    fs::{LazyHTTPFS, CONTROL},
+    layout,
+};
+use libfuzzer_sys::fuzz_target;
+
+const LAYOUT: &str = r#"[
+    {"name": "a", "content": "inline"},
+    {"name": "d", "contents": [
+        {"name": "b", "url": "mem://b", "size": 4},
+        {"name": "c", "url": "mem://c", "size": 10, "chunk_size": 3},
+        {"name": "gone", "url": "mem://gone", "size": 2}
+    ]}
+]"#;
+
+/// Inodes are taken modulo one past the last, so most of them exist.
+#[derive(Debug, Arbitrary)]
+enum Op {
+    Find { parent: u8, name: String },
+    Attributes(u8),
+    Xattrs(u8),
+    List(u8),
+    Open { ino: u8, write: bool },
+    Read { ino: u8, offset: i64, size: u16 },
+    Write { ino: u8, data: Vec<u8> },
+    /// Writes entries to `.lhttpfs/add`, as JSON.
+    Add(String),
+    Truncate(u8),
+}
+
+fn fs() -> LazyHTTPFS {
+    let origin = MemoryFetcher::new()
+        .with("mem://b", "bbbb")
+        .with("mem://c", "0123456789");
+    let mut fs = LazyHTTPFS::builder()
+        .cache_dir(None)
+        .fetcher("mem", Arc::new(origin))
+        .build(layout::parse(LAYOUT.as_bytes()).unwrap())
+        .unwrap();
+    fs.add_control();
+    fs.add_manifest();
+    fs
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut fs = fs();
+    for op in ops {
+        let inodes = fs.entries().map(|entry| entry.attr.ino).max().unwrap_or(1) + 2;
+        let ino = |ino: u8| ino as u64 % inodes;
+        match op {
+            Op::Find { parent, name } => {
+                let _ = fs.find(ino(parent), name.as_ref());
+            }
+            Op::Attributes(i) => {
+                let _ = fs.attributes(ino(i));
+            }
+            Op::Xattrs(i) => {
+                for name in fs.xattr_names(ino(i)).unwrap_or_default() {
+                    fs.xattr(ino(i), name.as_ref()).unwrap();
+                }
+            }
+            Op::List(i) => {
+                let _ = fs.list(ino(i));
+            }
+            Op::Open { ino: i, write } => {
+                let _ = fs.open_file(ino(i), write);
+            }
+            Op::Read { ino: i, offset, size } => {
+                let _ = fs.read_file(ino(i), offset, size.into());
+            }
+            Op::Write { ino: i, data } => {
+                let _ = fs.write_file(ino(i), &data);
+            }
+            Op::Add(entries) => {
+                let control = (fs.find(1, CONTROL.as_ref()).ok())
+                    .and_then(|(control, _)| fs.find(control.ino, "add".as_ref()).ok());
+                if let Some((add, _)) = control {
+                    let _ = fs.write_file(add.ino, entries.as_bytes());
+                }
+            }
+            Op::Truncate(i) => {
+                let _ = fs.truncate(ino(i));
+            }
+        }
+    }
+});
@@ -0,0 +1,59 @@
+/*
+ * lhttpfs: read a layout's remote files in-process, or mount it.
+ *
+ * Build the library with
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type cdylib
+ *
+ * (or --crate-type staticlib) and link against liblhttpfs. Calls that fail
+ * return NULL or -1; lhttpfs_last_error() then says why.
+ */
+
+#ifndef LHTTPFS_H
+#define LHTTPFS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct lhttpfs_tree lhttpfs_tree_t;
+typedef struct lhttpfs_file lhttpfs_file_t;
+typedef struct lhttpfs_mount lhttpfs_mount_t;
+
+/* What went wrong last on this thread, or NULL if nothing has. Valid until
+ * the thread's next failing call. */
+const char *lhttpfs_last_error(void);
+
+/* Opens the tree the JSON layout file at `layout` describes, caching
+ * fetched bytes in `cache_dir`, or only in memory if it is NULL. */
+lhttpfs_tree_t *lhttpfs_open_tree(const char *layout, const char *cache_dir);
+
+/* Closes a tree. Files opened from it stay readable until closed. */
+void lhttpfs_close_tree(lhttpfs_tree_t *tree);
+
+/* Opens the file at `path` of `tree`, from its root. */
+lhttpfs_file_t *lhttpfs_open(const lhttpfs_tree_t *tree, const char *path);
+
+/* The size of `file`, 0 while it isn't known. */
+uint64_t lhttpfs_size(const lhttpfs_file_t *file);
+
+/* Reads up to `len` bytes of `file` from `offset` into `buf`. Returns how
+ * many it read, fewer only at the end of the file, or -1. */
+int64_t lhttpfs_read_at(const lhttpfs_file_t *file, uint64_t offset, uint8_t *buf, size_t len);
+
+void lhttpfs_close(lhttpfs_file_t *file);
+
+/* Mounts the layout at `layout` on `mountpoint`, caching in `cache_dir` if
+ * not NULL, served on threads of its own until lhttpfs_unmount(). */
+lhttpfs_mount_t *lhttpfs_mount(const char *layout, const char *cache_dir, const char *mountpoint);
+
+void lhttpfs_unmount(lhttpfs_mount_t *mount);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,250 @@
+//! `pylhttpfs`: a layout's tree read from Python through
+//! [`lhttpfs::fs::RemoteTree`], with the same cache and backends as a
+//! mount but without one.
+//!
+//! ```python
+//! import pylhttpfs
+//!
+//! tree = pylhttpfs.Tree("layout.json", cache_dir="/var/cache/assets")
+//! for entry in tree.list("/images"):
+//!     print(entry.name, entry.size, entry.source)
+//! with tree.open("/images/a.png") as f:
+//!     header = f.read(8)
+//! ```
+
+use std::{
+    fs::File as StdFile,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use lhttpfs::{
+    fs::{LazyHTTPFS, RemoteFile, RemoteTree},
+    layout::{self, InputFile},
+    LhttpfsError,
+};
+use pyo3::{
+    exceptions::{PyFileNotFoundError, PyIsADirectoryError, PyOSError, PyValueError},
+    prelude::*,
+    types::{PyBytes, PyTuple},
+};
+
+/// `error` as the Python exception closest to it.
+fn raised(error: LhttpfsError) -> PyErr {
+    match error {
+        LhttpfsError::Layout(_) => PyValueError::new_err(error.to_string()),
+        LhttpfsError::Io(e) => io_raised(e),
+        error => PyOSError::new_err(error.to_string()),
+    }
+}
+
+fn io_raised(error: io::Error) -> PyErr {
+    match error.kind() {
+        io::ErrorKind::NotFound => PyFileNotFoundError::new_err(error.to_string()),
+        io::ErrorKind::IsADirectory => PyIsADirectoryError::new_err(error.to_string()),
+        _ => PyOSError::new_err(error.to_string()),
+    }
+}
+
+/// The tree of a layout, its files opened by path from the root.
+#[pyclass(frozen, module = "pylhttpfs")]
+struct Tree {
+    tree: RemoteTree,
+}
+
+impl Tree {
+    fn built(files: Vec<InputFile>, cache_dir: Option<PathBuf>) -> PyResult<Tree> {
+        let fs = LazyHTTPFS::builder().cache_dir(cache_dir).build(files);
+        Ok(Tree {
+            tree: RemoteTree::new(fs.map_err(raised)?),
+        })
+    }
+}
+
+#[pymethods]
+impl Tree {
+    /// The tree the JSON layout file at `layout` describes, caching fetched
+    /// bytes in `cache_dir`, or only in memory without one.
+    #[new]
+    #[pyo3(signature = (layout, cache_dir=None))]
+    fn new(layout: PathBuf, cache_dir: Option<PathBuf>) -> PyResult<Tree> {
+        let reader = BufReader::new(StdFile::open(layout)?);
+        Tree::built(layout::parse(reader).map_err(raised)?, cache_dir)
+    }
+
+    /// The tree of the JSON layout `text`.
+    #[staticmethod]
+    #[pyo3(signature = (text, cache_dir=None))]
+    fn from_json(text: &str, cache_dir: Option<PathBuf>) -> PyResult<Tree> {
+        Tree::built(layout::parse(text.as_bytes()).map_err(raised)?, cache_dir)
+    }
+
+    /// What the directory at `path` holds, in name order.
+    #[pyo3(signature = (path="/"))]
+    fn list(&self, path: &str) -> PyResult<Vec<Entry>> {
+        let entries = self.tree.list(path).map_err(raised)?;
+        let entries = entries.into_iter().map(|entry| Entry {
+            name: entry.name.to_string_lossy().into_owned(),
+            path: entry.path.to_string_lossy().into_owned(),
+            size: entry.attr.size,
+            is_dir: entry.is_dir(),
+            source: entry.source,
+        });
+        Ok(entries.collect())
+    }
+
+    /// The file at `path`, to read like one opened with `open(path, "rb")`.
+    fn open(&self, path: &str) -> PyResult<File> {
+        Ok(File {
+            file: Some(self.tree.open(path).map_err(raised)?),
+        })
+    }
+
+    /// Up to `size` bytes of the file at `path` from `offset`.
+    fn read_at<'py>(
+        &self,
+        py: Python<'py>,
+        path: &str,
+        offset: u64,
+        size: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let data = py.allow_threads(|| {
+            let file = self.tree.open(path).map_err(raised)?;
+            file.read_at(offset, size).map_err(raised)
+        });
+        Ok(PyBytes::new(py, &data?))
+    }
+}
+
+/// A directory entry, as `Tree.list` lists it.
+#[pyclass(frozen, get_all, module = "pylhttpfs")]
+struct Entry {
+    name: String,
+    /// From the root, which is `/`.
+    path: String,
+    /// 0 for a file whose size isn't known until it is read.
+    size: u64,
+    is_dir: bool,
+    /// The URL a file's bytes come from, `None` for directories.
+    source: Option<String>,
+}
+
+#[pymethods]
+impl Entry {
+    fn __repr__(&self) -> String {
+        format!(
+            "Entry({:?}, size={}, is_dir={})",
+            self.path, self.size, self.is_dir
+        )
+    }
+}
+
+/// An open file of a `Tree`, read-only and seekable.
+#[pyclass(module = "pylhttpfs")]
+struct File {
+    /// `None` once closed.
+    file: Option<RemoteFile>,
+}
+
+impl File {
+    fn file(&mut self) -> PyResult<&mut RemoteFile> {
+        let closed = || PyValueError::new_err("I/O operation on closed file");
+        self.file.as_mut().ok_or_else(closed)
+    }
+}
+
+#[pymethods]
+impl File {
+    /// Up to `size` bytes from where the file is, or all the rest of it.
+    #[pyo3(signature = (size=-1))]
+    fn read<'py>(&mut self, py: Python<'py>, size: i64) -> PyResult<Bound<'py, PyBytes>> {
+        let file = self.file()?;
+        let mut data = Vec::new();
+        let read = py.allow_threads(|| match usize::try_from(size) {
+            Ok(size) => (&mut *file).take(size as u64).read_to_end(&mut data),
+            Err(_) => file.read_to_end(&mut data),
+        });
+        read.map_err(io_raised)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Up to `size` bytes from `offset`, without moving the file's position.
+    fn read_at<'py>(
+        &mut self,
+        py: Python<'py>,
+        offset: u64,
+        size: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let file = self.file()?;
+        let data = py.allow_threads(|| file.read_at(offset, size).map_err(raised));
+        Ok(PyBytes::new(py, &data?))
+    }
+
+    /// Moves to `offset` from the start, the position (`whence` 1) or the
+    /// end (2), returning the new position.
+    #[pyo3(signature = (offset, whence=0))]
+    fn seek(&mut self, offset: i64, whence: i32) -> PyResult<u64> {
+        let to = match whence {
+            0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| {
+                PyValueError::new_err(format!("negative seek position {}", offset))
+            })?),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "invalid whence ({})",
+                    whence
+                )))
+            }
+        };
+        self.file()?.seek(to).map_err(io_raised)
+    }
+
+    fn tell(&mut self) -> PyResult<u64> {
+        self.file()?.stream_position().map_err(io_raised)
+    }
+
+    /// The file's size, 0 while it isn't known.
+    #[getter]
+    fn size(&mut self) -> PyResult<u64> {
+        Ok(self.file()?.size())
+    }
+
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn seekable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.file.is_none()
+    }
+
+    fn close(&mut self) {
+        self.file = None;
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, _args: &Bound<'_, PyTuple>) {
+        self.close();
+    }
+}
+
+#[pymodule]
+fn pylhttpfs(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Tree>()?;
+    module.add_class::<Entry>()?;
+    module.add_class::<File>()?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! `--access-log`: a line of JSON for every read through the mount, to
+//! audit which remote objects a workload actually touched.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::fetch::date;
+
+/// One read, as written to the log.
+#[derive(Serialize)]
+pub struct Access<'a> {
+    pub inode: u64,
+    pub offset: i64,
+    pub length: u32,
+    /// How much was returned, short of `length` at the end of the file.
+    pub bytes: usize,
+    /// `miss` if anything had to be fetched from the origin.
+    pub cache: &'a str,
+    /// `ok`, or the error the read failed with.
+    pub status: &'a str,
+    pub latency_ms: f64,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    path: &'a str,
+    #[serde(flatten)]
+    access: &'a Access<'a>,
+}
+
+/// The log file, shared by every mount of the process, and the paths of
+/// one mount's inodes.
+pub struct AccessLog {
+    file: Arc<File>,
+    paths: HashMap<u64, String>,
+}
+
+/// Opens `path` for appending, creating it if needed.
+pub fn open(path: &Path) -> io::Result<Arc<File>> {
+    let file = File::options().create(true).append(true).open(path)?;
+    Ok(Arc::new(file))
+}
+
+impl AccessLog {
+    /// Logs reads of the files in `paths`, by inode.
+    pub fn new(file: Arc<File>, paths: HashMap<u64, String>) -> AccessLog {
+        AccessLog { file, paths }
+    }
+
+    /// Appends `access`, each line in a single write so the mounts sharing
+    /// the file don't interleave.
+    pub fn record(&self, access: &Access) -> io::Result<()> {
+        let path = self.paths.get(&access.inode).map_or("", String::as_str);
+        let line = Line {
+            time: date::rfc3339(SystemTime::now()),
+            path,
+            access,
+        };
+        let mut line = serde_json::to_vec(&line)?;
+        line.push(b'\n');
+        (&*self.file).write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{open, Access, AccessLog};
+
+    #[test]
+    fn recorded() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let paths = HashMap::from([(2, "/mnt/a.bin".to_string())]);
+        let log = AccessLog::new(open(&path).unwrap(), paths);
+        for (inode, cache) in [(2, "miss"), (3, "hit")] {
+            let access = Access {
+                inode,
+                offset: 0,
+                length: 4096,
+                bytes: 3,
+                cache,
+                status: "ok",
+                latency_ms: 1.5,
+            };
+            log.record(&access).unwrap();
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["path"], "/mnt/a.bin");
+        assert_eq!(lines[0]["cache"], "miss");
+        assert_eq!(lines[0]["bytes"], 3);
+        assert!(lines[0]["time"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["path"], "");
+        std::fs::remove_file(path).unwrap();
+    }
+}
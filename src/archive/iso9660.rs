@@ -0,0 +1,311 @@
+//! ISO 9660 images. The primary volume descriptor at sector 16 points at
+//! the root directory, whose records point at the extents of files and
+//! further directories. Long names come from Rock Ridge entries when the
+//! image has them, or from a Joliet tree otherwise.
+
+use std::collections::HashSet;
+
+use tracing::warn;
+
+use crate::Result;
+
+use super::{components, BadArchive, Data, Member, MemberKind, ReadAt};
+
+const SECTOR: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+const DIRECTORY: u8 = 2;
+const MULTI_EXTENT: u8 = 0x80;
+
+fn bad(message: impl Into<String>) -> Box<dyn std::error::Error> {
+    Box::new(BadArchive(message.into()))
+}
+
+fn u32_at(data: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as u64
+}
+
+/// How names are stored in the tree being walked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    /// `NAME.EXT;1`, upper case.
+    Iso,
+    /// UCS-2 big endian.
+    Joliet,
+    /// ISO names, overridden by the `NM` entries after each record.
+    RockRidge,
+}
+
+struct Record<'a> {
+    extent: u64,
+    size: u64,
+    flags: u8,
+    name: &'a [u8],
+    system_use: &'a [u8],
+}
+
+/// The directory records in a directory's extent. Records don't cross
+/// sector boundaries, so a zero length means the rest of the sector is
+/// padding.
+fn records(data: &[u8]) -> Vec<Record<'_>> {
+    let mut records = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let len = data[at] as usize;
+        if len == 0 {
+            at = (at / SECTOR as usize + 1) * SECTOR as usize;
+            continue;
+        }
+        let Some(record) = data.get(at..at + len).filter(|_| len >= 34) else {
+            break;
+        };
+        let name_len = record[32] as usize;
+        let Some(name) = record.get(33..33 + name_len) else {
+            break;
+        };
+        // A padding byte keeps the system use area at an even offset.
+        let system_use = record
+            .get(33 + name_len + (name_len + 1) % 2..)
+            .unwrap_or_default();
+        records.push(Record {
+            extent: u32_at(record, 2),
+            size: u32_at(record, 10),
+            flags: record[25],
+            name,
+            system_use,
+        });
+        at += len;
+    }
+    records
+}
+
+/// The System Use Sharing Protocol entries of a record, as
+/// `(signature, data)`.
+fn susp_entries(mut area: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut entries = Vec::new();
+    while area.len() >= 4 {
+        let len = area[2] as usize;
+        if len < 4 || len > area.len() {
+            break;
+        }
+        entries.push((&area[..2], &area[4..len]));
+        area = &area[len..];
+    }
+    entries
+}
+
+/// A record's name and, with Rock Ridge, its permission bits.
+fn name(record: &Record, names: Names) -> (String, Option<u16>) {
+    let iso = || {
+        let name = String::from_utf8_lossy(record.name);
+        let name = name.split(';').next().unwrap_or_default();
+        name.strip_suffix('.').unwrap_or(name).to_string()
+    };
+    match names {
+        Names::Iso => (iso(), None),
+        Names::Joliet => {
+            let units = record
+                .name
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+            let name = String::from_utf16_lossy(&units.collect::<Vec<_>>());
+            (name.split(';').next().unwrap_or_default().to_string(), None)
+        }
+        Names::RockRidge => {
+            let mut name = None::<Vec<u8>>;
+            let mut mode = None;
+            for (signature, data) in susp_entries(record.system_use) {
+                match signature {
+                    // Long names may be split over several entries.
+                    b"NM" if !data.is_empty() => {
+                        name.get_or_insert_with(Vec::new).extend(&data[1..]);
+                    }
+                    b"PX" if data.len() >= 5 => mode = Some(u32_at(data, 1) as u16 & 0o7777),
+                    _ => {}
+                }
+            }
+            match name {
+                Some(name) => (String::from_utf8_lossy(&name).into_owned(), mode),
+                None => (iso(), mode),
+            }
+        }
+    }
+}
+
+pub fn list(reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    // The primary descriptor's root, and a Joliet one's if there is one.
+    let mut primary = None;
+    let mut joliet = None;
+    for sector in FIRST_DESCRIPTOR.. {
+        let descriptor = reader.read_at(sector * SECTOR, SECTOR)?;
+        if descriptor.len() < SECTOR as usize || &descriptor[1..6] != b"CD001" {
+            return Err(bad("not an ISO 9660 image"));
+        }
+        let root = descriptor[156..190].to_vec();
+        match descriptor[0] {
+            1 if primary.is_none() => primary = Some(root),
+            2 if matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E") => joliet = Some(root),
+            255 => break,
+            _ => {}
+        }
+    }
+    let primary = primary.ok_or_else(|| bad("no primary volume descriptor"))?;
+    let root_record = &records(&primary)[0];
+    let root_data = reader.read_at(root_record.extent * SECTOR, root_record.size)?;
+    // Rock Ridge images announce SUSP in the root's own `.` record.
+    let rock_ridge = records(&root_data).first().is_some_and(|dot| {
+        susp_entries(dot.system_use)
+            .iter()
+            .any(|(signature, _)| *signature == b"SP")
+    });
+    let (root, names) = match (rock_ridge, joliet) {
+        (true, _) => (primary, Names::RockRidge),
+        (false, Some(joliet)) => (joliet, Names::Joliet),
+        (false, None) => (primary, Names::Iso),
+    };
+    let root = &records(&root)[0];
+
+    let mut members = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![(Vec::<String>::new(), root.extent, root.size)];
+    while let Some((path, extent, size)) = pending.pop() {
+        if !seen.insert(extent) {
+            return Err(bad(format!("directory {} contains itself", path.join("/"))));
+        }
+        let data = reader.read_at(extent * SECTOR, size)?;
+        let mut subdirectories = Vec::new();
+        // Files over 4 GiB are split into records of the same name.
+        let mut partial: Option<(u64, u64)> = None;
+        for record in records(&data) {
+            // `.` and `..`.
+            if record.name == [0] || record.name == [1] {
+                continue;
+            }
+            let (name, mode) = name(&record, names);
+            let mut member_path = path.clone();
+            member_path.extend(components(&name)?);
+            if member_path.len() != path.len() + 1 {
+                return Err(bad(format!("bad name {:?} in {}", name, path.join("/"))));
+            }
+            if record.flags & DIRECTORY != 0 {
+                members.push(Member {
+                    path: member_path.clone(),
+                    mode,
+                    kind: MemberKind::Directory,
+                });
+                subdirectories.push((member_path, record.extent, record.size));
+                continue;
+            }
+            let start = record.extent * SECTOR;
+            let (start, size) = match partial.take() {
+                Some((first, len)) if first + len == start => (first, len + record.size),
+                Some(_) => {
+                    warn!("Skipping {}, whose extents aren't contiguous", name);
+                    continue;
+                }
+                None => (start, record.size),
+            };
+            if record.flags & MULTI_EXTENT != 0 {
+                partial = Some((start, size));
+                continue;
+            }
+            members.push(Member {
+                path: member_path,
+                mode,
+                kind: MemberKind::File {
+                    size,
+                    data: Data::Range { start, len: size },
+                },
+            });
+        }
+        pending.extend(subdirectories.into_iter().rev());
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::archive::{Data, MemberKind, ReadAt};
+
+    use super::{list, SECTOR};
+
+    fn record(extent: u32, size: u32, directory: bool, name: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; 33];
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = if directory { 2 } else { 0 };
+        record[32] = name.len() as u8;
+        record.extend(name);
+        if name.len().is_multiple_of(2) {
+            record.push(0);
+        }
+        record.extend(system_use);
+        record[0] = record.len() as u8;
+        record
+    }
+
+    fn nm(name: &str) -> Vec<u8> {
+        [b"NM", &[5 + name.len() as u8, 1, 0][..], name.as_bytes()].concat()
+    }
+
+    /// An image holding `DIR/README.TXT;1` and `HELLO.;1`, with Rock Ridge
+    /// names `docs/readme.txt` and `hello` when `rock_ridge` is set.
+    pub fn image(rock_ridge: bool) -> Vec<u8> {
+        let sector = |records: &[Vec<u8>]| {
+            let mut sector = records.concat();
+            sector.resize(SECTOR as usize, 0);
+            sector
+        };
+        let su = |entry: Vec<u8>| if rock_ridge { entry } else { Vec::new() };
+        let sp = [b"SP", &[7u8, 1, 0xbe, 0xef, 0][..]].concat();
+        let root = |name: &[u8], system_use: Vec<u8>| record(18, 2048, true, name, &system_use);
+        let mut image = vec![0; 16 * SECTOR as usize];
+        let mut pvd = vec![0; SECTOR as usize];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[156..190].copy_from_slice(&root(&[0], Vec::new()));
+        image.extend(pvd);
+        let mut terminator = vec![0; SECTOR as usize];
+        terminator[0] = 255;
+        terminator[1..6].copy_from_slice(b"CD001");
+        image.extend(terminator);
+        image.extend(sector(&[
+            root(&[0], su(sp)),
+            root(&[1], Vec::new()),
+            record(19, 2048, true, b"DIR", &su(nm("docs"))),
+            record(21, 5, false, b"HELLO.;1", &su(nm("hello"))),
+        ]));
+        image.extend(sector(&[
+            record(19, 2048, true, &[0], &[]),
+            root(&[1], Vec::new()),
+            record(20, 6, false, b"README.TXT;1", &su(nm("readme.txt"))),
+        ]));
+        image.extend(sector(&[b"read!\n".to_vec()]));
+        image.extend(sector(&[b"hello".to_vec()]));
+        image
+    }
+
+    #[test]
+    fn members() {
+        for (rock_ridge, paths) in [
+            (true, ["docs", "docs/readme.txt", "hello"]),
+            (false, ["DIR", "DIR/README.TXT", "HELLO"]),
+        ] {
+            let image = image(rock_ridge);
+            let members = list(&mut &image[..]).unwrap();
+            let names = members.iter().map(|m| m.path.join("/")).collect::<Vec<_>>();
+            assert_eq!(names, [paths[0], paths[2], paths[1]]);
+            let MemberKind::File {
+                size,
+                data: Data::Range { start, len },
+            } = members[1].kind
+            else {
+                panic!("Unexpected {:?}", members[1]);
+            };
+            assert_eq!(size, 5);
+            assert_eq!((&image[..]).read_at(start, len).unwrap(), b"hello");
+        }
+        assert!(list(&mut &[0; 40_000][..]).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Remote archives mounted as directories. Only the archive's index is
+//! read when loading the layout; members are read by range when opened.
+
+use std::{error::Error, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fetch::{clamped, Fetchers, Request},
+    transform::{Compression, GzipIndex},
+    Result,
+};
+
+// Without the `archive` feature nothing is listed, but the types members
+// are read through stay.
+#[cfg(feature = "archive")]
+mod iso9660;
+#[cfg_attr(not(feature = "archive"), allow(dead_code, unused_imports))]
+mod sevenz;
+#[cfg_attr(not(feature = "archive"), allow(dead_code, unused_imports))]
+mod squashfs;
+#[cfg(feature = "archive")]
+mod tar;
+#[cfg_attr(not(feature = "archive"), allow(dead_code, unused_imports))]
+mod zip;
+
+pub use sevenz::Folder;
+pub use squashfs::Blocks;
+pub use zip::{data_start as zip_data_start, LOCAL_HEADER_LEN as ZIP_LOCAL_HEADER_LEN};
+
+#[cfg(all(test, feature = "archive"))]
+pub use {
+    sevenz::test::archive as sevenz_fixture, squashfs::test as squashfs_fixture,
+    tar::test::tar as tar_fixture, zip::test::zip as zip_fixture,
+};
+
+/// The archive formats an entry's `archive` can name.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Archive {
+    Tar,
+    Zip,
+    Squashfs,
+    Iso9660,
+    #[serde(rename = "7z")]
+    SevenZ,
+}
+
+impl Display for Archive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Archive::Tar => write!(f, "tar"),
+            Archive::Zip => write!(f, "zip"),
+            Archive::Squashfs => write!(f, "SquashFS"),
+            Archive::Iso9660 => write!(f, "ISO 9660"),
+            Archive::SevenZ => write!(f, "7z"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BadArchive(pub String);
+
+impl Display for BadArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bad archive: {}", self.0)
+    }
+}
+
+impl Error for BadArchive {}
+
+/// Where a member's bytes are within the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Data {
+    /// Stored as is, `len` bytes from `start`.
+    Range { start: u64, len: u64 },
+    /// A zip member whose local header is at `header`, followed by
+    /// `compressed_size` bytes of data.
+    Zip {
+        header: u64,
+        compressed_size: u64,
+        compression: Option<Compression>,
+    },
+    /// In blocks compressed one by one, as in SquashFS images.
+    Blocks(Box<Blocks>),
+    /// Coded together with the other members of `folder`, as in solid 7z
+    /// archives, `offset` bytes into what it decodes to.
+    Folder { folder: Box<Folder>, offset: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberKind {
+    Directory,
+    File { size: u64, data: Data },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// Path components below the archive's root.
+    pub path: Vec<String>,
+    /// Permission bits, if the archive records them.
+    pub mode: Option<u16>,
+    pub kind: MemberKind,
+}
+
+/// Random access to the archive's bytes.
+pub trait ReadAt {
+    fn size(&self) -> u64;
+
+    /// Returns `len` bytes at `offset`, fewer only at the end.
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>>;
+}
+
+impl ReadAt for &[u8] {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let start = clamped(offset, self.len());
+        let end = start + clamped(len, self.len() - start);
+        Ok(self[start..end].to_vec())
+    }
+}
+
+/// Reads through a fetcher, asking for at least `WINDOW`
+/// bytes at a time so that neighbouring index entries come in one request.
+pub struct RangeReader<'a> {
+    fetchers: &'a Fetchers,
+    request: Request<'a>,
+    window_start: u64,
+    window: Vec<u8>,
+}
+
+impl<'a> RangeReader<'a> {
+    const WINDOW: u64 = 256 * 1024;
+
+    pub fn new(fetchers: &'a Fetchers, request: Request<'a>) -> RangeReader<'a> {
+        RangeReader {
+            fetchers,
+            request,
+            window_start: 0,
+            window: Vec::new(),
+        }
+    }
+}
+
+impl ReadAt for RangeReader<'_> {
+    fn size(&self) -> u64 {
+        self.request.size
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let end = offset.saturating_add(len).min(self.request.size);
+        let window_end = self.window_start + self.window.len() as u64;
+        if offset < self.window_start || end > window_end {
+            let fetch_len = len
+                .max(Self::WINDOW)
+                .min(self.request.size.saturating_sub(offset));
+            self.window = self
+                .fetchers
+                .fetch_range(&self.request, Some((offset, fetch_len)))?;
+            self.window_start = offset;
+        }
+        let mut window = &self.window[..];
+        window.read_at(offset - self.window_start, len)
+    }
+}
+
+/// The volumes of a split archive, read as one.
+pub struct VolumeReader<'a> {
+    volumes: Vec<RangeReader<'a>>,
+}
+
+impl<'a> VolumeReader<'a> {
+    pub fn new(volumes: Vec<RangeReader<'a>>) -> VolumeReader<'a> {
+        VolumeReader { volumes }
+    }
+}
+
+impl ReadAt for VolumeReader<'_> {
+    fn size(&self) -> u64 {
+        self.volumes.iter().map(ReadAt::size).sum()
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let end = offset.saturating_add(len);
+        let mut out = Vec::new();
+        let mut volume_start = 0;
+        for volume in &mut self.volumes {
+            let volume_end = volume_start + volume.size();
+            if offset < volume_end && end > volume_start {
+                let from = offset.max(volume_start) - volume_start;
+                let to = end.min(volume_end) - volume_start;
+                out.extend(volume.read_at(from, to - from)?);
+            }
+            volume_start = volume_end;
+        }
+        Ok(out)
+    }
+}
+
+/// The decompressed bytes of a gzip stream, as of a `.tar.gz`, through
+/// `index`. Its size isn't known without decompressing all of it.
+pub struct GzipReader<'a, R> {
+    pub inner: R,
+    pub index: &'a mut GzipIndex,
+}
+
+impl<R: ReadAt> ReadAt for GzipReader<'_, R> {
+    fn size(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.index.read(&mut self.inner, offset, len)
+    }
+}
+
+/// Lists the members of an archive.
+#[cfg(feature = "archive")]
+pub fn list(archive: Archive, reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    match archive {
+        Archive::Tar => tar::list(reader),
+        Archive::Zip => zip::list(reader),
+        Archive::Squashfs => squashfs::list(reader),
+        Archive::Iso9660 => iso9660::list(reader),
+        Archive::SevenZ => sevenz::list(reader),
+    }
+}
+
+#[cfg(not(feature = "archive"))]
+pub fn list(_archive: Archive, _reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    Err(Box::new(crate::NotBuilt("archive")))
+}
+
+/// Splits a member's path into its components, dropping `.` and leading
+/// slashes. Members trying to escape with `..` are refused, and so are
+/// names with a NUL in them, which no directory entry can have.
+fn components(path: &str) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                return Err(Box::new(BadArchive(format!(
+                    "member {} leaves the archive",
+                    path
+                ))))
+            }
+            part if part.contains('\0') => {
+                return Err(Box::new(BadArchive(format!(
+                    "member {:?} has a NUL in its name",
+                    path
+                ))))
+            }
+            part => out.push(part.to_string()),
+        }
+    }
+    Ok(out)
+}
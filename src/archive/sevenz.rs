@@ -0,0 +1,784 @@
+//! 7z archives. The header at the end, usually compressed itself, lists
+//! folders: runs of packed bytes that decode to several members one after
+//! the other. Members of uncompressed folders are read by range; the
+//! others need their whole folder decoded.
+
+#[cfg(feature = "archive")]
+use flate2::Crc;
+#[cfg(feature = "archive")]
+use lzma_rs::decompress::{Options, UnpackedSize};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{transform::Compression, Result};
+
+use super::{components, BadArchive, Data, Member, MemberKind, ReadAt};
+
+const MAGIC: &[u8] = b"7z\xbc\xaf\x27\x1c";
+const SIGNATURE_HEADER_LEN: u64 = 32;
+const DIRECTORY_ATTRIBUTE: u32 = 0x10;
+/// Set when the high 16 bits of the attributes are a Unix mode.
+const UNIX_EXTENSION: u32 = 0x8000;
+
+/// The ids of the header's properties.
+mod property {
+    pub const END: u8 = 0x00;
+    pub const HEADER: u8 = 0x01;
+    pub const ARCHIVE_PROPERTIES: u8 = 0x02;
+    pub const ADDITIONAL_STREAMS: u8 = 0x03;
+    pub const MAIN_STREAMS: u8 = 0x04;
+    pub const FILES: u8 = 0x05;
+    pub const PACK_INFO: u8 = 0x06;
+    pub const UNPACK_INFO: u8 = 0x07;
+    pub const SUBSTREAMS: u8 = 0x08;
+    pub const SIZE: u8 = 0x09;
+    pub const CRC: u8 = 0x0a;
+    pub const FOLDER: u8 = 0x0b;
+    pub const CODERS_UNPACK_SIZE: u8 = 0x0c;
+    pub const NUM_UNPACK_STREAM: u8 = 0x0d;
+    pub const EMPTY_STREAM: u8 = 0x0e;
+    pub const EMPTY_FILE: u8 = 0x0f;
+    pub const ANTI: u8 = 0x10;
+    pub const NAMES: u8 = 0x11;
+    pub const ATTRIBUTES: u8 = 0x15;
+    pub const ENCODED_HEADER: u8 = 0x17;
+}
+
+fn bad(message: impl Into<String>) -> Box<dyn std::error::Error> {
+    Box::new(BadArchive(message.into()))
+}
+
+#[cfg(feature = "archive")]
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// How a folder's packed bytes are coded. Folders chaining several coders,
+/// such as the BCJ filters 7-Zip puts in front of executables, aren't
+/// supported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Coder {
+    Copy,
+    /// LZMA, with its properties byte and dictionary size.
+    Lzma([u8; 5]),
+    Lzma2,
+    Deflate,
+}
+
+impl Coder {
+    fn from_id(id: &[u8], properties: &[u8]) -> Option<Coder> {
+        match id {
+            [0x00] => Some(Coder::Copy),
+            [0x03, 0x01, 0x01] => properties.try_into().ok().map(Coder::Lzma),
+            [0x21] => Some(Coder::Lzma2),
+            [0x04, 0x01, 0x08] => Some(Coder::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// The `packed_len` bytes from `start` that decode to `unpacked_len`
+/// bytes, the members of a folder one after the other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Folder {
+    pub start: u64,
+    pub packed_len: u64,
+    pub unpacked_len: u64,
+    pub coder: Coder,
+}
+
+impl Folder {
+    pub fn decode(&self, packed: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.unpacked_len as usize);
+        match &self.coder {
+            Coder::Copy => out.extend_from_slice(packed),
+            #[cfg(feature = "archive")]
+            Coder::Lzma(properties) => {
+                let options = Options {
+                    unpacked_size: UnpackedSize::UseProvided(Some(self.unpacked_len)),
+                    ..Options::default()
+                };
+                let input = [&properties[..], packed].concat();
+                lzma_rs::lzma_decompress_with_options(&mut &input[..], &mut out, &options)?;
+            }
+            #[cfg(feature = "archive")]
+            Coder::Lzma2 => lzma_rs::lzma2_decompress(&mut &packed[..], &mut out)?,
+            #[cfg(not(feature = "archive"))]
+            Coder::Lzma(_) | Coder::Lzma2 => return Err(Box::new(crate::NotBuilt("archive"))),
+            Coder::Deflate => out = Compression::Deflate.decompress(packed)?,
+        }
+        if out.len() as u64 != self.unpacked_len {
+            return Err(bad(format!(
+                "7z folder at {} decoded to {} bytes instead of {}",
+                self.start,
+                out.len(),
+                self.unpacked_len
+            )));
+        }
+        Ok(out)
+    }
+}
+
+/// Reads the header's numbers, bit vectors and properties.
+struct Cursor<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Cursor<'a> {
+        Cursor { data, at: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.at..self.at.saturating_add(len))
+            .ok_or_else(|| bad("truncated 7z header"))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// A number of one to nine bytes, as many as the first byte has
+    /// leading ones plus one.
+    fn number(&mut self) -> Result<u64> {
+        let first = self.byte()?;
+        let mut value = 0;
+        for i in 0..8 {
+            let mask = 0x80 >> i;
+            if first & mask == 0 {
+                return Ok(value | ((first & (mask - 1)) as u64) << (8 * i));
+            }
+            value |= (self.byte()? as u64) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    /// A number counting things that follow, each of which is at least a
+    /// bit long, so that a corrupt count can't make for a huge allocation.
+    fn count(&mut self) -> Result<usize> {
+        let count = self.number()?;
+        if count > (self.data.len() as u64) * 8 {
+            return Err(bad(format!("bad count {} in 7z header", count)));
+        }
+        Ok(count as usize)
+    }
+
+    fn bits(&mut self, len: usize) -> Result<Vec<bool>> {
+        let bytes = self.bytes(len.div_ceil(8))?;
+        Ok((0..len)
+            .map(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect())
+    }
+
+    /// Bits saying which of `len` items have a value, or a byte saying
+    /// they all have.
+    fn defined(&mut self, len: usize) -> Result<Vec<bool>> {
+        match self.byte()? {
+            0 => self.bits(len),
+            _ => Ok(vec![true; len]),
+        }
+    }
+
+    fn skip_digests(&mut self, len: usize) -> Result<()> {
+        let defined = self.defined(len)?.into_iter().filter(|&d| d).count();
+        self.bytes(4 * defined)?;
+        Ok(())
+    }
+
+    fn expect(&mut self, id: u8) -> Result<()> {
+        match self.byte()? {
+            found if found == id => Ok(()),
+            found => Err(unexpected(found)),
+        }
+    }
+}
+
+fn unexpected(id: u8) -> Box<dyn std::error::Error> {
+    bad(format!("unexpected property {:#04x} in 7z header", id))
+}
+
+struct FolderInfo {
+    /// `None` unless the folder has one coder, and one that is supported.
+    coder: Option<Coder>,
+    packed_streams: usize,
+    out_streams: usize,
+    /// The output stream not bound to another coder's input.
+    main_out: usize,
+    unpacked_len: u64,
+    crc: bool,
+}
+
+fn folder(c: &mut Cursor) -> Result<FolderInfo> {
+    let coders = c.count()?;
+    let (mut ins, mut outs) = (0, 0);
+    let mut coder = None;
+    for _ in 0..coders {
+        let flags = c.byte()?;
+        if flags & 0x80 != 0 {
+            return Err(bad("7z alternative coders aren't supported"));
+        }
+        let id = c.bytes((flags & 0x0f) as usize)?;
+        let (coder_ins, coder_outs) = match flags & 0x10 {
+            0 => (1, 1),
+            _ => (c.count()?, c.count()?),
+        };
+        let properties = match flags & 0x20 {
+            0 => &[][..],
+            _ => {
+                let len = c.count()?;
+                c.bytes(len)?
+            }
+        };
+        ins += coder_ins;
+        outs += coder_outs;
+        coder = Coder::from_id(id, properties).filter(|_| coders == 1);
+    }
+    let bind_pairs = outs
+        .checked_sub(1)
+        .ok_or_else(|| bad("7z folder without coders"))?;
+    let mut bound = Vec::new();
+    for _ in 0..bind_pairs {
+        c.number()?;
+        bound.push(c.number()?);
+    }
+    let packed_streams = ins
+        .checked_sub(bind_pairs)
+        .ok_or_else(|| bad("bad 7z bind pairs"))?;
+    if packed_streams > 1 {
+        for _ in 0..packed_streams {
+            c.number()?;
+        }
+    }
+    let main_out = (0..outs)
+        .find(|&out| !bound.contains(&(out as u64)))
+        .ok_or_else(|| bad("7z folder without an output"))?;
+    Ok(FolderInfo {
+        coder,
+        packed_streams,
+        out_streams: outs,
+        main_out,
+        unpacked_len: 0,
+        crc: false,
+    })
+}
+
+/// The folders of an archive, and the sizes of the members in each.
+#[derive(Default)]
+struct Streams {
+    /// `None` for folders coded in ways lhttpfs can't decode.
+    folders: Vec<Option<Folder>>,
+    members: Vec<Vec<u64>>,
+}
+
+fn streams(c: &mut Cursor) -> Result<Streams> {
+    use property::*;
+    let mut pack_pos = 0;
+    let mut pack_sizes = Vec::new();
+    let mut folders = Vec::new();
+    let mut members = None;
+    loop {
+        match c.byte()? {
+            END => break,
+            PACK_INFO => {
+                pack_pos = c.number()?;
+                let count = c.count()?;
+                loop {
+                    match c.byte()? {
+                        END => break,
+                        SIZE => {
+                            pack_sizes = (0..count).map(|_| c.number()).collect::<Result<_>>()?
+                        }
+                        CRC => c.skip_digests(count)?,
+                        id => return Err(unexpected(id)),
+                    }
+                }
+            }
+            UNPACK_INFO => {
+                c.expect(FOLDER)?;
+                let count = c.count()?;
+                if c.byte()? != 0 {
+                    return Err(bad("7z folders in another stream aren't supported"));
+                }
+                folders = (0..count).map(|_| folder(c)).collect::<Result<Vec<_>>>()?;
+                c.expect(CODERS_UNPACK_SIZE)?;
+                for folder in &mut folders {
+                    for out in 0..folder.out_streams {
+                        let size = c.number()?;
+                        if out == folder.main_out {
+                            folder.unpacked_len = size;
+                        }
+                    }
+                }
+                loop {
+                    match c.byte()? {
+                        END => break,
+                        CRC => {
+                            for (folder, defined) in folders.iter_mut().zip(c.defined(count)?) {
+                                folder.crc = defined;
+                                if defined {
+                                    c.u32()?;
+                                }
+                            }
+                        }
+                        id => return Err(unexpected(id)),
+                    }
+                }
+            }
+            SUBSTREAMS => members = Some(substreams(c, &folders)?),
+            id => return Err(unexpected(id)),
+        }
+    }
+    let members = match members {
+        Some(members) => members,
+        None => folders.iter().map(|f| vec![f.unpacked_len]).collect(),
+    };
+    // Each folder's packed streams follow the previous folder's.
+    let mut pack_stream = 0;
+    let mut start = SIGNATURE_HEADER_LEN + pack_pos;
+    let mut resolved = Vec::new();
+    for info in folders {
+        let sizes = pack_sizes
+            .get(pack_stream..pack_stream + info.packed_streams)
+            .ok_or_else(|| bad("7z folder without packed streams"))?;
+        let packed_len = sizes.iter().sum::<u64>();
+        resolved.push(
+            info.coder
+                .filter(|_| info.packed_streams == 1)
+                .map(|coder| Folder {
+                    start,
+                    packed_len,
+                    unpacked_len: info.unpacked_len,
+                    coder,
+                }),
+        );
+        start += packed_len;
+        pack_stream += info.packed_streams;
+    }
+    Ok(Streams {
+        folders: resolved,
+        members,
+    })
+}
+
+fn substreams(c: &mut Cursor, folders: &[FolderInfo]) -> Result<Vec<Vec<u64>>> {
+    use property::*;
+    let mut counts = vec![1; folders.len()];
+    let mut sizes = None;
+    loop {
+        match c.byte()? {
+            END => break,
+            NUM_UNPACK_STREAM => {
+                counts = (0..folders.len())
+                    .map(|_| c.count())
+                    .collect::<Result<_>>()?
+            }
+            SIZE => {
+                let mut all = Vec::new();
+                for (folder, &count) in folders.iter().zip(&counts) {
+                    let mut members = Vec::new();
+                    if count > 0 {
+                        for _ in 1..count {
+                            members.push(c.number()?);
+                        }
+                        let rest = folder
+                            .unpacked_len
+                            .checked_sub(members.iter().sum())
+                            .ok_or_else(|| bad("7z members larger than their folder"))?;
+                        members.push(rest);
+                    }
+                    all.push(members);
+                }
+                sizes = Some(all);
+            }
+            CRC => {
+                // Single members of folders with a digest aren't repeated.
+                let digests = folders
+                    .iter()
+                    .zip(&counts)
+                    .filter(|(folder, &count)| !(count == 1 && folder.crc))
+                    .map(|(_, &count)| count)
+                    .sum();
+                c.skip_digests(digests)?;
+            }
+            id => return Err(unexpected(id)),
+        }
+    }
+    match sizes {
+        Some(sizes) => Ok(sizes),
+        None => folders
+            .iter()
+            .zip(counts)
+            .map(|(folder, count)| match count {
+                0 => Ok(Vec::new()),
+                1 => Ok(vec![folder.unpacked_len]),
+                _ => Err(bad("7z member sizes missing")),
+            })
+            .collect(),
+    }
+}
+
+fn files(c: &mut Cursor, streams: Streams) -> Result<Vec<Member>> {
+    use property::*;
+    let count = c.count()?;
+    let mut empty_stream = vec![false; count];
+    let mut empty_file = Vec::new();
+    let mut anti = Vec::new();
+    let mut names = Vec::new();
+    let mut attributes = vec![None; count];
+    loop {
+        let id = c.byte()?;
+        if id == END {
+            break;
+        }
+        let len = c.count()?;
+        let mut body = Cursor::new(c.bytes(len)?);
+        let empties = empty_stream.iter().filter(|&&e| e).count();
+        match id {
+            EMPTY_STREAM => empty_stream = body.bits(count)?,
+            EMPTY_FILE => empty_file = body.bits(empties)?,
+            ANTI => anti = body.bits(empties)?,
+            NAMES => {
+                if body.byte()? != 0 {
+                    return Err(bad("7z names in another stream aren't supported"));
+                }
+                let units = body.data[body.at..]
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect::<Vec<_>>();
+                names = units
+                    .split(|&unit| unit == 0)
+                    .take(count)
+                    .map(String::from_utf16_lossy)
+                    .collect();
+            }
+            ATTRIBUTES => {
+                let defined = body.defined(count)?;
+                if body.byte()? != 0 {
+                    return Err(bad("7z attributes in another stream aren't supported"));
+                }
+                for (attribute, defined) in attributes.iter_mut().zip(defined) {
+                    if defined {
+                        *attribute = Some(body.u32()?);
+                    }
+                }
+            }
+            // Times and padding.
+            _ => {}
+        }
+    }
+    if names.len() != count {
+        return Err(bad("7z archive without names for every member"));
+    }
+
+    // Where each member with data is: its folder, and its offset there.
+    let mut data = streams
+        .members
+        .iter()
+        .enumerate()
+        .flat_map(|(folder, sizes)| {
+            sizes.iter().scan(0, move |offset, &size| {
+                *offset += size;
+                Some((folder, *offset - size, size))
+            })
+        });
+    let mut members = Vec::new();
+    let mut empty = 0;
+    for (i, name) in names.iter().enumerate() {
+        let attribute = attributes[i].unwrap_or(0);
+        let unix = (attribute & UNIX_EXTENSION != 0).then_some(attribute >> 16);
+        let (directory, kind) = match empty_stream[i] {
+            true => {
+                empty += 1;
+                if anti.get(empty - 1) == Some(&true) {
+                    continue;
+                }
+                let file = empty_file.get(empty - 1) == Some(&true);
+                let directory = !file || attribute & DIRECTORY_ATTRIBUTE != 0;
+                let kind = MemberKind::File {
+                    size: 0,
+                    data: Data::Range { start: 0, len: 0 },
+                };
+                (directory, kind)
+            }
+            false => {
+                let (folder, offset, size) = data
+                    .next()
+                    .ok_or_else(|| bad("7z archive with more members than streams"))?;
+                let Some(folder) = &streams.folders[folder] else {
+                    warn!("Skipping {}, coded in a way lhttpfs can't decode", name);
+                    continue;
+                };
+                let data = match folder.coder {
+                    Coder::Copy => Data::Range {
+                        start: folder.start + offset,
+                        len: size,
+                    },
+                    _ => Data::Folder {
+                        folder: Box::new(folder.clone()),
+                        offset,
+                    },
+                };
+                (false, MemberKind::File { size, data })
+            }
+        };
+        if unix.is_some_and(|mode| mode & 0o170000 == 0o120000) {
+            warn!("Skipping symlink {}", name);
+            continue;
+        }
+        members.push(Member {
+            path: components(&name.replace('\\', "/"))?,
+            mode: unix.map(|mode| mode as u16 & 0o7777),
+            kind: match directory {
+                true => MemberKind::Directory,
+                false => kind,
+            },
+        });
+    }
+    Ok(members)
+}
+
+fn header(c: &mut Cursor) -> Result<Vec<Member>> {
+    use property::*;
+    let mut main = Streams::default();
+    loop {
+        match c.byte()? {
+            END => return Ok(Vec::new()),
+            ARCHIVE_PROPERTIES => loop {
+                if c.byte()? == END {
+                    break;
+                }
+                let len = c.count()?;
+                c.bytes(len)?;
+            },
+            ADDITIONAL_STREAMS => {
+                streams(c)?;
+            }
+            MAIN_STREAMS => main = streams(c)?,
+            FILES => return files(c, main),
+            id => return Err(unexpected(id)),
+        }
+    }
+}
+
+#[cfg(feature = "archive")]
+pub fn list(reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    let signature = reader.read_at(0, SIGNATURE_HEADER_LEN)?;
+    if signature.len() < SIGNATURE_HEADER_LEN as usize || &signature[..6] != MAGIC {
+        return Err(bad("not a 7z archive"));
+    }
+    let u64_at = |at: usize| u64::from_le_bytes(signature[at..at + 8].try_into().unwrap());
+    let u32_at = |at: usize| u32::from_le_bytes(signature[at..at + 4].try_into().unwrap());
+    if crc32(&signature[12..32]) != u32_at(8) {
+        return Err(bad("corrupt 7z signature header"));
+    }
+    let (offset, len) = (u64_at(12), u64_at(20));
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let start = SIGNATURE_HEADER_LEN
+        .checked_add(offset)
+        .ok_or_else(|| bad("bad 7z header offset"))?;
+    let mut data = reader.read_at(start, len)?;
+    if crc32(&data) != u32_at(28) {
+        return Err(bad("corrupt 7z header"));
+    }
+    // The header is normally packed like a folder of its own, and the
+    // packed header is described by a small one in the clear.
+    for _ in 0..2 {
+        let mut c = Cursor::new(&data);
+        match c.byte()? {
+            property::HEADER => return header(&mut c),
+            property::ENCODED_HEADER => {
+                let streams = streams(&mut c)?;
+                let Some(Some(folder)) = streams.folders.first() else {
+                    return Err(bad("7z header coded in a way lhttpfs can't decode"));
+                };
+                let packed = reader.read_at(folder.start, folder.packed_len)?;
+                data = folder.decode(&packed)?;
+            }
+            id => return Err(unexpected(id)),
+        }
+    }
+    Err(bad("7z header encoded more than once"))
+}
+
+#[cfg(all(test, feature = "archive"))]
+pub mod test {
+    use lzma_rs::compress::{Options, UnpackedSize};
+
+    use crate::archive::{Data, MemberKind, ReadAt};
+
+    use super::{crc32, list, property::*, Coder, MAGIC};
+
+    fn number(out: &mut Vec<u8>, value: usize) {
+        match value {
+            0..0x80 => out.push(value as u8),
+            0x80..0x4000 => out.extend([0x80 | (value >> 8) as u8, value as u8]),
+            _ => {
+                out.push(0xff);
+                out.extend((value as u64).to_le_bytes());
+            }
+        }
+    }
+
+    /// Packs `data` for `coder`, returning the coder's properties too.
+    fn encode(coder: &str, data: &[u8]) -> (Coder, Vec<u8>) {
+        match coder {
+            "copy" => (Coder::Copy, data.to_vec()),
+            "lzma" => {
+                let mut out = Vec::new();
+                let options = Options {
+                    unpacked_size: UnpackedSize::SkipWritingToHeader,
+                };
+                lzma_rs::lzma_compress_with_options(&mut &data[..], &mut out, &options).unwrap();
+                (Coder::Lzma(out[..5].try_into().unwrap()), out[5..].to_vec())
+            }
+            "lzma2" => {
+                let mut out = Vec::new();
+                lzma_rs::lzma2_compress(&mut &data[..], &mut out).unwrap();
+                (Coder::Lzma2, out)
+            }
+            _ => panic!("Unknown coder {}", coder),
+        }
+    }
+
+    /// MAIN_STREAMS or ENCODED_HEADER's body: one folder at `pack_pos`
+    /// holding members of `sizes`.
+    fn streams(out: &mut Vec<u8>, pack_pos: usize, packed: usize, coder: &Coder, sizes: &[usize]) {
+        out.extend([PACK_INFO]);
+        number(out, pack_pos);
+        number(out, 1);
+        out.push(SIZE);
+        number(out, packed);
+        out.extend([END, UNPACK_INFO, FOLDER, 1, 0, 1]);
+        match coder {
+            Coder::Copy => out.extend([0x01, 0x00]),
+            Coder::Lzma(properties) => {
+                out.extend([0x23, 0x03, 0x01, 0x01, 5]);
+                out.extend(properties);
+            }
+            Coder::Lzma2 => out.extend([0x21, 0x21, 1, 24]),
+            Coder::Deflate => out.extend([0x03, 0x04, 0x01, 0x08]),
+        }
+        out.push(CODERS_UNPACK_SIZE);
+        number(out, sizes.iter().sum());
+        out.extend([END, SUBSTREAMS, NUM_UNPACK_STREAM]);
+        number(out, sizes.len());
+        out.push(SIZE);
+        for &size in &sizes[..sizes.len() - 1] {
+            number(out, size);
+        }
+        out.extend([END, END]);
+    }
+
+    /// A 7z archive of `files` in one solid folder coded with `coder`
+    /// ("copy", "lzma" or "lzma2"), plus a directory `docs`. Its header is
+    /// packed with the same coder, as 7-Zip does, unless copied.
+    pub fn archive(files: &[(&str, &[u8])], coder: &str) -> Vec<u8> {
+        let unpacked = files
+            .iter()
+            .map(|(_, data)| *data)
+            .collect::<Vec<_>>()
+            .concat();
+        let (folder_coder, packed) = encode(coder, &unpacked);
+        let sizes = files.iter().map(|(_, data)| data.len()).collect::<Vec<_>>();
+
+        let mut header = vec![HEADER, MAIN_STREAMS];
+        streams(&mut header, 0, packed.len(), &folder_coder, &sizes);
+        header.push(FILES);
+        number(&mut header, files.len() + 1);
+        // Only `docs`, last, has no stream.
+        let mut bits = vec![0; (files.len() + 1).div_ceil(8)];
+        bits[files.len() / 8] |= 0x80 >> (files.len() % 8);
+        header.push(EMPTY_STREAM);
+        number(&mut header, bits.len());
+        header.extend(&bits);
+        let names = files.iter().map(|(name, _)| *name).chain(["docs"]);
+        let names = names
+            .flat_map(|name| name.encode_utf16().chain([0]))
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        header.push(NAMES);
+        number(&mut header, names.len() + 1);
+        header.push(0);
+        header.extend(names);
+        header.push(ATTRIBUTES);
+        number(&mut header, 2 + 4 * (files.len() + 1));
+        header.extend([1, 0]);
+        for _ in files {
+            header.extend((0x8000u32 | 0o100644 << 16).to_le_bytes());
+        }
+        header.extend((0x10u32 | 0x8000 | 0o40755 << 16).to_le_bytes());
+        header.extend([END, END]);
+
+        let mut body = packed;
+        if coder != "copy" {
+            let (header_coder, packed_header) = encode(coder, &header);
+            let (pack_pos, len) = (body.len(), packed_header.len());
+            body.extend(packed_header);
+            let raw_len = header.len();
+            header = vec![ENCODED_HEADER];
+            streams(&mut header, pack_pos, len, &header_coder, &[raw_len]);
+        }
+        let mut start = [0; 20];
+        start[..8].copy_from_slice(&(body.len() as u64).to_le_bytes());
+        start[8..16].copy_from_slice(&(header.len() as u64).to_le_bytes());
+        start[16..].copy_from_slice(&crc32(&header).to_le_bytes());
+        let mut archive = MAGIC.to_vec();
+        archive.extend([0, 4]);
+        archive.extend(crc32(&start).to_le_bytes());
+        archive.extend(start);
+        archive.extend(body);
+        archive.extend(header);
+        archive
+    }
+
+    #[test]
+    fn members() {
+        let files: [(&str, &[u8]); 2] = [("hello", b"hello"), ("docs/readme.txt", b"read!\n")];
+        for coder in ["copy", "lzma", "lzma2"] {
+            let archive = archive(&files, coder);
+            let members = list(&mut &archive[..]).unwrap();
+            let paths = members.iter().map(|m| m.path.join("/")).collect::<Vec<_>>();
+            assert_eq!(paths, ["hello", "docs/readme.txt", "docs"], "{}", coder);
+            assert_eq!(members[1].mode, Some(0o644));
+            assert_eq!(members[2].kind, MemberKind::Directory);
+            let MemberKind::File { size, data } = &members[1].kind else {
+                panic!("Unexpected {:?}", members[1]);
+            };
+            assert_eq!(*size, 6);
+            let data = match data {
+                Data::Range { start, len } => (&archive[..]).read_at(*start, *len).unwrap(),
+                Data::Folder { folder, offset } => {
+                    let packed = (&archive[..])
+                        .read_at(folder.start, folder.packed_len)
+                        .unwrap();
+                    let unpacked = folder.decode(&packed).unwrap();
+                    unpacked[*offset as usize..*offset as usize + 6].to_vec()
+                }
+                data => panic!("Unexpected {:?}", data),
+            };
+            assert_eq!(data, b"read!\n", "{}", coder);
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut archive = archive(&[("hello", b"hello")], "lzma2");
+        assert!(list(&mut &archive[..10]).is_err());
+        let last = archive.len() - 1;
+        archive[last] ^= 1;
+        assert!(list(&mut &archive[..]).is_err());
+    }
+}
@@ -0,0 +1,505 @@
+//! SquashFS images. The superblock points at tables of compressed
+//! metadata blocks holding the inodes and directories, which are walked
+//! from the root inode. A file's data is in blocks of its own, except
+//! for its tail, which may share a fragment block with other files.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{transform::Compression, Result};
+
+use super::{BadArchive, Data, Member, MemberKind, ReadAt};
+
+const MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_LEN: u64 = 96;
+/// The most a metadata block decompresses to.
+const METADATA_LEN: u64 = 8192;
+const UNCOMPRESSED_METADATA: u64 = 1 << 15;
+const UNCOMPRESSED_BLOCK: u32 = 1 << 24;
+const NO_FRAGMENT: u32 = u32::MAX;
+/// Fragment table entries per metadata block.
+const FRAGMENTS_PER_BLOCK: u64 = METADATA_LEN / 16;
+
+fn bad(message: impl Into<String>) -> Box<dyn std::error::Error> {
+    Box::new(BadArchive(message.into()))
+}
+
+fn u16_at(data: &[u8], at: usize) -> u64 {
+    u16::from_le_bytes([data[at], data[at + 1]]) as u64
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// A data or fragment block: `len` bytes at `start` of the image, or a
+/// block of zeros when `len` is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    pub start: u64,
+    pub len: u32,
+    pub compressed: bool,
+}
+
+impl Block {
+    fn new(start: u64, size: u32) -> Block {
+        Block {
+            start,
+            len: size & !UNCOMPRESSED_BLOCK,
+            compressed: size & UNCOMPRESSED_BLOCK == 0,
+        }
+    }
+}
+
+/// Where a file's data is in the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Blocks {
+    pub compression: Compression,
+    pub block_size: u64,
+    pub blocks: Vec<Block>,
+    /// The block holding the file's tail, and where in it the tail starts.
+    pub fragment: Option<(Block, u64)>,
+}
+
+impl Blocks {
+    /// The decompressed sizes of the file's parts: its blocks, then its
+    /// tail if it has one.
+    pub fn part_sizes(&self, size: u64) -> impl Iterator<Item = u64> + '_ {
+        let parts = self.blocks.len() as u64 + self.fragment.is_some() as u64;
+        (0..parts).map(move |i| (size - i * self.block_size).min(self.block_size))
+    }
+
+    /// Part `i` of the file, as its block and where the part starts in it.
+    pub fn part(&self, i: usize) -> (Block, u64) {
+        match self.blocks.get(i) {
+            Some(&block) => (block, 0),
+            None => self.fragment.unwrap(),
+        }
+    }
+}
+
+struct Image<'a> {
+    reader: &'a mut dyn ReadAt,
+    compression: Compression,
+    block_size: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+    fragment_count: u64,
+    /// Decompressed metadata blocks by position, and the position of the
+    /// block after each.
+    metadata: HashMap<u64, (Vec<u8>, u64)>,
+}
+
+impl Image<'_> {
+    fn metadata_block(&mut self, at: u64) -> Result<&(Vec<u8>, u64)> {
+        if !self.metadata.contains_key(&at) {
+            let data = self.reader.read_at(at, 2 + METADATA_LEN)?;
+            if data.len() < 2 {
+                return Err(bad(format!("no metadata block at {}", at)));
+            }
+            let header = u16_at(&data, 0);
+            let len = header & !UNCOMPRESSED_METADATA;
+            let stored = data
+                .get(2..2 + len as usize)
+                .ok_or_else(|| bad(format!("truncated metadata block at {}", at)))?;
+            let block = match header & UNCOMPRESSED_METADATA {
+                0 => self.compression.decompress(stored)?,
+                _ => stored.to_vec(),
+            };
+            self.metadata.insert(at, (block, at + 2 + len));
+        }
+        Ok(&self.metadata[&at])
+    }
+
+    /// Reads `len` bytes of metadata, `offset` bytes into the block at
+    /// `at`, continuing into the blocks after it.
+    fn read_metadata(&mut self, mut at: u64, mut offset: usize, len: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let (block, next) = self.metadata_block(at)?;
+            if block.is_empty() {
+                return Err(bad(format!("empty metadata block at {}", at)));
+            }
+            if offset < block.len() {
+                let take = (len - out.len()).min(block.len() - offset);
+                out.extend_from_slice(&block[offset..offset + take]);
+                offset = 0;
+            } else {
+                offset -= block.len();
+            }
+            at = *next;
+        }
+        Ok(out)
+    }
+
+    fn fragment(&mut self, index: u32) -> Result<Block> {
+        let index = index as u64;
+        if index >= self.fragment_count {
+            return Err(bad(format!("no fragment {}", index)));
+        }
+        let pointer_at = self.fragment_table + 8 * (index / FRAGMENTS_PER_BLOCK);
+        let pointer = self.reader.read_at(pointer_at, 8)?;
+        if pointer.len() < 8 {
+            return Err(bad("truncated fragment table"));
+        }
+        let offset = (index % FRAGMENTS_PER_BLOCK) as usize * 16;
+        let entry = self.read_metadata(u64_at(&pointer, 0), offset, 16)?;
+        Ok(Block::new(u64_at(&entry, 0), u32_at(&entry, 8)))
+    }
+
+    fn inode(&mut self, reference: u64) -> Result<Inode> {
+        let at = self.inode_table + (reference >> 16);
+        let offset = (reference & 0xffff) as usize;
+        let header = self.read_metadata(at, offset, 16)?;
+        let kind = u16_at(&header, 0);
+        let mode = u16_at(&header, 2) as u16 & 0o7777;
+        let fixed = match kind {
+            1 => 16,
+            2 => 16,
+            8 => 24,
+            9 => 40,
+            _ => return Ok(Inode::Other { kind }),
+        };
+        let body = self.read_metadata(at, offset + 16, fixed)?;
+        match kind {
+            1 => Ok(Inode::Directory {
+                mode,
+                block: u32_at(&body, 0) as u64,
+                size: u16_at(&body, 8),
+                offset: u16_at(&body, 10) as usize,
+            }),
+            8 => Ok(Inode::Directory {
+                mode,
+                size: u32_at(&body, 4) as u64,
+                block: u32_at(&body, 8) as u64,
+                offset: u16_at(&body, 18) as usize,
+            }),
+            _ => {
+                let (start, size, fragment, fragment_offset) = match kind {
+                    2 => (
+                        u32_at(&body, 0) as u64,
+                        u32_at(&body, 12) as u64,
+                        u32_at(&body, 4),
+                        u32_at(&body, 8) as u64,
+                    ),
+                    _ => (
+                        u64_at(&body, 0),
+                        u64_at(&body, 8),
+                        u32_at(&body, 28),
+                        u32_at(&body, 32) as u64,
+                    ),
+                };
+                let count = match fragment {
+                    NO_FRAGMENT => size.div_ceil(self.block_size),
+                    _ => size / self.block_size,
+                };
+                let sizes = self.read_metadata(at, offset + 16 + fixed, count as usize * 4)?;
+                let mut blocks = Vec::with_capacity(count as usize);
+                let mut block_start = start;
+                for size in sizes.chunks_exact(4).map(|size| u32_at(size, 0)) {
+                    let block = Block::new(block_start, size);
+                    block_start += block.len as u64;
+                    blocks.push(block);
+                }
+                let fragment = match fragment {
+                    NO_FRAGMENT => None,
+                    index => Some((self.fragment(index)?, fragment_offset)),
+                };
+                Ok(Inode::File {
+                    mode,
+                    size,
+                    blocks: Blocks {
+                        compression: self.compression,
+                        block_size: self.block_size,
+                        blocks,
+                        fragment,
+                    },
+                })
+            }
+        }
+    }
+
+    /// The `(name, inode reference)` entries of a directory.
+    fn entries(&mut self, block: u64, offset: usize, size: u64) -> Result<Vec<(String, u64)>> {
+        // The size counts the `.` and `..` entries that aren't stored.
+        let Some(len) = size.checked_sub(3).filter(|&len| len > 0) else {
+            return Ok(Vec::new());
+        };
+        let data = self.read_metadata(self.directory_table + block, offset, len as usize)?;
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while at + 12 <= data.len() {
+            let count = u32_at(&data, at) as usize + 1;
+            let inode_block = u32_at(&data, at + 4) as u64;
+            at += 12;
+            for _ in 0..count {
+                if at + 8 > data.len() {
+                    return Err(bad("truncated directory"));
+                }
+                let inode_offset = u16_at(&data, at);
+                let name_len = u16_at(&data, at + 6) as usize + 1;
+                let name = data
+                    .get(at + 8..at + 8 + name_len)
+                    .ok_or_else(|| bad("truncated directory"))?;
+                entries.push((
+                    String::from_utf8_lossy(name).into_owned(),
+                    inode_block << 16 | inode_offset,
+                ));
+                at += 8 + name_len;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+enum Inode {
+    Directory {
+        mode: u16,
+        block: u64,
+        offset: usize,
+        size: u64,
+    },
+    File {
+        mode: u16,
+        size: u64,
+        blocks: Blocks,
+    },
+    Other {
+        kind: u64,
+    },
+}
+
+pub fn list(reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    let superblock = reader.read_at(0, SUPERBLOCK_LEN)?;
+    if superblock.len() < SUPERBLOCK_LEN as usize || u32_at(&superblock, 0) != MAGIC {
+        return Err(bad("not a SquashFS image"));
+    }
+    if u16_at(&superblock, 28) != 4 {
+        return Err(bad(format!(
+            "SquashFS version {} isn't supported",
+            u16_at(&superblock, 28)
+        )));
+    }
+    let compression = match u16_at(&superblock, 20) {
+        1 => Compression::Zlib,
+        4 => Compression::Xz,
+        6 => Compression::Zstd,
+        id => return Err(bad(format!("SquashFS compression {} isn't supported", id))),
+    };
+    let mut image = Image {
+        reader,
+        compression,
+        block_size: u32_at(&superblock, 12) as u64,
+        inode_table: u64_at(&superblock, 64),
+        directory_table: u64_at(&superblock, 72),
+        fragment_table: u64_at(&superblock, 80),
+        fragment_count: u32_at(&superblock, 16) as u64,
+        metadata: HashMap::new(),
+    };
+    if image.block_size == 0 {
+        return Err(bad("SquashFS block size of 0"));
+    }
+    let mut members = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![(Vec::new(), u64_at(&superblock, 32))];
+    while let Some((path, reference)) = pending.pop() {
+        let name = path.join("/");
+        match image.inode(reference)? {
+            Inode::Directory {
+                mode,
+                block,
+                offset,
+                size,
+            } => {
+                if !seen.insert(reference) {
+                    return Err(bad(format!("directory {} contains itself", name)));
+                }
+                if !path.is_empty() {
+                    members.push(Member {
+                        path: path.clone(),
+                        mode: Some(mode),
+                        kind: MemberKind::Directory,
+                    });
+                }
+                let mut entries = image.entries(block, offset, size)?;
+                entries.reverse();
+                for (entry, reference) in entries {
+                    if entry.is_empty() || entry == "." || entry == ".." || entry.contains('/') {
+                        return Err(bad(format!("bad name {:?} in {}", entry, name)));
+                    }
+                    let mut path = path.clone();
+                    path.push(entry);
+                    pending.push((path, reference));
+                }
+            }
+            Inode::File { mode, size, blocks } => members.push(Member {
+                path,
+                mode: Some(mode),
+                kind: MemberKind::File {
+                    size,
+                    data: Data::Blocks(Box::new(blocks)),
+                },
+            }),
+            Inode::Other { kind } => {
+                warn!("Skipping {}, an inode of type {}", name, kind);
+            }
+        }
+    }
+    Ok(members)
+}
+
+#[cfg(all(test, feature = "archive"))]
+pub mod test {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+
+    use crate::archive::{Data, MemberKind, ReadAt};
+
+    use super::{list, Blocks, UNCOMPRESSED_BLOCK, UNCOMPRESSED_METADATA};
+
+    /// The contents of the files in [`image`].
+    pub const A: &[u8] = &[b'a'; 5000];
+    pub const B: &[u8] = b"hi";
+
+    /// An image with 4 KiB blocks holding `d/a.txt`, one compressed block
+    /// and a tail in the fragment, and `d/b.txt`, only a tail.
+    pub fn image() -> Vec<u8> {
+        let block_size = 4096u32;
+        let mut image = vec![0; 96];
+
+        let data_start = image.len() as u32;
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&A[..4096]).unwrap();
+        let block = encoder.finish().unwrap();
+        image.extend(&block);
+        let fragment_start = image.len() as u64;
+        let fragment = [&A[4096..], B].concat();
+        image.extend(&fragment);
+
+        let metadata = |image: &mut Vec<u8>, data: &[u8]| {
+            image.extend((data.len() as u16 | UNCOMPRESSED_METADATA as u16).to_le_bytes());
+            image.extend(data);
+        };
+        let header = |inodes: &mut Vec<u8>, kind: u16, mode: u16, number: u32| {
+            inodes.extend(kind.to_le_bytes());
+            inodes.extend(mode.to_le_bytes());
+            inodes.extend([0; 8]);
+            inodes.extend(number.to_le_bytes());
+        };
+        // Inodes of a.txt at 0, b.txt at 36, d at 68 and the root at 100.
+        let mut inodes = Vec::new();
+        header(&mut inodes, 2, 0o644, 1);
+        inodes.extend(data_start.to_le_bytes());
+        inodes.extend(0u32.to_le_bytes());
+        inodes.extend(0u32.to_le_bytes());
+        inodes.extend((A.len() as u32).to_le_bytes());
+        inodes.extend((block.len() as u32).to_le_bytes());
+        header(&mut inodes, 2, 0o755, 2);
+        inodes.extend(0u32.to_le_bytes());
+        inodes.extend(0u32.to_le_bytes());
+        inodes.extend((A.len() as u32 - 4096).to_le_bytes());
+        inodes.extend((B.len() as u32).to_le_bytes());
+        // Directory listings are of the root at 0 and d at 21.
+        for (number, listing, len) in [(3, 21u16, 38u16), (4, 0, 21)] {
+            header(&mut inodes, 1, 0o755, number);
+            inodes.extend(0u32.to_le_bytes());
+            inodes.extend(2u32.to_le_bytes());
+            inodes.extend((len + 3).to_le_bytes());
+            inodes.extend(listing.to_le_bytes());
+            inodes.extend(4u32.to_le_bytes());
+        }
+        assert_eq!(inodes.len(), 132);
+        let mut directories = Vec::new();
+        for entries in [
+            &[("d", 68u16, 1u16)][..],
+            &[("a.txt", 0, 2), ("b.txt", 36, 2)],
+        ] {
+            directories.extend((entries.len() as u32 - 1).to_le_bytes());
+            directories.extend([0; 8]);
+            for (name, offset, kind) in entries {
+                directories.extend(offset.to_le_bytes());
+                directories.extend([0; 2]);
+                directories.extend(kind.to_le_bytes());
+                directories.extend((name.len() as u16 - 1).to_le_bytes());
+                directories.extend(name.as_bytes());
+            }
+        }
+        assert_eq!(directories.len(), 59);
+
+        let inode_table = image.len() as u64;
+        metadata(&mut image, &inodes);
+        let directory_table = image.len() as u64;
+        metadata(&mut image, &directories);
+        let fragment_entries = image.len() as u64;
+        let mut entry = fragment_start.to_le_bytes().to_vec();
+        entry.extend((fragment.len() as u32 | UNCOMPRESSED_BLOCK).to_le_bytes());
+        entry.extend([0; 4]);
+        metadata(&mut image, &entry);
+        let fragment_table = image.len() as u64;
+        image.extend(fragment_entries.to_le_bytes());
+
+        let mut superblock = Vec::new();
+        superblock.extend(0x7371_7368u32.to_le_bytes());
+        superblock.extend(4u32.to_le_bytes());
+        superblock.extend(0u32.to_le_bytes());
+        superblock.extend(block_size.to_le_bytes());
+        superblock.extend(1u32.to_le_bytes());
+        superblock.extend(1u16.to_le_bytes());
+        superblock.extend(12u16.to_le_bytes());
+        superblock.extend([0; 4]);
+        superblock.extend(4u16.to_le_bytes());
+        superblock.extend(0u16.to_le_bytes());
+        superblock.extend(100u64.to_le_bytes());
+        superblock.extend((image.len() as u64).to_le_bytes());
+        superblock.extend([0xff; 16]);
+        for table in [inode_table, directory_table, fragment_table, u64::MAX] {
+            superblock.extend(table.to_le_bytes());
+        }
+        image[..96].copy_from_slice(&superblock);
+        image
+    }
+
+    /// Reads a whole file the way a mount does, block by block.
+    pub fn read(image: &[u8], blocks: &Blocks, size: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, part_size) in blocks.part_sizes(size).enumerate() {
+            let (block, at) = blocks.part(i);
+            let stored = (&image[..]).read_at(block.start, block.len as u64).unwrap();
+            let data = match block.compressed {
+                true => blocks.compression.decompress(&stored).unwrap(),
+                false => stored,
+            };
+            out.extend(&data[at as usize..(at + part_size) as usize]);
+        }
+        out
+    }
+
+    #[test]
+    fn members() {
+        let image = image();
+        let members = list(&mut &image[..]).unwrap();
+        let paths = members.iter().map(|m| m.path.join("/")).collect::<Vec<_>>();
+        assert_eq!(paths, ["d", "d/a.txt", "d/b.txt"]);
+        assert_eq!(members[0].kind, MemberKind::Directory);
+        assert_eq!(members[2].mode, Some(0o755));
+        for (member, contents) in members[1..].iter().zip([A, B]) {
+            let MemberKind::File {
+                size,
+                data: Data::Blocks(blocks),
+            } = &member.kind
+            else {
+                panic!("Unexpected {:?}", member);
+            };
+            assert_eq!(read(&image, blocks, *size), contents);
+        }
+        assert!(list(&mut &image[..50]).is_err());
+    }
+}
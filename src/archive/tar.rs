@@ -0,0 +1,243 @@
+//! The tar index: a 512 byte header in front of every member. Headers are
+//! read one after the other, skipping over each member's data.
+
+use std::collections::HashMap;
+
+use tracing::warn;
+
+use crate::Result;
+
+use super::{components, BadArchive, Data, Member, MemberKind, ReadAt};
+
+const BLOCK: u64 = 512;
+
+/// A NUL terminated string field of a header.
+fn field(header: &[u8], start: usize, len: usize) -> String {
+    let field = &header[start..start + len];
+    let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A numeric field, in octal or, with the high bit set, GNU's base-256.
+fn number(header: &[u8], start: usize, len: usize) -> Result<u64> {
+    let field = &header[start..start + len];
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold(0u64, |n, &b| (n << 8) | b as u64));
+    }
+    let digits = field
+        .iter()
+        .map(|&b| b as char)
+        .filter(|c| !matches!(c, '\0' | ' '))
+        .collect::<String>();
+    match digits.is_empty() {
+        true => Ok(0),
+        false => u64::from_str_radix(&digits, 8)
+            .map_err(|_| Box::new(BadArchive(format!("bad tar number {:?}", digits))).into()),
+    }
+}
+
+fn checksum_ok(header: &[u8]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                b' ' as u64
+            } else {
+                b as u64
+            }
+        })
+        .sum();
+    number(header, 148, 8).is_ok_and(|expected| expected == sum)
+}
+
+/// The `key=value` records of a pax extended header.
+fn pax_records(data: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+pub fn list(reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    let mut members: Vec<Member> = Vec::new();
+    let mut offset = 0;
+    // What GNU long name and pax headers say about the next member.
+    let mut long_name = None;
+    let mut pax = HashMap::new();
+    while offset + BLOCK <= reader.size() {
+        let header = reader.read_at(offset, BLOCK)?;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if header.len() < BLOCK as usize {
+            return Err(Box::new(BadArchive(format!(
+                "tar header at {} is truncated",
+                offset
+            ))));
+        }
+        if !checksum_ok(&header) {
+            return Err(Box::new(BadArchive(format!(
+                "tar header at {} has a bad checksum",
+                offset
+            ))));
+        }
+        let mut size = number(&header, 124, 12)?;
+        if let Some(pax_size) = pax.get("size").and_then(|s: &String| s.parse().ok()) {
+            size = pax_size;
+        }
+        let data_start = offset + BLOCK;
+        let kind = header[156];
+        offset = data_start + size.div_ceil(BLOCK) * BLOCK;
+        match kind {
+            b'L' => {
+                let name = reader.read_at(data_start, size)?;
+                let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                long_name = Some(String::from_utf8_lossy(&name[..end]).into_owned());
+                continue;
+            }
+            b'x' => {
+                pax = pax_records(&reader.read_at(data_start, size)?);
+                continue;
+            }
+            b'g' | b'K' => continue,
+            _ => {}
+        }
+        let name = match (pax.remove("path"), long_name.take()) {
+            (Some(path), _) | (None, Some(path)) => path,
+            (None, None) => {
+                let name = field(&header, 0, 100);
+                let prefix = match &header[257..262] == b"ustar" {
+                    true => field(&header, 345, 155),
+                    false => String::new(),
+                };
+                match prefix.is_empty() {
+                    true => name,
+                    false => format!("{}/{}", prefix, name),
+                }
+            }
+        };
+        pax.clear();
+        let path = components(&name)?;
+        if path.is_empty() {
+            continue;
+        }
+        let mode = Some(number(&header, 100, 8)? as u16 & 0o7777);
+        let kind = match kind {
+            b'0' | b'\0' | b'7' => MemberKind::File {
+                size,
+                data: Data::Range {
+                    start: data_start,
+                    len: size,
+                },
+            },
+            b'5' => MemberKind::Directory,
+            // A hard link shares the data of a member before it.
+            b'1' => {
+                let target = components(&field(&header, 157, 100))?;
+                match members.iter().rfind(|m| m.path == target) {
+                    Some(target) => target.kind.clone(),
+                    None => {
+                        warn!("Skipping {}, a link to a missing member", name);
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                warn!("Skipping {}, which is not a file or directory", name);
+                continue;
+            }
+        };
+        members.push(Member { path, mode, kind });
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::archive::{Data, MemberKind};
+
+    use super::list;
+
+    /// A tar member header, with `mode` 0644.
+    pub fn header(name: &str, kind: u8, size: u64) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    /// An archive of `(name, contents)` files, with a directory `d/` first.
+    pub fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = header("d/", b'5', 0);
+        for (name, contents) in files {
+            tar.extend(header(name, b'0', contents.len() as u64));
+            tar.extend(*contents);
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        }
+        tar.resize(tar.len() + 1024, 0);
+        tar
+    }
+
+    #[test]
+    fn members() {
+        let mut data = tar(&[("d/a.txt", b"hello"), ("./b.bin", &[1; 600])]);
+        // A GNU long name for a third member.
+        let long = format!("d/{}", "x".repeat(120));
+        let at = data.len() - 1024;
+        let mut tail = header("././@LongLink", b'L', long.len() as u64 + 1);
+        tail.extend(long.as_bytes());
+        tail.resize(1024, 0);
+        tail.extend(header("ignored", b'0', 2));
+        tail.extend([b'h', b'i']);
+        tail.resize(tail.len() + 510 + 1024, 0);
+        data.splice(at.., tail);
+
+        let members = list(&mut &data[..]).unwrap();
+        let paths = members.iter().map(|m| m.path.join("/")).collect::<Vec<_>>();
+        assert_eq!(paths, ["d", "d/a.txt", "b.bin", &long]);
+        assert_eq!(members[0].kind, MemberKind::Directory);
+        assert_eq!(
+            members[1].kind,
+            MemberKind::File {
+                size: 5,
+                data: Data::Range {
+                    start: 1024,
+                    len: 5
+                }
+            }
+        );
+        assert_eq!(members[1].mode, Some(0o644));
+        let MemberKind::File {
+            data: Data::Range { start, .. },
+            ..
+        } = members[2].kind
+        else {
+            panic!("Unexpected {:?}", members[2]);
+        };
+        assert_eq!(&data[start as usize..start as usize + 600], &[1; 600]);
+        assert!(list(&mut &b"not a tar file"[..]).unwrap().is_empty());
+        let mut corrupt = data.clone();
+        corrupt[600] = b'!';
+        assert!(list(&mut &corrupt[..]).is_err());
+    }
+}
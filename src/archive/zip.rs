@@ -0,0 +1,271 @@
+//! The zip index: the central directory at the end of the archive, found
+//! through the end of central directory record. Where a member's data
+//! starts depends on its local header, which is only read when the member
+//! is opened.
+
+use tracing::warn;
+
+use crate::{transform::Compression, Result};
+
+use super::{components, BadArchive, Data, Member, MemberKind, ReadAt};
+
+const EOCD: u32 = 0x0605_4b50;
+const EOCD64: u32 = 0x0606_4b50;
+const EOCD64_LOCATOR: u32 = 0x0706_4b50;
+const CENTRAL: u32 = 0x0201_4b50;
+const LOCAL: u32 = 0x0403_4b50;
+/// The fixed part of an end of central directory record, before its
+/// comment of up to 64 KiB.
+const EOCD_LEN: u64 = 22;
+pub const LOCAL_HEADER_LEN: u64 = 30;
+
+fn u16_at(data: &[u8], at: usize) -> u64 {
+    u16::from_le_bytes([data[at], data[at + 1]]) as u64
+}
+
+fn u32_at(data: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as u64
+}
+
+fn signature_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn bad(message: impl Into<String>) -> Box<dyn std::error::Error> {
+    Box::new(BadArchive(message.into()))
+}
+
+/// Finds the central directory, returning its offset, size and number of
+/// entries.
+fn central_directory(reader: &mut dyn ReadAt) -> Result<(u64, u64, u64)> {
+    let size = reader.size();
+    let tail_len = size.min(EOCD_LEN + u16::MAX as u64);
+    let tail = reader.read_at(size - tail_len, tail_len)?;
+    let at = (0..tail.len().saturating_sub(EOCD_LEN as usize - 1))
+        .rev()
+        .find(|&i| signature_at(&tail, i) == EOCD)
+        .ok_or_else(|| bad("no zip end of central directory record"))?;
+    let eocd = &tail[at..];
+    let (mut count, mut cd_size, mut cd_offset) =
+        (u16_at(eocd, 10), u32_at(eocd, 12), u32_at(eocd, 16));
+    // Zip64 archives point at a larger record from just before this one.
+    if at >= 20 && signature_at(&tail, at - 20) == EOCD64_LOCATOR {
+        let record_at = u64_at(&tail, at - 20 + 8);
+        let record = reader.read_at(record_at, 56)?;
+        if record.len() < 56 || signature_at(&record, 0) != EOCD64 {
+            return Err(bad("bad zip64 end of central directory record"));
+        }
+        (count, cd_size, cd_offset) = (
+            u64_at(&record, 32),
+            u64_at(&record, 40),
+            u64_at(&record, 48),
+        );
+    }
+    Ok((cd_offset, cd_size, count))
+}
+
+pub fn list(reader: &mut dyn ReadAt) -> Result<Vec<Member>> {
+    let (cd_offset, cd_size, count) = central_directory(reader)?;
+    let cd = reader.read_at(cd_offset, cd_size)?;
+    let mut members = Vec::new();
+    let mut at = 0;
+    for _ in 0..count {
+        if at + 46 > cd.len() || signature_at(&cd, at) != CENTRAL {
+            return Err(bad(format!(
+                "bad zip central directory entry at {}",
+                cd_offset + at as u64
+            )));
+        }
+        let entry = &cd[at..];
+        let made_by = entry[5];
+        let method = u16_at(entry, 10);
+        let (mut compressed, mut size) = (u32_at(entry, 20), u32_at(entry, 24));
+        let (name_len, extra_len, comment_len) = (
+            u16_at(entry, 28) as usize,
+            u16_at(entry, 30) as usize,
+            u16_at(entry, 32) as usize,
+        );
+        let attributes = u32_at(entry, 38);
+        let mut header = u32_at(entry, 42);
+        if 46 + name_len + extra_len > entry.len() {
+            return Err(bad("truncated zip central directory"));
+        }
+        let name = String::from_utf8_lossy(&entry[46..46 + name_len]).into_owned();
+        // Fields too large for 32 bits are in the zip64 extra field, in
+        // this order, for each that is saturated.
+        let mut extra = &entry[46 + name_len..46 + name_len + extra_len];
+        while extra.len() >= 4 {
+            let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+            let data = &extra[4..(4 + len).min(extra.len())];
+            if id == 1 {
+                let mut values = data.chunks_exact(8).map(|v| u64_at(v, 0));
+                for field in [&mut size, &mut compressed, &mut header] {
+                    if *field == u32::MAX as u64 {
+                        *field = values.next().unwrap_or(*field);
+                    }
+                }
+            }
+            extra = &extra[(4 + len).min(extra.len())..];
+        }
+        at += 46 + name_len + extra_len + comment_len;
+
+        let path = components(&name)?;
+        if path.is_empty() {
+            continue;
+        }
+        // Unix permissions are kept in the high half of the attributes.
+        let mode = (made_by == 3).then_some((attributes >> 16) as u16 & 0o7777);
+        let mode = mode.filter(|&mode| mode != 0);
+        if name.ends_with('/') {
+            members.push(Member {
+                path,
+                mode,
+                kind: MemberKind::Directory,
+            });
+            continue;
+        }
+        let compression = match method {
+            0 => None,
+            8 => Some(Compression::Deflate),
+            _ => {
+                warn!(
+                    "Skipping {}, compressed with unsupported method {}",
+                    name, method
+                );
+                continue;
+            }
+        };
+        members.push(Member {
+            path,
+            mode,
+            kind: MemberKind::File {
+                size,
+                data: Data::Zip {
+                    header,
+                    compressed_size: compressed,
+                    compression,
+                },
+            },
+        });
+    }
+    Ok(members)
+}
+
+/// Where a member's data starts, given its local header.
+pub fn data_start(header_offset: u64, header: &[u8]) -> Result<u64> {
+    if header.len() < LOCAL_HEADER_LEN as usize || signature_at(header, 0) != LOCAL {
+        return Err(bad(format!("no zip local header at {}", header_offset)));
+    }
+    Ok(header_offset + LOCAL_HEADER_LEN + u16_at(header, 26) + u16_at(header, 28))
+}
+
+#[cfg(all(test, feature = "archive"))]
+pub mod test {
+    use std::io::Write;
+
+    use flate2::write::DeflateEncoder;
+
+    use crate::{
+        archive::{Data, MemberKind, ReadAt},
+        transform::Compression,
+    };
+
+    use super::{data_start, list, LOCAL_HEADER_LEN};
+
+    /// A zip of `(name, contents, deflate)` members, with an `extra` field
+    /// in local headers only, as some writers add.
+    pub fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut central = Vec::new();
+        for (name, contents, deflate) in files {
+            let data = match deflate {
+                true => {
+                    let mut encoder =
+                        DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(contents).unwrap();
+                    encoder.finish().unwrap()
+                }
+                false => contents.to_vec(),
+            };
+            let method: u16 = if *deflate { 8 } else { 0 };
+            let offset = zip.len() as u32;
+            let extra = [0xca, 0xfe, 2, 0, 0, 0];
+            zip.extend(0x0403_4b50u32.to_le_bytes());
+            zip.extend([20, 0, 0, 0]);
+            zip.extend(method.to_le_bytes());
+            zip.extend([0; 8]);
+            zip.extend((data.len() as u32).to_le_bytes());
+            zip.extend((contents.len() as u32).to_le_bytes());
+            zip.extend((name.len() as u16).to_le_bytes());
+            zip.extend((extra.len() as u16).to_le_bytes());
+            zip.extend(name.as_bytes());
+            zip.extend(extra);
+            zip.extend(&data);
+
+            central.extend(0x0201_4b50u32.to_le_bytes());
+            central.extend([20, 3, 20, 0, 0, 0]);
+            central.extend(method.to_le_bytes());
+            central.extend([0; 8]);
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend((contents.len() as u32).to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend([0; 8]);
+            central.extend((0o100755u32 << 16).to_le_bytes());
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+        let cd_offset = zip.len() as u32;
+        zip.extend(&central);
+        zip.extend(0x0605_4b50u32.to_le_bytes());
+        zip.extend([0; 4]);
+        zip.extend((files.len() as u16).to_le_bytes());
+        zip.extend((files.len() as u16).to_le_bytes());
+        zip.extend((central.len() as u32).to_le_bytes());
+        zip.extend(cd_offset.to_le_bytes());
+        zip.extend(3u16.to_le_bytes());
+        zip.extend(b"hi!");
+        zip
+    }
+
+    #[test]
+    fn members() {
+        let text = b"hello hello hello hello".repeat(10);
+        let data = zip(&[
+            ("d/", b"", false),
+            ("d/a.txt", &text, true),
+            ("b.bin", b"raw", false),
+        ]);
+        let members = list(&mut &data[..]).unwrap();
+        let paths = members.iter().map(|m| m.path.join("/")).collect::<Vec<_>>();
+        assert_eq!(paths, ["d", "d/a.txt", "b.bin"]);
+        assert_eq!(members[0].kind, MemberKind::Directory);
+        assert_eq!(members[1].mode, Some(0o755));
+        let MemberKind::File {
+            size,
+            data:
+                Data::Zip {
+                    header,
+                    compressed_size,
+                    compression,
+                },
+        } = members[1].kind.clone()
+        else {
+            panic!("Unexpected {:?}", members[1]);
+        };
+        assert_eq!(
+            (size, compression),
+            (text.len() as u64, Some(Compression::Deflate))
+        );
+        assert!(compressed_size < size);
+
+        let local = (&data[..]).read_at(header, LOCAL_HEADER_LEN).unwrap();
+        let start = data_start(header, &local).unwrap() as usize;
+        let compressed = &data[start..start + compressed_size as usize];
+        assert_eq!(Compression::Deflate.decompress(compressed).unwrap(), text);
+        assert!(list(&mut &b"PK but not a zip"[..]).is_err());
+    }
+}
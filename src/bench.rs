@@ -0,0 +1,284 @@
+//! `bench`: how fast reads and lookups are, against a local origin with a
+//! fresh cache, so changes to the read and cache paths can be measured
+//! before and after, and slowdowns caught.
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use serde::Serialize;
+use serde_json::json;
+
+use lhttpfs::testing::Origin;
+
+use crate::{
+    fs::{LazyHTTPFS, OpError, RemoteTree},
+    layout, Result,
+};
+
+/// How much each sequential read asks for, as the kernel's readahead does.
+const READ: usize = 128 << 10;
+/// How much each small read asks for, and how big the small files are.
+const SMALL: usize = 4 << 10;
+const RANDOM_READS: usize = 10_000;
+const METADATA_OPS: usize = 100_000;
+
+pub fn command() -> Command {
+    Command::new("bench")
+        .about(
+            "Measure read throughput, small read latency and metadata rates against a local origin",
+        )
+        .arg(
+            Arg::new("size")
+                .long("size")
+                .value_name("MIB")
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("64")
+                .help("How big the file read through is, in MiB"),
+        )
+        .arg(
+            Arg::new("files")
+                .long("files")
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("1000")
+                .help("How many small files to read and look up"),
+        )
+        .arg(
+            Arg::new("latency")
+                .long("latency")
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .help("Delay each of the origin's answers by MS milliseconds"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print one JSON object per measurement instead of text"),
+        )
+}
+
+/// How long `ops` operations took, reading `bytes` in all, and how long
+/// the slowest half and hundredth of them took each.
+#[derive(Debug, Serialize)]
+pub struct Measured {
+    pub name: &'static str,
+    pub ops: usize,
+    pub seconds: f64,
+    pub bytes: u64,
+    pub p50_us: f64,
+    pub p99_us: f64,
+}
+
+impl Measured {
+    fn of(name: &'static str, latencies: &mut [Duration], bytes: u64, took: Duration) -> Measured {
+        latencies.sort_unstable();
+        let percentile = |q: f64| {
+            let at = ((latencies.len().max(1) - 1) as f64 * q).round() as usize;
+            latencies.get(at).map_or(0.0, |d| d.as_secs_f64() * 1e6)
+        };
+        Measured {
+            name,
+            ops: latencies.len(),
+            seconds: took.as_secs_f64(),
+            bytes,
+            p50_us: percentile(0.5),
+            p99_us: percentile(0.99),
+        }
+    }
+}
+
+/// Runs `op` `times` times, timing each, as `name`. `op` returns how many
+/// bytes it read.
+fn measure(
+    name: &'static str,
+    times: usize,
+    mut op: impl FnMut(usize) -> Result<u64>,
+) -> Result<Measured> {
+    let mut latencies = Vec::with_capacity(times);
+    let mut bytes = 0;
+    let start = Instant::now();
+    for i in 0..times {
+        let op_start = Instant::now();
+        bytes += op(i)?;
+        latencies.push(op_start.elapsed());
+    }
+    Ok(Measured::of(name, &mut latencies, bytes, start.elapsed()))
+}
+
+/// An operation on the benchmark's own tree failing, which none should.
+fn failed(error: OpError) -> Box<dyn std::error::Error> {
+    format!("A metadata operation failed: {:?}", error).into()
+}
+
+/// Offsets of small reads spread over `size` bytes, the same every run.
+fn offsets(size: u64) -> impl Iterator<Item = u64> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    std::iter::repeat_with(move || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % size.saturating_sub(SMALL as u64).max(1)
+    })
+}
+
+pub fn run(matches: &ArgMatches, out: &mut impl Write) -> Result<()> {
+    let size = *matches.get_one::<u64>("size").unwrap() << 20;
+    let files = *matches.get_one::<u64>("files").unwrap() as usize;
+    let latency = Duration::from_millis(*matches.get_one::<u64>("latency").unwrap());
+    let big = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut origin = Origin::start().with("/big", big);
+    for i in 0..files {
+        origin = origin.with(&format!("/small/{}", i), vec![i as u8; SMALL]);
+    }
+    origin.latency(latency);
+    let small = (0..files).map(|i| {
+        let url = origin.url(&format!("/small/{}", i));
+        json!({"name": i.to_string(), "url": url, "size": SMALL})
+    });
+    let layout = json!([
+        {"name": "big", "url": origin.url("/big"), "size": size},
+        {"name": "small", "contents": small.collect::<Vec<_>>()},
+    ]);
+    let layout = || layout::parse(layout.to_string().as_bytes());
+    let cache_dir = std::env::temp_dir().join(format!("lhttpfs-bench-{}", std::process::id()));
+    let measured = measurements(layout()?, layout()?, cache_dir.clone(), size, files);
+    let _ = std::fs::remove_dir_all(&cache_dir);
+    for measured in measured? {
+        if matches.get_flag("json") {
+            writeln!(out, "{}", serde_json::to_string(&measured)?)?;
+            continue;
+        }
+        let rate = match measured.bytes {
+            0 => format!("{:.0} ops/s", measured.ops as f64 / measured.seconds),
+            bytes => format!(
+                "{:.1} MiB/s",
+                bytes as f64 / (1 << 20) as f64 / measured.seconds
+            ),
+        };
+        writeln!(
+            out,
+            "{:<12} {:>7} ops in {:>8.3} s  {:>14}  p50 {:>9.1} µs  p99 {:>9.1} µs",
+            measured.name, measured.ops, measured.seconds, rate, measured.p50_us, measured.p99_us
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads through one tree of `files`, caching in `cache_dir`, from cold,
+/// and looks up and lists another.
+fn measurements(
+    files: Vec<layout::InputFile>,
+    metadata: Vec<layout::InputFile>,
+    cache_dir: PathBuf,
+    size: u64,
+    small: usize,
+) -> Result<Vec<Measured>> {
+    let tree = RemoteTree::new(
+        LazyHTTPFS::builder()
+            .cache_dir(Some(cache_dir))
+            .build(files)?,
+    );
+    let big = tree.open("/big")?;
+    let reads = size.div_ceil(READ as u64) as usize;
+    let sequential = |i: usize| Ok(big.read_at((i * READ) as u64, READ)?.len() as u64);
+    let cold = measure("cold read", reads, sequential)?;
+    let warm = measure("warm read", reads, sequential)?;
+    let mut offsets = offsets(size);
+    let random = measure("random read", RANDOM_READS, |_| {
+        let offset = offsets.next().unwrap();
+        Ok(big.read_at(offset, SMALL)?.len() as u64)
+    })?;
+    let small_files = measure("small files", small, |i| {
+        let file = tree.open(&format!("/small/{}", i))?;
+        Ok(file.read_at(0, SMALL)?.len() as u64)
+    })?;
+    let fs = LazyHTTPFS::builder().cache_dir(None).build(metadata)?;
+    let (dir, _) = fs
+        .find(lhttpfs::fs::ROOT_INO, "small".as_ref())
+        .map_err(failed)?;
+    let names = (0..small).map(|i| i.to_string()).collect::<Vec<_>>();
+    let lookup = measure("lookup", METADATA_OPS, |i| {
+        fs.find(dir.ino, names[i % small].as_ref())
+            .map_err(failed)?;
+        Ok(0)
+    })?;
+    let inodes = (names.iter())
+        .map(|name| fs.find(dir.ino, name.as_ref()).map(|(attr, _)| attr.ino))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(failed)?;
+    let getattr = measure("getattr", METADATA_OPS, |i| {
+        fs.attributes(inodes[i % small]).map_err(failed)?;
+        Ok(0)
+    })?;
+    let listings = (METADATA_OPS / small).max(1);
+    let readdir = measure("readdir", listings, |_| {
+        fs.list(dir.ino).map_err(failed)?;
+        Ok(0)
+    })?;
+    Ok(vec![
+        cold,
+        warm,
+        random,
+        small_files,
+        lookup,
+        getattr,
+        readdir,
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{command, offsets, run, Measured, SMALL};
+
+    #[test]
+    fn percentiles() {
+        let mut latencies = (1..=100)
+            .rev()
+            .map(Duration::from_micros)
+            .collect::<Vec<_>>();
+        let measured = Measured::of("x", &mut latencies, 0, Duration::from_secs(1));
+        assert_eq!(
+            (measured.ops, measured.p50_us, measured.p99_us),
+            (100, 51.0, 99.0)
+        );
+        assert!(offsets(1 << 20)
+            .take(100)
+            .all(|offset| offset < (1 << 20) - SMALL as u64));
+    }
+
+    #[test]
+    fn measures() {
+        let matches =
+            command().get_matches_from(["bench", "--size", "1", "--files", "3", "--json"]);
+        let mut out = Vec::new();
+        run(&matches, &mut out).unwrap();
+        let lines = String::from_utf8(out).unwrap();
+        let measured = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap());
+        let measured: Vec<serde_json::Value> = measured.collect();
+        let names = measured.iter().map(|m| m["name"].as_str().unwrap());
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            [
+                "cold read",
+                "warm read",
+                "random read",
+                "small files",
+                "lookup",
+                "getattr",
+                "readdir"
+            ]
+        );
+        assert_eq!(measured[0]["bytes"], 1 << 20);
+        assert_eq!(measured[3]["bytes"], 3 * SMALL);
+    }
+}
@@ -0,0 +1,598 @@
+//! Downloaded bytes kept between reads, in memory or in a cache directory,
+//! according to each file's [`CachePolicy`], the least recently used going
+//! first once there is a [`Cache::limit`]. Entries of the cache directory
+//! keep the [`Validators`] their origin gave, if any, so once stale they
+//! can be asked about rather than fetched again.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{self, FileTimes},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    fetch::Validators,
+    hooks::{self, Event},
+    layout::CachePolicy,
+    observer::{FsObserver, Observers},
+    LhttpfsError,
+};
+
+/// The bytes fetched so far, by URL.
+pub struct Cache {
+    memory: HashMap<String, Cached>,
+    /// Where `disk` entries go, `None` if there's nowhere to put them, in
+    /// which case they are kept in memory instead.
+    dir: Option<PathBuf>,
+    /// How many times [`Cache::insert`] was called, each after fetching
+    /// from the origin.
+    inserted: u64,
+    held: HashMap<String, Vec<u8>>,
+    observers: Observers,
+    /// How many bytes the cache directory may hold, and memory, each.
+    limit: Option<u64>,
+    /// How many bytes the cache directory holds, once counted for the
+    /// limit, give or take those overwritten since.
+    disk_bytes: Option<u64>,
+}
+
+struct Cached {
+    data: Vec<u8>,
+    fetched: Instant,
+    used: Instant,
+}
+
+/// Where [`Cache::lookup`] found an entry. Disk entries are read right
+/// away, memory ones are borrowed with [`Cache::memory`].
+pub enum Hit {
+    Memory,
+    Disk(Vec<u8>),
+    /// Bytes [`Cache::hold`] kept for the read that fetched them.
+    Held(Vec<u8>),
+}
+
+/// How long a file's cached bytes may be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub kind: CachePolicy,
+    /// `None` keeps them until the cache goes away.
+    pub ttl: Option<Duration>,
+}
+
+impl Cache {
+    pub fn new(dir: Option<PathBuf>) -> Cache {
+        Cache {
+            memory: HashMap::new(),
+            dir,
+            inserted: 0,
+            held: HashMap::new(),
+            observers: Observers::default(),
+            limit: None,
+            disk_bytes: None,
+        }
+    }
+
+    /// Keeps the cache directory, and what is cached in memory, each under
+    /// `bytes`, evicting the least recently used entries to make room.
+    pub fn limit(&mut self, bytes: Option<u64>) {
+        self.limit = bytes;
+        self.disk_bytes = None;
+        self.make_room(0);
+        self.make_memory_room(0);
+    }
+
+    /// Tells `observer` about every eviction, whichever mount's it was.
+    pub fn observe(&mut self, observer: Arc<dyn FsObserver>) {
+        self.observers.add(observer);
+    }
+
+    /// Reports the entry of `key` dropped, `reason` being `expired`,
+    /// `flushed` or, for those [`Cache::limit`] made room by, `lru`.
+    pub fn evicted(&self, key: &str, reason: &'static str) {
+        hooks::fire(Event::Evicted { key, reason });
+        (self.observers).each(|observer| observer.on_cache_evict(key, reason));
+    }
+
+    /// How many entries were fetched and inserted, so a caller holding the
+    /// cache can tell whether a read went to the origin.
+    pub fn inserted(&self) -> u64 {
+        self.inserted
+    }
+
+    /// `$XDG_CACHE_HOME/lhttpfs`, falling back to `~/.cache/lhttpfs`.
+    pub fn default_dir() -> Option<PathBuf> {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map(|dir| dir.join("lhttpfs"))
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let digest = Sha256::digest(key.as_bytes());
+        let mut name = String::with_capacity(64);
+        for byte in digest {
+            write!(name, "{:02x}", byte).unwrap();
+        }
+        self.dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    /// Where the [`Validators`] of `key` are kept, next to its bytes.
+    fn validators_path(&self, key: &str) -> Option<PathBuf> {
+        self.path(key).map(|path| path.with_extension("validators"))
+    }
+
+    fn on_disk(&self, policy: Policy) -> bool {
+        policy.kind == CachePolicy::Disk && self.dir.is_some()
+    }
+
+    /// Finds fresh cached bytes of `key` under `policy`, dropping stale ones.
+    pub fn lookup(&mut self, key: &str, policy: Policy) -> Option<Hit> {
+        if let Some(data) = self.held.remove(key) {
+            return Some(Hit::Held(data));
+        }
+        let fresh = |age: Duration| policy.ttl.is_none_or(|ttl| age < ttl);
+        match policy.kind {
+            CachePolicy::None => None,
+            _ if self.on_disk(policy) => {
+                let path = self.path(key).unwrap();
+                let age = fs::metadata(&path)
+                    .and_then(|m| m.modified())
+                    .map(|modified| {
+                        SystemTime::now()
+                            .duration_since(modified)
+                            .unwrap_or_default()
+                    })
+                    .ok()?;
+                if !fresh(age) {
+                    // Kept to ask the origin about, if it can be.
+                    if self.stale(key, policy).is_none() {
+                        let _ = fs::remove_file(path);
+                        self.evicted(key, "expired");
+                    }
+                    return None;
+                }
+                match fs::read(&path) {
+                    Ok(data) => {
+                        let now = FileTimes::new().set_accessed(SystemTime::now());
+                        let _ = fs::File::open(&path).and_then(|file| file.set_times(now));
+                        Some(Hit::Disk(data))
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Reading cached {} from {} failed: {}",
+                            key,
+                            path.display(),
+                            e
+                        );
+                        None
+                    }
+                }
+            }
+            _ => match self.memory.get_mut(key) {
+                Some(cached) if fresh(cached.fetched.elapsed()) => {
+                    cached.used = Instant::now();
+                    Some(Hit::Memory)
+                }
+                Some(_) => {
+                    self.memory.remove(key);
+                    self.evicted(key, "expired");
+                    None
+                }
+                None => None,
+            },
+        }
+    }
+
+    /// Whether fresh bytes of `key` are cached under `policy`, without
+    /// reading or dropping them.
+    pub fn contains(&self, key: &str, policy: Policy) -> bool {
+        let fresh = |age: Duration| policy.ttl.is_none_or(|ttl| age < ttl);
+        match policy.kind {
+            CachePolicy::None => false,
+            _ if self.on_disk(policy) => fs::metadata(self.path(key).unwrap())
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| fresh(modified.elapsed().unwrap_or_default())),
+            _ => (self.memory.get(key)).is_some_and(|cached| fresh(cached.fetched.elapsed())),
+        }
+    }
+
+    /// The [`Validators`] of the entry of `key` in the cache directory, if
+    /// it has any and is stale under `policy`, to ask its origin whether it
+    /// is still current with.
+    pub fn stale(&self, key: &str, policy: Policy) -> Option<Validators> {
+        if !self.on_disk(policy) || self.contains(key, policy) {
+            return None;
+        }
+        fs::metadata(self.path(key)?).ok()?;
+        let validators = fs::read(self.validators_path(key)?).ok()?;
+        serde_json::from_slice(&validators).ok()
+    }
+
+    /// Makes the stale entry of `key` in the cache directory fresh again,
+    /// once its origin said it is still current, returning its bytes.
+    pub fn revalidated(&mut self, key: &str) -> Option<Vec<u8>> {
+        let path = self.path(key)?;
+        let now = SystemTime::now();
+        let times = FileTimes::new().set_accessed(now).set_modified(now);
+        fs::File::open(&path)
+            .and_then(|file| file.set_times(times))
+            .ok()?;
+        fs::read(path).ok()
+    }
+
+    /// The bytes of the entry of `key` in the cache directory, stale or
+    /// not, for when its origin can't be reached to fetch them anew.
+    pub fn stale_data(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.path(key)?).ok()
+    }
+
+    /// The entries in the cache directory. Anything else that is in there
+    /// is left out, so pointing the cache at a shared directory is safe.
+    fn disk_entries(&self) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let read = match fs::read_dir(dir) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for entry in read {
+            let entry = entry?;
+            let name = entry.file_name();
+            let digest = name.to_str().map(|name| name.split('.').next().unwrap());
+            if digest.is_some_and(|d| d.len() == 64 && d.bytes().all(|b| b.is_ascii_hexdigit())) {
+                entries.push((entry.path(), entry.metadata()?));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// How many entries the cache directory holds, and how many bytes.
+    pub fn usage(&self) -> Result<(u64, u64), LhttpfsError> {
+        let entries = self.disk_entries().map_err(|e| self.error(e))?;
+        let bytes = entries.iter().map(|(_, metadata)| metadata.len()).sum();
+        Ok((entries.len() as u64, bytes))
+    }
+
+    /// How many entries are held in memory, and how many bytes.
+    pub fn memory_usage(&self) -> (u64, u64) {
+        let bytes = self.memory.values().map(|cached| cached.data.len() as u64);
+        (self.memory.len() as u64, bytes.sum())
+    }
+
+    /// Removes every entry, from memory and the cache directory, returning
+    /// how many bytes that freed.
+    pub fn clear(&mut self) -> Result<u64, LhttpfsError> {
+        let (_, mut freed) = self.memory_usage();
+        self.memory.clear();
+        self.disk_bytes = None;
+        for (path, metadata) in self.disk_entries().map_err(|e| self.error(e))? {
+            fs::remove_file(path).map_err(|e| self.error(e))?;
+            freed += metadata.len();
+        }
+        Ok(freed)
+    }
+
+    /// Drops the entry of `key`, from wherever `policy` puts it, returning
+    /// how many bytes that freed.
+    pub fn remove(&mut self, key: &str, policy: Policy) -> Result<u64, LhttpfsError> {
+        if !self.on_disk(policy) {
+            return Ok((self.memory.remove(key)).map_or(0, |cached| cached.data.len() as u64));
+        }
+        let path = self.path(key).unwrap();
+        let _ = fs::remove_file(self.validators_path(key).unwrap());
+        match fs::metadata(&path) {
+            Ok(metadata) => fs::remove_file(path).map(|()| {
+                if let Some(bytes) = &mut self.disk_bytes {
+                    *bytes = bytes.saturating_sub(metadata.len());
+                }
+                metadata.len()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| self.error(e))
+    }
+
+    fn error(&self, source: io::Error) -> LhttpfsError {
+        let dir = self.dir.clone().unwrap_or_default();
+        LhttpfsError::Cache { dir, source }
+    }
+
+    /// The bytes of `key` after [`Cache::lookup`] found them in memory.
+    pub fn memory(&self, key: &str) -> &[u8] {
+        &self.memory[key].data
+    }
+
+    /// Keeps `data` under `key` whatever the policy, for the next
+    /// [`Cache::lookup`] of it only: bytes fetched ahead of a read that
+    /// doesn't cache them.
+    pub fn hold(&mut self, key: String, data: Vec<u8>) {
+        self.held.insert(key, data);
+    }
+
+    /// Whether [`Cache::hold`] keeps bytes under `key`.
+    pub fn holds(&self, key: &str) -> bool {
+        self.held.contains_key(key)
+    }
+
+    /// Drops what [`Cache::hold`] kept under `key`, if no lookup took it.
+    pub fn release(&mut self, key: &str) {
+        self.held.remove(key);
+    }
+
+    /// Caches `data` under `key` as `policy` says, handing it back.
+    pub fn insert(&mut self, key: String, data: Vec<u8>, policy: Policy) -> Cow<'_, [u8]> {
+        self.insert_validated(key, data, policy, None)
+    }
+
+    /// [`Cache::insert`], keeping `validators` with entries of the cache
+    /// directory.
+    pub fn insert_validated(
+        &mut self,
+        key: String,
+        data: Vec<u8>,
+        policy: Policy,
+        validators: Option<Validators>,
+    ) -> Cow<'_, [u8]> {
+        self.inserted += 1;
+        match policy.kind {
+            CachePolicy::None => Cow::Owned(data),
+            _ if self.on_disk(policy) => {
+                self.make_room(data.len() as u64);
+                let path = self.path(&key).unwrap();
+                let kept = self.validators_path(&key).unwrap();
+                let validators = validators.filter(|validators| !validators.is_empty());
+                let written = write_atomically(&path, &data).and_then(|()| match &validators {
+                    Some(validators) => write_atomically(&kept, &serde_json::to_vec(validators)?),
+                    None => fs::remove_file(&kept).or_else(|e| match e.kind() {
+                        io::ErrorKind::NotFound => Ok(()),
+                        _ => Err(e),
+                    }),
+                });
+                if let Err(e) = written {
+                    warn!("Caching {} in {} failed: {}", key, path.display(), e);
+                }
+                Cow::Owned(data)
+            }
+            _ => {
+                self.make_memory_room(data.len() as u64);
+                let now = Instant::now();
+                let cached = Cached {
+                    data,
+                    fetched: now,
+                    used: now,
+                };
+                let entry = self.memory.entry(key).insert_entry(cached);
+                Cow::Borrowed(&entry.into_mut().data[..])
+            }
+        }
+    }
+}
+
+impl Cache {
+    /// Evicts the least recently used entries of the cache directory until
+    /// `needed` more bytes fit under the limit.
+    fn make_room(&mut self, needed: u64) {
+        let Some(limit) = self.limit.filter(|_| self.dir.is_some()) else {
+            return;
+        };
+        let used = match self.disk_bytes {
+            Some(used) => used,
+            None => self.usage().map_or(0, |(_, bytes)| bytes),
+        };
+        if used + needed <= limit {
+            self.disk_bytes = Some(used + needed);
+            return;
+        }
+        let mut entries = match self.disk_entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Making room in the cache directory failed: {}", e);
+                return;
+            }
+        };
+        let used_at = |metadata: &fs::Metadata| metadata.accessed().or(metadata.modified()).ok();
+        entries.sort_by_key(|(_, metadata)| used_at(metadata));
+        let mut used = entries
+            .iter()
+            .map(|(_, metadata)| metadata.len())
+            .sum::<u64>();
+        for (path, metadata) in entries {
+            if used + needed <= limit {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                used -= metadata.len();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                self.evicted(&name, "lru");
+            }
+        }
+        self.disk_bytes = Some(used + needed);
+    }
+
+    /// Evicts the least recently used entries held in memory until `needed`
+    /// more bytes fit under the limit.
+    fn make_memory_room(&mut self, needed: u64) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        let (_, mut used) = self.memory_usage();
+        if used + needed <= limit {
+            return;
+        }
+        let mut entries = (self.memory.iter())
+            .map(|(key, cached)| (cached.used, key.clone()))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        for (_, key) in entries {
+            if used + needed <= limit {
+                break;
+            }
+            if let Some(cached) = self.memory.remove(&key) {
+                used -= cached.data.len() as u64;
+                self.evicted(&key, "lru");
+            }
+        }
+    }
+}
+
+/// Writes through a temporary file so a crash never leaves a truncated
+/// entry behind for the next mount to serve.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use crate::{fetch::Validators, layout::CachePolicy};
+
+    use super::{Cache, Hit, Policy};
+
+    #[test]
+    fn memory_ttl() {
+        let mut cache = Cache::new(None);
+        let forever = Policy {
+            kind: CachePolicy::Memory,
+            ttl: None,
+        };
+        let expired = Policy {
+            ttl: Some(Duration::ZERO),
+            ..forever
+        };
+        assert!(cache.lookup("a", forever).is_none());
+        assert!(!cache.contains("a", forever));
+        assert_eq!(
+            &cache.insert("a".into(), b"abc".to_vec(), forever)[..],
+            b"abc"
+        );
+        assert!(matches!(cache.lookup("a", forever), Some(Hit::Memory)));
+        assert_eq!(cache.memory("a"), b"abc");
+        assert!(cache.contains("a", forever) && !cache.contains("a", expired));
+        assert!(cache.lookup("a", expired).is_none());
+        assert!(cache.lookup("a", forever).is_none());
+
+        let none = Policy {
+            kind: CachePolicy::None,
+            ttl: None,
+        };
+        cache.insert("b".into(), b"abc".to_vec(), none);
+        assert!(cache.lookup("b", forever).is_none());
+        assert_eq!(cache.inserted(), 2);
+        cache.hold("b".into(), b"abc".to_vec());
+        assert!(matches!(cache.lookup("b", none), Some(Hit::Held(data)) if data == b"abc"));
+        assert!(cache.lookup("b", none).is_none());
+        cache.hold("b".into(), b"abc".to_vec());
+        assert!(cache.holds("b"));
+        cache.release("b");
+        assert!(cache.lookup("b", none).is_none());
+    }
+
+    #[test]
+    fn disk() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-cache-test-{}", std::process::id()));
+        let policy = Policy {
+            kind: CachePolicy::Disk,
+            ttl: None,
+        };
+        let mut cache = Cache::new(Some(dir.clone()));
+        cache.insert("https://example.com/a".into(), b"abc".to_vec(), policy);
+
+        // A fresh cache over the same directory, as after a remount.
+        let mut cache = Cache::new(Some(dir.clone()));
+        let Some(Hit::Disk(data)) = cache.lookup("https://example.com/a", policy) else {
+            panic!("Expected a disk hit");
+        };
+        assert_eq!(data, b"abc");
+        assert!(cache.memory.is_empty());
+        std::fs::write(dir.join("README"), b"mine").unwrap();
+        cache.insert("https://example.com/b".into(), b"de".to_vec(), policy);
+        assert_eq!(cache.remove("https://example.com/b", policy).unwrap(), 2);
+        assert_eq!(cache.remove("https://example.com/b", policy).unwrap(), 0);
+        assert_eq!(cache.usage().unwrap(), (1, 3));
+        assert_eq!(cache.clear().unwrap(), 3);
+        assert_eq!(cache.usage().unwrap(), (0, 0));
+        assert!(dir.join("README").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn limited() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-cache-limit-{}", std::process::id()));
+        let disk = Policy {
+            kind: CachePolicy::Disk,
+            ttl: None,
+        };
+        let memory = Policy {
+            kind: CachePolicy::Memory,
+            ..disk
+        };
+        let mut cache = Cache::new(Some(dir.clone()));
+        cache.limit(Some(5));
+        for (key, policy) in [("a", disk), ("b", disk), ("x", memory), ("y", memory)] {
+            cache.insert(
+                key.into(),
+                key.repeat(2 + (key < "b") as usize).into(),
+                policy,
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Used since, so what was put in after it goes first.
+        assert!(cache.lookup("a", disk).is_some() && cache.lookup("x", memory).is_some());
+        thread::sleep(Duration::from_millis(10));
+        cache.insert("c".into(), b"cc".to_vec(), disk);
+        cache.insert("z".into(), b"zz".to_vec(), memory);
+        let kept = ["a", "b", "c", "x", "y", "z"]
+            .map(|key| cache.contains(key, disk) || cache.contains(key, memory));
+        assert_eq!(kept, [true, false, true, true, false, true]);
+        assert_eq!(cache.usage().unwrap(), (2, 5));
+        // Too big to keep anything else.
+        cache.insert("d".into(), b"dddddd".to_vec(), disk);
+        assert!(!cache.contains("a", disk) && !cache.contains("c", disk));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn validated() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-cache-valid-{}", std::process::id()));
+        let stale = Policy {
+            kind: CachePolicy::Disk,
+            ttl: Some(Duration::ZERO),
+        };
+        let validators = Validators {
+            etag: Some("\"1\"".into()),
+            last_modified: None,
+        };
+        let mut cache = Cache::new(Some(dir.clone()));
+        cache.insert_validated("a".into(), b"abc".to_vec(), stale, Some(validators.clone()));
+        assert_eq!(cache.stale("a", stale), Some(validators));
+        // Kept to ask about, rather than dropped.
+        assert!(cache.lookup("a", stale).is_none());
+        assert_eq!(cache.stale_data("a").unwrap(), b"abc");
+        assert_eq!(cache.revalidated("a").unwrap(), b"abc");
+        assert_eq!(cache.usage().unwrap().0, 2);
+        // Fetched again without any, what was had is forgotten.
+        cache.insert("a".into(), b"abd".to_vec(), stale);
+        assert_eq!(cache.stale("a", stale), None);
+        assert!(cache.lookup("a", stale).is_none());
+        assert_eq!(cache.usage().unwrap(), (0, 0));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! `check`: compare a layout against what its URLs actually serve, to catch
+//! bit-rot in published catalogs.
+
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use serde::Serialize;
+
+use crate::{
+    fs::{Head, LazyHTTPFS, RemotePart},
+    inspect, Result,
+};
+
+pub fn command() -> Command {
+    Command::new("check")
+        .visible_alias("verify")
+        .about("Issue a HEAD request for every URL in a layout and report what doesn't match")
+        .args(inspect::load_args())
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .short('j')
+                .value_parser(value_parser!(usize))
+                .default_value("8")
+                .help("How many requests to have in flight at once"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print one JSON object per line instead of text"),
+        )
+        .arg(
+            Arg::new("all")
+                .long("all")
+                .action(ArgAction::SetTrue)
+                .help("Also report URLs that are fine"),
+        )
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum Status {
+    Ok,
+    Missing { http_status: u32 },
+    HttpError { http_status: u32 },
+    SizeMismatch { expected: u64, actual: u64 },
+    TooShort { needed: u64, actual: u64 },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub path: String,
+    pub url: String,
+    #[serde(flatten)]
+    pub status: Status,
+    /// Where the URL redirected to. Not a problem in itself, but worth
+    /// knowing since the layout could point there directly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<String>,
+}
+
+fn classify(part: &RemotePart, head: &Head) -> Status {
+    match (head.status, head.size) {
+        (404 | 410, _) => Status::Missing {
+            http_status: head.status,
+        },
+        (400.., _) => Status::HttpError {
+            http_status: head.status,
+        },
+        (_, Some(actual)) => match part.size {
+            Some(expected) if expected != actual => Status::SizeMismatch { expected, actual },
+            _ if actual < part.min_size => Status::TooShort {
+                needed: part.min_size,
+                actual,
+            },
+            _ => Status::Ok,
+        },
+        (_, None) => Status::Ok,
+    }
+}
+
+/// Checks every URL of `fs`, writing reports as they come in. Returns whether
+/// any URL had a problem.
+pub fn run(fs: &LazyHTTPFS, matches: &ArgMatches, out: &mut (impl Write + Send)) -> Result<bool> {
+    let mut parts = Vec::new();
+    let mut parents: Vec<String> = Vec::new();
+    for entry in fs.walk() {
+        parents.truncate(entry.depth);
+        let path = match parents.last() {
+            Some(parent) => format!("{}/{}", parent, entry.name.to_string_lossy()),
+            None => String::new(),
+        };
+        if entry.is_dir() {
+            parents.push(path);
+            continue;
+        }
+        for part in fs.remote_parts(entry.attr.ino) {
+            parts.push((path.clone(), entry.attr.ino, part));
+        }
+    }
+
+    let json = matches.get_flag("json");
+    let all = matches.get_flag("all");
+    let jobs = (*matches.get_one::<usize>("jobs").unwrap()).max(1);
+    let next = Mutex::new(parts.into_iter());
+    let out = Mutex::new(out);
+    let problems = Mutex::new(false);
+    std::thread::scope(|scope| -> io::Result<()> {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    loop {
+                        let Some((path, ino, part)) = next.lock().unwrap().next() else {
+                            return Ok(());
+                        };
+                        let (status, redirect) = match fs.head(ino, &part.url) {
+                            Ok(head) => (classify(&part, &head), head.redirect),
+                            Err(e) => (
+                                Status::Error {
+                                    message: e.to_string(),
+                                },
+                                None,
+                            ),
+                        };
+                        if status != Status::Ok {
+                            *problems.lock().unwrap() = true;
+                        } else if !all {
+                            continue;
+                        }
+                        let report = Report {
+                            path,
+                            url: part.url,
+                            status,
+                            redirect,
+                        };
+                        print_report(&mut **out.lock().unwrap(), &report, json)?;
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+    let problems = *problems.lock().unwrap();
+    Ok(problems)
+}
+
+fn print_report(out: &mut impl Write, report: &Report, json: bool) -> io::Result<()> {
+    if json {
+        serde_json::to_writer(&mut *out, report)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    let status = match &report.status {
+        Status::Ok => "ok".to_owned(),
+        Status::Missing { http_status } => format!("missing (HTTP {})", http_status),
+        Status::HttpError { http_status } => format!("HTTP {}", http_status),
+        Status::SizeMismatch { expected, actual } => {
+            format!("size is {}, expected {}", actual, expected)
+        }
+        Status::TooShort { needed, actual } => {
+            format!("size is {}, needs at least {}", actual, needed)
+        }
+        Status::Error { message } => message.clone(),
+    };
+    write!(out, "{}: {} ({})", report.path, status, report.url)?;
+    match &report.redirect {
+        Some(redirect) => writeln!(out, " -> {}", redirect)?,
+        None => writeln!(out)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fs::{Head, RemotePart};
+
+    use super::{classify, Status};
+
+    #[test]
+    fn classification() {
+        let part = RemotePart {
+            url: "https://example.com/a".into(),
+            size: Some(10),
+            min_size: 10,
+        };
+        let head = |status, size| Head {
+            status,
+            size,
+            redirect: None,
+        };
+        assert_eq!(classify(&part, &head(200, Some(10))), Status::Ok);
+        assert_eq!(classify(&part, &head(200, None)), Status::Ok);
+        assert_eq!(
+            classify(&part, &head(200, Some(9))),
+            Status::SizeMismatch {
+                expected: 10,
+                actual: 9
+            }
+        );
+        assert_eq!(
+            classify(&part, &head(404, None)),
+            Status::Missing { http_status: 404 }
+        );
+        assert_eq!(
+            classify(&part, &head(503, None)),
+            Status::HttpError { http_status: 503 }
+        );
+        let range = RemotePart {
+            size: None,
+            min_size: 20,
+            ..part
+        };
+        assert_eq!(classify(&range, &head(200, Some(100))), Status::Ok);
+        assert_eq!(
+            classify(&range, &head(200, Some(19))),
+            Status::TooShort {
+                needed: 20,
+                actual: 19
+            }
+        );
+    }
+}
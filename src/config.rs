@@ -0,0 +1,252 @@
+//! `--config`: a TOML file holding settings for any of the long options,
+//! plus `[defaults]` for every file in the layout, and the `LHTTPFS_*`
+//! environment variables that do the same.
+
+use std::{collections::HashMap, error::Error, fmt::Display, path::PathBuf};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use serde::Deserialize;
+
+use crate::{layout::Defaults, Result};
+
+pub fn arg() -> Arg {
+    Arg::new("config")
+        .long("config")
+        .global(true)
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .help("Read settings from a TOML file [default: ~/.config/lhttpfs/lhttpfs.toml]")
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// What files inherit where neither they nor their directories say
+    /// otherwise.
+    pub defaults: Defaults,
+    /// The values of options given by their long names, or for positional
+    /// arguments their names in kebab case.
+    options: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(flatten)]
+    options: toml::Table,
+}
+
+#[derive(Debug)]
+pub struct UnknownSetting(String);
+
+impl Display for UnknownSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The configuration file sets `{}`, which is not an option",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownSetting {}
+
+#[derive(Debug)]
+pub struct SettingType(String);
+
+impl Display for SettingType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` must be a string, number, boolean or an array of them",
+            self.0
+        )
+    }
+}
+
+impl Error for SettingType {}
+
+/// `$XDG_CONFIG_HOME/lhttpfs/lhttpfs.toml`, falling back to
+/// `~/.config/lhttpfs/lhttpfs.toml`.
+fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("lhttpfs").join("lhttpfs.toml"))
+}
+
+/// Reads the file given with `--config`, or the one at the default path if
+/// there is one there.
+pub fn load(matches: &ArgMatches) -> Result<Config> {
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None => match default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        },
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    parse(&text)
+}
+
+fn parse(text: &str) -> Result<Config> {
+    let file: ConfigFile = toml::from_str(text)?;
+    let mut options = HashMap::new();
+    for (key, value) in file.options {
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        let values = values
+            .into_iter()
+            .map(|value| match value {
+                toml::Value::String(s) => Ok(s),
+                toml::Value::Integer(n) => Ok(n.to_string()),
+                toml::Value::Float(n) => Ok(n.to_string()),
+                toml::Value::Boolean(b) => Ok(b.to_string()),
+                _ => Err(SettingType(key.clone())),
+            })
+            .collect::<core::result::Result<_, _>>()?;
+        options.insert(key, values);
+    }
+    Ok(Config {
+        defaults: file.defaults,
+        options,
+    })
+}
+
+/// The name an argument is set by in the configuration file.
+fn key(arg: &Arg) -> String {
+    match arg.get_long() {
+        Some(long) => long.to_string(),
+        None => arg.get_id().as_str().to_lowercase().replace('_', "-"),
+    }
+}
+
+/// Lets every argument of `command` and its subcommands be given as
+/// `LHTTPFS_<KEY>`, the configuration file key in upper snake case, unless
+/// it has a variable of its own already. The command line still wins, and
+/// the variables win over the configuration file.
+pub fn with_env(command: Command) -> Command {
+    command
+        .mut_args(|arg| match arg.get_env() {
+            Some(_) => arg,
+            None => {
+                let var = format!("LHTTPFS_{}", key(&arg).to_uppercase().replace('-', "_"));
+                arg.env(var)
+            }
+        })
+        .mut_subcommands(with_env)
+}
+
+impl Config {
+    /// Makes the settings the defaults of the matching arguments of
+    /// `command` and its subcommands, so the command line still overrides
+    /// them.
+    pub fn apply(&self, command: Command) -> Result<Command> {
+        let commands = std::iter::once(&command).chain(command.get_subcommands());
+        let known = commands
+            .flat_map(Command::get_arguments)
+            .map(key)
+            .collect::<Vec<_>>();
+        if let Some(key) = self.options.keys().find(|key| !known.contains(key)) {
+            return Err(Box::new(UnknownSetting(key.clone())));
+        }
+        let set = |arg: Arg| match self.options.get(&key(&arg)) {
+            Some(values) => arg.required(false).default_values(values.clone()),
+            None => arg,
+        };
+        Ok(command.mut_args(set).mut_subcommands(|c| c.mut_args(set)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{Arg, ArgAction, Command};
+
+    use super::{parse, with_env};
+    use crate::layout::CachePolicy;
+
+    fn command() -> Command {
+        Command::new("lhttpfs")
+            .arg(Arg::new("MOUNT_POINT").required(true))
+            .arg(
+                Arg::new("include")
+                    .long("include")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("allow-root")
+                    .long("allow-root")
+                    .action(ArgAction::SetTrue),
+            )
+            .subcommand(Command::new("check").arg(Arg::new("cache-dir").long("cache-dir")))
+    }
+
+    #[test]
+    fn settings() {
+        let config = parse(
+            r#"
+            mount-point = "/mnt"
+            include = ["*.bin", "*.txt"]
+            allow-root = true
+            cache-dir = "/var/cache"
+
+            [defaults]
+            cache = "disk"
+            ttl = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.defaults.cache, Some(CachePolicy::Disk));
+        assert_eq!(config.defaults.ttl, Some(60));
+        let matches = config
+            .apply(command())
+            .unwrap()
+            .get_matches_from(["lhttpfs"]);
+        assert_eq!(matches.get_one::<String>("MOUNT_POINT").unwrap(), "/mnt");
+        let include: Vec<_> = matches.get_many::<String>("include").unwrap().collect();
+        assert_eq!(include, ["*.bin", "*.txt"]);
+        assert!(matches.get_flag("allow-root"));
+        let matches = (config.apply(command()).unwrap()).get_matches_from([
+            "lhttpfs",
+            "/other",
+            "--include",
+            "*.csv",
+        ]);
+        assert_eq!(matches.get_one::<String>("MOUNT_POINT").unwrap(), "/other");
+        let include: Vec<_> = matches.get_many::<String>("include").unwrap().collect();
+        assert_eq!(include, ["*.csv"]);
+        let matches = (config.apply(command()).unwrap()).get_matches_from(["lhttpfs", "check"]);
+        let (_, check) = matches.subcommand().unwrap();
+        assert_eq!(check.get_one::<String>("cache-dir").unwrap(), "/var/cache");
+    }
+
+    #[test]
+    fn environment() {
+        let command = || {
+            with_env(
+                (command().arg(Arg::new("env-test").long("env-test")))
+                    .subcommand(Command::new("sub").arg(Arg::new("env-test").long("env-test")))
+                    .subcommand_negates_reqs(true),
+            )
+        };
+        std::env::set_var("LHTTPFS_ENV_TEST", "from-env");
+        let matches = command().get_matches_from(["lhttpfs", "/mnt"]);
+        assert_eq!(matches.get_one::<String>("env-test").unwrap(), "from-env");
+        let matches = command().get_matches_from(["lhttpfs", "sub"]);
+        let (_, sub) = matches.subcommand().unwrap();
+        assert_eq!(sub.get_one::<String>("env-test").unwrap(), "from-env");
+        let matches = command().get_matches_from(["lhttpfs", "/mnt", "--env-test", "given"]);
+        assert_eq!(matches.get_one::<String>("env-test").unwrap(), "given");
+        std::env::remove_var("LHTTPFS_ENV_TEST");
+    }
+
+    #[test]
+    fn unknown() {
+        let config = parse("mount_point = \"/mnt\"").unwrap();
+        assert!(config.apply(command()).is_err());
+        assert!(parse("include = [{ a = 1 }]").is_err());
+    }
+}
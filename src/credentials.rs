@@ -0,0 +1,145 @@
+//! `--header`, `--bearer-token-file` and `--netrc`: credentials for every
+//! file of a mount given on the command line, or through `LHTTPFS_*`, so
+//! they never have to be written into a layout. And `--keyring`, which
+//! hosts the `keyring:` references a layout does hold may be sent to.
+
+use std::path::PathBuf;
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches};
+
+use crate::{
+    fetch::{HostPolicy, Netrc},
+    keyring::{self, Keyring},
+    layout::Auth,
+    layout::Defaults,
+    Result,
+};
+
+pub fn args() -> [Arg; 4] {
+    [
+        Arg::new("header")
+            .long("header")
+            .global(true)
+            .value_name("NAME: VALUE")
+            .action(ArgAction::Append)
+            .value_parser(parse_header)
+            .help("Send this header with every request the layout doesn't set it for itself"),
+        Arg::new("bearer-token-file")
+            .long("bearer-token-file")
+            .global(true)
+            .value_name("FILE")
+            .value_parser(value_parser!(PathBuf))
+            .help("Authorize with the bearer token in FILE where the layout sets no auth of its own"),
+        Arg::new("netrc")
+            .long("netrc")
+            .global(true)
+            .value_name("FILE")
+            .num_args(0..=1)
+            .default_missing_value("")
+            .value_parser(value_parser!(PathBuf))
+            .help("Send the basic auth FILE has for a host where the layout sets no auth [default: ~/.netrc]"),
+        Arg::new("keyring")
+            .long("keyring")
+            .global(true)
+            .value_name("SERVICE/ACCOUNT=HOSTS")
+            .action(ArgAction::Append)
+            .value_parser(keyring::parse_grant)
+            .help("Look up keyring:SERVICE/ACCOUNT for files on HOSTS, a comma-separated list of host patterns"),
+    ]
+}
+
+/// Splits a `--header` value into its name and value.
+fn parse_header(header: &str) -> std::result::Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("Expected NAME: VALUE, got {:?}", header)),
+    }
+}
+
+/// `defaults`, with the headers and token given on the command line.
+pub fn defaults(matches: &ArgMatches, mut defaults: Defaults) -> Result<Defaults> {
+    let headers = matches.get_many::<(String, String)>("header");
+    defaults
+        .headers
+        .extend(headers.into_iter().flatten().cloned());
+    if let Some(path) = matches.get_one::<PathBuf>("bearer-token-file") {
+        let token = std::fs::read_to_string(path)
+            .map_err(|e| format!("Reading the token in {} failed: {}", path.display(), e))?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(format!("{} holds no token", path.display()).into());
+        }
+        defaults.auth = Some(Auth::Bearer(token.to_string()));
+    }
+    Ok(defaults)
+}
+
+/// The `.netrc` given with `--netrc`, read.
+pub fn netrc(matches: &ArgMatches) -> Result<Option<Netrc>> {
+    let Some(path) = matches.get_one::<PathBuf>("netrc") else {
+        return Ok(None);
+    };
+    let path = match path.as_os_str().is_empty() {
+        true => Netrc::default_path().ok_or("There is no ~/.netrc without $HOME")?,
+        false => path.clone(),
+    };
+    Netrc::read(&path).map(Some)
+}
+
+/// The `keyring:` references granted with `--keyring`.
+pub fn keyring(matches: &ArgMatches) -> Keyring {
+    let mut keyring = Keyring::default();
+    let grants = matches.get_many::<(String, HostPolicy)>("keyring");
+    for (reference, hosts) in grants.into_iter().flatten() {
+        keyring.grant(reference, hosts.clone());
+    }
+    keyring
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Command;
+
+    use crate::layout::{Auth, Defaults};
+
+    use super::{args, defaults, netrc};
+
+    #[test]
+    fn credentials() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-credentials-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("token"), "t0ken\n").unwrap();
+        std::fs::write(dir.join("netrc"), "machine example.com login u password p").unwrap();
+        let command = || Command::new("lhttpfs").args(args());
+        let matches = command().get_matches_from([
+            "lhttpfs".into(),
+            "--header=X-Team: data".into(),
+            "--header".into(),
+            "Accept:*/*".into(),
+            format!("--bearer-token-file={}", dir.join("token").display()),
+            format!("--netrc={}", dir.join("netrc").display()),
+        ]);
+        let given = defaults(&matches, Defaults::default()).unwrap();
+        assert_eq!(given.headers["X-Team"], "data");
+        assert_eq!(given.headers["Accept"], "*/*");
+        assert_eq!(given.auth, Some(Auth::Bearer("t0ken".into())));
+        // Over what the configuration file sets.
+        let configured = Defaults {
+            auth: Some(Auth::Bearer("configured".into())),
+            ..Defaults::default()
+        };
+        let given = defaults(&matches, configured).unwrap();
+        assert_eq!(given.auth, Some(Auth::Bearer("t0ken".into())));
+        let netrc = netrc(&matches).unwrap().unwrap();
+        assert!(netrc.auth("https://example.com/a").is_some());
+        assert!(command()
+            .try_get_matches_from(["lhttpfs", "--header", "no colon"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(["lhttpfs", "--keyring", "example.com/ci"])
+            .is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+//! `--control-socket` and `ctl`: a Unix socket that a running mount answers
+//! requests on, a line of JSON each way, and the client that sends them, to
+//! script against a mount without going through its files by hand. The
+//! server acts through each mount's `.lhttpfs/` control files, which the
+//! thread owning the tree serves.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    fs::{CONTROL, MANIFEST},
+    Result,
+};
+
+/// What a client asks of a mount.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    /// The mount points the socket serves.
+    Mounts,
+    /// Every file of the mount, as its manifest lists them.
+    List,
+    Stats,
+    /// Drops the cached bytes of what is under `path`, or of everything.
+    Flush {
+        path: Option<String>,
+    },
+    Reload,
+    /// Adds the layout entries to the tree.
+    Add {
+        entries: Value,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Message {
+    /// Which mount the request is for, the first one if left out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mount: Option<PathBuf>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+pub fn arg() -> Arg {
+    Arg::new("control-socket")
+        .long("control-socket")
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .help("Answer `lhttpfs ctl` requests on a Unix socket at FILE")
+}
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The listening socket, removed again when dropped.
+pub struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Socket {
+    /// Listens at `path`, taking the place of a socket nothing listens on
+    /// any more. Only the user running the mount may connect.
+    pub fn bind(path: &Path) -> io::Result<Socket> {
+        let path = std::path::absolute(path)?;
+        let stale = fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket())
+            && UnixStream::connect(&path).is_err();
+        if stale {
+            fs::remove_file(&path)?;
+        }
+        // Created 0600 rather than changed to it, so that there is no
+        // moment anyone else could connect.
+        // SAFETY: no preconditions.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&path);
+        // SAFETY: as above.
+        unsafe { libc::umask(umask) };
+        Ok(Socket {
+            listener: listener?,
+            path,
+        })
+    }
+
+    /// Answers requests for `mounts`, each connection on a thread of its
+    /// own, giving up on one that sends nothing for [`READ_TIMEOUT`].
+    pub fn serve(&self, mounts: Vec<PathBuf>) -> io::Result<()> {
+        let listener = self.listener.try_clone()?;
+        std::thread::spawn(move || {
+            let mounts = Arc::new(mounts);
+            for stream in listener.incoming() {
+                let mounts = mounts.clone();
+                let answered = stream.and_then(|stream| {
+                    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                    std::thread::spawn(move || {
+                        if let Err(e) = respond(stream, &mounts) {
+                            warn!("Answering on the control socket failed: {}", e);
+                        }
+                    });
+                    Ok(())
+                });
+                if let Err(e) = answered {
+                    warn!("Answering on the control socket failed: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn respond(stream: UnixStream, mounts: &[PathBuf]) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str(&line) {
+        Ok(Message { mount, request }) => match handle(mount.as_deref(), request, mounts) {
+            Ok(result) => Response::Ok(result),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Err(e) => Response::Error(format!("Bad request: {}", e)),
+    };
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(b'\n');
+    (&stream).write_all(&response)
+}
+
+/// Does what `request` asks of `mount`, one of `mounts` or the first of
+/// them, returning the reply.
+pub fn handle(mount: Option<&Path>, request: Request, mounts: &[PathBuf]) -> Result<Value> {
+    let mount = match mount {
+        Some(mount) => {
+            let mount = std::path::absolute(mount)?;
+            (mounts.iter().find(|m| **m == mount))
+                .ok_or_else(|| format!("{} isn't served here", mount.display()))?
+        }
+        None => mounts.first().ok_or("Nothing is mounted")?,
+    };
+    let control = mount.join(CONTROL);
+    let read = |path: &Path| -> Result<Value> { Ok(serde_json::from_slice(&fs::read(path)?)?) };
+    let write = |name: &str, data: &[u8]| -> Result<Value> {
+        match fs::write(control.join(name), data) {
+            Ok(()) => Ok(Value::Null),
+            // The write only fails with EIO, and stats says why.
+            Err(e) => {
+                let stats = read(&control.join("stats")).unwrap_or_default();
+                match stats["last_error"].as_str() {
+                    Some(error) => Err(error.into()),
+                    None => Err(e.into()),
+                }
+            }
+        }
+    };
+    match request {
+        Request::Mounts => Ok(serde_json::to_value(mounts)?),
+        Request::List => read(&mount.join(MANIFEST)),
+        Request::Stats => read(&control.join("stats")),
+        Request::Flush { path: Some(path) } => write(
+            "flush",
+            format!("/{}", path.trim_start_matches('/')).as_bytes(),
+        ),
+        Request::Flush { path: None } => write("flush", b"all"),
+        Request::Reload => write("reload", b"1\n"),
+        Request::Add { entries } => write("add", &serde_json::to_vec(&entries)?),
+    }
+}
+
+pub fn command() -> Command {
+    Command::new("ctl")
+        .about("Ask a mount started with --control-socket to do something, printing the reply")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .required(true)
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("The mount's --control-socket"),
+        )
+        .arg(
+            Arg::new("mount-point")
+                .long("mount-point")
+                .value_name("DIR")
+                .value_parser(value_parser!(PathBuf))
+                .help("Which of the process's mounts to ask [default: the first]"),
+        )
+        .subcommand(Command::new("mounts").about("List the mount points"))
+        .subcommand(Command::new("list").about("List the files, as the manifest does"))
+        .subcommand(Command::new("stats").about("Print what .lhttpfs/stats says"))
+        .subcommand(
+            Command::new("flush")
+                .about("Drop cached bytes of a file or directory, or of everything")
+                .arg(Arg::new("flush-path").value_name("PATH")),
+        )
+        .subcommand(Command::new("reload").about("Load the layouts again"))
+        .subcommand(
+            Command::new("add")
+                .about("Add the entries of a layout file to the tree")
+                .arg(Arg::new("entries").required(true).value_name("LAYOUT")),
+        )
+}
+
+pub fn run(matches: &ArgMatches, out: &mut impl Write) -> Result<()> {
+    let request = match matches.subcommand().unwrap() {
+        ("mounts", _) => Request::Mounts,
+        ("list", _) => Request::List,
+        ("stats", _) => Request::Stats,
+        ("flush", matches) => Request::Flush {
+            path: matches.get_one::<String>("flush-path").cloned(),
+        },
+        ("reload", _) => Request::Reload,
+        (_, matches) => {
+            let path = matches.get_one::<String>("entries").unwrap();
+            let entries = serde_json::from_slice(&fs::read(path)?)
+                .map_err(|e| format!("{} isn't a JSON layout: {}", path, e))?;
+            Request::Add { entries }
+        }
+    };
+    let message = Message {
+        mount: matches.get_one::<PathBuf>("mount-point").cloned(),
+        request,
+    };
+    let socket = matches.get_one::<PathBuf>("socket").unwrap();
+    match send(socket, &message)? {
+        Value::Null => {}
+        result => writeln!(out, "{}", serde_json::to_string_pretty(&result)?)?,
+    }
+    Ok(())
+}
+
+/// Sends `message` to the socket at `path` and waits for the reply.
+fn send(path: &Path, message: &Message) -> Result<Value> {
+    let stream = UnixStream::connect(path)
+        .map_err(|e| format!("Couldn't connect to {}: {}", path.display(), e))?;
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    (&stream).write_all(&line)?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    match serde_json::from_str(&reply)? {
+        Response::Ok(result) => Ok(result),
+        Response::Error(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        path::PathBuf,
+    };
+
+    use serde_json::json;
+
+    use super::{send, Message, Request, Socket};
+    use crate::fs::{CONTROL, MANIFEST};
+
+    #[test]
+    fn requests() {
+        let message = |mount: Option<&str>, request| Message {
+            mount: mount.map(PathBuf::from),
+            request,
+        };
+        let flush = message(None, Request::Flush { path: None });
+        assert_eq!(
+            serde_json::to_value(&flush).unwrap(),
+            json!({"command": "flush", "path": null})
+        );
+        let parsed: Message =
+            serde_json::from_str(r#"{"mount": "/mnt", "command": "stats"}"#).unwrap();
+        assert_eq!(parsed, message(Some("/mnt"), Request::Stats));
+    }
+
+    /// The server against a directory laid out like a mount, since it only
+    /// goes through the files.
+    #[test]
+    fn served() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-ctl-{}", std::process::id()));
+        let mount = dir.join("mnt");
+        fs::create_dir_all(mount.join(CONTROL)).unwrap();
+        fs::write(mount.join(MANIFEST), r#"[{"path": "/a"}]"#).unwrap();
+        fs::write(mount.join(CONTROL).join("stats"), r#"{"files": 1}"#).unwrap();
+        let socket = Socket::bind(&dir.join("socket")).unwrap();
+        let mode = fs::metadata(dir.join("socket"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        socket.serve(vec![mount.clone()]).unwrap();
+        // A client that says nothing holds up no other.
+        let _idle = UnixStream::connect(dir.join("socket")).unwrap();
+        let ask = |mount: Option<&PathBuf>, request| {
+            let mount = mount.cloned();
+            send(&dir.join("socket"), &Message { mount, request })
+        };
+        assert_eq!(ask(None, Request::Stats).unwrap(), json!({"files": 1}));
+        assert_eq!(ask(Some(&mount), Request::List).unwrap()[0]["path"], "/a");
+        assert_eq!(ask(None, Request::Mounts).unwrap(), json!([mount]));
+        ask(None, Request::Reload).unwrap();
+        assert_eq!(
+            fs::read(mount.join(CONTROL).join("reload")).unwrap(),
+            b"1\n"
+        );
+        let path = Some("d/a".into());
+        ask(None, Request::Flush { path }).unwrap();
+        assert_eq!(
+            fs::read(mount.join(CONTROL).join("flush")).unwrap(),
+            b"/d/a"
+        );
+        assert!(ask(Some(&dir), Request::Stats).is_err());
+        drop(socket);
+        assert!(!dir.join("socket").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
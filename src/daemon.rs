@@ -0,0 +1,160 @@
+//! `--daemon` and `--pidfile`: running the mount in the background once
+//! it is up, with failures still reported on the terminal that started it,
+//! and unmounting cleanly when the process is told to stop.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+};
+
+use fuser::SessionUnmounter;
+use tracing::{info, warn};
+
+use crate::{systemd, Result};
+
+/// The daemon's end of the pipe its parent waits on.
+pub struct Ready(File);
+
+impl Ready {
+    /// Lets the parent exit successfully and detaches from the terminal,
+    /// pointing standard input and output at `/dev/null`.
+    pub fn notify(mut self) -> Result<()> {
+        self.0.write_all(b"1")?;
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        for fd in 0..3 {
+            // SAFETY: both are open descriptors.
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        std::env::set_current_dir("/")?;
+        Ok(())
+    }
+}
+
+/// Forks into the background. The parent never returns: it exits with 0
+/// once the child calls [`Ready::notify`], or with 1 if the child exits
+/// first, whose error has gone to the still shared stderr by then. Call
+/// this before starting any threads.
+pub fn daemonize() -> Result<Ready> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: `pipe` just opened them and nothing else owns them.
+    let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // SAFETY: the process is still single threaded.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            drop(read);
+            // SAFETY: no preconditions, and a forked child is never a
+            // process group leader, so it can't fail.
+            unsafe { libc::setsid() };
+            Ok(Ready(write))
+        }
+        _ => {
+            drop(write);
+            let mut status = Vec::new();
+            let _ = read.read_to_end(&mut status);
+            std::process::exit(if status == b"1" { 0 } else { 1 })
+        }
+    }
+}
+
+/// Unmounts through each of `unmounters` in turn.
+pub fn unmounting(mut unmounters: Vec<SessionUnmounter>) -> impl FnMut() + Send {
+    move || {
+        for unmounter in &mut unmounters {
+            if let Err(e) = unmounter.unmount() {
+                warn!("Unmounting failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Calls `unmount` on SIGINT, SIGTERM or SIGHUP, which makes
+/// [`fuser::Session::run`] return as if `umount` had been run, instead of
+/// the process dying with the mount point left dangling. The signals are
+/// blocked in the calling thread, and so in every thread it starts after
+/// this, and waited for in the thread returned.
+pub fn unmount_on_signals(mut unmount: impl FnMut() + Send + 'static) -> Result<JoinHandle<()>> {
+    // SAFETY: `sigemptyset` initializes the set before anything reads it.
+    let signals = unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut signals, signal);
+        }
+        signals
+    };
+    // SAFETY: `signals` is initialized and the old mask isn't asked for.
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result).into());
+    }
+    Ok(std::thread::spawn(move || loop {
+        let mut signal = 0;
+        // SAFETY: both point to live values.
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            return;
+        }
+        info!("Unmounting on signal {}", signal);
+        systemd::notify("STOPPING=1");
+        unmount();
+    }))
+}
+
+/// A file holding the process's pid, removed again when dropped.
+pub struct Pidfile(PathBuf);
+
+impl Pidfile {
+    pub fn create(path: &Path) -> Result<Pidfile> {
+        let path = std::path::absolute(path)?;
+        std::fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(Pidfile(path))
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::thread::JoinHandleExt, sync::mpsc, time::Duration};
+
+    use super::{unmount_on_signals, Pidfile};
+
+    #[test]
+    fn pidfile() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-{}.pid", std::process::id()));
+        let pidfile = Pidfile::create(&path).unwrap();
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(pid.trim().parse::<u32>().unwrap(), std::process::id());
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn signals() {
+        let (sender, unmounted) = mpsc::channel();
+        let waiter = unmount_on_signals(move || sender.send(()).unwrap()).unwrap();
+        // Sent to the waiting thread alone: the test harness's other threads
+        // don't block them and would die of them.
+        for signal in [libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the thread is alive, waiting for signals.
+            assert_eq!(
+                unsafe { libc::pthread_kill(waiter.as_pthread_t(), signal) },
+                0
+            );
+            unmounted.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! `serve-dav`: the tree a mount would show, served over WebDAV with the
+//! same cache and backends, for Windows clients and file managers to
+//! open without FUSE. It is class 1 WebDAV, without locks, which clients
+//! take for a read-only share. With `--auth-file` every request has to log
+//! in with basic auth, and only then can `--allow-control-writes` let the
+//! control files be written to, with `PUT`.
+
+use std::{
+    ffi::OsStr,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::{value_parser, Arg, ArgMatches, Command};
+use lhttpfs::fs::{FileAttr, FileType};
+
+use crate::{
+    fetch::{self, date},
+    fs::{LazyHTTPFS, OpError},
+    inspect, prefetch,
+    server::{self, escape, href, resolve, Request, Response},
+};
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PROPFIND";
+const ALLOW_PUT: &str = "OPTIONS, GET, HEAD, PROPFIND, PUT";
+
+/// Who may do what.
+struct Access {
+    /// The `user:password` pairs of `--auth-file`, if it was given.
+    users: Option<Vec<String>>,
+    /// Whether the control files can be written to.
+    writes: bool,
+}
+
+impl Access {
+    fn allow(&self) -> &'static str {
+        if self.writes {
+            ALLOW_PUT
+        } else {
+            ALLOW
+        }
+    }
+
+    /// Whether `request` logged in as one of the users, if there are any.
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(users) = &self.users else {
+            return true;
+        };
+        let credentials = request.header("authorization").and_then(|value| {
+            let (scheme, encoded) = value.split_once(' ')?;
+            let encoded = encoded.trim();
+            scheme.eq_ignore_ascii_case("basic").then_some(encoded)
+        });
+        let Some(credentials) = credentials.and_then(|c| BASE64.decode(c).ok()) else {
+            return false;
+        };
+        // Every pair is compared in full, to give nothing away by timing.
+        users.iter().fold(false, |found, user| {
+            let same = user.len() == credentials.len()
+                && user
+                    .bytes()
+                    .zip(&credentials)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0;
+            found | same
+        })
+    }
+}
+
+pub fn command() -> Command {
+    Command::new("serve-dav")
+        .about("Serve layouts over WebDAV, for clients that can't mount them")
+        .args(inspect::load_args())
+        .arg(prefetch::cache_dir_arg())
+        .arg(prefetch::cache_size_arg())
+        .arg(fetch::plugin::arg())
+        .arg(
+            Arg::new("auth-file")
+                .long("auth-file")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("File of user:password lines, one of which clients have to log in as"),
+        )
+        .arg(inspect::control_writes_arg().requires("auth-file"))
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .value_parser(value_parser!(SocketAddr))
+                .default_value("127.0.0.1:8080")
+                .help("Address to serve WebDAV at"),
+        )
+}
+
+/// Serves `fs` until the process is stopped.
+pub fn run(mut fs: LazyHTTPFS, matches: &ArgMatches) -> crate::Result<()> {
+    let addr = matches.get_one::<SocketAddr>("listen").unwrap();
+    let users = match matches.get_one::<PathBuf>("auth-file") {
+        Some(path) => Some(users(path)?),
+        None => None,
+    };
+    let access = Access {
+        users,
+        writes: matches.get_flag("allow-control-writes"),
+    };
+    if !access.writes {
+        fs.deny_control_writes();
+    }
+    let listener = std::net::TcpListener::bind(addr)
+        .map_err(|e| format!("Couldn't listen on {}: {}", addr, e))?;
+    eprintln!("Serving WebDAV at http://{}/", addr);
+    server::serve(listener, fs, move |fs, request| {
+        handle(fs, request, &access)
+    });
+    Ok(())
+}
+
+/// The `user:password` lines of `path`.
+fn users(path: &Path) -> crate::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let users: Vec<String> = (text.lines().map(str::trim))
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    if users.is_empty() || users.iter().any(|user| !user.contains(':')) {
+        return Err(format!("{} should hold a user:password per line", path.display()).into());
+    }
+    Ok(users)
+}
+
+fn handle(fs: &mut LazyHTTPFS, request: &Request, access: &Access) -> Response {
+    if !access.authorized(request) {
+        return Response::status("401 Unauthorized")
+            .header("WWW-Authenticate", "Basic realm=\"lhttpfs\"");
+    }
+    if request.method == "OPTIONS" {
+        return Response::new("200 OK", "text/plain", Vec::new())
+            .header("DAV", "1")
+            .header("Allow", access.allow())
+            .header("MS-Author-Via", "DAV");
+    }
+    if request.method == "PUT" && !access.writes {
+        return Response::status("405 Method Not Allowed").header("Allow", ALLOW);
+    }
+    let attr = match resolve(fs, &request.path) {
+        Ok(attr) => attr,
+        // Nothing can be created.
+        Err(OpError::NotFound) if request.method == "PUT" => {
+            return Response::status("403 Forbidden")
+        }
+        Err(e) => return Response::status(server::status(e)),
+    };
+    match request.method.as_str() {
+        "PROPFIND" => propfind(fs, request, attr),
+        "GET" | "HEAD" if attr.kind == FileType::Directory => server::listing(fs, request, &attr),
+        "GET" | "HEAD" => server::get(fs, request, &attr),
+        "PUT" => match fs.write_file(attr.ino, &request.body) {
+            Ok(()) => Response::new("204 No Content", "text/plain", Vec::new()),
+            Err(e) => Response::status(server::status(e)),
+        },
+        _ => Response::status("405 Method Not Allowed").header("Allow", access.allow()),
+    }
+}
+
+/// Answers `PROPFIND` with every property of `attr`, and with `Depth: 1`
+/// those of what it holds too. An infinite depth is taken for 1.
+fn propfind(fs: &mut LazyHTTPFS, request: &Request, attr: FileAttr) -> Response {
+    let path = request.path.trim_end_matches('/');
+    let mut entries = vec![(path.to_string(), attr)];
+    if attr.kind == FileType::Directory && request.header("depth") != Some("0") {
+        let children = fs.list(attr.ino).unwrap_or_default().into_iter();
+        let children = children.filter(|(_, name, _)| *name != "." && *name != "..");
+        let children: Vec<_> = children
+            .map(|(ino, name, _)| (ino, format!("{}/{}", path, name.to_string_lossy())))
+            .collect();
+        for (ino, path) in children {
+            if let Ok((attr, _)) = fs.attributes(ino) {
+                entries.push((path, attr));
+            }
+        }
+    }
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+    for (path, attr) in entries {
+        // Generated files are generated anew, for their size.
+        fs.regenerate(attr.ino);
+        let attr = fs.attributes(attr.ino).map_or(attr, |(attr, _)| attr);
+        let name = path.rsplit('/').next().unwrap_or("");
+        xml.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+             <D:displayname>{}</D:displayname>\
+             <D:getlastmodified>{}</D:getlastmodified>\
+             <D:creationdate>{}</D:creationdate>",
+            escape(&href(if path.is_empty() { "/" } else { &path }, &attr)),
+            escape(name),
+            date::http_date(attr.mtime),
+            date::rfc3339(attr.crtime),
+        ));
+        if attr.kind == FileType::Directory {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            let content_type = fs.xattr(attr.ino, OsStr::new("user.mime_type"));
+            let content_type = content_type.map_or("application/octet-stream".into(), |mime| {
+                String::from_utf8_lossy(mime).into_owned()
+            });
+            xml.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                 <D:getcontenttype>{}</D:getcontenttype>",
+                attr.size,
+                escape(&content_type)
+            ));
+        }
+        xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+    Response::new("207 Multi-Status", "application/xml; charset=utf-8", xml)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::Access;
+    use crate::{fs::LazyHTTPFS, layout, server};
+
+    /// Serves `layout` with `access`, returning where.
+    fn start(layout: &str, access: Access) -> std::net::SocketAddr {
+        let mut fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        fs.add_control();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            server::serve(listener, fs, move |fs, r| super::handle(fs, r, &access))
+        });
+        addr
+    }
+
+    fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn served() {
+        let layout = r#"[{"name": "d", "contents": [
+            {"name": "a&b", "content": "abcdef", "content_type": "text/plain"}
+        ]}]"#;
+        let access = Access {
+            users: None,
+            writes: false,
+        };
+        let addr = start(layout, access);
+
+        let listing = request(
+            addr,
+            "PROPFIND /d/ HTTP/1.1\r\nDepth: 1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(listing.starts_with("HTTP/1.1 207 Multi-Status\r\n"));
+        assert!(listing.contains("<D:href>/d/</D:href>"));
+        assert!(listing.contains("<D:href>/d/a%26b</D:href>"));
+        assert!(listing.contains("<D:displayname>a&amp;b</D:displayname>"));
+        assert!(listing.contains("<D:getcontentlength>6</D:getcontentlength>"));
+        assert!(listing.contains("<D:getcontenttype>text/plain</D:getcontenttype>"));
+
+        let part = request(
+            addr,
+            "GET /d/a%26b HTTP/1.1\r\nRange: bytes=1-3\r\nConnection: close\r\n\r\n",
+        );
+        assert!(part.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(part.contains("Content-Range: bytes 1-3/6\r\n"));
+        assert!(part.ends_with("\r\n\r\nbcd"));
+
+        let put = "PUT /.lhttpfs/flush HTTP/1.1\r\nContent-Length: 1\r\nConnection: close\r\n\r\n1";
+        assert!(request(addr, put).starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        let missing = request(addr, "GET /e HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn authenticated() {
+        let access = Access {
+            users: Some(vec!["ops:secret".to_string()]),
+            writes: true,
+        };
+        let addr = start(r#"[{"name": "a", "content": "abc"}]"#, access);
+        let get = "GET /a HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let refused = request(addr, get);
+        assert!(refused.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(refused.contains("WWW-Authenticate: Basic realm=\"lhttpfs\"\r\n"));
+        // ops:wrong
+        let wrong =
+            "GET /a HTTP/1.1\r\nAuthorization: Basic b3BzOndyb25n\r\nConnection: close\r\n\r\n";
+        assert!(request(addr, wrong).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+        // ops:secret
+        let login = "Authorization: Basic b3BzOnNlY3JldA==\r\nConnection: close\r\n";
+        let get = format!("GET /a HTTP/1.1\r\n{}\r\n", login);
+        assert!(request(addr, &get).ends_with("\r\n\r\nabc"));
+        let put = format!("PUT /a HTTP/1.1\r\n{}Content-Length: 1\r\n\r\nx", login);
+        assert!(request(addr, &put).starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let put = format!(
+            "PUT /.lhttpfs/flush HTTP/1.1\r\n{}Content-Length: 1\r\n\r\n1",
+            login
+        );
+        assert!(request(addr, &put).starts_with("HTTP/1.1 204 No Content\r\n"));
+    }
+}
@@ -0,0 +1,662 @@
+//! `--dbus`: the requests of `lhttpfs ctl` as methods on the session or
+//! system bus, for desktop applets to show how mounts are doing and flush
+//! or reload them. Only as much of the protocol is spoken as it takes to
+//! own a name and answer calls with strings.
+
+use std::{
+    ffi::OsStr,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        net::{SocketAddr, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+
+use clap::{builder::PossibleValuesParser, Arg};
+use percent_encoding::percent_decode_str;
+use tracing::{info, warn};
+
+use crate::{
+    ctl::{self, Request},
+    Result,
+};
+
+/// The name taken on the bus, and where the object is.
+const NAME: &str = "io.github.DolphinGui.Lhttpfs";
+const OBJECT: &str = "/io/github/DolphinGui/Lhttpfs";
+const INTERFACE: &str = "io.github.DolphinGui.Lhttpfs1";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="io.github.DolphinGui.Lhttpfs1">
+    <method name="Mounts"><arg name="mount_points" type="as" direction="out"/></method>
+    <method name="Stats">
+      <arg name="mount_point" type="s" direction="in"/>
+      <arg name="json" type="s" direction="out"/>
+    </method>
+    <method name="List">
+      <arg name="mount_point" type="s" direction="in"/>
+      <arg name="json" type="s" direction="out"/>
+    </method>
+    <method name="Flush">
+      <arg name="mount_point" type="s" direction="in"/>
+      <arg name="path" type="s" direction="in"/>
+    </method>
+    <method name="Reload"><arg name="mount_point" type="s" direction="in"/></method>
+    <method name="Add">
+      <arg name="mount_point" type="s" direction="in"/>
+      <arg name="entries" type="s" direction="in"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg name="xml" type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+/// The header fields, by code.
+const PATH: u8 = 1;
+const MEMBER_INTERFACE: u8 = 2;
+const MEMBER: u8 = 3;
+const ERROR_NAME: u8 = 4;
+const REPLY_SERIAL: u8 = 5;
+const DESTINATION: u8 = 6;
+const SENDER: u8 = 7;
+const SIGNATURE: u8 = 8;
+
+/// The longest message the specification allows.
+const MAX_MESSAGE: usize = 128 << 20;
+
+pub fn arg() -> Arg {
+    Arg::new("dbus")
+        .long("dbus")
+        .value_name("BUS")
+        .value_parser(PossibleValuesParser::new(["session", "system"]))
+        .help("Answer the requests of `lhttpfs ctl` on D-Bus, as io.github.DolphinGui.Lhttpfs")
+}
+
+/// A value of one of the few types used here.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    U32(u32),
+    Str(String),
+    Path(String),
+    Signature(String),
+    Strs(Vec<String>),
+}
+
+impl Value {
+    fn signature(&self) -> &'static str {
+        match self {
+            Value::U32(_) => "u",
+            Value::Str(_) => "s",
+            Value::Path(_) => "o",
+            Value::Signature(_) => "g",
+            Value::Strs(_) => "as",
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Message {
+    kind: u8,
+    serial: u32,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    body: Vec<Value>,
+}
+
+/// Marshals values, aligned as if the buffer were where a message starts,
+/// which bodies are too since they start 8-aligned.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn pad(&mut self, align: usize) {
+        self.0.resize(self.0.len().next_multiple_of(align), 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.pad(4);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::U32(value) => self.u32(*value),
+            Value::Str(s) | Value::Path(s) => {
+                self.u32(s.len() as u32);
+                self.0.extend_from_slice(s.as_bytes());
+                self.0.push(0);
+            }
+            Value::Signature(s) => {
+                self.0.push(s.len() as u8);
+                self.0.extend_from_slice(s.as_bytes());
+                self.0.push(0);
+            }
+            Value::Strs(strs) => {
+                self.u32(0);
+                let (at, start) = (self.0.len() - 4, self.0.len());
+                for s in strs {
+                    self.value(&Value::Str(s.clone()));
+                }
+                let len = (self.0.len() - start) as u32;
+                self.0[at..start].copy_from_slice(&len.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Unmarshals a message, in either byte order.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = (self.buf.get(self.pos..self.pos + len)).ok_or("Truncated D-Bus message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.pos = self.pos.next_multiple_of(4);
+        let bytes = self.bytes(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        let s = String::from_utf8(self.bytes(len)?.to_vec())?;
+        self.bytes(1)?;
+        Ok(s)
+    }
+
+    fn value(&mut self, signature: &str) -> Result<Value> {
+        Ok(match signature {
+            "y" => Value::U32(self.u8()? as u32),
+            "u" => Value::U32(self.u32()?),
+            "s" | "o" => {
+                let len = self.u32()? as usize;
+                let s = self.string(len)?;
+                match signature {
+                    "s" => Value::Str(s),
+                    _ => Value::Path(s),
+                }
+            }
+            "g" => {
+                let len = self.u8()? as usize;
+                Value::Signature(self.string(len)?)
+            }
+            "as" => {
+                let end = self.u32()? as usize + self.pos;
+                let mut strs = Vec::new();
+                while self.pos < end {
+                    let Value::Str(s) = self.value("s")? else {
+                        unreachable!()
+                    };
+                    strs.push(s);
+                }
+                Value::Strs(strs)
+            }
+            _ => return Err(format!("Unsupported D-Bus type {}", signature).into()),
+        })
+    }
+}
+
+/// Splits a signature into its complete types, as far as they are simple
+/// or arrays of simple ones.
+fn types(signature: &str) -> Vec<&str> {
+    let mut types = Vec::new();
+    let mut rest = signature;
+    while !rest.is_empty() {
+        let len = if rest.starts_with('a') { 2 } else { 1 };
+        let len = len.min(rest.len());
+        types.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    types
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Writer(Vec::new());
+        for value in &self.body {
+            body.value(value);
+        }
+        let mut fields = Vec::new();
+        let strings = [
+            (PATH, &self.path),
+            (MEMBER_INTERFACE, &self.interface),
+            (MEMBER, &self.member),
+            (ERROR_NAME, &self.error_name),
+            (DESTINATION, &self.destination),
+            (SENDER, &self.sender),
+        ];
+        for (code, value) in strings {
+            if let Some(value) = value {
+                let value = match code {
+                    PATH => Value::Path(value.clone()),
+                    _ => Value::Str(value.clone()),
+                };
+                fields.push((code, value));
+            }
+        }
+        if let Some(serial) = self.reply_serial {
+            fields.push((REPLY_SERIAL, Value::U32(serial)));
+        }
+        if !self.body.is_empty() {
+            let signature = self.body.iter().map(Value::signature).collect();
+            fields.push((SIGNATURE, Value::Signature(signature)));
+        }
+        let mut out = Writer(vec![b'l', self.kind, 0, 1]);
+        out.u32(body.0.len() as u32);
+        out.u32(self.serial);
+        out.u32(0);
+        for (code, value) in fields {
+            out.pad(8);
+            out.0.push(code);
+            out.value(&Value::Signature(value.signature().to_string()));
+            out.value(&value);
+        }
+        let len = (out.0.len() - 16) as u32;
+        out.0[12..16].copy_from_slice(&len.to_le_bytes());
+        out.pad(8);
+        out.0.extend_from_slice(&body.0);
+        out.0
+    }
+
+    fn decode(buf: &[u8]) -> Result<Message> {
+        let big_endian = match buf.first() {
+            Some(b'l') => false,
+            Some(b'B') => true,
+            _ => return Err("Not a D-Bus message".into()),
+        };
+        let mut reader = Reader {
+            buf,
+            pos: 4,
+            big_endian,
+        };
+        let body_len = reader.u32()? as usize;
+        let mut message = Message {
+            kind: buf[1],
+            serial: reader.u32()?,
+            ..Message::default()
+        };
+        let end = reader.u32()? as usize + 16;
+        let mut signature = String::new();
+        while reader.pos < end {
+            reader.pos = reader.pos.next_multiple_of(8);
+            let code = reader.u8()?;
+            let Value::Signature(kind) = reader.value("g")? else {
+                unreachable!()
+            };
+            match (code, reader.value(&kind)?) {
+                (PATH, Value::Path(s)) => message.path = Some(s),
+                (MEMBER_INTERFACE, Value::Str(s)) => message.interface = Some(s),
+                (MEMBER, Value::Str(s)) => message.member = Some(s),
+                (ERROR_NAME, Value::Str(s)) => message.error_name = Some(s),
+                (REPLY_SERIAL, Value::U32(serial)) => message.reply_serial = Some(serial),
+                (DESTINATION, Value::Str(s)) => message.destination = Some(s),
+                (SENDER, Value::Str(s)) => message.sender = Some(s),
+                (SIGNATURE, Value::Signature(s)) => signature = s,
+                _ => {}
+            }
+        }
+        reader.pos = reader.pos.next_multiple_of(8);
+        let body_end = reader.pos + body_len;
+        for kind in types(&signature) {
+            message.body.push(reader.value(kind)?);
+        }
+        if reader.pos != body_end {
+            return Err("Malformed D-Bus message body".into());
+        }
+        Ok(message)
+    }
+}
+
+/// A connection to a bus.
+pub struct Bus {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    serial: u32,
+}
+
+/// Where the bus is listening, as in `$DBUS_SESSION_BUS_ADDRESS`: the
+/// `unix:path=` and `unix:abstract=` addresses among those given.
+fn addresses(addresses: &str) -> Vec<io::Result<SocketAddr>> {
+    let mut found = Vec::new();
+    for address in addresses.split(';') {
+        let Some(params) = address.strip_prefix("unix:") else {
+            continue;
+        };
+        for param in params.split(',') {
+            let value = |value: &str| percent_decode_str(value).collect::<Vec<u8>>();
+            if let Some(path) = param.strip_prefix("path=") {
+                found.push(SocketAddr::from_pathname(Path::new(OsStr::from_bytes(
+                    &value(path),
+                ))));
+            } else if let Some(name) = param.strip_prefix("abstract=") {
+                #[cfg(target_os = "linux")]
+                found.push(SocketAddr::from_abstract_name(value(name)));
+                // The abstract namespace is Linux's own.
+                #[cfg(not(target_os = "linux"))]
+                found.push(Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("No abstract sockets here for {:?}", name),
+                )));
+            }
+        }
+    }
+    found
+}
+
+impl Bus {
+    /// Connects to the session or system bus, authenticating as the user
+    /// running the process, and asks for [`NAME`].
+    pub fn connect(system: bool) -> Result<Bus> {
+        let (var, default) = match system {
+            true => (
+                "DBUS_SYSTEM_BUS_ADDRESS",
+                "unix:path=/var/run/dbus/system_bus_socket",
+            ),
+            false => ("DBUS_SESSION_BUS_ADDRESS", ""),
+        };
+        let address = std::env::var(var).unwrap_or(default.to_string());
+        let mut error = format!("${} has no Unix socket address", var);
+        let mut stream = None;
+        for addr in addresses(&address) {
+            match addr.and_then(|addr| UnixStream::connect_addr(&addr)) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(e) => error = e.to_string(),
+            }
+        }
+        let stream = stream.ok_or_else(|| format!("Couldn't connect to D-Bus: {}", error))?;
+        let writer = stream.try_clone()?;
+        let mut bus = Bus {
+            reader: BufReader::new(stream),
+            writer,
+            serial: 0,
+        };
+        bus.authenticate()?;
+        bus.call("Hello", Vec::new())?;
+        let reply = bus.call("RequestName", vec![Value::Str(NAME.into()), Value::U32(0)])?;
+        if reply != [Value::U32(1)] {
+            warn!("{} is taken on D-Bus, waiting in line for it", NAME);
+        }
+        Ok(bus)
+    }
+
+    fn authenticate(&mut self) -> Result<()> {
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        write!(self.writer, "\0AUTH EXTERNAL {}\r\n", hex)?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        if !line.starts_with("OK ") {
+            return Err(format!("D-Bus refused us: {}", line.trim()).into());
+        }
+        self.writer.write_all(b"BEGIN\r\n")?;
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Message) -> io::Result<()> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.writer.write_all(&message.encode())
+    }
+
+    /// Reads the next message, to be decoded.
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; 16];
+        self.reader.read_exact(&mut buf)?;
+        let len = |at: usize| match buf[0] {
+            b'B' => u32::from_be_bytes(buf[at..at + 4].try_into().unwrap()),
+            _ => u32::from_le_bytes(buf[at..at + 4].try_into().unwrap()),
+        } as usize;
+        let len = (16 + len(12)).next_multiple_of(8) + len(4);
+        if len > MAX_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "D-Bus message too long",
+            ));
+        }
+        buf.resize(len, 0);
+        self.reader.read_exact(&mut buf[16..])?;
+        Ok(buf)
+    }
+
+    /// Calls a method of the bus itself, waiting for its reply.
+    fn call(&mut self, member: &str, body: Vec<Value>) -> Result<Vec<Value>> {
+        self.send(Message {
+            kind: METHOD_CALL,
+            path: Some("/org/freedesktop/DBus".into()),
+            interface: Some("org.freedesktop.DBus".into()),
+            member: Some(member.into()),
+            destination: Some("org.freedesktop.DBus".into()),
+            body,
+            ..Message::default()
+        })?;
+        let serial = self.serial;
+        loop {
+            let reply = Message::decode(&self.read()?)?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            return match (reply.kind, reply.body.first()) {
+                (ERROR, Some(Value::Str(e))) => {
+                    Err(format!("D-Bus {} failed: {}", member, e).into())
+                }
+                (ERROR, _) => Err(format!("D-Bus {} failed", member).into()),
+                _ => Ok(reply.body),
+            };
+        }
+    }
+
+    /// Answers method calls for `mounts` from a thread of its own.
+    pub fn serve(mut self, mounts: Vec<PathBuf>) {
+        std::thread::spawn(move || loop {
+            let message = match self.read() {
+                Ok(message) => Message::decode(&message),
+                Err(e) => {
+                    warn!("D-Bus connection lost: {}", e);
+                    return;
+                }
+            };
+            // Calls with arguments of other types go unanswered.
+            let Ok(message) = message else {
+                continue;
+            };
+            if message.kind != METHOD_CALL {
+                continue;
+            }
+            if let Err(e) = self.send(answer(&message, &mounts)) {
+                warn!("Answering on D-Bus failed: {}", e);
+            }
+        });
+        info!("Listening on D-Bus as {}", NAME);
+    }
+}
+
+/// The reply to `call`.
+fn answer(call: &Message, mounts: &[PathBuf]) -> Message {
+    let result = match (call.interface.as_deref(), call.member.as_deref()) {
+        _ if call.path.as_deref() != Some(OBJECT) => Err((
+            "org.freedesktop.DBus.Error.UnknownObject",
+            format!("No object at {}", call.path.as_deref().unwrap_or("")),
+        )),
+        (Some("org.freedesktop.DBus.Introspectable") | None, Some("Introspect")) => {
+            Ok(vec![Value::Str(INTROSPECTION.into())])
+        }
+        (Some("org.freedesktop.DBus.Peer") | None, Some("Ping")) => Ok(Vec::new()),
+        (Some(INTERFACE) | None, Some(member)) => method(member, &call.body, mounts),
+        (_, member) => Err((
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            format!("No method {}", member.unwrap_or("")),
+        )),
+    };
+    let (kind, error_name, body) = match result {
+        Ok(body) => (METHOD_RETURN, None, body),
+        Err((name, e)) => (ERROR, Some(name.to_string()), vec![Value::Str(e)]),
+    };
+    Message {
+        kind,
+        error_name,
+        reply_serial: Some(call.serial),
+        destination: call.sender.clone(),
+        body,
+        ..Message::default()
+    }
+}
+
+type MethodResult = std::result::Result<Vec<Value>, (&'static str, String)>;
+
+/// Calls method `member` of [`INTERFACE`] with `args`, the mount point
+/// first, empty for the first mount.
+fn method(member: &str, args: &[Value], mounts: &[PathBuf]) -> MethodResult {
+    let args: Vec<&str> = (args.iter())
+        .map_while(|arg| match arg {
+            Value::Str(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect();
+    let invalid = || {
+        Err((
+            "org.freedesktop.DBus.Error.InvalidArgs",
+            format!("Wrong arguments for {}", member),
+        ))
+    };
+    let (mount, request) = match (member, &args[..]) {
+        ("Mounts", []) => ("", Request::Mounts),
+        ("Stats", [mount]) => (*mount, Request::Stats),
+        ("List", [mount]) => (*mount, Request::List),
+        ("Flush", [mount, path]) => {
+            let path = (!path.is_empty()).then(|| path.to_string());
+            (*mount, Request::Flush { path })
+        }
+        ("Reload", [mount]) => (*mount, Request::Reload),
+        ("Add", [mount, entries]) => match serde_json::from_str(entries) {
+            Ok(entries) => (*mount, Request::Add { entries }),
+            Err(_) => return invalid(),
+        },
+        ("Mounts" | "Stats" | "List" | "Flush" | "Reload" | "Add", _) => return invalid(),
+        _ => {
+            return Err((
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                format!("No method {}", member),
+            ))
+        }
+    };
+    let mount = (!mount.is_empty()).then(|| Path::new(mount));
+    let error =
+        |e: Box<dyn std::error::Error>| ("io.github.DolphinGui.Lhttpfs1.Error", e.to_string());
+    Ok(match ctl::handle(mount, request, mounts).map_err(error)? {
+        serde_json::Value::Null => Vec::new(),
+        serde_json::Value::Array(mounts) if member == "Mounts" => {
+            let mounts = mounts.iter().filter_map(|m| m.as_str().map(str::to_string));
+            vec![Value::Strs(mounts.collect())]
+        }
+        json => vec![Value::Str(json.to_string())],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{addresses, answer, Message, Value, ERROR, METHOD_CALL, METHOD_RETURN, OBJECT};
+    use crate::fs::CONTROL;
+
+    fn call(member: &str, body: Vec<Value>) -> Message {
+        Message {
+            kind: METHOD_CALL,
+            serial: 7,
+            path: Some(OBJECT.into()),
+            member: Some(member.into()),
+            sender: Some(":1.42".into()),
+            body,
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn marshalled() {
+        let message = Message {
+            destination: Some("org.example".into()),
+            reply_serial: Some(3),
+            ..call(
+                "Flush",
+                vec![
+                    Value::Str("/mnt".into()),
+                    Value::Strs(vec!["a".into(), "bc".into()]),
+                    Value::U32(5),
+                ],
+            )
+        };
+        let encoded = message.encode();
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+        assert!(Message::decode(&encoded[..encoded.len() - 1]).is_err());
+        let found = addresses("tcp:host=x;unix:path=/run/bus,guid=1;unix:abstract=%2Fbus");
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            found[0].as_ref().unwrap().as_pathname(),
+            Some("/run/bus".as_ref())
+        );
+    }
+
+    #[test]
+    fn answered() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-dbus-{}", std::process::id()));
+        fs::create_dir_all(dir.join(CONTROL)).unwrap();
+        fs::write(dir.join(CONTROL).join("stats"), r#"{"files": 1}"#).unwrap();
+        let mounts = vec![dir.clone()];
+        let reply = answer(&call("Stats", vec![Value::Str("".into())]), &mounts);
+        assert_eq!(reply.kind, METHOD_RETURN);
+        assert_eq!(reply.reply_serial, Some(7));
+        assert_eq!(reply.destination.as_deref(), Some(":1.42"));
+        assert_eq!(reply.body, [Value::Str(r#"{"files":1}"#.into())]);
+        let reply = answer(&call("Mounts", Vec::new()), &mounts);
+        let mount = dir.to_str().unwrap().to_string();
+        assert_eq!(reply.body, [Value::Strs(vec![mount])]);
+        let reload = call("Reload", vec![Value::Str("".into())]);
+        assert_eq!(answer(&reload, &mounts).kind, METHOD_RETURN);
+        assert_eq!(fs::read(dir.join(CONTROL).join("reload")).unwrap(), b"1\n");
+        for (member, body) in [("Stats", Vec::new()), ("Nope", Vec::new())] {
+            assert_eq!(answer(&call(member, body), &mounts).kind, ERROR);
+        }
+        let elsewhere = Message {
+            path: Some("/".into()),
+            ..call("Mounts", Vec::new())
+        };
+        assert_eq!(answer(&elsewhere, &mounts).kind, ERROR);
+        assert!(answer(&call("Introspect", Vec::new()), &mounts).body[0] != Value::Str("".into()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
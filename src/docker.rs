@@ -0,0 +1,413 @@
+//! `docker-plugin`: a Docker volume plugin, so that containers can ask
+//! for a volume of a layout and have it mounted while they run:
+//! `docker volume create -d lhttpfs -o layout=https://example.com/layout.json assets`.
+//!
+//! Each volume is mounted by `lhttpfs mount --daemon`, once however many
+//! containers use it, and unmounted after the last one stops. Options
+//! other than `layout` are passed on as the mount's own, `-o retries=3`
+//! becoming `--retries=3` and `-o allow-root=` or `=true` a flag, but only
+//! the [`VOLUME_OPTIONS`]: the mount runs as root, and anyone who can
+//! create a volume mustn't be able to have it run commands or read and
+//! write files of their choosing. A layout given as a URL is downloaded
+//! anew by each mount, under the volume's `deny-host` and `require-https`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    io::{self, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::Command as Process,
+    time::Duration,
+};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+#[cfg(feature = "http")]
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::server;
+
+const CONTENT_TYPE: &str = "application/vnd.docker.plugins.v1.2+json";
+
+/// The mount options a volume may be given, none of which name a command,
+/// a file or credentials, or loosen the mount's limits.
+const VOLUME_OPTIONS: [&str; 20] = [
+    "allow-root",
+    "auto-decompress",
+    "checksum-files",
+    "deny-host",
+    "dir-mode",
+    "fetch-threads",
+    "file-mode",
+    "format",
+    "fuse-threads",
+    "gid",
+    "no-probe",
+    "on-conflict",
+    "profile",
+    "readahead",
+    "refresh",
+    "require-https",
+    "retries",
+    "retry-delay",
+    "source-files",
+    "uid",
+];
+
+pub fn command() -> Command {
+    Command::new("docker-plugin")
+        .about("Serve Docker's volume plugin API, mounting layouts as containers need them")
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .default_value("/run/docker/plugins/lhttpfs.sock")
+                .help("Socket to answer Docker at, whose name is the driver's"),
+        )
+        .arg(
+            Arg::new("root")
+                .long("root")
+                .value_name("DIR")
+                .value_parser(value_parser!(PathBuf))
+                .default_value("/var/lib/lhttpfs/volumes")
+                .help("Directory to keep the volumes and their mount points in"),
+        )
+}
+
+/// The volumes created, with their options, and the containers using each
+/// one that is mounted.
+struct Plugin {
+    root: PathBuf,
+    volumes: BTreeMap<String, BTreeMap<String, String>>,
+    mounted: HashMap<String, HashSet<String>>,
+}
+
+/// Answers Docker until the process is stopped.
+pub fn run(matches: &ArgMatches) -> crate::Result<()> {
+    let root = matches.get_one::<PathBuf>("root").unwrap();
+    let socket = matches.get_one::<PathBuf>("socket").unwrap();
+    fs::create_dir_all(root)?;
+    let state = fs::read(root.join("volumes.json"));
+    let volumes = match state {
+        Ok(state) => serde_json::from_slice(&state)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut plugin = Plugin {
+        root: root.clone(),
+        volumes,
+        mounted: HashMap::new(),
+    };
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let _ = fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| format!("Couldn't listen on {}: {}", socket.display(), e))?;
+    eprintln!("Serving the lhttpfs volume driver at {}", socket.display());
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| plugin.respond(stream)) {
+            warn!("Answering Docker failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+impl Plugin {
+    /// Answers the requests of one connection, Docker making one at a time.
+    fn respond(&mut self, stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+        let mut reader = BufReader::new(&stream);
+        while let Some((request, keep_alive)) = server::read_request(&mut reader)? {
+            let body = serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+            let endpoint = request.path.trim_start_matches('/');
+            let reply = match self.handle(endpoint, &body) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    warn!("{} failed: {}", endpoint, e);
+                    json!({"Err": e.to_string()})
+                }
+            };
+            let reply = reply.to_string();
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                CONTENT_TYPE,
+                reply.len(),
+                reply
+            )?;
+            if !keep_alive {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn mountpoint(&self, name: &str) -> PathBuf {
+        self.root.join("mounts").join(name)
+    }
+
+    /// Writes the volumes down, for them to outlive the plugin.
+    fn save(&self) -> crate::Result<()> {
+        let path = self.root.join("volumes.json");
+        fs::write(&path, serde_json::to_vec_pretty(&self.volumes)?)?;
+        Ok(())
+    }
+
+    fn handle(&mut self, endpoint: &str, body: &Value) -> crate::Result<Value> {
+        let name = body["Name"].as_str().unwrap_or("");
+        if endpoint.starts_with("VolumeDriver.") && !endpoint.ends_with(".List") {
+            let valid = name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b));
+            if name.is_empty() || !valid || name.starts_with('.') {
+                return Err(format!("Bad volume name {:?}", name).into());
+            }
+        }
+        let known = || -> crate::Result<()> {
+            match self.volumes.contains_key(name) {
+                true => Ok(()),
+                false => Err(format!("No volume named {}", name).into()),
+            }
+        };
+        Ok(match endpoint {
+            "Plugin.Activate" => json!({"Implements": ["VolumeDriver"]}),
+            "VolumeDriver.Capabilities" => json!({"Capabilities": {"Scope": "local"}}),
+            "VolumeDriver.Create" => {
+                let opts: BTreeMap<String, String> = match &body["Opts"] {
+                    Value::Null => BTreeMap::new(),
+                    opts => serde_json::from_value(opts.clone())?,
+                };
+                if !opts.contains_key("layout") {
+                    return Err("The volume needs a layout, given with -o layout=PATH|URL".into());
+                }
+                checked(&opts)?;
+                self.volumes.insert(name.to_string(), opts);
+                self.save()?;
+                json!({"Err": ""})
+            }
+            "VolumeDriver.Remove" => {
+                known()?;
+                if self.mounted.contains_key(name) {
+                    return Err(format!("Volume {} is in use", name).into());
+                }
+                self.volumes.remove(name);
+                self.save()?;
+                let _ = fs::remove_dir(self.mountpoint(name));
+                json!({"Err": ""})
+            }
+            "VolumeDriver.Mount" => {
+                known()?;
+                let id = body["ID"].as_str().unwrap_or("").to_string();
+                if !self.mounted.contains_key(name) {
+                    self.mount(name)?;
+                }
+                self.mounted.entry(name.to_string()).or_default().insert(id);
+                json!({"Mountpoint": self.mountpoint(name), "Err": ""})
+            }
+            "VolumeDriver.Unmount" => {
+                known()?;
+                let id = body["ID"].as_str().unwrap_or("");
+                let users = self.mounted.get_mut(name);
+                if users.is_some_and(|users| users.remove(id) && users.is_empty()) {
+                    self.mounted.remove(name);
+                    self.unmount(name)?;
+                }
+                json!({"Err": ""})
+            }
+            "VolumeDriver.Path" => {
+                known()?;
+                let mountpoint = self
+                    .mounted
+                    .contains_key(name)
+                    .then(|| self.mountpoint(name));
+                json!({"Mountpoint": mountpoint.unwrap_or_default(), "Err": ""})
+            }
+            "VolumeDriver.Get" => {
+                known()?;
+                json!({"Volume": self.volume(name), "Err": ""})
+            }
+            "VolumeDriver.List" => {
+                let volumes: Vec<_> = self.volumes.keys().map(|name| self.volume(name)).collect();
+                json!({"Volumes": volumes, "Err": ""})
+            }
+            _ => return Err(format!("Unknown endpoint {}", endpoint).into()),
+        })
+    }
+
+    fn volume(&self, name: &str) -> Value {
+        let mut volume = json!({"Name": name, "Status": {"Options": self.volumes[name]}});
+        if self.mounted.contains_key(name) {
+            volume["Mountpoint"] = json!(self.mountpoint(name));
+        }
+        volume
+    }
+
+    /// Mounts the volume `name` with `lhttpfs mount --daemon`, which only
+    /// exits once the mount is up.
+    fn mount(&self, name: &str) -> crate::Result<()> {
+        let mountpoint = self.mountpoint(name);
+        fs::create_dir_all(&mountpoint)?;
+        let opts = &self.volumes[name];
+        let pidfile = self.root.join(format!("{}.pid", name));
+        let args = mount_args(opts, &mountpoint, &pidfile)?;
+        info!("Mounting volume {} at {}", name, mountpoint.display());
+        let output = Process::new(std::env::current_exe()?).args(args).output()?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("Mounting {} failed: {}", name, error.trim()).into());
+        }
+        Ok(())
+    }
+
+    /// Stops the mount of `name`, which unmounts it on the way out, and
+    /// waits for it to be gone.
+    fn unmount(&self, name: &str) -> crate::Result<()> {
+        let pidfile = self.root.join(format!("{}.pid", name));
+        let pid: libc::pid_t = fs::read_to_string(&pidfile)?.trim().parse()?;
+        info!("Unmounting volume {}", name);
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        for _ in 0..100 {
+            if !pidfile.exists() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Err(format!("Volume {} is still mounted", name).into())
+    }
+}
+
+/// Fails on the first of `opts`, but for `layout`, that isn't one of the
+/// [`VOLUME_OPTIONS`].
+fn checked(opts: &BTreeMap<String, String>) -> crate::Result<()> {
+    let refused = opts
+        .keys()
+        .find(|key| *key != "layout" && !VOLUME_OPTIONS.contains(&key.as_str()));
+    match refused {
+        Some(key) => Err(format!(
+            "Volumes can't be given -o {}, only layout and {}",
+            key,
+            VOLUME_OPTIONS.join(", ")
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// The arguments `lhttpfs` is run with to mount a volume with `opts`,
+/// failing on any it can't be given.
+fn mount_args(
+    opts: &BTreeMap<String, String>,
+    mountpoint: &Path,
+    pidfile: &Path,
+) -> crate::Result<Vec<OsString>> {
+    checked(opts)?;
+    let mut args: Vec<OsString> = vec!["mount".into(), "--daemon".into(), "--pidfile".into()];
+    args.push(pidfile.into());
+    for (key, value) in opts.iter().filter(|(key, _)| *key != "layout") {
+        args.push(match value.as_str() {
+            "" | "true" => format!("--{}", key).into(),
+            value => format!("--{}={}", key, value).into(),
+        });
+    }
+    // After `--`, so that a layout can't be taken for an option.
+    args.extend([
+        "--".into(),
+        mountpoint.into(),
+        opts["layout"].clone().into(),
+    ]);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        path::Path,
+    };
+
+    use serde_json::json;
+
+    use super::{mount_args, Plugin};
+
+    #[test]
+    fn volumes() {
+        let root = std::env::temp_dir().join(format!("lhttpfs-docker-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut plugin = Plugin {
+            root: root.clone(),
+            volumes: BTreeMap::new(),
+            mounted: HashMap::new(),
+        };
+        let activated = plugin.handle("Plugin.Activate", &json!({})).unwrap();
+        assert_eq!(activated, json!({"Implements": ["VolumeDriver"]}));
+        let create = json!({"Name": "assets", "Opts": {"layout": "/etc/layout.json"}});
+        plugin.handle("VolumeDriver.Create", &create).unwrap();
+        let bad = json!({"Name": "other", "Opts": {}});
+        assert!(plugin.handle("VolumeDriver.Create", &bad).is_err());
+        for opt in ["on-event", "allow-filters", "cache-dir", "header"] {
+            let bad = json!({"Name": "other", "Opts": {"layout": "l.json", opt: "x"}});
+            assert!(plugin.handle("VolumeDriver.Create", &bad).is_err());
+        }
+        assert!(plugin
+            .handle("VolumeDriver.Get", &json!({"Name": "../x"}))
+            .is_err());
+        let listed = plugin.handle("VolumeDriver.List", &json!({})).unwrap();
+        assert_eq!(listed["Volumes"][0]["Name"], "assets");
+        assert!(listed["Volumes"][0].get("Mountpoint").is_none());
+
+        plugin
+            .mounted
+            .insert("assets".into(), HashSet::from(["c1".into()]));
+        let path = plugin
+            .handle("VolumeDriver.Path", &json!({"Name": "assets"}))
+            .unwrap();
+        assert_eq!(path["Mountpoint"], json!(root.join("mounts/assets")));
+        assert!(plugin
+            .handle("VolumeDriver.Remove", &json!({"Name": "assets"}))
+            .is_err());
+        plugin.mounted.clear();
+        plugin
+            .handle("VolumeDriver.Remove", &json!({"Name": "assets"}))
+            .unwrap();
+        let saved = std::fs::read_to_string(root.join("volumes.json")).unwrap();
+        assert_eq!(saved.trim(), "{}");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn mounted() {
+        let opts = BTreeMap::from([
+            (
+                "layout".to_string(),
+                "https://example.com/l.json".to_string(),
+            ),
+            ("readahead".into(), "4".into()),
+            ("allow-root".into(), "".into()),
+        ]);
+        let (mountpoint, pidfile) = (Path::new("/v/mounts/a"), Path::new("/v/a.pid"));
+        let args = mount_args(&opts, mountpoint, pidfile).unwrap();
+        assert_eq!(
+            args,
+            [
+                "mount",
+                "--daemon",
+                "--pidfile",
+                "/v/a.pid",
+                "--allow-root",
+                "--readahead=4",
+                "--",
+                "/v/mounts/a",
+                "https://example.com/l.json"
+            ]
+        );
+        let mut opts = opts;
+        opts.insert("on-event".into(), "touch /x".into());
+        assert!(mount_args(&opts, mountpoint, pidfile).is_err());
+    }
+}
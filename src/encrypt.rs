@@ -0,0 +1,52 @@
+//! `encrypt`: prepare a file for an entry with `decrypt`, so it can be
+//! uploaded to storage that shouldn't see its contents.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use crate::{
+    transform::{Cipher, Encryption},
+    Result,
+};
+
+pub fn command() -> Command {
+    Command::new("encrypt")
+        .about("Encrypt a file with AES-256-GCM for an entry with \"decrypt\"")
+        .arg(Arg::new("INPUT").required(true).help("File to encrypt"))
+        .arg(
+            Arg::new("OUTPUT")
+                .required(true)
+                .help("Where to write the encrypted file"),
+        )
+        .arg(
+            Arg::new("key-file")
+                .long("key-file")
+                .required(true)
+                .help("File holding the key, as 32 bytes or 64 hex digits"),
+        )
+        .arg(
+            Arg::new("new-key")
+                .long("new-key")
+                .action(ArgAction::SetTrue)
+                .help("Generate a new key into --key-file first, which mustn't exist"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let encryption = Encryption {
+        cipher: Cipher::Aes256Gcm,
+        key_file: matches.get_one::<String>("key-file").unwrap().into(),
+    };
+    if matches.get_flag("new-key") {
+        encryption.generate_key()?;
+    }
+    let data = std::fs::read(matches.get_one::<String>("INPUT").unwrap())?;
+    let output = matches.get_one::<String>("OUTPUT").unwrap();
+    std::fs::write(output, encryption.encrypt(&data)?)?;
+    eprintln!(
+        "Wrote {}; its entry needs \"size\": {} and \"decrypt\": {{\"key_file\": {:?}}}",
+        output,
+        data.len(),
+        encryption.key_file
+    );
+    Ok(())
+}
@@ -0,0 +1,238 @@
+//! [`LhttpfsError`], what the public entry points fail with.
+//!
+//! Inside the crate errors are mostly small types of their own behind
+//! `Box<dyn Error>`, each saying exactly what went wrong. At the edges they
+//! are sorted into what went wrong in terms a caller can act on: the
+//! layout, the origin, the cache directory or the mount. The box they came
+//! in stays the [`std::error::Error::source`], for matching on the exact
+//! cause.
+
+use std::{error::Error, io, path::PathBuf};
+
+use crate::fetch::{HostRefused, HttpStatus};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum LhttpfsError {
+    /// A layout that couldn't be parsed or doesn't describe a tree, such
+    /// as one using a newer format or with two entries of the same name.
+    #[error("Invalid layout: {0}")]
+    Layout(#[source] Box<dyn Error>),
+    /// Reading `url` from its origin failed, with the HTTP status it
+    /// answered if that's what it did.
+    #[error("Couldn't fetch {url}: {source}")]
+    Fetch {
+        url: String,
+        status: Option<u32>,
+        source: Box<dyn Error>,
+    },
+    /// The cache directory `dir` couldn't be read or written.
+    #[error("Cache directory {}: {source}", dir.display())]
+    Cache { dir: PathBuf, source: io::Error },
+    /// Mounting at `mountpoint` failed, with what to do about it, most
+    /// likely first.
+    #[error(
+        "Couldn't mount at {}: {source}{}",
+        mountpoint.display(),
+        hints.iter().map(|hint| format!("\n  {}", hint)).collect::<String>()
+    )]
+    Mount {
+        mountpoint: PathBuf,
+        source: io::Error,
+        hints: Vec<String>,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Other(#[source] Box<dyn Error>),
+}
+
+impl LhttpfsError {
+    /// `error`, from reading `url`, as a [`LhttpfsError::Fetch`], unless it
+    /// already is one.
+    pub fn fetch(url: &str, error: Box<dyn Error>) -> LhttpfsError {
+        match error.downcast::<LhttpfsError>() {
+            Ok(error) => match *error {
+                LhttpfsError::Other(error) => LhttpfsError::fetch(url, error),
+                error => error,
+            },
+            Err(error) => LhttpfsError::Fetch {
+                url: url.to_string(),
+                status: error.downcast_ref::<HttpStatus>().map(|e| e.status),
+                source: error,
+            },
+        }
+    }
+
+    /// `error`, from reading or resolving a layout, as a
+    /// [`LhttpfsError::Layout`], unless it is already sorted, as a failed
+    /// fetch of an archive's index would be, or came from reading it.
+    pub fn layout(error: Box<dyn Error>) -> LhttpfsError {
+        match error.downcast::<LhttpfsError>() {
+            Ok(error) => *error,
+            Err(error) => match error.downcast::<io::Error>() {
+                Ok(error) => LhttpfsError::Io(*error),
+                Err(error) => LhttpfsError::Layout(error),
+            },
+        }
+    }
+}
+
+impl LhttpfsError {
+    /// The HTTP status the origin answered a failed fetch with, if it did.
+    pub fn status(&self) -> Option<u32> {
+        match self {
+            LhttpfsError::Fetch { status, .. } => *status,
+            _ => causes(self).find_map(|e| e.downcast_ref::<HttpStatus>().map(|e| e.status)),
+        }
+    }
+
+    /// Whether the fetch failed for taking too long, by the origin's
+    /// `408` or `504` or curl's own timeouts.
+    pub fn timed_out(&self) -> bool {
+        matches!(self.status(), Some(408 | 504))
+            || causes(self).any(|e| {
+                #[cfg(feature = "http")]
+                if let Some(e) = e.downcast_ref::<curl::Error>() {
+                    return e.is_operation_timedout();
+                }
+                e.downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+            })
+    }
+
+    /// Whether the same fetch may well succeed if tried again: the origin
+    /// was overloaded or failed with a `5xx`, it timed out, or the
+    /// connection to it was lost.
+    pub fn transient(&self) -> bool {
+        match self.status() {
+            Some(status) => matches!(status, 408 | 429 | 500 | 502 | 503 | 504),
+            None => {
+                self.timed_out()
+                    || causes(self).any(|e| {
+                        #[cfg(feature = "http")]
+                        if let Some(e) = e.downcast_ref::<curl::Error>() {
+                            return e.is_couldnt_connect()
+                                || e.is_recv_error()
+                                || e.is_send_error()
+                                || e.is_got_nothing()
+                                || e.is_partial_file();
+                        }
+                        e.downcast_ref::<io::Error>().is_some_and(|e| {
+                            matches!(
+                                e.kind(),
+                                io::ErrorKind::ConnectionRefused
+                                    | io::ErrorKind::ConnectionReset
+                                    | io::ErrorKind::ConnectionAborted
+                                    | io::ErrorKind::UnexpectedEof
+                            )
+                        })
+                    })
+            }
+        }
+    }
+}
+
+/// `error` and every error under it, down to the root cause.
+fn causes(error: &LhttpfsError) -> impl Iterator<Item = &(dyn Error + 'static)> {
+    std::iter::successors(Some(error as &(dyn Error + 'static)), |&e| e.source())
+}
+
+impl From<Box<dyn Error>> for LhttpfsError {
+    fn from(error: Box<dyn Error>) -> LhttpfsError {
+        match error.downcast::<LhttpfsError>() {
+            Ok(error) => *error,
+            Err(error) => match error.downcast::<io::Error>() {
+                Ok(error) => LhttpfsError::Io(*error),
+                Err(error) => LhttpfsError::Other(error),
+            },
+        }
+    }
+}
+
+/// What using something this build of lhttpfs left out fails with.
+#[derive(Debug, thiserror::Error)]
+#[error("lhttpfs was built without the `{0}` feature")]
+pub struct NotBuilt(pub &'static str);
+
+// What the fetchers fail with on their way to a
+// [`LhttpfsError::Fetch`], sorted once [`Fetchers`] knows the URL.
+//
+// [`Fetchers`]: crate::fetch::Fetchers
+macro_rules! other {
+    ($($(#[$meta:meta])* $error:ty),*) => {$(
+        $(#[$meta])*
+        impl From<$error> for LhttpfsError {
+            fn from(error: $error) -> LhttpfsError {
+                LhttpfsError::Other(error.into())
+            }
+        }
+    )*};
+}
+
+other!(
+    String,
+    &str,
+    #[cfg(feature = "http")]
+    curl::Error,
+    std::str::Utf8Error,
+    url::ParseError,
+    #[cfg(feature = "sftp")]
+    ssh2::Error
+);
+
+other!(HttpStatus, HostRefused);
+
+#[cfg(test)]
+mod test {
+    use std::{error::Error, io, path::PathBuf};
+
+    use super::LhttpfsError;
+    use crate::fetch::HttpStatus;
+
+    #[test]
+    fn sorted() {
+        let status = HttpStatus {
+            url: "https://h/a".into(),
+            status: 404,
+        };
+        let error = LhttpfsError::fetch("https://h/a", Box::new(status));
+        let LhttpfsError::Fetch { status, .. } = &error else {
+            panic!("Unexpected {:?}", error);
+        };
+        assert_eq!(*status, Some(404));
+        assert_eq!(
+            error.to_string(),
+            "Couldn't fetch https://h/a: https://h/a returned HTTP 404"
+        );
+        assert!(error.source().unwrap().is::<HttpStatus>());
+
+        // Already sorted errors stay as they are.
+        let again = LhttpfsError::layout(Box::new(error));
+        assert!(matches!(again, LhttpfsError::Fetch { .. }));
+        let io: Box<dyn Error> = Box::new(io::Error::other("gone"));
+        assert!(matches!(LhttpfsError::from(io), LhttpfsError::Io(_)));
+
+        let status = |status| {
+            let url = "https://h/a".to_string();
+            LhttpfsError::fetch("https://h/a", Box::new(HttpStatus { url, status }))
+        };
+        assert!(status(503).transient() && !status(503).timed_out());
+        assert!(status(504).transient() && status(504).timed_out());
+        assert!(!status(403).transient() && !status(404).transient());
+        let timeout: Box<dyn Error> = Box::new(io::Error::from(io::ErrorKind::TimedOut));
+        let timeout = LhttpfsError::fetch("https://h/a", timeout);
+        assert!(timeout.timed_out() && timeout.transient() && timeout.status().is_none());
+        let layout = LhttpfsError::Layout("no".into());
+        assert!(!layout.transient());
+
+        let mount = LhttpfsError::Mount {
+            mountpoint: PathBuf::from("/mnt/a"),
+            source: io::Error::from_raw_os_error(libc::EPERM),
+            hints: vec!["Install fuse3.".into()],
+        };
+        let text = mount.to_string();
+        assert!(text.starts_with("Couldn't mount at /mnt/a: "));
+        assert!(text.ends_with("\n  Install fuse3."));
+    }
+}
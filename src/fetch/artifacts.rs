@@ -0,0 +1,221 @@
+//! artifactory+https:// and nexus+https:// URLs, build artifacts in an
+//! Artifactory or Nexus repository. Both are plain HTTP downloads, but
+//! with the servers' own credentials and checksums: whole files are
+//! checked against the sha256 the repository recorded at upload.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{
+    layout::{hex, Auth},
+    LhttpfsError,
+};
+
+use super::{
+    http::{easy, transfer},
+    oci::DigestMismatch,
+    perform, FetchResult, Fetcher, Request,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Artifactory,
+    Nexus,
+}
+
+pub struct Artifacts {
+    kind: Kind,
+    /// Recorded sha256 by URL, `None` where the server has none.
+    checksums: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Artifacts {
+    pub fn new(kind: Kind) -> Artifacts {
+        Artifacts {
+            kind,
+            checksums: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Fetcher for Artifacts {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let url = http_url(request.url)?;
+        let headers = self.headers(request);
+        let basic = self.basic();
+        let auth = request.auth.or(basic.as_ref());
+        let request = Request {
+            url,
+            headers: &headers,
+            auth,
+            size: request.size,
+        };
+        let data = transfer(&mut easy(&request)?, request.size, range)?;
+        if range.is_none() {
+            if let Some(expected) = self.checksum(&request)? {
+                if hex(&Sha256::digest(&data.data)) != expected {
+                    return Err(LhttpfsError::Other(Box::new(DigestMismatch(format!(
+                        "sha256:{}",
+                        expected
+                    )))));
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn plaintext(&self, url: &str) -> bool {
+        http_url(url).is_ok_and(|url| url.starts_with("http://"))
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+impl Artifacts {
+    /// The request's headers plus Artifactory's token or API key header,
+    /// unless the layout brings its own auth.
+    fn headers(&self, request: &Request) -> BTreeMap<String, String> {
+        let mut headers = request.headers.clone();
+        if self.kind == Kind::Artifactory && request.auth.is_none() {
+            if let Some(token) = env("ARTIFACTORY_ACCESS_TOKEN") {
+                headers.insert("Authorization".into(), format!("Bearer {}", token));
+            } else if let Some(key) = env("ARTIFACTORY_API_KEY") {
+                headers.insert("X-JFrog-Art-Api".into(), key);
+            }
+        }
+        headers
+    }
+
+    /// Nexus user tokens are sent as basic auth.
+    fn basic(&self) -> Option<Auth> {
+        match self.kind {
+            Kind::Nexus => Some(Auth::Basic {
+                username: env("NEXUS_USERNAME")?,
+                password: env("NEXUS_PASSWORD")?,
+            }),
+            Kind::Artifactory => None,
+        }
+    }
+
+    fn checksum(&self, request: &Request) -> crate::Result<Option<String>> {
+        if let Some(checksum) = self.checksums.lock().unwrap().get(request.url) {
+            return Ok(checksum.clone());
+        }
+        let url = match self.kind {
+            Kind::Artifactory => storage_url(request.url)?,
+            Kind::Nexus => format!("{}.sha256", request.url),
+        };
+        let body = perform(easy(&Request {
+            url: &url,
+            ..*request
+        })?);
+        let checksum = match body {
+            Ok(body) => parse_checksum(self.kind, &body),
+            // Not every repository keeps checksums, so going without is
+            // fine.
+            Err(e) => {
+                debug!("No checksum for {}: {}", request.url, e);
+                None
+            }
+        };
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(request.url.to_string(), checksum.clone());
+        Ok(checksum)
+    }
+}
+
+/// The https:// (or http://) URL of the artifact.
+fn http_url(url: &str) -> crate::Result<&str> {
+    url.strip_prefix("artifactory+")
+        .or_else(|| url.strip_prefix("nexus+"))
+        .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+        .ok_or_else(|| {
+            format!(
+                "{} is not an artifactory+https:// or nexus+https:// URL",
+                url
+            )
+            .into()
+        })
+}
+
+/// Artifactory's storage API URL for an artifact, which describes it
+/// including its checksums.
+fn storage_url(url: &str) -> crate::Result<String> {
+    let mut url = url::Url::parse(url)?;
+    let path = url.path().to_string();
+    let path = match path.strip_prefix("/artifactory/") {
+        Some(rest) => format!("/artifactory/api/storage/{}", rest),
+        None => format!("/api/storage{}", path),
+    };
+    url.set_path(&path);
+    Ok(url.into())
+}
+
+fn parse_checksum(kind: Kind, body: &[u8]) -> Option<String> {
+    let checksum = match kind {
+        Kind::Artifactory => {
+            let info: serde_json::Value = serde_json::from_slice(body).ok()?;
+            info["checksums"]["sha256"].as_str()?.to_string()
+        }
+        // The sidecar may also carry the file name after the hash.
+        Kind::Nexus => String::from_utf8_lossy(body)
+            .split_whitespace()
+            .next()?
+            .to_string(),
+    };
+    let checksum = checksum.to_ascii_lowercase();
+    (checksum.len() == 64 && checksum.bytes().all(|b| b.is_ascii_hexdigit())).then_some(checksum)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{http_url, parse_checksum, storage_url, Kind};
+
+    const SHA: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+    #[test]
+    fn urls() {
+        let url = "artifactory+https://acme.jfrog.io/artifactory/libs/app/1.0/app.jar";
+        let url = http_url(url).unwrap();
+        assert_eq!(
+            url,
+            "https://acme.jfrog.io/artifactory/libs/app/1.0/app.jar"
+        );
+        assert_eq!(
+            storage_url(url).unwrap(),
+            "https://acme.jfrog.io/artifactory/api/storage/libs/app/1.0/app.jar"
+        );
+        assert_eq!(
+            storage_url("https://repo.acme.com/libs/a.jar").unwrap(),
+            "https://repo.acme.com/api/storage/libs/a.jar"
+        );
+        assert!(http_url("artifactory+ftp://host/a").is_err());
+    }
+
+    #[test]
+    fn checksums() {
+        let storage = format!(
+            r#"{{"repo": "libs", "checksums": {{"sha1": "ab", "sha256": "{}"}}}}"#,
+            SHA
+        );
+        assert_eq!(
+            parse_checksum(Kind::Artifactory, storage.as_bytes()).as_deref(),
+            Some(SHA)
+        );
+        let sidecar = format!("{}  app.jar\n", SHA.to_uppercase());
+        assert_eq!(
+            parse_checksum(Kind::Nexus, sidecar.as_bytes()).as_deref(),
+            Some(SHA)
+        );
+        assert_eq!(parse_checksum(Kind::Nexus, b"<html>Not found</html>"), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! b2://bucket/file URLs, read through Backblaze B2's native API.
+
+use std::sync::Mutex;
+
+use curl::easy::{Auth as CurlAuth, Easy};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{
+    http::{easy, transfer},
+    perform, FetchResult, Fetcher, Request,
+};
+
+/// B2 file names keep their slashes in download URLs.
+const FILE_NAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct Account {
+    authorization_token: Option<String>,
+    download_url: String,
+}
+
+#[derive(Default)]
+pub struct B2 {
+    account: Mutex<Option<Account>>,
+}
+
+impl Fetcher for B2 {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let (bucket, file) = parse(request.url)?;
+        // Authorizations last a day, so an HTTP error is retried once with
+        // a fresh one.
+        for attempt in 0..2 {
+            let account = self.account(attempt > 0)?;
+            let url = download_url(&account, bucket, file);
+            let mut headers = request.headers.clone();
+            if let Some(token) = &account.authorization_token {
+                headers.insert("Authorization".into(), token.clone());
+            }
+            let mut curl = easy(&Request {
+                url: &url,
+                headers: &headers,
+                auth: None,
+                ..*request
+            })?;
+            match transfer(&mut curl, request.size, range) {
+                Ok(data) => return Ok(data),
+                Err(e) if attempt == 0 && account.authorization_token.is_some() => {
+                    debug!("Retrying {} with a new authorization: {}", request.url, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl B2 {
+    fn account(&self, refresh: bool) -> crate::Result<Account> {
+        let mut account = self.account.lock().unwrap();
+        match &*account {
+            Some(account) if !refresh => return Ok(account.clone()),
+            _ => {}
+        }
+        let new = authorize()?;
+        *account = Some(new.clone());
+        Ok(new)
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Calls `b2_authorize_account` with `B2_APPLICATION_KEY_ID` and
+/// `B2_APPLICATION_KEY`. Without them, public buckets can still be read
+/// from `B2_DOWNLOAD_URL`.
+fn authorize() -> crate::Result<Account> {
+    let (Some(id), Some(key)) = (env("B2_APPLICATION_KEY_ID"), env("B2_APPLICATION_KEY")) else {
+        return match env("B2_DOWNLOAD_URL") {
+            Some(download_url) => Ok(Account {
+                authorization_token: None,
+                download_url,
+            }),
+            None => Err(
+                "b2:// URLs need B2_APPLICATION_KEY_ID and B2_APPLICATION_KEY, \
+                 or B2_DOWNLOAD_URL for public buckets"
+                    .into(),
+            ),
+        };
+    };
+    let mut curl = Easy::new();
+    curl.url("https://api.backblazeb2.com/b2api/v2/b2_authorize_account")?;
+    let mut auth = CurlAuth::new();
+    auth.basic(true);
+    curl.http_auth(&auth)?;
+    curl.username(&id)?;
+    curl.password(&key)?;
+    Ok(serde_json::from_slice(&perform(curl)?)?)
+}
+
+fn parse(url: &str) -> crate::Result<(&str, &str)> {
+    url.strip_prefix("b2://")
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(bucket, file)| !bucket.is_empty() && !file.is_empty())
+        .ok_or_else(|| format!("{} is not a b2://bucket/file URL", url).into())
+}
+
+fn download_url(account: &Account, bucket: &str, file: &str) -> String {
+    format!(
+        "{}/file/{}/{}",
+        account.download_url.trim_end_matches('/'),
+        bucket,
+        utf8_percent_encode(file, FILE_NAME)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{download_url, parse, Account};
+
+    #[test]
+    fn urls() {
+        let response = r#"{"accountId": "a1", "apiUrl": "https://api002.backblazeb2.com",
+            "authorizationToken": "4_0022", "downloadUrl": "https://f002.backblazeb2.com",
+            "recommendedPartSize": 100000000}"#;
+        let account: Account = serde_json::from_str(response).unwrap();
+        assert_eq!(account.authorization_token.as_deref(), Some("4_0022"));
+        let (bucket, file) = parse("b2://my-bucket/data/a b.csv").unwrap();
+        assert_eq!(
+            download_url(&account, bucket, file),
+            "https://f002.backblazeb2.com/file/my-bucket/data/a%20b.csv"
+        );
+        assert!(parse("b2://bucket").is_err());
+        assert!(parse("b2:///file").is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! data: URLs (RFC 2397), small files carried in the layout itself.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use percent_encoding::percent_decode_str;
+
+use super::{cut, FetchResult, Fetcher, Request};
+
+pub struct Data;
+
+impl Fetcher for Data {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        Ok(cut(decode(request.url)?, range).into())
+    }
+}
+
+fn decode(url: &str) -> crate::Result<Vec<u8>> {
+    let (header, payload) = url
+        .split_once(':')
+        .and_then(|(_, rest)| rest.split_once(','))
+        .ok_or_else(|| format!("{} is not a data: URL", url))?;
+    let payload = percent_decode_str(payload).collect::<Vec<u8>>();
+    if !header.split(';').any(|p| p.eq_ignore_ascii_case("base64")) {
+        return Ok(payload);
+    }
+    // Whitespace is allowed in base64 payloads, e.g. when wrapped.
+    let payload = payload
+        .into_iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect::<Vec<u8>>();
+    Ok(BASE64.decode(payload)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::decode;
+
+    #[test]
+    fn data_urls() {
+        assert_eq!(decode("data:,hello%20world").unwrap(), b"hello world");
+        assert_eq!(
+            decode("data:text/plain;charset=utf-8;base64,aGVs%0AbG8=").unwrap(),
+            b"hello"
+        );
+        assert_eq!(decode("DATA:;base64,AAEC/w==").unwrap(), [0, 1, 2, 255]);
+        assert!(decode("data:text/plain").is_err());
+        assert!(decode("data:;base64,!!").is_err());
+    }
+}
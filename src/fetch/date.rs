@@ -0,0 +1,121 @@
+//! The little calendar arithmetic request signing and the access log need.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A point in time broken down into its UTC date and time of day.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Utc {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+    /// Days since the last Sunday.
+    pub weekday: u64,
+}
+
+impl From<SystemTime> for Utc {
+    fn from(time: SystemTime) -> Utc {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let days = secs / 86400;
+        let (year, month, day) = civil_from_days(days as i64);
+        Utc {
+            year,
+            month,
+            day,
+            hour: secs % 86400 / 3600,
+            minute: secs % 3600 / 60,
+            second: secs % 60,
+            // 1970-01-01 was a Thursday.
+            weekday: (days + 4) % 7,
+        }
+    }
+}
+
+/// Formats `time` like the HTTP `Date` header, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let t = Utc::from(time);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[t.weekday as usize],
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+/// Formats `time` as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let t = Utc::from(time);
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_millis();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second, millis
+    )
+}
+
+/// Parses `YYYY-MM-DDTHH:MM:SS` timestamps in UTC, ignoring any fraction of
+/// a second and the zone suffix.
+pub fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| text.get(range)?.parse::<i64>().ok();
+    let days = days_from_civil(number(0..4)?, number(5..7)?, number(8..10)?);
+    let secs = days * 86400 + number(11..13)? * 3600 + number(14..16)? * 60 + number(17..19)?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs.try_into().ok()?))
+}
+
+// Howard Hinnant's conversions between days since 1970-01-01 and dates.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{http_date, parse_rfc3339, rfc3339, Utc};
+
+    #[test]
+    fn dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_rfc3339("1994-11-06T08:49:37Z"), Some(time));
+        let later = time + Duration::from_millis(5);
+        assert_eq!(rfc3339(later), "1994-11-06T08:49:37.005Z");
+        assert_eq!(parse_rfc3339("1994-11-06T08:49:37.123+00:00"), Some(time));
+        let leap = Utc::from(parse_rfc3339("2024-02-29T23:59:59Z").unwrap());
+        assert_eq!((leap.month, leap.day, leap.weekday), (2, 29, 4));
+        assert_eq!(parse_rfc3339("yesterday"), None);
+    }
+}
@@ -0,0 +1,60 @@
+//! dav:// and davs:// URLs, WebDAV shares served over http and https.
+
+use curl::easy::Auth as CurlAuth;
+
+use crate::layout::Auth;
+
+use super::{
+    http::{easy, transfer},
+    FetchResult, Fetcher, Request,
+};
+
+pub struct Dav;
+
+impl Fetcher for Dav {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let url = http_url(request.url)?;
+        let mut curl = easy(&Request {
+            url: &url,
+            ..*request
+        })?;
+        // Some servers only offer digest auth.
+        if let Some(Auth::Basic { .. }) = request.auth {
+            let mut auth = CurlAuth::new();
+            auth.basic(true).digest(true);
+            curl.http_auth(&auth)?;
+        }
+        transfer(&mut curl, request.size, range)
+    }
+
+    fn plaintext(&self, url: &str) -> bool {
+        http_url(url).is_ok_and(|url| url.starts_with("http://"))
+    }
+}
+
+/// The http(s) URL of a dav(s) URL.
+pub fn http_url(url: &str) -> crate::Result<String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("{} is not a URL", url))?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "dav" => Ok(format!("http://{}", rest)),
+        "davs" => Ok(format!("https://{}", rest)),
+        _ => Err(format!("{} is not a dav:// or davs:// URL", url).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::http_url;
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            http_url("davs://cloud.example.com/remote.php/dav/files/me/a.bin").unwrap(),
+            "https://cloud.example.com/remote.php/dav/files/me/a.bin"
+        );
+        assert_eq!(http_url("DAV://h/a").unwrap(), "http://h/a");
+        assert!(http_url("https://h/a").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Dropbox share links, which point at a preview page unless told to
+//! download. They are handled by the http backend so the links can be
+//! pasted into layouts as they are.
+
+use url::Url;
+
+use super::{
+    http::{easy, transfer},
+    FetchResult, Request,
+};
+
+/// Whether `url` is a Dropbox file or folder share link.
+pub fn is_share_link(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    matches!(url.host_str(), Some("dropbox.com" | "www.dropbox.com"))
+        && ["/s/", "/sh/", "/scl/fi/", "/scl/fo/"]
+            .iter()
+            .any(|prefix| url.path().starts_with(prefix))
+}
+
+/// `url` with its `dl` and `raw` parameters replaced by `param=1`.
+fn with_param(url: &str, param: &str) -> crate::Result<String> {
+    let mut url = Url::parse(url)?;
+    let pairs = url
+        .query_pairs()
+        .filter(|(key, _)| key != "dl" && key != "raw")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(param, "1");
+    Ok(url.into())
+}
+
+/// Downloads a share link. `dl=1` is tried first, as it also works for
+/// folders, which Dropbox zips on the fly; if that still yields a web
+/// page, the file is asked for with `raw=1` instead.
+pub fn fetch(request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+    for param in ["dl", "raw"] {
+        let url = with_param(request.url, param)?;
+        let mut curl = easy(&Request {
+            url: &url,
+            ..*request
+        })?;
+        let data = transfer(&mut curl, request.size, range)?;
+        if !curl
+            .content_type()?
+            .is_some_and(|t| t.starts_with("text/html"))
+        {
+            return Ok(data);
+        }
+    }
+    Err(format!(
+        "Dropbox answered {} with a web page; the link may have expired or need a password",
+        request.url
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_share_link, with_param};
+
+    #[test]
+    fn share_links() {
+        let link = "https://www.dropbox.com/scl/fi/abc123/data.csv?rlkey=xyz&dl=0";
+        assert!(is_share_link(link));
+        assert!(is_share_link("https://www.dropbox.com/sh/abc/def?dl=0"));
+        assert!(!is_share_link("https://www.dropbox.com/home"));
+        assert!(!is_share_link(
+            "https://dl.dropboxusercontent.com/s/abc/a.csv"
+        ));
+        assert_eq!(
+            with_param(link, "dl").unwrap(),
+            "https://www.dropbox.com/scl/fi/abc123/data.csv?rlkey=xyz&dl=1"
+        );
+        assert_eq!(
+            with_param("https://www.dropbox.com/s/abc/a.csv", "raw").unwrap(),
+            "https://www.dropbox.com/s/abc/a.csv?raw=1"
+        );
+    }
+}
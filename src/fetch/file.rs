@@ -0,0 +1,77 @@
+//! file:// URLs, read straight from the local filesystem.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use url::Url;
+
+use super::{FetchResult, Fetcher, Metadata, Request};
+use crate::LhttpfsError;
+
+pub struct LocalFile;
+
+impl Fetcher for LocalFile {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let path = Url::parse(request.url)?
+            .to_file_path()
+            .map_err(|_| format!("{} is not a local path", request.url))?;
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        match range {
+            Some((start, len)) => {
+                file.seek(SeekFrom::Start(start))?;
+                file.take(len).read_to_end(&mut data)?;
+            }
+            None => {
+                file.read_to_end(&mut data)?;
+            }
+        }
+        Ok(data.into())
+    }
+
+    fn metadata(&self, request: &Request) -> Result<Metadata, LhttpfsError> {
+        let path = Url::parse(request.url)?
+            .to_file_path()
+            .map_err(|_| format!("{} is not a local path", request.url))?;
+        Ok(Metadata {
+            size: Some(std::fs::metadata(path)?.len()),
+            modified: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use url::Url;
+
+    use crate::fetch::{Fetchers, Request};
+
+    #[test]
+    fn local_file() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-file-{}", std::process::id()));
+        std::fs::write(&path, b"hello, world").unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+        let headers = BTreeMap::new();
+        let request = Request {
+            url: url.as_str(),
+            headers: &headers,
+            auth: None,
+            size: 12,
+        };
+        let fetchers = Fetchers::default();
+        assert_eq!(
+            fetchers.fetch_range(&request, None).unwrap(),
+            b"hello, world"
+        );
+        assert_eq!(
+            fetchers.fetch_range(&request, Some((7, 10))).unwrap(),
+            b"world"
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(fetchers.fetch_range(&request, None).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! ftp:// and ftps:// URLs, fetched with curl. Ranges are read with `REST`.
+
+use super::{byte_range, http::easy, perform, scheme, FetchResult, Fetched, Fetcher, Request};
+
+pub struct Ftp;
+
+impl Fetcher for Ftp {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        if let Some((_, 0)) = range {
+            return Ok(Fetched::default());
+        }
+        let mut curl = easy(request)?;
+        if let Some(range) = range.and_then(byte_range) {
+            curl.range(&range)?;
+        }
+        // Unlike HTTP there's no way to tell if a server ignored the range,
+        // but an FTP server that can't `REST` fails the transfer instead.
+        Ok(perform(curl)?.into())
+    }
+
+    /// Only `ftps://` is encrypted: plain `ftp://` isn't upgraded.
+    fn plaintext(&self, url: &str) -> bool {
+        scheme(url).eq_ignore_ascii_case("ftp")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::Ftp;
+    use crate::fetch::{Fetcher, Request};
+
+    const FILE: &[u8] = b"0123456789";
+
+    /// Serves [`FILE`] as `/f` to one client at a time, keeping the
+    /// commands it was sent.
+    fn server(commands: Arc<Mutex<Vec<String>>>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for control in listener.incoming() {
+                let control = control.unwrap();
+                let mut reply = control.try_clone().unwrap();
+                let mut say = |line: &str| write!(reply, "{}\r\n", line).unwrap();
+                say("220 Ready");
+                let (mut data, mut rest) = (None, 0);
+                for line in BufReader::new(control).lines() {
+                    let line = line.unwrap();
+                    commands.lock().unwrap().push(line.clone());
+                    let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+                    match command {
+                        "USER" => say("331 Password"),
+                        "PASS" => say("230 In"),
+                        "PWD" => say("257 \"/\""),
+                        "CWD" | "TYPE" => say("200 OK"),
+                        "SIZE" => say(&format!("213 {}", FILE.len())),
+                        "EPSV" => {
+                            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                            let port = listener.local_addr().unwrap().port();
+                            say(&format!("229 Passive (|||{}|)", port));
+                            data = Some(listener);
+                        }
+                        "REST" => {
+                            rest = argument.parse().unwrap();
+                            say("350 Restarting");
+                        }
+                        "RETR" => {
+                            let (mut stream, _): (TcpStream, _) =
+                                data.take().unwrap().accept().unwrap();
+                            say("150 Sending");
+                            // Clients reading a range hang up early.
+                            let _ = stream.write_all(&FILE[rest..]);
+                            drop(stream);
+                            say("226 Done");
+                            rest = 0;
+                        }
+                        "QUIT" => {
+                            say("221 Bye");
+                            break;
+                        }
+                        _ => say("502 Not implemented"),
+                    }
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    #[ignore = "needs libcurl with ftp"]
+    fn ranges() {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let url = format!("ftp://127.0.0.1:{}/f", server(commands.clone()));
+        let headers = BTreeMap::new();
+        let request = Request {
+            url: &url,
+            headers: &headers,
+            auth: None,
+            size: FILE.len() as u64,
+        };
+        assert_eq!(Ftp.fetch_range(&request, None).unwrap().data, FILE);
+        assert!(!commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.starts_with("REST")));
+        assert_eq!(
+            Ftp.fetch_range(&request, Some((3, 4))).unwrap().data,
+            b"3456"
+        );
+        assert!(commands.lock().unwrap().contains(&"REST 3".to_string()));
+        assert_eq!(
+            Ftp.fetch_range(&request, Some((8, 10))).unwrap().data,
+            b"89"
+        );
+        // Nothing is asked of the server for an empty range.
+        let asked = commands.lock().unwrap().len();
+        assert!(Ftp
+            .fetch_range(&request, Some((3, 0)))
+            .unwrap()
+            .data
+            .is_empty());
+        assert_eq!(commands.lock().unwrap().len(), asked);
+    }
+}
@@ -0,0 +1,318 @@
+//! gs://bucket/object URLs, read through the Cloud Storage JSON API with
+//! application default credentials.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use curl::easy::{Easy, List};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rsa::{
+    pkcs1v15::SigningKey,
+    pkcs8::DecodePrivateKey,
+    signature::{SignatureEncoding, Signer},
+    RsaPrivateKey,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use tracing::debug;
+
+use crate::LhttpfsError;
+
+use super::{date, http::Http, perform, FetchResult, Fetcher, Metadata, Request};
+
+/// Everything but the unreserved characters, so that `/` in object names
+/// is escaped too.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+
+/// The JSON key files `gcloud` and the IAM console hand out.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KeyFile {
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        token_uri: String,
+    },
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+}
+
+#[derive(Clone)]
+struct Token {
+    access_token: String,
+    expires: SystemTime,
+}
+
+#[derive(Default)]
+pub struct Gcs {
+    /// Fetched on first use, `Some(None)` for anonymous access.
+    token: Mutex<Option<Option<Token>>>,
+}
+
+impl Fetcher for Gcs {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let url = format!("{}?alt=media", object_url(request.url)?);
+        Http.fetch_range(
+            &Request {
+                url: &url,
+                headers: &self.headers(request)?,
+                auth: None,
+                size: request.size,
+            },
+            range,
+        )
+    }
+
+    /// The `size` and `updated` of the object's metadata.
+    fn metadata(&self, request: &Request) -> Result<Metadata, LhttpfsError> {
+        let url = object_url(request.url)?;
+        let body = Http.fetch_range(
+            &Request {
+                url: &url,
+                headers: &self.headers(request)?,
+                auth: None,
+                size: 0,
+            },
+            None,
+        )?;
+        let (size, modified) = parse_metadata(&body.data)?;
+        Ok(Metadata {
+            size: Some(size),
+            modified,
+        })
+    }
+
+    fn plaintext(&self, url: &str) -> bool {
+        object_url(url).is_ok_and(|url| url.starts_with("http://"))
+    }
+}
+
+impl Gcs {
+    /// `request`'s headers, with the access token found, if any.
+    fn headers(&self, request: &Request) -> crate::Result<BTreeMap<String, String>> {
+        let mut headers = request.headers.clone();
+        if let Some(token) = self.token()? {
+            headers.insert(
+                "Authorization".into(),
+                format!("Bearer {}", token.access_token),
+            );
+        }
+        Ok(headers)
+    }
+
+    fn token(&self) -> crate::Result<Option<Token>> {
+        let mut cached = self.token.lock().unwrap();
+        let expired = |t: &Option<Token>| {
+            t.as_ref()
+                .is_some_and(|t| t.expires < SystemTime::now() + Duration::from_secs(60))
+        };
+        if cached.as_ref().is_none_or(expired) {
+            let token = load_token()?;
+            if token.is_none() {
+                debug!("No Google credentials found, accessing Cloud Storage anonymously");
+            }
+            *cached = Some(token);
+        }
+        Ok(cached.clone().flatten())
+    }
+}
+
+/// The JSON API URL of `gs://bucket/object`'s metadata, and of its bytes
+/// with `alt=media`. `STORAGE_EMULATOR_HOST` replaces the API host, as in
+/// Google's own client libraries.
+fn object_url(url: &str) -> crate::Result<String> {
+    let (bucket, object) = url
+        .strip_prefix("gs://")
+        .and_then(|rest| rest.split_once('/'))
+        .ok_or_else(|| format!("{} is not a gs://bucket/object URL", url))?;
+    let object = percent_decode_str(object).decode_utf8()?;
+    let host = std::env::var("STORAGE_EMULATOR_HOST")
+        .ok()
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "https://storage.googleapis.com".into());
+    Ok(format!(
+        "{}/storage/v1/b/{}/o/{}",
+        host.trim_end_matches('/'),
+        utf8_percent_encode(bucket, COMPONENT),
+        utf8_percent_encode(&object, COMPONENT)
+    ))
+}
+
+fn key_file() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+        return Some(path.into());
+    }
+    let config = std::env::var_os("CLOUDSDK_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config/gcloud")))?;
+    Some(config.join("application_default_credentials.json")).filter(|path| path.exists())
+}
+
+/// Gets an access token like Google's libraries look for application
+/// default credentials: `GOOGLE_APPLICATION_CREDENTIALS`, the file written
+/// by `gcloud auth application-default login`, then the metadata server.
+fn load_token() -> crate::Result<Option<Token>> {
+    if let Some(path) = key_file() {
+        let key: KeyFile = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let form = match key {
+            KeyFile::ServiceAccount {
+                client_email,
+                private_key,
+                token_uri,
+            } => {
+                let assertion = jwt(&client_email, &private_key, &token_uri, SystemTime::now())?;
+                let form = form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", &assertion),
+                ]);
+                (token_uri, form)
+            }
+            KeyFile::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+            } => {
+                let form = form(&[
+                    ("grant_type", "refresh_token"),
+                    ("client_id", &client_id),
+                    ("client_secret", &client_secret),
+                    ("refresh_token", &refresh_token),
+                ]);
+                ("https://oauth2.googleapis.com/token".into(), form)
+            }
+        };
+        let mut curl = Easy::new();
+        curl.url(&form.0)?;
+        curl.post(true)?;
+        curl.post_fields_copy(form.1.as_bytes())?;
+        return Ok(Some(parse_token(&perform(curl)?)?));
+    }
+    let host = std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| "169.254.169.254".into());
+    let mut curl = Easy::new();
+    curl.url(&format!(
+        "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+        host
+    ))?;
+    curl.connect_timeout(Duration::from_secs(1))?;
+    let mut list = List::new();
+    list.append("Metadata-Flavor: Google")?;
+    curl.http_headers(list)?;
+    // Not being on Google Cloud just means there are no credentials.
+    match perform(curl) {
+        Ok(body) => Ok(Some(parse_token(&body)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The size of an object and when it was last updated, from its metadata.
+fn parse_metadata(body: &[u8]) -> crate::Result<(u64, Option<SystemTime>)> {
+    // Sizes are strings, since they may not fit in a JavaScript number.
+    #[derive(Deserialize)]
+    struct Metadata {
+        size: String,
+        updated: Option<String>,
+    }
+    let metadata: Metadata = serde_json::from_slice(body)?;
+    let updated = metadata.updated.as_deref().and_then(date::parse_rfc3339);
+    Ok((metadata.size.parse()?, updated))
+}
+
+fn form(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, COMPONENT)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn parse_token(body: &[u8]) -> crate::Result<Token> {
+    #[derive(Deserialize)]
+    struct Response {
+        access_token: String,
+        expires_in: u64,
+    }
+    let response: Response = serde_json::from_slice(body)?;
+    Ok(Token {
+        access_token: response.access_token,
+        expires: SystemTime::now() + Duration::from_secs(response.expires_in),
+    })
+}
+
+/// The signed assertion a service account trades for an access token.
+fn jwt(email: &str, private_key: &str, audience: &str, now: SystemTime) -> crate::Result<String> {
+    let iat = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let header = json!({"alg": "RS256", "typ": "JWT"});
+    let claims = json!({
+        "iss": email,
+        "scope": SCOPE,
+        "aud": audience,
+        "iat": iat,
+        "exp": iat + 3600,
+    });
+    let message = format!(
+        "{}.{}",
+        BASE64URL.encode(header.to_string()),
+        BASE64URL.encode(claims.to_string())
+    );
+    let key = SigningKey::<Sha256>::new(RsaPrivateKey::from_pkcs8_pem(private_key)?);
+    let signature = key.sign(message.as_bytes()).to_bytes();
+    Ok(format!("{}.{}", message, BASE64URL.encode(signature)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{form, object_url, parse_metadata, KeyFile};
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            object_url("gs://bucket/dir/a b.bin").unwrap(),
+            "https://storage.googleapis.com/storage/v1/b/bucket/o/dir%2Fa%20b.bin"
+        );
+        assert!(object_url("gs://bucket").is_err());
+        assert_eq!(form(&[("a", "b c"), ("d", "e")]), "a=b%20c&d=e");
+    }
+
+    #[test]
+    fn metadata() {
+        let body = br#"{"kind": "storage#object", "name": "dir/a.bin", "size": "5368709120",
+            "updated": "2024-03-01T12:00:00.000Z", "md5Hash": "1B2M2Y8AsgTpgAmY7PhCfg=="}"#;
+        let (size, updated) = parse_metadata(body).unwrap();
+        assert_eq!(size, 5368709120);
+        assert_eq!(updated, Some(UNIX_EPOCH + Duration::from_secs(1709294400)));
+        assert!(parse_metadata(br#"{"size": 5}"#).is_err());
+    }
+
+    #[test]
+    fn key_files() {
+        let user = r#"{"type": "authorized_user", "client_id": "id",
+            "client_secret": "secret", "refresh_token": "refresh"}"#;
+        assert_eq!(
+            serde_json::from_str::<KeyFile>(user).unwrap(),
+            KeyFile::AuthorizedUser {
+                client_id: "id".into(),
+                client_secret: "secret".into(),
+                refresh_token: "refresh".into()
+            }
+        );
+        assert!(serde_json::from_str::<KeyFile>(r#"{"type": "external_account"}"#).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! `gdrive://<file id>` URLs, files shared on Google Drive.
+//!
+//! With an OAuth token or an API key, files are read through the Drive
+//! API. Without either, the public download endpoint is used, which
+//! answers large files with a "can't scan for viruses" page first; the
+//! confirmation it asks for is sent back automatically.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::layout::Auth;
+
+use super::{
+    cut,
+    http::{easy, transfer},
+    FetchResult, Fetcher, Request,
+};
+
+#[derive(Default)]
+pub struct GDrive {
+    /// The download form's fields by file id, once a warning was answered.
+    confirmations: Mutex<HashMap<String, Vec<(String, String)>>>,
+}
+
+impl Fetcher for GDrive {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let file = parse(request.url)?;
+        let token = match request.auth {
+            Some(Auth::Bearer(token)) => Some(token.clone()),
+            _ => env("GOOGLE_DRIVE_TOKEN"),
+        };
+        let key = env("GOOGLE_DRIVE_API_KEY");
+        if token.is_some() || key.is_some() {
+            let mut headers = request.headers.clone();
+            if let Some(token) = token {
+                headers.insert("Authorization".into(), format!("Bearer {}", token));
+            }
+            let url = file.api_url(key.as_deref());
+            let mut curl = easy(&Request {
+                url: &url,
+                headers: &headers,
+                auth: None,
+                ..*request
+            })?;
+            return match file.export {
+                // Exports are generated on the fly and can't be ranged.
+                Some(_) => Ok(cut(transfer(&mut curl, request.size, None)?.data, range).into()),
+                None => transfer(&mut curl, request.size, range),
+            };
+        }
+        if file.export.is_some() {
+            return Err(
+                "Exporting Google Docs needs GOOGLE_DRIVE_TOKEN or GOOGLE_DRIVE_API_KEY".into(),
+            );
+        }
+        let cached = self.confirmations.lock().unwrap().get(file.id).cloned();
+        let mut fields = cached.unwrap_or_default();
+        // At most one confirmation page is expected.
+        for _ in 0..2 {
+            let url = file.download_url(&fields);
+            let mut curl = easy(&Request {
+                url: &url,
+                ..*request
+            })?;
+            let data = transfer(&mut curl, request.size, range)?;
+            if !curl
+                .content_type()?
+                .is_some_and(|t| t.starts_with("text/html"))
+            {
+                return Ok(data);
+            }
+            // The warning page ignores ranges, so it is fetched whole to
+            // find the form in it.
+            let page = match range {
+                Some(_) => {
+                    let mut curl = easy(&Request {
+                        url: &url,
+                        ..*request
+                    })?;
+                    transfer(&mut curl, 0, None)?.data
+                }
+                None => data.data.clone(),
+            };
+            match form_fields(&String::from_utf8_lossy(&page)) {
+                Some(confirmed) => fields = confirmed,
+                None => return Ok(data),
+            }
+            self.confirmations
+                .lock()
+                .unwrap()
+                .insert(file.id.to_string(), fields.clone());
+        }
+        Err(format!(
+            "Google Drive kept asking to confirm downloading {}",
+            file.id
+        )
+        .into())
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct DriveFile<'a> {
+    id: &'a str,
+    /// The MIME type to export a Google Doc, Sheet or Slides file as.
+    export: Option<&'a str>,
+}
+
+impl DriveFile<'_> {
+    fn api_url(&self, key: Option<&str>) -> String {
+        let id = utf8_percent_encode(self.id, NON_ALPHANUMERIC);
+        let mut url = match self.export {
+            Some(mime) => format!(
+                "https://www.googleapis.com/drive/v3/files/{}/export?mimeType={}",
+                id,
+                utf8_percent_encode(mime, NON_ALPHANUMERIC)
+            ),
+            None => format!(
+                "https://www.googleapis.com/drive/v3/files/{}?alt=media&supportsAllDrives=true",
+                id
+            ),
+        };
+        if let Some(key) = key {
+            url.push_str(&format!(
+                "&key={}",
+                utf8_percent_encode(key, NON_ALPHANUMERIC)
+            ));
+        }
+        url
+    }
+
+    fn download_url(&self, fields: &[(String, String)]) -> String {
+        let mut url = format!(
+            "https://drive.usercontent.google.com/download?id={}&export=download",
+            utf8_percent_encode(self.id, NON_ALPHANUMERIC)
+        );
+        for (name, value) in fields {
+            if name != "id" && name != "export" {
+                url.push_str(&format!(
+                    "&{}={}",
+                    utf8_percent_encode(name, NON_ALPHANUMERIC),
+                    utf8_percent_encode(value, NON_ALPHANUMERIC)
+                ));
+            }
+        }
+        url
+    }
+}
+
+/// Accepts `gdrive://<id>`, optionally with `?export=<mime type>`.
+fn parse(url: &str) -> crate::Result<DriveFile<'_>> {
+    let rest = url
+        .strip_prefix("gdrive://")
+        .ok_or_else(|| format!("{} is not a gdrive://<file id> URL", url))?;
+    let (id, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let id = id.trim_end_matches('/');
+    if id.is_empty() || id.contains('/') {
+        return Err(format!("{} is not a gdrive://<file id> URL", url).into());
+    }
+    let export = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("export="))
+        .filter(|mime| !mime.is_empty());
+    Ok(DriveFile { id, export })
+}
+
+/// The hidden fields of the virus scan warning's download form, if
+/// `html` is that page.
+fn form_fields(html: &str) -> Option<Vec<(String, String)>> {
+    let form = html.find("id=\"download-form\"")?;
+    let form = &html[form..html[form..]
+        .find("</form>")
+        .map_or(html.len(), |end| form + end)];
+    let attribute = |tag: &str, name: &str| {
+        let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+        let end = tag[start..].find('"')? + start;
+        Some(tag[start..end].replace("&amp;", "&"))
+    };
+    let fields = form
+        .split("<input")
+        .skip(1)
+        .filter(|tag| tag.contains("type=\"hidden\""))
+        .filter_map(|tag| Some((attribute(tag, "name")?, attribute(tag, "value")?)))
+        .collect::<Vec<_>>();
+    Some(fields).filter(|fields| !fields.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{form_fields, parse, DriveFile};
+
+    #[test]
+    fn urls() {
+        let file = parse("gdrive://1AbC_d-E").unwrap();
+        assert_eq!(
+            file,
+            DriveFile {
+                id: "1AbC_d-E",
+                export: None
+            }
+        );
+        assert_eq!(
+            file.api_url(Some("k")),
+            "https://www.googleapis.com/drive/v3/files/1AbC%5Fd%2DE?alt=media&supportsAllDrives=true&key=k"
+        );
+        let doc = parse("gdrive://1xyz?export=application/pdf").unwrap();
+        assert_eq!(doc.export, Some("application/pdf"));
+        assert!(parse("gdrive://folder/file").is_err());
+        assert!(parse("https://drive.google.com/file/d/1xyz").is_err());
+    }
+
+    #[test]
+    fn virus_scan_warning() {
+        let html = r#"<html><body><p>Google Drive can't scan this file for viruses.</p>
+<form id="download-form" action="https://drive.usercontent.google.com/download" method="get">
+<input type="submit" id="uc-download-link" value="Download anyway"/>
+<input type="hidden" name="id" value="1xyz"><input type="hidden" name="export" value="download">
+<input type="hidden" name="confirm" value="t"><input type="hidden" name="uuid" value="0a-1b"></form>
+</body></html>"#;
+        let fields = form_fields(html).unwrap();
+        assert_eq!(fields.len(), 4);
+        let file = parse("gdrive://1xyz").unwrap();
+        assert_eq!(
+            file.download_url(&fields),
+            "https://drive.usercontent.google.com/download?id=1xyz&export=download&confirm=t&uuid=0a%2D1b"
+        );
+        assert_eq!(form_fields("<html>a file that is a web page</html>"), None);
+    }
+}
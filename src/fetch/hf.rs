@@ -0,0 +1,159 @@
+//! hf://owner/repo/path URLs, files of Hugging Face Hub repositories.
+//!
+//! Files are read through the `resolve/` endpoint, which redirects large
+//! (LFS) files to signed CDN URLs. Those are only kept for a few minutes,
+//! so a layout never depends on one.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{
+    http::{easy, transfer},
+    FetchResult, Fetcher, Request,
+};
+
+/// How long a redirect target is reused before asking the Hub again.
+const LOCATION_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+pub struct Hf {
+    /// Where `resolve/` URLs last redirected to, and when.
+    locations: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Fetcher for Hf {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let endpoint = endpoint();
+        let url = resolve_url(&endpoint, request.url)?;
+        let mut headers = request.headers.clone();
+        if request.auth.is_none() {
+            if let Some(token) = token() {
+                headers.insert("Authorization".into(), format!("Bearer {}", token));
+            }
+        }
+        let cached = self
+            .locations
+            .lock()
+            .unwrap()
+            .get(&url)
+            .filter(|(_, at)| at.elapsed() < LOCATION_TTL)
+            .map(|(location, _)| location.clone());
+        if let Some(location) = cached {
+            let no_headers = Default::default();
+            let mut curl = easy(&Request {
+                url: &location,
+                headers: &no_headers,
+                auth: None,
+                size: request.size,
+            })?;
+            match transfer(&mut curl, request.size, range) {
+                Ok(data) => return Ok(data),
+                Err(_) => {
+                    self.locations.lock().unwrap().remove(&url);
+                }
+            }
+        }
+        let mut curl = easy(&Request {
+            url: &url,
+            headers: &headers,
+            ..*request
+        })?;
+        // The Authorization header isn't sent on to the CDN.
+        let data = transfer(&mut curl, request.size, range)?;
+        // Only CDN links are kept; they need no credentials.
+        let location = curl.effective_url()?;
+        if let Some(location) = location.filter(|location| !location.starts_with(&endpoint)) {
+            let location = location.to_string();
+            self.locations
+                .lock()
+                .unwrap()
+                .insert(url, (location, Instant::now()));
+        }
+        Ok(data)
+    }
+
+    fn plaintext(&self, _: &str) -> bool {
+        endpoint().starts_with("http://")
+    }
+}
+
+fn endpoint() -> String {
+    std::env::var("HF_ENDPOINT")
+        .ok()
+        .filter(|e| !e.is_empty())
+        .unwrap_or_else(|| "https://huggingface.co".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// The Hub token, from `HF_TOKEN` or the file `huggingface-cli login`
+/// writes.
+fn token() -> Option<String> {
+    if let Some(token) = std::env::var("HF_TOKEN").ok().filter(|t| !t.is_empty()) {
+        return Some(token);
+    }
+    let path = match std::env::var_os("HF_TOKEN_PATH") {
+        Some(path) => PathBuf::from(path),
+        None => match std::env::var_os("HF_HOME") {
+            Some(home) => PathBuf::from(home).join("token"),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".cache/huggingface/token"),
+        },
+    };
+    let token = std::fs::read_to_string(path).ok()?;
+    Some(token.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/// Turns `hf://[datasets/|spaces/]owner/repo[@revision]/path` into its
+/// `resolve/` URL on `endpoint`.
+fn resolve_url(endpoint: &str, url: &str) -> crate::Result<String> {
+    let bad = || format!("{} is not an hf://owner/repo/path URL", url);
+    let rest = url.strip_prefix("hf://").ok_or_else(bad)?;
+    let (kind, rest) = match rest.split_once('/') {
+        Some((kind @ ("datasets" | "spaces"), rest)) => (Some(kind), rest),
+        _ => (None, rest),
+    };
+    let mut parts = rest.splitn(3, '/');
+    let (Some(owner), Some(repo), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(bad().into());
+    };
+    // Revisions with slashes, such as `refs/pr/1`, are given
+    // percent-encoded.
+    let (repo, revision) = match repo.split_once('@') {
+        Some((repo, revision)) => (repo, revision),
+        None => (repo, "main"),
+    };
+    if owner.is_empty() || repo.is_empty() || path.is_empty() {
+        return Err(bad().into());
+    }
+    Ok(match kind {
+        Some(kind) => format!("{endpoint}/{kind}/{owner}/{repo}/resolve/{revision}/{path}"),
+        None => format!("{endpoint}/{owner}/{repo}/resolve/{revision}/{path}"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::resolve_url;
+
+    #[test]
+    fn urls() {
+        let hub = "https://huggingface.co";
+        assert_eq!(
+            resolve_url(hub, "hf://openai-community/gpt2/onnx/model.onnx").unwrap(),
+            "https://huggingface.co/openai-community/gpt2/resolve/main/onnx/model.onnx"
+        );
+        assert_eq!(
+            resolve_url(hub, "hf://datasets/owner/data@v1.0/train.parquet").unwrap(),
+            "https://huggingface.co/datasets/owner/data/resolve/v1.0/train.parquet"
+        );
+        assert_eq!(
+            resolve_url("http://localhost", "hf://owner/model@refs%2Fpr%2F1/a.bin").unwrap(),
+            "http://localhost/owner/model/resolve/refs%2Fpr%2F1/a.bin"
+        );
+        assert!(resolve_url(hub, "hf://gpt2/config.json").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! [`HostPolicy`]: the hosts a mount may fetch from, given by
+//! `--allow-host` and `--deny-host`, checked before every fetch so a
+//! layout from anyone can only reach origins its administrator approved.
+
+use std::{
+    error::Error,
+    fmt::Display,
+    net::{IpAddr, ToSocketAddrs},
+};
+
+use globset::{GlobBuilder, GlobMatcher};
+use url::{Host, Url};
+
+/// A host name glob, such as `*.example.com`, or a network, such as
+/// `10.0.0.0/8` or a single address.
+#[derive(Debug, Clone)]
+enum Pattern {
+    Name(GlobMatcher),
+    Net(IpAddr, u8),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Pattern, BadHostPattern> {
+        let bad = |reason: String| BadHostPattern(pattern.to_string(), reason);
+        let (addr, prefix) = pattern.split_once('/').unwrap_or((pattern, ""));
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(addr) = addr.parse::<IpAddr>() {
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                "" => max,
+                prefix => prefix.parse().map_err(|e| bad(format!("{}", e)))?,
+            };
+            if prefix > max {
+                return Err(bad(format!("the prefix is longer than {} bits", max)));
+            }
+            return Ok(Pattern::Net(addr, prefix));
+        }
+        let glob = GlobBuilder::new(pattern).case_insensitive(true).build();
+        Ok(Pattern::Name(
+            glob.map_err(|e| bad(e.kind().to_string()))?
+                .compile_matcher(),
+        ))
+    }
+
+    /// Whether the host named `name`, at `addrs`, matches.
+    fn matches(&self, name: &str, addrs: &[IpAddr]) -> bool {
+        match self {
+            Pattern::Name(glob) => glob.is_match(name),
+            Pattern::Net(net, prefix) => addrs.iter().any(|addr| within(addr, net, *prefix)),
+        }
+    }
+}
+
+/// Whether `addr` is in the network of the first `prefix` bits of `net`.
+fn within(addr: &IpAddr, net: &IpAddr, prefix: u8) -> bool {
+    let (addr, net, bits) = match (addr, net) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => {
+            (u32::from(*addr).into(), u32::from(*net).into(), 32)
+        }
+        (IpAddr::V6(addr), IpAddr::V6(net)) => (u128::from(*addr), u128::from(*net), 128),
+        _ => return false,
+    };
+    // Shifting out all 128 bits, for a /0, leaves nothing to compare.
+    let shift = bits - prefix as u32;
+    addr.checked_shr(shift).unwrap_or(0) == net.checked_shr(shift).unwrap_or(0)
+}
+
+/// A pattern given to `--allow-host` or `--deny-host` that is neither a
+/// glob nor a network.
+#[derive(Debug)]
+pub struct BadHostPattern(String, String);
+
+impl Display for BadHostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid host pattern {:?}: {}", self.0, self.1)
+    }
+}
+
+impl Error for BadHostPattern {}
+
+/// A fetch [`HostPolicy`] refused, before it was made.
+#[derive(Debug)]
+pub struct HostRefused(String);
+
+impl Display for HostRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} isn't one of the hosts --allow-host and --deny-host allow",
+            self.0
+        )
+    }
+}
+
+impl Error for HostRefused {}
+
+/// Hosts to fetch from. A host is refused if it matches a `deny` pattern,
+/// or there are `allow` patterns and it matches none of them. Names are
+/// resolved to be matched against networks: a host is denied if any of
+/// its addresses is, and only allowed by a network holding all of them.
+/// URLs without a host, such as `file://` ones, match no pattern.
+#[derive(Debug, Clone, Default)]
+pub struct HostPolicy {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl HostPolicy {
+    pub fn new<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Result<HostPolicy, BadHostPattern> {
+        let parse = |patterns: &[S]| {
+            (patterns.iter())
+                .map(|pattern| Pattern::parse(pattern.as_ref()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(HostPolicy {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    /// Fails unless `url` may be fetched.
+    pub fn check(&self, url: &str) -> Result<(), HostRefused> {
+        self.addresses(url).map(drop)
+    }
+
+    /// Fails unless `url` may be fetched, or returns the addresses its host
+    /// was allowed at: none unless its name had to be looked up to be
+    /// matched against networks. A fetch connecting to only these can't be
+    /// led elsewhere by the name resolving differently the next time.
+    pub fn addresses(&self, url: &str) -> Result<Vec<IpAddr>, HostRefused> {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Ok(Vec::new());
+        }
+        let refused = || HostRefused(url.to_string());
+        let parsed = Url::parse(url).ok();
+        let (name, addrs) = match parsed.as_ref().and_then(Url::host) {
+            Some(Host::Ipv4(addr)) => (addr.to_string(), vec![IpAddr::V4(addr)]),
+            Some(Host::Ipv6(addr)) => (addr.to_string(), vec![IpAddr::V6(addr)]),
+            Some(Host::Domain(name)) if !name.is_empty() => {
+                let networks = (self.allow.iter().chain(&self.deny))
+                    .any(|pattern| matches!(pattern, Pattern::Net(..)));
+                let port = parsed.as_ref().and_then(Url::port_or_known_default);
+                let resolved = match networks {
+                    true => (name, port.unwrap_or(0)).to_socket_addrs().ok(),
+                    false => None,
+                };
+                let addrs = resolved.into_iter().flatten().map(|addr| addr.ip());
+                (name.to_string(), addrs.collect())
+            }
+            _ => {
+                return if self.allow.is_empty() {
+                    Ok(Vec::new())
+                } else {
+                    Err(refused())
+                }
+            }
+        };
+        if self
+            .deny
+            .iter()
+            .any(|pattern| pattern.matches(&name, &addrs))
+        {
+            return Err(refused());
+        }
+        let allowed = |pattern: &Pattern| match pattern {
+            Pattern::Net(..) => {
+                !addrs.is_empty() && addrs.iter().all(|addr| pattern.matches("", &[*addr]))
+            }
+            Pattern::Name(_) => pattern.matches(&name, &addrs),
+        };
+        if !self.allow.is_empty() && !self.allow.iter().any(allowed) {
+            return Err(refused());
+        }
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HostPolicy;
+
+    #[test]
+    fn globs() {
+        let policy =
+            HostPolicy::new(&["*.example.com", "example.com"], &["private.example.com"]).unwrap();
+        assert!(policy.check("https://example.com/a").is_ok());
+        assert!(policy.check("https://CDN.Example.com/a").is_ok());
+        assert!(policy.check("s3://bucket/a").is_err());
+        assert!(policy.check("https://private.example.com/a").is_err());
+        assert!(policy.check("https://example.org/a").is_err());
+        // No host to allow.
+        assert!(policy.check("file:///etc/passwd").is_err());
+        let open = HostPolicy::default();
+        assert!(open.check("file:///etc/passwd").is_ok());
+        let error = policy.check("https://example.org/a").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "https://example.org/a isn't one of the hosts --allow-host and --deny-host allow"
+        );
+    }
+
+    #[test]
+    fn networks() {
+        let policy =
+            HostPolicy::new(&[] as &[&str], &["10.0.0.0/8", "169.254.169.254", "::1"]).unwrap();
+        assert!(policy.check("http://10.1.2.3/a").is_err());
+        assert_eq!(
+            policy
+                .addresses("http://10.1.2.4:8080/a")
+                .unwrap_err()
+                .to_string(),
+            policy
+                .check("http://10.1.2.4:8080/a")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            policy.addresses("http://192.0.2.1/a").unwrap(),
+            ["192.0.2.1".parse::<std::net::IpAddr>().unwrap()]
+        );
+        assert!(policy
+            .check("http://169.254.169.254/latest/meta-data")
+            .is_err());
+        assert!(policy.check("http://[::1]:8080/a").is_err());
+        assert!(policy.check("http://11.0.0.1/a").is_ok());
+        // Names are resolved.
+        let local = HostPolicy::new(&[] as &[&str], &["127.0.0.0/8"]).unwrap();
+        assert!(local.check("http://localhost/a").is_err());
+        let allowed = HostPolicy::new(&["192.168.0.0/16"], &[]).unwrap();
+        assert!(allowed.check("http://192.168.1.10/a").is_ok());
+        assert!(allowed.check("http://192.169.1.10/a").is_err());
+        assert!(HostPolicy::new(&["10.0.0.0/33"], &[]).is_err());
+        assert!(HostPolicy::new(&["a[b"], &[]).is_err());
+    }
+}
@@ -0,0 +1,625 @@
+//! http(s) URLs, fetched with curl.
+
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt::Display,
+    io,
+    net::IpAddr,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use curl::easy::{Auth as CurlAuth, Easy, List};
+use url::{Host, Url};
+
+use crate::{layout::Auth, LhttpfsError};
+
+use super::{
+    admitted, byte_range, cancelled, dropbox, https_only, scheme, FetchResult, Fetched, Fetcher,
+    HttpStatus, Metadata, NotModified, Request, Validators,
+};
+
+pub struct Http;
+
+thread_local! {
+    /// The curl handle of the last fetch on this thread, kept for the next
+    /// one. Resetting a handle keeps its connections, so reads one after
+    /// the other from the same server go over one kept-alive connection
+    /// instead of each opening its own.
+    static HANDLE: RefCell<Option<Easy>> = const { RefCell::new(None) };
+}
+
+impl Fetcher for Http {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        if dropbox::is_share_link(request.url) {
+            return dropbox::fetch(request, range);
+        }
+        reusing(request, |curl| transfer(curl, request.size, range))
+    }
+
+    /// The `Content-Length` and `Last-Modified` of a HEAD request,
+    /// following redirects.
+    fn metadata(&self, request: &Request) -> Result<Metadata, crate::LhttpfsError> {
+        // Their HEAD describes the page showing the file, not the file.
+        if dropbox::is_share_link(request.url) {
+            return Ok(Metadata::default());
+        }
+        reusing(request, |curl| {
+            curl.nobody(true)?;
+            curl.fail_on_error(true)?;
+            curl.fetch_filetime(true)?;
+            abortable(curl)?;
+            following(curl, |curl| {
+                Ok(curl.perform().map_err(|e| status_error(curl, e))?)
+            })?;
+            let length = curl.content_length_download()?;
+            let secs = curl.filetime()?.and_then(|secs| u64::try_from(secs).ok());
+            Ok(Metadata {
+                size: (length >= 0.0).then_some(length as u64),
+                modified: secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            })
+        })
+    }
+
+    fn plaintext(&self, url: &str) -> bool {
+        scheme(url).eq_ignore_ascii_case("http")
+    }
+}
+
+/// Runs `f` with this thread's curl handle, set up for `request` as
+/// [`easy`] would, and keeps the handle for the next fetch.
+fn reusing<T>(
+    request: &Request,
+    f: impl FnOnce(&mut Easy) -> Result<T, crate::LhttpfsError>,
+) -> Result<T, crate::LhttpfsError> {
+    let kept = HANDLE.with(|handle| handle.borrow_mut().take());
+    let mut curl = match kept {
+        Some(mut curl) => {
+            curl.reset();
+            curl
+        }
+        None => Easy::new(),
+    };
+    set_up(&mut curl, request)?;
+    let result = f(&mut curl);
+    HANDLE.with(|handle| *handle.borrow_mut() = Some(curl));
+    result
+}
+
+/// Performs `curl` for a body of `size` bytes, of which only `range` is
+/// requested when given.
+pub fn transfer(curl: &mut Easy, size: u64, range: Option<(u64, u64)>) -> FetchResult {
+    transfer_forwarding(curl, size, range, |_| false)
+}
+
+/// [`transfer`], sending the headers and credentials on to redirects to
+/// other origins too when `forward` says so of them.
+pub fn transfer_forwarding(
+    curl: &mut Easy,
+    size: u64,
+    range: Option<(u64, u64)>,
+    forward: impl Fn(&Url) -> bool,
+) -> FetchResult {
+    if let Some((_, 0)) = range {
+        return Ok(Fetched::default());
+    }
+    curl.fail_on_error(true)?;
+    abortable(curl)?;
+    if let Some(range) = range.and_then(byte_range) {
+        curl.range(&range)?;
+    }
+    let fetched = follow(curl, &forward, |curl| transfer_once(curl, size, range))?;
+    // Such as a redirect without a Location to follow.
+    let status = curl.response_code()?;
+    if !(200..300).contains(&status) {
+        let url = curl.effective_url()?.unwrap_or_default().to_string();
+        return Err(LhttpfsError::Other(Box::new(HttpStatus { url, status })));
+    }
+    Ok(fetched)
+}
+
+/// Performs `curl` for [`transfer`], without following a redirect.
+fn transfer_once(curl: &mut Easy, size: u64, range: Option<(u64, u64)>) -> FetchResult {
+    let expected = range.map_or(size, |(_, len)| len);
+    let mut vec = Vec::with_capacity(expected.min(super::CAPACITY_MAX) as usize);
+    // Servers that ignore the range send the whole body instead, answering
+    // without a Content-Range: only what is in range is kept of it, and the
+    // rest isn't waited for, so a block of a huge file is never all of it.
+    let partial = Cell::new(false);
+    // The length of the whole body, as the Content-Range gives it.
+    let total = Cell::new(None::<u64>);
+    let validators = RefCell::new(Validators::default());
+    let (mut passed, mut stopped) = (0, false);
+    let performed = {
+        let mut transaction = curl.transfer();
+        transaction.header_function(|header| {
+            let header = String::from_utf8_lossy(header);
+            match header.split_once(':') {
+                // Each response, after an interim one, has its own.
+                _ if header.starts_with("HTTP/") => {
+                    partial.set(false);
+                    total.set(None);
+                    *validators.borrow_mut() = Validators::default();
+                }
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("content-range") => {
+                    partial.set(true);
+                    total.set(
+                        value
+                            .rsplit_once('/')
+                            .and_then(|(_, total)| total.trim().parse().ok()),
+                    );
+                }
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("etag") => {
+                    validators.borrow_mut().etag = Some(value.trim().to_string())
+                }
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("last-modified") => {
+                    validators.borrow_mut().last_modified = Some(value.trim().to_string())
+                }
+                _ => {}
+            }
+            true
+        })?;
+        transaction.write_function(|data| {
+            let Some((start, len)) = range.filter(|_| !partial.get()) else {
+                vec.extend(data);
+                return Ok(data.len());
+            };
+            let (at, end) = (passed, start.saturating_add(len));
+            passed += data.len() as u64;
+            let within = |offset: u64| offset.saturating_sub(at).min(data.len() as u64) as usize;
+            vec.extend(&data[within(start)..within(end)]);
+            if passed >= end {
+                stopped = true;
+                // Fewer bytes than given aborts the transfer.
+                return Ok(0);
+            }
+            Ok(data.len())
+        })?;
+        transaction.perform()
+    };
+    match performed {
+        Err(e) if stopped && e.is_write_error() => {}
+        performed => performed.map_err(|e| status_error(curl, e))?,
+    }
+    if curl.response_code()? == 304 {
+        return Err(crate::LhttpfsError::Other(Box::new(NotModified)));
+    }
+    // A server saying there is more than it sent of the range stopped short.
+    if let (Some((start, len)), Some(total)) = (range, total.get()) {
+        let expected = len.min(total.saturating_sub(start));
+        if (vec.len() as u64) < expected {
+            return Err(LhttpfsError::Other(Box::new(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Got {} of the {} bytes asked for", vec.len(), expected),
+            ))));
+        }
+    }
+    let validators = validators.into_inner();
+    Ok(Fetched {
+        data: vec,
+        validators: (!validators.is_empty()).then_some(validators),
+    })
+}
+
+/// Has `curl` abort once the fetch it is performed for is
+/// [`cancelled`](super::cancelled).
+pub fn abortable(curl: &mut Easy) -> Result<(), curl::Error> {
+    curl.progress(true)?;
+    curl.progress_function(|_, _, _, _| !cancelled())
+}
+
+/// `error`, from performing `curl`, as an [`HttpStatus`] if the server
+/// answered with an error status.
+pub fn status_error(curl: &mut Easy, error: curl::Error) -> Box<dyn Error> {
+    if !error.is_http_returned_error() {
+        return Box::new(error);
+    }
+    let url = curl.effective_url().ok().flatten().map(str::to_string);
+    match (url, curl.response_code()) {
+        (Some(url), Ok(status)) => Box::new(HttpStatus { url, status }),
+        _ => Box::new(error),
+    }
+}
+
+/// How many redirects [`following`] follows before giving up.
+const MAX_REDIRECTS: u32 = 10;
+
+/// Performs `curl` with `perform`, and again for every URL the response
+/// redirects to, rather than having curl follow redirects: each is [`aim`]ed
+/// at as the first was, and only sent the first's headers and credentials
+/// if it has the same origin. Returns what the last one performed.
+pub fn following<T>(
+    curl: &mut Easy,
+    perform: impl FnMut(&mut Easy) -> Result<T, LhttpfsError>,
+) -> Result<T, LhttpfsError> {
+    follow(curl, &|_| false, perform)
+}
+
+/// [`following`], also sending the headers and credentials on to the
+/// other origins `forward` says to.
+fn follow<T>(
+    curl: &mut Easy,
+    forward: &dyn Fn(&Url) -> bool,
+    mut perform: impl FnMut(&mut Easy) -> Result<T, LhttpfsError>,
+) -> Result<T, LhttpfsError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let performed = perform(curl)?;
+        let status = curl.response_code()?;
+        let next = match curl.redirect_url()? {
+            Some(next) if (300..400).contains(&status) => next.to_string(),
+            _ => return Ok(performed),
+        };
+        let from = Url::parse(curl.effective_url()?.unwrap_or_default()).ok();
+        let kept = match (from, Url::parse(&next)) {
+            (Some(from), Ok(next)) => from.origin() == next.origin() || forward(&next),
+            _ => false,
+        };
+        if !kept {
+            curl.http_headers(List::new())?;
+            curl.http_auth(&CurlAuth::new())?;
+        }
+        aim(curl, &next)?;
+    }
+    Err(LhttpfsError::Other(Box::new(TooManyRedirects)))
+}
+
+/// Points `curl` at `url`, once the fetch under way is [`admitted`] there,
+/// and encrypted if it must be, with its host resolved to only the
+/// addresses it was allowed at.
+pub fn aim(curl: &mut Easy, url: &str) -> Result<(), LhttpfsError> {
+    let addrs = admitted(url)?;
+    if https_only()
+        && !["https", "ftps"]
+            .iter()
+            .any(|s| scheme(url).eq_ignore_ascii_case(s))
+    {
+        return Err(LhttpfsError::Other(Box::new(Unencrypted(url.to_string()))));
+    }
+    curl.url(url)?;
+    let parsed = Url::parse(url).ok();
+    let port = parsed.as_ref().and_then(Url::port_or_known_default);
+    if let (Some(Host::Domain(name)), Some(port)) = (parsed.as_ref().and_then(Url::host), port) {
+        if !addrs.is_empty() {
+            let addrs = (addrs.iter())
+                .map(|addr| match addr {
+                    IpAddr::V4(addr) => addr.to_string(),
+                    IpAddr::V6(addr) => format!("[{}]", addr),
+                })
+                .collect::<Vec<_>>();
+            let mut list = List::new();
+            list.append(&format!("{}:{}:{}", name, port, addrs.join(",")))?;
+            curl.resolve(list)?;
+        }
+    }
+    Ok(())
+}
+
+/// A URL a fetch that must stay encrypted was sent on to, which isn't.
+#[derive(Debug)]
+pub struct Unencrypted(String);
+
+impl Display for Unencrypted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} isn't encrypted, which --require-https refuses to go on to",
+            self.0
+        )
+    }
+}
+
+impl Error for Unencrypted {}
+
+/// A fetch redirected more than [`MAX_REDIRECTS`] times.
+#[derive(Debug)]
+pub struct TooManyRedirects;
+
+impl Display for TooManyRedirects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Redirected more than {} times", MAX_REDIRECTS)
+    }
+}
+
+impl Error for TooManyRedirects {}
+
+/// A curl handle for `request.url` that sends the request's headers and auth.
+pub fn easy(request: &Request) -> Result<Easy, LhttpfsError> {
+    let mut curl = Easy::new();
+    set_up(&mut curl, request)?;
+    Ok(curl)
+}
+
+fn set_up(curl: &mut Easy, request: &Request) -> Result<(), LhttpfsError> {
+    aim(curl, request.url)?;
+    let mut list = List::new();
+    for (key, value) in request.headers {
+        list.append(&format!("{}: {}", key, value))?;
+    }
+    match request.auth {
+        Some(Auth::Bearer(token)) => list.append(&format!("Authorization: Bearer {}", token))?,
+        Some(Auth::Basic { username, password }) => {
+            let mut auth = CurlAuth::new();
+            auth.basic(true);
+            curl.http_auth(&auth)?;
+            curl.username(username)?;
+            curl.password(password)?;
+        }
+        Some(Auth::Ssh { .. }) | None => {}
+    }
+    Ok(curl.http_headers(list)?)
+}
+
+/// A response, of whatever status.
+pub struct Response {
+    pub status: u32,
+    pub body: Vec<u8>,
+    /// Raw header lines of every response, including redirects.
+    pub headers: Vec<String>,
+}
+
+impl Response {
+    /// The value of the last `name` header received.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().rev().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// Performs a GET, leaving error statuses for the caller to interpret.
+pub fn get(url: &str, headers: &[String]) -> crate::Result<Response> {
+    let mut curl = Easy::new();
+    curl.url(url)?;
+    curl.follow_location(true)?;
+    curl.useragent(concat!("lhttpfs/", env!("CARGO_PKG_VERSION")))?;
+    let mut list = List::new();
+    for header in headers {
+        list.append(header)?;
+    }
+    curl.http_headers(list)?;
+    let mut body = Vec::new();
+    let mut response_headers = Vec::new();
+    {
+        let mut transfer = curl.transfer();
+        transfer.header_function(|header| {
+            response_headers.push(String::from_utf8_lossy(header).trim_end().to_string());
+            true
+        })?;
+        transfer.write_function(|data| {
+            body.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    Ok(Response {
+        status: curl.response_code()?,
+        body,
+        headers: response_headers,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::fetch::{Fetchers, HostPolicy, Request};
+
+    #[test]
+    fn cancelled() {
+        // Takes the request and never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            thread::sleep(Duration::from_secs(30));
+        });
+        let fetchers = Fetchers::default();
+        let fetching = fetchers.clone();
+        let start = Instant::now();
+        let fetched = thread::spawn(move || {
+            let headers = BTreeMap::new();
+            let request = Request {
+                url: &url,
+                headers: &headers,
+                auth: None,
+                size: 0,
+            };
+            fetching.fetch_range(&request, None).is_err()
+        });
+        thread::sleep(Duration::from_millis(100));
+        fetchers.cancel();
+        assert!(fetched.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn connections_reused() {
+        // Answers every request on a connection with the same five bytes,
+        // keeping it open, and counts the connections.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counted.fetch_add(1, Ordering::Relaxed);
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut line = String::new();
+                        while reader.read_line(&mut line).unwrap_or(0) > 0
+                            && !line.ends_with("\r\n\r\n")
+                        {}
+                        if line.is_empty() {
+                            return;
+                        }
+                        let head = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+                        let body = if line.starts_with("HEAD") {
+                            ""
+                        } else {
+                            "hello"
+                        };
+                        let _ = stream.write_all(format!("{}{}", head, body).as_bytes());
+                    }
+                });
+            }
+        });
+        let fetchers = Fetchers::default();
+        let headers = BTreeMap::new();
+        let request = Request {
+            url: &url,
+            headers: &headers,
+            auth: None,
+            size: 5,
+        };
+        assert_eq!(fetchers.metadata(&request).unwrap().size, Some(5));
+        for _ in 0..3 {
+            assert_eq!(fetchers.fetch_range(&request, None).unwrap(), b"hello");
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    /// Serves every request, one per connection, with what `respond` makes
+    /// of its head, and returns the server's address.
+    fn serve(respond: impl Fn(&str) -> String + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let respond = Arc::new(respond);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (mut stream, respond) = (stream.unwrap(), respond.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut head = String::new();
+                    while reader.read_line(&mut head).unwrap_or(0) > 0
+                        && !head.ends_with("\r\n\r\n")
+                    {}
+                    let _ = stream.write_all(respond(&head).as_bytes());
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn redirects_checked() {
+        // Sends /a on to the same server under another name, which answers
+        // whether it was given the Authorization header.
+        let addr = serve(|head| {
+            let port = head.split_once("Host: ").unwrap().1.lines().next().unwrap();
+            let port = port.rsplit_once(':').unwrap().1.to_string();
+            let body = match head.to_ascii_lowercase().contains("authorization:") {
+                _ if head.contains(" /a ") => {
+                    return format!(
+                        "HTTP/1.1 302 Found\r\nLocation: http://localhost:{}/b\r\n\
+                         Content-Length: 0\r\nConnection: close\r\n\r\n",
+                        port
+                    )
+                }
+                true => "yes",
+                false => "no",
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        });
+        let url = format!("http://{}/a", addr);
+        let headers = BTreeMap::from([("Authorization".to_string(), "Bearer t".to_string())]);
+        let request = Request {
+            url: &url,
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        let mut fetchers = Fetchers::default();
+        // Followed, without the credentials meant for the first host.
+        assert_eq!(fetchers.fetch_range(&request, None).unwrap(), b"no");
+        fetchers.restrict(HostPolicy::new(&[] as &[&str], &["localhost"]).unwrap());
+        let error = fetchers.fetch_range(&request, None).unwrap_err();
+        assert!(error.to_string().contains("localhost"), "{}", error);
+        assert!(fetchers.metadata(&request).is_err());
+    }
+
+    #[test]
+    fn kept_encrypted() {
+        let mut fetchers = Fetchers::default();
+        fetchers.require_https();
+        let headers = BTreeMap::new();
+        let plain = Request {
+            url: "http://example.com/b",
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        // As a redirect, or a download link a Git LFS server hands out.
+        let aimed = fetchers.fetching("https://example.com/a", || super::easy(&plain).map(drop));
+        let error = aimed.unwrap_err();
+        assert!(error.to_string().contains("isn't encrypted"), "{}", error);
+        // Only let through unencrypted to begin with by `allow_http`.
+        let aimed = fetchers.fetching("http://example.com/a", || super::easy(&plain).map(drop));
+        assert!(aimed.is_ok());
+        assert!(Fetchers::default()
+            .fetching("https://example.com/a", || super::easy(&plain).map(drop))
+            .is_ok());
+    }
+
+    #[test]
+    fn redirected() {
+        // Sends /a on to /b, which holds five bytes but sends two of any
+        // range; /c redirects nowhere.
+        let addr = serve(|head| {
+            let (status, headers, body) = match head.split(' ').nth(1).unwrap() {
+                "/a" => ("302 Found", "Location: /b\r\n", ""),
+                "/b" if head.contains("Range: bytes=1-3") => (
+                    "206 Partial Content",
+                    "Content-Range: bytes 1-2/5\r\n",
+                    "el",
+                ),
+                "/b" => ("200 OK", "", "hello"),
+                _ => ("302 Found", "", ""),
+            };
+            format!(
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                headers,
+                body.len(),
+                body
+            )
+        });
+        let fetchers = Fetchers::default();
+        let headers = BTreeMap::new();
+        let (a, c) = (format!("http://{}/a", addr), format!("http://{}/c", addr));
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 5,
+        };
+        assert_eq!(fetchers.fetch_range(&request(&a), None).unwrap(), b"hello");
+        let error = fetchers
+            .fetch_range(&request(&a), Some((1, 3)))
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Got 2 of the 3 bytes"),
+            "{}",
+            error
+        );
+        let error = fetchers.fetch_range(&request(&c), None).unwrap_err();
+        assert_eq!(error.status(), Some(302));
+    }
+}
@@ -0,0 +1,66 @@
+//! ia://identifier/file URLs, files of Internet Archive items.
+
+use super::{
+    http::{easy, transfer_forwarding},
+    FetchResult, Fetcher, Request,
+};
+
+pub struct InternetArchive;
+
+impl Fetcher for InternetArchive {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let url = download_url(request.url)?;
+        let mut headers = request.headers.clone();
+        if let Some(authorization) = authorization() {
+            headers.insert("Authorization".into(), authorization);
+        }
+        let mut curl = easy(&Request {
+            url: &url,
+            headers: &headers,
+            ..*request
+        })?;
+        // archive.org redirects downloads to the server holding the item.
+        transfer_forwarding(&mut curl, request.size, range, |url| {
+            (url.host_str())
+                .is_some_and(|host| host == "archive.org" || host.ends_with(".archive.org"))
+        })
+    }
+}
+
+/// The archive.org S3-style keys that unlock restricted items, from
+/// `IA_ACCESS_KEY` and `IA_SECRET_KEY`.
+pub fn authorization() -> Option<String> {
+    let access = std::env::var("IA_ACCESS_KEY")
+        .ok()
+        .filter(|k| !k.is_empty())?;
+    let secret = std::env::var("IA_SECRET_KEY")
+        .ok()
+        .filter(|k| !k.is_empty())?;
+    Some(format!("LOW {}:{}", access, secret))
+}
+
+fn download_url(url: &str) -> crate::Result<String> {
+    let rest = url
+        .strip_prefix("ia://")
+        .filter(|rest| {
+            rest.split_once('/')
+                .is_some_and(|(id, file)| !id.is_empty() && !file.is_empty())
+        })
+        .ok_or_else(|| format!("{} is not an ia://identifier/file URL", url))?;
+    Ok(format!("https://archive.org/download/{}", rest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::download_url;
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            download_url("ia://nasa-apollo/images/a%20b.jpg").unwrap(),
+            "https://archive.org/download/nasa-apollo/images/a%20b.jpg"
+        );
+        assert!(download_url("ia://nasa-apollo").is_err());
+        assert!(download_url("ia:///file").is_err());
+    }
+}
@@ -0,0 +1,530 @@
+//! ipfs://CID[/path] URLs. Blocks come from an HTTP gateway or the local
+//! daemon's API and every one is checked against its CID, so an untrusted
+//! gateway can't serve the wrong bytes. Ranged reads only fetch the blocks
+//! they cover.
+
+use std::{collections::HashMap, error::Error, fmt::Display, sync::Mutex};
+
+use curl::easy::{Easy, List};
+use sha2::{Digest, Sha256};
+
+use super::{aim, clamped, perform, FetchResult, Fetcher, Request};
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const SHA2_256: u64 = 0x12;
+const IDENTITY: u64 = 0x00;
+
+const BASE58: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BASE32: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug)]
+pub struct BadBlock(String);
+
+impl Display for BadBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IPFS block {} does not match its CID", self.0)
+    }
+}
+
+impl Error for BadBlock {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Cid {
+    version: u64,
+    codec: u64,
+    hash: u64,
+    digest: Vec<u8>,
+}
+
+impl Cid {
+    fn parse(text: &str) -> crate::Result<Cid> {
+        if text.len() == 46 && text.starts_with("Qm") {
+            return Cid::from_multihash(0, DAG_PB, &base58_decode(text)?);
+        }
+        let bytes = match text.strip_prefix('b') {
+            Some(rest) => base32_decode(&rest.to_ascii_lowercase())?,
+            None => return Err(format!("Unsupported CID encoding in {}", text).into()),
+        };
+        Cid::from_bytes(&bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> crate::Result<Cid> {
+        if bytes.len() == 34 && bytes[0] == 0x12 && bytes[1] == 0x20 {
+            return Cid::from_multihash(0, DAG_PB, bytes);
+        }
+        let mut rest = bytes;
+        let version = varint(&mut rest)?;
+        let codec = varint(&mut rest)?;
+        if version != 1 {
+            return Err(format!("Unsupported CID version {}", version).into());
+        }
+        Cid::from_multihash(version, codec, rest)
+    }
+
+    fn from_multihash(version: u64, codec: u64, mut multihash: &[u8]) -> crate::Result<Cid> {
+        let hash = varint(&mut multihash)?;
+        let len = varint(&mut multihash)? as usize;
+        if multihash.len() != len {
+            return Err("Truncated multihash in CID".into());
+        }
+        if hash != SHA2_256 && hash != IDENTITY {
+            return Err(format!("Unsupported multihash 0x{:x} in CID", hash).into());
+        }
+        Ok(Cid {
+            version,
+            codec,
+            hash,
+            digest: multihash.to_vec(),
+        })
+    }
+
+    fn multihash(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, self.hash);
+        put_varint(&mut bytes, self.digest.len() as u64);
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    fn verify(&self, block: &[u8]) -> bool {
+        match self.hash {
+            SHA2_256 => Sha256::digest(block).as_slice() == self.digest,
+            _ => block == self.digest,
+        }
+    }
+}
+
+impl Display for Cid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.version == 0 {
+            return write!(f, "{}", base58_encode(&self.multihash()));
+        }
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, self.version);
+        put_varint(&mut bytes, self.codec);
+        bytes.extend(self.multihash());
+        write!(f, "b{}", base32_encode(&bytes))
+    }
+}
+
+#[derive(Default)]
+pub struct Ipfs {
+    /// Interior blocks of files, which are small and read over and over
+    /// for ranged reads. Leaves go through the filesystem's cache instead.
+    nodes: Mutex<HashMap<Cid, Vec<u8>>>,
+}
+
+impl Fetcher for Ipfs {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let rest = request
+            .url
+            .strip_prefix("ipfs://")
+            .ok_or_else(|| format!("{} is not an ipfs:// URL", request.url))?;
+        let mut parts = rest.split('/').filter(|part| !part.is_empty());
+        let mut cid = Cid::parse(parts.next().ok_or("ipfs:// URLs need a CID")?)?;
+        let get = |cid: &Cid| self.block(cid);
+        for name in parts {
+            let name = percent_encoding::percent_decode_str(name).decode_utf8()?;
+            cid = child(&get, &cid, &name)?;
+        }
+        let (start, len) = range.unwrap_or((0, u64::MAX));
+        let mut out = Vec::new();
+        read(&get, &cid, start, start.saturating_add(len), &mut out)?;
+        Ok(out.into())
+    }
+
+    /// Over the node or gateway blocks are read from.
+    fn plaintext(&self, _: &str) -> bool {
+        let env = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        (env("IPFS_API").or_else(|| env("IPFS_GATEWAY")))
+            .is_some_and(|base| base.starts_with("http://"))
+    }
+}
+
+impl Ipfs {
+    fn block(&self, cid: &Cid) -> crate::Result<Vec<u8>> {
+        if cid.hash == IDENTITY {
+            return Ok(cid.digest.clone());
+        }
+        if let Some(block) = self.nodes.lock().unwrap().get(cid) {
+            return Ok(block.clone());
+        }
+        let mut curl = Easy::new();
+        let mut list = List::new();
+        match std::env::var("IPFS_API").ok().filter(|api| !api.is_empty()) {
+            Some(api) => {
+                let api = api.trim_end_matches('/');
+                aim(&mut curl, &format!("{}/api/v0/block/get?arg={}", api, cid))?;
+                curl.post(true)?;
+                curl.post_fields_copy(b"")?;
+            }
+            None => {
+                let gateway = std::env::var("IPFS_GATEWAY")
+                    .ok()
+                    .filter(|gateway| !gateway.is_empty())
+                    .unwrap_or_else(|| "https://ipfs.io".into());
+                let gateway = gateway.trim_end_matches('/');
+                aim(&mut curl, &format!("{}/ipfs/{}?format=raw", gateway, cid))?;
+                list.append("Accept: application/vnd.ipld.raw")?;
+            }
+        }
+        curl.http_headers(list)?;
+        let block = perform(curl)?;
+        if !cid.verify(&block) {
+            return Err(Box::new(BadBlock(cid.to_string())));
+        }
+        if cid.codec == DAG_PB {
+            self.nodes
+                .lock()
+                .unwrap()
+                .insert(cid.clone(), block.clone());
+        }
+        Ok(block)
+    }
+}
+
+struct Link {
+    cid: Cid,
+    name: String,
+}
+
+/// The parts of a dag-pb node and the UnixFS data inside it that reading
+/// files needs.
+struct Node {
+    links: Vec<Link>,
+    kind: u64,
+    data: Vec<u8>,
+    blocksizes: Vec<u64>,
+}
+
+const DIRECTORY: u64 = 1;
+const FILE: u64 = 2;
+
+impl Node {
+    fn parse(block: &[u8]) -> crate::Result<Node> {
+        let mut node = Node {
+            links: Vec::new(),
+            kind: 0,
+            data: Vec::new(),
+            blocksizes: Vec::new(),
+        };
+        for (field, value) in fields(block)? {
+            match (field, value) {
+                (1, Value::Bytes(unixfs)) => {
+                    for (field, value) in fields(unixfs)? {
+                        match (field, value) {
+                            (1, Value::Varint(kind)) => node.kind = kind,
+                            (2, Value::Bytes(data)) => node.data = data.to_vec(),
+                            (4, Value::Varint(size)) => node.blocksizes.push(size),
+                            (4, Value::Bytes(mut packed)) => {
+                                while !packed.is_empty() {
+                                    node.blocksizes.push(varint(&mut packed)?);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                (2, Value::Bytes(link)) => {
+                    let mut cid = None;
+                    let mut name = String::new();
+                    for (field, value) in fields(link)? {
+                        match (field, value) {
+                            (1, Value::Bytes(hash)) => cid = Some(Cid::from_bytes(hash)?),
+                            (2, Value::Bytes(bytes)) => {
+                                name = String::from_utf8_lossy(bytes).into_owned()
+                            }
+                            _ => {}
+                        }
+                    }
+                    let cid = cid.ok_or("dag-pb link without a hash")?;
+                    node.links.push(Link { cid, name });
+                }
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+}
+
+/// Looks up `name` in the UnixFS directory `dir`.
+fn child(
+    get: &dyn Fn(&Cid) -> crate::Result<Vec<u8>>,
+    dir: &Cid,
+    name: &str,
+) -> crate::Result<Cid> {
+    let node = Node::parse(&get(dir)?)?;
+    if dir.codec != DAG_PB || node.kind != DIRECTORY {
+        return Err(format!("{} is not a plain UnixFS directory", dir).into());
+    }
+    node.links
+        .into_iter()
+        .find(|link| link.name == name)
+        .map(|link| link.cid)
+        .ok_or_else(|| format!("No {} in {}", name, dir).into())
+}
+
+/// Appends the bytes of file `cid` from `start` up to `end` to `out`,
+/// skipping blocks outside of that.
+fn read(
+    get: &dyn Fn(&Cid) -> crate::Result<Vec<u8>>,
+    cid: &Cid,
+    start: u64,
+    end: u64,
+    out: &mut Vec<u8>,
+) -> crate::Result<()> {
+    let block = get(cid)?;
+    let slice = |data: &[u8], out: &mut Vec<u8>| {
+        let from = clamped(start, data.len());
+        let to = clamped(end, data.len()).max(from);
+        out.extend_from_slice(&data[from..to]);
+    };
+    match cid.codec {
+        RAW => {
+            slice(&block, out);
+            Ok(())
+        }
+        DAG_PB => {
+            let node = Node::parse(&block)?;
+            if node.kind != FILE && node.kind != 0 {
+                return Err(format!("{} is not a UnixFS file", cid).into());
+            }
+            slice(&node.data, out);
+            let mut offset = node.data.len() as u64;
+            for (link, size) in node.links.iter().zip(&node.blocksizes) {
+                let link_end = offset + size;
+                if link_end > start && offset < end {
+                    read(
+                        get,
+                        &link.cid,
+                        start.saturating_sub(offset),
+                        end - offset,
+                        out,
+                    )?;
+                }
+                offset = link_end;
+            }
+            Ok(())
+        }
+        codec => Err(format!("Unsupported IPLD codec 0x{:x}", codec).into()),
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Splits a protobuf message into its fields, skipping fixed-size ones.
+fn fields(mut message: &[u8]) -> crate::Result<Vec<(u64, Value<'_>)>> {
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = varint(&mut message)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(&mut message)?),
+            2 => {
+                let len = varint(&mut message)? as usize;
+                if len > message.len() {
+                    return Err("Truncated protobuf field".into());
+                }
+                let (bytes, rest) = message.split_at(len);
+                message = rest;
+                Value::Bytes(bytes)
+            }
+            1 | 5 => {
+                let len = if key & 7 == 1 { 8 } else { 4 };
+                message = message.get(len..).ok_or("Truncated protobuf field")?;
+                continue;
+            }
+            wire => return Err(format!("Unsupported protobuf wire type {}", wire).into()),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn varint(bytes: &mut &[u8]) -> crate::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or("Truncated varint")?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Varint is too long".into())
+}
+
+fn put_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn base58_decode(text: &str) -> crate::Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = BASE58
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| format!("Invalid base58 in {}", text))?;
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as usize * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|&c| c == b'1').count();
+    Ok([vec![0; zeros], bytes].concat())
+}
+
+fn base58_encode(bytes: &[u8]) -> String {
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in bytes {
+        let mut carry = byte as usize;
+        for digit in digits.iter_mut().rev() {
+            carry += *digit as usize * 256;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.insert(0, (carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().map(|&d| BASE58[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base32_decode(text: &str) -> crate::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in text.bytes() {
+        let value = BASE32
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| format!("Invalid base32 in {}", text))?;
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let (mut buffer, mut bits) = (0u64, 0);
+    for &byte in bytes {
+        buffer = buffer << 8 | byte as u64;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use sha2::{Digest, Sha256};
+
+    use super::{put_varint, read, Cid, DAG_PB, RAW, SHA2_256};
+
+    fn field(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+        put_varint(out, number << 3 | 2);
+        put_varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn number(out: &mut Vec<u8>, number: u64, value: u64) {
+        put_varint(out, number << 3);
+        put_varint(out, value);
+    }
+
+    fn cid(version: u64, codec: u64, block: &[u8]) -> Cid {
+        Cid {
+            version,
+            codec,
+            hash: SHA2_256,
+            digest: Sha256::digest(block).to_vec(),
+        }
+    }
+
+    #[test]
+    fn cids() {
+        let v0 = "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+        let v1 = "bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4";
+        assert_eq!(Cid::parse(v0).unwrap().to_string(), v0);
+        assert_eq!(Cid::parse(v1).unwrap().to_string(), v1);
+        assert!(Cid::parse(v1).unwrap().verify(b"hello world\n"));
+
+        // What `ipfs add` makes of a small file.
+        let mut unixfs = Vec::new();
+        number(&mut unixfs, 1, 2);
+        field(&mut unixfs, 2, b"hello world\n");
+        number(&mut unixfs, 3, 12);
+        let mut node = Vec::new();
+        field(&mut node, 1, &unixfs);
+        assert_eq!(cid(0, DAG_PB, &node).to_string(), v0);
+    }
+
+    #[test]
+    fn chunked_file() {
+        let leaves = [b"hello ".as_slice(), b"world\n".as_slice()];
+        let mut blocks = HashMap::new();
+        let mut node = Vec::new();
+        for leaf in leaves {
+            let leaf_cid = cid(1, RAW, leaf);
+            let mut link = Vec::new();
+            let mut hash = vec![1, RAW as u8];
+            hash.extend(leaf_cid.multihash());
+            field(&mut link, 1, &hash);
+            field(&mut node, 2, &link);
+            blocks.insert(leaf_cid, leaf.to_vec());
+        }
+        let mut unixfs = Vec::new();
+        number(&mut unixfs, 1, 2);
+        number(&mut unixfs, 3, 12);
+        number(&mut unixfs, 4, 6);
+        number(&mut unixfs, 4, 6);
+        let mut root = Vec::new();
+        field(&mut root, 1, &unixfs);
+        root.extend(node);
+        let root_cid = cid(1, DAG_PB, &root);
+        blocks.insert(root_cid.clone(), root);
+
+        let fetched = std::cell::RefCell::new(Vec::new());
+        let get = |cid: &Cid| {
+            fetched.borrow_mut().push(cid.clone());
+            Ok(blocks[cid].clone())
+        };
+        let mut out = Vec::new();
+        read(&get, &root_cid, 0, u64::MAX, &mut out).unwrap();
+        assert_eq!(out, b"hello world\n");
+        fetched.borrow_mut().clear();
+        out.clear();
+        read(&get, &root_cid, 7, 10, &mut out).unwrap();
+        assert_eq!(out, b"orl");
+        assert_eq!(fetched.borrow().len(), 2, "the first leaf isn't needed");
+    }
+}
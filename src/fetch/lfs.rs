@@ -0,0 +1,201 @@
+//! `lfs+https://host/owner/repo#<oid>` URLs, Git LFS objects whose download
+//! URL is asked from the repository's LFS server on every mount, so
+//! layouts don't bake in short-lived links.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{layout::hex, LhttpfsError};
+
+use super::{
+    date::parse_rfc3339,
+    http::{easy, transfer},
+    oci::DigestMismatch,
+    perform, scheme, FetchResult, Fetcher, Request,
+};
+
+#[derive(Debug, Clone)]
+struct Action {
+    href: String,
+    header: BTreeMap<String, String>,
+    expires: Option<SystemTime>,
+}
+
+#[derive(Default)]
+pub struct Lfs {
+    /// Download actions by object URL, reused until shortly before they
+    /// expire.
+    actions: Mutex<HashMap<String, Action>>,
+}
+
+impl Fetcher for Lfs {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let (server, oid) = parse(request.url)?;
+        let action = self.action(request, &server, &oid)?;
+        let mut curl = easy(&Request {
+            url: &action.href,
+            headers: &action.header,
+            auth: None,
+            size: request.size,
+        })?;
+        let data = transfer(&mut curl, request.size, range)?;
+        if range.is_none() && hex(&Sha256::digest(&data.data)) != oid {
+            return Err(LhttpfsError::Other(Box::new(DigestMismatch(format!(
+                "sha256:{}",
+                oid
+            )))));
+        }
+        Ok(data)
+    }
+
+    /// The server's own; the download links it hands out are checked as
+    /// they are fetched.
+    fn plaintext(&self, url: &str) -> bool {
+        scheme(url).eq_ignore_ascii_case("lfs+http")
+    }
+}
+
+impl Lfs {
+    fn action(&self, request: &Request, server: &str, oid: &str) -> crate::Result<Action> {
+        let soon = SystemTime::now() + Duration::from_secs(30);
+        if let Some(action) = self.actions.lock().unwrap().get(request.url) {
+            if action.expires.is_none_or(|expires| expires > soon) {
+                return Ok(action.clone());
+            }
+        }
+        let mut headers = request.headers.clone();
+        headers.insert("Accept".into(), "application/vnd.git-lfs+json".into());
+        headers.insert("Content-Type".into(), "application/vnd.git-lfs+json".into());
+        let url = format!("{}/objects/batch", server);
+        let mut curl = easy(&Request {
+            url: &url,
+            headers: &headers,
+            ..*request
+        })?;
+        let body = json!({
+            "operation": "download",
+            "transfers": ["basic"],
+            "objects": [{"oid": oid, "size": request.size}],
+        });
+        curl.post(true)?;
+        curl.post_fields_copy(body.to_string().as_bytes())?;
+        let action = parse_batch(&perform(curl)?, SystemTime::now())?;
+        self.actions
+            .lock()
+            .unwrap()
+            .insert(request.url.to_string(), action.clone());
+        Ok(action)
+    }
+}
+
+/// Splits an object URL into the repository's LFS server URL and the oid.
+fn parse(url: &str) -> crate::Result<(String, String)> {
+    let bad = || format!("{} is not an lfs+https://host/repo#<sha256> URL", url);
+    let (repo, oid) = url
+        .strip_prefix("lfs+")
+        .and_then(|url| url.split_once('#'))
+        .ok_or_else(bad)?;
+    if oid.len() != 64 || !oid.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(bad().into());
+    }
+    let repo = repo.trim_end_matches('/');
+    let server = match repo.ends_with(".git") {
+        true => format!("{}/info/lfs", repo),
+        false => format!("{}.git/info/lfs", repo),
+    };
+    Ok((server, oid.to_ascii_lowercase()))
+}
+
+fn parse_batch(body: &[u8], now: SystemTime) -> crate::Result<Action> {
+    #[derive(Deserialize)]
+    struct Batch {
+        objects: Vec<Object>,
+    }
+    #[derive(Deserialize)]
+    struct Object {
+        actions: Option<Actions>,
+        error: Option<ObjectError>,
+    }
+    #[derive(Deserialize)]
+    struct Actions {
+        download: Download,
+    }
+    #[derive(Deserialize)]
+    struct Download {
+        href: String,
+        #[serde(default)]
+        header: BTreeMap<String, String>,
+        expires_in: Option<u64>,
+        expires_at: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct ObjectError {
+        code: u32,
+        message: String,
+    }
+    let batch: Batch = serde_json::from_slice(body)?;
+    let object = batch
+        .objects
+        .into_iter()
+        .next()
+        .ok_or("The LFS server returned no object")?;
+    if let Some(error) = object.error {
+        return Err(format!("LFS error {}: {}", error.code, error.message).into());
+    }
+    let download = object
+        .actions
+        .ok_or("The LFS server offered no download")?
+        .download;
+    let expires = match download.expires_in {
+        Some(secs) => Some(now + Duration::from_secs(secs)),
+        None => download.expires_at.as_deref().and_then(parse_rfc3339),
+    };
+    Ok(Action {
+        href: download.href,
+        header: download.header,
+        expires,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{parse, parse_batch};
+
+    const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+    #[test]
+    fn urls() {
+        let (server, oid) = parse(&format!("lfs+https://github.com/owner/repo#{}", OID)).unwrap();
+        assert_eq!(server, "https://github.com/owner/repo.git/info/lfs");
+        assert_eq!(oid, OID);
+        let (server, _) = parse(&format!("lfs+https://host/repo.git#{}", OID)).unwrap();
+        assert_eq!(server, "https://host/repo.git/info/lfs");
+        assert!(parse("lfs+https://host/repo#abc").is_err());
+        assert!(parse(&format!("https://host/repo#{}", OID)).is_err());
+    }
+
+    #[test]
+    fn batch_responses() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let ok = br#"{"objects": [{"oid": "x", "size": 1, "actions": {"download":
+            {"href": "https://cdn/x", "header": {"Authorization": "RemoteAuth t"}, "expires_in": 60}}}]}"#;
+        let action = parse_batch(ok, now).unwrap();
+        assert_eq!(action.href, "https://cdn/x");
+        assert_eq!(action.header["Authorization"], "RemoteAuth t");
+        assert_eq!(action.expires, Some(now + Duration::from_secs(60)));
+
+        let missing = br#"{"objects": [{"oid": "x", "size": 1,
+            "error": {"code": 404, "message": "Object does not exist"}}]}"#;
+        let err = parse_batch(missing, now).unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+}
@@ -0,0 +1,126 @@
+//! [`MemoryFetcher`], objects held in memory by URL, for testing what a
+//! mount does without an origin to read from.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use super::{cut, FetchResult, Fetcher, HttpStatus, Metadata, Request};
+use crate::LhttpfsError;
+
+/// A URL asked for, and the range of it.
+type Fetched = (String, Option<(u64, u64)>);
+
+/// Serves the bytes given for each URL, and a 404 for any other, keeping
+/// every request it answered. Register it for a scheme of its own with
+/// [`super::Fetchers::register`], or for `https` to stand in for the
+/// network.
+#[derive(Default)]
+pub struct MemoryFetcher {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+    fetched: Mutex<Vec<Fetched>>,
+}
+
+impl MemoryFetcher {
+    pub fn new() -> MemoryFetcher {
+        MemoryFetcher::default()
+    }
+
+    /// Serves `data` for `url`.
+    pub fn with(self, url: impl Into<String>, data: impl Into<Vec<u8>>) -> MemoryFetcher {
+        self.insert(url, data);
+        self
+    }
+
+    /// Serves `data` for `url` from now on, as an origin whose object
+    /// changed would.
+    pub fn insert(&self, url: impl Into<String>, data: impl Into<Vec<u8>>) {
+        self.objects.lock().unwrap().insert(url.into(), data.into());
+    }
+
+    /// Answers `url` with a 404 from now on.
+    pub fn remove(&self, url: &str) {
+        self.objects.lock().unwrap().remove(url);
+    }
+
+    /// The URL and range of every request answered so far, in order.
+    pub fn fetched(&self) -> Vec<Fetched> {
+        self.fetched.lock().unwrap().clone()
+    }
+}
+
+impl Fetcher for MemoryFetcher {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let data = self.objects.lock().unwrap().get(request.url).cloned();
+        let data = data.ok_or_else(|| HttpStatus {
+            url: request.url.to_string(),
+            status: 404,
+        })?;
+        (self.fetched.lock().unwrap()).push((request.url.to_string(), range));
+        Ok(cut(data, range).into())
+    }
+
+    fn metadata(&self, request: &Request) -> Result<Metadata, LhttpfsError> {
+        match self.objects.lock().unwrap().get(request.url) {
+            Some(data) => Ok(Metadata {
+                size: Some(data.len() as u64),
+                modified: None,
+            }),
+            None => Err(HttpStatus {
+                url: request.url.to_string(),
+                status: 404,
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, error::Error};
+
+    use super::MemoryFetcher;
+    use crate::fetch::{Fetcher, HttpStatus, Request};
+
+    #[test]
+    fn served() {
+        let fetcher = MemoryFetcher::new().with("mem://a", "hello");
+        let headers = BTreeMap::new();
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 5,
+        };
+        assert_eq!(
+            fetcher.fetch_range(&request("mem://a"), None).unwrap().data,
+            b"hello"
+        );
+        let range = fetcher.fetch_range(&request("mem://a"), Some((3, 10)));
+        assert_eq!(range.unwrap().data, b"lo");
+        let missing = fetcher.fetch_range(&request("mem://b"), None).unwrap_err();
+        assert_eq!(
+            missing
+                .source()
+                .unwrap()
+                .downcast_ref::<HttpStatus>()
+                .unwrap()
+                .status,
+            404
+        );
+
+        fetcher.insert("mem://a", "bye");
+        assert_eq!(
+            fetcher.fetch_range(&request("mem://a"), None).unwrap().data,
+            b"bye"
+        );
+        fetcher.remove("mem://a");
+        assert!(fetcher.fetch_range(&request("mem://a"), None).is_err());
+        assert_eq!(
+            fetcher.fetched(),
+            [
+                ("mem://a".into(), None),
+                ("mem://a".into(), Some((3, 10))),
+                ("mem://a".into(), None)
+            ]
+        );
+    }
+}
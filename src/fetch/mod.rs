@@ -0,0 +1,929 @@
+//! Where file contents come from. Each URL scheme is served by a
+//! [`Fetcher`], so supporting a new protocol means adding one here rather
+//! than touching the filesystem.
+
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(feature = "http")]
+use curl::easy::Easy;
+use tracing::{field::Empty, info, info_span, warn};
+
+use crate::{
+    health::HEALTH,
+    hooks,
+    keyring::Keyring,
+    layout::Auth,
+    metrics::METRICS,
+    observer::{FsObserver, Observers},
+    LhttpfsError,
+};
+
+#[cfg(feature = "http")]
+mod artifacts;
+#[cfg(feature = "http")]
+mod azure;
+#[cfg(feature = "http")]
+mod b2;
+mod data;
+pub mod date;
+#[cfg(feature = "http")]
+pub mod dav;
+#[cfg(feature = "http")]
+mod dropbox;
+mod file;
+#[cfg(feature = "http")]
+mod ftp;
+#[cfg(feature = "http")]
+mod gcs;
+#[cfg(feature = "http")]
+mod gdrive;
+#[cfg(feature = "http")]
+mod hf;
+mod hosts;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "http")]
+mod ia;
+#[cfg(feature = "http")]
+mod ipfs;
+#[cfg(feature = "http")]
+mod lfs;
+mod memory;
+pub mod netrc;
+#[cfg(feature = "http")]
+pub mod oci;
+pub mod plugin;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod webseed;
+
+pub use hosts::{BadHostPattern, HostPolicy, HostRefused};
+#[cfg(feature = "http")]
+pub use http::{aim, easy, following, get, Response, TooManyRedirects, Unencrypted};
+#[cfg(feature = "http")]
+pub use ia::authorization as ia_authorization;
+pub use memory::MemoryFetcher;
+pub use netrc::Netrc;
+pub use webseed::fetch_pieces;
+
+/// What a [`Fetcher`] needs to know about the file being read.
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    pub url: &'a str,
+    pub headers: &'a BTreeMap<String, String>,
+    pub auth: Option<&'a Auth>,
+    /// The size the layout gives the whole object, as a capacity hint.
+    pub size: u64,
+}
+
+/// A backend reading the URLs of one scheme or more.
+pub trait Fetcher: Send + Sync {
+    /// Returns the body of `request.url`, or `len` bytes of it from `start`
+    /// when a range is given.
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult;
+
+    /// What the backend can tell of `request.url` without fetching it:
+    /// what a mount gives files whose layout has no `size` or `mtime`.
+    fn metadata(&self, request: &Request) -> std::result::Result<Metadata, LhttpfsError> {
+        let _ = request;
+        Ok(Metadata::default())
+    }
+
+    /// Whether the backend reads `url` without encryption, for
+    /// `--require-https` to refuse.
+    fn plaintext(&self, url: &str) -> bool {
+        let _ = url;
+        false
+    }
+}
+
+/// The scheme of `url`, as given.
+fn scheme(url: &str) -> &str {
+    url.split_once(':').map_or("", |(scheme, _)| scheme)
+}
+
+/// The size of a URL and when it was last modified, as far as its backend
+/// can tell without fetching it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+/// What a [`Fetcher`] answers with.
+pub type FetchResult = std::result::Result<Fetched, LhttpfsError>;
+
+/// The bytes a [`Fetcher`] fetched, with what identifies their version if
+/// the origin said.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Fetched {
+    pub data: Vec<u8>,
+    pub validators: Option<Validators>,
+}
+
+impl From<Vec<u8>> for Fetched {
+    fn from(data: Vec<u8>) -> Fetched {
+        Fetched {
+            data,
+            validators: None,
+        }
+    }
+}
+
+/// An HTTP error status, as an error.
+#[derive(Debug)]
+pub struct HttpStatus {
+    pub url: String,
+    pub status: u32,
+}
+
+impl Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} returned HTTP {}", self.url, self.status)
+    }
+}
+
+impl Error for HttpStatus {}
+
+#[derive(Debug)]
+pub struct UnsupportedScheme(String);
+
+impl Display for UnsupportedScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No backend for {} URLs", self.0)
+    }
+}
+
+impl Error for UnsupportedScheme {}
+
+/// The fetchers available to a mount, by URL scheme.
+#[derive(Clone)]
+pub struct Fetchers {
+    schemes: HashMap<String, Arc<dyn Fetcher>>,
+    observers: Observers,
+    /// Set by [`Fetchers::cancel`], for this and every clone.
+    cancel: Arc<AtomicBool>,
+    hosts: Arc<HostPolicy>,
+    /// Credentials for URLs without any of their own, by host.
+    netrc: Option<Arc<Netrc>>,
+    keyring: Arc<Keyring>,
+    retry: Retry,
+    /// Set by [`Fetchers::require_https`].
+    https_only: bool,
+}
+
+/// How [`Fetchers`] try a fetch again after a failure that may pass, as
+/// [`LhttpfsError::transient`] tells: `attempts` more times at most,
+/// waiting `delay` before the first and twice as long before each after.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+thread_local! {
+    /// The flag of the [`Fetchers`] fetching on this thread, if any.
+    static CANCEL: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+    /// The flag of the reads ahead [`abandonable`] fetches on this thread for.
+    static ABANDON: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+    /// The hosts the [`Fetchers`] fetching on this thread allow, if any.
+    static HOSTS: RefCell<Option<Arc<HostPolicy>>> = const { RefCell::new(None) };
+    /// Whether the fetch under way on this thread must stay encrypted.
+    static HTTPS_ONLY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// An origin's `ETag` and `Last-Modified` for what it sent, to ask it with
+/// `If-None-Match` and `If-Modified-Since` whether a copy is still current.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// `headers`, asking for the body only if it changed since these.
+    pub fn conditional(&self, headers: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+        let mut headers = headers.clone();
+        if let Some(etag) = &self.etag {
+            headers.insert("If-None-Match".to_string(), etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert("If-Modified-Since".to_string(), last_modified.clone());
+        }
+        headers
+    }
+}
+
+fn set(flag: &RefCell<Option<Arc<AtomicBool>>>) -> bool {
+    (flag.borrow().as_ref()).is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Whether the fetch under way on this thread was cancelled with
+/// [`Fetchers::cancel`], or abandoned, for a backend to give up at its next
+/// chance.
+pub fn cancelled() -> bool {
+    CANCEL.with(set) || ABANDON.with(set)
+}
+
+/// Fails unless the fetch under way on this thread may go to `url`, as
+/// [`Fetchers::restrict`] says, for a backend to check every URL it is
+/// redirected to. Returns the addresses the host was allowed at, if it had
+/// to be looked up, for the backend to connect to no others.
+pub fn admitted(url: &str) -> std::result::Result<Vec<IpAddr>, HostRefused> {
+    HOSTS.with(|hosts| match hosts.borrow().as_ref() {
+        Some(hosts) => hosts.addresses(url),
+        None => Ok(Vec::new()),
+    })
+}
+
+/// Whether the fetch under way on this thread may only go on to encrypted
+/// URLs, as [`Fetchers::require_https`] says.
+pub fn https_only() -> bool {
+    HTTPS_ONLY.get()
+}
+
+/// Runs `f`, whose fetches give up as if cancelled once `abandon` is set,
+/// as those reading ahead of a player do once it seeks elsewhere.
+pub fn abandonable<T>(abandon: &Arc<AtomicBool>, f: impl FnOnce() -> T) -> T {
+    let outer = ABANDON.with(|flag| flag.replace(Some(abandon.clone())));
+    let result = f();
+    ABANDON.with(|flag| *flag.borrow_mut() = outer);
+    result
+}
+
+/// A fetch asking whether a copy with [`Validators`] is still current,
+/// answered `304 Not Modified`: it is.
+#[derive(Debug)]
+pub struct NotModified;
+
+impl NotModified {
+    /// Whether `error` is the answer that a copy is still current.
+    pub fn answered(error: &LhttpfsError) -> bool {
+        match error {
+            LhttpfsError::Fetch { source, .. } | LhttpfsError::Other(source) => {
+                source.is::<NotModified>()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for NotModified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not modified")
+    }
+}
+
+impl Error for NotModified {}
+
+/// A fetch given up on, or not started, after [`Fetchers::cancel`].
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fetch cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+impl Default for Fetchers {
+    fn default() -> Fetchers {
+        let mut fetchers = Fetchers {
+            schemes: HashMap::new(),
+            observers: Observers::default(),
+            cancel: Arc::default(),
+            hosts: Arc::default(),
+            netrc: None,
+            keyring: Arc::default(),
+            retry: Retry::default(),
+            https_only: false,
+        };
+        fetchers.register("file", Arc::new(file::LocalFile));
+        fetchers.register("data", Arc::new(data::Data));
+        #[cfg(feature = "http")]
+        fetchers.http();
+        #[cfg(feature = "s3")]
+        fetchers.register("s3", Arc::new(s3::S3::default()));
+        #[cfg(feature = "sftp")]
+        fetchers.register("sftp", Arc::new(sftp::Sftp::default()));
+        fetchers
+    }
+}
+
+impl Fetchers {
+    /// Serves `scheme` URLs with `fetcher`, replacing any previous one.
+    pub fn register(&mut self, scheme: &str, fetcher: Arc<dyn Fetcher>) {
+        self.schemes.insert(scheme.to_ascii_lowercase(), fetcher);
+    }
+
+    /// Tells `observer` about every fetch.
+    pub fn observe(&mut self, observer: Arc<dyn FsObserver>) {
+        self.observers.add(observer);
+    }
+
+    /// Aborts the fetches under way, at the next chance their backend
+    /// checks [`cancelled`], and fails every later one, for a mount
+    /// shutting down. Curl transfers check about once a second.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// Refuses every later fetch from a host `policy` doesn't allow.
+    pub fn restrict(&mut self, policy: HostPolicy) {
+        self.hosts = Arc::new(policy);
+    }
+
+    /// Tries fetches that fail in a way that may pass again, as `retry`
+    /// says, rather than failing them right away.
+    pub fn retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+
+    /// Sends the credentials `netrc` has for a URL's host with every later
+    /// fetch of a file without an `auth` of its own.
+    pub fn use_netrc(&mut self, netrc: Netrc) {
+        self.netrc = Some(Arc::new(netrc));
+    }
+
+    /// Looks up the `keyring:` references `keyring` grants for the hosts
+    /// it grants them for, and fails fetches with any others.
+    pub fn use_keyring(&mut self, keyring: Keyring) {
+        self.keyring = Arc::new(keyring);
+    }
+
+    /// Fails, as a fetch of `url`, if it is on a host
+    /// [`Fetchers::restrict`] refuses.
+    pub fn permitted(&self, url: &str) -> std::result::Result<(), LhttpfsError> {
+        (self.hosts.check(url)).map_err(|e| LhttpfsError::fetch(url, Box::new(e)))
+    }
+
+    /// Has every fetch of a URL its backend reads encrypted refuse any URL
+    /// it goes on to that isn't, as a redirect from `https://` to `http://`
+    /// or a download link a Git LFS server hands out would be.
+    pub fn require_https(&mut self) {
+        self.https_only = true;
+    }
+
+    /// Whether the backend of `url` reads it without encryption.
+    pub fn plaintext(&self, url: &str) -> bool {
+        self.get(url).is_ok_and(|fetcher| fetcher.plaintext(url))
+    }
+
+    /// Runs `f` as a fetch of `url` by these fetchers: given up on once they
+    /// are cancelled, only [`admitted`] to the hosts they allow, and kept
+    /// encrypted if [`Fetchers::require_https`] says so.
+    pub fn fetching<T>(&self, url: &str, f: impl FnOnce() -> T) -> T {
+        let cancel = CANCEL.with(|cancel| cancel.replace(Some(self.cancel.clone())));
+        let hosts = HOSTS.with(|hosts| hosts.replace(Some(self.hosts.clone())));
+        let https_only = HTTPS_ONLY.replace(self.https_only && !self.plaintext(url));
+        let result = f();
+        CANCEL.with(|outer| *outer.borrow_mut() = cancel);
+        HOSTS.with(|outer| *outer.borrow_mut() = hosts);
+        HTTPS_ONLY.set(https_only);
+        result
+    }
+
+    /// The same backends and observers, cancelled apart from these.
+    pub fn fork(&self) -> Fetchers {
+        Fetchers {
+            cancel: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Registers the backends built on curl.
+    #[cfg(feature = "http")]
+    fn http(&mut self) {
+        let http = Arc::new(http::Http);
+        self.register("http", http.clone());
+        self.register("https", http);
+        self.register("dav", Arc::new(dav::Dav));
+        self.register("davs", Arc::new(dav::Dav));
+        self.register("ftp", Arc::new(ftp::Ftp));
+        self.register("ftps", Arc::new(ftp::Ftp));
+        let artifactory = Arc::new(artifacts::Artifacts::new(artifacts::Kind::Artifactory));
+        self.register("artifactory+https", artifactory.clone());
+        self.register("artifactory+http", artifactory);
+        let nexus = Arc::new(artifacts::Artifacts::new(artifacts::Kind::Nexus));
+        self.register("nexus+https", nexus.clone());
+        self.register("nexus+http", nexus);
+        self.register("gs", Arc::new(gcs::Gcs::default()));
+        self.register("b2", Arc::new(b2::B2::default()));
+        self.register("az", Arc::new(azure::Azure::default()));
+        self.register("ia", Arc::new(ia::InternetArchive));
+        self.register("ipfs", Arc::new(ipfs::Ipfs::default()));
+        self.register("gdrive", Arc::new(gdrive::GDrive::default()));
+        self.register("hf", Arc::new(hf::Hf::default()));
+        self.register("oci", Arc::new(oci::Oci::default()));
+        let lfs = Arc::new(lfs::Lfs::default());
+        self.register("lfs+https", lfs.clone());
+        self.register("lfs+http", lfs);
+    }
+
+    /// The fetcher for `url`, failing as a fetch of it for schemes nothing
+    /// serves.
+    pub fn get(&self, url: &str) -> std::result::Result<&dyn Fetcher, LhttpfsError> {
+        let scheme = scheme(url);
+        match self.schemes.get(&scheme.to_ascii_lowercase()) {
+            Some(fetcher) => Ok(fetcher.as_ref()),
+            None => Err(LhttpfsError::fetch(
+                url,
+                Box::new(UnsupportedScheme(scheme.to_string())),
+            )),
+        }
+    }
+
+    /// The auth to send with `request`: its own, with the `keyring:`
+    /// references granted for its host resolved, or what the `.netrc` has
+    /// for it.
+    pub(crate) fn auth<'a>(
+        &self,
+        request: &Request<'a>,
+    ) -> std::result::Result<Option<Cow<'a, Auth>>, LhttpfsError> {
+        let resolved = |auth| self.keyring.resolved(auth, request.url);
+        let auth = (request.auth.map(resolved).transpose())
+            .map_err(|e| LhttpfsError::fetch(request.url, Box::new(e)))?;
+        Ok(auth.or_else(|| {
+            (self.netrc.as_ref()).and_then(|netrc| netrc.auth(request.url).map(Cow::Owned))
+        }))
+    }
+
+    /// What the backend of `request.url` tells of it without fetching it.
+    pub fn metadata(&self, request: &Request) -> std::result::Result<Metadata, LhttpfsError> {
+        self.permitted(request.url)?;
+        let fetcher = self.get(request.url)?;
+        let auth = self.auth(request)?;
+        let request = &Request {
+            auth: auth.as_deref(),
+            ..*request
+        };
+        let _span = info_span!("size", otel.kind = "client", url.full = request.url).entered();
+        (self.fetching(request.url, || {
+            self.retrying(request.url, || fetcher.metadata(request))
+        }))
+        .map_err(|e| LhttpfsError::fetch(request.url, Box::new(e)))
+    }
+
+    /// Runs `fetch` of `url`, and again after a growing delay while it
+    /// fails in a way that may pass, as often as [`Fetchers::retry`] allows.
+    fn retrying<T>(
+        &self,
+        url: &str,
+        mut fetch: impl FnMut() -> std::result::Result<T, LhttpfsError>,
+    ) -> std::result::Result<T, LhttpfsError> {
+        let mut delay = self.retry.delay;
+        for _ in 0..self.retry.attempts {
+            match fetch() {
+                Err(e) if e.transient() && !cancelled() => {
+                    warn!(
+                        "Fetching {} failed, trying again in {:?}: {}",
+                        url, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+        fetch()
+    }
+
+    /// Fetches `range` of `request.url`, or all of it, as
+    /// [`Fetchers::fetch_validated`] without the validators.
+    pub fn fetch_range(
+        &self,
+        request: &Request,
+        range: Option<(u64, u64)>,
+    ) -> std::result::Result<Vec<u8>, LhttpfsError> {
+        self.fetch_validated(request, range)
+            .map(|fetched| fetched.data)
+    }
+
+    /// Fetches `range` of `request.url`, or all of it, logging a `fetch`
+    /// event with how long it took, in a span of its own.
+    pub fn fetch_validated(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        if let Some((_, 0)) = range {
+            return Ok(Fetched::default());
+        }
+        if self.cancel.load(Ordering::Relaxed) || ABANDON.with(set) {
+            return Err(LhttpfsError::fetch(request.url, Box::new(Cancelled)));
+        }
+        self.permitted(request.url)?;
+        let span = info_span!(
+            "fetch",
+            otel.kind = "client",
+            url.full = request.url,
+            http.request.header.range = Empty,
+            lhttpfs.bytes = Empty,
+            otel.status_message = Empty,
+        )
+        .entered();
+        if let Some(range) = range.and_then(byte_range) {
+            span.record("http.request.header.range", format!("bytes={}", range));
+        }
+        let start = Instant::now();
+        let fetcher = self.get(request.url)?;
+        let auth = self.auth(request)?;
+        let request = &Request {
+            auth: auth.as_deref(),
+            ..*request
+        };
+        (self.observers).each(|observer| observer.on_fetch_start(request, range));
+        METRICS.fetch_started();
+        let result = self.fetching(request.url, || {
+            self.retrying(request.url, || fetcher.fetch_range(request, range))
+        });
+        let latency = start.elapsed();
+        (self.observers).each(|observer| {
+            let result = result.as_ref().map(|fetched| fetched.data.len());
+            observer.on_fetch_finish(request, range, result, latency)
+        });
+        METRICS.fetch(
+            result.as_ref().ok().map(|fetched| fetched.data.len()),
+            latency,
+        );
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let (offset, length) = range.unwrap_or((0, request.size));
+        match &result {
+            Ok(fetched) => {
+                span.record("lhttpfs.bytes", fetched.data.len());
+                HEALTH.succeeded();
+            }
+            Err(e) if NotModified::answered(e) => HEALTH.succeeded(),
+            // Shutting down, or giving up, says nothing about the origin.
+            Err(_) if self.cancel.load(Ordering::Relaxed) || ABANDON.with(set) => {
+                span.record("otel.status_message", Cancelled.to_string());
+            }
+            Err(e) => {
+                span.record("otel.status_message", e.to_string());
+                HEALTH.failed(e);
+                hooks::fire(hooks::Event::FetchFailed {
+                    url: request.url,
+                    error: e.to_string(),
+                });
+            }
+        }
+        match &result {
+            Ok(fetched) => info!(
+                op = "fetch",
+                url = request.url,
+                offset,
+                length,
+                bytes = fetched.data.len(),
+                latency_ms,
+                status = "ok",
+                "Fetched {}",
+                request.url
+            ),
+            Err(e) => info!(
+                op = "fetch", url = request.url, offset, length, bytes = 0, latency_ms,
+                status = "error", error = %e,
+                "Fetching {} failed: {}", request.url, e
+            ),
+        }
+        result.map_err(|e| LhttpfsError::fetch(request.url, Box::new(e)))
+    }
+}
+
+/// Performs `curl`, following redirects and failing on HTTP errors, and
+/// returns the body.
+#[cfg(feature = "http")]
+pub fn perform(mut curl: Easy) -> crate::Result<Vec<u8>> {
+    curl.fail_on_error(true)?;
+    http::abortable(&mut curl)?;
+    let body = http::following(&mut curl, |curl| {
+        let mut body = Vec::new();
+        let performed = {
+            let mut transfer = curl.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.perform()
+        };
+        performed.map_err(|e| http::status_error(curl, e))?;
+        Ok(body)
+    })?;
+    Ok(body)
+}
+
+/// A range of `len` bytes from `start` as the inclusive `start-end` that
+/// curl and `Range` headers take, or `None` for an empty range, which
+/// can't be written as one and needn't be fetched.
+pub fn byte_range((start, len): (u64, u64)) -> Option<String> {
+    let end = start.saturating_add(len).checked_sub(1)?;
+    (end >= start).then(|| format!("{}-{}", start, end))
+}
+
+/// The most a body's buffer is allocated for before it arrives: sizes come
+/// from layouts, which can be wrong.
+pub(crate) const CAPACITY_MAX: u64 = 64 << 20;
+
+/// `offset`, as an index into something `len` bytes long, which it is past
+/// the end of if it's larger, even where `usize` is narrower than it.
+pub(crate) fn clamped(offset: u64, len: usize) -> usize {
+    usize::try_from(offset).map_or(len, |offset| offset.min(len))
+}
+
+/// The part of `data` covered by `range`, for sources that can only
+/// deliver whole objects.
+pub fn cut(mut data: Vec<u8>, range: Option<(u64, u64)>) -> Vec<u8> {
+    if let Some((start, len)) = range {
+        let start = clamped(start, data.len());
+        let end = start + clamped(len, data.len() - start);
+        data.truncate(end);
+        data.drain(..start);
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        error::Error,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{
+        abandonable, byte_range, cancelled, cut, Cancelled, FetchResult, Fetched, Fetcher,
+        Fetchers, HostPolicy, HostRefused, HttpStatus, Request, Retry, UnsupportedScheme,
+    };
+    use crate::{keyring::KeyringError, layout::Auth, LhttpfsError};
+
+    struct Echo;
+
+    impl Fetcher for Echo {
+        fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+            Ok(cut(request.url.as_bytes().to_vec(), range).into())
+        }
+    }
+
+    #[test]
+    fn schemes() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("echo", Arc::new(Echo));
+        let headers = BTreeMap::new();
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        let data = fetchers.fetch_range(&request("ECHO:hello"), Some((5, 3)));
+        assert_eq!(data.unwrap(), b"hel");
+        #[cfg(feature = "http")]
+        assert!(fetchers.get("https://example.com").is_ok());
+        #[cfg(feature = "http")]
+        assert!(fetchers.get("ftp://ftp.example.com/pub/a").is_ok());
+        let unknown = fetchers.fetch_range(&request("gopher://example.com"), None);
+        assert!(unknown.is_err_and(|e| e.source().unwrap().is::<UnsupportedScheme>()));
+        // Nothing to fetch, whoever would have served it.
+        let empty = fetchers.fetch_range(&request("gopher://example.com"), Some((3, 0)));
+        assert!(empty.unwrap().is_empty());
+    }
+
+    /// Never answers, until cancelled.
+    struct Stalled;
+
+    impl Fetcher for Stalled {
+        fn fetch_range(&self, _request: &Request, _range: Option<(u64, u64)>) -> FetchResult {
+            while !cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(std::io::Error::other(Cancelled).into())
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("stalled", Arc::new(Stalled));
+        fetchers.register("echo", Arc::new(Echo));
+        let forked = fetchers.fork();
+        let fetching = fetchers.clone();
+        let stalled = thread::spawn(move || {
+            let headers = BTreeMap::new();
+            let request = Request {
+                url: "stalled:a",
+                headers: &headers,
+                auth: None,
+                size: 0,
+            };
+            fetching.fetch_range(&request, None).is_err()
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert!(!cancelled());
+        fetchers.cancel();
+        assert!(stalled.join().unwrap());
+        let headers = BTreeMap::new();
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        let later = fetchers.fetch_range(&request("echo:a"), None);
+        assert!(later.is_err_and(|e| e.source().unwrap().is::<Cancelled>()));
+        assert_eq!(
+            forked.fetch_range(&request("echo:a"), None).unwrap(),
+            b"echo:a"
+        );
+    }
+
+    #[test]
+    fn abandon() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("stalled", Arc::new(Stalled));
+        fetchers.register("echo", Arc::new(Echo));
+        let headers = BTreeMap::new();
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        let abandon = Arc::new(AtomicBool::new(false));
+        let (fetching, flag) = (fetchers.clone(), abandon.clone());
+        let stalled = thread::spawn(move || {
+            let headers = BTreeMap::new();
+            let request = Request {
+                url: "stalled:a",
+                headers: &headers,
+                auth: None,
+                size: 0,
+            };
+            abandonable(&flag, || fetching.fetch_range(&request, None).is_err())
+        });
+        thread::sleep(Duration::from_millis(10));
+        abandon.store(true, Ordering::Relaxed);
+        assert!(stalled.join().unwrap());
+        let later = abandonable(&abandon, || fetchers.fetch_range(&request("echo:a"), None));
+        assert!(later.is_err_and(|e| e.source().unwrap().is::<Cancelled>()));
+        // Only what was run abandonable gives up, and the rest of the mount
+        // fetches on.
+        assert!(fetchers.fetch_range(&request("echo:a"), None).is_ok());
+    }
+
+    /// Fails with each of the statuses given, then echoes.
+    struct Flaky(std::sync::Mutex<Vec<u32>>);
+
+    impl Fetcher for Flaky {
+        fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+            match self.0.lock().unwrap().pop() {
+                Some(status) => Err(HttpStatus {
+                    url: request.url.to_string(),
+                    status,
+                }
+                .into()),
+                None => Echo.fetch_range(request, range),
+            }
+        }
+    }
+
+    #[test]
+    fn retried() {
+        let headers = BTreeMap::new();
+        let request = Request {
+            url: "flaky:a",
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        let fetchers = |statuses: &[u32], attempts| {
+            let mut fetchers = Fetchers::default();
+            let flaky = Flaky(std::sync::Mutex::new(statuses.to_vec()));
+            fetchers.register("flaky", Arc::new(flaky));
+            fetchers.retry(Retry {
+                attempts,
+                delay: Duration::from_millis(1),
+            });
+            fetchers
+        };
+        let fetched = fetchers(&[503, 502], 2).fetch_range(&request, None);
+        assert_eq!(fetched.unwrap(), b"flaky:a");
+        let fetched = fetchers(&[503, 502, 500], 2).fetch_range(&request, None);
+        assert_eq!(fetched.unwrap_err().status(), Some(503));
+        // Only what may pass is tried again.
+        let fetched = fetchers(&[503, 404], 2).fetch_range(&request, None);
+        assert_eq!(fetched.unwrap_err().status(), Some(404));
+        let fetched = fetchers(&[503], 0).fetch_range(&request, None);
+        assert_eq!(fetched.unwrap_err().status(), Some(503));
+    }
+
+    #[test]
+    fn sorted_once() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("flaky", Arc::new(Flaky(std::sync::Mutex::new(vec![404]))));
+        let headers = BTreeMap::new();
+        let request = Request {
+            url: "flaky:a",
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        // Backends leave the URL to Fetchers, which say it once.
+        let Err(LhttpfsError::Fetch { source, status, .. }) = fetchers.fetch_range(&request, None)
+        else {
+            panic!("Expected a failed fetch");
+        };
+        assert_eq!(status, Some(404));
+        assert!(source.is::<HttpStatus>());
+        assert_eq!(source.to_string(), "flaky:a returned HTTP 404");
+    }
+
+    #[test]
+    fn restricted() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("echo", Arc::new(Echo));
+        fetchers.restrict(HostPolicy::new(&["allowed"], &[]).unwrap());
+        let headers = BTreeMap::new();
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 0,
+        };
+        let allowed = fetchers.fetch_range(&request("echo://allowed/a"), None);
+        assert_eq!(allowed.unwrap(), b"echo://allowed/a");
+        let refused = fetchers.fetch_range(&request("echo://other/a"), None);
+        assert!(refused.is_err_and(|e| e.source().unwrap().is::<HostRefused>()));
+        // Forks keep the policy.
+        assert!(fetchers.fork().permitted("echo://other/a").is_err());
+    }
+
+    /// Answers with the bearer token it was given.
+    struct Token;
+
+    impl Fetcher for Token {
+        fn fetch_range(&self, request: &Request, _range: Option<(u64, u64)>) -> FetchResult {
+            match request.auth {
+                Some(Auth::Bearer(token)) => Ok(token.clone().into_bytes().into()),
+                _ => Ok(Fetched::default()),
+            }
+        }
+    }
+
+    #[test]
+    fn keyring_references() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("token", Arc::new(Token));
+        let headers = BTreeMap::new();
+        let fetch = |token: &str| {
+            let auth = Auth::Bearer(token.into());
+            let request = Request {
+                url: "token:a",
+                headers: &headers,
+                auth: Some(&auth),
+                size: 0,
+            };
+            fetchers.fetch_range(&request, None)
+        };
+        assert_eq!(fetch("plain").unwrap(), b"plain");
+        // Not looked up unless granted for the host, whatever the layout.
+        let Err(LhttpfsError::Fetch { url, source, .. }) = fetch("keyring:example.com/ci") else {
+            panic!("Expected a reference that wasn't granted to fail the fetch");
+        };
+        assert_eq!(url, "token:a");
+        assert!(source.to_string().contains("not granted for token:a"));
+        assert!(source.is::<KeyringError>());
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(byte_range((0, 10)).as_deref(), Some("0-9"));
+        assert_eq!(byte_range((5, 1)).as_deref(), Some("5-5"));
+        assert_eq!(byte_range((0, 0)), None);
+        assert_eq!(byte_range((7, 0)), None);
+        assert_eq!(
+            byte_range((u64::MAX - 1, 5)).as_deref(),
+            Some("18446744073709551614-18446744073709551614")
+        );
+    }
+}
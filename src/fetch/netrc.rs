@@ -0,0 +1,163 @@
+//! [`Netrc`]: credentials by host from a `.netrc` file, given by `--netrc`,
+//! sent as basic auth to any host it names that a file's `auth` doesn't
+//! already cover, as curl's `--netrc` and most download tools do.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use url::Url;
+
+use crate::layout::Auth;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Netrc {
+    /// `login` and `password` by `machine`.
+    machines: HashMap<String, (String, String)>,
+    /// Those of the `default` entry, for any other host.
+    default: Option<(String, String)>,
+}
+
+/// A `.netrc` that couldn't be parsed.
+#[derive(Debug)]
+pub struct BadNetrc(String);
+
+impl Display for BadNetrc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid .netrc: {}", self.0)
+    }
+}
+
+impl Error for BadNetrc {}
+
+impl Netrc {
+    /// Parses the `machine`, `default`, `login` and `password` tokens of
+    /// `text`, skipping `account`s and `macdef` macros.
+    pub fn parse(text: &str) -> Result<Netrc, BadNetrc> {
+        let mut netrc = Netrc::default();
+        // Where the entry being read goes: a machine, or `None` for the
+        // default.
+        let mut entry: Option<(Option<String>, String, String)> = None;
+        let mut finish = |entry: Option<(Option<String>, String, String)>| match entry {
+            Some((Some(machine), login, password)) => {
+                netrc.machines.entry(machine).or_insert((login, password));
+            }
+            Some((None, login, password)) => netrc.default = Some((login, password)),
+            None => {}
+        };
+        let mut lines = text.lines();
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                let mut value = || {
+                    (tokens.next()).ok_or_else(|| BadNetrc(format!("`{}` without a value", token)))
+                };
+                match token {
+                    "machine" => {
+                        let machine = value()?.to_ascii_lowercase();
+                        finish(entry.replace((Some(machine), String::new(), String::new())));
+                    }
+                    "default" => finish(entry.replace((None, String::new(), String::new()))),
+                    "login" | "password" | "account" => {
+                        let value = value()?.to_string();
+                        let Some((_, login, password)) = &mut entry else {
+                            return Err(BadNetrc(format!("`{}` before any machine", token)));
+                        };
+                        match token {
+                            "login" => *login = value,
+                            "password" => *password = value,
+                            _ => {}
+                        }
+                    }
+                    // A macro runs to the next empty line.
+                    "macdef" => {
+                        for line in lines.by_ref() {
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    token if token.starts_with('#') => break,
+                    token => return Err(BadNetrc(format!("unknown token `{}`", token))),
+                }
+            }
+        }
+        finish(entry);
+        Ok(netrc)
+    }
+
+    /// Reads and parses the file at `path`.
+    pub fn read(path: &Path) -> crate::Result<Netrc> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Reading {} failed: {}", path.display(), e))?;
+        Ok(Netrc::parse(&text)?)
+    }
+
+    /// `~/.netrc`, or `$NETRC` if set, as curl has it.
+    pub fn default_path() -> Option<PathBuf> {
+        match std::env::var_os("NETRC") {
+            Some(path) => Some(path.into()),
+            None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc")),
+        }
+    }
+
+    /// The basic auth to fetch `url` with, if its host has any.
+    pub fn auth(&self, url: &str) -> Option<Auth> {
+        let url = Url::parse(url).ok()?;
+        let host = url.host_str()?.to_ascii_lowercase();
+        let (username, password) = self.machines.get(&host).or(self.default.as_ref())?;
+        Some(Auth::Basic {
+            username: username.clone(),
+            password: password.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::Auth;
+
+    use super::Netrc;
+
+    #[test]
+    fn parsed() {
+        let netrc = Netrc::parse(
+            "machine artifacts.example.com login ci password s3cret\n\
+             # a comment\n\
+             machine Other.example.com\n  login o\n  password p account a\n\
+             macdef init\n  cd pub\n\n\
+             default login anonymous password me@example.com\n",
+        )
+        .unwrap();
+        let basic = |username: &str, password: &str| {
+            Some(Auth::Basic {
+                username: username.into(),
+                password: password.into(),
+            })
+        };
+        assert_eq!(
+            netrc.auth("https://artifacts.example.com/a.bin"),
+            basic("ci", "s3cret")
+        );
+        assert_eq!(
+            netrc.auth("http://other.example.com:8080/b"),
+            basic("o", "p")
+        );
+        assert_eq!(
+            netrc.auth("https://elsewhere.org/c"),
+            basic("anonymous", "me@example.com")
+        );
+        assert_eq!(
+            Netrc::parse("machine a login x")
+                .unwrap()
+                .auth("https://b/"),
+            None
+        );
+        assert!(Netrc::parse("login x").is_err());
+        assert!(Netrc::parse("machine").is_err());
+    }
+}
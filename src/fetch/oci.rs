@@ -0,0 +1,287 @@
+//! oci://registry/repository@sha256:digest URLs, blobs such as image
+//! layers and ORAS artifacts fetched through the distribution API.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use sha2::{Digest, Sha256};
+
+use serde::Deserialize;
+
+use crate::{layout::hex, LhttpfsError};
+
+use super::{
+    http::{easy, get, transfer, Response},
+    FetchResult, Fetched, Fetcher, HttpStatus, Request,
+};
+
+#[derive(Debug)]
+pub struct DigestMismatch(pub String);
+
+impl std::fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Blob {} does not match its digest", self.0)
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+#[derive(Default)]
+pub struct Oci {
+    /// Pull tokens by registry and repository, `None` where none is needed.
+    tokens: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Fetcher for Oci {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let image = parse(request.url)?;
+        let url = image.blob_url(&image.reference);
+        let scope = format!("{}/{}", image.registry, image.repository);
+        // A cached token may have expired, so an HTTP error is retried once
+        // with a fresh one.
+        for attempt in 0..2 {
+            let token = self.token(&scope, &url, attempt > 0)?;
+            let mut headers = request.headers.clone();
+            if let Some(token) = &token {
+                headers.insert("Authorization".into(), format!("Bearer {}", token));
+            }
+            // Registries redirect blob downloads to a CDN, which the token
+            // isn't sent on to.
+            let mut curl = easy(&Request {
+                url: &url,
+                headers: &headers,
+                ..*request
+            })?;
+            match transfer(&mut curl, request.size, range) {
+                Ok(data) => return verify(&image.reference, data, range),
+                Err(e) if attempt == 0 && unauthorized(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Oci {
+    fn token(&self, scope: &str, url: &str, refresh: bool) -> crate::Result<Option<String>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if let (Some(token), false) = (tokens.get(scope), refresh) {
+            return Ok(token.clone());
+        }
+        // Probing with a one byte range is enough to get the challenge.
+        let response = get(url, &["Range: bytes=0-0".to_string()])?;
+        let token = match response.status {
+            401 => anonymous_token(&response)?,
+            _ => None,
+        };
+        tokens.insert(scope.to_string(), token.clone());
+        Ok(token)
+    }
+}
+
+fn parse(url: &str) -> crate::Result<Reference> {
+    let image = url
+        .strip_prefix("oci://")
+        .filter(|image| image.contains('@'))
+        .map(Reference::parse)
+        .ok_or_else(|| format!("{} is not an oci://registry/repository@digest URL", url))?;
+    if !image.reference.starts_with("sha256:") {
+        return Err(format!("{} has to name a sha256 digest", url).into());
+    }
+    Ok(image)
+}
+
+fn unauthorized(error: &LhttpfsError) -> bool {
+    std::error::Error::source(error).is_some_and(|e| e.is::<HttpStatus>())
+}
+
+/// Whole blobs are checked against their digest; ranges can't be.
+fn verify(digest: &str, fetched: Fetched, range: Option<(u64, u64)>) -> FetchResult {
+    let digested = hex(&Sha256::digest(&fetched.data));
+    if range.is_none() && digest.strip_prefix("sha256:") != Some(&digested) {
+        return Err(LhttpfsError::Other(Box::new(DigestMismatch(
+            digest.to_string(),
+        ))));
+    }
+    Ok(fetched)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    /// A tag or a digest.
+    pub reference: String,
+}
+
+impl Reference {
+    pub fn parse(image: &str) -> Reference {
+        let (name, reference) = match image.split_once('@') {
+            Some((name, digest)) => (name, digest.to_string()),
+            None => match image.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+                _ => (image, "latest".to_string()),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_string(), rest.to_string())
+            }
+            _ => ("docker.io".to_string(), name.to_string()),
+        };
+        if registry == "docker.io" {
+            let repository = if repository.contains('/') {
+                repository
+            } else {
+                format!("library/{}", repository)
+            };
+            return Reference {
+                registry: "registry-1.docker.io".into(),
+                repository,
+                reference,
+            };
+        }
+        Reference {
+            registry,
+            repository,
+            reference,
+        }
+    }
+
+    pub fn blob_url(&self, digest: &str) -> String {
+        format!(
+            "https://{}/v2/{}/blobs/{}",
+            self.registry, self.repository, digest
+        )
+    }
+
+    pub fn manifest_url(&self, reference: &str) -> String {
+        format!(
+            "https://{}/v2/{}/manifests/{}",
+            self.registry, self.repository, reference
+        )
+    }
+}
+
+/// Parses the parameters of a `WWW-Authenticate: Bearer k="v",...` challenge.
+pub fn bearer_challenge(header: &str) -> Option<HashMap<String, String>> {
+    let params = header.trim().strip_prefix("Bearer ")?;
+    let mut result = HashMap::new();
+    let mut rest = params;
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let value = value.trim_start();
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            },
+        };
+        result.insert(key, value.to_string());
+        rest = remainder;
+    }
+    Some(result)
+}
+
+/// Requests an anonymous pull token from the realm named by a 401 response.
+pub fn anonymous_token(unauthorized: &Response) -> crate::Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct Token {
+        token: Option<String>,
+        access_token: Option<String>,
+    }
+    let Some(challenge) = unauthorized
+        .header("WWW-Authenticate")
+        .and_then(bearer_challenge)
+    else {
+        return Ok(None);
+    };
+    let Some(realm) = challenge.get("realm") else {
+        return Ok(None);
+    };
+    let mut url = url::Url::parse(realm)?;
+    for key in ["service", "scope"] {
+        if let Some(value) = challenge.get(key) {
+            url.query_pairs_mut().append_pair(key, value);
+        }
+    }
+    let response = get(url.as_str(), &[])?;
+    if response.status >= 400 {
+        return Err(Box::new(HttpStatus {
+            url: url.into(),
+            status: response.status,
+        }));
+    }
+    let token: Token = serde_json::from_slice(&response.body)?;
+    Ok(token.token.or(token.access_token))
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::{bearer_challenge, parse, verify, DigestMismatch, Fetched, Reference};
+
+    const EMPTY: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn references() {
+        let image = parse(&format!("oci://ghcr.io/owner/model@{}", EMPTY)).unwrap();
+        assert_eq!(
+            image.blob_url(&image.reference),
+            format!("https://ghcr.io/v2/owner/model/blobs/{}", EMPTY)
+        );
+        let hub = parse(&format!("oci://alpine@{}", EMPTY)).unwrap();
+        assert_eq!(hub.registry, "registry-1.docker.io");
+        assert!(parse("oci://ghcr.io/owner/model:latest").is_err());
+        assert!(parse("oci://ghcr.io/owner/model@md5:00").is_err());
+    }
+
+    #[test]
+    fn digests() {
+        assert!(verify(EMPTY, Fetched::default(), None).is_ok());
+        assert!(verify(EMPTY, b"x".to_vec().into(), None)
+            .is_err_and(|e| e.source().unwrap().is::<DigestMismatch>()));
+        assert!(verify(EMPTY, b"x".to_vec().into(), Some((0, 1))).is_ok());
+    }
+
+    #[test]
+    fn images() {
+        let parse = |s| {
+            let r = Reference::parse(s);
+            (r.registry, r.repository, r.reference)
+        };
+        assert_eq!(
+            parse("alpine"),
+            (
+                "registry-1.docker.io".into(),
+                "library/alpine".into(),
+                "latest".into()
+            )
+        );
+        assert_eq!(
+            parse("ghcr.io/owner/image:v1"),
+            ("ghcr.io".into(), "owner/image".into(), "v1".into())
+        );
+        assert_eq!(
+            parse("localhost:5000/app@sha256:abc"),
+            ("localhost:5000".into(), "app".into(), "sha256:abc".into())
+        );
+    }
+
+    #[test]
+    fn challenge() {
+        let params = bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        )
+        .unwrap();
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+        assert!(bearer_challenge("Basic realm=x").is_none());
+    }
+}
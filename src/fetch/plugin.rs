@@ -0,0 +1,246 @@
+//! Backends provided by external programs, for schemes lhttpfs doesn't
+//! know, registered with `--backend-plugin scheme=/path/to/program`.
+//!
+//! The program is started on the first read and spoken to over its stdin
+//! and stdout, one JSON object per line. Each request is
+//!
+//! ```json
+//! {"id": 1, "url": "...", "headers": {}, "auth": null, "size": 10, "range": [0, 4]}
+//! ```
+//!
+//! where `range` is `[start, length]`, or `null` for the whole file, and
+//! the answer to it is `{"id": 1, "data": "<base64>"}` or
+//! `{"id": 1, "error": "<message>"}`. Requests are sent one at a time.
+//! Anything the program writes to stderr ends up in lhttpfs' own.
+
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::{Arg, ArgAction};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::LhttpfsError;
+
+use super::{FetchResult, Fetched, Fetcher, Request};
+
+#[derive(Debug)]
+pub struct PluginError {
+    program: PathBuf,
+    message: String,
+}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Plugin {}: {}", self.program.display(), self.message)
+    }
+}
+
+impl Error for PluginError {}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Plugin {
+    program: PathBuf,
+    /// The running program and the id of the last request, started again
+    /// if it goes away.
+    process: Mutex<(Option<Process>, u64)>,
+}
+
+impl Plugin {
+    pub fn new(program: impl Into<PathBuf>) -> Plugin {
+        Plugin {
+            program: program.into(),
+            process: Mutex::new((None, 0)),
+        }
+    }
+
+    fn error(&self, message: impl Display) -> LhttpfsError {
+        LhttpfsError::Other(Box::new(PluginError {
+            program: self.program.clone(),
+            message: message.to_string(),
+        }))
+    }
+
+    fn spawn(&self) -> Result<Process, LhttpfsError> {
+        debug!("Starting plugin {}", self.program.display());
+        let mut child = Command::new(&self.program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| self.error(e))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Process {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: u64,
+    data: Option<String>,
+    error: Option<String>,
+}
+
+impl Fetcher for Plugin {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let mut state = self.process.lock().unwrap();
+        let (process, id) = &mut *state;
+        *id += 1;
+        let line = json!({
+            "id": *id,
+            "url": request.url,
+            "headers": request.headers,
+            "auth": request.auth,
+            "size": request.size,
+            "range": range.map(|(start, len)| [start, len]),
+        });
+        if process.is_none() {
+            *process = Some(self.spawn()?);
+        }
+        let running = process.as_mut().unwrap();
+        let mut answer = String::new();
+        let exchanged = writeln!(running.stdin, "{}", line)
+            .and_then(|_| running.stdin.flush())
+            .and_then(|_| running.stdout.read_line(&mut answer));
+        match exchanged {
+            Ok(n) if n > 0 => {}
+            result => {
+                // The next request starts the program again.
+                *process = None;
+                return Err(match result {
+                    Err(e) => self.error(e),
+                    Ok(_) => self.error("exited"),
+                });
+            }
+        }
+        let response: Response = serde_json::from_str(&answer).map_err(|e| {
+            *process = None;
+            self.error(format!("bad response: {}", e))
+        })?;
+        if response.id != *id {
+            warn!("Plugin {} is out of step", self.program.display());
+            *process = None;
+            return Err(self.error(format!(
+                "answered request {} instead of {}",
+                response.id, id
+            )));
+        }
+        match (response.data, response.error) {
+            (_, Some(error)) => Err(self.error(error)),
+            (Some(data), None) => {
+                (BASE64.decode(data).map(Fetched::from)).map_err(|e| self.error(e))
+            }
+            (None, None) => Err(self.error("response has neither data nor error")),
+        }
+    }
+}
+
+pub fn arg() -> Arg {
+    Arg::new("backend-plugin")
+        .long("backend-plugin")
+        .value_name("SCHEME=PROGRAM")
+        .action(ArgAction::Append)
+        .value_parser(parse_spec)
+        .help("Read SCHEME:// URLs with an external program, see the README")
+}
+
+/// Splits a `--backend-plugin` value into its scheme and program.
+pub fn parse_spec(spec: &str) -> Result<(String, PathBuf), String> {
+    match spec.split_once('=') {
+        Some((scheme, program))
+            if !scheme.is_empty()
+                && !program.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+        {
+            Ok((scheme.to_ascii_lowercase(), PathBuf::from(program)))
+        }
+        _ => Err(format!("{} is not scheme=/path/to/program", spec)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, error::Error, os::unix::fs::PermissionsExt};
+
+    use crate::fetch::{Fetcher, Request};
+
+    use super::{parse_spec, Plugin, PluginError};
+
+    #[test]
+    fn specs() {
+        let (scheme, program) = parse_spec("Vault+S=/opt/vault-fetch").unwrap();
+        assert_eq!(scheme, "vault+s");
+        assert_eq!(program.to_str(), Some("/opt/vault-fetch"));
+        assert!(parse_spec("/opt/vault-fetch").is_err());
+        assert!(parse_spec("a b=/x").is_err());
+    }
+
+    #[test]
+    fn protocol() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-plugin-{}", std::process::id()));
+        // Answers "hello", or an error for URLs ending in "missing".
+        std::fs::write(
+            &path,
+            r#"#!/bin/sh
+while read -r line; do
+  id=$(echo "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+  case "$line" in
+    *missing*) echo "{\"id\":$id,\"error\":\"no such object\"}" ;;
+    *) echo "{\"id\":$id,\"data\":\"aGVsbG8=\"}" ;;
+  esac
+done
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let plugin = Plugin::new(&path);
+        let headers = BTreeMap::new();
+        let request = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: 5,
+        };
+        assert_eq!(
+            plugin.fetch_range(&request("x://a"), None).unwrap().data,
+            b"hello"
+        );
+        let error = plugin.fetch_range(&request("x://missing"), Some((0, 2)));
+        assert!(error.is_err_and(|e| e
+            .source()
+            .unwrap()
+            .downcast_ref::<PluginError>()
+            .is_some_and(|e| e.message == "no such object")));
+        assert_eq!(
+            plugin.fetch_range(&request("x://b"), None).unwrap().data,
+            b"hello"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
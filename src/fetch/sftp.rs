@@ -0,0 +1,191 @@
+//! sftp://user@host/path URLs, read over SSH with public key
+//! authentication. Hosts have to be in `known_hosts`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use percent_encoding::percent_decode_str;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp as Channel};
+use tracing::debug;
+use url::Url;
+
+use crate::layout::Auth;
+
+use super::{FetchResult, Fetcher, Request};
+
+/// Keys tried when a layout doesn't name one, in order.
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+#[derive(Default)]
+pub struct Sftp {
+    /// Open connections by `user@host:port`, reused across reads.
+    sessions: Mutex<HashMap<String, (Session, Channel)>>,
+}
+
+impl Fetcher for Sftp {
+    fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+        let url = Url::parse(request.url)?;
+        let host = url.host_str().ok_or("sftp:// URLs need a host")?;
+        let port = url.port().unwrap_or(22);
+        let user = match (url.username(), request.auth) {
+            ("", Some(Auth::Basic { username, .. })) => username.clone(),
+            ("", _) => std::env::var("USER").map_err(|_| "sftp:// URLs need a user")?,
+            (user, _) => percent_decode_str(user).decode_utf8()?.into_owned(),
+        };
+        let path = percent_decode_str(url.path()).decode_utf8()?.into_owned();
+        let id = format!("{}@{}:{}", user, host, port);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        // A cached connection may have been closed by the server meanwhile,
+        // so a failed read is retried once on a new one.
+        for attempt in 0..2 {
+            if !sessions.contains_key(&id) {
+                let session = connect(host, port, &user, request.auth)?;
+                let channel = session.sftp()?;
+                sessions.insert(id.clone(), (session, channel));
+            }
+            match read(&sessions[&id].1, &path, range) {
+                Ok(data) => return Ok(data.into()),
+                Err(e) if attempt == 0 && e.kind() != io::ErrorKind::NotFound => {
+                    debug!("Reading {} failed ({}), reconnecting", request.url, e);
+                    sessions.remove(&id);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!()
+    }
+}
+
+fn read(channel: &Channel, path: &str, range: Option<(u64, u64)>) -> io::Result<Vec<u8>> {
+    let mut file = channel.open(Path::new(path))?;
+    let mut data = Vec::new();
+    match range {
+        Some((start, len)) => {
+            file.seek(SeekFrom::Start(start))?;
+            file.take(len).read_to_end(&mut data)?;
+        }
+        None => {
+            file.read_to_end(&mut data)?;
+        }
+    }
+    Ok(data)
+}
+
+/// Fails unless `key` is the key listed for `host` in `known_hosts`.
+fn check_host(
+    session: &Session,
+    known_hosts: &Path,
+    host: &str,
+    port: u16,
+    key: &[u8],
+) -> crate::Result<()> {
+    let mut known = session.known_hosts()?;
+    known.read_file(known_hosts, KnownHostFileKind::OpenSSH)?;
+    match known.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => {
+            Err(format!("{} is not in {}", host, known_hosts.display()).into())
+        }
+        CheckResult::Mismatch => Err(format!(
+            "The host key of {} does not match the one in {}",
+            host,
+            known_hosts.display()
+        )
+        .into()),
+        CheckResult::Failure => Err("Checking the host key failed".into()),
+    }
+}
+
+fn ssh_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var_os("HOME")?).join(".ssh"))
+}
+
+fn connect(host: &str, port: u16, user: &str, auth: Option<&Auth>) -> crate::Result<Session> {
+    let (key, passphrase, known_hosts) = match auth {
+        Some(Auth::Ssh {
+            key,
+            passphrase,
+            known_hosts,
+        }) => (
+            key.as_deref(),
+            passphrase.as_deref(),
+            known_hosts.as_deref(),
+        ),
+        _ => (None, None, None),
+    };
+    let mut session = Session::new()?;
+    session.set_tcp_stream(TcpStream::connect((host, port))?);
+    session.handshake()?;
+
+    let known_hosts = match known_hosts {
+        Some(path) => PathBuf::from(path),
+        None => ssh_dir()
+            .ok_or("No home directory to find known_hosts in")?
+            .join("known_hosts"),
+    };
+    let (host_key, _) = session.host_key().ok_or("The server sent no host key")?;
+    check_host(&session, &known_hosts, host, port, host_key)?;
+
+    match (auth, key) {
+        (Some(Auth::Basic { password, .. }), _) => session.userauth_password(user, password)?,
+        (_, Some(key)) => session.userauth_pubkey_file(user, None, Path::new(key), passphrase)?,
+        (_, None) => {
+            if session.userauth_agent(user).is_err() {
+                let keys = DEFAULT_KEYS
+                    .iter()
+                    .filter_map(|name| Some(ssh_dir()?.join(name)))
+                    .filter(|key| key.exists());
+                for key in keys {
+                    if session
+                        .userauth_pubkey_file(user, None, &key, passphrase)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    if !session.authenticated() {
+        return Err(format!("Could not authenticate as {} on {}", user, host).into());
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod test {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use ssh2::Session;
+
+    use super::check_host;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIEA5SDzH4feSBAtXWcL5BiZTCtCaMgxI4hv46xt49QMI";
+
+    #[test]
+    fn known_hosts() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-known-{}", std::process::id()));
+        let other = KEY.replace('M', "N");
+        let lines = format!(
+            "example.com ssh-ed25519 {}\n[example.org]:2222 ssh-ed25519 {}\n",
+            KEY, other
+        );
+        std::fs::write(&path, lines).unwrap();
+        let session = Session::new().unwrap();
+        let key = BASE64.decode(KEY).unwrap();
+        assert!(check_host(&session, &path, "example.com", 22, &key).is_ok());
+        let mismatch = check_host(&session, &path, "example.org", 2222, &key).unwrap_err();
+        assert!(
+            mismatch.to_string().contains("does not match"),
+            "{}",
+            mismatch
+        );
+        assert!(check_host(&session, &path, "example.net", 22, &key).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+//! Torrent files read from several web seeds at once (BEP 19), checking
+//! every piece against the torrent's SHA-1 hashes.
+
+use std::{error::Error, fmt::Display, sync::Mutex};
+
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use crate::{
+    layout::{hex, Pieces},
+    Result,
+};
+
+use super::{Fetchers, Request};
+
+/// Pieces fetched at once from each seed.
+const PER_SEED: usize = 2;
+
+#[derive(Debug)]
+pub struct BadPieces(Vec<usize>);
+
+impl Display for BadPieces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No web seed served good data for pieces {:?}", self.0)
+    }
+}
+
+impl Error for BadPieces {}
+
+/// Where piece `index` lies within the file, and whether it lies there
+/// completely. Pieces hanging over into neighbouring files can't be
+/// checked, as only this file's bytes are fetched.
+fn span(pieces: &Pieces, size: u64, index: usize) -> (u64, u64, bool) {
+    let first = pieces.offset - pieces.offset % pieces.length;
+    let start = first + index as u64 * pieces.length;
+    let end = start + pieces.length;
+    let from = start.max(pieces.offset) - pieces.offset;
+    let to = end.min(pieces.offset + size) - pieces.offset;
+    let whole = start >= pieces.offset && end <= pieces.offset + size;
+    (from, to - from, whole)
+}
+
+/// Reads the whole file from `seeds`, which serve the same bytes, spreading
+/// the pieces across them and moving on to the next seed when one fails or
+/// sends a piece that doesn't match its hash.
+pub fn fetch_pieces(fetchers: &Fetchers, seeds: &[Request], pieces: &Pieces) -> Result<Vec<u8>> {
+    let size = seeds[0].size;
+    let count = pieces
+        .sha1
+        .len()
+        .min(size.div_ceil(pieces.length.max(1)) as usize + 1);
+    let spans = (0..count)
+        .map(|i| span(pieces, size, i))
+        .filter(|(_, len, _)| *len > 0)
+        .enumerate()
+        .collect::<Vec<_>>();
+    let todo = Mutex::new(spans.iter());
+    let done = Mutex::new(vec![None; spans.len()]);
+    let failed = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for worker in 0..(seeds.len() * PER_SEED).min(spans.len()) {
+            let (todo, done, failed) = (&todo, &done, &failed);
+            scope.spawn(move || loop {
+                let Some(&(i, (start, len, whole))) = todo.lock().unwrap().next() else {
+                    return;
+                };
+                let data = (0..seeds.len()).find_map(|attempt| {
+                    let seed = &seeds[(worker + attempt) % seeds.len()];
+                    match fetchers.fetch_range(seed, Some((start, len))) {
+                        Ok(data) if whole && hex(&Sha1::digest(&data)) != pieces.sha1[i] => {
+                            warn!("Piece {} from {} does not match its hash", i, seed.url);
+                            None
+                        }
+                        Ok(data) if data.len() as u64 != len => {
+                            warn!("Piece {} from {} is short", i, seed.url);
+                            None
+                        }
+                        Ok(data) => Some(data),
+                        Err(e) => {
+                            warn!("Fetching piece {} from {} failed: {}", i, seed.url, e);
+                            None
+                        }
+                    }
+                });
+                match data {
+                    Some(data) => done.lock().unwrap()[i] = Some(data),
+                    None => failed.lock().unwrap().push(i),
+                }
+            });
+        }
+    });
+    let mut failed = failed.into_inner().unwrap();
+    if !failed.is_empty() {
+        failed.sort();
+        return Err(Box::new(BadPieces(failed)));
+    }
+    let mut data = Vec::with_capacity(size.min(super::CAPACITY_MAX) as usize);
+    for piece in done.into_inner().unwrap() {
+        data.extend(piece.unwrap());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        fetch::{cut, FetchResult, Fetcher, Fetchers, Request},
+        layout::{hex, Pieces},
+    };
+
+    use super::{fetch_pieces, span, BadPieces};
+
+    /// Serves `DATA`, or a corrupted copy of it for `bad:` URLs.
+    struct Seed;
+
+    const DATA: &[u8] = b"0123456789abcdefghij";
+
+    impl Fetcher for Seed {
+        fn fetch_range(&self, request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+            let mut data = DATA.to_vec();
+            if request.url.starts_with("bad:") {
+                data[12] = b'!';
+            }
+            Ok(cut(data, range).into())
+        }
+    }
+
+    #[test]
+    fn spans() {
+        // A 20 byte file starting 6 bytes into the torrent's 8 byte pieces.
+        let pieces = Pieces {
+            length: 8,
+            offset: 6,
+            sha1: vec![String::new(); 4],
+        };
+        let spans = (0..4).map(|i| span(&pieces, 20, i)).collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [(0, 2, false), (2, 8, true), (10, 8, true), (18, 2, false)]
+        );
+    }
+
+    #[test]
+    fn seeds() {
+        let mut fetchers = Fetchers::default();
+        fetchers.register("good", Arc::new(Seed));
+        fetchers.register("bad", Arc::new(Seed));
+        let pieces = Pieces {
+            length: 8,
+            offset: 0,
+            sha1: DATA
+                .chunks(8)
+                .map(|piece| hex(&Sha1::digest(piece)))
+                .collect(),
+        };
+        let headers = BTreeMap::new();
+        let seed = |url| Request {
+            url,
+            headers: &headers,
+            auth: None,
+            size: DATA.len() as u64,
+        };
+        let data = fetch_pieces(&fetchers, &[seed("bad:a"), seed("good:a")], &pieces);
+        assert_eq!(data.unwrap(), DATA);
+        // The last piece is short and can't be told apart from one hanging
+        // over into the next file, so only the middle one is caught.
+        let data = fetch_pieces(&fetchers, &[seed("bad:a")], &pieces);
+        assert!(data.is_err_and(|e| e.downcast_ref::<BadPieces>().unwrap().0 == [1]));
+    }
+}
@@ -0,0 +1,275 @@
+//! A C ABI over [`RemoteTree`] and mounting, declared in
+//! `include/lhttpfs.h`, for C and C++ programs to read a layout's files
+//! without a mount or to mount one of their own.
+//!
+//! Every call that can fail returns `NULL` or -1 and leaves what went
+//! wrong for [`lhttpfs_last_error`] on the calling thread.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    ptr, slice,
+};
+
+use fuser::BackgroundSession;
+
+use crate::{
+    fs::{LazyHTTPFS, MountBuilder, RemoteFile, RemoteTree},
+    layout, LhttpfsError,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+pub struct Tree(RemoteTree);
+
+pub struct OpenFile(RemoteFile);
+
+pub struct Mount(#[allow(dead_code)] BackgroundSession);
+
+/// `result`'s value, or `None` with its error kept for
+/// [`lhttpfs_last_error`].
+fn kept<T>(result: Result<T, LhttpfsError>) -> Option<T> {
+    result
+        .map_err(|e| {
+            let message = CString::new(e.to_string().replace('\0', " "));
+            LAST_ERROR.with(|last| *last.borrow_mut() = message.ok());
+        })
+        .ok()
+}
+
+/// The string `s` points to, which must be UTF-8, or `None` for `NULL`.
+unsafe fn string(s: *const c_char, what: &str) -> Result<Option<String>, LhttpfsError> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    let s = CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("The {} isn't UTF-8", what));
+    Ok(Some(
+        s.map_err(|e| LhttpfsError::Other(e.into()))?.to_owned(),
+    ))
+}
+
+/// The filesystem of the layout at `layout`, caching in `cache_dir` if not
+/// `NULL`.
+unsafe fn built(
+    layout: *const c_char,
+    cache_dir: *const c_char,
+) -> Result<LazyHTTPFS, LhttpfsError> {
+    let missing = || LhttpfsError::Other("No layout given".into());
+    let path = string(layout, "layout path")?.ok_or_else(missing)?;
+    let files = layout::parse(BufReader::new(File::open(path)?))?;
+    let cache_dir = string(cache_dir, "cache directory")?.map(PathBuf::from);
+    LazyHTTPFS::builder().cache_dir(cache_dir).build(files)
+}
+
+/// What went wrong last on this thread, or `NULL` if nothing has. The
+/// string stays valid until the thread's next failing call.
+#[no_mangle]
+pub extern "C" fn lhttpfs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Opens the tree the JSON layout file at `layout` describes, caching
+/// fetched bytes in `cache_dir`, or only in memory if it is `NULL`.
+///
+/// # Safety
+///
+/// `layout` and `cache_dir` must be `NULL` or point to NUL-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_open_tree(
+    layout: *const c_char,
+    cache_dir: *const c_char,
+) -> *mut Tree {
+    match kept(built(layout, cache_dir)) {
+        Some(fs) => Box::into_raw(Box::new(Tree(RemoteTree::new(fs)))),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Closes a tree, once the files opened from it are closed or not:
+/// they keep what they need of it.
+///
+/// # Safety
+///
+/// `tree` must be `NULL` or from [`lhttpfs_open_tree`], and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_close_tree(tree: *mut Tree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+/// Opens the file at `path` of `tree`, from its root.
+///
+/// # Safety
+///
+/// `tree` must be from [`lhttpfs_open_tree`] and not closed, `path` a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_open(tree: *const Tree, path: *const c_char) -> *mut OpenFile {
+    let opened = string(path, "path").and_then(|path| (*tree).0.open(&path.unwrap_or_default()));
+    match kept(opened) {
+        Some(file) => Box::into_raw(Box::new(OpenFile(file))),
+        None => ptr::null_mut(),
+    }
+}
+
+/// The size of `file`, 0 while it isn't known.
+///
+/// # Safety
+///
+/// `file` must be from [`lhttpfs_open`] and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_size(file: *const OpenFile) -> u64 {
+    (*file).0.size()
+}
+
+/// Reads up to `len` bytes of `file` from `offset` into `buf`, returning
+/// how many it read, fewer only at the end of the file, or -1.
+///
+/// # Safety
+///
+/// `file` must be from [`lhttpfs_open`] and not closed, `buf` writable
+/// for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_read_at(
+    file: *const OpenFile,
+    offset: u64,
+    buf: *mut u8,
+    len: usize,
+) -> i64 {
+    match kept((*file).0.read_at(offset, len)) {
+        Some(data) => {
+            slice::from_raw_parts_mut(buf, len)[..data.len()].copy_from_slice(&data);
+            data.len() as i64
+        }
+        None => -1,
+    }
+}
+
+/// # Safety
+///
+/// `file` must be `NULL` or from [`lhttpfs_open`], and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_close(file: *mut OpenFile) {
+    if !file.is_null() {
+        drop(Box::from_raw(file));
+    }
+}
+
+/// Mounts the layout at `layout` on `mountpoint`, caching in `cache_dir`
+/// if not `NULL`, served on threads of its own until
+/// [`lhttpfs_unmount`].
+///
+/// # Safety
+///
+/// `layout`, `cache_dir` and `mountpoint` must be `NULL` or point to
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_mount(
+    layout: *const c_char,
+    cache_dir: *const c_char,
+    mountpoint: *const c_char,
+) -> *mut Mount {
+    let mounted = built(layout, cache_dir).and_then(|fs| {
+        let missing = || LhttpfsError::Other("No mountpoint given".into());
+        let mountpoint = PathBuf::from(string(mountpoint, "mountpoint")?.ok_or_else(missing)?);
+        MountBuilder::new(fs).spawn_mount(mountpoint)
+    });
+    match kept(mounted) {
+        Some(session) => Box::into_raw(Box::new(Mount(session))),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Unmounts what [`lhttpfs_mount`] mounted, once its reads are answered.
+///
+/// # Safety
+///
+/// `mount` must be `NULL` or from [`lhttpfs_mount`], and not unmounted.
+#[no_mangle]
+pub unsafe extern "C" fn lhttpfs_unmount(mount: *mut Mount) {
+    if !mount.is_null() {
+        drop(Box::from_raw(mount));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ffi::{CStr, CString},
+        ptr,
+    };
+
+    use super::{
+        lhttpfs_close, lhttpfs_close_tree, lhttpfs_last_error, lhttpfs_mount, lhttpfs_open,
+        lhttpfs_open_tree, lhttpfs_read_at, lhttpfs_size,
+    };
+
+    /// The functions this module exports, as `include/lhttpfs.h` must
+    /// declare them.
+    fn exported() -> Vec<&'static str> {
+        let source = include_str!("ffi.rs");
+        let names = source.split("extern \"C\" fn ").skip(1);
+        let names = names.filter_map(|rest| rest.split('(').next());
+        names.filter(|name| name.starts_with("lhttpfs_")).collect()
+    }
+
+    #[test]
+    fn header() {
+        let header = include_str!("../include/lhttpfs.h");
+        let declared = header
+            .lines()
+            .filter(|line| !line.starts_with(['/', ' ', '#']));
+        let header = declared.collect::<Vec<_>>().join("\n").replace('*', " ");
+        let exported = exported();
+        assert_eq!(exported.len(), 9);
+        for name in exported {
+            assert!(
+                header.contains(&format!(" {}(", name)),
+                "{} isn't declared",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn read_through_c() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-ffi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let layout = dir.join("layout.json");
+        let json = r#"[{"name": "d", "contents": [{"name": "a", "content": "hello"}]}]"#;
+        std::fs::write(&layout, json).unwrap();
+        let layout = CString::new(layout.to_str().unwrap()).unwrap();
+        unsafe {
+            let tree = lhttpfs_open_tree(layout.as_ptr(), ptr::null());
+            assert!(!tree.is_null());
+            let file = lhttpfs_open(tree, c"/d/a".as_ptr());
+            // The file keeps the tree open.
+            lhttpfs_close_tree(tree);
+            assert_eq!(lhttpfs_size(file), 5);
+            let mut buf = [0u8; 8];
+            assert_eq!(lhttpfs_read_at(file, 1, buf.as_mut_ptr(), buf.len()), 4);
+            assert_eq!(&buf[..4], b"ello");
+            lhttpfs_close(file);
+
+            let tree = lhttpfs_open_tree(layout.as_ptr(), ptr::null());
+            assert!(lhttpfs_open(tree, c"/d/b".as_ptr()).is_null());
+            let error = CStr::from_ptr(lhttpfs_last_error()).to_str().unwrap();
+            assert!(error.contains("/d/b"), "{}", error);
+            lhttpfs_close_tree(tree);
+            assert!(lhttpfs_open_tree(c"/nonexistent.json".as_ptr(), ptr::null()).is_null());
+            assert!(lhttpfs_mount(layout.as_ptr(), ptr::null(), ptr::null()).is_null());
+            let error = CStr::from_ptr(lhttpfs_last_error()).to_str().unwrap();
+            assert_eq!(error, "No mountpoint given");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! `--include`/`--exclude` globs that prune a layout before it's written or
+//! mounted.
+
+use clap::{Arg, ArgAction, ArgMatches};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::{layout::InputFile, Result};
+
+pub fn args() -> [Arg; 2] {
+    [
+        Arg::new("include")
+            .long("include")
+            .action(ArgAction::Append)
+            .help("Only keep files matching this glob, e.g. '*.safetensors' (repeatable)"),
+        Arg::new("exclude")
+            .long("exclude")
+            .action(ArgAction::Append)
+            .help("Drop files and directories matching this glob (repeatable)"),
+    ]
+}
+
+/// Globs without a `/` match the name of an entry at any depth, the rest
+/// match its whole path from the root, where `*` stops at `/` and `**`
+/// doesn't.
+pub struct Filter {
+    include: Option<Patterns>,
+    exclude: Patterns,
+}
+
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    fn new<'a>(globs: impl Iterator<Item = &'a String>) -> Result<Patterns> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for glob in globs {
+            match glob.trim_start_matches('/') {
+                g if g.contains('/') => {
+                    paths.add(GlobBuilder::new(g).literal_separator(true).build()?)
+                }
+                g => names.add(Glob::new(g)?),
+            };
+        }
+        Ok(Patterns {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    fn matches(&self, path: &str, name: &str) -> bool {
+        self.names.is_match(name) || self.paths.is_match(path)
+    }
+}
+
+impl Filter {
+    /// The filter given by `--include`/`--exclude`, if either was.
+    pub fn from_matches(matches: &ArgMatches) -> Result<Option<Filter>> {
+        let include = matches.get_many::<String>("include");
+        let exclude = matches.get_many::<String>("exclude");
+        if include.is_none() && exclude.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Filter {
+            include: include.map(Patterns::new).transpose()?,
+            exclude: Patterns::new(exclude.into_iter().flatten())?,
+        }))
+    }
+
+    /// Drops excluded entries, and files that aren't included along with the
+    /// directories this leaves empty.
+    pub fn apply(&self, files: Vec<InputFile>) -> Vec<InputFile> {
+        self.prune(files, "")
+    }
+
+    fn prune(&self, files: Vec<InputFile>, parent: &str) -> Vec<InputFile> {
+        files
+            .into_iter()
+            .filter_map(|file| {
+                let path = format!("{}{}", parent, file.name());
+                if self.exclude.matches(&path, file.name()) {
+                    return None;
+                }
+                match file {
+                    InputFile::Directory(mut dir) => {
+                        let included = self
+                            .include
+                            .as_ref()
+                            .is_some_and(|i| i.matches(&path, &dir.name));
+                        if !included {
+                            let was_empty = dir.contents.is_empty();
+                            dir.contents = self.prune(dir.contents, &format!("{}/", path));
+                            if dir.contents.is_empty() && !(was_empty && self.include.is_none()) {
+                                return None;
+                            }
+                        }
+                        Some(InputFile::Directory(dir))
+                    }
+                    file => match &self.include {
+                        Some(include) if !include.matches(&path, file.name()) => None,
+                        _ => Some(file),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::{self, InputFile};
+
+    use super::{args, Filter};
+
+    fn names(files: &[InputFile]) -> Vec<String> {
+        let mut out = Vec::new();
+        for file in files {
+            match file {
+                InputFile::Directory(dir) => {
+                    out.push(format!("{}/", dir.name));
+                    out.extend(
+                        names(&dir.contents)
+                            .iter()
+                            .map(|n| format!("{}/{}", dir.name, n)),
+                    );
+                }
+                file => out.push(file.name().to_owned()),
+            }
+        }
+        out
+    }
+
+    fn filter(argv: &[&str]) -> Filter {
+        let command = clap::Command::new("test").args(args());
+        Filter::from_matches(&command.get_matches_from(argv))
+            .unwrap()
+            .unwrap()
+    }
+
+    const LAYOUT: &str = r#"[
+        {"name": "model.safetensors", "size": 1, "url": "https://example.com/m"},
+        {"name": "README.md", "content": "hi"},
+        {"name": "onnx", "contents": [
+            {"name": "model.onnx", "size": 1, "url": "https://example.com/o"},
+            {"name": "extra.safetensors", "size": 1, "url": "https://example.com/e"}
+        ]},
+        {"name": "empty", "contents": []}
+    ]"#;
+
+    #[test]
+    fn include_and_exclude() {
+        let files = || layout::parse(LAYOUT.as_bytes()).unwrap();
+        let only = filter(&["test", "--include", "*.safetensors"]).apply(files());
+        assert_eq!(
+            names(&only),
+            ["model.safetensors", "onnx/", "onnx/extra.safetensors"]
+        );
+
+        let rooted = filter(&["test", "--include", "onnx/*"]).apply(files());
+        assert_eq!(
+            names(&rooted),
+            ["onnx/", "onnx/model.onnx", "onnx/extra.safetensors"]
+        );
+
+        let without = filter(&["test", "--exclude", "onnx", "--exclude", "*.md"]).apply(files());
+        assert_eq!(names(&without), ["model.safetensors", "empty/"]);
+    }
+}
@@ -0,0 +1,365 @@
+//! [`LazyHTTPFS::builder`]: what a mount is resolved and served with,
+//! besides the layout.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{add_inodes, resolve_slices, Control, LazyHTTPFS, BLOCKWISE, TTL};
+use crate::{
+    cache::Cache,
+    fetch::{Fetcher, Fetchers, HostPolicy, Netrc, Retry},
+    keyring::Keyring,
+    layout::{self, Defaults, Directory, InputFile, Limits},
+    observer::{FsObserver, Observers},
+    LhttpfsError,
+};
+
+/// Configures a [`LazyHTTPFS`] before [`Builder::build`] resolves a layout
+/// with it.
+pub struct Builder {
+    defaults: Defaults,
+    /// `None` for a cache of its own in [`Cache::default_dir`].
+    cache: Option<Arc<Mutex<Cache>>>,
+    fetchers: Fetchers,
+    attr_ttl: Duration,
+    hooks: Option<PathBuf>,
+    access_log: Option<(Arc<File>, PathBuf)>,
+    observers: Vec<Arc<dyn FsObserver>>,
+    limits: Limits,
+    probe: bool,
+}
+
+/// How many files [`Builder::build`] asks the size of at once.
+const PROBE_JOBS: usize = 8;
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder {
+            defaults: Defaults::default(),
+            cache: None,
+            fetchers: Fetchers::default(),
+            attr_ttl: TTL,
+            hooks: None,
+            access_log: None,
+            observers: Vec::new(),
+            limits: Limits::default(),
+            probe: true,
+        }
+    }
+}
+
+impl Builder {
+    /// What the layout's entries inherit, under the root's own.
+    pub fn defaults(mut self, defaults: Defaults) -> Builder {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Keeps `disk` cached files in `dir`, or in memory with `None`,
+    /// instead of in [`Cache::default_dir`].
+    pub fn cache_dir(mut self, dir: Option<PathBuf>) -> Builder {
+        self.cache = Some(Arc::new(Mutex::new(Cache::new(dir))));
+        self
+    }
+
+    /// Sends what `netrc` has for a host with fetches of files without an
+    /// `auth`, as [`Fetchers::use_netrc`].
+    pub fn netrc(mut self, netrc: Netrc) -> Builder {
+        self.fetchers.use_netrc(netrc);
+        self
+    }
+
+    /// Looks up the `keyring:` references `keyring` grants, for the hosts
+    /// it grants them for, as [`Fetchers::use_keyring`].
+    pub fn keyring(mut self, keyring: Keyring) -> Builder {
+        self.fetchers.use_keyring(keyring);
+        self
+    }
+
+    /// Uses the cache and backends of `other`, as [`LazyHTTPFS::share`].
+    pub fn share(mut self, other: &LazyHTTPFS) -> Builder {
+        self.cache = Some(other.cache.clone());
+        self.fetchers = other.fetchers.clone();
+        self
+    }
+
+    /// Reads `scheme` URLs with `fetcher`, replacing the built-in one if
+    /// there is one. Archives are indexed while building, so this has to
+    /// come before [`Builder::build`] for those to be read through it.
+    pub fn fetcher(mut self, scheme: &str, fetcher: Arc<dyn Fetcher>) -> Builder {
+        self.fetchers.register(scheme, fetcher);
+        self
+    }
+
+    /// Refuses fetches from hosts `policy` doesn't allow, of archive
+    /// indexes while building and of files once built.
+    pub fn hosts(mut self, policy: HostPolicy) -> Builder {
+        self.fetchers.restrict(policy);
+        self
+    }
+
+    /// Tries fetches that fail in a way that may pass again, as
+    /// [`Fetchers::retry`], while building and once built.
+    pub fn retry(mut self, retry: Retry) -> Builder {
+        self.fetchers.retry(retry);
+        self
+    }
+
+    /// How long cached bytes stay valid, unless the layout says otherwise.
+    pub fn ttl(mut self, ttl: Duration) -> Builder {
+        self.defaults.ttl = Some(ttl.as_secs());
+        self
+    }
+
+    /// How long the kernel may keep attributes and directory entries.
+    /// Files of unknown size are never kept, whatever this is.
+    pub fn attr_ttl(mut self, ttl: Duration) -> Builder {
+        self.attr_ttl = ttl;
+        self
+    }
+
+    /// The owner of every entry, unless the layout says otherwise, rather
+    /// than the user the process runs as.
+    pub fn uid(mut self, uid: u32) -> Builder {
+        self.defaults.uid = Some(uid);
+        self
+    }
+
+    /// The group of every entry, unless the layout says otherwise, rather
+    /// than the process's.
+    pub fn gid(mut self, gid: u32) -> Builder {
+        self.defaults.gid = Some(gid);
+        self
+    }
+
+    /// The permissions of every file, unless the layout says otherwise.
+    pub fn mode(mut self, mode: u16) -> Builder {
+        self.defaults.mode = Some(mode);
+        self
+    }
+
+    /// The permissions of every directory, unless the layout says
+    /// otherwise, rather than `0555`.
+    pub fn dir_mode(mut self, mode: u16) -> Builder {
+        self.defaults.dir_mode = Some(mode);
+        self
+    }
+
+    /// Fires [`crate::hooks`] events, naming files by their path under `root`,
+    /// the mount point.
+    pub fn hooks(mut self, root: impl Into<PathBuf>) -> Builder {
+        self.hooks = Some(root.into());
+        self
+    }
+
+    /// Records every read in `file`, naming files by their path under
+    /// `root`.
+    pub fn access_log(mut self, file: Arc<File>, root: impl Into<PathBuf>) -> Builder {
+        self.access_log = Some((file, root.into()));
+        self
+    }
+
+    /// Tells `observer` about the mount's lookups, reads, fetches and
+    /// evictions. A cache shared with [`Builder::share`] reports its
+    /// evictions to the observers of every mount sharing it.
+    pub fn observer(mut self, observer: Arc<dyn FsObserver>) -> Builder {
+        self.fetchers.observe(observer.clone());
+        self.observers.push(observer);
+        self
+    }
+
+    /// What `files` given to [`Builder::build`], and layouts added to the
+    /// running mount, may hold, instead of [`Limits::default`].
+    pub fn limits(mut self, limits: Limits) -> Builder {
+        self.limits = limits;
+        self
+    }
+
+    /// Whether files without a size in the layout have their backend asked
+    /// for it while building, as [`LazyHTTPFS::probe_sizes`], rather than
+    /// only once they are opened. On by default.
+    pub fn probe(mut self, probe: bool) -> Builder {
+        self.probe = probe;
+        self
+    }
+
+    /// Resolves `files` into the tree to serve, once they are found within
+    /// the limits.
+    pub fn build(self, files: Vec<InputFile>) -> Result<LazyHTTPFS, LhttpfsError> {
+        self.limits.check(&files)?;
+        layout::validate(&files)?;
+        let mut inode = 1;
+        let root = InputFile::Directory(Directory::new("/", files));
+        let files = [root];
+        let mut slices = Vec::new();
+        let mut defaults = self.defaults;
+        defaults.uid = defaults.uid.or_else(|| Some(unsafe { libc::getuid() }));
+        defaults.gid = defaults.gid.or_else(|| Some(unsafe { libc::getgid() }));
+        let (mut nodes, _) = add_inodes(
+            &files,
+            &mut inode,
+            &defaults,
+            None,
+            &self.fetchers,
+            &mut slices,
+        )
+        .map_err(LhttpfsError::layout)?;
+        nodes.sort_unstable_by_key(|f| f.get_attr().ino);
+        resolve_slices(&mut nodes, &slices).map_err(LhttpfsError::layout)?;
+        let cache = self
+            .cache
+            .unwrap_or_else(|| Arc::new(Mutex::new(Cache::new(Cache::default_dir()))));
+        let mut observers = Observers::default();
+        for observer in self.observers {
+            cache.lock().unwrap().observe(observer.clone());
+            observers.add(observer);
+        }
+        let mut fs = LazyHTTPFS {
+            nodes,
+            cache,
+            fetchers: self.fetchers,
+            seek_tables: HashMap::new(),
+            zip_starts: HashMap::new(),
+            verifying: HashMap::new(),
+            gzip_indexes: HashMap::new(),
+            manifest: Vec::new(),
+            access_log: None,
+            hooks: None,
+            observers,
+            control: Control::new(self.limits),
+            attr_ttl: self.attr_ttl,
+            blockwise: BLOCKWISE,
+            handles: 0,
+        };
+        if let Some(root) = &self.hooks {
+            fs.set_hooks(root);
+        }
+        fs.check_https(&self.limits)?;
+        if let Some((file, root)) = self.access_log {
+            fs.set_access_log(file, &root);
+        }
+        if self.probe {
+            fs.probe_sizes(PROBE_JOBS);
+        }
+        Ok(fs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        fetch::MemoryFetcher,
+        fs::{LazyHTTPFS, PlainHttp},
+        layout::{self, LimitExceeded, Limits},
+        LhttpfsError,
+    };
+
+    #[test]
+    fn built() {
+        let layout = r#"[
+            {"name": "a", "url": "mem://a", "size": 4},
+            {"name": "b", "url": "mem://b", "size": 2, "uid": 7}
+        ]"#;
+        let origin = Arc::new(MemoryFetcher::new().with("mem://a", "abcd"));
+        let mut fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("mem", origin.clone())
+            .uid(1234)
+            .gid(5678)
+            .mode(0o400)
+            .dir_mode(0o500)
+            .ttl(Duration::from_secs(60))
+            .attr_ttl(Duration::from_secs(5))
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let (a, ttl) = fs.find(1, "a".as_ref()).unwrap();
+        assert_eq!((a.uid, a.gid, a.perm), (1234, 5678, 0o400));
+        assert_eq!(fs.attributes(1).unwrap().0.perm, 0o500);
+        assert_eq!(ttl, Duration::from_secs(5));
+        let (b, _) = fs.find(1, "b".as_ref()).unwrap();
+        assert_eq!((b.uid, b.gid), (7, 5678));
+        fs.open_file(a.ino, false).unwrap();
+        assert_eq!(fs.read_file(a.ino, 1, 2).unwrap(), b"bc");
+        assert_eq!(fs.read_file(a.ino, 0, 4).unwrap(), b"abcd");
+        assert_eq!(origin.fetched(), [("mem://a".into(), Some((0, 4)))]);
+
+        let shared = LazyHTTPFS::builder()
+            .share(&fs)
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        assert!(Arc::ptr_eq(&fs.cache, &shared.cache));
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(shared.attributes(a.ino).unwrap().0.uid, uid);
+
+        let limits = Limits {
+            max_entries: 1,
+            ..Limits::default()
+        };
+        let result = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .limits(limits)
+            .build(layout::parse(layout.as_bytes()).unwrap());
+        let Err(LhttpfsError::Layout(error)) = result else {
+            panic!("Expected the limit to be exceeded");
+        };
+        assert!(error.is::<LimitExceeded>());
+    }
+
+    #[test]
+    fn require_https() {
+        let build = |layout: &str| {
+            LazyHTTPFS::builder()
+                .cache_dir(None)
+                .limits(Limits {
+                    require_https: true,
+                    ..Limits::default()
+                })
+                .build(layout::parse(layout.as_bytes()).unwrap())
+        };
+        let layout = r#"[
+            {"name": "a", "url": "https://example.com/a", "size": 1},
+            {"name": "d", "base_url": "http://example.com/", "contents": [
+                {"name": "b", "url": "b", "size": 1}
+            ]}
+        ]"#;
+        let Err(LhttpfsError::Layout(error)) = build(layout) else {
+            panic!("Expected http://example.com/b to be refused");
+        };
+        let error = error.downcast::<PlainHttp>().unwrap();
+        assert_eq!(error.path, std::path::Path::new("/d/b"));
+        assert_eq!(error.url, "http://example.com/b");
+        let mirrored = r#"[
+            {"name": "a", "url": "https://example.com/a", "size": 1,
+             "mirrors": ["http://mirror.example.com/a"]}
+        ]"#;
+        assert!(build(mirrored).is_err());
+        // Whatever the backend reads them over.
+        for url in [
+            "dav://example.com/a",
+            "ftp://example.com/a",
+            "lfs+http://example.com/r#0000000000000000000000000000000000000000000000000000000000000000",
+            "nexus+http://example.com/a",
+        ] {
+            let layout = format!(r#"[{{"name": "a", "url": "{}", "size": 1}}]"#, url);
+            assert!(build(&layout).is_err(), "{}", url);
+        }
+        let encrypted = r#"[{"name": "a", "url": "davs://example.com/a", "size": 1}]"#;
+        assert!(build(encrypted).is_ok());
+        // Opted out of, by the entry or a directory above it.
+        let exempt = r#"[
+            {"name": "a", "url": "http://example.com/a", "size": 1, "allow_http": true},
+            {"name": "d", "defaults": {"allow_http": true}, "contents": [
+                {"name": "b", "url": "http://example.com/b", "size": 1}
+            ]}
+        ]"#;
+        assert!(build(exempt).is_ok());
+        assert!(LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).is_ok());
+    }
+}
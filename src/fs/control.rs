@@ -0,0 +1,608 @@
+//! The hidden `.lhttpfs/` directory at the root of a mount: reading
+//! `stats` says how the mount is doing and `health` whether its origins
+//! answer, writing to `flush` or `reload`
+//! empties the cache or reloads the layouts, and writing entries to `add`
+//! adds them to the tree, so a running mount can be looked after with `cat`
+//! and `echo`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "fuse")]
+use std::{
+    io,
+    sync::{Arc, OnceLock},
+};
+
+#[cfg(feature = "fuse")]
+use fuser::Notifier;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+#[cfg(feature = "fuse")]
+use tracing::warn;
+
+use super::{dir_attr, file_node, DirNode, Entry, FileAttr, FileType, LazyHTTPFS, Node, Source};
+use crate::{
+    hooks::{self, Event},
+    layout::{self, Defaults, Limits},
+    metrics::METRICS,
+    LhttpfsError,
+};
+
+/// The name of the directory at the root.
+pub const CONTROL: &str = ".lhttpfs";
+
+/// A file of the control directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlFile {
+    Stats,
+    Health,
+    Flush,
+    Reload,
+    Add,
+}
+
+const FILES: [(&str, ControlFile); 5] = [
+    ("stats", ControlFile::Stats),
+    ("health", ControlFile::Health),
+    ("flush", ControlFile::Flush),
+    ("reload", ControlFile::Reload),
+    ("add", ControlFile::Add),
+];
+
+/// Loads the layouts again, for `reload`, with the layouts written to `add`
+/// since the mount started merged in.
+pub type Reloader = Box<dyn FnMut(&[Vec<u8>]) -> Result<LazyHTTPFS, Box<dyn Error>> + Send + Sync>;
+
+/// What the control directory keeps between requests.
+#[derive(Default)]
+pub struct Control {
+    /// `stats` and `health` as of when they were last opened.
+    pub stats: Vec<u8>,
+    pub health: Vec<u8>,
+    pub reloader: Option<Reloader>,
+    /// Set once the mount is up, to have the kernel drop what a reload
+    /// changed.
+    #[cfg(feature = "fuse")]
+    pub notifier: Arc<OnceLock<Notifier>>,
+    /// What was written to `add`, each a layout of its own.
+    added: Vec<Vec<u8>>,
+    reloads: u64,
+    /// Why the last write to a control file failed, if it did.
+    error: Option<String>,
+    /// What a layout written to `add` may hold.
+    limits: Limits,
+    /// The user the mount runs as, who may write to the control files
+    /// along with root.
+    owner: u32,
+    /// The files [`LazyHTTPFS::deny_writes`] was called for.
+    pub(super) denied: Vec<ControlFile>,
+    /// How many reads of each file failed, by inode.
+    pub(super) read_errors: HashMap<u64, u64>,
+}
+
+impl Control {
+    pub fn new(limits: Limits) -> Control {
+        Control {
+            limits,
+            owner: unsafe { libc::getuid() },
+            ..Control::default()
+        }
+    }
+}
+
+impl ControlFile {
+    /// Whether writing to the file does anything.
+    pub fn writable(self) -> bool {
+        !matches!(self, ControlFile::Stats | ControlFile::Health)
+    }
+}
+
+impl LazyHTTPFS {
+    /// Adds [`CONTROL`] to the root, unless the layout has an entry of that
+    /// name there.
+    pub fn add_control(&mut self) {
+        let dir = self.nodes.len() as u64 + 1;
+        let Some(Node::DirNode(root)) = self.nodes.first_mut() else {
+            return;
+        };
+        if root.contents.contains_key(OsStr::new(CONTROL)) {
+            return;
+        }
+        root.contents.insert(CONTROL.into(), dir);
+        // Owned by whoever owns the mount.
+        let (uid, gid) = (Some(root.attr.uid), Some(root.attr.gid));
+        let mut contents = HashMap::new();
+        let mut files = Vec::new();
+        for (i, (name, file)) in FILES.into_iter().enumerate() {
+            let ino = dir + 1 + i as u64;
+            contents.insert(name.into(), ino);
+            let mode = if file.writable() { 0o644 } else { 0o444 };
+            let defaults = Defaults {
+                mode: Some(mode),
+                uid,
+                gid,
+                ..Defaults::default()
+            };
+            let node = file_node(ino, 0, defaults, Source::Control(file));
+            files.push(Node::FileNode(Box::new(node)));
+        }
+        let defaults = Defaults {
+            uid,
+            gid,
+            ..Defaults::default()
+        };
+        self.nodes.push(Node::DirNode(DirNode {
+            attr: dir_attr(dir, &defaults),
+            contents,
+        }));
+        self.nodes.extend(files);
+    }
+
+    /// `stats`: the size of the tree, what the cache holds and the
+    /// process's counters, as JSON.
+    pub fn stats(&self) -> Vec<u8> {
+        let entries = self.walk();
+        let files = entries.iter().filter(|e| !e.is_dir());
+        let cache = self.cache.lock().unwrap();
+        let (memory_entries, memory_bytes) = cache.memory_usage();
+        let (disk_entries, disk_bytes) = cache.usage().unwrap_or_default();
+        let stats = serde_json::json!({
+            "directories": entries.iter().filter(|e| e.is_dir()).count() - 1,
+            "files": files.clone().count(),
+            "size": files.map(|e| e.attr.size).sum::<u64>(),
+            "cache": {
+                "memory_entries": memory_entries,
+                "memory_bytes": memory_bytes,
+                "disk_entries": disk_entries,
+                "disk_bytes": disk_bytes,
+            },
+            "counters": METRICS.counters(),
+            "reloads": self.control.reloads,
+            "added": self.control.added.len(),
+            "last_error": self.control.error,
+            "read_errors": self.read_errors(),
+        });
+        let mut json = serde_json::to_vec_pretty(&stats).unwrap();
+        json.push(b'\n');
+        json
+    }
+
+    /// How many reads of each file that had any fail failed, by path.
+    fn read_errors(&self) -> BTreeMap<String, u64> {
+        let errors = &self.control.read_errors;
+        let paths = self.paths().into_iter();
+        let failed = paths.filter_map(|(path, attr)| Some((path, *errors.get(&attr.ino)?)));
+        failed
+            .map(|(path, count)| (format!("/{}", path.display()), count))
+            .collect()
+    }
+
+    /// Does what writing `data` to `file` asks for, keeping the error for
+    /// `stats` since the writer only gets `EIO`.
+    pub fn control(&mut self, file: ControlFile, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let result = self.act(file, data);
+        self.control.error = result.as_ref().err().map(|e| e.to_string());
+        result
+    }
+
+    fn act(&mut self, file: ControlFile, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        match file {
+            ControlFile::Stats | ControlFile::Health => Err("Read-only control file".into()),
+            // A path flushes just what is under it, and anything else,
+            // such as the `1` of `echo 1 >`, all of it.
+            ControlFile::Flush => {
+                let path = std::str::from_utf8(data)?.trim();
+                let freed = match path.starts_with('/') {
+                    true => self.flush(path)?,
+                    false => self.cache.lock().unwrap().clear()?,
+                };
+                info!("Flushed {}, freeing {} bytes", path, freed);
+                Ok(())
+            }
+            ControlFile::Reload => self.reload(),
+            ControlFile::Add => {
+                self.control.limits.check(&layout::parse(data)?)?;
+                self.control.added.push(data.to_vec());
+                let result = self.reload();
+                if result.is_err() {
+                    self.control.added.pop();
+                }
+                result
+            }
+        }
+    }
+
+    /// Drops the cached bytes of the files at or under `path`, from the
+    /// root, returning how many bytes that freed.
+    fn flush(&self, path: &str) -> Result<u64, Box<dyn Error>> {
+        let path = Path::new(path.trim_start_matches('/'));
+        let paths = self.paths();
+        let mut under = paths.iter().filter(|(p, _)| p.starts_with(path)).peekable();
+        if under.peek().is_none() {
+            return Err(format!("No such file or directory: /{}", path.display()).into());
+        }
+        let mut freed = 0;
+        for (_, attr) in under {
+            freed += self.evict(attr.ino)?;
+        }
+        Ok(freed)
+    }
+
+    /// Drops the cached bytes of file `ino`, returning how many bytes that
+    /// freed.
+    pub(super) fn evict(&self, ino: u64) -> Result<u64, LhttpfsError> {
+        let Some(Node::FileNode(file)) = self.get_inode(ino) else {
+            return Ok(0);
+        };
+        let mut cache = self.cache.lock().unwrap();
+        let mut freed = 0;
+        for (key, policy) in self.cache_keys(ino, file) {
+            let removed = cache.remove(&key, policy)?;
+            if removed > 0 {
+                cache.evicted(&key, "flushed");
+            }
+            freed += removed;
+        }
+        Ok(freed)
+    }
+
+    /// Where the mount's [`Notifier`] goes once its session exists.
+    #[cfg(feature = "fuse")]
+    pub fn notifier(&self) -> Arc<OnceLock<Notifier>> {
+        self.control.notifier.clone()
+    }
+
+    /// Makes writing to `file` fail with [`OpError::NotPermitted`], for
+    /// those a front end or the layouts' signatures rule out.
+    ///
+    /// [`OpError::NotPermitted`]: super::ops::OpError::NotPermitted
+    pub fn deny_writes(&mut self, file: ControlFile) {
+        if !self.control.denied.contains(&file) {
+            self.control.denied.push(file);
+        }
+    }
+
+    /// Whether the user `uid` may write to the control files: the user the
+    /// mount runs as, or root. Others let in with `allow_other` or
+    /// `allow_root` may only read them.
+    pub fn may_control(&self, uid: u32) -> bool {
+        uid == 0 || uid == self.control.owner
+    }
+
+    /// [`Self::deny_writes`] for every control file that can be written to,
+    /// for servers whose clients can't all be trusted with them.
+    pub fn deny_control_writes(&mut self) {
+        for (_, file) in FILES {
+            if file.writable() {
+                self.deny_writes(file);
+            }
+        }
+    }
+
+    /// Makes `reload` load the layouts with `reloader`.
+    pub fn set_reloader(&mut self, reloader: Reloader) {
+        self.control.reloader = Some(reloader);
+    }
+
+    /// Loads the layouts again and serves the new tree, keeping the cache,
+    /// backends and, where a path still has a file or directory of the
+    /// same size, its inode number, which the kernel may have kept.
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let reloader = (self.control.reloader.as_mut()).ok_or("Nothing to reload from")?;
+        let mut new = reloader(&self.control.added)?;
+        new.add_manifest();
+        new.add_control();
+        let invalidations = self.replace_nodes(new);
+        #[cfg(feature = "fuse")]
+        if let Some(notifier) = self.control.notifier.get() {
+            invalidate(notifier, invalidations);
+        }
+        #[cfg(not(feature = "fuse"))]
+        drop(invalidations);
+        self.seek_tables.clear();
+        self.zip_starts.clear();
+        self.verifying.clear();
+        self.control.reloads += 1;
+        info!("Reloaded the layout: {} inodes", self.nodes.len());
+        if let Some(mount) = &self.hooks {
+            let paths = self.file_paths(&mount.root);
+            let mount = self.hooks.as_mut().unwrap();
+            mount.paths = paths;
+            hooks::fire(Event::Reloaded {
+                mount: &mount.root.to_string_lossy(),
+                inodes: self.nodes.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Every entry of the tree with its path from the root, which is "".
+    fn paths(&self) -> Vec<(PathBuf, FileAttr)> {
+        let relative =
+            |entry: Entry| (entry.path.strip_prefix("/").unwrap().to_owned(), entry.attr);
+        self.entries().map(relative).collect()
+    }
+
+    /// Serves the tree of `new` in place of this one. Nodes keep the inode
+    /// number of the one at their path if it is alike, and others get new
+    /// ones; nodes no longer in the tree stay, for files still open. Returns
+    /// what the kernel has to forget: entries that now lead elsewhere, and
+    /// files whose contents may have changed.
+    fn replace_nodes(&mut self, new: LazyHTTPFS) -> Vec<Invalidation> {
+        let old: HashMap<_, _> = self.paths().into_iter().collect();
+        let mut next = self.nodes.len() as u64 + 1;
+        let mut used = HashSet::new();
+        let mut inodes = HashMap::new();
+        let mut paths = HashMap::new();
+        let mut invalidations = Vec::new();
+        for (path, attr) in new.paths() {
+            // Files whose size is only learnt once read can't be compared by it.
+            let sized = |old: &FileAttr| !self.get_inode(old.ino).is_some_and(Node::size_unknown);
+            let alike = |old: &&FileAttr| {
+                old.kind == attr.kind
+                    && (old.kind == FileType::Directory || !sized(old) || old.size == attr.size)
+            };
+            let ino = match old.get(&path).filter(alike) {
+                Some(old) if used.insert(old.ino) => {
+                    if old.kind == FileType::RegularFile {
+                        invalidations.push(Invalidation::Inode(old.ino));
+                    }
+                    old.ino
+                }
+                _ => {
+                    next += 1;
+                    next - 1
+                }
+            };
+            inodes.insert(attr.ino, ino);
+            paths.insert(path, ino);
+        }
+        for (path, attr) in &old {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            if paths.get(path) != Some(&attr.ino) {
+                invalidations.push(Invalidation::Entry(old[parent].ino, name.to_owned()));
+            }
+        }
+        let mut fresh = Vec::new();
+        for (i, mut node) in new.nodes.into_iter().enumerate() {
+            let Some(&ino) = inodes.get(&(i as u64 + 1)) else {
+                continue;
+            };
+            match &mut node {
+                Node::DirNode(dir) => {
+                    dir.attr.ino = ino;
+                    for child in dir.contents.values_mut() {
+                        *child = inodes[&*child];
+                    }
+                }
+                Node::FileNode(file) => file.attr.ino = ino,
+            }
+            match ino as usize <= self.nodes.len() {
+                true => self.nodes[ino as usize - 1] = node,
+                false => fresh.push((ino, node)),
+            }
+        }
+        fresh.sort_unstable_by_key(|(ino, _)| *ino);
+        self.nodes.extend(fresh.into_iter().map(|(_, node)| node));
+        invalidations
+    }
+}
+
+/// Something the kernel may have cached that a reload made stale.
+#[derive(Debug, PartialEq, Eq)]
+enum Invalidation {
+    /// The entry `name` in directory `parent`.
+    Entry(u64, OsString),
+    /// The attributes and data of a file.
+    Inode(u64),
+}
+
+/// Tells the kernel to forget `invalidations`, from a thread of its own:
+/// it may need locks that the request that caused the reload still holds.
+#[cfg(feature = "fuse")]
+fn invalidate(notifier: &Notifier, invalidations: Vec<Invalidation>) {
+    let notifier = notifier.clone();
+    std::thread::spawn(move || {
+        for invalidation in invalidations {
+            let result = match &invalidation {
+                Invalidation::Entry(parent, name) => notifier.inval_entry(*parent, name),
+                Invalidation::Inode(ino) => notifier.inval_inode(*ino, 0, 0),
+            };
+            // The kernel may not have the entry or inode cached at all.
+            if let Err(e) = result.or_else(|e| match e.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            }) {
+                warn!("Invalidating {:?} failed: {}", invalidation, e);
+            }
+        }
+    });
+}
+
+/// A file node of the control directory, if `node` is one.
+pub fn control_file(node: Option<&Node>) -> Option<ControlFile> {
+    match node {
+        Some(Node::FileNode(file)) => match file.source {
+            Source::Control(file) => Some(file),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{error::Error, ffi::OsStr};
+
+    use super::{control_file, ControlFile, Invalidation, Reloader, CONTROL};
+    use crate::{
+        fs::{LazyHTTPFS, OpError},
+        layout::{self, LimitExceeded},
+        LhttpfsError,
+    };
+
+    fn fs(json: &str) -> LazyHTTPFS {
+        LazyHTTPFS::new(layout::parse(json.as_bytes()).unwrap()).unwrap()
+    }
+
+    fn ino(fs: &LazyHTTPFS, path: &str) -> Option<u64> {
+        let paths = fs.paths();
+        let (_, attr) = paths
+            .iter()
+            .find(|(p, _)| p.as_os_str() == OsStr::new(path))?;
+        Some(attr.ino)
+    }
+
+    #[test]
+    fn stats() {
+        let mut fs = fs(r#"[
+            {"name": "d", "contents": [{"name": "a", "content": "abc"}]},
+            {"name": "gone", "url": "file:///nonexistent/gone", "size": 3}
+        ]"#);
+        fs.add_control();
+        let stats = ino(&fs, &format!("{}/stats", CONTROL)).unwrap();
+        assert_eq!(control_file(fs.get_inode(stats)), Some(ControlFile::Stats));
+        let json: serde_json::Value = serde_json::from_slice(&fs.stats()).unwrap();
+        let health = ino(&fs, &format!("{}/health", CONTROL)).unwrap();
+        assert_eq!(
+            control_file(fs.get_inode(health)),
+            Some(ControlFile::Health)
+        );
+        assert_eq!(json["files"], 7);
+        assert_eq!(json["directories"], 2);
+        assert_eq!(json["reloads"], 0);
+        assert!(json["last_error"].is_null());
+        assert!(fs.control(ControlFile::Stats, b"").is_err());
+        assert!(fs.control(ControlFile::Health, b"").is_err());
+        assert!(fs.control(ControlFile::Reload, b"1\n").is_err());
+        let json: serde_json::Value = serde_json::from_slice(&fs.stats()).unwrap();
+        assert_eq!(json["last_error"], "Nothing to reload from");
+        fs.control(ControlFile::Flush, b"/d/a\n").unwrap();
+        assert!(fs.control(ControlFile::Flush, b"/d/b").is_err());
+        fs.control(ControlFile::Flush, b"1\n").unwrap();
+        let gone = ino(&fs, "gone").unwrap();
+        assert!(fs.read_file(gone, 0, 3).is_err());
+        assert!(fs.read_file(gone, 0, 3).is_err());
+        let json: serde_json::Value = serde_json::from_slice(&fs.stats()).unwrap();
+        assert_eq!(json["read_errors"], serde_json::json!({"/gone": 2}));
+    }
+
+    /// A reloader loading `json` and whatever was added.
+    fn layout(json: &'static str) -> Reloader {
+        Box::new(move |added: &[Vec<u8>]| {
+            let mut layouts = vec![layout::parse(json.as_bytes())?];
+            for added in added {
+                layouts.push(layout::parse(&added[..])?);
+            }
+            Ok(LazyHTTPFS::new(layout::merge(
+                layouts,
+                layout::OnConflict::Error,
+            )?)?)
+        })
+    }
+
+    #[test]
+    fn reload() {
+        let before = r#"[
+            {"name": "same", "content": "abc"},
+            {"name": "resized", "content": "abc"},
+            {"name": "removed", "content": "abc"}
+        ]"#;
+        let mut fs = fs(before);
+        fs.add_manifest();
+        fs.add_control();
+        let (same, resized, removed) = (
+            ino(&fs, "same").unwrap(),
+            ino(&fs, "resized").unwrap(),
+            ino(&fs, "removed").unwrap(),
+        );
+        let len = fs.nodes.len() as u64;
+        fs.set_reloader(layout(
+            r#"[
+                {"name": "added", "content": "abcdef"},
+                {"name": "resized", "content": "abcdef"},
+                {"name": "same", "content": "xyz"}
+            ]"#,
+        ));
+        let mut new = fs.control.reloader.as_mut().unwrap()(&[]).unwrap();
+        new.add_manifest();
+        new.add_control();
+        let invalidations = fs.replace_nodes(new);
+        assert!(invalidations.contains(&Invalidation::Inode(same)));
+        assert!(invalidations.contains(&Invalidation::Entry(1, "resized".into())));
+        assert!(invalidations.contains(&Invalidation::Entry(1, "removed".into())));
+        assert!(!invalidations.contains(&Invalidation::Entry(1, "same".into())));
+        assert_eq!(ino(&fs, "same"), Some(same));
+        assert!(ino(&fs, "resized").unwrap() > len);
+        assert!(ino(&fs, "added").unwrap() > len);
+        assert_eq!(ino(&fs, "removed"), None);
+        // Still there for whoever has it open.
+        assert!(fs.get_inode(removed).is_some() && fs.get_inode(resized).is_some());
+        assert!(ino(&fs, CONTROL).is_some());
+        // Loading the same layout again changes nothing.
+        let added = ino(&fs, "added");
+        fs.control(ControlFile::Reload, b"1\n").unwrap();
+        assert_eq!(ino(&fs, "added"), added);
+        let json: serde_json::Value = serde_json::from_slice(&fs.stats()).unwrap();
+        assert_eq!(json["reloads"], 1);
+        assert_eq!(&fs.read_data(same, 0, 10).unwrap().unwrap(), b"xyz");
+    }
+
+    #[test]
+    fn add() {
+        let mut fs = fs(r#"[{"name": "a", "content": "abc"}]"#);
+        fs.add_control();
+        fs.set_reloader(layout(r#"[{"name": "a", "content": "abc"}]"#));
+        let entries = br#"[{"name": "d", "contents": [{"name": "b", "content": "xyz"}]}]"#;
+        fs.control(ControlFile::Add, entries).unwrap();
+        let b = ino(&fs, "d/b").unwrap();
+        assert_eq!(&fs.read_data(b, 0, 10).unwrap().unwrap(), b"xyz");
+        // Still there after reloading.
+        fs.control(ControlFile::Reload, b"").unwrap();
+        assert_eq!(ino(&fs, "d/b"), Some(b));
+        assert!(fs.control(ControlFile::Add, b"not json").is_err());
+        assert!((fs.control(ControlFile::Add, br#"[{"name": "a", "content": "x"}]"#)).is_err());
+        // Held to the same limits as the layouts mounted.
+        let long = format!(r#"[{{"name": "{}", "content": "x"}}]"#, "n".repeat(300));
+        let error = fs.control(ControlFile::Add, long.as_bytes()).unwrap_err();
+        let error = error.downcast::<LhttpfsError>().unwrap();
+        assert!(error.source().unwrap().is::<LimitExceeded>(), "{}", error);
+        let json: serde_json::Value = serde_json::from_slice(&fs.stats()).unwrap();
+        assert_eq!(json["added"], 1);
+        assert!(json["last_error"].as_str().is_some());
+    }
+
+    #[test]
+    fn denied() {
+        let mut fs = fs(r#"[{"name": "a", "content": "abc"}]"#);
+        fs.add_control();
+        fs.set_reloader(layout(r#"[{"name": "a", "content": "abc"}]"#));
+        fs.deny_writes(ControlFile::Add);
+        let add = ino(&fs, ".lhttpfs/add").unwrap();
+        let entries = br#"[{"name": "b", "content": "xyz"}]"#;
+        assert_eq!(fs.write_file(add, entries), Err(OpError::NotPermitted));
+        assert_eq!(ino(&fs, "b"), None);
+        // The others still do what they do.
+        let reload = ino(&fs, ".lhttpfs/reload").unwrap();
+        assert_eq!(fs.write_file(reload, b"1"), Ok(()));
+        fs.deny_control_writes();
+        assert_eq!(fs.write_file(reload, b"1"), Err(OpError::NotPermitted));
+        let flush = ino(&fs, ".lhttpfs/flush").unwrap();
+        assert_eq!(fs.write_file(flush, b"1"), Err(OpError::NotPermitted));
+    }
+
+    #[test]
+    fn owner() {
+        let fs = fs(r#"[{"name": "a", "content": "abc"}]"#);
+        let owner = unsafe { libc::getuid() };
+        assert!(fs.may_control(owner));
+        assert!(fs.may_control(0));
+        assert!(!fs.may_control(owner + 1));
+    }
+}
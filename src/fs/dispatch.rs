@@ -0,0 +1,1020 @@
+//! Answers reads on a tokio runtime, so an origin that stalls holds up the
+//! reads waiting on it rather than the whole mount. A read fetches what
+//! it is missing before locking the filesystem, all of it at once, and
+//! only reads what is then cached with it locked, so lookups, listings
+//! and reads of cached files go on in the meantime. The backends block,
+//! so fetches run on the runtime's blocking threads, taking turns by the
+//! [`Priority`] of the files they are for when there aren't enough.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    ffi::OsStr,
+    path::Path,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Instant,
+};
+
+use fuser::Filesystem;
+use tokio::{runtime::Runtime, sync::oneshot, task::JoinSet};
+use tracing::warn;
+
+use super::{
+    cache_entry,
+    epoch::{Epoch, Moved},
+    fuse::errno,
+    lock,
+    ops::OpError,
+    part_reads,
+    readahead::Readahead,
+    stream::{Ahead, Streaming, Streams},
+    Blockwise, LazyHTTPFS, Node, Source, ROUNDS,
+};
+use crate::{fetch::abandonable, layout::Priority, LhttpfsError};
+
+/// A [`LazyHTTPFS`] served with up to `threads` fetching at once, or on
+/// the session thread with none.
+pub struct Dispatched {
+    fs: Arc<Mutex<LazyHTTPFS>>,
+    threads: usize,
+    slots: Arc<Slots>,
+    epoch: Option<Epoch>,
+    streams: Option<Streams>,
+    readahead: Option<Readahead>,
+    /// How many threads answer the reads handed off by the session thread.
+    dispatchers: usize,
+    /// Started on the first read, once the mount is up and any sandbox
+    /// is in place, which its threads then inherit.
+    runtime: Option<Runtime>,
+}
+
+impl Dispatched {
+    pub fn new(fs: LazyHTTPFS, threads: usize) -> Dispatched {
+        Dispatched {
+            fs: Arc::new(Mutex::new(fs)),
+            threads,
+            slots: Slots::new(threads),
+            epoch: None,
+            streams: None,
+            readahead: None,
+            dispatchers: 1,
+            runtime: None,
+        }
+    }
+
+    /// Answers reads on `dispatchers` threads instead of one. They only
+    /// ask what is missing and read from the cache, so more of them help
+    /// with many small reads of cached files, and the `threads` fetching
+    /// with reads of big files that aren't.
+    pub fn dispatchers(mut self, dispatchers: usize) -> Dispatched {
+        self.dispatchers = dispatchers.max(1);
+        self
+    }
+
+    /// Fetches the files of `epoch` just ahead of the reads getting to
+    /// them, and drops those they are past from the cache. Without fetch
+    /// threads there is nothing to fetch them with.
+    pub fn epoch(mut self, epoch: Epoch) -> Dispatched {
+        self.epoch = Some(epoch);
+        self
+    }
+
+    /// Fetches files in blocks of `streaming.block`, reading ahead of where
+    /// each is read and dropping what is behind it as [`Streaming`] says,
+    /// and gives up on what was ahead once a read seeks elsewhere.
+    pub fn streaming(mut self, streaming: Streaming) -> Dispatched {
+        self.fs().blockwise = Blockwise {
+            size: streaming.block,
+        };
+        self.streams = Some(Streams::new(streaming));
+        self
+    }
+
+    /// Fetches up to `bytes` ahead of the reads that go through a file in
+    /// order, as [`Readahead`] says, unless [`Dispatched::streaming`] does
+    /// that its own way.
+    pub fn readahead(mut self, bytes: u64) -> Dispatched {
+        self.readahead = (bytes > 0).then(|| Readahead::new(bytes));
+        self
+    }
+
+    fn fs(&self) -> MutexGuard<'_, LazyHTTPFS> {
+        lock(&self.fs)
+    }
+
+    /// The runtime, started if it wasn't, or `None` if it can't be.
+    fn runtime(&mut self) -> Option<&Runtime> {
+        if self.runtime.is_none() {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(self.dispatchers)
+                .max_blocking_threads(self.threads)
+                .thread_name("lhttpfs-read")
+                .build();
+            match runtime {
+                Ok(runtime) => self.runtime = Some(runtime),
+                Err(e) => warn!("Couldn't start the threads to read with: {}", e),
+            }
+        }
+        self.runtime.as_ref()
+    }
+}
+
+impl LazyHTTPFS {
+    /// Drops the cached blocks of file `ino` that lie within `start..end`,
+    /// for files fetched in blocks: those fetched whole are kept.
+    pub(super) fn evict_blocks(
+        &self,
+        ino: u64,
+        (start, end): (u64, u64),
+    ) -> Result<u64, LhttpfsError> {
+        let Some(Node::FileNode(file)) = self.get_inode(ino) else {
+            return Ok(0);
+        };
+        let Source::Url(url) = &file.source else {
+            return Ok(0);
+        };
+        let mut cache = self.cache.lock().unwrap();
+        let mut freed = 0;
+        let reads = part_reads(
+            self.blockwise,
+            file,
+            file.attr.size,
+            start,
+            end.saturating_sub(start),
+        );
+        for (range, _, _) in reads {
+            let Some(range) = range.filter(|&(block, len)| block >= start && block + len <= end)
+            else {
+                continue;
+            };
+            let (key, policy) = cache_entry(file, url, Some(range));
+            let removed = cache.remove(&key, policy)?;
+            if removed > 0 {
+                cache.evicted(&key, "flushed");
+            }
+            freed += removed;
+        }
+        Ok(freed)
+    }
+}
+
+/// Turns to fetch, `threads` at a time, given to the fetches waiting for
+/// one by the priority of their files, then in the order they asked.
+pub(super) struct Slots(Mutex<Queue>);
+
+struct Queue {
+    free: usize,
+    waiting: BinaryHeap<Waiting>,
+    asked: u64,
+}
+
+struct Waiting {
+    priority: Priority,
+    order: Reverse<u64>,
+    turn: oneshot::Sender<Slot>,
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Waiting) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiting {}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Waiting) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Waiting) -> Ordering {
+        (self.priority, self.order).cmp(&(other.priority, other.order))
+    }
+}
+
+/// A turn to fetch, passed on when dropped.
+struct Slot(Option<Arc<Slots>>);
+
+impl Slots {
+    pub(super) fn new(threads: usize) -> Arc<Slots> {
+        Arc::new(Slots(Mutex::new(Queue {
+            free: threads.max(1),
+            waiting: BinaryHeap::new(),
+            asked: 0,
+        })))
+    }
+
+    /// Waits for a turn to fetch for a file of `priority`.
+    async fn turn(self: &Arc<Slots>, priority: Priority) -> Slot {
+        let turn = {
+            let mut queue = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            if queue.free > 0 {
+                queue.free -= 1;
+                return Slot(Some(self.clone()));
+            }
+            let (turn, waiting) = oneshot::channel();
+            queue.asked += 1;
+            let order = Reverse(queue.asked);
+            queue.waiting.push(Waiting {
+                priority,
+                order,
+                turn,
+            });
+            waiting
+        };
+        // Only dropped unsent with the slots, which this holds on to.
+        turn.await.unwrap_or_else(|_| Slot(None))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(slots) = self.0.take() else {
+            return;
+        };
+        let mut queue = slots.0.lock().unwrap_or_else(PoisonError::into_inner);
+        while let Some(waiting) = queue.waiting.pop() {
+            // A read that gave up waiting has no use for it.
+            match waiting.turn.send(Slot(Some(slots.clone()))) {
+                Ok(()) => return,
+                Err(mut unwanted) => drop(unwanted.0.take()),
+            }
+        }
+        queue.free += 1;
+    }
+}
+
+/// A failure carried out of a blocking thread. An [`LhttpfsError`]'s
+/// source isn't `Send`, so only what it says and the [`OpError`] it
+/// comes to are.
+struct Failure {
+    error: OpError,
+    said: String,
+}
+
+impl From<LhttpfsError> for Failure {
+    fn from(error: LhttpfsError) -> Failure {
+        Failure {
+            error: OpError::fetching(&error),
+            said: error.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.said)
+    }
+}
+
+/// Runs `f` on a blocking thread, failing the read if it panics.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T, OpError> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        warn!("A read panicked: {}", e);
+        OpError::Failed
+    })
+}
+
+/// Reads `size` bytes of `ino` from `offset`, fetching what isn't cached
+/// with `fs` unlocked, each fetch once it has a turn of `slots`.
+async fn read(
+    fs: Arc<Mutex<LazyHTTPFS>>,
+    slots: Arc<Slots>,
+    ino: u64,
+    offset: i64,
+    size: u32,
+) -> Result<Vec<u8>, OpError> {
+    let start = Instant::now();
+    let mut held = Vec::new();
+    let fetched = fetch_missing(&fs, &slots, ino, offset, size, &For::Read, &mut held).await?;
+    blocking(move || {
+        let mut fs = lock(&fs);
+        let data = fs.read_since(ino, offset, size, start, fetched);
+        let mut cache = fs.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        for key in held {
+            cache.release(&key);
+        }
+        data
+    })
+    .await?
+}
+
+/// What fetches are for: a read waiting on them, or reading ahead of one,
+/// behind every read and, with a flag, given up on once it is set.
+#[derive(Clone)]
+enum For {
+    Read,
+    Ahead(Option<Arc<AtomicBool>>),
+}
+
+impl For {
+    fn abandoned(&self) -> bool {
+        let flag = match self {
+            For::Ahead(Some(flag)) => flag,
+            _ => return false,
+        };
+        flag.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Fetches what a read of `size` bytes of `ino` from `offset` is missing,
+/// each fetch once it has a turn of `slots` by the priority of its file,
+/// or after reads if `ahead` of one, keeping the keys of what is held for
+/// the read in `held`. Returns whether anything was fetched.
+async fn fetch_missing(
+    fs: &Arc<Mutex<LazyHTTPFS>>,
+    slots: &Arc<Slots>,
+    ino: u64,
+    offset: i64,
+    size: u32,
+    fetching: &For,
+    held: &mut Vec<String>,
+) -> Result<bool, OpError> {
+    let mut fetched = false;
+    for _ in 0..ROUNDS {
+        let locked = fs.clone();
+        let misses = blocking(move || lock(&locked).misses(ino, offset, size)).await?;
+        if misses.is_empty() {
+            break;
+        }
+        fetched = true;
+        let mut warming = JoinSet::new();
+        for miss in misses {
+            let (fs, slots, fetching) = (fs.clone(), slots.clone(), fetching.clone());
+            warming.spawn(async move {
+                let priority = match fetching {
+                    For::Read => miss.priority(),
+                    For::Ahead(_) => Priority::Low,
+                };
+                let _turn = slots.turn(priority).await;
+                let warm = move || match &fetching {
+                    For::Ahead(Some(abandon)) => abandonable(abandon, || miss.warm(&fs)),
+                    _ => miss.warm(&fs),
+                };
+                tokio::task::spawn_blocking(move || warm().map_err(Failure::from)).await
+            });
+        }
+        while let Some(warmed) = warming.join_next().await {
+            match warmed.and_then(|warmed| warmed) {
+                Ok(Ok(key)) => held.extend(key),
+                Ok(Err(failure)) => {
+                    if !fetching.abandoned() {
+                        warn!("Reading inode {} failed: {}", ino, failure);
+                    }
+                    return Err(failure.error);
+                }
+                Err(e) => {
+                    warn!("A read panicked: {}", e);
+                    return Err(OpError::Failed);
+                }
+            }
+        }
+    }
+    Ok(fetched)
+}
+
+/// How much of a file [`move_on`] fetches at once.
+const AHEAD_CHUNK: u64 = 64 << 20;
+
+/// Drops the files the reader of an epoch is past from the cache, then
+/// fetches those it is about to get to, each taking its turns behind
+/// every read.
+async fn move_on(fs: Arc<Mutex<LazyHTTPFS>>, slots: Arc<Slots>, moved: Moved) {
+    let locked = fs.clone();
+    let dropped = blocking(move || {
+        let fs = lock(&locked);
+        let mut dropped = moved.drop.iter().map(|&ino| fs.evict(ino));
+        dropped.try_for_each(|freed| freed.map(drop).map_err(Failure::from))
+    });
+    if let Ok(Err(e)) = dropped.await {
+        warn!("Dropping what an epoch's reader is past failed: {}", e);
+    }
+    for (ino, size) in moved.fetch {
+        let (fs, slots) = (fs.clone(), slots.clone());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            for offset in (0..size.max(1)).step_by(AHEAD_CHUNK as usize) {
+                let (offset, len) = (offset as i64, AHEAD_CHUNK as u32);
+                let ahead = For::Ahead(None);
+                if fetch_missing(&fs, &slots, ino, offset, len, &ahead, &mut held)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            // Nothing is kept for files that aren't cached.
+            let fs = lock(&fs);
+            let mut cache = fs.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            for key in held {
+                cache.release(&key);
+            }
+        });
+    }
+}
+
+/// Drops what a player left behind in `ino` from the cache, then fetches
+/// what is ahead of it, or of a reader going through it in order, `block`
+/// bytes at a time, behind every read, until it seeks elsewhere.
+async fn read_ahead(
+    fs: Arc<Mutex<LazyHTTPFS>>,
+    slots: Arc<Slots>,
+    ino: u64,
+    block: u64,
+    ahead: Ahead,
+) {
+    let Ahead {
+        fetch,
+        drop: behind,
+        abandon,
+    } = ahead;
+    let locked = fs.clone();
+    let dropped = blocking(move || {
+        let fs = lock(&locked);
+        let mut dropped = behind.into_iter().map(|range| fs.evict_blocks(ino, range));
+        dropped.try_for_each(|freed| freed.map(drop).map_err(Failure::from))
+    });
+    if let Ok(Err(e)) = dropped.await {
+        warn!("Dropping what a stream is past failed: {}", e);
+    }
+    let fetching = For::Ahead(Some(abandon));
+    let mut held = Vec::new();
+    'fetch: for (start, len) in fetch {
+        for offset in (start..start + len).step_by(block.max(1) as usize) {
+            let len = block.min(start + len - offset) as u32;
+            if fetching.abandoned()
+                || (fetch_missing(&fs, &slots, ino, offset as i64, len, &fetching, &mut held).await)
+                    .is_err()
+            {
+                break 'fetch;
+            }
+        }
+    }
+    let fs = lock(&fs);
+    let mut cache = fs.cache.lock().unwrap_or_else(PoisonError::into_inner);
+    for key in held {
+        cache.release(&key);
+    }
+}
+
+impl Filesystem for Dispatched {
+    fn destroy(&mut self) {
+        // Transfers still under way would otherwise hold up the unmount
+        // until they finished, and the reads they are for fail anyway.
+        self.fs().cancel_fetches();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.fs().lookup(req, parent, name, reply)
+    }
+
+    fn getattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: Option<u64>,
+        reply: fuser::ReplyAttr,
+    ) {
+        self.fs().getattr(req, ino, fh, reply)
+    }
+
+    fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        self.fs().readlink(req, ino, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        self.fs().getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        self.fs().listxattr(req, ino, size, reply)
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectory,
+    ) {
+        self.fs().readdir(req, ino, fh, offset, reply)
+    }
+
+    fn open(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        self.fs().open(req, ino, flags, reply)
+    }
+
+    fn read(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        let (fs, slots) = (self.fs.clone(), self.slots.clone());
+        let moved = self.epoch.as_mut().map(|epoch| epoch.read(ino));
+        let (file_size, block) = {
+            let fs = lock(&fs);
+            let file_size = match fs.get_inode(ino) {
+                Some(Node::FileNode(file)) => Some(file.attr.size),
+                _ => None,
+            };
+            (file_size, fs.blockwise.size)
+        };
+        let (from, len) = (offset.max(0) as u64, size as u64);
+        let ahead = match (file_size, &mut self.streams, &mut self.readahead) {
+            (Some(file_size), Some(streams), _) => {
+                Some((streams.block(), streams.read(fh, file_size, from, len)))
+            }
+            (Some(file_size), None, Some(readahead)) => {
+                let ahead = readahead.read(fh, file_size, from, len);
+                ahead.map(|ahead| (block, ahead))
+            }
+            _ => None,
+        };
+        let runtime = match self.threads {
+            0 => None,
+            _ => self.runtime(),
+        };
+        match runtime {
+            Some(runtime) => {
+                if let Some(moved) = moved.filter(|moved| !moved.is_empty()) {
+                    runtime.spawn(move_on(fs.clone(), slots.clone(), moved));
+                }
+                if let Some((block, ahead)) = ahead {
+                    let (fs, slots) = (fs.clone(), slots.clone());
+                    runtime.spawn(read_ahead(fs, slots, ino, block, ahead));
+                }
+                runtime.spawn(async move {
+                    match read(fs, slots, ino, offset, size).await {
+                        Ok(data) => reply.data(&data),
+                        Err(e) => reply.error(errno(e)),
+                    }
+                });
+            }
+            _ => match self.fs().read_file(ino, offset, size) {
+                Ok(data) => reply.data(&data),
+                Err(e) => reply.error(errno(e)),
+            },
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if let Some(streams) = &mut self.streams {
+            streams.release(fh);
+        }
+        if let Some(readahead) = &mut self.readahead {
+            readahead.release(fh);
+        }
+        reply.ok();
+    }
+
+    fn write(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        let mut fs = self.fs();
+        fs.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn setattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<std::time::SystemTime>,
+        chgtime: Option<std::time::SystemTime>,
+        bkuptime: Option<std::time::SystemTime>,
+        flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        let mut fs = self.fs();
+        fs.setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn mknod(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.fs().mknod(req, parent, name, mode, umask, rdev, reply)
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.fs().mkdir(req, parent, name, mode, umask, reply)
+    }
+
+    fn create(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        self.fs()
+            .create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    fn unlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs().unlink(req, parent, name, reply)
+    }
+
+    fn rmdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs().rmdir(req, parent, name, reply)
+    }
+
+    fn symlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.fs().symlink(req, parent, link_name, target, reply)
+    }
+
+    fn rename(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs()
+            .rename(req, parent, name, newparent, newname, flags, reply)
+    }
+
+    fn link(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.fs().link(req, ino, newparent, newname, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs()
+            .setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    fn removexattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs().removexattr(req, ino, name, reply)
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs()
+            .fallocate(req, ino, fh, offset, length, mode, reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::{move_on, read, read_ahead, Ahead, Blockwise, Moved, Slots};
+    use crate::{
+        fetch::{FetchResult, Fetcher, MemoryFetcher, Request},
+        fs::LazyHTTPFS,
+        layout::{self, Priority},
+    };
+
+    /// Serves ten bytes, saying what of them was asked for.
+    struct Counted(Mutex<Vec<(u64, u64)>>);
+
+    impl Fetcher for Counted {
+        fn fetch_range(&self, _request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+            let (start, len) = range.unwrap_or((0, 10));
+            self.0.lock().unwrap().push((start, len));
+            Ok(b"0123456789"[start as usize..(start + len) as usize]
+                .to_vec()
+                .into())
+        }
+    }
+
+    /// Answers once told to, saying when it was asked.
+    struct Stalled {
+        asked: Mutex<mpsc::Sender<()>>,
+        answer: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Fetcher for Stalled {
+        fn fetch_range(&self, _request: &Request, range: Option<(u64, u64)>) -> FetchResult {
+            self.asked.lock().unwrap().send(()).unwrap();
+            self.answer.lock().unwrap().recv().unwrap();
+            let (start, len) = range.unwrap_or((0, 4));
+            Ok(b"abcd"[start as usize..(start + len) as usize]
+                .to_vec()
+                .into())
+        }
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread().build().unwrap()
+    }
+
+    #[test]
+    fn moved_on() {
+        let layout = r#"[
+            {"name": "a", "url": "mem://a", "size": 4},
+            {"name": "b", "url": "mem://b", "size": 2}
+        ]"#;
+        let origin = MemoryFetcher::new()
+            .with("mem://a", "abcd")
+            .with("mem://b", "ef");
+        let fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("mem", Arc::new(origin))
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let fs = Arc::new(Mutex::new(fs));
+        let runtime = runtime();
+        let slots = Slots::new(1);
+        let [a, b] = ["a", "b"].map(|name| fs.lock().unwrap().find(1, name.as_ref()).unwrap().0);
+        let cached = |ino| fs.lock().unwrap().cached(ino) == Some(true);
+        let fetch = vec![(a.ino, 4), (b.ino, 2)];
+        let moved = Moved {
+            fetch,
+            drop: Vec::new(),
+        };
+        runtime.block_on(move_on(fs.clone(), slots.clone(), moved));
+        let start = Instant::now();
+        while !(cached(a.ino) && cached(b.ino)) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Not fetched ahead"
+            );
+            std::thread::yield_now();
+        }
+        let moved = Moved {
+            fetch: Vec::new(),
+            drop: vec![a.ino],
+        };
+        runtime.block_on(move_on(fs.clone(), slots, moved));
+        assert!(!cached(a.ino) && cached(b.ino));
+    }
+
+    #[test]
+    fn streamed() {
+        let layout = r#"[{"name": "a", "url": "counted://a", "size": 10}]"#;
+        let counted = Arc::new(Counted(Mutex::default()));
+        let mut fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("counted", counted.clone())
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        fs.blockwise = Blockwise { size: 2 };
+        let (a, _) = fs.find(1, "a".as_ref()).unwrap();
+        let fs = Arc::new(Mutex::new(fs));
+        let runtime = runtime();
+        let slots = Slots::new(1);
+        let asked = || std::mem::take(&mut *counted.0.lock().unwrap());
+        let ahead = |fetch: &[(u64, u64)], drop: &[(u64, u64)], abandon: bool| Ahead {
+            fetch: fetch.to_vec(),
+            drop: drop.to_vec(),
+            abandon: Arc::new(abandon.into()),
+        };
+        let streamed = |ahead| read_ahead(fs.clone(), slots.clone(), a.ino, 2, ahead);
+        runtime.block_on(streamed(ahead(&[(0, 6)], &[], false)));
+        assert_eq!(asked(), [(0, 2), (2, 2), (4, 2)]);
+        // Given up on after a seek.
+        runtime.block_on(streamed(ahead(&[(6, 4)], &[], true)));
+        assert!(asked().is_empty());
+        // Only the blocks wholly behind are dropped.
+        runtime.block_on(streamed(ahead(&[], &[(0, 5)], false)));
+        let read = runtime.block_on(read(fs.clone(), slots.clone(), a.ino, 0, 6));
+        assert_eq!(read.unwrap(), b"012345");
+        assert_eq!(asked(), [(0, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn turns() {
+        let runtime = runtime();
+        let slots = Slots::new(1);
+        let first = runtime.block_on(slots.turn(Priority::Low));
+        let (done, finished) = mpsc::channel();
+        let waiters = [
+            Priority::Normal,
+            Priority::Low,
+            Priority::High,
+            Priority::Normal,
+        ];
+        for (i, priority) in waiters.into_iter().enumerate() {
+            let (waiter, done) = (slots.clone(), done.clone());
+            runtime.spawn(async move {
+                let _turn = waiter.turn(priority).await;
+                done.send(i).unwrap();
+            });
+            // Asked in this order.
+            while slots.0.lock().unwrap().waiting.len() <= i {
+                std::thread::yield_now();
+            }
+        }
+        // One that gave up waiting is passed over.
+        let gave_up = runtime.spawn({
+            let slots = slots.clone();
+            async move { slots.turn(Priority::High).await }
+        });
+        while slots.0.lock().unwrap().waiting.len() < 5 {
+            std::thread::yield_now();
+        }
+        gave_up.abort();
+        assert!(runtime.block_on(gave_up).is_err());
+        drop(first);
+        let order = (0..4).map(|_| finished.recv().unwrap());
+        assert_eq!(order.collect::<Vec<_>>(), [2, 0, 3, 1]);
+        // And handed back once they are done.
+        drop(runtime.block_on(slots.turn(Priority::Low)));
+    }
+
+    #[test]
+    fn dispatched() {
+        let layout = r#"[
+            {"name": "a", "url": "stalled://a", "size": 4},
+            {"name": "b", "content": "b"}
+        ]"#;
+        let (asked, asking) = mpsc::channel();
+        let (answer, answering) = mpsc::channel();
+        let stalled = Stalled {
+            asked: Mutex::new(asked),
+            answer: Mutex::new(answering),
+        };
+        let fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("stalled", Arc::new(stalled))
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let fs = Arc::new(Mutex::new(fs));
+        let runtime = runtime();
+        let (a, _) = fs.lock().unwrap().find(1, "a".as_ref()).unwrap();
+        let reader = runtime.spawn(read(fs.clone(), Slots::new(1), a.ino, 1, 2));
+        asking.recv().unwrap();
+        // The origin stalls, but the rest of the mount doesn't.
+        let (b, _) = fs.try_lock().unwrap().find(1, "b".as_ref()).unwrap();
+        let b = runtime.block_on(read(fs.clone(), Slots::new(1), b.ino, 0, 1));
+        assert_eq!(b.unwrap(), b"b");
+        answer.send(()).unwrap();
+        assert_eq!(runtime.block_on(reader).unwrap().unwrap(), b"bc");
+        // Now cached, it isn't fetched again, which would fail.
+        drop(answer);
+        let a = runtime.block_on(read(fs.clone(), Slots::new(1), a.ino, 0, 4));
+        assert_eq!(a.unwrap(), b"abcd");
+    }
+
+    #[test]
+    fn uncached() {
+        let layout = r#"[{"name": "a", "url": "stalled://a", "size": 4, "cache": "none"}]"#;
+        let (asked, asking) = mpsc::channel();
+        let (answer, answering) = mpsc::channel();
+        let stalled = Stalled {
+            asked: Mutex::new(asked),
+            answer: Mutex::new(answering),
+        };
+        let fs = LazyHTTPFS::builder()
+            .fetcher("stalled", Arc::new(stalled))
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let fs = Arc::new(Mutex::new(fs));
+        let runtime = runtime();
+        let (a, _) = fs.lock().unwrap().find(1, "a".as_ref()).unwrap();
+        for _ in 0..2 {
+            let reader = runtime.spawn(read(fs.clone(), Slots::new(1), a.ino, 1, 2));
+            // Fetched with the filesystem unlocked, and again each time.
+            asking.recv().unwrap();
+            drop(fs.try_lock().unwrap());
+            answer.send(()).unwrap();
+            assert_eq!(runtime.block_on(reader).unwrap().unwrap(), b"bc");
+        }
+        assert!(asking.try_recv().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! [`Epoch`]: the order a mount's files are read in, known ahead, such as
+//! the shuffled sample order of a training epoch, so each file is fetched
+//! just before the reader gets to it and dropped once it is past, and a
+//! dataset far larger than the cache streams through it.
+
+use std::{collections::HashMap, error::Error, fmt::Display, path::Path};
+
+use super::LazyHTTPFS;
+use crate::LhttpfsError;
+
+/// A path of an order that isn't a file of the layout.
+#[derive(Debug)]
+pub struct NotInLayout(String);
+
+impl Display for NotInLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of the epoch order isn't a file of the layout",
+            self.0
+        )
+    }
+}
+
+impl Error for NotInLayout {}
+
+/// Files read in order, `ahead` of them fetched before the reader gets
+/// there. Reads out of order, as several workers sharing an epoch make,
+/// count for where they are as long as they are within `ahead` files of
+/// the furthest one, and files stay cached until the reader is `ahead`
+/// past them. Once read to the end, the order starts over.
+#[derive(Debug)]
+pub struct Epoch {
+    /// Inodes and sizes, in the order they are read.
+    order: Vec<(u64, u64)>,
+    /// Where each inode comes in `order`.
+    positions: HashMap<u64, Vec<usize>>,
+    ahead: usize,
+    /// One past the furthest file read.
+    reached: usize,
+    /// How far the files ahead were fetched, and those behind dropped.
+    fetched: usize,
+    dropped: usize,
+}
+
+/// What the reader of an [`Epoch`] getting further asks for: the files,
+/// with their sizes, to fetch and those to drop from the cache.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Moved {
+    pub fetch: Vec<(u64, u64)>,
+    pub drop: Vec<u64>,
+}
+
+impl Moved {
+    pub fn is_empty(&self) -> bool {
+        self.fetch.is_empty() && self.drop.is_empty()
+    }
+}
+
+impl Epoch {
+    /// The order of `paths`, from the root of `fs`, fetching `ahead` files
+    /// in advance.
+    pub fn new<S: AsRef<str>>(
+        fs: &LazyHTTPFS,
+        paths: impl IntoIterator<Item = S>,
+        ahead: usize,
+    ) -> Result<Epoch, LhttpfsError> {
+        let files: HashMap<_, _> = (fs.entries())
+            .filter(|entry| !entry.is_dir())
+            .map(|entry| (entry.path, (entry.attr.ino, entry.attr.size)))
+            .collect();
+        let mut order = Vec::new();
+        let mut positions = HashMap::<u64, Vec<usize>>::new();
+        for path in paths {
+            let path = path.as_ref();
+            let file = Path::new("/").join(path.trim_start_matches('/'));
+            let Some(&(ino, size)) = files.get(&file) else {
+                let missing = NotInLayout(path.to_string());
+                return Err(LhttpfsError::Other(Box::new(missing)));
+            };
+            positions.entry(ino).or_default().push(order.len());
+            order.push((ino, size));
+        }
+        Ok(Epoch {
+            order,
+            positions,
+            ahead: ahead.max(1),
+            reached: 0,
+            fetched: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Where the reader is after reading `ino`, and what that asks for.
+    pub fn read(&mut self, ino: u64) -> Moved {
+        if self.reached == self.order.len() {
+            (self.reached, self.fetched, self.dropped) = (0, 0, 0);
+        }
+        let Some(positions) = self.positions.get(&ino) else {
+            return Moved::default();
+        };
+        let behind = self.reached.saturating_sub(self.ahead);
+        let Some(&at) = positions.iter().find(|&&at| at >= behind) else {
+            return Moved::default();
+        };
+        if at < self.reached {
+            return Moved::default();
+        }
+        self.reached = at + 1;
+        let end = (self.reached + self.ahead).min(self.order.len());
+        let fetch = self.order[self.fetched.max(self.reached)..end].to_vec();
+        self.fetched = end;
+        let keep = self.reached.saturating_sub(self.ahead);
+        let wanted =
+            |ino: &u64| (self.positions[ino].iter()).any(|&at| at >= keep && at < self.fetched);
+        let drop = (self.order[self.dropped.min(keep)..keep].iter())
+            .map(|&(ino, _)| ino)
+            .filter(|ino| !wanted(ino))
+            .collect();
+        self.dropped = self.dropped.max(keep);
+        Moved { fetch, drop }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Epoch, Moved, NotInLayout};
+    use crate::{fs::LazyHTTPFS, layout, LhttpfsError};
+
+    fn epoch(ahead: usize) -> (Epoch, Vec<u64>) {
+        let layout = r#"[{"name": "d", "contents": [
+            {"name": "0", "content": "a"}, {"name": "1", "content": "bb"},
+            {"name": "2", "content": "c"}, {"name": "3", "content": "d"},
+            {"name": "4", "content": "e"}
+        ]}]"#;
+        let fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        let paths = ["/d/3", "d/1", "/d/0", "/d/4", "/d/2"];
+        let ino = |path: &str| {
+            let entries = fs.entries();
+            let mut entries = entries.filter(|e| e.path.ends_with(path.trim_start_matches('/')));
+            entries.next().unwrap().attr.ino
+        };
+        let inodes = paths.iter().map(|path| ino(path)).collect();
+        (Epoch::new(&fs, paths, ahead).unwrap(), inodes)
+    }
+
+    #[test]
+    fn ahead_and_behind() {
+        let (mut epoch, inodes) = epoch(2);
+        let [i3, i1, i0, i4, i2] = inodes.try_into().unwrap();
+        let moved = |fetch: &[(u64, u64)], drop: &[u64]| Moved {
+            fetch: fetch.to_vec(),
+            drop: drop.to_vec(),
+        };
+        assert_eq!(epoch.read(i3), moved(&[(i1, 2), (i0, 1)], &[]));
+        // Again, or from a second worker: nothing new.
+        assert_eq!(epoch.read(i3), moved(&[], &[]));
+        assert_eq!(epoch.read(i0), moved(&[(i4, 1), (i2, 1)], &[i3]));
+        // Within reach behind.
+        assert_eq!(epoch.read(i1), moved(&[], &[]));
+        assert_eq!(epoch.read(i4), moved(&[], &[i1]));
+        assert_eq!(epoch.read(i2), moved(&[], &[i0]));
+        // The next epoch.
+        assert_eq!(epoch.read(i3), moved(&[(i1, 2), (i0, 1)], &[]));
+        assert_eq!(epoch.read(i0), moved(&[(i4, 1), (i2, 1)], &[i3]));
+    }
+
+    #[test]
+    fn not_in_layout() {
+        let fs = LazyHTTPFS::new(layout::parse(&b"[]"[..]).unwrap()).unwrap();
+        let error = Epoch::new(&fs, ["/a"], 4).unwrap_err();
+        assert!(matches!(&error, LhttpfsError::Other(e) if e.is::<NotInLayout>()));
+        assert_eq!(
+            error.to_string(),
+            "/a of the epoch order isn't a file of the layout"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! Answers FUSE requests with the operations of [`super::ops`].
+
+use std::{ffi::OsStr, path::Path};
+
+use fuser::{consts, Filesystem};
+use libc::{EACCES, EINVAL, EIO, ENOENT, EPERM, ERANGE, EROFS, ETIMEDOUT};
+use tracing::trace;
+
+use super::{
+    ops::{FileAttr, FileType, OpError},
+    LazyHTTPFS,
+};
+
+impl From<FileType> for fuser::FileType {
+    fn from(kind: FileType) -> fuser::FileType {
+        match kind {
+            FileType::Directory => fuser::FileType::Directory,
+            FileType::RegularFile => fuser::FileType::RegularFile,
+            FileType::Symlink => fuser::FileType::Symlink,
+        }
+    }
+}
+
+impl From<FileAttr> for fuser::FileAttr {
+    fn from(attr: FileAttr) -> fuser::FileAttr {
+        fuser::FileAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: attr.crtime,
+            kind: attr.kind.into(),
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: 0,
+            blksize: attr.blksize,
+            flags: 0,
+        }
+    }
+}
+
+pub(super) fn errno(error: OpError) -> i32 {
+    match error {
+        OpError::NotFound => ENOENT,
+        #[cfg(not(target_os = "macos"))]
+        OpError::NoAttribute => libc::ENODATA,
+        #[cfg(target_os = "macos")]
+        OpError::NoAttribute => libc::ENOATTR,
+        OpError::Denied => EACCES,
+        OpError::ReadOnly => EROFS,
+        OpError::NotPermitted => EPERM,
+        OpError::Failed => EIO,
+        OpError::Refused => EACCES,
+        OpError::TimedOut => ETIMEDOUT,
+        OpError::NotLink => EINVAL,
+    }
+}
+
+/// Replies with `data`, or just its length when the caller asked for the
+/// size by passing 0.
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+impl Filesystem for LazyHTTPFS {
+    fn lookup(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        match self.find(parent, name) {
+            Ok((attr, ttl)) => reply.entry(&ttl, &attr.into(), 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn getattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: Option<u64>,
+        reply: fuser::ReplyAttr,
+    ) {
+        match self.attributes(ino) {
+            Ok((attr, ttl)) => reply.attr(&ttl, &attr.into()),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        match self.link_target(ino) {
+            Ok(target) => reply.data(target),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        match self.xattr(ino, name) {
+            Ok(value) => reply_xattr(reply, size, value),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let keys = match self.xattr_names(ino) {
+            Ok(keys) => keys,
+            Err(e) => return reply.error(errno(e)),
+        };
+        let mut names = Vec::new();
+        for key in keys {
+            names.extend_from_slice(key.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(reply, size, &names);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        let entries = match self.list(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(errno(e)),
+        };
+        trace!("reading directory {} at offset {}", ino, offset);
+        for (i, (inode, name, kind)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(inode, (i + 1) as i64, kind.into(), name) {
+                break;
+            }
+            trace!("READDIR listing file {}: {:?}", inode, name);
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        if write && !self.may_control(req.uid()) {
+            return reply.error(EACCES);
+        }
+        match self.open_file(ino, write) {
+            Ok(opened) if opened.direct => reply.opened(opened.fh, consts::FOPEN_DIRECT_IO),
+            Ok(opened) => reply.opened(opened.fh, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        match self.read_file(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        if !self.may_control(req.uid()) {
+            return reply.error(EACCES);
+        }
+        match self.write_file(ino, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        if !self.may_control(req.uid()) {
+            return reply.error(EACCES);
+        }
+        match self.truncate(ino) {
+            Ok((attr, ttl)) => reply.attr(&ttl, &attr.into()),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn create(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn unlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn rmdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _link_name: &OsStr,
+        _target: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn link(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn removexattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+}
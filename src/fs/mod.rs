@@ -0,0 +1,3859 @@
+//! The tree a layout resolves into, its inodes and the reads through it.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt::{Debug, Display},
+    fs::File,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use tracing::{field::Empty, info, info_span, warn};
+use url::Url;
+
+use crate::{
+    access::AccessLog,
+    archive::{self, Archive, Blocks, Folder, GzipReader, MemberKind, RangeReader, VolumeReader},
+    cache::{Cache, Hit, Policy},
+    fetch::{self, Fetched, Fetchers, NotModified, Request, Validators},
+    hooks, keyring,
+    layout::{
+        Auth, CachePolicy, Defaults, Encoding, InputFile, LimitExceeded, Limits, Pieces, Priority,
+        Segment, SliceFile, COMPILED_MAGIC,
+    },
+    observer::Observers,
+    transform::{
+        self, Compression, Encryption, GzipIndex, SeekTable, ENCRYPTION_OVERHEAD, SEEK_FOOTER_LEN,
+    },
+    LhttpfsError,
+};
+
+mod builder;
+mod control;
+#[cfg(feature = "fuse")]
+mod dispatch;
+mod epoch;
+#[cfg(feature = "fuse")]
+mod fuse;
+#[cfg(feature = "fuse")]
+mod mount;
+mod ops;
+#[cfg(feature = "fuse")]
+mod readahead;
+#[cfg(feature = "fuse")]
+mod stream;
+mod tree;
+mod verify;
+
+pub use builder::Builder;
+#[cfg(feature = "fuse")]
+pub use dispatch::Dispatched;
+pub use epoch::{Epoch, NotInLayout};
+#[cfg(feature = "fuse")]
+pub use mount::MountBuilder;
+pub use ops::{FileAttr, FileType, OpError, ROOT_INO};
+#[cfg(feature = "fuse")]
+pub use stream::Streaming;
+pub use tree::{RemoteFile, RemoteTree};
+pub use verify::ChecksumMismatch;
+
+use control::{control_file, Control};
+pub use control::{ControlFile, CONTROL};
+
+/// A resolved layout, served as a filesystem: the `fuser::Filesystem`
+/// mounted with the `fuse` feature, and what the other front ends read
+/// through.
+pub struct LazyHTTPFS {
+    nodes: Vec<Node>,
+    /// Shared with the other mounts of the process, see
+    /// [`LazyHTTPFS::share`], and with the threads of a [`Dispatched`]
+    /// mount fetching what is missing from it.
+    cache: Arc<Mutex<Cache>>,
+    fetchers: Fetchers,
+    /// Seek tables of seekable zstd files by inode, `None` for files that
+    /// turned out not to have one.
+    seek_tables: HashMap<u64, Option<SeekTable>>,
+    /// Where the data of zip members starts, by inode, once their local
+    /// header was read.
+    zip_starts: HashMap<u64, u64>,
+    /// How far files with a checksum have been hashed by reading them in
+    /// order, by inode, `None` once checked.
+    verifying: HashMap<u64, Option<verify::Verifier>>,
+    /// Access points into gzipped archives by URL, shared by their members.
+    gzip_indexes: HashMap<String, GzipIndex>,
+    /// The manifest as of when it was last opened.
+    manifest: Vec<u8>,
+    access_log: Option<AccessLog>,
+    hooks: Option<hooks::Mount>,
+    /// Told about lookups and reads, see [`Builder::observer`].
+    observers: Observers,
+    control: Control,
+    /// How long the kernel may keep attributes, see [`Builder::attr_ttl`].
+    attr_ttl: Duration,
+    blockwise: Blockwise,
+    /// How many times files were opened, which numbers their handles.
+    handles: u64,
+}
+
+#[derive(Debug)]
+pub struct ZeroChunkSize(String);
+
+impl Display for ZeroChunkSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Chunked file {} has a chunk_size of 0", self.0)
+    }
+}
+
+impl Error for ZeroChunkSize {}
+
+#[derive(Debug)]
+pub struct NoSuchFile(u64);
+
+impl Display for NoSuchFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Inode {} is not a file", self.0)
+    }
+}
+
+impl Error for NoSuchFile {}
+
+#[derive(Debug)]
+pub struct EmptyFilename();
+
+impl Display for EmptyFilename {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Empty filenames are not allowed")
+    }
+}
+
+impl Error for EmptyFilename {}
+
+/// A file [`Limits::require_https`] refuses, read from `url`.
+///
+/// [`Limits::require_https`]: crate::layout::Limits::require_https
+#[derive(Debug)]
+pub struct PlainHttp {
+    pub path: PathBuf,
+    pub url: String,
+}
+
+impl Display for PlainHttp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} would be fetched unencrypted from {}, which --require-https refuses \
+             unless the entry sets \"allow_http\"",
+            self.path.display(),
+            self.url
+        )
+    }
+}
+
+impl Error for PlainHttp {}
+
+/// A name no directory entry can have, and why.
+#[derive(Debug)]
+pub struct BadFilename(String, &'static str);
+
+impl Display for BadFilename {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} isn't allowed as a name: {}", self.0, self.1)
+    }
+}
+
+impl Error for BadFilename {}
+
+/// Refuses names the kernel would take for something else or couldn't
+/// pass on: `.`, `..`, and names with a `/` or NUL in them.
+pub(crate) fn check_name(name: &str) -> Result<(), Box<dyn Error>> {
+    let bad = |reason| Err(Box::new(BadFilename(name.to_string(), reason)) as Box<dyn Error>);
+    match name {
+        "" => Err(Box::new(EmptyFilename())),
+        "." | ".." => bad("it is how a directory refers to itself or its parent"),
+        _ if name.contains('/') => bad("names can't contain a /"),
+        _ if name.contains('\0') => bad("names can't contain a NUL byte"),
+        _ => Ok(()),
+    }
+}
+
+impl LazyHTTPFS {
+    /// Starts configuring a mount, for [`Builder::build`] to resolve a
+    /// layout with.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Resolves `files` with everything else left as it is by default, as
+    /// `LazyHTTPFS::builder().build(files)`: a layout past the default
+    /// [`Limits`](crate::layout::Limits) fails with [`LhttpfsError::Layout`].
+    pub fn new(files: Vec<InputFile>) -> Result<LazyHTTPFS, LhttpfsError> {
+        LazyHTTPFS::builder().build(files)
+    }
+
+    /// Resolves `files` into the mounted tree, with `defaults` under those
+    /// of the root, and the default limits as [`LazyHTTPFS::new`].
+    #[deprecated(note = "use `LazyHTTPFS::builder().defaults(defaults).build(files)`")]
+    pub fn with_defaults(
+        files: Vec<InputFile>,
+        defaults: Defaults,
+    ) -> Result<LazyHTTPFS, LhttpfsError> {
+        LazyHTTPFS::builder().defaults(defaults).build(files)
+    }
+
+    /// Keeps `disk` cached files in `dir` instead of [`Cache::default_dir`].
+    pub fn set_cache_dir(&mut self, dir: PathBuf) {
+        self.cache = Arc::new(Mutex::new(Cache::new(Some(dir))));
+    }
+
+    /// Keeps what is cached under `bytes`, as [`Cache::limit`].
+    pub fn set_cache_limit(&mut self, bytes: Option<u64>) {
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.limit(bytes);
+    }
+
+    /// Records every read in `file`, naming files by their path under
+    /// `root`, the mount point.
+    pub fn set_access_log(&mut self, file: Arc<File>, root: &Path) {
+        self.access_log = Some(AccessLog::new(file, self.file_paths(root)));
+    }
+
+    /// Fires [`hooks`] events for this mount, at `root`.
+    pub fn set_hooks(&mut self, root: &Path) {
+        self.hooks = Some(hooks::Mount::new(root.into(), self.file_paths(root)));
+    }
+
+    /// The path of every file under `root`, by inode.
+    fn file_paths(&self, root: &Path) -> HashMap<u64, String> {
+        let mut paths = HashMap::new();
+        let mut dirs = vec![root.to_path_buf()];
+        for entry in self.walk().into_iter().skip(1) {
+            dirs.truncate(entry.depth);
+            let path = dirs[entry.depth - 1].join(&entry.name);
+            match entry.is_dir() {
+                true => dirs.push(path),
+                false => {
+                    paths.insert(entry.attr.ino, path.to_string_lossy().into_owned());
+                }
+            }
+        }
+        paths
+    }
+
+    /// Makes this mount use the cache and backends of `other`, so files
+    /// they share are fetched once and backends keep one set of
+    /// connections and credentials. Each still cancels only its own
+    /// fetches.
+    pub fn share(&mut self, other: &LazyHTTPFS) {
+        self.cache = other.cache.clone();
+        self.fetchers = other.fetchers.fork();
+    }
+
+    /// Aborts the fetches under way for this tree and fails every later
+    /// one, see [`Fetchers::cancel`].
+    pub fn cancel_fetches(&self) {
+        self.fetchers.cancel();
+    }
+
+    /// The backends files are read with, to register more.
+    pub fn fetchers_mut(&mut self) -> &mut Fetchers {
+        &mut self.fetchers
+    }
+
+    /// Writes the resolved inode table, which [`LazyHTTPFS::read_compiled`]
+    /// loads without parsing or resolving the layout again. Trees with
+    /// credentials, whether from the layout or the command line, are
+    /// refused rather than written to disk with them: `keyring:`
+    /// references are all an `auth` may hold.
+    pub fn compile(&self, mut writer: impl Write) -> Result<(), LhttpfsError> {
+        if let Some(path) = self.credentials() {
+            return Err(LhttpfsError::layout(Box::new(CompiledCredentials(path))));
+        }
+        writer.write_all(COMPILED_MAGIC)?;
+        bincode::serialize_into(&mut writer, &(COMPILED_VERSION, &self.nodes))
+            .map_err(|e| LhttpfsError::Other(e))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads a table written by [`LazyHTTPFS::compile`], or returns `None`
+    /// without consuming anything if `reader` doesn't hold one. The tree
+    /// has to be within `limits`, as layouts given to [`Builder::build`]
+    /// do, and so do those added to it once mounted.
+    pub fn read_compiled(
+        reader: &mut impl BufRead,
+        limits: Limits,
+    ) -> Result<Option<LazyHTTPFS>, LhttpfsError> {
+        if !reader.fill_buf()?.starts_with(COMPILED_MAGIC) {
+            return Ok(None);
+        }
+        reader.consume(COMPILED_MAGIC.len());
+        let invalid = |e| LhttpfsError::layout(Box::new(e));
+        let version: u64 = bincode::deserialize_from(&mut *reader).map_err(invalid)?;
+        if version != COMPILED_VERSION {
+            return Err(LhttpfsError::layout(Box::new(CompiledVersion(version))));
+        }
+        let mut fs = LazyHTTPFS {
+            nodes: bincode::deserialize_from(reader).map_err(invalid)?,
+            cache: Arc::new(Mutex::new(Cache::new(Cache::default_dir()))),
+            fetchers: Fetchers::default(),
+            seek_tables: HashMap::new(),
+            zip_starts: HashMap::new(),
+            verifying: HashMap::new(),
+            gzip_indexes: HashMap::new(),
+            manifest: Vec::new(),
+            access_log: None,
+            hooks: None,
+            observers: Observers::default(),
+            control: Control::new(limits),
+            attr_ttl: TTL,
+            blockwise: BLOCKWISE,
+            handles: 0,
+        };
+        fs.check_limits(&limits)?;
+        Ok(Some(fs))
+    }
+
+    /// Fails as [`Limits::check`] does on the first limit the tree is
+    /// past, then as [`LazyHTTPFS::check_https`] does.
+    fn check_limits(&mut self, limits: &Limits) -> Result<(), LhttpfsError> {
+        let exceeded = |message: String| -> Result<(), LhttpfsError> {
+            Err(LhttpfsError::Layout(Box::new(LimitExceeded(message))))
+        };
+        for (entries, entry) in (1..).zip(self.entries().skip(1)) {
+            let path = entry.path.display();
+            if entry.name.len() > limits.max_name_length {
+                return exceeded(format!(
+                    "The name of {} is {} bytes long, more than the limit of {}",
+                    path,
+                    entry.name.len(),
+                    limits.max_name_length
+                ));
+            }
+            if entries > limits.max_entries {
+                return exceeded(format!(
+                    "The layout has more than {} entries (reached at {})",
+                    limits.max_entries, path
+                ));
+            }
+            if entry.is_dir() && entry.depth > limits.max_depth {
+                return exceeded(format!(
+                    "{} is nested more than {} directories deep",
+                    path, limits.max_depth
+                ));
+            }
+            let filtered = matches!(
+                self.get_inode(entry.attr.ino),
+                Some(Node::FileNode(file)) if file.filter.is_some()
+            );
+            if filtered && !limits.filters {
+                return exceeded(format!(
+                    "{} has a filter command, and filters aren't allowed",
+                    path
+                ));
+            }
+        }
+        self.check_https(limits)
+    }
+
+    /// Fails on the first file [`Limits::require_https`] refuses, if it is
+    /// set, and otherwise has the fetchers keep every other fetch encrypted.
+    pub(crate) fn check_https(&mut self, limits: &Limits) -> Result<(), LhttpfsError> {
+        if !limits.require_https {
+            return Ok(());
+        }
+        if let Some(plaintext) = self.plaintext() {
+            return Err(LhttpfsError::Layout(Box::new(plaintext)));
+        }
+        self.fetchers.require_https();
+        Ok(())
+    }
+
+    /// The path of the first file sent with headers or with an `auth`
+    /// holding more than `keyring:` references, if any.
+    fn credentials(&self) -> Option<PathBuf> {
+        self.entries()
+            .find_map(|entry| match self.get_inode(entry.attr.ino) {
+                Some(Node::FileNode(file))
+                    if !file.headers.is_empty()
+                        || file
+                            .auth
+                            .as_ref()
+                            .is_some_and(|auth| !keyring::referenced(auth)) =>
+                {
+                    Some(entry.path)
+                }
+                _ => None,
+            })
+    }
+}
+
+/// A tree [`LazyHTTPFS::compile`] refuses, as it would write the
+/// credentials of the file at this path to disk.
+#[derive(Debug)]
+pub struct CompiledCredentials(PathBuf);
+
+impl Display for CompiledCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is fetched with headers or credentials, which compiled layouts can't hold; refer to them with keyring: or give them with --netrc when mounting instead",
+            self.0.display()
+        )
+    }
+}
+
+impl Error for CompiledCredentials {}
+
+/// Bumped whenever the shape of [`Node`] changes. Compiled layouts are a
+/// cache of the JSON they came from, so other versions are simply refused.
+const COMPILED_VERSION: u64 = 14;
+
+#[derive(Debug)]
+pub struct CompiledVersion(u64);
+
+impl Display for CompiledVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "This layout was compiled by another lhttpfs release (format {}, this one reads {}), compile it again",
+            self.0, COMPILED_VERSION
+        )
+    }
+}
+
+impl Error for CompiledVersion {}
+
+/// One entry of the resolved tree, as listed by [`LazyHTTPFS::walk`].
+#[derive(Debug)]
+pub struct Entry {
+    /// Number of directories above this entry; the root has depth 0.
+    pub depth: usize,
+    pub name: OsString,
+    /// From the root, which is `/`.
+    pub path: PathBuf,
+    pub attr: FileAttr,
+    /// Where a file's bytes come from, `None` for directories.
+    pub source: Option<String>,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attr.kind == FileType::Directory
+    }
+}
+
+/// The entries of a tree, as [`LazyHTTPFS::entries`] goes through them.
+pub struct Entries<'a> {
+    fs: &'a LazyHTTPFS,
+    /// What is left to visit, the next entry last: its inode, depth and
+    /// path.
+    stack: Vec<(u64, usize, PathBuf)>,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let (ino, depth, path) = self.stack.pop()?;
+            let Some(node) = self.fs.get_inode(ino) else {
+                continue;
+            };
+            if let Node::DirNode(dir) = node {
+                let mut contents: Vec<_> = dir.contents.iter().collect();
+                contents.sort();
+                let contents = contents.into_iter().rev();
+                (self.stack).extend(contents.map(|(name, &ino)| (ino, depth + 1, path.join(name))));
+            }
+            return Some(Entry {
+                depth,
+                name: path.file_name().unwrap_or(OsStr::new("/")).to_owned(),
+                path,
+                attr: node.get_attr(),
+                source: match node {
+                    Node::DirNode(_) => None,
+                    Node::FileNode(file) => Some(file.source.to_string()),
+                },
+            });
+        }
+    }
+}
+
+impl LazyHTTPFS {
+    /// Goes through the tree that would be mounted depth-first, each
+    /// directory followed by its contents in name order, one entry at a
+    /// time: [`LazyHTTPFS::remote_parts`] says where a file's bytes are
+    /// fetched from and [`LazyHTTPFS::cached`] whether they are cached.
+    pub fn entries(&self) -> Entries<'_> {
+        Entries {
+            fs: self,
+            stack: vec![(ROOT_INO, 0, PathBuf::from("/"))],
+        }
+    }
+
+    /// All of [`LazyHTTPFS::entries`] at once.
+    pub fn walk(&self) -> Vec<Entry> {
+        self.entries().collect()
+    }
+}
+
+impl LazyHTTPFS {
+    /// Whether any file is piped through a `filter` command.
+    pub fn filters(&self) -> bool {
+        (self.nodes.iter())
+            .any(|node| matches!(node, Node::FileNode(file) if file.filter.is_some()))
+    }
+
+    /// The first file, with its path, read from a URL its backend reads
+    /// without encryption, such as a plain `http://` or `dav://` one,
+    /// without `allow_http`, and the URL.
+    pub fn plaintext(&self) -> Option<PlainHttp> {
+        self.entries().find_map(|entry| {
+            match self.get_inode(entry.attr.ino) {
+                Some(Node::FileNode(file)) if !file.allow_http => (),
+                _ => return None,
+            }
+            let mut urls = self.remote_parts(entry.attr.ino).into_iter();
+            let part = urls.find(|part| self.fetchers.plaintext(&part.url))?;
+            Some(PlainHttp {
+                path: entry.path,
+                url: part.url,
+            })
+        })
+    }
+
+    /// Adds a hidden `.<name>.url` file next to every file read from URLs,
+    /// listing them one per line. Names that are already taken are left
+    /// alone.
+    pub fn add_source_files(&mut self) {
+        let mut added = Vec::new();
+        for (dir, node) in self.nodes.iter().enumerate() {
+            let Node::DirNode(dir_node) = node else {
+                continue;
+            };
+            for (name, &ino) in &dir_node.contents {
+                let Some(Node::FileNode(file)) = self.get_inode(ino) else {
+                    continue;
+                };
+                let urls = self.remote_parts(ino);
+                let name = format!(".{}.url", name.to_string_lossy());
+                if urls.is_empty() || dir_node.contents.contains_key(OsStr::new(&name)) {
+                    continue;
+                }
+                let content = urls
+                    .iter()
+                    .map(|part| format!("{}\n", part.url))
+                    .collect::<String>();
+                let options = Defaults {
+                    uid: Some(file.attr.uid),
+                    gid: Some(file.attr.gid),
+                    ..Defaults::default()
+                };
+                added.push((dir, name, content, options));
+            }
+        }
+        for (dir, name, content, options) in added {
+            let ino = self.nodes.len() as u64 + 1;
+            let size = content.len() as u64;
+            let node = file_node(ino, size, options, Source::Inline(content.into_bytes()));
+            self.nodes.push(Node::FileNode(Box::new(node)));
+            if let Node::DirNode(dir) = &mut self.nodes[dir] {
+                dir.contents.insert(name.into(), ino);
+            }
+        }
+    }
+}
+
+impl LazyHTTPFS {
+    /// Adds [`MANIFEST`] to the root, unless the layout has a file of that
+    /// name there.
+    pub fn add_manifest(&mut self) {
+        let ino = self.nodes.len() as u64 + 1;
+        let Some(Node::DirNode(root)) = self.nodes.first_mut() else {
+            return;
+        };
+        if root.contents.contains_key(OsStr::new(MANIFEST)) {
+            return;
+        }
+        root.contents.insert(MANIFEST.into(), ino);
+        let node = file_node(ino, 0, Defaults::default(), Source::Manifest);
+        self.nodes.push(Node::FileNode(Box::new(node)));
+    }
+
+    /// The manifest at the root: every other file, with where it is read
+    /// from and whether it is cached now, as JSON.
+    pub fn manifest(&self) -> Vec<u8> {
+        let mut files = Vec::new();
+        self.manifest_entries(1, "", &mut files);
+        let mut json = serde_json::to_vec_pretty(&serde_json::json!({ "files": files })).unwrap();
+        json.push(b'\n');
+        json
+    }
+
+    fn manifest_entries<'a>(&'a self, dir: u64, path: &str, files: &mut Vec<ManifestEntry<'a>>) {
+        let Some(Node::DirNode(dir)) = self.get_inode(dir) else {
+            return;
+        };
+        let mut contents = dir.contents.iter().collect::<Vec<_>>();
+        contents.sort();
+        for (name, &ino) in contents {
+            let path = format!("{}/{}", path, name.to_string_lossy());
+            match self.get_inode(ino) {
+                Some(Node::DirNode(_)) => self.manifest_entries(ino, &path, files),
+                Some(Node::FileNode(file))
+                    if !matches!(
+                        file.source,
+                        Source::Manifest | Source::Control(_) | Source::Link(_)
+                    ) =>
+                {
+                    files.push(ManifestEntry {
+                        url: match &file.source {
+                            Source::Url(url) => Some(url.clone()),
+                            _ => self.remote_parts(ino).into_iter().next().map(|p| p.url),
+                        },
+                        path,
+                        size: file.attr.size,
+                        sha256: file.sha256.as_deref(),
+                        md5: file.md5.as_deref(),
+                        cache: file.cache,
+                        cached: self.cached(ino),
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The priority of file `ino`, `None` for directories.
+    pub fn priority(&self, ino: u64) -> Option<Priority> {
+        match self.get_inode(ino) {
+            Some(Node::FileNode(file)) => Some(file.priority),
+            _ => None,
+        }
+    }
+
+    /// Whether all of the bytes of file `ino` are cached, `None` for
+    /// directories and where that can't be told without reading it.
+    pub fn cached(&self, ino: u64) -> Option<bool> {
+        let Some(Node::FileNode(file)) = self.get_inode(ino) else {
+            return None;
+        };
+        match &file.source {
+            // Seekable files are cached a frame at a time.
+            Source::Url(_)
+                if file.decompress == Some(Compression::Zstd) && file.compressed_size.is_some() =>
+            {
+                return None
+            }
+            Source::Inline(_) | Source::Manifest | Source::Control(_) | Source::Link(_) => {
+                return None
+            }
+            Source::Zip { .. } if !self.zip_starts.contains_key(&ino) => return Some(false),
+            _ => {}
+        }
+        let cache = self.cache.lock().unwrap();
+        let keys = self.cache_keys(ino, file);
+        Some(
+            keys.iter()
+                .all(|(key, policy)| cache.contains(key, *policy)),
+        )
+    }
+
+    /// The cache entries a file's bytes are kept under, as far as they are
+    /// known: the frames of a seekable file and the data of a zip member
+    /// only once they were first read.
+    fn cache_keys(&self, ino: u64, file: &FileNode) -> Vec<(String, Policy)> {
+        let entry = |url: &str, range| cache_entry(file, url, range);
+        let span = |span: &Span| entry(&span.url, Some((span.start, span.len)));
+        match &file.source {
+            Source::Url(url)
+                if file.decompress == Some(Compression::Zstd) && file.compressed_size.is_some() =>
+            {
+                let Some(Some(table)) = self.seek_tables.get(&ino) else {
+                    return Vec::new();
+                };
+                (table.frames().iter())
+                    .map(|frame| entry(url, Some((frame.compressed_offset, frame.compressed_size))))
+                    .collect()
+            }
+            Source::Url(url) => {
+                (part_reads(self.blockwise, file, file.attr.size, 0, file.attr.size).into_iter())
+                    .map(|(range, _, _)| entry(url, range))
+                    .collect()
+            }
+            Source::Inline(_) | Source::Manifest | Source::Control(_) | Source::Link(_) => {
+                Vec::new()
+            }
+            Source::Concat(segments) => (segments.iter())
+                .flat_map(|s| {
+                    (part_reads(self.blockwise, file, s.size, 0, s.size).into_iter())
+                        .map(|(range, _, _)| entry(&s.url, range))
+                })
+                .collect(),
+            Source::Range { url, start, len } => vec![entry(url, Some((*start, *len)))],
+            Source::Zip {
+                url,
+                compressed_size,
+                ..
+            } => (self.zip_starts.get(&ino).into_iter())
+                .map(|&start| entry(url, Some((start, *compressed_size))))
+                .collect(),
+            Source::GzipRange {
+                url, start, len, ..
+            } => vec![(gzip_key(url, *start, *len), file.policy())],
+            Source::Blocks { url, blocks } => (blocks.blocks.iter())
+                .chain(blocks.fragment.iter().map(|(block, _)| block))
+                .filter(|block| block.len > 0)
+                .map(|block| entry(url, Some((block.start, block.len as u64))))
+                .collect(),
+            Source::Spans(spans) => spans.iter().map(span).collect(),
+            Source::Folder { packed, folder, .. } => {
+                vec![(folder_key(packed, folder), file.policy())]
+            }
+        }
+    }
+}
+
+/// The name [`LazyHTTPFS::add_manifest`] gives the manifest.
+pub const MANIFEST: &str = ".lhttpfs-manifest.json";
+
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    path: String,
+    url: Option<String>,
+    size: u64,
+    sha256: Option<&'a str>,
+    md5: Option<&'a str>,
+    cache: CachePolicy,
+    cached: Option<bool>,
+}
+
+/// A remote object that a file reads from, as listed by
+/// [`LazyHTTPFS::remote_parts`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct RemotePart {
+    pub url: String,
+    /// The exact size the object should have, when the file covers all of it.
+    pub size: Option<u64>,
+    /// How many bytes the object needs to have at least.
+    pub min_size: u64,
+}
+
+/// What a HEAD request says about a URL.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Head {
+    pub status: u32,
+    pub size: Option<u64>,
+    /// Where the URL redirected to, if it did.
+    pub redirect: Option<String>,
+}
+
+impl LazyHTTPFS {
+    /// The URLs file `ino` reads from, including its mirrors.
+    pub fn remote_parts(&self, ino: u64) -> Vec<RemotePart> {
+        let Some(Node::FileNode(file)) = self.get_inode(ino) else {
+            return Vec::new();
+        };
+        let whole = |url: &String, size: u64| RemotePart {
+            url: url.clone(),
+            size: Some(size),
+            min_size: size,
+        };
+        match &file.source {
+            // The remote size of filtered files isn't known at all.
+            Source::Url(url) if file.filter.is_some() => std::iter::once(url)
+                .chain(&file.mirrors)
+                .map(|url| RemotePart {
+                    url: url.clone(),
+                    size: None,
+                    min_size: 0,
+                })
+                .collect(),
+            // The layout's size is the decompressed one, so only a
+            // `compressed_size` can be checked.
+            Source::Url(url) if file.decompress.is_some() => std::iter::once(url)
+                .chain(&file.mirrors)
+                .map(|url| RemotePart {
+                    url: url.clone(),
+                    size: file.compressed_size,
+                    min_size: file.compressed_size.unwrap_or(0),
+                })
+                .collect(),
+            Source::Url(url) if file.decrypt.is_some() => std::iter::once(url)
+                .chain(&file.mirrors)
+                .map(|url| whole(url, file.attr.size + ENCRYPTION_OVERHEAD))
+                .collect(),
+            Source::Url(url) => std::iter::once(url)
+                .chain(&file.mirrors)
+                .map(|url| whole(url, file.attr.size))
+                .collect(),
+            Source::Inline(_) | Source::Manifest | Source::Control(_) | Source::Link(_) => {
+                Vec::new()
+            }
+            Source::Concat(segments) => segments
+                .iter()
+                .map(|segment| whole(&segment.url, segment.size))
+                .collect(),
+            Source::Range { url, start, len } => vec![RemotePart {
+                url: url.clone(),
+                size: None,
+                min_size: start + len,
+            }],
+            Source::Zip {
+                url,
+                header,
+                compressed_size,
+            } => vec![RemotePart {
+                url: url.clone(),
+                size: None,
+                min_size: header + compressed_size,
+            }],
+            Source::GzipRange {
+                url, archive_size, ..
+            } => vec![whole(url, *archive_size)],
+            Source::Blocks { url, blocks } => vec![RemotePart {
+                url: url.clone(),
+                size: None,
+                min_size: (blocks.blocks.iter())
+                    .chain(blocks.fragment.iter().map(|(block, _)| block))
+                    .map(|block| block.start + block.len as u64)
+                    .max()
+                    .unwrap_or(0),
+            }],
+            Source::Spans(spans) | Source::Folder { packed: spans, .. } => spans
+                .iter()
+                .map(|span| RemotePart {
+                    url: span.url.clone(),
+                    size: None,
+                    min_size: span.start + span.len,
+                })
+                .collect(),
+        }
+    }
+
+    /// Asks the backends for the size of every file the layout gave none,
+    /// `jobs` at a time. Those they can't tell keep it unknown, to be asked
+    /// again when opened.
+    pub fn probe_sizes(&mut self, jobs: usize) {
+        let todo: Vec<u64> = (self.nodes.iter())
+            .filter(|node| node.unprobed())
+            .map(|node| node.get_attr().ino)
+            .collect();
+        if todo.is_empty() {
+            return;
+        }
+        info!("Probing the size of {} files", todo.len());
+        let todo = Mutex::new(todo.into_iter());
+        let sizes = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..jobs.max(1) {
+                scope.spawn(|| loop {
+                    let Some(ino) = todo.lock().unwrap().next() else {
+                        return;
+                    };
+                    match self.probed(ino) {
+                        Ok(probed) => sizes.lock().unwrap().push((ino, probed)),
+                        Err(e) => warn!("Couldn't find the size of inode {}: {}", ino, e),
+                    }
+                });
+            }
+        });
+        for (ino, (size, modified)) in sizes.into_inner().unwrap() {
+            learn_size(&mut self.nodes, ino, size);
+            learn_modified(&mut self.nodes, ino, modified);
+        }
+    }
+
+    /// The size the backend of file `ino` gives its URL, and when it was
+    /// last modified, as far as it can tell.
+    fn probed(&self, ino: u64) -> Result<(Option<u64>, Option<SystemTime>), LhttpfsError> {
+        match self.get_inode(ino) {
+            Some(Node::FileNode(file)) => match &file.source {
+                Source::Url(url) => {
+                    let metadata = self.fetchers.metadata(&file.request(url))?;
+                    Ok((metadata.size, metadata.modified))
+                }
+                _ => Ok((None, None)),
+            },
+            _ => Ok((None, None)),
+        }
+    }
+
+    /// Asks for the size of file `ino` if the layout gave none and
+    /// [`LazyHTTPFS::probe_sizes`] didn't find it.
+    fn probe(&mut self, ino: u64) -> Result<(), LhttpfsError> {
+        if self.get_inode(ino).is_some_and(Node::unprobed) {
+            let (size, modified) = self.probed(ino)?;
+            learn_size(&mut self.nodes, ino, size);
+            learn_modified(&mut self.nodes, ino, modified);
+        }
+        Ok(())
+    }
+
+    /// Issues a HEAD request for `url` with the headers and auth of file
+    /// `ino`, following redirects.
+    #[cfg(feature = "http")]
+    pub fn head(&self, ino: u64, url: &str) -> Result<Head, LhttpfsError> {
+        let Some(Node::FileNode(file)) = self.get_inode(ino) else {
+            return Err(LhttpfsError::Other(Box::new(NoSuchFile(ino))));
+        };
+        self.fetchers.permitted(url)?;
+        let request = file.request(url);
+        let auth = self.fetchers.auth(&request)?;
+        let request = Request {
+            auth: auth.as_deref(),
+            ..request
+        };
+        let head = || -> Result<Head, LhttpfsError> {
+            let mut curl = fetch::easy(&request)?;
+            curl.nobody(true)?;
+            fetch::following(&mut curl, |curl| Ok(curl.perform()?))?;
+            let length = curl.content_length_download()?;
+            let effective = curl.effective_url()?.map(str::to_owned);
+            Ok(Head {
+                status: curl.response_code()?,
+                size: (length >= 0.0).then_some(length as u64),
+                redirect: effective.filter(|effective| effective != url),
+            })
+        };
+        (self.fetchers.fetching(url, head)).map_err(|e| LhttpfsError::fetch(url, Box::new(e)))
+    }
+
+    #[cfg(not(feature = "http"))]
+    pub fn head(&self, _ino: u64, url: &str) -> Result<Head, LhttpfsError> {
+        Err(LhttpfsError::fetch(url, Box::new(crate::NotBuilt("http"))))
+    }
+}
+
+const DEFAULT_ATTR: FileAttr = FileAttr {
+    ino: 0,
+    size: 0,
+    blocks: 0,
+    atime: UNIX_EPOCH,
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::RegularFile,
+    perm: 0o444,
+    nlink: 1,
+    uid: 1000,
+    gid: 1000,
+    blksize: 512,
+};
+
+/// The permissions of directories the layout gives none.
+const DIR_MODE: u16 = 0o555;
+
+/// `attr` with every timestamp at `mtime`, in seconds since the epoch, if
+/// it is known.
+fn dated(attr: FileAttr, mtime: Option<u64>) -> FileAttr {
+    let Some(mtime) = mtime else {
+        return attr;
+    };
+    let time = UNIX_EPOCH + Duration::from_secs(mtime);
+    FileAttr {
+        atime: time,
+        mtime: time,
+        ctime: time,
+        crtime: time,
+        ..attr
+    }
+}
+
+/// The attributes of directory `ino`, owned, dated and with the
+/// permissions `options` give it.
+fn dir_attr(ino: u64, options: &Defaults) -> FileAttr {
+    let attr = FileAttr {
+        ino,
+        kind: FileType::Directory,
+        perm: options.dir_mode.unwrap_or(DIR_MODE),
+        uid: options.uid.unwrap_or(DEFAULT_ATTR.uid),
+        gid: options.gid.unwrap_or(DEFAULT_ATTR.gid),
+        ..DEFAULT_ATTR
+    };
+    dated(attr, options.mtime)
+}
+
+fn file_node(ino: u64, size: u64, options: Defaults, source: Source) -> FileNode {
+    let attr = DEFAULT_ATTR;
+    FileNode {
+        attr: dated(
+            FileAttr {
+                ino,
+                size,
+                blocks: size / 512,
+                perm: options.mode.unwrap_or(attr.perm),
+                uid: options.uid.unwrap_or(attr.uid),
+                gid: options.gid.unwrap_or(attr.gid),
+                ..attr
+            },
+            options.mtime,
+        ),
+        source,
+        headers: options.headers,
+        auth: options.auth,
+        cache: options.cache.unwrap_or_default(),
+        content_type: options.content_type,
+        ttl: match options.pin {
+            Some(true) => None,
+            _ => options.ttl.map(Duration::from_secs),
+        },
+        mirrors: Vec::new(),
+        pieces: None,
+        decompress: None,
+        compressed_size: None,
+        decrypt: None,
+        filter: None,
+        sha256: None,
+        md5: None,
+        allow_http: options.allow_http.unwrap_or(false),
+        priority: options.priority.unwrap_or_default(),
+    }
+}
+
+/// Turns `files` into nodes, numbering them from `inode`. Slices are added
+/// with no source, and listed in `slices` to be resolved once every node
+/// exists.
+fn add_inodes<'a>(
+    files: &'a [InputFile],
+    inode: &mut u64,
+    inherited: &Defaults,
+    base: Option<&Url>,
+    fetchers: &Fetchers,
+    slices: &mut Vec<(u64, &'a SliceFile)>,
+) -> Result<(Vec<Node>, Vec<usize>), Box<dyn Error>> {
+    let mut result = Vec::new();
+    let mut toplev = Vec::new();
+    for file in files {
+        match file {
+            InputFile::ChunkedFile(chunked) => {
+                if chunked.chunk_size == 0 {
+                    return Err(Box::new(ZeroChunkSize(chunked.name.clone())));
+                }
+                let mut options = chunked.options.inherit(inherited);
+                // A chunk on its own isn't a file of the whole's type.
+                options.content_type = None;
+                let url = resolve_url(base, &chunked.url)?;
+                let dir_inode = *inode;
+                toplev.push(*inode as usize);
+                *inode += 1;
+                let (size, chunk_size) = (chunked.size, chunked.chunk_size);
+                let count = size.div_ceil(chunk_size);
+                let width = count.saturating_sub(1).to_string().len().max(3);
+                let mut contents = HashMap::new();
+                for i in 0..count {
+                    let start = i * chunk_size;
+                    let len = chunk_size.min(size - start);
+                    let name = format!("{}.{:0width$}", chunked.name, i);
+                    contents.insert(OsString::from(name), *inode);
+                    result.push(Node::FileNode(Box::new(file_node(
+                        *inode,
+                        len,
+                        options.clone(),
+                        Source::Range {
+                            url: url.clone(),
+                            start,
+                            len,
+                        },
+                    ))));
+                    *inode += 1;
+                }
+                result.push(Node::DirNode(DirNode {
+                    attr: dir_attr(dir_inode, &options),
+                    contents,
+                }));
+            }
+            InputFile::URLFile(urlfile) if urlfile.archive.is_some() => {
+                if urlfile.decrypt.is_some() {
+                    return Err(format!("{}: archives can't be decrypted", urlfile.name).into());
+                }
+                if urlfile.filter.is_some() {
+                    return Err(format!("{}: archives can't be filtered", urlfile.name).into());
+                }
+                toplev.push(*inode as usize);
+                let volume = Segment {
+                    url: resolve_url(base, &urlfile.url)?,
+                    size: urlfile.size,
+                };
+                result.extend(archive_nodes(
+                    urlfile.archive.unwrap(),
+                    urlfile.decompress,
+                    &[volume],
+                    urlfile.options.inherit(inherited),
+                    inode,
+                    fetchers,
+                )?);
+            }
+            InputFile::URLFile(urlfile) => {
+                let mut node = file_node(
+                    *inode,
+                    urlfile.size,
+                    urlfile.options.inherit(inherited),
+                    Source::Url(resolve_url(base, &urlfile.url)?),
+                );
+                node.mirrors = urlfile
+                    .mirrors
+                    .iter()
+                    .map(|mirror| resolve_url(base, mirror))
+                    .collect::<Result<_, _>>()?;
+                node.pieces = urlfile.pieces.clone().map(Box::new);
+                node.decompress = urlfile.decompress;
+                node.compressed_size = urlfile.compressed_size;
+                node.decrypt = urlfile.decrypt.clone().map(Box::new);
+                node.filter = urlfile.filter.clone();
+                node.sha256 = urlfile.sha256.as_deref().map(str::to_ascii_lowercase);
+                node.md5 = urlfile.md5.as_deref().map(str::to_ascii_lowercase);
+                result.push(Node::FileNode(Box::new(node)));
+                toplev.push(*inode as usize);
+                *inode += 1;
+            }
+            InputFile::ConcatFile(concat) => {
+                let segments = concat
+                    .segments
+                    .iter()
+                    .map(|segment| {
+                        Ok(Segment {
+                            url: resolve_url(base, &segment.url)?,
+                            size: segment.size,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, url::ParseError>>()?;
+                toplev.push(*inode as usize);
+                // The segments of an archive are its volumes.
+                if let Some(archive) = concat.archive {
+                    result.extend(archive_nodes(
+                        archive,
+                        None,
+                        &segments,
+                        concat.options.inherit(inherited),
+                        inode,
+                        fetchers,
+                    )?);
+                    continue;
+                }
+                result.push(Node::FileNode(Box::new(file_node(
+                    *inode,
+                    segments.iter().map(|s| s.size).sum(),
+                    concat.options.inherit(inherited),
+                    Source::Concat(segments),
+                ))));
+                *inode += 1;
+            }
+            InputFile::SliceFile(slice) => {
+                slices.push((*inode, slice));
+                result.push(Node::FileNode(Box::new(file_node(
+                    *inode,
+                    slice.size,
+                    slice.options.inherit(inherited),
+                    Source::Inline(Vec::new()),
+                ))));
+                toplev.push(*inode as usize);
+                *inode += 1;
+            }
+            InputFile::Link(link) => {
+                let mut node = file_node(
+                    *inode,
+                    link.target.len() as u64,
+                    link.options.inherit(inherited),
+                    Source::Link(link.target.clone()),
+                );
+                node.attr.kind = FileType::Symlink;
+                node.attr.perm = 0o777;
+                result.push(Node::FileNode(Box::new(node)));
+                toplev.push(*inode as usize);
+                *inode += 1;
+            }
+            InputFile::InlineFile(inline) => {
+                let data = match inline.encoding {
+                    Encoding::Utf8 => inline.content.as_bytes().to_vec(),
+                    Encoding::Base64 => BASE64.decode(&inline.content)?,
+                };
+                result.push(Node::FileNode(Box::new(file_node(
+                    *inode,
+                    data.len() as u64,
+                    inline.options.inherit(inherited),
+                    Source::Inline(data),
+                ))));
+                toplev.push(*inode as usize);
+                *inode += 1;
+            }
+            InputFile::Directory(dir) => {
+                let options = dir.defaults.inherit(inherited);
+                let dir_base = match &dir.base_url {
+                    Some(url) => Some(base_directory(base, url)?),
+                    None => base.cloned(),
+                };
+                result.push(Node::DirNode(DirNode {
+                    attr: dir_attr(*inode, &options),
+                    contents: HashMap::new(),
+                }));
+                let dir_index = result.len() - 1;
+                toplev.push(*inode as usize);
+                *inode += 1;
+                for file in &dir.contents {
+                    check_name(file.name())?;
+                }
+                let (results, toplev) = add_inodes(
+                    &dir.contents,
+                    inode,
+                    &options,
+                    dir_base.as_ref(),
+                    fetchers,
+                    slices,
+                )?;
+                let inodes = toplev
+                    .iter()
+                    .zip(&dir.contents)
+                    .map(|(inode, file)| (OsString::from(file.name()), *inode as u64));
+                result.extend(results);
+                if let Some(Node::DirNode(n)) = result.get_mut(dir_index) {
+                    n.contents = inodes.collect();
+                } else {
+                    panic!("Directory indexing failed!");
+                }
+            }
+        }
+    }
+    Ok((result, toplev))
+}
+
+/// The nodes of an `archive` entry: a directory at inode `inode` holding
+/// the members listed in the archive's index. A gzipped tar is read through
+/// a [`GzipIndex`], so only its decompressed offsets are known. An archive
+/// split into several `volumes` is read as their concatenation.
+fn archive_nodes(
+    archive: Archive,
+    decompress: Option<Compression>,
+    volumes: &[Segment],
+    mut options: Defaults,
+    inode: &mut u64,
+    fetchers: &Fetchers,
+) -> Result<Vec<Node>, Box<dyn Error>> {
+    let (url, size) = match volumes {
+        [] => return Err("archives need at least one volume".into()),
+        [volume] => (volume.url.clone(), volume.size),
+        [first, ..] if matches!(archive, Archive::Tar | Archive::Iso9660 | Archive::SevenZ) => {
+            (first.url.clone(), volumes.iter().map(|v| v.size).sum())
+        }
+        _ => return Err(format!("{} archives can't be split into volumes", archive).into()),
+    };
+    let mut reader = VolumeReader::new(
+        volumes
+            .iter()
+            .map(|volume| {
+                let request = Request {
+                    url: &volume.url,
+                    headers: &options.headers,
+                    auth: options.auth.as_ref(),
+                    size: volume.size,
+                };
+                RangeReader::new(fetchers, request)
+            })
+            .collect(),
+    );
+    let archive_size = size;
+    let members = match (archive, decompress) {
+        (_, None) => archive::list(archive, &mut reader),
+        (Archive::Tar, Some(Compression::Gzip)) => {
+            let index = &mut GzipIndex::default();
+            archive::list(
+                archive,
+                &mut GzipReader {
+                    inner: reader,
+                    index,
+                },
+            )
+        }
+        (_, Some(compression)) => {
+            return Err(format!(
+                "{} archives can't be read {} compressed",
+                archive, compression
+            )
+            .into())
+        }
+    }
+    .map_err(|e| format!("Listing {}: {}", url, e))?;
+    // Members have types of their own.
+    options.content_type = None;
+    let dir = |ino| {
+        Node::DirNode(DirNode {
+            attr: dir_attr(ino, &options),
+            contents: HashMap::new(),
+        })
+    };
+    let mut nodes = vec![dir(*inode)];
+    *inode += 1;
+    // Indices into `nodes` of the directories created so far, by path.
+    let mut dirs = HashMap::from([(Vec::new(), 0)]);
+    for member in members {
+        let mut parent = 0;
+        let dir_count = match member.kind {
+            MemberKind::Directory => member.path.len(),
+            MemberKind::File { .. } => member.path.len() - 1,
+        };
+        for depth in 1..=dir_count {
+            let path = &member.path[..depth];
+            parent = match dirs.get(path) {
+                Some(&index) => index,
+                None => {
+                    nodes.push(dir(*inode));
+                    let index = nodes.len() - 1;
+                    if let Node::DirNode(dir) = &mut nodes[parent] {
+                        dir.contents
+                            .insert(OsString::from(&path[depth - 1]), *inode);
+                    }
+                    *inode += 1;
+                    dirs.insert(path.to_vec(), index);
+                    index
+                }
+            };
+        }
+        let MemberKind::File { size, data } = member.kind else {
+            continue;
+        };
+        let mut member_options = options.clone();
+        member_options.mode = options.mode.or(member.mode.map(|mode| mode & 0o555));
+        let (source, compression) = match data {
+            archive::Data::Range { start, len } if decompress.is_some() => (
+                Source::GzipRange {
+                    url: url.clone(),
+                    archive_size,
+                    start,
+                    len,
+                },
+                None,
+            ),
+            archive::Data::Range { start, len } => (spans_source(spans(volumes, start, len)), None),
+            archive::Data::Zip {
+                header,
+                compressed_size,
+                compression,
+            } => (
+                Source::Zip {
+                    url: url.clone(),
+                    header,
+                    compressed_size,
+                },
+                compression,
+            ),
+            archive::Data::Blocks(blocks) => (
+                Source::Blocks {
+                    url: url.clone(),
+                    blocks,
+                },
+                None,
+            ),
+            archive::Data::Folder { folder, offset } => (
+                Source::Folder {
+                    packed: spans(volumes, folder.start, folder.packed_len),
+                    folder,
+                    offset,
+                },
+                None,
+            ),
+        };
+        let mut node = file_node(*inode, size, member_options, source);
+        node.decompress = compression;
+        nodes.push(Node::FileNode(Box::new(node)));
+        if let Node::DirNode(dir) = &mut nodes[parent] {
+            let name = member.path.last().unwrap();
+            dir.contents.insert(OsString::from(name), *inode);
+        }
+        *inode += 1;
+    }
+    Ok(nodes)
+}
+
+/// Where `len` bytes from `start` of the concatenation of `volumes` are.
+fn spans(volumes: &[Segment], start: u64, len: u64) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut volume_start = 0;
+    for volume in volumes {
+        let volume_end = volume_start + volume.size;
+        let (from, to) = (start.max(volume_start), (start + len).min(volume_end));
+        if from < to {
+            spans.push(Span {
+                url: volume.url.clone(),
+                start: from - volume_start,
+                len: to.saturating_sub(from),
+            });
+        }
+        volume_start = volume_end;
+    }
+    spans
+}
+
+/// A range that may cross from one volume into the next.
+fn spans_source(mut spans: Vec<Span>) -> Source {
+    match spans.len() {
+        0 => Source::Inline(Vec::new()),
+        1 => {
+            let Span { url, start, len } = spans.pop().unwrap();
+            Source::Range { url, start, len }
+        }
+        _ => Source::Spans(spans),
+    }
+}
+
+#[derive(Debug)]
+pub struct BadSlice {
+    name: String,
+    reason: String,
+}
+
+impl Display for BadSlice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Slice {}: {}", self.name, self.reason)
+    }
+}
+
+impl Error for BadSlice {}
+
+/// Points each slice at the bytes of the file it names. Only files read
+/// as they are stored can be sliced, and not other slices.
+fn resolve_slices(nodes: &mut [Node], slices: &[(u64, &SliceFile)]) -> Result<(), Box<dyn Error>> {
+    let slice_inodes = slices.iter().map(|(ino, _)| *ino).collect::<Vec<_>>();
+    for (ino, slice) in slices {
+        let bad = |reason: String| BadSlice {
+            name: slice.name.clone(),
+            reason,
+        };
+        let mut target = 1;
+        for part in slice.slice_of.split('/').filter(|part| !part.is_empty()) {
+            target = match node(nodes, target) {
+                Some(Node::DirNode(dir)) => dir.contents.get(OsStr::new(part)).copied(),
+                _ => None,
+            }
+            .ok_or_else(|| bad(format!("{} isn't in the layout", slice.slice_of)))?;
+        }
+        let Some(Node::FileNode(file)) = node(nodes, target) else {
+            return Err(Box::new(bad(format!("{} isn't a file", slice.slice_of))));
+        };
+        let (offset, len) = (slice.offset, slice.size);
+        if slice_inodes.contains(&target) {
+            return Err(Box::new(bad(format!("{} is a slice too", slice.slice_of))));
+        }
+        if offset + len > file.attr.size {
+            return Err(Box::new(bad(format!(
+                "{} is only {} bytes",
+                slice.slice_of, file.attr.size
+            ))));
+        }
+        let source = match &file.source {
+            Source::Url(url)
+                if file.decompress.is_none()
+                    && file.decrypt.is_none()
+                    && file.filter.is_none()
+                    && file.pieces.is_none() =>
+            {
+                Source::Range {
+                    url: url.clone(),
+                    start: offset,
+                    len,
+                }
+            }
+            Source::Range { url, start, .. } => Source::Range {
+                url: url.clone(),
+                start: start + offset,
+                len,
+            },
+            Source::Inline(data) => {
+                Source::Inline(data[offset as usize..(offset + len) as usize].to_vec())
+            }
+            source => return Err(Box::new(bad(format!("{} can't be sliced", source)))),
+        };
+        let (headers, auth) = (file.headers.clone(), file.auth.clone());
+        if let Some(Node::FileNode(file)) = nodes.get_mut(*ino as usize - 1) {
+            file.source = source;
+            file.headers = headers;
+            file.auth = auth;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a file's `url` against the base URL of its directory. Without a
+/// base the URL is passed through untouched.
+fn resolve_url(base: Option<&Url>, url: &str) -> Result<String, url::ParseError> {
+    match base {
+        Some(base) => Ok(base.join(url)?.into()),
+        None => Ok(url.into()),
+    }
+}
+
+/// Parses a directory's `base_url`. A trailing slash is implied, so that
+/// `https://mirror/pub` + `file` gives `https://mirror/pub/file` rather than
+/// replacing the last path segment.
+fn base_directory(base: Option<&Url>, url: &str) -> Result<Url, url::ParseError> {
+    let mut url = match base {
+        Some(base) => base.join(url)?,
+        None => Url::parse(url)?,
+    };
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Node {
+    DirNode(DirNode),
+    FileNode(Box<FileNode>),
+}
+
+impl Node {
+    /// Whether the file's size is only known once it was read, as for
+    /// decompressed or filtered files without a size in the layout.
+    fn size_unknown(&self) -> bool {
+        match self {
+            Node::FileNode(file) => {
+                matches!(
+                    file.source,
+                    Source::Manifest | Source::Control(ControlFile::Stats | ControlFile::Health)
+                ) || (file.decompress.is_some() || file.filter.is_some())
+                    && file.attr.size == 0
+                    && !matches!(file.source, Source::Zip { .. })
+                    || self.unprobed()
+            }
+            Node::DirNode(_) => false,
+        }
+    }
+
+    /// Whether the file is read as a whole URL the layout gave no size,
+    /// which [`LazyHTTPFS::probe_sizes`] asks its backend for. Empty files
+    /// are asked again on every open.
+    fn unprobed(&self) -> bool {
+        matches!(self, Node::FileNode(file)
+            if matches!(file.source, Source::Url(_))
+                && file.attr.size == 0
+                && file.decompress.is_none()
+                && file.filter.is_none()
+                && file.decrypt.is_none())
+    }
+
+    /// How long the kernel may keep the node's attributes, at most `ttl`.
+    fn attr_ttl(&self, ttl: Duration) -> Duration {
+        match self.size_unknown() {
+            true => Duration::ZERO,
+            false => ttl,
+        }
+    }
+
+    fn get_attr(&self) -> FileAttr {
+        match self {
+            Node::DirNode(dir_node) => dir_node.attr,
+            Node::FileNode(file_node) => file_node.attr,
+        }
+    }
+
+    fn filetype(&self) -> FileType {
+        match self {
+            Node::DirNode(_) => FileType::Directory,
+            Node::FileNode(file) => file.attr.kind,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Serialize, Deserialize)]
+struct DirNode {
+    #[serde(with = "attr")]
+    attr: FileAttr,
+    contents: HashMap<OsString, u64>,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileNode {
+    #[serde(with = "attr")]
+    attr: FileAttr,
+    source: Source,
+    headers: BTreeMap<String, String>,
+    auth: Option<Auth>,
+    cache: CachePolicy,
+    content_type: Option<String>,
+    /// How long cached bytes are served before fetching them again, `None`
+    /// for as long as they stay cached.
+    ttl: Option<Duration>,
+    /// Alternative URLs for the same bytes, tried in order when `source`
+    /// can't be fetched.
+    mirrors: Vec<String>,
+    /// Torrent piece hashes; with these, `source` and `mirrors` are web
+    /// seeds read from together.
+    pieces: Option<Box<Pieces>>,
+    decompress: Option<Compression>,
+    compressed_size: Option<u64>,
+    decrypt: Option<Box<Encryption>>,
+    /// A command piped the whole file, after `decrypt` and `decompress`.
+    filter: Option<String>,
+    /// Hex digests from the layout, for the manifest.
+    sha256: Option<String>,
+    md5: Option<String>,
+    /// Exempt from [`Limits::require_https`](crate::layout::Limits).
+    #[serde(default)]
+    allow_http: bool,
+    #[serde(default)]
+    priority: Priority,
+}
+
+/// The parts of a [`FileAttr`] that differ between nodes, for compiled
+/// layouts. The rest comes from [`DEFAULT_ATTR`].
+mod attr {
+    use std::time::UNIX_EPOCH;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{dated, FileAttr, FileType, DEFAULT_ATTR};
+
+    #[derive(Serialize, Deserialize)]
+    struct Attr {
+        ino: u64,
+        size: u64,
+        directory: bool,
+        link: bool,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        /// Seconds since the epoch, 0 if the layout gave none.
+        mtime: u64,
+    }
+
+    pub fn serialize<S: Serializer>(attr: &FileAttr, s: S) -> Result<S::Ok, S::Error> {
+        Attr {
+            ino: attr.ino,
+            size: attr.size,
+            directory: attr.kind == FileType::Directory,
+            link: attr.kind == FileType::Symlink,
+            perm: attr.perm,
+            uid: attr.uid,
+            gid: attr.gid,
+            mtime: (attr.mtime.duration_since(UNIX_EPOCH)).map_or(0, |since| since.as_secs()),
+        }
+        .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<FileAttr, D::Error> {
+        let attr = Attr::deserialize(d)?;
+        let mtime = (attr.mtime != 0).then_some(attr.mtime);
+        Ok(dated(
+            FileAttr {
+                ino: attr.ino,
+                size: attr.size,
+                blocks: attr.size / 512,
+                kind: match (attr.directory, attr.link) {
+                    (true, _) => FileType::Directory,
+                    (false, true) => FileType::Symlink,
+                    (false, false) => FileType::RegularFile,
+                },
+                perm: attr.perm,
+                uid: attr.uid,
+                gid: attr.gid,
+                ..DEFAULT_ATTR
+            },
+            mtime,
+        ))
+    }
+}
+
+/// Where the bytes of a file come from.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Source {
+    Url(String),
+    Inline(Vec<u8>),
+    Concat(Vec<Segment>),
+    /// `len` bytes of `url` starting at `start`.
+    Range {
+        url: String,
+        start: u64,
+        len: u64,
+    },
+    /// A zip member, whose local header at `header` in `url` says where
+    /// its `compressed_size` bytes of data start.
+    Zip {
+        url: String,
+        header: u64,
+        compressed_size: u64,
+    },
+    /// `len` bytes from `start` of the decompressed gzip stream at `url`,
+    /// which is `archive_size` bytes long.
+    GzipRange {
+        url: String,
+        archive_size: u64,
+        start: u64,
+        len: u64,
+    },
+    /// A file of a SquashFS image at `url`.
+    Blocks {
+        url: String,
+        blocks: Box<Blocks>,
+    },
+    /// A range of a split archive that crosses volumes, one span of each.
+    Spans(Vec<Span>),
+    /// The manifest at the root, made afresh each time it is opened.
+    Manifest,
+    /// A file of the `.lhttpfs/` control directory.
+    Control(ControlFile),
+    /// A member `offset` bytes into a 7z folder, which is packed in
+    /// `packed` and decoded as a whole.
+    Folder {
+        packed: Vec<Span>,
+        folder: Box<Folder>,
+        offset: u64,
+    },
+    /// A symbolic link, to this path.
+    Link(String),
+}
+
+/// `len` bytes of `url` starting at `start`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Span {
+    url: String,
+    start: u64,
+    len: u64,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Url(url) => write!(f, "{}", url),
+            Source::Inline(data) => write!(f, "<inline, {} bytes>", data.len()),
+            Source::Link(target) => write!(f, "{}", target),
+            Source::Concat(segments) => match segments.first() {
+                Some(first) => write!(f, "{} (+{} segments)", first.url, segments.len() - 1),
+                None => write!(f, "<empty concatenation>"),
+            },
+            Source::Range { url, start, len } => {
+                write!(f, "{} (bytes {}-{})", url, start, start + len)
+            }
+            Source::Zip { url, header, .. } => {
+                write!(f, "{} (zip member at byte {})", url, header)
+            }
+            Source::GzipRange {
+                url, start, len, ..
+            } => {
+                write!(f, "{} (decompressed bytes {}-{})", url, start, start + len)
+            }
+            Source::Blocks { url, blocks } => {
+                write!(f, "{} ({} blocks)", url, blocks.blocks.len())
+            }
+            Source::Manifest => write!(f, "<manifest>"),
+            Source::Control(file) => write!(f, "<control: {:?}>", file),
+            Source::Spans(spans) | Source::Folder { packed: spans, .. } => {
+                let first = &spans[0];
+                write!(
+                    f,
+                    "{} (bytes {}-{}, +{} volumes)",
+                    first.url,
+                    first.start,
+                    first.start + first.len,
+                    spans.len() - 1
+                )
+            }
+        }
+    }
+}
+
+impl FileNode {
+    /// How the file's bytes are cached when they aren't transformed.
+    fn policy(&self) -> Policy {
+        Policy {
+            kind: self.cache,
+            ttl: self.ttl,
+        }
+    }
+
+    /// What a fetcher needs to read `url` on behalf of this file.
+    fn request<'a>(&'a self, url: &'a str) -> Request<'a> {
+        Request {
+            url,
+            headers: &self.headers,
+            auth: self.auth.as_ref(),
+            size: self.attr.size,
+        }
+    }
+}
+
+impl Debug for FileNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ino {}, source: {}, size: {}",
+            self.attr.ino, self.source, self.attr.size
+        )
+    }
+}
+
+impl Debug for DirNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ino {}, contents {:?}", self.attr.ino, self.contents)
+    }
+}
+
+const TTL: Duration = Duration::from_secs(1000000);
+
+impl LazyHTTPFS {
+    fn get_inode(&self, i: u64) -> Option<&Node> {
+        node(&self.nodes, i)
+    }
+}
+
+fn node(nodes: &[Node], i: u64) -> Option<&Node> {
+    if i == 0 {
+        None
+    } else {
+        nodes.get(i as usize - 1)
+    }
+}
+
+/// How much [`LazyHTTPFS::prefetch`] reads at once.
+const PREFETCH_CHUNK: u32 = 64 << 20;
+
+impl LazyHTTPFS {
+    /// Reads all of file `ino` so it is in the cache directory, returning
+    /// its size, or `None` if it isn't a `disk` cached file, since nothing
+    /// else outlives the process.
+    pub fn prefetch(&mut self, ino: u64) -> Result<Option<u64>, LhttpfsError> {
+        match self.get_inode(ino) {
+            Some(Node::FileNode(file)) if file.cache == CachePolicy::Disk => {}
+            _ => return Ok(None),
+        }
+        let mut offset = 0;
+        loop {
+            let Some(data) = self.read_data(ino, offset as i64, PREFETCH_CHUNK)? else {
+                return Ok(None);
+            };
+            offset += data.len() as u64;
+            if data.len() < PREFETCH_CHUNK as usize {
+                return Ok(Some(offset));
+            }
+        }
+    }
+
+    /// Reads `size` bytes of file `ino` from `offset`, `None` if there's no
+    /// such file.
+    fn read_data(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<Option<Vec<u8>>, LhttpfsError> {
+        let cache = self.cache.clone();
+        let mut cache = cache.lock().unwrap();
+        self.read_cached(&mut cache, ino, offset, size)
+    }
+
+    /// [`LazyHTTPFS::read_data`] with the cache already locked.
+    fn read_cached(
+        &mut self,
+        cache: &mut Cache,
+        ino: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<Option<Vec<u8>>, LhttpfsError> {
+        let Some(Node::FileNode(file)) = node(&self.nodes, ino) else {
+            return Ok(None);
+        };
+        let mut learned_size = None;
+        if let (Source::Url(url), Some(Compression::Zstd), Some(compressed_size), None) = (
+            &file.source,
+            file.decompress,
+            file.compressed_size,
+            &file.filter,
+        ) {
+            if !self.seek_tables.contains_key(&ino) {
+                let table = seek_table(cache, &self.fetchers, file, url, compressed_size)?;
+                self.seek_tables.insert(ino, table);
+            }
+            if let Some(Some(table)) = self.seek_tables.get(&ino) {
+                let sizes = table.frames().iter().map(|frame| frame.size);
+                let mut out = Vec::with_capacity(size as usize);
+                for (i, from, len) in split_read(sizes, offset, size) {
+                    let frame = table.frames()[i];
+                    let range = (frame.compressed_offset, frame.compressed_size);
+                    let data = fetch(cache, &self.fetchers, file, url, &[], Some(range))?;
+                    let data = (Compression::Zstd.decompress(&data)).map_err(failed(url))?;
+                    out.extend_from_slice(slice(&data, from as i64, len as u32));
+                }
+                if file.attr.size == 0 {
+                    learned_size = Some(table.size());
+                }
+                learn_size(&mut self.nodes, ino, learned_size);
+                return Ok(Some(out));
+            }
+        }
+        let data = match &file.source {
+            Source::Url(url) => {
+                let start = offset.max(0) as u64;
+                let mut out = Vec::new();
+                for (range, from, len) in
+                    part_reads(self.blockwise, file, file.attr.size, start, size as u64)
+                {
+                    let data = fetch(cache, &self.fetchers, file, url, &file.mirrors, range)?;
+                    let at = range.map_or(0, |(start, _)| start);
+                    let whole = range.is_none();
+                    if let Err(e) = verify::check(&mut self.verifying, ino, file, at, &data, whole)
+                    {
+                        // So that the next read fetches it anew.
+                        for (key, policy) in self.cache_keys(ino, file) {
+                            cache.remove(&key, policy)?;
+                        }
+                        return Err(LhttpfsError::fetch(url, Box::new(e)));
+                    }
+                    let transformed = file.decompress.is_some() || file.filter.is_some();
+                    if transformed && file.attr.size == 0 {
+                        learned_size = Some(data.len() as u64);
+                    }
+                    out.extend_from_slice(slice(&data, from as i64, len as u32));
+                }
+                out
+            }
+            Source::Inline(data) => slice(data, offset, size).to_vec(),
+            Source::Manifest => slice(&self.manifest, offset, size).to_vec(),
+            Source::Control(ControlFile::Stats) => {
+                slice(&self.control.stats, offset, size).to_vec()
+            }
+            Source::Control(ControlFile::Health) => {
+                slice(&self.control.health, offset, size).to_vec()
+            }
+            Source::Control(_) | Source::Link(_) => Vec::new(),
+            Source::Concat(segments) => {
+                let sizes = segments.iter().map(|s| s.size);
+                let mut out = Vec::with_capacity(size as usize);
+                for (i, from, len) in split_read(sizes, offset, size) {
+                    let segment = &segments[i];
+                    for (range, from, len) in
+                        part_reads(self.blockwise, file, segment.size, from, len)
+                    {
+                        let data = fetch(cache, &self.fetchers, file, &segment.url, &[], range)?;
+                        out.extend_from_slice(slice(&data, from as i64, len as u32));
+                    }
+                }
+                out
+            }
+            Source::Range { url, start, len } => {
+                let data = fetch(cache, &self.fetchers, file, url, &[], Some((*start, *len)))?;
+                slice(&data, offset, size).to_vec()
+            }
+            Source::Zip {
+                url,
+                header,
+                compressed_size,
+            } => {
+                let start = match self.zip_starts.get(&ino) {
+                    Some(&start) => start,
+                    None => {
+                        let start = zip_data_start(&self.fetchers, file, url, *header)?;
+                        self.zip_starts.insert(ino, start);
+                        start
+                    }
+                };
+                let data = fetch(
+                    cache,
+                    &self.fetchers,
+                    file,
+                    url,
+                    &[],
+                    Some((start, *compressed_size)),
+                )?;
+                slice(&data, offset, size).to_vec()
+            }
+            Source::GzipRange {
+                url,
+                archive_size,
+                start,
+                len,
+            } => {
+                let index = self.gzip_indexes.entry(url.clone()).or_default();
+                let request = Request {
+                    size: *archive_size,
+                    ..file.request(url)
+                };
+                let range = (*start, *len);
+                let data = fetch_gzip(cache, &self.fetchers, index, file, request, range)?;
+                slice(&data, offset, size).to_vec()
+            }
+            Source::Blocks { url, blocks } => {
+                let mut out = Vec::with_capacity(size as usize);
+                for (i, from, len) in split_read(blocks.part_sizes(file.attr.size), offset, size) {
+                    let data = fetch_block(cache, &self.fetchers, file, url, blocks, i)?;
+                    out.extend_from_slice(slice(&data, from as i64, len as u32));
+                }
+                out
+            }
+            Source::Spans(spans) => {
+                let mut out = Vec::with_capacity(size as usize);
+                for (i, from, len) in split_read(spans.iter().map(|s| s.len), offset, size) {
+                    let span = &spans[i];
+                    let range = Some((span.start, span.len));
+                    let data = fetch(cache, &self.fetchers, file, &span.url, &[], range)?;
+                    out.extend_from_slice(slice(&data, from as i64, len as u32));
+                }
+                out
+            }
+            Source::Folder {
+                packed,
+                folder,
+                offset: at,
+            } => {
+                let data = fetch_folder(cache, &self.fetchers, file, packed, folder)?;
+                let start = fetch::clamped(*at, data.len());
+                let end = start + fetch::clamped(file.attr.size, data.len() - start);
+                slice(&data[start..end], offset, size).to_vec()
+            }
+        };
+        learn_size(&mut self.nodes, ino, learned_size);
+        Ok(Some(data))
+    }
+}
+
+/// Records the size of a file that was only learned by reading it.
+fn learn_size(nodes: &mut [Node], ino: u64, size: Option<u64>) {
+    if let (Some(size), Some(Node::FileNode(file))) = (size, nodes.get_mut(ino as usize - 1)) {
+        file.attr.size = size;
+        file.attr.blocks = size.div_ceil(512);
+    }
+}
+
+/// Dates file `ino` `modified`, as its server said, unless the layout gave
+/// it a time of its own.
+fn learn_modified(nodes: &mut [Node], ino: u64, modified: Option<SystemTime>) {
+    if let (Some(modified), Some(Node::FileNode(file))) =
+        (modified, nodes.get_mut(ino as usize - 1))
+    {
+        if file.attr.mtime == UNIX_EPOCH {
+            let secs = modified.duration_since(UNIX_EPOCH).ok();
+            file.attr = dated(file.attr, secs.map(|since| since.as_secs()));
+        }
+    }
+}
+
+/// Reads the seek table at the end of a seekable zstd file that is
+/// `compressed_size` bytes long, if it has one.
+fn seek_table(
+    cache: &mut Cache,
+    fetchers: &Fetchers,
+    file: &FileNode,
+    url: &str,
+    compressed_size: u64,
+) -> Result<Option<SeekTable>, LhttpfsError> {
+    let Some(footer_at) = compressed_size.checked_sub(SEEK_FOOTER_LEN) else {
+        return Ok(None);
+    };
+    let range = (footer_at, SEEK_FOOTER_LEN);
+    let footer = fetch(cache, fetchers, file, url, &[], Some(range))?;
+    let Some(len) = SeekTable::len_from_footer(&footer) else {
+        return Ok(None);
+    };
+    let Some(table_at) = compressed_size.checked_sub(len) else {
+        return Ok(None);
+    };
+    let table = fetch(cache, fetchers, file, url, &[], Some((table_at, len)))?;
+    Ok(SeekTable::parse(&table))
+}
+
+/// Reads the local header of the zip member at `header` for where its data
+/// starts. It is small and read once, so it bypasses the cache.
+fn zip_data_start(
+    fetchers: &Fetchers,
+    file: &FileNode,
+    url: &str,
+    header: u64,
+) -> Result<u64, LhttpfsError> {
+    let range = (header, archive::ZIP_LOCAL_HEADER_LEN);
+    let local = fetchers.fetch_range(&file.request(url), Some(range))?;
+    archive::zip_data_start(header, &local).map_err(failed(url))
+}
+
+/// Fails a read of `url` with `error`, for bytes that arrived but can't be
+/// served, as those that don't decompress.
+fn failed<E: Into<Box<dyn Error>>>(url: &str) -> impl FnOnce(E) -> LhttpfsError + '_ {
+    move |error| LhttpfsError::fetch(url, error.into())
+}
+
+/// Parts of files that are served as fetched are fetched `size` bytes at a
+/// time rather than whole.
+#[derive(Debug, Clone, Copy)]
+struct Blockwise {
+    size: u64,
+}
+
+const BLOCKWISE: Blockwise = Blockwise { size: 8 << 20 };
+
+/// A range to fetch, `None` for all of it, then the offset and length of
+/// what is read within it.
+type PartRead = (Option<(u64, u64)>, u64, u64);
+
+/// How to read `len` bytes from `start` of a part of `file` `size` bytes
+/// long: the blocks the read falls in, so that no read fetches more of a
+/// file than it needs, or all of it when it must be transformed as a whole
+/// or its size isn't known.
+fn part_reads(
+    blockwise: Blockwise,
+    file: &FileNode,
+    size: u64,
+    start: u64,
+    len: u64,
+) -> Vec<PartRead> {
+    let served_as_fetched = file.decompress.is_none()
+        && file.decrypt.is_none()
+        && file.filter.is_none()
+        && file.pieces.is_none();
+    if size == 0 || !served_as_fetched {
+        return vec![(None, start, len)];
+    }
+    let end = start.saturating_add(len).min(size);
+    if start >= end {
+        return Vec::new();
+    }
+    let block_size = blockwise.size;
+    (start / block_size..=(end - 1) / block_size)
+        .map(|i| {
+            let block = i * block_size;
+            let from = start.max(block) - block;
+            let range = (block, block_size.min(size - block));
+            (
+                Some(range),
+                from,
+                end.min(block + block_size) - block - from,
+            )
+        })
+        .collect()
+}
+
+/// Splits a read of `size` bytes at `offset` across consecutive parts with
+/// the given sizes, as `(part index, offset within part, length)`.
+fn split_read(sizes: impl Iterator<Item = u64>, offset: i64, size: u32) -> Vec<(usize, u64, u64)> {
+    let start = offset.max(0) as u64;
+    let end = start + size as u64;
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+    for (i, part_size) in sizes.enumerate() {
+        let part_end = part_start + part_size;
+        if part_end > start && part_start < end {
+            let from = start.saturating_sub(part_start);
+            parts.push((i, from, end.min(part_end) - part_start - from));
+        }
+        part_start = part_end;
+    }
+    parts
+}
+
+/// Returns the body of `url`, or `len` bytes of it from `start` when a range
+/// is given, downloading it unless it is already cached.
+fn fetch<'a>(
+    cache: &'a mut Cache,
+    fetchers: &Fetchers,
+    file: &FileNode,
+    url: &str,
+    mirrors: &[String],
+    range: Option<(u64, u64)>,
+) -> Result<Cow<'a, [u8]>, LhttpfsError> {
+    let (key, policy) = cache_entry(file, url, range);
+    let span = info_span!(
+        "cache",
+        otel.kind = "internal",
+        lhttpfs.cache.key = key.as_str(),
+        lhttpfs.cache = Empty,
+        lhttpfs.retries = Empty,
+    )
+    .entered();
+    match cache.lookup(&key, policy) {
+        Some(Hit::Disk(data)) => {
+            span.record("lhttpfs.cache", "disk");
+            return Ok(Cow::Owned(data));
+        }
+        Some(Hit::Held(data)) => {
+            span.record("lhttpfs.cache", "miss");
+            return Ok(Cow::Owned(data));
+        }
+        Some(Hit::Memory) => {
+            span.record("lhttpfs.cache", "memory");
+            return Ok(Cow::Borrowed(cache.memory(&key)));
+        }
+        None => span.record("lhttpfs.cache", "miss"),
+    };
+    let stale = cache.stale(&key, policy);
+    match fetch_fresh(fetchers, file, url, mirrors, range, stale.as_ref())? {
+        Fresh::Fetched(data, validators) => {
+            Ok(cache.insert_validated(key, data, policy, validators))
+        }
+        Fresh::Unchanged => match cache.revalidated(&key) {
+            Some(data) => Ok(Cow::Owned(data)),
+            // Gone in the meantime, so fetched as if never cached.
+            None => fetch(cache, fetchers, file, url, mirrors, range),
+        },
+        Fresh::Unreachable(e) => {
+            warn!("Serving a stale copy of {}: {}", url, e);
+            cache.stale_data(&key).map(Cow::Owned).ok_or(e)
+        }
+    }
+}
+
+/// What fetching anew says of a copy, if there is a stale one.
+enum Fresh {
+    /// The bytes, changed or fetched for the first time, with what
+    /// identifies their version.
+    Fetched(Vec<u8>, Option<Validators>),
+    /// The stale copy is still current.
+    Unchanged,
+    /// The origin couldn't be asked, so the stale copy is all there is.
+    Unreachable(LhttpfsError),
+}
+
+/// [`fetch_origin`], keeping the [`Validators`] the origin answered with,
+/// unless `stale`, those of a stale copy, show the copy is still current.
+fn fetch_fresh(
+    fetchers: &Fetchers,
+    file: &FileNode,
+    url: &str,
+    mirrors: &[String],
+    range: Option<(u64, u64)>,
+    stale: Option<&Validators>,
+) -> Result<Fresh, LhttpfsError> {
+    if let Some(stale) = stale {
+        let headers = stale.conditional(&file.headers);
+        let request = Request {
+            headers: &headers,
+            ..file.request(url)
+        };
+        match fetchers.fetch_validated(&request, range) {
+            Ok(Fetched { data, validators }) => {
+                let data = match whole(file, range) {
+                    true => transform(file, url, data)?,
+                    false => data,
+                };
+                return Ok(Fresh::Fetched(data, validators));
+            }
+            Err(e) if NotModified::answered(&e) => return Ok(Fresh::Unchanged),
+            Err(e) => warn!("Revalidating {} failed: {}", url, e),
+        }
+    }
+    match fetch_origin(fetchers, file, url, mirrors, range) {
+        Ok(Fetched { data, validators }) => Ok(Fresh::Fetched(data, validators)),
+        Err(e) if stale.is_some() => Ok(Fresh::Unreachable(e)),
+        Err(e) => Err(e),
+    }
+}
+
+/// What [`fetch`] caches on a miss: `range` of `url`, or of the first of
+/// `mirrors` that answers, transformed if it is the whole file.
+fn fetch_origin(
+    fetchers: &Fetchers,
+    file: &FileNode,
+    url: &str,
+    mirrors: &[String],
+    range: Option<(u64, u64)>,
+) -> Result<Fetched, LhttpfsError> {
+    let fetched = match (&file.pieces, range) {
+        (Some(pieces), None) => {
+            let seeds = std::iter::once(url)
+                .chain(mirrors.iter().map(String::as_str))
+                .map(|seed| file.request(seed))
+                .collect::<Vec<_>>();
+            // Those of whichever piece came last wouldn't be of the whole.
+            Fetched::from(
+                fetch::fetch_pieces(fetchers, &seeds, pieces)
+                    .map_err(|e| LhttpfsError::fetch(url, e))?,
+            )
+        }
+        _ => {
+            let mut result = fetchers.fetch_validated(&file.request(url), range);
+            for (retries, mirror) in mirrors.iter().enumerate() {
+                let Err(e) = &result else {
+                    break;
+                };
+                warn!("{}, trying mirror {}", e, mirror);
+                tracing::Span::current().record("lhttpfs.retries", retries + 1);
+                result = fetchers.fetch_validated(&file.request(mirror), range);
+            }
+            result?
+        }
+    };
+    match whole(file, range) {
+        true => Ok(Fetched {
+            data: transform(file, url, fetched.data)?,
+            ..fetched
+        }),
+        false => Ok(fetched),
+    }
+}
+
+/// What reading a file would fetch and the cache can't answer, for
+/// [`Miss::warm`] to fetch without the filesystem locked.
+pub(super) struct Miss(Wanted);
+
+enum Wanted {
+    /// A URL, or a range of it, to be cached as [`fetch`] would.
+    Fetch {
+        file: FileNode,
+        url: String,
+        mirrors: Vec<String>,
+        range: Option<(u64, u64)>,
+    },
+    /// The seek table of a seekable zstd file, after which its frames are
+    /// known.
+    SeekTable {
+        ino: u64,
+        file: FileNode,
+        url: String,
+        compressed_size: u64,
+    },
+    /// Where the data of a zip member starts, after which it is known.
+    ZipStart {
+        ino: u64,
+        file: FileNode,
+        url: String,
+        header: u64,
+    },
+    /// `len` bytes from `start` of a decompressed gzip stream.
+    Gzip {
+        file: FileNode,
+        url: String,
+        archive_size: u64,
+        range: (u64, u64),
+    },
+    /// A 7z folder, decoded as a whole.
+    Folder {
+        file: FileNode,
+        packed: Vec<Span>,
+        folder: Folder,
+    },
+}
+
+impl Miss {
+    /// The priority of the file the bytes are for.
+    #[cfg(feature = "fuse")]
+    pub(super) fn priority(&self) -> Priority {
+        match &self.0 {
+            Wanted::Fetch { file, .. }
+            | Wanted::SeekTable { file, .. }
+            | Wanted::ZipStart { file, .. }
+            | Wanted::Gzip { file, .. }
+            | Wanted::Folder { file, .. } => file.priority,
+        }
+    }
+
+    /// The cache entry the bytes go in, if they are cached at all.
+    fn entry(&self) -> Option<(String, Policy)> {
+        match &self.0 {
+            Wanted::Fetch {
+                file, url, range, ..
+            } => Some(cache_entry(file, url, *range)),
+            Wanted::SeekTable { .. } | Wanted::ZipStart { .. } => None,
+            Wanted::Gzip {
+                file,
+                url,
+                range: (start, len),
+                ..
+            } => Some((gzip_key(url, *start, *len), file.policy())),
+            Wanted::Folder {
+                file,
+                packed,
+                folder,
+            } => Some((folder_key(packed, folder), file.policy())),
+        }
+    }
+
+    /// Fetches the bytes into the cache of `fs`, or what they tell into
+    /// `fs` itself, locking either only to put them there. Bytes that
+    /// aren't to be cached are held for the read, under the key returned.
+    pub(super) fn warm(self, fs: &Mutex<LazyHTTPFS>) -> Result<Option<String>, LhttpfsError> {
+        let (cache, fetchers) = {
+            let fs = fs.lock().unwrap_or_else(PoisonError::into_inner);
+            (fs.cache.clone(), fs.fetchers.clone())
+        };
+        let entry = self.entry();
+        let mut validators = None;
+        let data = match self.0 {
+            Wanted::Fetch {
+                file,
+                url,
+                mirrors,
+                range,
+            } => {
+                let stale = entry.as_ref().and_then(|(key, policy)| {
+                    let cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+                    Some((key.clone(), cache.stale(key, *policy)?))
+                });
+                let revalidating = stale.as_ref().map(|(_, validators)| validators);
+                match (
+                    fetch_fresh(&fetchers, &file, &url, &mirrors, range, revalidating)?,
+                    stale,
+                ) {
+                    (Fresh::Fetched(data, fetched), _) => {
+                        validators = fetched;
+                        data
+                    }
+                    // Held for the read as well, as it may be stale again by
+                    // then.
+                    (Fresh::Unchanged, Some((key, _))) => {
+                        let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+                        let Some(data) = cache.revalidated(&key) else {
+                            return Ok(None);
+                        };
+                        cache.hold(key.clone(), data);
+                        return Ok(Some(key));
+                    }
+                    (Fresh::Unreachable(e), Some((key, _))) => {
+                        warn!("Serving a stale copy of {}: {}", url, e);
+                        let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+                        let data = cache.stale_data(&key).ok_or(e)?;
+                        cache.hold(key.clone(), data);
+                        return Ok(Some(key));
+                    }
+                    (_, None) => unreachable!("Only a stale copy is revalidated"),
+                }
+            }
+            Wanted::SeekTable {
+                ino,
+                file,
+                url,
+                compressed_size,
+            } => {
+                // The footer and table are only read once, into the table.
+                let mut scratch = Cache::new(None);
+                let table = seek_table(&mut scratch, &fetchers, &file, &url, compressed_size)?;
+                let mut fs = fs.lock().unwrap_or_else(PoisonError::into_inner);
+                if fs.same_file(ino, &file) {
+                    fs.seek_tables.insert(ino, table);
+                }
+                return Ok(None);
+            }
+            Wanted::ZipStart {
+                ino,
+                file,
+                url,
+                header,
+            } => {
+                let start = zip_data_start(&fetchers, &file, &url, header)?;
+                let mut fs = fs.lock().unwrap_or_else(PoisonError::into_inner);
+                if fs.same_file(ino, &file) {
+                    fs.zip_starts.insert(ino, start);
+                }
+                return Ok(None);
+            }
+            Wanted::Gzip {
+                file,
+                url,
+                archive_size,
+                range,
+            } => {
+                // Taken from the filesystem while in use; a read of the same
+                // stream in the meantime starts an index of its own.
+                let mut locked = fs.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut index = locked.gzip_indexes.remove(&url).unwrap_or_default();
+                drop(locked);
+                let request = Request {
+                    size: archive_size,
+                    ..file.request(&url)
+                };
+                let data = read_gzip(&fetchers, &mut index, request, range);
+                let mut fs = fs.lock().unwrap_or_else(PoisonError::into_inner);
+                fs.gzip_indexes.insert(url, index);
+                data?
+            }
+            Wanted::Folder {
+                file,
+                packed,
+                folder,
+            } => decode_folder(&fetchers, &file, &packed, &folder)?,
+        };
+        let Some((key, policy)) = entry else {
+            return Ok(None);
+        };
+        let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if policy.kind == CachePolicy::None {
+            cache.hold(key.clone(), data);
+            return Ok(Some(key));
+        }
+        cache.insert_validated(key, data, policy, validators);
+        Ok(None)
+    }
+}
+
+/// How many times a read asks what it is missing, each fetch telling what
+/// the next needs, as a zip member's header does where its data is.
+const ROUNDS: usize = 3;
+
+/// Locks `fs`, even if a read panicked holding it: every operation leaves
+/// it as it was before or after.
+fn lock(fs: &Mutex<LazyHTTPFS>) -> MutexGuard<'_, LazyHTTPFS> {
+    fs.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl LazyHTTPFS {
+    /// What reading `size` bytes of `ino` from `offset` would fetch that
+    /// isn't cached. Warming them can tell more, such as the frames of a
+    /// seekable file once its seek table is known, so a read asks again
+    /// until there are none.
+    pub(super) fn misses(&self, ino: u64, offset: i64, size: u32) -> Vec<Miss> {
+        let Some(Node::FileNode(file)) = node(&self.nodes, ino) else {
+            return Vec::new();
+        };
+        let fetch = |url: &String, mirrors: &[String], range| Wanted::Fetch {
+            file: (**file).clone(),
+            url: url.clone(),
+            mirrors: mirrors.to_vec(),
+            range,
+        };
+        let seekable = file.decompress == Some(Compression::Zstd) && file.filter.is_none();
+        let wanted: Vec<Wanted> = match &file.source {
+            Source::Url(url) if seekable && file.compressed_size.is_some() => {
+                match self.seek_tables.get(&ino) {
+                    None => vec![Wanted::SeekTable {
+                        ino,
+                        file: (**file).clone(),
+                        url: url.clone(),
+                        compressed_size: file.compressed_size.unwrap(),
+                    }],
+                    Some(None) => vec![fetch(url, &file.mirrors, None)],
+                    Some(Some(table)) => {
+                        let frames = table.frames();
+                        let sizes = frames.iter().map(|frame| frame.size);
+                        (split_read(sizes, offset, size).into_iter())
+                            .map(|(i, _, _)| frames[i])
+                            .map(|f| {
+                                fetch(url, &[], Some((f.compressed_offset, f.compressed_size)))
+                            })
+                            .collect()
+                    }
+                }
+            }
+            Source::Url(url) => {
+                let start = offset.max(0) as u64;
+                (part_reads(self.blockwise, file, file.attr.size, start, size as u64).into_iter())
+                    .map(|(range, _, _)| fetch(url, &file.mirrors, range))
+                    .collect()
+            }
+            Source::Range { url, start, len } => vec![fetch(url, &[], Some((*start, *len)))],
+            Source::Concat(segments) => {
+                let sizes = segments.iter().map(|s| s.size);
+                (split_read(sizes, offset, size).into_iter())
+                    .flat_map(|(i, from, len)| {
+                        let segment = &segments[i];
+                        (part_reads(self.blockwise, file, segment.size, from, len).into_iter())
+                            .map(|(range, _, _)| fetch(&segment.url, &[], range))
+                    })
+                    .collect()
+            }
+            Source::Spans(spans) => {
+                let sizes = spans.iter().map(|s| s.len);
+                (split_read(sizes, offset, size).into_iter())
+                    .map(|(i, _, _)| {
+                        fetch(&spans[i].url, &[], Some((spans[i].start, spans[i].len)))
+                    })
+                    .collect()
+            }
+            Source::Zip {
+                url,
+                header,
+                compressed_size,
+            } => match self.zip_starts.get(&ino) {
+                Some(&start) => vec![fetch(url, &[], Some((start, *compressed_size)))],
+                None => vec![Wanted::ZipStart {
+                    ino,
+                    file: (**file).clone(),
+                    url: url.clone(),
+                    header: *header,
+                }],
+            },
+            Source::GzipRange {
+                url,
+                archive_size,
+                start,
+                len,
+            } => vec![Wanted::Gzip {
+                file: (**file).clone(),
+                url: url.clone(),
+                archive_size: *archive_size,
+                range: (*start, *len),
+            }],
+            Source::Blocks { url, blocks } => {
+                let sizes = blocks.part_sizes(file.attr.size);
+                (split_read(sizes, offset, size).into_iter())
+                    .map(|(i, _, _)| blocks.part(i).0)
+                    .filter(|block| block.len > 0)
+                    .map(|block| fetch(url, &[], Some((block.start, block.len as u64))))
+                    .collect()
+            }
+            Source::Folder { packed, folder, .. } => vec![Wanted::Folder {
+                file: (**file).clone(),
+                packed: packed.clone(),
+                folder: (**folder).clone(),
+            }],
+            Source::Inline(_) | Source::Manifest | Source::Control(_) | Source::Link(_) => {
+                Vec::new()
+            }
+        };
+        let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        (wanted.into_iter().map(Miss))
+            .filter(|miss| match miss.entry() {
+                Some((key, policy)) => !cache.contains(&key, policy) && !cache.holds(&key),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Whether `ino` is still `file`, which a reload may have changed.
+    fn same_file(&self, ino: u64, file: &FileNode) -> bool {
+        matches!(node(&self.nodes, ino), Some(Node::FileNode(f)) if f.source == file.source)
+    }
+}
+
+/// Whether a fetch of `range` gets all of `file`. A zip member's data is
+/// the whole of the member, though only a range of the archive.
+fn whole(file: &FileNode, range: Option<(u64, u64)>) -> bool {
+    range.is_none() || matches!(file.source, Source::Zip { .. })
+}
+
+/// The key and policy `fetch` caches `url`, or a range of it, under for
+/// `file`. Whole files are cached as transformed, so the key says how.
+fn cache_entry(file: &FileNode, url: &str, range: Option<(u64, u64)>) -> (String, Policy) {
+    let whole = whole(file, range);
+    let key = match range {
+        Some((start, len)) => format!("{} bytes={}-{}", url, start, start + len),
+        None => url.to_string(),
+    };
+    let key = match (whole, file.decompress) {
+        (true, Some(compression)) => format!("{} {}", key, compression),
+        _ => key,
+    };
+    let key = match (whole, &file.decrypt) {
+        (true, Some(_)) => format!("{} decrypted", key),
+        _ => key,
+    };
+    let key = match (whole, &file.filter) {
+        (true, Some(command)) => format!("{} | {}", key, command),
+        _ => key,
+    };
+    let policy = Policy {
+        // Decrypted bytes are never written to disk.
+        kind: match (whole && file.decrypt.is_some(), file.cache) {
+            (true, CachePolicy::Disk) => CachePolicy::Memory,
+            (_, kind) => kind,
+        },
+        ttl: file.ttl,
+    };
+    (key, policy)
+}
+
+/// Returns part `i` of a SquashFS file, decompressed.
+fn fetch_block(
+    cache: &mut Cache,
+    fetchers: &Fetchers,
+    file: &FileNode,
+    url: &str,
+    blocks: &Blocks,
+    i: usize,
+) -> Result<Vec<u8>, LhttpfsError> {
+    let (block, at) = blocks.part(i);
+    if block.len == 0 {
+        return Ok(vec![0; blocks.block_size as usize]);
+    }
+    let range = (block.start, block.len as u64);
+    let data = fetch(cache, fetchers, file, url, &[], Some(range))?;
+    let data = match block.compressed {
+        true => Cow::Owned((blocks.compression.decompress(&data)).map_err(failed(url))?),
+        false => data,
+    };
+    Ok(data.get(at as usize..).unwrap_or_default().to_vec())
+}
+
+/// Returns `len` bytes from `start` of the decompressed gzip stream of
+/// `request`, decompressing it from the nearest point in `index`.
+fn fetch_gzip<'a>(
+    cache: &'a mut Cache,
+    fetchers: &Fetchers,
+    index: &mut GzipIndex,
+    file: &FileNode,
+    request: Request,
+    (start, len): (u64, u64),
+) -> Result<Cow<'a, [u8]>, LhttpfsError> {
+    let key = gzip_key(request.url, start, len);
+    let policy = file.policy();
+    match cache.lookup(&key, policy) {
+        Some(Hit::Disk(data) | Hit::Held(data)) => return Ok(Cow::Owned(data)),
+        Some(Hit::Memory) => return Ok(Cow::Borrowed(cache.memory(&key))),
+        None => {}
+    }
+    let data = read_gzip(fetchers, index, request, (start, len))?;
+    Ok(cache.insert(key, data, policy))
+}
+
+/// `len` bytes from `start` of the gzip stream `request` fetches,
+/// decompressed from the point of `index` before them.
+fn read_gzip(
+    fetchers: &Fetchers,
+    index: &mut GzipIndex,
+    request: Request,
+    (start, len): (u64, u64),
+) -> Result<Vec<u8>, LhttpfsError> {
+    let mut reader = RangeReader::new(fetchers, request);
+    (index.read(&mut reader, start, len)).map_err(failed(request.url))
+}
+
+fn gzip_key(url: &str, start: u64, len: u64) -> String {
+    format!("{} gzip bytes={}-{}", url, start, start + len)
+}
+
+fn folder_key(packed: &[Span], folder: &Folder) -> String {
+    let first = &packed[0];
+    let end = first.start + folder.packed_len;
+    format!("{} bytes={}-{} 7z", first.url, first.start, end)
+}
+
+/// Returns what the 7z folder in `packed` decodes to. It is cached as a
+/// whole, for all the members in it.
+fn fetch_folder<'a>(
+    cache: &'a mut Cache,
+    fetchers: &Fetchers,
+    file: &FileNode,
+    packed: &[Span],
+    folder: &Folder,
+) -> Result<Cow<'a, [u8]>, LhttpfsError> {
+    let key = folder_key(packed, folder);
+    let policy = file.policy();
+    match cache.lookup(&key, policy) {
+        Some(Hit::Disk(data) | Hit::Held(data)) => return Ok(Cow::Owned(data)),
+        Some(Hit::Memory) => return Ok(Cow::Borrowed(cache.memory(&key))),
+        None => {}
+    }
+    let decoded = decode_folder(fetchers, file, packed, folder)?;
+    Ok(cache.insert(key, decoded, policy))
+}
+
+/// Fetches the 7z folder in `packed` and decodes it.
+fn decode_folder(
+    fetchers: &Fetchers,
+    file: &FileNode,
+    packed: &[Span],
+    folder: &Folder,
+) -> Result<Vec<u8>, LhttpfsError> {
+    let mut data = Vec::with_capacity(folder.packed_len as usize);
+    for span in packed {
+        let range = Some((span.start, span.len));
+        data.extend(fetchers.fetch_range(&file.request(&span.url), range)?);
+    }
+    folder.decode(&data).map_err(failed(&packed[0].url))
+}
+
+/// Decrypts, decompresses, then filters the whole of `url` as fetched.
+fn transform(file: &FileNode, url: &str, data: Vec<u8>) -> Result<Vec<u8>, LhttpfsError> {
+    let data = match &file.decrypt {
+        Some(encryption) => encryption.decrypt(&data).map_err(failed(url))?,
+        None => data,
+    };
+    let data = match file.decompress {
+        Some(compression) => compression.decompress(&data).map_err(failed(url))?,
+        None => data,
+    };
+    Ok(match &file.filter {
+        Some(command) => transform::filter(command, &data).map_err(failed(url))?,
+        None => data,
+    })
+}
+
+/// The part of `data` covered by a read of `size` bytes at `offset`.
+fn slice(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let start = fetch::clamped(offset.max(0) as u64, data.len());
+    let end = start + fetch::clamped(size as u64, data.len() - start);
+    &data[start..end]
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "compression")]
+    use std::io::Write;
+    use std::{
+        collections::BTreeMap,
+        error::Error,
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[cfg(feature = "compression")]
+    use flate2::{write::GzEncoder, Compression};
+
+    use crate::{
+        fetch::MemoryFetcher,
+        fs::{check_name, Builder, EmptyFilename},
+        LhttpfsError,
+    };
+
+    use crate::layout::{
+        Auth, CachePolicy, Defaults, Directory, InputFile, InvalidLayout, LimitExceeded, Limits,
+        URLFile,
+    };
+
+    #[cfg(feature = "compression")]
+    use super::Encryption;
+    use super::{
+        fetch, ops::xattrs, slice, split_read, Blockwise, CompiledCredentials, FileType,
+        LazyHTTPFS, Node, OpError, Source, ZeroChunkSize, ROOT_INO,
+    };
+    #[cfg(feature = "archive")]
+    use super::{fetch_block, fetch_folder, fetch_gzip, zip_data_start, GzipIndex, Request, Span};
+
+    /// A builder reading `mem://` URLs from `objects`, caching in memory.
+    fn serving(objects: &[(&str, &[u8])]) -> Builder {
+        let origin = (objects.iter()).fold(MemoryFetcher::new(), |origin, (url, data)| {
+            origin.with(*url, *data)
+        });
+        LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("mem", Arc::new(origin))
+    }
+
+    const JSON: &str = r#"
+[
+  {
+    "name": "helloworld.txt",
+    "size": 25,
+    "url": "https://ping.archlinux.org/nm-check.txt"
+  },{
+    "name": "outer.dir",
+  "contents": [
+    {
+      "name": "inner.txt",
+      "size": 25,
+      "url": "https://ping.archlinux.org/nm-check.txt"
+    }
+  ]}
+]"#;
+
+    #[test]
+    fn deserialize() {
+        let result: Vec<InputFile> = serde_json::from_str(JSON).unwrap();
+        let expected: Vec<InputFile> = vec![
+            InputFile::URLFile(URLFile {
+                name: "helloworld.txt".into(),
+                url: "https://ping.archlinux.org/nm-check.txt".into(),
+                size: 25,
+                sha256: None,
+                md5: None,
+                mirrors: Vec::new(),
+                pieces: None,
+                decompress: None,
+                compressed_size: None,
+                archive: None,
+                decrypt: None,
+                filter: None,
+                options: Defaults::default(),
+            }),
+            InputFile::Directory(Directory {
+                name: "outer.dir".into(),
+                contents: vec![InputFile::URLFile(URLFile {
+                    name: "inner.txt".into(),
+                    url: "https://ping.archlinux.org/nm-check.txt".into(),
+                    size: 25,
+                    sha256: None,
+                    md5: None,
+                    mirrors: Vec::new(),
+                    pieces: None,
+                    decompress: None,
+                    compressed_size: None,
+                    archive: None,
+                    decrypt: None,
+                    filter: None,
+                    options: Defaults::default(),
+                })],
+                defaults: Defaults::default(),
+                base_url: None,
+                profiles: BTreeMap::new(),
+            }),
+        ];
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn parsing() {
+        let result: Vec<InputFile> = serde_json::from_str(JSON).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        for (inode, node) in fs.nodes.iter().enumerate() {
+            println!("{}: {:?}\n", inode + 1, node);
+            assert_eq!(inode as u64 + 1, node.get_attr().ino);
+        }
+    }
+
+    const JSON2: &str = include_str!("../models.json");
+
+    #[test]
+    fn parsing2() {
+        let result: Vec<InputFile> = serde_json::from_str(JSON2).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        let Node::DirNode(ref root) = fs.nodes[0] else {
+            panic!("Root needs to be a directory");
+        };
+        for (inode, node) in fs.nodes.iter().enumerate() {
+            println!("{}: {:?}", inode, node);
+            assert_eq!(inode as u64 + 1, node.get_attr().ino);
+        }
+
+        for (name, inode) in root.contents.iter() {
+            match fs.get_inode(*inode).unwrap() {
+                Node::DirNode(_) => {}
+                Node::FileNode(ref f) => {
+                    panic!(
+                        "Expected directory for {:?} inode {}, got {:?}",
+                        name, *inode, f
+                    )
+                }
+            };
+        }
+    }
+
+    #[test]
+    fn compiled() {
+        let result: Vec<InputFile> = serde_json::from_str(JSON2).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        let mut out = Vec::new();
+        fs.compile(&mut out).unwrap();
+        let loaded = LazyHTTPFS::read_compiled(&mut out.as_slice(), Limits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.nodes, fs.nodes);
+        assert!(
+            LazyHTTPFS::read_compiled(&mut JSON.as_bytes(), Limits::default())
+                .unwrap()
+                .is_none()
+        );
+        // Held to the same limits as the layout it came from.
+        let small = Limits {
+            max_entries: 3,
+            ..Limits::default()
+        };
+        let Err(LhttpfsError::Layout(e)) = LazyHTTPFS::read_compiled(&mut out.as_slice(), small)
+        else {
+            panic!("Expected a compiled layout past the limits to be refused");
+        };
+        assert!(e.is::<LimitExceeded>());
+    }
+
+    #[test]
+    fn compiled_credentials() {
+        let compile = |auth: &str| {
+            let json = format!(
+                r#"[{{"name": "a", "url": "https://example.com/a", "size": 1, "auth": {}}}]"#,
+                auth
+            );
+            let fs = LazyHTTPFS::new(serde_json::from_str(&json).unwrap()).unwrap();
+            fs.compile(&mut Vec::new())
+        };
+        let Err(LhttpfsError::Layout(e)) = compile(r#"{"bearer": "s3cret"}"#) else {
+            panic!("Expected a secret to be kept out of the compiled layout");
+        };
+        assert!(e.is::<CompiledCredentials>());
+        assert!(compile(r#"{"bearer": "keyring:example.com/ci"}"#).is_ok());
+        let fs = serving(&[])
+            .defaults(Defaults {
+                headers: BTreeMap::from([("Authorization".into(), "Bearer s3cret".into())]),
+                ..Defaults::default()
+            })
+            .build(serde_json::from_str(JSON2).unwrap())
+            .unwrap();
+        assert!(fs.compile(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn empty_file() {
+        let json = r#"[{"name":"", "size": 23, "url": "https://ping.archlinux.com/nm-check.txt"}]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let Err(error) = LazyHTTPFS::new(result) else {
+            panic!("An empty name was taken");
+        };
+        assert!(error.source().unwrap().is::<InvalidLayout>());
+        assert!(error.to_string().ends_with(&EmptyFilename().to_string()));
+    }
+
+    #[test]
+    fn bad_names() {
+        for name in [".", "..", "/", "a/b", "../etc", "a\\u0000b"] {
+            let json = format!(
+                r#"[{{"name": "d", "contents": [{{"name": "{}", "content": "x"}}]}}]"#,
+                name
+            );
+            let fs = LazyHTTPFS::new(serde_json::from_str(&json).unwrap());
+            let Err(LhttpfsError::Layout(e)) = fs else {
+                panic!("{} was taken", name);
+            };
+            assert!(e.is::<InvalidLayout>(), "{}", e);
+            assert!(e.to_string().contains("isn't allowed as a name"), "{}", e);
+        }
+        let error = check_name("a/b").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            r#""a/b" isn't allowed as a name: names can't contain a /"#
+        );
+        assert!(check_name("...").is_ok() && check_name(".hidden").is_ok());
+    }
+
+    #[test]
+    fn inherited_defaults() {
+        let json = r#"[{
+            "name": "private",
+            "defaults": {
+                "headers": {"X-Mirror": "eu", "Accept": "*/*"},
+                "auth": {"bearer": "secret"},
+                "mode": "0440",
+                "dir_mode": "0550",
+                "uid": 0,
+                "ttl": 60,
+                "mtime": 1714564800
+            },
+            "contents": [
+                {"name": "a", "size": 1, "url": "https://example.com/a"},
+                {"name": "b", "size": 1, "url": "https://example.com/b",
+                 "headers": {"Accept": "text/plain"}, "cache": "disk", "pin": true, "gid": 7,
+                 "content_type": "text/plain"}
+            ]
+        }]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        let (Node::FileNode(a), Node::FileNode(b)) = (&fs.nodes[2], &fs.nodes[3]) else {
+            panic!("Expected two files, got {:?}", fs.nodes);
+        };
+        assert_eq!(fs.nodes[1].get_attr().uid, 0);
+        assert_eq!(fs.nodes[1].get_attr().perm, 0o550);
+        assert_eq!(fs.nodes[0].get_attr().perm, 0o555);
+        assert_eq!(a.attr.perm, 0o440);
+        let modified = UNIX_EPOCH + Duration::from_secs(1714564800);
+        assert_eq!(
+            (a.attr.mtime, fs.nodes[1].get_attr().mtime),
+            (modified, modified)
+        );
+        assert_eq!(fs.nodes[0].get_attr().mtime, UNIX_EPOCH);
+        assert_eq!((a.attr.uid, a.attr.gid), (0, unsafe { libc::getgid() }));
+        assert_eq!(a.auth, Some(Auth::Bearer("secret".into())));
+        assert_eq!(a.cache, CachePolicy::Memory);
+        assert_eq!(a.ttl, Some(Duration::from_secs(60)));
+        assert_eq!(a.headers["Accept"], "*/*");
+        assert_eq!(b.headers["Accept"], "text/plain");
+        assert_eq!(b.headers["X-Mirror"], "eu");
+        assert_eq!((b.cache, b.ttl), (CachePolicy::Disk, None));
+        assert_eq!(b.attr.gid, 7);
+        assert_eq!(xattrs(&fs.nodes[2]), []);
+        assert_eq!(
+            xattrs(&fs.nodes[3]),
+            [("user.mime_type", &b"text/plain"[..])]
+        );
+    }
+
+    #[test]
+    fn relative_urls() {
+        let json = r#"[{
+            "name": "mirror",
+            "base_url": "https://mirror.example.com/pub",
+            "contents": [
+                {"name": "a", "size": 1, "url": "a.bin"},
+                {"name": "b", "size": 1, "url": "https://other.example.com/b.bin"},
+                {"name": "sub", "base_url": "nested/", "contents": [
+                    {"name": "c", "size": 1, "url": "../c.bin"},
+                    {"name": "d", "size": 1, "url": "/root/d.bin"}
+                ]}
+            ]
+        }]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        let urls: Vec<_> = fs
+            .nodes
+            .iter()
+            .filter_map(|n| match n {
+                Node::FileNode(file) => match &file.source {
+                    Source::Url(url) => Some(url.as_str()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://mirror.example.com/pub/a.bin",
+                "https://other.example.com/b.bin",
+                "https://mirror.example.com/pub/c.bin",
+                "https://mirror.example.com/root/d.bin",
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn decompressed() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"a,b\n1,2\n").unwrap();
+        let url = "mem://a.csv.gz";
+        let json = format!(
+            r#"[{{"name": "a.csv", "url": "{}", "size": 0, "decompress": "gzip"}}]"#,
+            url
+        );
+        let fs = (serving(&[(url, &encoder.finish().unwrap())]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert!(fs.nodes[1].size_unknown());
+        let Node::FileNode(file) = &fs.nodes[1] else {
+            panic!("Expected a file, got {:?}", fs.nodes);
+        };
+        let data = fetch(
+            &mut fs.cache.lock().unwrap(),
+            &fs.fetchers,
+            file,
+            url,
+            &[],
+            None,
+        )
+        .unwrap()
+        .into_owned();
+        assert_eq!(&*data, b"a,b\n1,2\n");
+    }
+
+    #[test]
+    fn probed() {
+        let json = r#"[{"name": "a", "url": "mem://a"}, {"name": "b", "url": "mem://gone"}]"#;
+        let files = || serde_json::from_str::<Vec<InputFile>>(json).unwrap();
+        let fs = serving(&[("mem://a", b"hello")]).build(files()).unwrap();
+        assert_eq!(fs.nodes[1].get_attr().size, 5);
+        assert!(!fs.nodes[1].size_unknown());
+        // What can't be asked for stays unknown, for opening to ask again.
+        assert!(fs.nodes[2].size_unknown());
+
+        let mut fs = (serving(&[("mem://a", b"hello")]).probe(false))
+            .build(files())
+            .unwrap();
+        assert!(fs.nodes[1].size_unknown());
+        fs.open_file(2, false).unwrap();
+        assert_eq!(fs.read_file(2, 0, 10).unwrap(), b"hello");
+        assert_eq!(fs.open_file(3, false), Err(OpError::Failed));
+    }
+
+    #[test]
+    fn checksums() {
+        let json = r#"[
+            {"name": "a", "url": "mem://a", "size": 5,
+             "sha256": "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824"},
+            {"name": "b", "url": "mem://b", "size": 5, "md5": "5d41402abc4b2a76b9719d911017c592"},
+            {"name": "c", "url": "mem://c", "size": 5, "md5": "5d41402abc4b2a76b9719d911017c592"}
+        ]"#;
+        let files = serde_json::from_str::<Vec<InputFile>>(json).unwrap();
+        let objects: [(&str, &[u8]); 3] = [
+            ("mem://a", b"hello"),
+            ("mem://b", b"hello"),
+            ("mem://c", b"jello"),
+        ];
+        let mut fs = serving(&objects).build(files).unwrap();
+        fs.blockwise = Blockwise { size: 2 };
+        assert_eq!(fs.read_file(2, 0, 10).unwrap(), b"hello");
+        let sha256 = fs
+            .xattr(2, std::ffi::OsStr::new("user.lhttpfs.sha256"))
+            .unwrap();
+        assert_eq!(
+            sha256,
+            b"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(fs.xattr_names(3).unwrap(), ["user.lhttpfs.md5"]);
+        // Read in blocks, the file is checked once read through in order.
+        assert_eq!(fs.read_file(3, 0, 3).unwrap(), b"hel");
+        assert_eq!(fs.read_file(3, 3, 2).unwrap(), b"lo");
+        assert_eq!(fs.read_file(4, 0, 3).unwrap(), b"jel");
+        assert_eq!(fs.read_file(4, 3, 2), Err(OpError::Failed));
+        // Still wrong when read again, rather than served from the cache.
+        assert_eq!(fs.read_file(4, 0, 5), Err(OpError::Failed));
+    }
+
+    #[test]
+    fn blocks() {
+        let files = r#"[{"name": "a", "url": "mem://a", "size": 10}]"#;
+        let files = serde_json::from_str::<Vec<InputFile>>(files).unwrap();
+        let origin = Arc::new(MemoryFetcher::new().with("mem://a", "0123456789"));
+        let mut fs = (LazyHTTPFS::builder().cache_dir(None))
+            .fetcher("mem", origin.clone())
+            .build(files)
+            .unwrap();
+        fs.blockwise = Blockwise { size: 4 };
+        // However small the file, only the block read from is fetched.
+        assert_eq!(fs.read_file(2, 9, 1).unwrap(), b"9");
+        assert_eq!(origin.fetched(), [("mem://a".into(), Some((8, 2)))]);
+    }
+
+    #[test]
+    fn links() {
+        let json = r#"[
+            {"name": "v2", "contents": [{"name": "model.bin", "url": "mem://m", "size": 5}]},
+            {"name": "latest", "target": "v2/model.bin"},
+            {"name": "v2-again", "target": "./v2/../v2"},
+            {"name": "loop", "target": "loop"}
+        ]"#;
+        let files = serde_json::from_str::<Vec<InputFile>>(json).unwrap();
+        let mut fs = serving(&[("mem://m", b"model")]).build(files).unwrap();
+        let (latest, _) = fs.find(1, std::ffi::OsStr::new("latest")).unwrap();
+        assert_eq!(latest.kind, FileType::Symlink);
+        assert_eq!(latest.size, 12);
+        assert_eq!(fs.link_target(latest.ino).unwrap(), b"v2/model.bin");
+        assert_eq!(fs.link_target(2), Err(OpError::NotLink));
+        let mut compiled = Vec::new();
+        fs.compile(&mut compiled).unwrap();
+        let loaded = LazyHTTPFS::read_compiled(&mut compiled.as_slice(), Limits::default());
+        assert_eq!(loaded.unwrap().unwrap().nodes, fs.nodes);
+        // Followed, links reach what they point to.
+        let model = fs.resolve("latest").unwrap();
+        assert_eq!(model.kind, FileType::RegularFile);
+        assert_eq!(fs.read_file(model.ino, 0, 10).unwrap(), b"model");
+        let model = fs.resolve("v2-again/model.bin").unwrap();
+        assert_eq!(fs.read_file(model.ino, 0, 10).unwrap(), b"model");
+        assert_eq!(fs.resolve("loop").map(|_| ()), Err(OpError::NotFound));
+    }
+
+    #[test]
+    fn filtered() {
+        let url = "mem://a.csv";
+        let json = format!(
+            r#"[{{"name": "a.tsv", "url": "{}", "size": 0, "filter": "tr , '\\t'"}}]"#,
+            url
+        );
+        // Layouts have to be trusted to run commands.
+        assert!(LazyHTTPFS::new(serde_json::from_str(&json).unwrap()).is_err());
+        let fs = serving(&[(url, b"a,b\n1,2\n")])
+            .limits(Limits {
+                filters: true,
+                ..Limits::default()
+            })
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert!(fs.nodes[1].size_unknown());
+        assert_eq!(fs.remote_parts(2)[0].size, None);
+        let Node::FileNode(file) = &fs.nodes[1] else {
+            panic!("Expected a file, got {:?}", fs.nodes);
+        };
+        let data = fetch(
+            &mut fs.cache.lock().unwrap(),
+            &fs.fetchers,
+            file,
+            url,
+            &[],
+            None,
+        )
+        .unwrap()
+        .into_owned();
+        assert_eq!(&*data, b"a\tb\n1\t2\n");
+        let key = format!("{} | tr , '\\t'", url);
+        assert!(fs.cache.lock().unwrap().memory(&key) == b"a\tb\n1\t2\n");
+    }
+
+    #[test]
+    fn manifest() {
+        let url = "mem://a.csv";
+        let json = format!(
+            r#"[
+                {{"name": "d", "contents": [{{"name": "a.csv", "url": "{}", "size": 4,
+                  "sha256": "ab"}}]}},
+                {{"name": "b.txt", "content": "b"}}
+            ]"#,
+            url
+        );
+        let mut fs = (serving(&[(url, b"a,b\n")]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        fs.add_manifest();
+        let manifest = |fs: &LazyHTTPFS| {
+            serde_json::from_slice::<serde_json::Value>(&fs.manifest()).unwrap()["files"].clone()
+        };
+        assert_eq!(
+            manifest(&fs),
+            serde_json::json!([
+                {"path": "/b.txt", "url": null, "size": 1, "sha256": null, "md5": null,
+                 "cache": "memory", "cached": null},
+                {"path": "/d/a.csv", "url": url, "size": 4, "sha256": "ab", "md5": null,
+                 "cache": "memory", "cached": false}
+            ])
+        );
+        let Node::FileNode(file) = &fs.nodes[2] else {
+            panic!("Expected a file, got {:?}", fs.nodes);
+        };
+        fetch(
+            &mut fs.cache.lock().unwrap(),
+            &fs.fetchers,
+            file,
+            url,
+            &[],
+            Some((0, 4)),
+        )
+        .unwrap();
+        assert_eq!(manifest(&fs)[1]["cached"], true);
+    }
+
+    #[test]
+    fn slices() {
+        let layout = |slice: &str| {
+            let json = format!(
+                r#"[
+                    {{"name": "d", "contents": [
+                        {{"name": "dump.bin", "url": "https://example.com/dump", "size": 1000,
+                         "headers": {{"X-Token": "t"}}}},
+                        {{"name": "note", "content": "hello world"}}
+                    ]}},
+                    {slice}
+                ]"#
+            );
+            LazyHTTPFS::new(serde_json::from_str(&json).unwrap())
+        };
+        let fs = layout(
+            r#"{"name": "record", "slice_of": "d/dump.bin", "offset": 100, "size": 50},
+               {"name": "word", "slice_of": "/d/note", "offset": 6, "size": 5}"#,
+        )
+        .unwrap();
+        let Node::FileNode(record) = &fs.nodes[4] else {
+            panic!("Expected a file, got {:?}", fs.nodes[4]);
+        };
+        assert!(
+            record.source
+                == Source::Range {
+                    url: "https://example.com/dump".into(),
+                    start: 100,
+                    len: 50
+                }
+        );
+        assert_eq!(record.headers["X-Token"], "t");
+        assert_eq!(record.attr.size, 50);
+        let Node::FileNode(word) = &fs.nodes[5] else {
+            panic!("Expected a file, got {:?}", fs.nodes[5]);
+        };
+        assert!(word.source == Source::Inline(b"world".to_vec()));
+
+        for bad in [
+            r#"{"name": "s", "slice_of": "d/missing", "size": 1}"#,
+            r#"{"name": "s", "slice_of": "d", "size": 1}"#,
+            r#"{"name": "s", "slice_of": "d/dump.bin", "offset": 990, "size": 20}"#,
+            r#"{"name": "s", "slice_of": "s2", "size": 1}, {"name": "s2", "slice_of": "d/note", "size": 1}"#,
+        ] {
+            assert!(layout(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn source_files() {
+        let json = r#"[
+            {"name": "a.bin", "url": "https://example.com/a", "size": 1,
+             "mirrors": ["https://mirror.example.com/a"]},
+            {"name": "b.txt", "content": "b"},
+            {"name": "d", "base_url": "https://example.com/d/", "contents": [
+                {"name": "c.bin", "url": "c", "size": 1},
+                {"name": ".c.bin.url", "content": "mine"}
+            ]}
+        ]"#;
+        let mut fs = LazyHTTPFS::new(serde_json::from_str(json).unwrap()).unwrap();
+        fs.add_source_files();
+        let names = fs
+            .walk()
+            .into_iter()
+            .map(|entry| entry.name.into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "/",
+                ".a.bin.url",
+                "a.bin",
+                "b.txt",
+                "d",
+                ".c.bin.url",
+                "c.bin"
+            ]
+        );
+        let sources = fs
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::FileNode(file) => match &file.source {
+                    Source::Inline(data) => Some(String::from_utf8(data.clone()).unwrap()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                "b",
+                "mine",
+                "https://example.com/a\nhttps://mirror.example.com/a\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn entries() {
+        let json = r#"[
+            {"name": "d", "contents": [{"name": "a", "url": "mem://a", "size": 3}]},
+            {"name": "b", "content": "b"}
+        ]"#;
+        let mut fs = (serving(&[("mem://a", b"abc")]))
+            .build(serde_json::from_str(json).unwrap())
+            .unwrap();
+        let paths = fs.entries().map(|entry| entry.path).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            ["/", "/b", "/d", "/d/a"].map(std::path::PathBuf::from)
+        );
+        let a = fs.entries().last().unwrap();
+        assert_eq!((a.name.to_str(), a.depth, a.attr.size), (Some("a"), 2, 3));
+        assert_eq!(fs.remote_parts(a.attr.ino)[0].url, "mem://a");
+        assert_eq!(fs.cached(a.attr.ino), Some(false));
+        fs.read_file(a.attr.ino, 0, 3).unwrap();
+        assert_eq!(fs.cached(a.attr.ino), Some(true));
+        assert_eq!(fs.cached(ROOT_INO), None);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn decrypted() {
+        let dir = std::env::temp_dir();
+        let key_file = dir.join(format!("lhttpfs-encrypted-key-{}", std::process::id()));
+        let encryption = Encryption {
+            cipher: Default::default(),
+            key_file: key_file.clone(),
+        };
+        encryption.generate_key().unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"a,b\n1,2\n").unwrap();
+        let encrypted = encryption.encrypt(&encoder.finish().unwrap()).unwrap();
+        let url = "mem://a.csv.gz.enc";
+        let json = format!(
+            r#"[{{"name": "a.csv", "url": "{}", "size": 8, "decompress": "gzip", "cache": "disk",
+                "decrypt": {{"key_file": {:?}}}}}]"#,
+            url, key_file
+        );
+        let mut fs = (serving(&[(url, &encrypted)]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        // Though cached on disk, decrypted bytes are only kept in memory.
+        let cache_dir = dir.join(format!("lhttpfs-encrypted-cache-{}", std::process::id()));
+        fs.set_cache_dir(cache_dir.clone());
+        let Node::FileNode(file) = &fs.nodes[1] else {
+            panic!("Expected a file, got {:?}", fs.nodes);
+        };
+        let data = fetch(
+            &mut fs.cache.lock().unwrap(),
+            &fs.fetchers,
+            file,
+            url,
+            &[],
+            None,
+        )
+        .unwrap()
+        .into_owned();
+        assert_eq!(&*data, b"a,b\n1,2\n");
+        assert!(
+            fs.cache
+                .lock()
+                .unwrap()
+                .memory(&format!("{} gzip decrypted", url))
+                == b"a,b\n1,2\n"
+        );
+        assert!(!cache_dir.exists());
+        std::fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn tar_archive() {
+        let tar = crate::archive::tar_fixture(&[("d/e/a.txt", b"hello"), ("b.txt", b"hi")]);
+        let json = format!(
+            r#"[{{"name": "data", "url": "mem://data.tar", "size": {}, "archive": "tar"}}]"#,
+            tar.len()
+        );
+        let fs = (serving(&[("mem://data.tar", &tar)]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        let tree = fs
+            .walk()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.depth,
+                    entry.name.into_string().unwrap(),
+                    entry.attr.size,
+                )
+            })
+            .collect::<Vec<_>>();
+        let tree = tree
+            .iter()
+            .map(|(d, n, s)| (*d, n.as_str(), *s))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tree,
+            [
+                (0, "/", 0),
+                (1, "data", 0),
+                (2, "b.txt", 2),
+                (2, "d", 0),
+                (3, "e", 0),
+                (4, "a.txt", 5),
+            ]
+        );
+        let a = fs
+            .nodes
+            .iter()
+            .find(|node| node.get_attr().size == 5)
+            .unwrap();
+        let Node::FileNode(file) = a else {
+            panic!("Expected a file, got {:?}", a);
+        };
+        let (Source::Range { start, len, .. }, attr) = (&file.source, &file.attr) else {
+            panic!("Expected a range, got {:?}", a);
+        };
+        assert_eq!(&tar[*start as usize..(start + len) as usize], b"hello");
+        assert_eq!(attr.perm, 0o444);
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn split_sevenz() {
+        let files: [(&str, &[u8]); 2] = [("hello", b"hello"), ("docs/readme.txt", b"read!\n")];
+        for coder in ["copy", "lzma2"] {
+            let archive = crate::archive::sevenz_fixture(&files, coder);
+            // Split within the members' data.
+            let (first, second) = archive.split_at(36);
+            let json = format!(
+                r#"[{{"name": "mods", "archive": "7z", "segments": [
+                    {{"url": "mem://mods.7z.001", "size": {}}},
+                    {{"url": "mem://mods.7z.002", "size": {}}}]}}]"#,
+                first.len(),
+                second.len()
+            );
+            let fs = serving(&[("mem://mods.7z.001", first), ("mem://mods.7z.002", second)])
+                .build(serde_json::from_str(&json).unwrap())
+                .unwrap();
+            let mut contents = Vec::new();
+            for node in &fs.nodes {
+                let Node::FileNode(file) = node else {
+                    continue;
+                };
+                let mut cache = fs.cache.lock().unwrap();
+                let (cache, fetchers) = (&mut *cache, &fs.fetchers);
+                let range = |span: &Span| Some((span.start, span.len));
+                contents.push(match &file.source {
+                    Source::Range { url, start, len } => {
+                        fetch(cache, fetchers, file, url, &[], Some((*start, *len)))
+                            .unwrap()
+                            .into_owned()
+                    }
+                    Source::Spans(spans) => spans
+                        .iter()
+                        .flat_map(|span| {
+                            fetch(cache, fetchers, file, &span.url, &[], range(span))
+                                .unwrap()
+                                .into_owned()
+                        })
+                        .collect(),
+                    Source::Folder {
+                        packed,
+                        folder,
+                        offset,
+                    } => {
+                        assert_eq!(packed.len(), 2);
+                        let data = fetch_folder(cache, fetchers, file, packed, folder).unwrap();
+                        let start = *offset as usize;
+                        data[start..start + file.attr.size as usize].to_vec()
+                    }
+                    source => panic!("Unexpected source {}", source),
+                });
+            }
+            assert_eq!(contents, [&b"hello"[..], b"read!\n"], "{}", coder);
+        }
+        let json = r#"[{"name": "a", "archive": "zip", "segments": [
+            {"url": "https://example.com/a.zip.001", "size": 1},
+            {"url": "https://example.com/a.zip.002", "size": 1}]}]"#;
+        assert!(LazyHTTPFS::new(serde_json::from_str(json).unwrap()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn tar_gz_archive() {
+        let tar = crate::archive::tar_fixture(&[("a.txt", b"hello"), ("b.txt", b"world")]);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let tgz = encoder.finish().unwrap();
+        let json = format!(
+            r#"[{{"name": "data", "url": "mem://data.tgz", "size": {}, "archive": "tar", "decompress": "gzip"}}]"#,
+            tgz.len()
+        );
+        let fs = (serving(&[("mem://data.tgz", &tgz)]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        let mut index = GzipIndex::default();
+        let mut contents = Vec::new();
+        for node in &fs.nodes {
+            let Node::FileNode(file) = node else {
+                continue;
+            };
+            let Source::GzipRange {
+                url,
+                archive_size,
+                start,
+                len,
+            } = &file.source
+            else {
+                panic!("Expected a gzip range, got {:?}", file);
+            };
+            assert_eq!(*archive_size, tgz.len() as u64);
+            let request = Request {
+                size: *archive_size,
+                ..file.request(url)
+            };
+            let mut cache = fs.cache.lock().unwrap();
+            let data = fetch_gzip(
+                &mut cache,
+                &fs.fetchers,
+                &mut index,
+                file,
+                request,
+                (*start, *len),
+            );
+            contents.push(data.unwrap().into_owned());
+        }
+        assert_eq!(contents, [b"hello", b"world"]);
+
+        let json = json.replace(r#""archive": "tar""#, r#""archive": "zip""#);
+        assert!(LazyHTTPFS::new(serde_json::from_str(&json).unwrap()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn squashfs_image() {
+        let image = crate::archive::squashfs_fixture::image();
+        let json = format!(
+            r#"[{{"name": "image", "url": "mem://image.sqfs", "size": {}, "archive": "squashfs", "mode": "0444"}}]"#,
+            image.len()
+        );
+        let fs = (serving(&[("mem://image.sqfs", &image)]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        let names = fs
+            .walk()
+            .into_iter()
+            .map(|entry| entry.name.into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["/", "image", "d", "a.txt", "b.txt"]);
+        let mut contents = Vec::new();
+        for node in &fs.nodes {
+            let Node::FileNode(file) = node else {
+                continue;
+            };
+            let Source::Blocks { url, blocks } = &file.source else {
+                panic!("Expected blocks, got {:?}", file);
+            };
+            assert_eq!(file.attr.perm, 0o444);
+            let mut data = Vec::new();
+            for (i, from, len) in split_read(blocks.part_sizes(file.attr.size), 0, 8192) {
+                let mut cache = fs.cache.lock().unwrap();
+                let part = fetch_block(&mut cache, &fs.fetchers, file, url, blocks, i).unwrap();
+                data.extend_from_slice(&part[from as usize..(from + len) as usize]);
+            }
+            contents.push(data);
+        }
+        contents.sort_by_key(Vec::len);
+        use crate::archive::squashfs_fixture::{A, B};
+        assert_eq!(contents, [B, A]);
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn zip_archive() {
+        let text = b"hello, deflated world ".repeat(20);
+        let zip = crate::archive::zip_fixture(&[("d/a.txt", &text, true), ("b.txt", b"hi", false)]);
+        let json = format!(
+            r#"[{{"name": "data.zip", "url": "mem://data.zip", "size": {}, "archive": "zip"}}]"#,
+            zip.len()
+        );
+        let fs = (serving(&[("mem://data.zip", &zip)]))
+            .build(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        let read = |size: u64| {
+            let Some(Node::FileNode(file)) = fs.nodes.iter().find(|n| n.get_attr().size == size)
+            else {
+                panic!("No file of {} bytes", size);
+            };
+            let Source::Zip {
+                url,
+                header,
+                compressed_size,
+            } = &file.source
+            else {
+                panic!("Expected a zip member, got {:?}", file);
+            };
+            let start = zip_data_start(&fs.fetchers, file, url, *header).unwrap();
+            let range = Some((start, *compressed_size));
+            fetch(
+                &mut fs.cache.lock().unwrap(),
+                &fs.fetchers,
+                file,
+                url,
+                &[],
+                range,
+            )
+            .unwrap()
+            .into_owned()
+        };
+        assert_eq!(read(text.len() as u64), text);
+        assert_eq!(read(2), b"hi");
+    }
+
+    #[test]
+    fn inline_content() {
+        let json = r#"[
+            {"name": "README", "content": "hello\n"},
+            {"name": "blob", "content": "AAEC/w==", "encoding": "base64", "mode": "0400"}
+        ]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        let (Node::FileNode(readme), Node::FileNode(blob)) = (&fs.nodes[1], &fs.nodes[2]) else {
+            panic!("Expected two files, got {:?}", fs.nodes);
+        };
+        assert!(readme.source == Source::Inline(b"hello\n".to_vec()));
+        assert_eq!(readme.attr.size, 6);
+        assert!(blob.source == Source::Inline(vec![0, 1, 2, 255]));
+        assert_eq!(blob.attr.perm, 0o400);
+        assert_eq!(slice(b"hello\n", 2, 2), b"ll");
+        assert_eq!(slice(b"hello\n", 10, 2), b"");
+    }
+
+    #[test]
+    fn concatenated_segments() {
+        let json = r#"[{
+            "name": "mirror",
+            "base_url": "https://mirror.example.com/",
+            "contents": [{"name": "file.bin", "segments": [
+                {"url": "file.bin.000", "size": 100},
+                {"url": "file.bin.001", "size": 50}
+            ]}]
+        }]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        let Node::FileNode(file) = &fs.nodes[2] else {
+            panic!("Expected a file, got {:?}", fs.nodes[2]);
+        };
+        assert_eq!(file.attr.size, 150);
+        let Source::Concat(segments) = &file.source else {
+            panic!("Expected segments, got {}", file.source);
+        };
+        assert_eq!(segments[1].url, "https://mirror.example.com/file.bin.001");
+        let sizes = || segments.iter().map(|s| s.size);
+        assert_eq!(split_read(sizes(), 0, 10), [(0, 0, 10)]);
+        assert_eq!(split_read(sizes(), 90, 20), [(0, 90, 10), (1, 0, 10)]);
+        assert_eq!(split_read(sizes(), 120, 100), [(1, 20, 30)]);
+        assert_eq!(split_read(sizes(), 150, 10), []);
+    }
+
+    #[test]
+    fn chunked_file() {
+        let json = r#"[{"name": "huge.bin", "url": "https://example.com/huge.bin",
+            "size": 2500, "chunk_size": 1000}]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let fs = LazyHTTPFS::new(result).unwrap();
+        for (inode, node) in fs.nodes.iter().enumerate() {
+            assert_eq!(inode as u64 + 1, node.get_attr().ino);
+        }
+        let Node::DirNode(dir) = &fs.nodes[1] else {
+            panic!("Expected a directory, got {:?}", fs.nodes[1]);
+        };
+        let last = dir.contents[std::ffi::OsStr::new("huge.bin.002")];
+        let Some(Node::FileNode(chunk)) = fs.get_inode(last) else {
+            panic!("Expected a chunk at inode {}", last);
+        };
+        assert_eq!(dir.contents.len(), 3);
+        assert_eq!(chunk.attr.size, 500);
+        assert!(matches!(
+            chunk.source,
+            Source::Range {
+                start: 2000,
+                len: 500,
+                ..
+            }
+        ));
+
+        let json =
+            r#"[{"name": "bad", "url": "https://example.com/", "size": 1, "chunk_size": 0}]"#;
+        let result: Vec<InputFile> = serde_json::from_str(json).unwrap();
+        let Err(error) = LazyHTTPFS::new(result) else {
+            panic!("Expected an error");
+        };
+        assert!(matches!(&error, LhttpfsError::Layout(e) if e.is::<ZeroChunkSize>()));
+    }
+
+    /// An origin of files too large to hold, whose byte at `i` is `i % 251`,
+    /// recording what it is asked for.
+    #[derive(Default)]
+    struct Huge(std::sync::Mutex<Vec<Asked>>);
+
+    /// A URL and the range of it asked for.
+    type Asked = (String, Option<(u64, u64)>);
+
+    impl crate::fetch::Fetcher for Huge {
+        fn fetch_range(
+            &self,
+            request: &super::Request,
+            range: Option<(u64, u64)>,
+        ) -> crate::fetch::FetchResult {
+            (self.0.lock().unwrap()).push((request.url.to_string(), range));
+            // Only the small segment is small enough to be fetched whole.
+            let (start, len) = range.unwrap_or((0, 100));
+            assert!(len <= 64 << 20, "{} bytes fetched at once", len);
+            Ok((start..start + len)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>()
+                .into())
+        }
+    }
+
+    #[test]
+    fn huge_files() {
+        let huge = Arc::new(Huge::default());
+        let big = 5u64 << 30;
+        let layout = format!(
+            r#"[
+                {{"name": "big", "url": "huge://big", "size": {big}}},
+                {{"name": "joined", "segments": [
+                    {{"url": "huge://small", "size": 100}},
+                    {{"url": "huge://part", "size": {big}}}
+                ]}}
+            ]"#
+        );
+        let fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("huge", huge.clone())
+            .build(crate::layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let tree = super::RemoteTree::new(fs);
+        let expected = |from: u64, len: u64| (from..from + len).map(|i| (i % 251) as u8);
+        let file = tree.open("/big").unwrap();
+        assert_eq!(file.size(), big);
+        // Across the end of one block and past 4 GiB.
+        let at = (4 << 30) + (8 << 20) - 10;
+        let data = file.read_at(at, 20).unwrap();
+        assert!(data.iter().copied().eq(expected(at, 20)));
+        let data = file.read_at(big - 5, 100).unwrap();
+        assert!(data.iter().copied().eq(expected(big - 5, 5)));
+        assert!(file.read_at(big + 10, 100).unwrap().is_empty());
+        let joined = tree.open("/joined").unwrap();
+        let data = joined.read_at(90, 20).unwrap();
+        assert!(data[..10].iter().copied().eq(expected(90, 10)));
+        assert!(data[10..].iter().copied().eq(expected(0, 10)));
+        let block = 8 << 20;
+        let fetched = |url: &str, range| (url.to_string(), range);
+        assert_eq!(
+            *huge.0.lock().unwrap(),
+            [
+                fetched("huge://big", Some((at - block + 10, block))),
+                fetched("huge://big", Some((at + 10, block))),
+                fetched("huge://big", Some((big - block, block))),
+                fetched("huge://small", Some((0, 100))),
+                fetched("huge://part", Some((0, block))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! [`MountBuilder`]: mounting a built [`LazyHTTPFS`] from a program of its
+//! own, as `lhttpfs mount` does, without going through the binary.
+
+use std::path::Path;
+
+use fuser::{BackgroundSession, MountOption, Session};
+
+use super::{Dispatched, LazyHTTPFS, Streaming};
+use crate::LhttpfsError;
+
+/// How a [`LazyHTTPFS`] is served once mounted, with the same defaults as
+/// `lhttpfs mount`.
+pub struct MountBuilder {
+    fs: LazyHTTPFS,
+    fetch_threads: usize,
+    fuse_threads: usize,
+    readahead: u64,
+    streaming: Option<Streaming>,
+    control: bool,
+    options: Vec<MountOption>,
+}
+
+impl MountBuilder {
+    /// Mounts `fs` read-only, fetching on 8 threads and reading 8 MiB ahead
+    /// of files read in order.
+    pub fn new(fs: LazyHTTPFS) -> MountBuilder {
+        MountBuilder {
+            fs,
+            fetch_threads: 8,
+            fuse_threads: 1,
+            readahead: 8 << 20,
+            streaming: None,
+            control: false,
+            options: vec![MountOption::FSName("lhttp".to_string())],
+        }
+    }
+
+    /// How many fetches are made at once, as `--fetch-threads`.
+    pub fn fetch_threads(mut self, threads: usize) -> MountBuilder {
+        self.fetch_threads = threads;
+        self
+    }
+
+    /// How many threads take requests from the kernel, as `--fuse-threads`.
+    pub fn fuse_threads(mut self, threads: usize) -> MountBuilder {
+        self.fuse_threads = threads;
+        self
+    }
+
+    /// How far ahead of reads going through a file in order it is fetched,
+    /// as `--readahead`; 0 fetches nothing ahead.
+    pub fn readahead(mut self, bytes: u64) -> MountBuilder {
+        self.readahead = bytes;
+        self
+    }
+
+    /// Serves files as [`Dispatched::streaming`] does, for media.
+    pub fn streaming(mut self, streaming: Streaming) -> MountBuilder {
+        self.streaming = Some(streaming);
+        self
+    }
+
+    /// Adds the manifest and the `.lhttpfs` control directory, as the
+    /// binary does. The mount isn't `ro` then, so that the control files
+    /// can be written to.
+    pub fn control(mut self) -> MountBuilder {
+        self.control = true;
+        self
+    }
+
+    /// Mounts with `option` too, such as [`MountOption::AllowRoot`] or
+    /// [`MountOption::AutoUnmount`].
+    pub fn option(mut self, option: MountOption) -> MountBuilder {
+        self.options.push(option);
+        self
+    }
+
+    /// Mounts at `mountpoint` and serves it until it is unmounted.
+    pub fn mount(self, mountpoint: impl AsRef<Path>) -> Result<(), LhttpfsError> {
+        let mountpoint = mountpoint.as_ref();
+        let mut session = self.session(mountpoint)?;
+        session.run().map_err(|e| mount_failed(mountpoint, e))
+    }
+
+    /// Mounts at `mountpoint` and serves it from threads of its own, until
+    /// the returned session is dropped.
+    pub fn spawn_mount(
+        self,
+        mountpoint: impl AsRef<Path>,
+    ) -> Result<BackgroundSession, LhttpfsError> {
+        let mountpoint = mountpoint.as_ref();
+        let session = self.session(mountpoint)?;
+        session.spawn().map_err(|e| mount_failed(mountpoint, e))
+    }
+
+    fn session(self, mountpoint: &Path) -> Result<Session<Dispatched>, LhttpfsError> {
+        let MountBuilder {
+            mut fs,
+            mut options,
+            ..
+        } = self;
+        if self.control {
+            fs.add_manifest();
+            fs.add_control();
+        } else {
+            options.push(MountOption::RO);
+        }
+        let mut fs = (Dispatched::new(fs, self.fetch_threads).dispatchers(self.fuse_threads))
+            .readahead(self.readahead);
+        if let Some(streaming) = self.streaming {
+            fs = fs.streaming(streaming);
+        }
+        Session::new(fs, mountpoint, &options).map_err(|e| mount_failed(mountpoint, e))
+    }
+}
+
+fn mount_failed(mountpoint: &Path, source: std::io::Error) -> LhttpfsError {
+    LhttpfsError::Mount {
+        mountpoint: mountpoint.to_owned(),
+        source,
+        hints: Vec::new(),
+    }
+}
@@ -0,0 +1,505 @@
+//! What the filesystem does, apart from how it is asked: each operation
+//! takes inode numbers and returns what to answer, as a [`FileAttr`] or
+//! otherwise, or an [`OpError`] saying why not. With the `fuse` feature,
+//! `super::fuse` answers FUSE requests with them, and the NFS, 9P and
+//! WebDAV servers answer theirs the same way; a layer for another platform,
+//! such as WinFsp on Windows, would too.
+
+use std::{
+    ffi::OsStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{debug, error, field::Empty, info_span, trace, warn};
+
+use super::{control_file, learn_size, node, ControlFile, LazyHTTPFS, Node, Source};
+use crate::{access::Access, health::HEALTH, metrics::METRICS, LhttpfsError};
+
+/// The inode of the root directory, as FUSE numbers it.
+pub const ROOT_INO: u64 = 1;
+
+/// What an inode is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    Directory,
+    RegularFile,
+    Symlink,
+}
+
+/// The attributes of an inode, for each platform to answer with in its
+/// own terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileAttr {
+    pub ino: u64,
+    pub size: u64,
+    /// How many 512-byte blocks the file takes.
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+    pub kind: FileType,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub blksize: u32,
+}
+
+/// Why an operation failed, for each platform to answer with its own code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpError {
+    NotFound,
+    /// The node has no such extended attribute.
+    NoAttribute,
+    /// A control file that can only be read was opened for writing.
+    Denied,
+    /// Anything but a control file was to be changed.
+    ReadOnly,
+    /// A control file was written to that this mount doesn't allow writing
+    /// to, with [`LazyHTTPFS::deny_writes`].
+    NotPermitted,
+    /// Writing to a control file did nothing, `stats` saying why, or the
+    /// bytes to read couldn't be fetched.
+    Failed,
+    /// The origin refused to send the bytes to read without other
+    /// credentials, with a `401` or `403`.
+    Refused,
+    /// Fetching the bytes to read took too long.
+    TimedOut,
+    /// What was read as a link isn't one.
+    NotLink,
+}
+
+impl OpError {
+    /// What a read failing to fetch with `error` fails with: the origin
+    /// not having the file at all is [`OpError::NotFound`].
+    pub(super) fn fetching(error: &LhttpfsError) -> OpError {
+        match error.status() {
+            Some(401 | 403) => OpError::Refused,
+            Some(404 | 410) => OpError::NotFound,
+            _ if error.timed_out() => OpError::TimedOut,
+            _ => OpError::Failed,
+        }
+    }
+}
+
+/// How a file was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opened {
+    /// Whether reads must go to the filesystem every time, bypassing the
+    /// page cache, because the file's size isn't known up front.
+    pub direct: bool,
+    /// The handle the reads of this opening come with, a new one each time.
+    pub fh: u64,
+}
+
+/// Extended attributes of a node as `(name, value)`.
+pub(super) fn xattrs(node: &Node) -> Vec<(&str, &[u8])> {
+    let Node::FileNode(file) = node else {
+        return Vec::new();
+    };
+    let attributes = [
+        ("user.mime_type", &file.content_type),
+        ("user.lhttpfs.sha256", &file.sha256),
+        ("user.lhttpfs.md5", &file.md5),
+    ];
+    (attributes.into_iter())
+        .filter_map(|(name, value)| Some((name, value.as_deref()?.as_bytes())))
+        .collect()
+}
+
+/// How many links [`LazyHTTPFS::resolve`] follows before giving up, as
+/// many as Linux does.
+const MAX_LINKS: usize = 40;
+
+impl LazyHTTPFS {
+    /// The attributes of `name` in directory `parent`, and how long they
+    /// may be kept.
+    pub fn find(&self, parent: u64, name: &OsStr) -> Result<(FileAttr, Duration), OpError> {
+        let found = self.find_unobserved(parent, name);
+        let ino = found.as_ref().ok().map(|(attr, _)| attr.ino);
+        (self.observers).each(|observer| observer.on_lookup(parent, name, ino));
+        found
+    }
+
+    fn find_unobserved(&self, parent: u64, name: &OsStr) -> Result<(FileAttr, Duration), OpError> {
+        let name_text = name.to_string_lossy();
+        let _span = info_span!(
+            "lookup",
+            otel.kind = "server",
+            lhttpfs.inode = parent,
+            lhttpfs.name = &*name_text
+        )
+        .entered();
+        trace!("Searching for {:?} with parent {}", name, parent);
+        match self.get_inode(parent).ok_or(OpError::NotFound)? {
+            Node::DirNode(dir_node) => {
+                let f = dir_node.contents.get(name);
+                let file = f.and_then(|i| self.get_inode(*i));
+                let file = file.ok_or(OpError::NotFound)?;
+                trace!("Reply with {:?}", file);
+                Ok((file.get_attr(), file.attr_ttl(self.attr_ttl)))
+            }
+            Node::FileNode(file_node) => {
+                error!(
+                    "Inode {}, source {} was erroneously used in lookup() as a parent directory",
+                    parent, file_node.source
+                );
+                Err(OpError::NotFound)
+            }
+        }
+    }
+
+    /// The attributes of `ino`, and how long they may be kept.
+    pub fn attributes(&self, ino: u64) -> Result<(FileAttr, Duration), OpError> {
+        let file = self.get_inode(ino).ok_or(OpError::NotFound)?;
+        Ok((file.get_attr(), file.attr_ttl(self.attr_ttl)))
+    }
+
+    /// The extended attribute `name` of `ino`.
+    pub fn xattr(&self, ino: u64, name: &OsStr) -> Result<&[u8], OpError> {
+        let file = self.get_inode(ino).ok_or(OpError::NotFound)?;
+        match xattrs(file).into_iter().find(|(key, _)| name == *key) {
+            Some((_, value)) => Ok(value),
+            None => Err(OpError::NoAttribute),
+        }
+    }
+
+    /// The names of the extended attributes of `ino`.
+    pub fn xattr_names(&self, ino: u64) -> Result<Vec<&str>, OpError> {
+        let file = self.get_inode(ino).ok_or(OpError::NotFound)?;
+        Ok(xattrs(file).into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// Where link `ino` points.
+    pub fn link_target(&self, ino: u64) -> Result<&[u8], OpError> {
+        match self.get_inode(ino).ok_or(OpError::NotFound)? {
+            Node::FileNode(file) => match &file.source {
+                Source::Link(target) => Ok(target.as_bytes()),
+                _ => Err(OpError::NotLink),
+            },
+            Node::DirNode(_) => Err(OpError::NotLink),
+        }
+    }
+
+    /// The attributes of what `path` names from the root, following the
+    /// links along the way, for front ends that are asked for paths. A
+    /// link's absolute target is taken from the root of the tree.
+    pub fn resolve(&self, path: &str) -> Result<FileAttr, OpError> {
+        let (root, _) = self.attributes(ROOT_INO)?;
+        // The directories on the way, so that `..` can go back up.
+        let mut walked = vec![root];
+        let mut names: Vec<String> = (path.split('/').rev())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        let mut links = 0;
+        while let Some(name) = names.pop() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if walked.len() > 1 {
+                        walked.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let (attr, _) = self.find(walked.last().unwrap().ino, OsStr::new(&name))?;
+            if attr.kind != FileType::Symlink {
+                walked.push(attr);
+                continue;
+            }
+            links += 1;
+            if links > MAX_LINKS {
+                return Err(OpError::NotFound);
+            }
+            let target = String::from_utf8_lossy(self.link_target(attr.ino)?).into_owned();
+            if target.starts_with('/') {
+                walked.truncate(1);
+            }
+            let target = target.split('/').rev().filter(|name| !name.is_empty());
+            names.extend(target.map(str::to_string));
+        }
+        Ok(*walked.last().unwrap())
+    }
+
+    /// What directory `ino` holds, `.` and `..` first, each as its inode,
+    /// name and type.
+    pub fn list(&self, ino: u64) -> Result<Vec<(u64, &OsStr, FileType)>, OpError> {
+        let _span = info_span!("readdir", otel.kind = "server", lhttpfs.inode = ino).entered();
+        match self.get_inode(ino).ok_or(OpError::NotFound)? {
+            Node::DirNode(dir) => {
+                let dots = [
+                    (ino, OsStr::new("."), FileType::Directory),
+                    (ino, OsStr::new(".."), FileType::Directory),
+                ];
+                let contents = dir.contents.iter().filter_map(|(filename, inode)| {
+                    self.get_inode(*inode)
+                        .map(|file| (*inode, filename.as_os_str(), file.filetype()))
+                });
+                Ok(dots.into_iter().chain(contents).collect())
+            }
+            Node::FileNode(file_node) => {
+                error!(
+                    "Inode {}, source {} was erroneously used in readdir() as a parent directory",
+                    ino, file_node.source
+                );
+                Err(OpError::NotFound)
+            }
+        }
+    }
+
+    /// Opens `ino`, for writing too if `write`, which only the writable
+    /// control files may be. Generated files are generated anew.
+    pub fn open_file(&mut self, ino: u64, write: bool) -> Result<Opened, OpError> {
+        let _span = info_span!("open", otel.kind = "server", lhttpfs.inode = ino).entered();
+        let control = control_file(self.get_inode(ino));
+        if write {
+            match control {
+                Some(file) if self.control.denied.contains(&file) => {
+                    return Err(OpError::NotPermitted)
+                }
+                Some(file) if file.writable() => {}
+                Some(_) => return Err(OpError::Denied),
+                None => return Err(OpError::ReadOnly),
+            }
+        }
+        self.regenerate(ino);
+        if let Err(e) = self.probe(ino) {
+            warn!("Finding the size of inode {} failed: {}", ino, e);
+            return Err(OpError::Failed);
+        }
+        if let (Some(mount), Some(Node::FileNode(_))) = (&mut self.hooks, node(&self.nodes, ino)) {
+            mount.opened(ino);
+        }
+        match self.get_inode(ino) {
+            // Without a size nothing would ever ask for any bytes.
+            Some(file) => {
+                let direct = file.size_unknown();
+                self.handles += 1;
+                Ok(Opened {
+                    direct,
+                    fh: self.handles,
+                })
+            }
+            None => Err(OpError::NotFound),
+        }
+    }
+
+    /// Generates `ino` anew if it is a generated file, learning its size.
+    pub fn regenerate(&mut self, ino: u64) {
+        if let Some(Node::FileNode(file)) = self.get_inode(ino) {
+            match file.source {
+                Source::Manifest => {
+                    self.manifest = self.manifest();
+                    learn_size(&mut self.nodes, ino, Some(self.manifest.len() as u64));
+                }
+                Source::Control(ControlFile::Stats) => {
+                    self.control.stats = self.stats();
+                    learn_size(&mut self.nodes, ino, Some(self.control.stats.len() as u64));
+                }
+                Source::Control(ControlFile::Health) => {
+                    self.control.health = HEALTH.report();
+                    learn_size(&mut self.nodes, ino, Some(self.control.health.len() as u64));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads `size` bytes of `ino` from `offset`, counting, logging and
+    /// tracing the read.
+    pub fn read_file(&mut self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, OpError> {
+        self.read_since(ino, offset, size, Instant::now(), false)
+    }
+
+    /// [`LazyHTTPFS::read_file`] of a read that started at `start`, and
+    /// already `fetched` what it was missing if so.
+    pub(super) fn read_since(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        size: u32,
+        start: Instant,
+        fetched: bool,
+    ) -> Result<Vec<u8>, OpError> {
+        let span = info_span!(
+            "read",
+            otel.kind = "server",
+            lhttpfs.inode = ino,
+            lhttpfs.offset = offset,
+            lhttpfs.length = size,
+            lhttpfs.bytes = Empty,
+            lhttpfs.cache = Empty,
+            otel.status_message = Empty,
+        )
+        .entered();
+        let cache = self.cache.clone();
+        let mut cache = cache.lock().unwrap();
+        let inserted = cache.inserted();
+        let data = self.read_cached(&mut cache, ino, offset, size);
+        let fetched = fetched || cache.inserted() > inserted;
+        drop(cache);
+        let data = match data {
+            Ok(Some(data)) => Ok(data),
+            Ok(None) => Err(OpError::NotFound),
+            Err(e) => {
+                warn!("Reading inode {} failed: {}", ino, e);
+                *self.control.read_errors.entry(ino).or_default() += 1;
+                Err(OpError::fetching(&e))
+            }
+        };
+        let latency = start.elapsed();
+        METRICS.read(data.as_ref().ok().map(Vec::len), fetched, latency);
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let access = Access {
+            inode: ino,
+            offset,
+            length: size,
+            bytes: data.as_ref().map_or(0, Vec::len),
+            cache: if fetched { "miss" } else { "hit" },
+            status: match data {
+                Ok(_) => "ok",
+                Err(OpError::NotFound) => "ENOENT",
+                Err(OpError::Refused) => "EACCES",
+                Err(OpError::TimedOut) => "ETIMEDOUT",
+                Err(_) => "EIO",
+            },
+            latency_ms,
+        };
+        debug!(
+            op = "read",
+            inode = ino,
+            offset,
+            length = size,
+            bytes = access.bytes,
+            latency_ms,
+            cache = access.cache,
+            status = access.status,
+            "Read {} bytes of inode {} at {}",
+            access.bytes,
+            ino,
+            offset
+        );
+        span.record("lhttpfs.bytes", access.bytes);
+        span.record("lhttpfs.cache", access.cache);
+        if data.is_err() {
+            span.record("otel.status_message", access.status);
+        }
+        if let Some(log) = &self.access_log {
+            if let Err(e) = log.record(&access) {
+                warn!("Writing the access log failed: {}", e);
+            }
+        }
+        (self.observers).each(|observer| observer.on_read(&access));
+        data
+    }
+
+    // Only the control files can be written to, and writing anything at all
+    // is what does something. Everything else is read-only, though the mount
+    // can't say so without the kernel refusing those writes too.
+
+    /// Writes `data` to `ino`.
+    pub fn write_file(&mut self, ino: u64, data: &[u8]) -> Result<(), OpError> {
+        match control_file(self.get_inode(ino)) {
+            Some(file) if self.control.denied.contains(&file) => {
+                warn!("Refused a write to the control file {:?}", file);
+                Err(OpError::NotPermitted)
+            }
+            Some(file) if file.writable() => self.control(file, data).map_err(|e| {
+                warn!("Writing to the control file {:?} failed: {}", file, e);
+                OpError::Failed
+            }),
+            Some(_) => Err(OpError::Denied),
+            None => Err(OpError::ReadOnly),
+        }
+    }
+
+    /// Truncates `ino`, returning its attributes. Truncating, as `echo 1 >`
+    /// does on the way to writing, is let be for the writable control
+    /// files and refused for anything else.
+    pub fn truncate(&self, ino: u64) -> Result<(FileAttr, Duration), OpError> {
+        match self.get_inode(ino) {
+            Some(file) if control_file(Some(file)).is_some_and(ControlFile::writable) => {
+                Ok((file.get_attr(), file.attr_ttl(self.attr_ttl)))
+            }
+            Some(_) => Err(OpError::ReadOnly),
+            None => Err(OpError::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, sync::Arc};
+
+    use super::{OpError, Opened};
+    use crate::{
+        fetch::MemoryFetcher,
+        fs::LazyHTTPFS,
+        layout::{self, Limits},
+    };
+
+    #[test]
+    fn operations() {
+        let layout = r#"[{"name": "d", "contents": [
+            {"name": "a", "content": "abc", "content_type": "text/plain"}
+        ]}]"#;
+        let mut fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        fs.add_control();
+        let (dir, _) = fs.find(1, OsStr::new("d")).unwrap();
+        let (file, _) = fs.find(dir.ino, OsStr::new("a")).unwrap();
+        assert_eq!(fs.find(dir.ino, OsStr::new("b")), Err(OpError::NotFound));
+        assert_eq!(fs.find(file.ino, OsStr::new("a")), Err(OpError::NotFound));
+        let names: Vec<_> = (fs.list(dir.ino).unwrap().into_iter())
+            .map(|(_, name, _)| name)
+            .collect();
+        assert_eq!(names, [".", "..", "a"]);
+        let opened = |fh| Ok(Opened { direct: false, fh });
+        assert_eq!(fs.open_file(file.ino, false), opened(1));
+        assert_eq!(fs.open_file(file.ino, false), opened(2));
+        assert_eq!(fs.open_file(file.ino, true), Err(OpError::ReadOnly));
+        assert_eq!(fs.read_file(file.ino, 1, 10).unwrap(), b"bc");
+        assert_eq!(fs.write_file(file.ino, b"x"), Err(OpError::ReadOnly));
+        assert_eq!(fs.truncate(file.ino).err(), Some(OpError::ReadOnly));
+        let mime = fs.xattr(file.ino, OsStr::new("user.mime_type"));
+        assert_eq!(mime, Ok(&b"text/plain"[..]));
+        assert_eq!(fs.xattr_names(dir.ino), Ok(Vec::new()));
+        assert_eq!(fs.attributes(0).err(), Some(OpError::NotFound));
+    }
+
+    #[test]
+    fn failed_reads() {
+        let layout = r#"[
+            {"name": "gone", "url": "mem://gone", "size": 3},
+            {"name": "zst", "url": "mem://zst", "size": 3, "decompress": "zstd"},
+            {"name": "sealed", "url": "mem://here", "size": 3,
+             "decrypt": {"key_file": "/nonexistent/lhttpfs.key"}},
+            {"name": "filtered", "url": "mem://here", "size": 3, "filter": "exit 1"},
+            {"name": "here", "url": "mem://here", "size": 3}
+        ]"#;
+        let origin = MemoryFetcher::new()
+            .with("mem://zst", "not zstd")
+            .with("mem://here", "abc");
+        let mut fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("mem", Arc::new(origin))
+            .limits(Limits {
+                filters: true,
+                ..Limits::default()
+            })
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let ino = |fs: &LazyHTTPFS, name: &str| fs.find(1, OsStr::new(name)).unwrap().0.ino;
+        // A 404, bytes that don't decompress or decrypt and a failing
+        // filter fail the read, not the mount.
+        assert_eq!(fs.read_file(ino(&fs, "gone"), 0, 3), Err(OpError::NotFound));
+        for name in ["zst", "sealed", "filtered"] {
+            assert_eq!(fs.read_file(ino(&fs, name), 0, 3), Err(OpError::Failed));
+        }
+        let here = ino(&fs, "here");
+        assert_eq!(fs.read_file(here, 0, 3).unwrap(), b"abc");
+    }
+}
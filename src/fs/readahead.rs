@@ -0,0 +1,134 @@
+//! [`Readahead`]: fetching ahead of reads that go through a file in order,
+//! as copying it, unpacking a tarball from it or playing it does, so that
+//! the next reads find what they want cached instead of each waiting on a
+//! fetch of its own. Reads out of order fetch nothing ahead, and a seek or
+//! closing the file gives up on what was still being fetched. Each open
+//! file handle is followed on its own, so two programs reading the same
+//! file don't take each other's reads for seeks.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use super::stream::Ahead;
+
+/// How far ahead of the reads of each file handle it is fetched.
+#[derive(Debug)]
+pub(super) struct Readahead {
+    window: u64,
+    handles: HashMap<u64, Sequence>,
+}
+
+#[derive(Debug)]
+struct Sequence {
+    /// Where the last read started and ended.
+    read: (u64, u64),
+    /// The end of what was fetched ahead.
+    fetched_to: u64,
+    /// Set once a seek makes what is being fetched ahead pointless.
+    abandon: Arc<AtomicBool>,
+}
+
+impl Readahead {
+    pub fn new(window: u64) -> Readahead {
+        Readahead {
+            window,
+            handles: HashMap::new(),
+        }
+    }
+
+    /// What a read of `len` bytes from `offset` through handle `fh` of a
+    /// file `size` bytes long fetches ahead, if it carries on from the read
+    /// before it. So that each read doesn't fetch a sliver of its own, more
+    /// is only fetched once less than half the window is left ahead.
+    pub fn read(&mut self, fh: u64, size: u64, offset: u64, len: u64) -> Option<Ahead> {
+        let end = offset.saturating_add(len).min(size);
+        let Some(sequence) = self.handles.get_mut(&fh) else {
+            self.handles.insert(
+                fh,
+                Sequence {
+                    read: (offset, end),
+                    fetched_to: 0,
+                    abandon: Arc::default(),
+                },
+            );
+            return None;
+        };
+        let (last_start, last_end) = sequence.read;
+        sequence.read = (offset, end);
+        // Reads just behind the last one end, or overlapping it, as the
+        // kernel's own readahead and parallel readers leave them.
+        if !(last_start..=last_end).contains(&offset) {
+            sequence.abandon.store(true, Ordering::Relaxed);
+            sequence.abandon = Arc::default();
+            sequence.fetched_to = 0;
+            return None;
+        }
+        let from = sequence.fetched_to.max(end);
+        let to = end.saturating_add(self.window).min(size);
+        if from >= to || to - from < self.window / 2 && to < size {
+            return None;
+        }
+        sequence.fetched_to = to;
+        Some(Ahead {
+            fetch: vec![(from, to - from)],
+            drop: Vec::new(),
+            abandon: sequence.abandon.clone(),
+        })
+    }
+
+    /// Forgets handle `fh`, once the file is closed, giving up on what is
+    /// still being fetched ahead for it. What was fetched stays cached.
+    pub fn release(&mut self, fh: u64) {
+        if let Some(sequence) = self.handles.remove(&fh) {
+            sequence.abandon.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use super::Readahead;
+
+    #[test]
+    fn sequential() {
+        let mut readahead = Readahead::new(100);
+        let mut fetched = |ino, size, offset, len| {
+            let ahead = readahead.read(ino, size, offset, len)?;
+            Some((ahead.fetch, ahead.abandon))
+        };
+        let fetch = |fetched: Option<(Vec<_>, _)>| fetched.map(|(fetch, _)| fetch);
+        // Nothing comes before the first read to follow on from.
+        assert_eq!(fetch(fetched(1, 1000, 0, 10)), None);
+        assert_eq!(fetch(fetched(1, 1000, 10, 10)), Some(vec![(20, 100)]));
+        // Only once half of the window is used up.
+        assert_eq!(fetch(fetched(1, 1000, 20, 40)), None);
+        let (ahead, abandon) = fetched(1, 1000, 60, 10).unwrap();
+        assert_eq!(ahead, [(120, 50)]);
+        // A seek gives up on what is ahead, and fetches nothing itself.
+        assert_eq!(fetch(fetched(1, 1000, 500, 10)), None);
+        assert!(abandon.load(Ordering::Relaxed));
+        assert_eq!(fetch(fetched(1, 1000, 510, 10)), Some(vec![(520, 100)]));
+        // Nothing past the end, but up to it even if that is close.
+        assert_eq!(fetch(fetched(1, 1000, 520, 480)), None);
+        assert_eq!(fetch(fetched(2, 50, 0, 10)), None);
+        assert_eq!(fetch(fetched(2, 50, 10, 10)), Some(vec![(20, 30)]));
+    }
+
+    #[test]
+    fn released() {
+        let mut readahead = Readahead::new(100);
+        readahead.read(1, 1000, 0, 10);
+        let ahead = readahead.read(1, 1000, 10, 10).unwrap();
+        readahead.release(1);
+        assert!(ahead.abandon.load(Ordering::Relaxed));
+        // Opened again, it starts over.
+        assert!(readahead.read(1, 1000, 20, 10).is_none());
+    }
+}
@@ -0,0 +1,168 @@
+//! [`Streaming`]: reads tuned for playing media straight from a mount, for
+//! `--profile streaming`. Files are fetched in small blocks, far ahead of
+//! where a player reads them, and only a few blocks behind it are kept;
+//! once it seeks or closes the file, what was still being fetched ahead is
+//! given up on. Each open file handle is a stream of its own.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// How far ahead of a player, and behind it, a file is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Streaming {
+    /// The size of the blocks files are fetched in.
+    pub block: u64,
+    pub ahead: u64,
+    pub behind: u64,
+}
+
+impl Streaming {
+    /// The `--profile` that streams.
+    pub const PROFILE: &'static str = "streaming";
+}
+
+impl Default for Streaming {
+    fn default() -> Streaming {
+        Streaming {
+            block: 1 << 20,
+            ahead: 32 << 20,
+            behind: 4 << 20,
+        }
+    }
+}
+
+/// Where each open file handle is being read.
+#[derive(Debug, Default)]
+pub(super) struct Streams {
+    streaming: Streaming,
+    handles: HashMap<u64, Stream>,
+}
+
+#[derive(Debug)]
+struct Stream {
+    /// What of the file is kept: from the first byte still cached to the
+    /// end of what was fetched ahead.
+    kept: (u64, u64),
+    /// Set once a seek makes what is being fetched ahead pointless.
+    abandon: Arc<AtomicBool>,
+}
+
+/// What a read of a streamed file asks for: `fetch`, ranges to fetch
+/// ahead, giving up once `abandon` is set, and ranges to `drop` from the
+/// cache.
+#[derive(Debug)]
+pub(super) struct Ahead {
+    pub fetch: Vec<(u64, u64)>,
+    pub drop: Vec<(u64, u64)>,
+    pub abandon: Arc<AtomicBool>,
+}
+
+impl Streams {
+    pub fn new(streaming: Streaming) -> Streams {
+        Streams {
+            streaming,
+            handles: HashMap::new(),
+        }
+    }
+
+    pub fn block(&self) -> u64 {
+        self.streaming.block
+    }
+
+    /// Where a read of `len` bytes from `offset` through handle `fh` of a
+    /// file `size` bytes long leaves its stream, and what that asks for.
+    pub fn read(&mut self, fh: u64, size: u64, offset: u64, len: u64) -> Ahead {
+        let Streaming { ahead, behind, .. } = self.streaming;
+        let end = offset.saturating_add(len).min(size);
+        let window = (
+            offset.saturating_sub(behind),
+            end.saturating_add(ahead).min(size),
+        );
+        let stream = self.handles.entry(fh).or_insert_with(|| Stream {
+            kept: (offset, offset),
+            abandon: Arc::default(),
+        });
+        let (kept_from, fetched_to) = stream.kept;
+        let mut drop = Vec::new();
+        // Reading just behind, or within what was fetched ahead, carries on.
+        let fetch_from = if (kept_from..=fetched_to).contains(&offset) {
+            if kept_from < window.0 {
+                drop.push((kept_from, window.0));
+            }
+            fetched_to.max(end)
+        } else {
+            // A seek, past what is kept or back before it.
+            stream.abandon.store(true, Ordering::Relaxed);
+            stream.abandon = Arc::default();
+            let outside = [
+                (kept_from, window.0.min(fetched_to)),
+                (window.1.max(kept_from), fetched_to),
+            ];
+            drop.extend(outside.into_iter().filter(|(from, to)| from < to));
+            end
+        };
+        let fetch = (fetch_from < window.1).then_some((fetch_from, window.1 - fetch_from));
+        stream.kept = (window.0, window.1.max(fetch_from));
+        Ahead {
+            fetch: fetch.into_iter().collect(),
+            drop,
+            abandon: stream.abandon.clone(),
+        }
+    }
+
+    /// Forgets handle `fh`, once the file is closed, giving up on what is
+    /// still being fetched ahead for it.
+    pub fn release(&mut self, fh: u64) {
+        if let Some(stream) = self.handles.remove(&fh) {
+            stream.abandon.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use super::{Streaming, Streams};
+
+    #[test]
+    fn ahead_and_seeks() {
+        let mut streams = Streams::new(Streaming {
+            block: 10,
+            ahead: 100,
+            behind: 20,
+        });
+        let size = 1000;
+        let first = streams.read(1, size, 0, 10);
+        assert_eq!((first.fetch, first.drop), (vec![(10, 100)], vec![]));
+        // Carrying on only fetches what is newly in reach, and drops what
+        // is out of it behind.
+        let next = streams.read(1, size, 10, 40);
+        assert_eq!((next.fetch, next.drop), (vec![(110, 40)], vec![]));
+        let next = streams.read(1, size, 50, 10);
+        assert_eq!((next.fetch, next.drop), (vec![(150, 10)], vec![(0, 30)]));
+        assert!(!first.abandon.load(Ordering::Relaxed));
+        // A seek gives up on what was ahead and drops what isn't in reach.
+        let seek = streams.read(1, size, 500, 10);
+        assert!(first.abandon.load(Ordering::Relaxed));
+        assert_eq!((seek.fetch, seek.drop), (vec![(510, 100)], vec![(30, 160)]));
+        // Back within what is kept, nothing is given up on.
+        let back = streams.read(1, size, 505, 5);
+        assert_eq!((back.fetch, back.drop), (vec![], vec![(480, 485)]));
+        assert!(!seek.abandon.load(Ordering::Relaxed));
+        let end = streams.read(1, size, 990, 100);
+        assert_eq!((end.fetch, end.drop), (vec![], vec![(485, 610)]));
+        assert!(back.abandon.load(Ordering::Relaxed));
+        // Other handles are streams of their own, and closing one ends it.
+        let other = streams.read(2, 50, 0, 10);
+        assert_eq!(other.fetch, [(10, 40)]);
+        streams.release(2);
+        assert!(other.abandon.load(Ordering::Relaxed));
+        assert!(!end.abandon.load(Ordering::Relaxed));
+    }
+}
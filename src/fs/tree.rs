@@ -0,0 +1,233 @@
+//! [`RemoteTree`]: a resolved layout read by path, in-process, through the
+//! same cache and backends as a mount but without FUSE.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use super::{lock, ops::OpError, Entry, LazyHTTPFS, Node, ROUNDS};
+use crate::LhttpfsError;
+
+/// A [`LazyHTTPFS`] to open files of and list, from any number of threads.
+/// What a read is missing is fetched with the tree unlocked, so reads of
+/// what is cached, listings and other fetches go on in the meantime.
+#[derive(Clone)]
+pub struct RemoteTree {
+    fs: Arc<Mutex<LazyHTTPFS>>,
+}
+
+impl RemoteTree {
+    pub fn new(fs: LazyHTTPFS) -> RemoteTree {
+        RemoteTree {
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// Opens the file at `path`, from the root.
+    pub fn open(&self, path: &str) -> Result<RemoteFile, LhttpfsError> {
+        let mut fs = lock(&self.fs);
+        let ino = resolve(&fs, path)?;
+        if let Some(Node::DirNode(_)) = fs.get_inode(ino) {
+            let error = format!("{} is a directory", path);
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, error).into());
+        }
+        fs.open_file(ino, false).map_err(|e| failed(e, path))?;
+        Ok(RemoteFile {
+            tree: self.clone(),
+            ino,
+            position: 0,
+        })
+    }
+
+    /// What the directory at `path` holds, in name order, each entry one
+    /// deeper than the directory.
+    pub fn list(&self, path: &str) -> Result<Vec<Entry>, LhttpfsError> {
+        let fs = lock(&self.fs);
+        let ino = resolve(&fs, path)?;
+        let depth = path.split('/').filter(|name| !name.is_empty()).count() + 1;
+        let listed = fs.list(ino).map_err(|e| failed(e, path))?;
+        let dir = Path::new("/").join(path);
+        let mut entries = (listed.into_iter().skip(2))
+            .filter_map(|(ino, name, _)| {
+                let node = fs.get_inode(ino)?;
+                Some(Entry {
+                    depth,
+                    name: name.to_owned(),
+                    path: dir.join(name),
+                    attr: node.get_attr(),
+                    source: match node {
+                        Node::DirNode(_) => None,
+                        Node::FileNode(file) => Some(file.source.to_string()),
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Reads up to `len` bytes of `ino` from `offset`, fewer at the end.
+    fn read(&self, ino: u64, offset: u64, len: u32) -> Result<Vec<u8>, LhttpfsError> {
+        let start = Instant::now();
+        let mut fetched = false;
+        let mut held = Vec::new();
+        let mut warmed = Ok(());
+        for _ in 0..ROUNDS {
+            let misses = lock(&self.fs).misses(ino, offset as i64, len);
+            if misses.is_empty() {
+                break;
+            }
+            fetched = true;
+            warmed = misses.into_iter().try_for_each(|miss| {
+                held.extend(miss.warm(&self.fs)?);
+                Ok(())
+            });
+            if warmed.is_err() {
+                break;
+            }
+        }
+        let mut fs = lock(&self.fs);
+        let data = warmed.and_then(|()| {
+            let data = fs.read_since(ino, offset as i64, len, start, fetched);
+            data.map_err(|e| failed(e, &format!("inode {}", ino)))
+        });
+        let mut cache = fs.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        for key in held {
+            cache.release(&key);
+        }
+        data
+    }
+}
+
+/// The inode of what `path` names, from the root, following links.
+fn resolve(fs: &LazyHTTPFS, path: &str) -> Result<u64, LhttpfsError> {
+    let attr = fs.resolve(path).map_err(|e| failed(e, path))?;
+    Ok(attr.ino)
+}
+
+fn failed(error: OpError, what: &str) -> LhttpfsError {
+    let kind = match error {
+        OpError::NotFound => io::ErrorKind::NotFound,
+        OpError::Refused => io::ErrorKind::PermissionDenied,
+        OpError::TimedOut => io::ErrorKind::TimedOut,
+        OpError::NotLink => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{}: {:?}", what, error)).into()
+}
+
+/// A file of a [`RemoteTree`], read at any offset with
+/// [`RemoteFile::read_at`] or from where [`Seek`] put it with [`Read`].
+pub struct RemoteFile {
+    tree: RemoteTree,
+    ino: u64,
+    position: u64,
+}
+
+impl RemoteFile {
+    /// The file's size, once known for files whose size the layout leaves
+    /// to be learned by reading them, 0 until then.
+    pub fn size(&self) -> u64 {
+        let fs = lock(&self.tree.fs);
+        fs.attributes(self.ino).map_or(0, |(attr, _)| attr.size)
+    }
+
+    /// Reads up to `len` bytes from `offset`, fewer at the end of the file.
+    pub fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>, LhttpfsError> {
+        let len = len.min(u32::MAX as usize) as u32;
+        self.tree.read(self.ino, offset, len)
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = match self.read_at(self.position, buf.len()) {
+            Err(LhttpfsError::Io(e)) => return Err(e),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+            Ok(data) => data,
+        };
+        buf[..data.len()].copy_from_slice(&data);
+        self.position += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let position = match to {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Seeking before the start");
+        self.position = position.ok_or_else(invalid)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        sync::Arc,
+    };
+
+    use super::RemoteTree;
+    use crate::{fetch::MemoryFetcher, fs::LazyHTTPFS, layout, LhttpfsError};
+
+    #[test]
+    fn read_in_process() {
+        let layout = r#"[
+            {"name": "d", "contents": [
+                {"name": "b.txt", "url": "mem://b", "size": 5},
+                {"name": "a.txt", "content": "inline"}
+            ]},
+            {"name": "gone", "url": "mem://gone", "size": 3}
+        ]"#;
+        let origin = MemoryFetcher::new().with("mem://b", "hello");
+        let fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher("mem", Arc::new(origin))
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        let tree = RemoteTree::new(fs);
+        let names = |path| {
+            let entries = tree.list(path).unwrap();
+            let names = entries.iter().map(|e| e.name.to_str().unwrap().to_owned());
+            names.collect::<Vec<_>>()
+        };
+        assert_eq!(names("/"), ["d", "gone"]);
+        assert_eq!(names("d/"), ["a.txt", "b.txt"]);
+        let listed = tree.list("/d").unwrap();
+        assert_eq!(
+            (listed[1].depth, listed[1].source.as_deref()),
+            (2, Some("mem://b"))
+        );
+        assert_eq!(listed[1].path, std::path::Path::new("/d/b.txt"));
+
+        let mut file = tree.open("/d/b.txt").unwrap();
+        assert_eq!(file.size(), 5);
+        assert_eq!(file.read_at(1, 3).unwrap(), b"ell");
+        assert_eq!(file.read_at(3, 10).unwrap(), b"lo");
+        file.seek(SeekFrom::End(-4)).unwrap();
+        let mut text = String::new();
+        file.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "ello");
+        assert!(file.seek(SeekFrom::Current(-6)).is_err());
+
+        let Err(LhttpfsError::Io(e)) = tree.open("/d/c.txt") else {
+            panic!("Expected no such file");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(tree.open("/d").is_err());
+        // The origin's error, URL and all.
+        let gone = tree.open("/gone").unwrap().read_at(0, 3);
+        let Err(LhttpfsError::Fetch { url, .. }) = gone else {
+            panic!("Expected a failed fetch, got {:?}", gone);
+        };
+        assert_eq!(url, "mem://gone");
+    }
+}
@@ -0,0 +1,212 @@
+//! Checking files against the `sha256` or `md5` of their layout entry,
+//! hashing their bytes as they are read from start to end.
+
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use sha2::{Digest, Sha256};
+
+use crate::layout::hex;
+
+use super::FileNode;
+
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    expected: String,
+    actual: String,
+}
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// How far into a file its bytes have been hashed.
+pub(super) struct Verifier {
+    hashed: u64,
+    hasher: Hasher,
+}
+
+enum Hasher {
+    Sha256(Sha256, String),
+    Md5(Md5, String),
+}
+
+impl Verifier {
+    /// A verifier for `file`'s sha256, or its md5 without one. Only files
+    /// served as fetched can be checked, since the layout's checksum is of
+    /// what the URL serves.
+    fn new(file: &FileNode) -> Option<Verifier> {
+        let as_fetched = file.decompress.is_none()
+            && file.decrypt.is_none()
+            && file.filter.is_none()
+            && file.pieces.is_none();
+        let hasher = match (&file.sha256, &file.md5) {
+            _ if !as_fetched => return None,
+            (Some(sha256), _) => Hasher::Sha256(Sha256::new(), sha256.clone()),
+            (None, Some(md5)) => Hasher::Md5(Md5::new(), md5.clone()),
+            (None, None) => return None,
+        };
+        Some(Verifier { hashed: 0, hasher })
+    }
+
+    fn finish(self) -> Result<(), ChecksumMismatch> {
+        let (name, actual, expected) = match self.hasher {
+            Hasher::Sha256(hasher, expected) => ("sha256", hex(&hasher.finalize()), expected),
+            Hasher::Md5(hasher, expected) => ("md5", hex(&hasher.finish()), expected),
+        };
+        if actual == expected {
+            return Ok(());
+        }
+        Err(ChecksumMismatch {
+            expected: format!("{}:{}", name, expected),
+            actual: format!("{}:{}", name, actual),
+        })
+    }
+}
+
+/// Hashes `data`, the bytes of file `ino` from `at`, if they carry on from
+/// what of it was hashed before, and checks the checksum once the end of
+/// the file is reached, which `whole` says `data` is all of. A file that
+/// passed, or has nothing to check, is `None` in `verifying` from then on;
+/// one that failed is left out, to be hashed again from the start.
+pub(super) fn check(
+    verifying: &mut HashMap<u64, Option<Verifier>>,
+    ino: u64,
+    file: &FileNode,
+    at: u64,
+    data: &[u8],
+    whole: bool,
+) -> Result<(), ChecksumMismatch> {
+    let entry = verifying.entry(ino).or_insert_with(|| Verifier::new(file));
+    let Some(verifier) = entry.as_mut() else {
+        return Ok(());
+    };
+    if at != verifier.hashed {
+        return Ok(());
+    }
+    match &mut verifier.hasher {
+        Hasher::Sha256(hasher, _) => hasher.update(data),
+        Hasher::Md5(hasher, _) => hasher.update(data),
+    }
+    verifier.hashed += data.len() as u64;
+    if !whole && verifier.hashed < file.attr.size {
+        return Ok(());
+    }
+    let verifier = entry.take().unwrap();
+    let result = verifier.finish();
+    if result.is_err() {
+        verifying.remove(&ino);
+    }
+    result
+}
+
+/// MD5, which some catalogs still give as the only checksum, as RFC 1321
+/// describes it.
+struct Md5 {
+    state: [u32; 4],
+    block: Vec<u8>,
+    length: u64,
+}
+
+const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+impl Md5 {
+    fn new() -> Md5 {
+        Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            block: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.block.is_empty() {
+            let take = data.len().min(64 - self.block.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.block);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.block.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self) -> [u8; 16] {
+        let bits = self.length.wrapping_mul(8);
+        let mut tail = std::mem::take(&mut self.block);
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&bits.to_le_bytes());
+        for block in tail.chunks_exact(64) {
+            self.compress(block);
+        }
+        let mut digest = [0; 16];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let words: Vec<u32> = (block.chunks_exact(4))
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let k = ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32;
+            let f = f.wrapping_add(a).wrapping_add(k).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (state, word) in self.state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(word);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::hex;
+
+    use super::Md5;
+
+    #[test]
+    fn md5() {
+        let digest = |parts: &[&[u8]]| {
+            let mut md5 = Md5::new();
+            for part in parts {
+                md5.update(part);
+            }
+            hex(&md5.finish())
+        };
+        assert_eq!(digest(&[]), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(digest(&[b"abc"]), "900150983cd24fb0d6963f7d28e17f72");
+        let long =
+            b"12345678901234567890123456789012345678901234567890123456789012345678901234567890";
+        assert_eq!(digest(&[long]), "57edf4a22be3c955ac49da2e2107b67a");
+        assert_eq!(
+            digest(&[&long[..30], &long[30..63], &long[63..]]),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+}
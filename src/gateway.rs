@@ -0,0 +1,99 @@
+//! `serve-http`: the tree a mount would show, served over plain HTTP
+//! with ranges and directory listings, which makes lhttpfs a caching
+//! proxy in front of every backend it knows, for anything that can fetch
+//! a URL and needs no FUSE.
+
+use std::net::{SocketAddr, TcpListener};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use lhttpfs::fs::FileType;
+
+use crate::{
+    fetch,
+    fs::LazyHTTPFS,
+    inspect, prefetch,
+    server::{self, resolve, Request, Response},
+};
+
+pub fn command() -> Command {
+    Command::new("serve-http")
+        .about("Serve layouts over plain HTTP, with ranges and directory listings")
+        .args(inspect::load_args())
+        .arg(prefetch::cache_dir_arg())
+        .arg(prefetch::cache_size_arg())
+        .arg(fetch::plugin::arg())
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .value_parser(value_parser!(SocketAddr))
+                .default_value("127.0.0.1:8080")
+                .help("Address to serve HTTP at"),
+        )
+}
+
+/// Serves `fs` until the process is stopped.
+pub fn run(fs: LazyHTTPFS, matches: &ArgMatches) -> crate::Result<()> {
+    let addr = matches.get_one::<SocketAddr>("listen").unwrap();
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("Couldn't listen on {}: {}", addr, e))?;
+    eprintln!("Serving HTTP at http://{}/", addr);
+    server::serve(listener, fs, handle);
+    Ok(())
+}
+
+fn handle(fs: &mut LazyHTTPFS, request: &Request) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::status("405 Method Not Allowed").header("Allow", "GET, HEAD");
+    }
+    match resolve(fs, &request.path) {
+        Ok(attr) if attr.kind == FileType::Directory => server::listing(fs, request, &attr),
+        Ok(attr) => server::get(fs, request, &attr),
+        Err(e) => Response::status(server::status(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{fs::LazyHTTPFS, layout, server};
+
+    #[test]
+    fn served() {
+        let layout = r#"[{"name": "d", "contents": [
+            {"name": "b", "content": "abcdef"},
+            {"name": "a <1>", "content": "xyz"}
+        ]}]"#;
+        let fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server::serve(listener, fs, super::handle));
+
+        // All three go over one connection.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let requests = "GET /d HTTP/1.1\r\n\r\nHEAD /d/b HTTP/1.1\r\nRange: bytes=-2\r\n\r\n\
+                        GET /d/ HTTP/1.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(requests.as_bytes()).unwrap();
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).unwrap();
+        let mut responses = responses.split("HTTP/1.1 ").skip(1);
+        let moved = responses.next().unwrap();
+        assert!(moved.starts_with("301 Moved Permanently\r\n"));
+        assert!(moved.contains("Location: /d/\r\n"));
+        let head = responses.next().unwrap();
+        assert!(head.starts_with("206 Partial Content\r\n"));
+        assert!(head.contains("Content-Range: bytes 4-5/6\r\n"));
+        assert!(head.contains("Content-Length: 2\r\n") && head.ends_with("\r\n\r\n"));
+        let listing = responses.next().unwrap();
+        assert!(listing.starts_with("200 OK\r\n"));
+        let a = listing
+            .find("<a href=\"a%20%3C1%3E\">a &lt;1&gt;</a>")
+            .unwrap();
+        assert!(a < listing.find("<a href=\"b\">b</a>").unwrap());
+        assert!(listing.contains("<a href=\"../\">"));
+    }
+}
@@ -0,0 +1,262 @@
+//! `generate apt`: the `.deb`s of a Debian repository, together with the
+//! `dists/` indexes that let apt use the mount as a read-only mirror.
+
+use std::collections::HashMap;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    layout::{tree_from_paths, InputFile, URLFile},
+    transform::Compression,
+    Result,
+};
+
+use super::{get, head_size, hex, read_source};
+
+pub fn command() -> Command {
+    Command::new("apt")
+        .about("Emit a layout of a Debian/apt repository")
+        .arg(
+            Arg::new("MIRROR")
+                .required(true)
+                .help("Repository root, such as https://deb.debian.org/debian"),
+        )
+        .arg(
+            Arg::new("dist")
+                .long("dist")
+                .required_unless_present("packages")
+                .help("Distribution to read dists/<dist>/Release from"),
+        )
+        .arg(
+            Arg::new("component")
+                .long("component")
+                .action(ArgAction::Append)
+                .help("Component to include, default all in Release"),
+        )
+        .arg(
+            Arg::new("arch")
+                .long("arch")
+                .action(ArgAction::Append)
+                .help("Architecture to include, default all in Release"),
+        )
+        .arg(
+            Arg::new("packages")
+                .long("packages")
+                .conflicts_with("dist")
+                .help("Read this Packages file instead of walking a distribution"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let mirror = matches
+        .get_one::<String>("MIRROR")
+        .unwrap()
+        .trim_end_matches('/');
+    if let Some(packages) = matches.get_one::<String>("packages") {
+        let text = decode(read_source(packages)?)?;
+        return Ok(tree_from_paths(debs(&text, mirror)));
+    }
+
+    let dist = matches.get_one::<String>("dist").unwrap();
+    let dist_url = format!("{}/dists/{}", mirror, dist);
+    let release = get(&format!("{}/Release", dist_url), &[])?;
+    let release_text = String::from_utf8_lossy(&release).into_owned();
+    let fields = paragraphs(&release_text)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let listed = |field: &str| -> Vec<String> {
+        fields
+            .get(field)
+            .map(|v| v.split_whitespace().map(String::from).collect())
+            .unwrap_or_default()
+    };
+    let selected = |name: &str, field: &str| -> Vec<String> {
+        matches
+            .get_many::<String>(name)
+            .map(|values| values.cloned().collect())
+            .unwrap_or_else(|| listed(field))
+    };
+    let components = selected("component", "Components");
+    let mut arches = selected("arch", "Architectures");
+    if !arches.iter().any(|a| a == "all") {
+        arches.push("all".into());
+    }
+    let prefixes: Vec<_> = components
+        .iter()
+        .flat_map(|c| arches.iter().map(move |a| format!("{}/binary-{}/", c, a)))
+        .collect();
+
+    let mut entries = Vec::new();
+    let mut release_file = URLFile::new("", format!("{}/Release", dist_url), release.len() as u64);
+    release_file.sha256 = Some(hex(&Sha256::digest(&release)));
+    entries.push((format!("dists/{}/Release", dist), release_file));
+    for signature in ["InRelease", "Release.gpg"] {
+        let url = format!("{}/{}", dist_url, signature);
+        if let Ok(Some(size)) = head_size(&url, &[]) {
+            entries.push((
+                format!("dists/{}/{}", dist, signature),
+                URLFile::new("", url, size),
+            ));
+        }
+    }
+
+    let indexes = release_indexes(fields.get("SHA256").map(String::as_str).unwrap_or(""));
+    let mut debs_paths = Vec::new();
+    for prefix in &prefixes {
+        let mut found = false;
+        for (path, size, sha256) in indexes
+            .iter()
+            .filter(|(p, ..)| p.starts_with(prefix.as_str()))
+        {
+            let mut file = URLFile::new("", format!("{}/{}", dist_url, path), *size);
+            file.sha256 = Some(sha256.clone());
+            entries.push((format!("dists/{}/{}", dist, path), file));
+            if !found && (path.ends_with("/Packages.gz") || path.ends_with("/Packages")) {
+                debs_paths.push(format!("{}/{}", dist_url, path));
+                found = true;
+            }
+        }
+        if !found {
+            warn!("No Packages index for {} in {}", prefix, dist_url);
+        }
+    }
+    let mut files: Vec<_> = entries
+        .into_iter()
+        .map(|(path, file)| (path, InputFile::URLFile(file)))
+        .collect();
+    for packages in debs_paths {
+        let text = decode(get(&packages, &[])?)?;
+        files.extend(debs(&text, mirror));
+    }
+    Ok(tree_from_paths(files))
+}
+
+/// Undoes gzip compression of an index, if any.
+fn decode(data: Vec<u8>) -> Result<String> {
+    match data.starts_with(&[0x1f, 0x8b]) {
+        true => Ok(String::from_utf8(Compression::Gzip.decompress(&data)?)?),
+        false => Ok(String::from_utf8(data)?),
+    }
+}
+
+/// Splits a deb822 control file into paragraphs of fields. Continuation
+/// lines are joined to their field with newlines.
+fn paragraphs(text: &str) -> Vec<HashMap<String, String>> {
+    let mut result = Vec::new();
+    let mut current: HashMap<String, String> = HashMap::new();
+    let mut last_key: Option<String> = None;
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                result.push(std::mem::take(&mut current));
+            }
+            last_key = None;
+        } else if line.starts_with([' ', '\t']) {
+            if let Some(value) = last_key.as_ref().and_then(|k| current.get_mut(k)) {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            current.insert(key.to_string(), value.trim().to_string());
+            last_key = Some(key.to_string());
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result
+}
+
+/// The `(path, size, sha256)` lines of a Release file's SHA256 field.
+fn release_indexes(field: &str) -> Vec<(String, u64, String)> {
+    field
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let sha256 = parts.next()?.to_string();
+            let size = parts.next()?.parse().ok()?;
+            Some((parts.next()?.to_string(), size, sha256))
+        })
+        .collect()
+}
+
+/// The packages of a Packages index, keyed by their path in the pool.
+fn debs(text: &str, mirror: &str) -> Vec<(String, InputFile)> {
+    paragraphs(text)
+        .into_iter()
+        .filter_map(|fields| {
+            let path = fields.get("Filename")?;
+            let size = fields.get("Size")?.parse().ok()?;
+            let mut file = URLFile::new("", format!("{}/{}", mirror, path), size);
+            file.sha256 = fields.get("SHA256").cloned();
+            Some((path.clone(), InputFile::URLFile(file)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::{tree_from_paths, InputFile};
+
+    use super::{debs, paragraphs, release_indexes};
+
+    const RELEASE: &str = "Origin: Debian
+Suite: stable
+Components: main contrib
+Architectures: amd64 arm64
+SHA256:
+ 0a1b 1234 main/binary-amd64/Packages.gz
+ 2c3d 5678 main/binary-amd64/Release
+";
+
+    const PACKAGES: &str = "Package: hello
+Version: 2.10-3
+Filename: pool/main/h/hello/hello_2.10-3_amd64.deb
+Size: 53304
+SHA256: 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+Description: example package
+ based on GNU hello
+
+Package: broken
+Version: 1
+";
+
+    #[test]
+    fn release_file() {
+        let fields = paragraphs(RELEASE).remove(0);
+        assert_eq!(fields["Components"], "main contrib");
+        let indexes = release_indexes(&fields["SHA256"]);
+        assert_eq!(
+            indexes[0],
+            ("main/binary-amd64/Packages.gz".into(), 1234, "0a1b".into())
+        );
+    }
+
+    #[test]
+    fn packages_file() {
+        let files = tree_from_paths(debs(PACKAGES, "https://deb.example.org/debian"));
+        let [InputFile::Directory(pool)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        let mut dir = pool;
+        for name in ["main", "h", "hello"] {
+            let [InputFile::Directory(next)] = &dir.contents[..] else {
+                panic!("Unexpected layout {:?}", dir.contents);
+            };
+            assert_eq!(next.name, name);
+            dir = next;
+        }
+        let [InputFile::URLFile(deb)] = &dir.contents[..] else {
+            panic!("Unexpected layout {:?}", dir.contents);
+        };
+        assert_eq!(deb.size, 53304);
+        assert_eq!(
+            deb.url,
+            "https://deb.example.org/debian/pool/main/h/hello/hello_2.10-3_amd64.deb"
+        );
+        assert!(deb.sha256.is_some());
+    }
+}
@@ -0,0 +1,200 @@
+//! `generate feed`: one file per enclosure of an RSS or Atom feed, which is
+//! how podcasts publish their episodes.
+
+use std::collections::HashSet;
+
+use clap::{Arg, ArgMatches, Command};
+use percent_encoding::percent_decode_str;
+use roxmltree::{Document, Node};
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    layout::{InputFile, URLFile},
+    Result,
+};
+
+use super::read_source;
+
+pub fn command() -> Command {
+    Command::new("feed")
+        .about("Emit a layout of the enclosures of an RSS/Atom feed")
+        .arg(
+            Arg::new("FEED")
+                .required(true)
+                .help("URL or path of the feed"),
+        )
+        .arg(
+            Arg::new("names")
+                .long("names")
+                .value_parser(["url", "title"])
+                .default_value("url")
+                .help("Name files after the enclosure URL or the item title"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let feed = read_source(matches.get_one::<String>("FEED").unwrap())?;
+    let by_title = matches.get_one::<String>("names").unwrap() == "title";
+    parse(&String::from_utf8_lossy(&feed), by_title)
+}
+
+struct Enclosure {
+    title: Option<String>,
+    url: String,
+    size: u64,
+}
+
+fn parse(feed: &str, by_title: bool) -> Result<Vec<InputFile>> {
+    let document = Document::parse(feed)?;
+    let mut enclosures = Vec::new();
+    for item in document.descendants() {
+        let title = || child_text(item, "title");
+        match item.tag_name().name() {
+            "item" => {
+                for enclosure in item.children().filter(|c| c.has_tag_name("enclosure")) {
+                    let Some(url) = enclosure.attribute("url") else {
+                        continue;
+                    };
+                    enclosures.push(Enclosure {
+                        title: title(),
+                        url: url.to_string(),
+                        size: length(enclosure, url),
+                    });
+                }
+            }
+            "entry" => {
+                for link in item
+                    .children()
+                    .filter(|c| c.has_tag_name("link") && c.attribute("rel") == Some("enclosure"))
+                {
+                    let Some(url) = link.attribute("href") else {
+                        continue;
+                    };
+                    enclosures.push(Enclosure {
+                        title: title(),
+                        url: url.to_string(),
+                        size: length(link, url),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut seen = HashSet::new();
+    Ok(enclosures
+        .into_iter()
+        .map(|enclosure| {
+            let from_url = url_file_name(&enclosure.url);
+            let name = match (&enclosure.title, by_title) {
+                (Some(title), true) => {
+                    let extension = from_url.rsplit_once('.').map(|(_, ext)| ext);
+                    let title = title.replace('/', "-");
+                    match extension {
+                        Some(ext) => format!("{}.{}", title, ext),
+                        None => title,
+                    }
+                }
+                _ => from_url,
+            };
+            let name = unique(&mut seen, name);
+            InputFile::URLFile(URLFile::new(name, enclosure.url, enclosure.size))
+        })
+        .collect())
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    node.children()
+        .find(|c| c.has_tag_name(name))
+        .and_then(|c| c.text())
+        .map(|t| t.trim().to_string())
+}
+
+fn length(node: Node, url: &str) -> u64 {
+    match node.attribute("length").and_then(|l| l.trim().parse().ok()) {
+        Some(length) => length,
+        None => {
+            warn!("Enclosure {} has no length, recording size 0", url);
+            0
+        }
+    }
+}
+
+/// The percent-decoded last path segment of `url`.
+pub(crate) fn url_file_name(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            let segment = url
+                .path_segments()?
+                .rev()
+                .find(|s| !s.is_empty())?
+                .to_string();
+            let name = percent_decode_str(&segment).decode_utf8().ok()?;
+            Some(name.replace('/', "-"))
+        })
+        .unwrap_or_else(|| "index".into())
+}
+
+/// Appends ` (2)`, ` (3)`, ... to names that have already been used.
+pub(crate) fn unique(seen: &mut HashSet<String>, name: String) -> String {
+    if seen.insert(name.clone()) {
+        return name;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (name.clone(), String::new()),
+    };
+    (2..)
+        .map(|i| format!("{} ({}){}", stem, i, ext))
+        .find(|candidate| seen.insert(candidate.clone()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::parse;
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0"><channel><title>Show</title>
+  <item><title>Episode 2: More</title>
+    <enclosure url="https://cdn.example.com/ep%202.mp3" length="2000" type="audio/mpeg"/></item>
+  <item><title>Episode 1/Pilot</title>
+    <enclosure url="https://cdn.example.com/other/ep%202.mp3" length="1000" type="audio/mpeg"/></item>
+</channel></rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>Show</title>
+  <entry><title>First</title>
+    <link rel="alternate" href="https://example.com/first"/>
+    <link rel="enclosure" href="https://cdn.example.com/first.ogg" length="42"/></entry>
+</feed>"#;
+
+    fn names(files: &[InputFile]) -> Vec<&str> {
+        files.iter().map(InputFile::name).collect()
+    }
+
+    #[test]
+    fn rss_enclosures() {
+        let files = parse(RSS, false).unwrap();
+        assert_eq!(names(&files), ["ep 2.mp3", "ep 2 (2).mp3"]);
+        let InputFile::URLFile(first) = &files[0] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(first.size, 2000);
+        let files = parse(RSS, true).unwrap();
+        assert_eq!(
+            names(&files),
+            ["Episode 2: More.mp3", "Episode 1-Pilot.mp3"]
+        );
+    }
+
+    #[test]
+    fn atom_enclosures() {
+        let files = parse(ATOM, false).unwrap();
+        assert_eq!(names(&files), ["first.ogg"]);
+    }
+}
@@ -0,0 +1,130 @@
+//! `generate github`: the release assets of a GitHub repository, one
+//! directory per tag.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+
+use crate::{
+    layout::{Directory, InputFile, URLFile},
+    Result,
+};
+
+use super::get_json;
+
+pub fn command() -> Command {
+    Command::new("github")
+        .about("Emit a layout of a GitHub repository's release assets, organized by tag")
+        .arg(
+            Arg::new("REPO")
+                .required(true)
+                .help("Repository as owner/name"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .env("GITHUB_TOKEN")
+                .hide_env_values(true)
+                .help("API token, for private repositories and higher rate limits"),
+        )
+        .arg(
+            Arg::new("prereleases")
+                .long("prereleases")
+                .action(ArgAction::SetTrue)
+                .help("Include pre-releases"),
+        )
+        .arg(
+            Arg::new("api")
+                .long("api")
+                .default_value("https://api.github.com")
+                .help("API root, for GitHub Enterprise"),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    draft: bool,
+    prerelease: bool,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    size: u64,
+    browser_download_url: String,
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let repo = matches.get_one::<String>("REPO").unwrap();
+    let api = matches.get_one::<String>("api").unwrap();
+    let mut headers = vec!["Accept: application/vnd.github+json".to_string()];
+    if let Some(token) = matches.get_one::<String>("token") {
+        headers.push(format!("Authorization: Bearer {}", token));
+    }
+    let mut releases = Vec::new();
+    for page in 1.. {
+        let url = format!("{}/repos/{}/releases?per_page=100&page={}", api, repo, page);
+        let batch: Vec<Release> = get_json(&url, &headers)?;
+        if batch.is_empty() {
+            break;
+        }
+        releases.extend(batch);
+    }
+    Ok(to_layout(releases, matches.get_flag("prereleases")))
+}
+
+fn to_layout(releases: Vec<Release>, prereleases: bool) -> Vec<InputFile> {
+    releases
+        .into_iter()
+        .filter(|release| !release.draft && (prereleases || !release.prerelease))
+        .map(|release| {
+            let assets = release
+                .assets
+                .into_iter()
+                .map(|asset| {
+                    InputFile::URLFile(URLFile::new(
+                        asset.name,
+                        asset.browser_download_url,
+                        asset.size,
+                    ))
+                })
+                .collect();
+            InputFile::Directory(Directory::new(release.tag_name, assets))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::{to_layout, Release};
+
+    const RELEASES: &str = r#"[
+        {"tag_name": "v2.0.0-rc1", "draft": false, "prerelease": true, "assets": []},
+        {"tag_name": "v1.0.0", "draft": false, "prerelease": false, "assets": [
+            {"name": "tool-x86_64.tar.gz", "size": 1234, "content_type": "application/gzip",
+             "browser_download_url": "https://github.com/o/r/releases/download/v1.0.0/tool-x86_64.tar.gz"}
+        ]},
+        {"tag_name": "v0.9.0", "draft": true, "prerelease": false, "assets": []}
+    ]"#;
+
+    #[test]
+    fn releases_by_tag() {
+        let releases: Vec<Release> = serde_json::from_str(RELEASES).unwrap();
+        let files = to_layout(releases, false);
+        let [InputFile::Directory(v1)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(v1.name, "v1.0.0");
+        let [InputFile::URLFile(asset)] = &v1.contents[..] else {
+            panic!("Unexpected layout {:?}", v1.contents);
+        };
+        assert_eq!(asset.size, 1234);
+        assert!(asset.url.ends_with("/v1.0.0/tool-x86_64.tar.gz"));
+
+        let releases: Vec<Release> = serde_json::from_str(RELEASES).unwrap();
+        assert_eq!(to_layout(releases, true).len(), 2);
+    }
+}
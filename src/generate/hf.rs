@@ -0,0 +1,197 @@
+//! `generate hf`: the files of a Hugging Face model, dataset or space
+//! repository, pointing at their `resolve/` URLs.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+
+use crate::{
+    layout::{tree_from_paths, Auth, InputFile, URLFile},
+    Result,
+};
+
+use super::get_paged_json;
+
+pub fn command() -> Command {
+    Command::new("hf")
+        .about("Emit a layout of a Hugging Face repository")
+        .arg(
+            Arg::new("REPO_ID")
+                .required(true)
+                .help("Repository id, such as google-bert/bert-base-uncased"),
+        )
+        .arg(
+            Arg::new("type")
+                .long("type")
+                .value_parser(["model", "dataset", "space"])
+                .default_value("model")
+                .help("Kind of repository"),
+        )
+        .arg(
+            Arg::new("revision")
+                .long("revision")
+                .default_value("main")
+                .help("Branch, tag or commit to list"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .env("HF_TOKEN")
+                .hide_env_values(true)
+                .help("Access token for private or gated repositories"),
+        )
+        .arg(
+            Arg::new("embed-token")
+                .long("embed-token")
+                .action(ArgAction::SetTrue)
+                .help("Also write the token into the layout so the mount can authenticate"),
+        )
+        .arg(
+            Arg::new("endpoint")
+                .long("endpoint")
+                .env("HF_ENDPOINT")
+                .default_value("https://huggingface.co")
+                .help("Hub endpoint, for mirrors"),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct TreeEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    path: String,
+    size: u64,
+    lfs: Option<Lfs>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lfs {
+    oid: String,
+    size: u64,
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let repo = matches.get_one::<String>("REPO_ID").unwrap();
+    let revision = matches.get_one::<String>("revision").unwrap();
+    let endpoint = matches.get_one::<String>("endpoint").unwrap();
+    let token = matches.get_one::<String>("token");
+    let (api_kind, url_prefix) = match matches.get_one::<String>("type").unwrap().as_str() {
+        "dataset" => ("datasets", "datasets/"),
+        "space" => ("spaces", "spaces/"),
+        _ => ("models", ""),
+    };
+    let headers: Vec<_> = token
+        .map(|token| format!("Authorization: Bearer {}", token))
+        .into_iter()
+        .collect();
+    let revision_path = encode(revision);
+    let url = format!(
+        "{}/api/{}/{}/tree/{}?recursive=true",
+        endpoint, api_kind, repo, revision_path
+    );
+    let entries: Vec<TreeEntry> = get_paged_json(&url, &headers)?;
+    let base = format!(
+        "{}/{}{}/resolve/{}/",
+        endpoint, url_prefix, repo, revision_path
+    );
+    let auth = token
+        .filter(|_| matches.get_flag("embed-token"))
+        .map(|token| Auth::Bearer(token.clone()));
+    Ok(to_layout(entries, &base, auth))
+}
+
+fn to_layout(entries: Vec<TreeEntry>, base: &str, auth: Option<Auth>) -> Vec<InputFile> {
+    tree_from_paths(
+        entries
+            .into_iter()
+            .filter(|entry| entry.kind == "file")
+            .map(|entry| {
+                let url = format!(
+                    "{}{}",
+                    base,
+                    entry
+                        .path
+                        .split('/')
+                        .map(encode)
+                        .collect::<Vec<_>>()
+                        .join("/")
+                );
+                let (size, sha256) = match entry.lfs {
+                    // The oid of an LFS object is the sha256 of its contents.
+                    Some(lfs) => (lfs.size, Some(lfs.oid)),
+                    None => (entry.size, None),
+                };
+                let mut file = URLFile::new("", url, size);
+                file.sha256 = sha256;
+                file.options.auth = auth.clone();
+                (entry.path, InputFile::URLFile(file))
+            }),
+    )
+}
+
+fn encode(segment: &str) -> String {
+    url::form_urlencoded::byte_serialize(segment.as_bytes())
+        .collect::<String>()
+        .replace('+', "%20")
+}
+
+#[cfg(test)]
+mod test {
+    use lhttpfs::{
+        fs::{LazyHTTPFS, RemoteTree},
+        testing::Origin,
+    };
+
+    use crate::layout::{Auth, InputFile};
+
+    use super::{to_layout, TreeEntry};
+
+    const TREE: &str = r#"[
+        {"type": "file", "oid": "a1", "size": 1523, "path": "config.json"},
+        {"type": "directory", "oid": "b2", "size": 0, "path": "onnx"},
+        {"type": "file", "oid": "c3", "size": 1340, "path": "onnx/model q8.onnx",
+         "lfs": {"oid": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+                 "size": 438000000, "pointerSize": 134}}
+    ]"#;
+
+    #[test]
+    fn lfs_and_plain_files() {
+        let entries: Vec<TreeEntry> = serde_json::from_str(TREE).unwrap();
+        let base = "https://huggingface.co/org/model/resolve/main/";
+        let files = to_layout(entries, base, Some(Auth::Bearer("hf_x".into())));
+        let [InputFile::URLFile(config), InputFile::Directory(onnx)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(
+            config.url,
+            "https://huggingface.co/org/model/resolve/main/config.json"
+        );
+        assert_eq!(config.sha256, None);
+        let [InputFile::URLFile(model)] = &onnx.contents[..] else {
+            panic!("Unexpected layout {:?}", onnx.contents);
+        };
+        assert_eq!(model.name, "model q8.onnx");
+        assert_eq!(model.size, 438000000);
+        assert!(model.url.ends_with("/resolve/main/onnx/model%20q8.onnx"));
+        assert!(model.sha256.as_deref().unwrap().starts_with("9f86d0"));
+        assert_eq!(model.options.auth, Some(Auth::Bearer("hf_x".into())));
+    }
+
+    #[test]
+    fn read_through_redirect() {
+        // The Hub sends `resolve/` URLs of LFS files on to its CDN.
+        let origin = Origin::start()
+            .redirect("/org/model/resolve/main/model.bin", "/cdn/sha256-9f86d0")
+            .with("/cdn/sha256-9f86d0", "hello");
+        let entries: Vec<TreeEntry> = serde_json::from_str(
+            r#"[{"type": "file", "path": "model.bin", "size": 134,
+                 "lfs": {"oid": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                         "size": 5}}]"#,
+        )
+        .unwrap();
+        let files = to_layout(entries, &origin.url("/org/model/resolve/main/"), None);
+        let fs = LazyHTTPFS::builder().cache_dir(None).build(files).unwrap();
+        let tree = RemoteTree::new(fs);
+        let model = tree.open("/model.bin").unwrap();
+        assert_eq!(model.read_at(0, 5).unwrap(), b"hello");
+    }
+}
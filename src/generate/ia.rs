@@ -0,0 +1,134 @@
+//! `generate ia`: the files of an Internet Archive item, from its metadata
+//! API, as ia:// URLs.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+
+use crate::{
+    fetch::ia_authorization,
+    layout::{tree_from_paths, InputFile, URLFile},
+    Result,
+};
+
+use super::{encode_segment, get_json};
+
+pub fn command() -> Command {
+    Command::new("ia")
+        .about("Emit a layout of an Internet Archive item")
+        .arg(
+            Arg::new("IDENTIFIER")
+                .required(true)
+                .help("Item identifier, or its archive.org/details URL"),
+        )
+        .arg(
+            Arg::new("originals")
+                .long("originals")
+                .action(ArgAction::SetTrue)
+                .help("Leave out the derivatives and metadata files archive.org generates"),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    files: Vec<ItemFile>,
+    #[serde(default)]
+    is_dark: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemFile {
+    name: String,
+    source: Option<String>,
+    /// The metadata API gives sizes as strings.
+    size: Option<String>,
+    md5: Option<String>,
+}
+
+fn identifier(item: &str) -> &str {
+    let item = item.trim().trim_end_matches('/');
+    for prefix in [
+        "https://archive.org/details/",
+        "http://archive.org/details/",
+    ] {
+        if let Some(rest) = item.strip_prefix(prefix) {
+            return rest.split('/').next().unwrap_or(rest);
+        }
+    }
+    item
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let id = identifier(matches.get_one::<String>("IDENTIFIER").unwrap());
+    let headers: Vec<_> = ia_authorization()
+        .map(|authorization| format!("Authorization: {}", authorization))
+        .into_iter()
+        .collect();
+    let url = format!("https://archive.org/metadata/{}", encode_segment(id));
+    let metadata: Metadata = get_json(&url, &headers)?;
+    if metadata.files.is_empty() {
+        return Err(format!(
+            "Internet Archive item {} has no files, or does not exist",
+            id
+        )
+        .into());
+    }
+    if metadata.is_dark {
+        return Err(format!("Internet Archive item {} has been taken down", id).into());
+    }
+    Ok(to_layout(id, metadata, matches.get_flag("originals")))
+}
+
+fn to_layout(id: &str, metadata: Metadata, originals: bool) -> Vec<InputFile> {
+    tree_from_paths(
+        metadata
+            .files
+            .into_iter()
+            .filter(|file| !originals || file.source.as_deref() == Some("original"))
+            .map(|file| {
+                let path = file
+                    .name
+                    .split('/')
+                    .map(encode_segment)
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let size = file.size.and_then(|s| s.parse().ok()).unwrap_or(0);
+                let mut entry = URLFile::new("", format!("ia://{}/{}", id, path), size);
+                entry.md5 = file.md5;
+                (file.name, InputFile::URLFile(entry))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::{identifier, to_layout, Metadata};
+
+    #[test]
+    fn items() {
+        assert_eq!(
+            identifier("https://archive.org/details/gutenberg/"),
+            "gutenberg"
+        );
+        assert_eq!(identifier("nasa"), "nasa");
+        let metadata: Metadata = serde_json::from_str(
+            r#"{"server": "ia800.us.archive.org", "files": [
+                {"name": "scans/page 1.jpg", "source": "original", "size": "2048", "md5": "ab"},
+                {"name": "scans/page 1_thumb.jpg", "source": "derivative", "size": "64"},
+                {"name": "item_meta.xml", "source": "metadata"}]}"#,
+        )
+        .unwrap();
+        let files = to_layout("item", metadata, true);
+        let [InputFile::Directory(scans)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        let [InputFile::URLFile(page)] = &scans.contents[..] else {
+            panic!("Unexpected directory {:?}", scans);
+        };
+        assert_eq!(page.name, "page 1.jpg");
+        assert_eq!(page.url, "ia://item/scans/page%201.jpg");
+        assert_eq!((page.size, page.md5.as_deref()), (2048, Some("ab")));
+    }
+}
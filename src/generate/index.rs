@@ -0,0 +1,178 @@
+//! `generate index`: a layout of a directory tree served as HTML index
+//! pages, such as Apache's or nginx's autoindex.
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::{
+    layout::{Directory, InputFile, URLFile},
+    Result,
+};
+
+use super::get;
+
+pub fn command() -> Command {
+    Command::new("index")
+        .about("Emit a layout of a directory listing, following its subdirectories")
+        .arg(
+            Arg::new("URL")
+                .required(true)
+                .help("http(s):// URL of the listing"),
+        )
+        .arg(
+            Arg::new("depth")
+                .long("depth")
+                .value_parser(value_parser!(usize))
+                .help("How many levels of subdirectories to follow, all of them if not given"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let mut url = Url::parse(matches.get_one::<String>("URL").unwrap())?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    walk(&url, matches.get_one::<usize>("depth").copied())
+}
+
+/// Listings don't give sizes in any dependable format, so files are
+/// recorded with size 0 for `--probe` or the mount to fill in.
+fn walk(url: &Url, depth: Option<usize>) -> Result<Vec<InputFile>> {
+    let page = get(url.as_str(), &[])?;
+    let mut files = Vec::new();
+    for (name, child) in children(url, &String::from_utf8_lossy(&page)) {
+        if child.path().ends_with('/') {
+            if depth == Some(0) {
+                continue;
+            }
+            let contents = walk(&child, depth.map(|d| d - 1))?;
+            files.push(InputFile::Directory(Directory::new(name, contents)));
+        } else {
+            files.push(InputFile::URLFile(URLFile::new(name, child, 0)));
+        }
+    }
+    Ok(files)
+}
+
+/// The entries a listing of `base` links to, with their decoded names.
+/// Only links to the immediate children of `base` count, which leaves out
+/// the parent directory, column sorting links and anything off-site.
+fn children(base: &Url, html: &str) -> Vec<(String, Url)> {
+    let mut entries: Vec<(String, Url)> = Vec::new();
+    for href in hrefs(html) {
+        let Ok(mut url) = base.join(&href) else {
+            continue;
+        };
+        url.set_fragment(None);
+        if url.query().is_some() || url.origin() != base.origin() {
+            continue;
+        }
+        let Some(rest) = url.path().strip_prefix(base.path()) else {
+            continue;
+        };
+        let name = rest.strip_suffix('/').unwrap_or(rest);
+        if name.is_empty() || name.contains('/') {
+            continue;
+        }
+        let name = percent_decode_str(name).decode_utf8_lossy().into_owned();
+        if name == "." || name == ".." || entries.iter().any(|(n, _)| *n == name) {
+            continue;
+        }
+        entries.push((name, url));
+    }
+    entries
+}
+
+/// The `href` attributes of the `<a>` tags in `html`, unescaped.
+fn hrefs(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut hrefs = Vec::new();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<a").map(|i| rest + i + 2) {
+        let Some(end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        rest = end;
+        if !lower[start..].starts_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(attr) = lower[start..end].find("href").map(|i| start + i + 4) else {
+            continue;
+        };
+        let value = html[attr..end].trim_start();
+        let Some(value) = value.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next(),
+            _ => value.split(|c: char| c.is_ascii_whitespace()).next(),
+        };
+        if let Some(value) = value {
+            hrefs.push(unescape(value));
+        }
+    }
+    hrefs
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use super::children;
+
+    #[test]
+    fn autoindex() {
+        let base = Url::parse("https://files.example.com/pub/").unwrap();
+        // Apache, with sorting links and a parent directory.
+        let apache = r#"<html><body><h1>Index of /pub</h1><table>
+<tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+<tr><td><a href="/">Parent Directory</a></td></tr>
+<tr><td><a href="data%20set.tar.gz">data set.tar.gz</a></td><td>1.2G</td></tr>
+<tr><td><a href="docs/">docs/</a></td><td>-</td></tr>
+<tr><td><A HREF='a&amp;b.txt'>a&amp;b.txt</A></td></tr>
+</table></body></html>"#;
+        let names: Vec<_> = children(&base, apache)
+            .into_iter()
+            .map(|(name, url)| (name, url.to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (
+                    "data set.tar.gz".into(),
+                    "https://files.example.com/pub/data%20set.tar.gz".to_string()
+                ),
+                ("docs".into(), "https://files.example.com/pub/docs/".into()),
+                (
+                    "a&b.txt".into(),
+                    "https://files.example.com/pub/a&b.txt".into()
+                ),
+            ]
+        );
+
+        // nginx, with absolute links and one elsewhere.
+        let nginx = r#"<html><head><title>Index of /pub/</title></head><body>
+<pre><a href="../">../</a>
+<a href="/pub/iso/">iso/</a>                                  01-Jan-2024 00:00       -
+<a href="https://mirror.example.org/pub/iso/">mirror</a>
+<a href="/pub/iso/x.iso">x.iso</a>
+<abbr title="x">x</abbr>
+</pre></body></html>"#;
+        let names: Vec<_> = children(&base, nginx)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["iso"]);
+    }
+}
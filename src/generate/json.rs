@@ -0,0 +1,106 @@
+//! `generate json`: file entries extracted from any JSON API response with
+//! a [mapping](super::mapping) expression.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    layout::{tree_from_paths, InputFile, URLFile},
+    Result,
+};
+
+use super::{get, mapping::Expr};
+
+pub fn command() -> Command {
+    Command::new("json")
+        .about("Emit a layout extracted from a JSON document with a mapping expression")
+        .arg(
+            Arg::new("SOURCE")
+                .required(true)
+                .help("URL or path of the JSON document"),
+        )
+        .arg(Arg::new("map").long("map").required(true).help(
+            "Expression producing {name or path, size, url[, sha256, md5]} objects, \
+                     e.g. '.files[] | {path: .key, size: .size, url: .links.self}'",
+        ))
+        .arg(
+            Arg::new("header")
+                .long("header")
+                .short('H')
+                .action(ArgAction::Append)
+                .help("Extra request header, as 'Name: value'"),
+        )
+}
+
+/// What the mapping has to produce for each file. `path` may contain `/`
+/// to place the file in subdirectories.
+#[derive(Debug, Deserialize)]
+struct Mapped {
+    name: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    size: u64,
+    url: String,
+    sha256: Option<String>,
+    md5: Option<String>,
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let source = matches.get_one::<String>("SOURCE").unwrap();
+    let headers: Vec<String> = matches
+        .get_many::<String>("header")
+        .map(|h| h.cloned().collect())
+        .unwrap_or_default();
+    let data = if source.starts_with("http://") || source.starts_with("https://") {
+        get(source, &headers)?
+    } else {
+        std::fs::read(source)?
+    };
+    let expr = Expr::parse(matches.get_one::<String>("map").unwrap())?;
+    to_layout(&serde_json::from_slice(&data)?, &expr)
+}
+
+fn to_layout(document: &Value, expr: &Expr) -> Result<Vec<InputFile>> {
+    let mut entries = Vec::new();
+    for value in expr.eval(document)? {
+        let mapped: Mapped = serde_json::from_value(value.clone())
+            .map_err(|e| format!("Mapped value {} is not a file: {}", value, e))?;
+        let path = mapped
+            .path
+            .or(mapped.name)
+            .ok_or_else(|| format!("Mapped value {} has neither a name nor a path", value))?;
+        let mut file = URLFile::new("", mapped.url, mapped.size);
+        file.sha256 = mapped.sha256;
+        file.md5 = mapped.md5;
+        entries.push((path, InputFile::URLFile(file)));
+    }
+    Ok(tree_from_paths(entries))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::{generate::mapping::Expr, layout::InputFile};
+
+    use super::to_layout;
+
+    #[test]
+    fn mapped_catalog() {
+        let document = json!({"items": [
+            {"key": "raw/a.bin", "bytes": 3, "href": "https://cdn.example.com/a"},
+            {"key": "b.bin", "bytes": 4, "href": "https://cdn.example.com/b"}
+        ]});
+        let expr = Expr::parse(".items[] | {path: .key, size: .bytes, url: .href}").unwrap();
+        let files = to_layout(&document, &expr).unwrap();
+        let [InputFile::Directory(raw), InputFile::URLFile(b)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(raw.contents[0].name(), "a.bin");
+        assert_eq!((b.name.as_str(), b.size), ("b.bin", 4));
+
+        let missing = Expr::parse(".items[] | {size: .bytes, url: .href}").unwrap();
+        assert!(to_layout(&document, &missing).is_err());
+    }
+}
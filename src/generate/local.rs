@@ -0,0 +1,118 @@
+//! `generate local`: mirrors a local copy of a tree that has been uploaded
+//! to a static host.
+
+use std::{fs, io, path::Path};
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    layout::{Directory, InputFile, URLFile},
+    Result,
+};
+
+use super::hex;
+
+pub fn command() -> Command {
+    Command::new("local")
+        .about("Walk a local directory and emit a layout pointing at where it is published")
+        .arg(
+            Arg::new("DIR")
+                .required(true)
+                .help("Local copy of the published tree"),
+        )
+        .arg(
+            Arg::new("base-url")
+                .long("base-url")
+                .help("URL that DIR is published at, file:// URLs of DIR itself if unset"),
+        )
+        .arg(
+            Arg::new("checksums")
+                .long("checksums")
+                .action(ArgAction::SetTrue)
+                .help("Hash every file and record its sha256"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let dir = matches.get_one::<String>("DIR").unwrap();
+    let base = match matches.get_one::<String>("base-url") {
+        Some(url) => Url::parse(url)?,
+        None => Url::from_directory_path(fs::canonicalize(dir)?)
+            .map_err(|_| format!("{} can't be turned into a file:// URL", dir))?,
+    };
+    walk(Path::new(dir), &base, matches.get_flag("checksums"))
+}
+
+/// Lists `dir` in name order. `base` is the URL of `dir` itself; names are
+/// percent-encoded as they are appended to it.
+fn walk(dir: &Path, base: &Url, checksums: bool) -> Result<Vec<InputFile>> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let mut url = base.clone();
+        url.path_segments_mut()
+            .map_err(|_| "The base URL cannot have a path")?
+            .pop_if_empty()
+            .push(&name);
+        let metadata = fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            url.path_segments_mut().unwrap().push("");
+            let contents = walk(&entry.path(), &url, checksums)?;
+            files.push(InputFile::Directory(Directory::new(name, contents)));
+        } else if metadata.is_file() {
+            let mut file = URLFile::new(name, url, metadata.len());
+            if checksums {
+                file.sha256 = Some(sha256(&entry.path())?);
+            }
+            files.push(InputFile::URLFile(file));
+        }
+    }
+    Ok(files)
+}
+
+pub(crate) fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use url::Url;
+
+    use crate::layout::InputFile;
+
+    use super::walk;
+
+    #[test]
+    fn walks_directory() {
+        let root = std::env::temp_dir().join(format!("lhttpfs-local-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub dir")).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("sub dir/b.txt"), "").unwrap();
+
+        let base = Url::parse("https://example.com/pub").unwrap();
+        let files = walk(&root, &base, true).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let [InputFile::URLFile(a), InputFile::Directory(sub)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(a.url, "https://example.com/pub/a.txt");
+        assert_eq!(a.size, 5);
+        assert_eq!(
+            a.sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        let [InputFile::URLFile(b)] = &sub.contents[..] else {
+            panic!("Unexpected layout {:?}", sub.contents);
+        };
+        assert_eq!(b.url, "https://example.com/pub/sub%20dir/b.txt");
+    }
+}
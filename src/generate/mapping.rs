@@ -0,0 +1,350 @@
+//! A small jq-like language for pulling file entries out of arbitrary JSON.
+//!
+//! ```text
+//! .data.files[] | {name: .filename, size: .bytes, url: "https://cdn/" + .id}
+//! ```
+//!
+//! Supported are paths (`.`, `.key`, `."quoted key"`, `[n]`, `[]`), pipes,
+//! object construction, string and number literals, and `+` for
+//! concatenating strings or adding numbers.
+
+use std::{error::Error, fmt::Display};
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    Path(Vec<Step>),
+    Literal(Value),
+    Object(Vec<(String, Expr)>),
+    Pipe(Box<Expr>, Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Key(String),
+    Index(i64),
+    Iterate,
+}
+
+#[derive(Debug)]
+pub struct MappingError(String);
+
+impl Display for MappingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid mapping expression: {}", self.0)
+    }
+}
+
+impl Error for MappingError {}
+
+fn error<T>(message: impl Into<String>) -> Result<T, MappingError> {
+    Err(MappingError(message.into()))
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, MappingError> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let expr = parser.pipeline()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.chars.len() {
+            return error(format!("unexpected input at {}", parser.pos));
+        }
+        Ok(expr)
+    }
+
+    /// Produces every output of the expression applied to `input`.
+    pub fn eval(&self, input: &Value) -> Result<Vec<Value>, MappingError> {
+        match self {
+            Expr::Literal(value) => Ok(vec![value.clone()]),
+            Expr::Path(steps) => {
+                let mut values = vec![input.clone()];
+                for step in steps {
+                    let mut next = Vec::new();
+                    for value in values {
+                        match (step, value) {
+                            (Step::Key(key), Value::Object(mut object)) => {
+                                next.push(object.remove(key).unwrap_or(Value::Null))
+                            }
+                            (Step::Key(_), Value::Null) => next.push(Value::Null),
+                            (Step::Index(i), Value::Array(array)) => {
+                                let index = if *i < 0 { array.len() as i64 + i } else { *i };
+                                next.push(array.get(index as usize).cloned().unwrap_or(Value::Null))
+                            }
+                            (Step::Iterate, Value::Array(array)) => next.extend(array),
+                            (Step::Iterate, Value::Object(object)) => {
+                                next.extend(object.into_iter().map(|(_, v)| v))
+                            }
+                            (step, value) => {
+                                return error(format!("can't apply {:?} to {}", step, value))
+                            }
+                        }
+                    }
+                    values = next;
+                }
+                Ok(values)
+            }
+            Expr::Object(fields) => {
+                let mut object = Map::new();
+                for (key, expr) in fields {
+                    let value = expr.eval(input)?.into_iter().next().unwrap_or(Value::Null);
+                    object.insert(key.clone(), value);
+                }
+                Ok(vec![Value::Object(object)])
+            }
+            Expr::Pipe(left, right) => {
+                let mut out = Vec::new();
+                for value in left.eval(input)? {
+                    out.extend(right.eval(&value)?);
+                }
+                Ok(out)
+            }
+            Expr::Add(left, right) => {
+                let mut out = Vec::new();
+                for l in left.eval(input)? {
+                    for r in right.eval(input)? {
+                        out.push(add(&l, &r)?);
+                    }
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+fn add(left: &Value, right: &Value) -> Result<Value, MappingError> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => match (l.as_i64(), r.as_i64()) {
+            (Some(l), Some(r)) => Ok(Value::from(l + r)),
+            _ => Ok(Value::from(
+                l.as_f64().unwrap_or(0.0) + r.as_f64().unwrap_or(0.0),
+            )),
+        },
+        (Value::Null, other) | (other, Value::Null) => Ok(other.clone()),
+        (Value::String(_), _) | (_, Value::String(_)) => {
+            Ok(Value::String(format!("{}{}", text(left), text(right))))
+        }
+        _ => error(format!("can't add {} and {}", left, right)),
+    }
+}
+
+/// The text of a value as it would be concatenated: strings without quotes.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), MappingError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            error(format!("expected '{}' at {}", c, self.pos))
+        }
+    }
+
+    fn pipeline(&mut self) -> Result<Expr, MappingError> {
+        let mut expr = self.sum()?;
+        while self.eat('|') {
+            expr = Expr::Pipe(Box::new(expr), Box::new(self.sum()?));
+        }
+        Ok(expr)
+    }
+
+    fn sum(&mut self) -> Result<Expr, MappingError> {
+        let mut expr = self.term()?;
+        while self.eat('+') {
+            expr = Expr::Add(Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expr, MappingError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('.') => self.path(),
+            Some('{') => self.object(),
+            Some('"') => Ok(Expr::Literal(Value::String(self.string()?))),
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.pipeline()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' => {
+                Ok(Expr::Literal(Value::from(self.integer()?)))
+            }
+            _ => error(format!("expected an expression at {}", self.pos)),
+        }
+    }
+
+    fn path(&mut self) -> Result<Expr, MappingError> {
+        let mut steps = Vec::new();
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                Some('"') => steps.push(Step::Key(self.string()?)),
+                Some(c) if c.is_alphanumeric() || c == '_' => steps.push(Step::Key(self.ident())),
+                _ => {}
+            }
+            match self.peek() {
+                Some('[') => {
+                    self.pos += 1;
+                    if self.eat(']') {
+                        steps.push(Step::Iterate);
+                    } else {
+                        self.skip_whitespace();
+                        steps.push(Step::Index(self.integer()?));
+                        self.expect(']')?;
+                    }
+                }
+                Some('.') => self.pos += 1,
+                _ => return Ok(Expr::Path(steps)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Expr, MappingError> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+        if self.eat('}') {
+            return Ok(Expr::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = match self.peek() {
+                Some('"') => self.string()?,
+                _ => self.ident(),
+            };
+            if key.is_empty() {
+                return error(format!("expected a key at {}", self.pos));
+            }
+            let value = if self.eat(':') {
+                self.sum()?
+            } else {
+                Expr::Path(vec![Step::Key(key.clone())])
+            };
+            fields.push((key, value));
+            if self.eat('}') {
+                return Ok(Expr::Object(fields));
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn integer(&mut self) -> Result<i64, MappingError> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits
+            .parse()
+            .or_else(|_| error(format!("expected a number at {}", start)))
+    }
+
+    fn string(&mut self) -> Result<String, MappingError> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            match self.peek() {
+                None => return error("unterminated string"),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some(c) => out.push(c),
+                        None => return error("unterminated string"),
+                    }
+                    self.pos += 1;
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::Expr;
+
+    #[test]
+    fn paths_and_objects() {
+        let input = json!({"data": {"files": [
+            {"filename": "a.csv", "bytes": 10, "id": 1},
+            {"filename": "b.csv", "bytes": 20, "id": 2}
+        ]}});
+        let expr = Expr::parse(
+            r#".data.files[] | {name: .filename, size: .bytes + 1, url: "https://cdn/" + .id, id}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            expr.eval(&input).unwrap(),
+            [
+                json!({"name": "a.csv", "size": 11, "url": "https://cdn/1", "id": 1}),
+                json!({"name": "b.csv", "size": 21, "url": "https://cdn/2", "id": 2}),
+            ]
+        );
+        let last = Expr::parse(r#"."data".files[-1].filename"#).unwrap();
+        assert_eq!(last.eval(&input).unwrap(), [json!("b.csv")]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Expr::parse(".a | ").is_err());
+        assert!(Expr::parse("{name: .a").is_err());
+        assert!(Expr::parse(".a[]").unwrap().eval(&json!({"a": 1})).is_err());
+    }
+}
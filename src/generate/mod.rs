@@ -0,0 +1,208 @@
+//! Subcommands that build a layout from some existing description of a
+//! published tree, so it doesn't have to be written by hand.
+
+use std::{fs::File, io::stdout};
+
+use clap::{Arg, ArgMatches, Command};
+use curl::easy::{Easy, List};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    fetch::{self, HttpStatus, Response},
+    filter::{self, Filter},
+    layout::{self, hex},
+    Result,
+};
+
+mod apt;
+mod feed;
+mod github;
+mod hf;
+mod ia;
+mod index;
+mod json;
+mod local;
+mod mapping;
+pub(crate) mod oci;
+mod probe;
+mod sitemap;
+mod torrent;
+mod webdav;
+mod zenodo;
+
+pub fn command() -> Command {
+    Command::new("generate")
+        .about("Generate a layout and print it, or write it to --output")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .global(true)
+                .help("File to write the layout to instead of stdout"),
+        )
+        .args(filter::args().map(|arg| arg.global(true)))
+        .args(probe::args().map(|arg| arg.global(true)))
+        .subcommand(local::command())
+        .subcommand(github::command())
+        .subcommand(hf::command())
+        .subcommand(feed::command())
+        .subcommand(sitemap::command())
+        .subcommand(apt::command())
+        .subcommand(oci::command())
+        .subcommand(torrent::command())
+        .subcommand(zenodo::command())
+        .subcommand(ia::command())
+        .subcommand(json::command())
+        .subcommand(webdav::command())
+        .subcommand(index::command())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let mut files = match matches.subcommand() {
+        Some(("local", m)) => local::run(m)?,
+        Some(("github", m)) => github::run(m)?,
+        Some(("hf", m)) => hf::run(m)?,
+        Some(("feed", m)) => feed::run(m)?,
+        Some(("sitemap", m)) => sitemap::run(m)?,
+        Some(("apt", m)) => apt::run(m)?,
+        Some(("oci", m)) => oci::run(m)?,
+        Some(("torrent", m)) => torrent::run(m)?,
+        Some(("zenodo", m)) => zenodo::run(m)?,
+        Some(("ia", m)) => ia::run(m)?,
+        Some(("json", m)) => json::run(m)?,
+        Some(("webdav", m)) => webdav::run(m)?,
+        Some(("index", m)) => index::run(m)?,
+        _ => unreachable!("clap requires a generate subcommand"),
+    };
+    if let Some(filter) = Filter::from_matches(matches)? {
+        files = filter.apply(files);
+    }
+    probe::run(&mut files, matches)?;
+    match matches.get_one::<String>("output") {
+        Some(path) => layout::write(File::create(path)?, &files)?,
+        None => layout::write(stdout().lock(), &files)?,
+    }
+    Ok(())
+}
+
+/// Downloads `url` with the extra `headers` (`"Name: value"`), failing on
+/// any HTTP error status.
+pub(crate) fn get(url: &str, headers: &[String]) -> Result<Vec<u8>> {
+    Ok(request(url, headers)?.body)
+}
+
+/// Characters that have to be escaped in a URL path segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub(crate) fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+/// Reads `source`, which is either an http(s) URL or a local path.
+pub(crate) fn read_source(source: &str) -> Result<Vec<u8>> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        get(source, &[])
+    } else {
+        Ok(std::fs::read(source)?)
+    }
+}
+
+/// Issues a HEAD request for `url` and returns its `Content-Length`, if the
+/// server reports one.
+pub(crate) fn head_size(url: &str, headers: &[String]) -> Result<Option<u64>> {
+    let mut curl = Easy::new();
+    curl.url(url)?;
+    curl.nobody(true)?;
+    curl.follow_location(true)?;
+    curl.useragent(concat!("lhttpfs/", env!("CARGO_PKG_VERSION")))?;
+    let mut list = List::new();
+    for header in headers {
+        list.append(header)?;
+    }
+    curl.http_headers(list)?;
+    curl.perform()?;
+    let status = curl.response_code()?;
+    if status >= 400 {
+        return Err(Box::new(HttpStatus {
+            url: url.into(),
+            status,
+        }));
+    }
+    let length = curl.content_length_download()?;
+    Ok((length >= 0.0).then_some(length as u64))
+}
+
+pub(crate) fn get_json<T: DeserializeOwned>(url: &str, headers: &[String]) -> Result<T> {
+    Ok(serde_json::from_slice(&get(url, headers)?)?)
+}
+
+/// Fetches every page of a JSON array, following `Link: <...>; rel="next"`
+/// response headers.
+pub(crate) fn get_paged_json<T: DeserializeOwned>(url: &str, headers: &[String]) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    let mut next = Some(url.to_string());
+    while let Some(url) = next {
+        let response = request(&url, headers)?;
+        items.extend(serde_json::from_slice::<Vec<T>>(&response.body)?);
+        next = response.headers.iter().find_map(|h| next_link(h));
+    }
+    Ok(items)
+}
+
+fn next_link(header: &str) -> Option<String> {
+    let (name, value) = header.split_once(':')?;
+    if !name.eq_ignore_ascii_case("link") {
+        return None;
+    }
+    value.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        params
+            .split(';')
+            .any(|p| p.trim() == r#"rel="next""#)
+            .then(|| {
+                target
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+fn request(url: &str, headers: &[String]) -> Result<Response> {
+    let response = fetch::get(url, headers)?;
+    if response.status >= 400 {
+        return Err(Box::new(HttpStatus {
+            url: url.into(),
+            status: response.status,
+        }));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::next_link;
+
+    #[test]
+    fn link_header() {
+        let header =
+            r#"Link: <https://h/api?cursor=a>; rel="prev", <https://h/api?cursor=b>; rel="next""#;
+        assert_eq!(next_link(header).as_deref(), Some("https://h/api?cursor=b"));
+        assert_eq!(next_link(r#"Link: <https://h/api>; rel="last""#), None);
+        assert_eq!(next_link("Content-Type: text/plain"), None);
+    }
+}
@@ -0,0 +1,208 @@
+//! `generate oci`: the config and layer blobs of a container image,
+//! resolved through the registry's distribution API.
+
+use clap::{Arg, ArgMatches, Command};
+use serde::Deserialize;
+
+use crate::{
+    fetch::oci::{anonymous_token, Reference},
+    layout::{Directory, InlineFile, InputFile, URLFile},
+    Result,
+};
+
+use super::{fetch, HttpStatus};
+
+const ACCEPT: &str = "Accept: application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+pub fn command() -> Command {
+    Command::new("oci")
+        .about("Emit a layout of the blobs of a container image")
+        .arg(
+            Arg::new("IMAGE")
+                .required(true)
+                .help("Image reference, such as ghcr.io/owner/image:tag or alpine:3.19"),
+        )
+        .arg(
+            Arg::new("platform")
+                .long("platform")
+                .help("Only include this os/arch[/variant] of a multi-platform image"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .env("REGISTRY_TOKEN")
+                .hide_env_values(true)
+                .help("Bearer token to use instead of requesting an anonymous one"),
+        )
+}
+
+struct Registry {
+    image: Reference,
+    token: Option<String>,
+}
+
+impl Registry {
+    fn manifest(&mut self, reference: &str) -> Result<Vec<u8>> {
+        let url = self.image.manifest_url(reference);
+        for _ in 0..2 {
+            let mut headers = vec![ACCEPT.to_string()];
+            if let Some(token) = &self.token {
+                headers.push(format!("Authorization: Bearer {}", token));
+            }
+            let response = fetch::get(&url, &headers)?;
+            match response.status {
+                401 if self.token.is_none() => {
+                    self.token = anonymous_token(&response)?;
+                    if self.token.is_none() {
+                        break;
+                    }
+                }
+                status if status >= 400 => return Err(Box::new(HttpStatus { url, status })),
+                _ => return Ok(response.body),
+            }
+        }
+        Err(Box::new(HttpStatus { url, status: 401 }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    size: u64,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    fn name(&self) -> String {
+        match &self.variant {
+            Some(variant) => format!("{}-{}-{}", self.os, self.architecture, variant),
+            None => format!("{}-{}", self.os, self.architecture),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    manifests: Option<Vec<Descriptor>>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let image = Reference::parse(matches.get_one::<String>("IMAGE").unwrap());
+    let mut registry = Registry {
+        token: matches.get_one::<String>("token").cloned(),
+        image,
+    };
+    let platform = matches
+        .get_one::<String>("platform")
+        .map(|p| p.replace('/', "-"));
+    let reference = registry.image.reference.clone();
+    let raw = registry.manifest(&reference)?;
+    let manifest: Manifest = serde_json::from_slice(&raw)?;
+    let Some(manifests) = manifest.manifests else {
+        return Ok(image_layout(&registry.image, &raw, manifest));
+    };
+    let mut files = vec![InputFile::InlineFile(InlineFile::new(
+        "index.json",
+        String::from_utf8(raw)?,
+    ))];
+    for descriptor in manifests {
+        let name = match &descriptor.platform {
+            Some(p) => p.name(),
+            None => digest_hex(&descriptor.digest).to_string(),
+        };
+        if platform.as_ref().is_some_and(|p| *p != name) {
+            continue;
+        }
+        let raw = registry.manifest(&descriptor.digest)?;
+        let manifest = serde_json::from_slice(&raw)?;
+        let contents = image_layout(&registry.image, &raw, manifest);
+        files.push(InputFile::Directory(Directory::new(name, contents)));
+    }
+    Ok(files)
+}
+
+/// `manifest.json`, `config.json` and a `layers/` directory for one image.
+fn image_layout(image: &Reference, raw: &[u8], manifest: Manifest) -> Vec<InputFile> {
+    let blob = |name: String, descriptor: &Descriptor| {
+        let mut file = URLFile::new(name, image.blob_url(&descriptor.digest), descriptor.size);
+        if let Some(hex) = descriptor.digest.strip_prefix("sha256:") {
+            file.sha256 = Some(hex.to_string());
+        }
+        InputFile::URLFile(file)
+    };
+    let mut files = vec![InputFile::InlineFile(InlineFile::new(
+        "manifest.json",
+        String::from_utf8_lossy(raw),
+    ))];
+    if let Some(config) = &manifest.config {
+        files.push(blob("config.json".into(), config));
+    }
+    let layers = manifest
+        .layers
+        .iter()
+        .map(|layer| {
+            let extension = match layer.media_type.rsplit_once('+') {
+                Some((_, "gzip")) => ".tar.gz",
+                Some((_, "zstd")) => ".tar.zst",
+                _ if layer.media_type.ends_with(".tar") => ".tar",
+                _ => "",
+            };
+            blob(format!("{}{}", digest_hex(&layer.digest), extension), layer)
+        })
+        .collect();
+    files.push(InputFile::Directory(Directory::new("layers", layers)));
+    files
+}
+
+fn digest_hex(digest: &str) -> &str {
+    digest.split_once(':').map_or(digest, |(_, hex)| hex)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::{image_layout, Manifest, Reference};
+
+    #[test]
+    fn manifest() {
+        let raw = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {"mediaType": "application/vnd.oci.image.config.v1+json",
+                       "digest": "sha256:c0nf", "size": 581},
+            "layers": [{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                        "digest": "sha256:1a7e", "size": 3401613}]
+        }"#;
+        let manifest: Manifest = serde_json::from_str(raw).unwrap();
+        let image = Reference::parse("ghcr.io/owner/image:v1");
+        let files = image_layout(&image, raw.as_bytes(), manifest);
+        let [InputFile::InlineFile(_), InputFile::URLFile(config), InputFile::Directory(layers)] =
+            &files[..]
+        else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(
+            config.url,
+            "https://ghcr.io/v2/owner/image/blobs/sha256:c0nf"
+        );
+        assert_eq!(config.sha256.as_deref(), Some("c0nf"));
+        assert_eq!(layers.contents[0].name(), "1a7e.tar.gz");
+    }
+}
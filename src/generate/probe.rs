@@ -0,0 +1,175 @@
+//! `--probe`: fills in what a generator couldn't tell about its files by
+//! asking the servers, so the written layout is complete and verifiable.
+
+use std::{error::Error, fmt::Display, sync::Mutex};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches};
+use curl::easy::Easy;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{
+    layout::{InputFile, URLFile},
+    Result,
+};
+
+use super::{head_size, hex, HttpStatus};
+
+pub fn args() -> [Arg; 3] {
+    [
+        Arg::new("probe")
+            .long("probe")
+            .action(ArgAction::SetTrue)
+            .help("Issue HEAD requests for files whose size is unknown"),
+        Arg::new("probe-hash")
+            .long("probe-hash")
+            .action(ArgAction::SetTrue)
+            .help("Like --probe, but also download files without a sha256 to hash them"),
+        Arg::new("probe-jobs")
+            .long("probe-jobs")
+            .value_parser(value_parser!(usize))
+            .default_value("8")
+            .help("How many files to probe at once"),
+    ]
+}
+
+#[derive(Debug)]
+pub struct ProbeFailed(Vec<String>);
+
+impl Display for ProbeFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not probe {} files", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ProbeFailed {}
+
+/// Probes the files of `files` that need it, if `--probe` or `--probe-hash`
+/// was given.
+pub fn run(files: &mut [InputFile], matches: &ArgMatches) -> Result<()> {
+    let hash = matches.get_flag("probe-hash");
+    if !hash && !matches.get_flag("probe") {
+        return Ok(());
+    }
+    let mut todo = Vec::new();
+    collect(files, hash, &mut todo);
+    info!("Probing {} files", todo.len());
+    let jobs = (*matches.get_one::<usize>("probe-jobs").unwrap()).max(1);
+    let todo = Mutex::new(todo.into_iter());
+    let failures = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let Some(file) = todo.lock().unwrap().next() else {
+                    return;
+                };
+                if let Err(e) = probe(file, hash) {
+                    failures
+                        .lock()
+                        .unwrap()
+                        .push(format!("{}: {}", file.url, e));
+                }
+            });
+        }
+    });
+    let mut failures = failures.into_inner().unwrap();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    failures.sort();
+    Err(Box::new(ProbeFailed(failures)))
+}
+
+/// Gathers the files that are missing a size, or a checksum when hashing.
+fn collect<'a>(files: &'a mut [InputFile], hash: bool, todo: &mut Vec<&'a mut URLFile>) {
+    for file in files {
+        match file {
+            InputFile::URLFile(file) if file.size == 0 || hash && needs_hash(file) => {
+                todo.push(file)
+            }
+            InputFile::Directory(dir) => collect(&mut dir.contents, hash, todo),
+            _ => {}
+        }
+    }
+}
+
+fn needs_hash(file: &URLFile) -> bool {
+    file.sha256.is_none() && file.pieces.is_none()
+}
+
+fn probe(file: &mut URLFile, hash: bool) -> Result<()> {
+    if hash && needs_hash(file) {
+        let (size, sha256) = download_hash(&file.url)?;
+        file.size = size;
+        file.sha256 = Some(sha256);
+        return Ok(());
+    }
+    match head_size(&file.url, &[])? {
+        Some(size) => file.size = size,
+        None => return Err("No Content-Length in the response".into()),
+    }
+    Ok(())
+}
+
+/// Downloads `url` without keeping it, returning its size and sha256.
+fn download_hash(url: &str) -> Result<(u64, String)> {
+    let mut curl = Easy::new();
+    curl.url(url)?;
+    curl.follow_location(true)?;
+    curl.useragent(concat!("lhttpfs/", env!("CARGO_PKG_VERSION")))?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    {
+        let mut transfer = curl.transfer();
+        transfer.write_function(|data| {
+            hasher.update(data);
+            size += data.len() as u64;
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    let status = curl.response_code()?;
+    if status >= 400 {
+        return Err(Box::new(HttpStatus {
+            url: url.into(),
+            status,
+        }));
+    }
+    Ok((size, hex(&hasher.finalize())))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::{Directory, InputFile, URLFile};
+
+    use super::collect;
+
+    #[test]
+    fn needs_probing() {
+        let mut known = URLFile::new("known", "https://example.com/known", 3);
+        known.sha256 = Some("ab".into());
+        let mut files = vec![
+            InputFile::URLFile(URLFile::new("sized", "https://example.com/sized", 3)),
+            InputFile::Directory(Directory::new(
+                "d",
+                vec![InputFile::URLFile(URLFile::new(
+                    "unsized",
+                    "https://example.com/unsized",
+                    0,
+                ))],
+            )),
+            InputFile::URLFile(known),
+        ];
+        let names = |files: &mut [InputFile], hash| {
+            let mut todo = Vec::new();
+            collect(files, hash, &mut todo);
+            todo.iter().map(|f| f.name.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&mut files, false), ["unsized"]);
+        assert_eq!(names(&mut files, true), ["sized", "unsized"]);
+    }
+}
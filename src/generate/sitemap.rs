@@ -0,0 +1,194 @@
+//! `generate sitemap`: a tree mirroring the URL paths listed in a
+//! sitemap, following sitemap indexes.
+
+use std::collections::HashSet;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use percent_encoding::percent_decode_str;
+use roxmltree::Document;
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    layout::{tree_from_paths, InputFile, URLFile},
+    transform::Compression,
+    Result,
+};
+
+use super::{head_size, read_source};
+
+pub fn command() -> Command {
+    Command::new("sitemap")
+        .about("Emit a layout mirroring the URLs of a sitemap")
+        .arg(
+            Arg::new("SITEMAP")
+                .required(true)
+                .help("URL or path of sitemap.xml or a sitemap index"),
+        )
+        .arg(
+            Arg::new("no-head")
+                .long("no-head")
+                .action(ArgAction::SetTrue)
+                .help("Don't issue HEAD requests for sizes, record 0 instead"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let mut urls = Vec::new();
+    let mut visited = HashSet::new();
+    collect(
+        matches.get_one::<String>("SITEMAP").unwrap(),
+        &mut urls,
+        &mut visited,
+    )?;
+    let probe = !matches.get_flag("no-head");
+    let sizes = urls.iter().map(|url| {
+        if !probe {
+            return 0;
+        }
+        match head_size(url, &[]) {
+            Ok(Some(size)) => size,
+            Ok(None) => {
+                warn!("{} did not report a size", url);
+                0
+            }
+            Err(e) => {
+                warn!("HEAD {} failed: {}", url, e);
+                0
+            }
+        }
+    });
+    let sized: Vec<_> = urls.iter().cloned().zip(sizes).collect();
+    to_layout(sized)
+}
+
+/// Adds the page URLs of `source` to `urls`, descending into the sitemaps
+/// listed by an index.
+fn collect(source: &str, urls: &mut Vec<String>, visited: &mut HashSet<String>) -> Result<()> {
+    if !visited.insert(source.to_string()) {
+        return Ok(());
+    }
+    let mut data = read_source(source)?;
+    if data.starts_with(&[0x1f, 0x8b]) {
+        data = Compression::Gzip.decompress(&data)?;
+    }
+    let text = String::from_utf8_lossy(&data);
+    let (pages, sitemaps) = parse(&text)?;
+    urls.extend(pages);
+    for sitemap in sitemaps {
+        collect(&sitemap, urls, visited)?;
+    }
+    Ok(())
+}
+
+/// Splits a sitemap into its page URLs and the sitemaps it indexes.
+fn parse(text: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let document = Document::parse(text)?;
+    let mut pages = Vec::new();
+    let mut sitemaps = Vec::new();
+    for loc in document.descendants().filter(|n| n.has_tag_name("loc")) {
+        let Some(url) = loc.text().map(str::trim) else {
+            continue;
+        };
+        match loc.parent_element().map(|p| p.tag_name().name()) {
+            Some("url") => pages.push(url.to_string()),
+            Some("sitemap") => sitemaps.push(url.to_string()),
+            _ => {}
+        }
+    }
+    Ok((pages, sitemaps))
+}
+
+/// URLs ending in `/` become an `index.html` in that directory. When the
+/// sitemap spans several hosts each gets its own top-level directory.
+fn to_layout(urls: Vec<(String, u64)>) -> Result<Vec<InputFile>> {
+    let parsed = urls
+        .into_iter()
+        .map(|(url, size)| Ok((Url::parse(&url)?, size)))
+        .collect::<Result<Vec<_>>>()?;
+    let hosts: HashSet<_> = parsed
+        .iter()
+        .map(|(url, _)| url.host_str().map(str::to_string))
+        .collect();
+    let mut seen = HashSet::new();
+    let entries = parsed.into_iter().filter_map(|(url, size)| {
+        let mut path: Vec<String> = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .map(|s| percent_decode_str(s).decode_utf8_lossy().replace('/', "-"))
+            .collect();
+        match path.last_mut() {
+            Some(last) if last.is_empty() => *last = "index.html".into(),
+            None => path.push("index.html".into()),
+            _ => {}
+        }
+        if hosts.len() > 1 {
+            path.insert(0, url.host_str().unwrap_or("unknown").to_string());
+        }
+        let path = path.join("/");
+        if !seen.insert(path.clone()) {
+            return None;
+        }
+        let file = URLFile::new("", url, size);
+        Some((path, InputFile::URLFile(file)))
+    });
+    Ok(tree_from_paths(entries))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::{parse, to_layout};
+
+    #[test]
+    fn urlset_and_index() {
+        let (pages, sitemaps) = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://docs.example.com/</loc><lastmod>2024-01-01</lastmod></url>
+  <url><loc> https://docs.example.com/guide/intro%20page.html </loc></url>
+</urlset>"#,
+        )
+        .unwrap();
+        assert_eq!(pages.len(), 2);
+        assert!(sitemaps.is_empty());
+
+        let (pages, sitemaps) = parse(
+            r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>https://docs.example.com/sitemap-1.xml.gz</loc></sitemap>
+</sitemapindex>"#,
+        )
+        .unwrap();
+        assert!(pages.is_empty());
+        assert_eq!(sitemaps, ["https://docs.example.com/sitemap-1.xml.gz"]);
+    }
+
+    #[test]
+    fn paths_become_tree() {
+        let files = to_layout(vec![
+            ("https://docs.example.com/".into(), 10),
+            (
+                "https://docs.example.com/guide/intro%20page.html".into(),
+                20,
+            ),
+            ("https://docs.example.com/guide/".into(), 30),
+        ])
+        .unwrap();
+        let [InputFile::URLFile(index), InputFile::Directory(guide)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!((index.name.as_str(), index.size), ("index.html", 10));
+        let names: Vec<_> = guide.contents.iter().map(InputFile::name).collect();
+        assert_eq!(names, ["intro page.html", "index.html"]);
+
+        let files = to_layout(vec![
+            ("https://a.example.com/x".into(), 1),
+            ("https://b.example.com/x".into(), 1),
+        ])
+        .unwrap();
+        let names: Vec<_> = files.iter().map(InputFile::name).collect();
+        assert_eq!(names, ["a.example.com", "b.example.com"]);
+    }
+}
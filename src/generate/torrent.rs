@@ -0,0 +1,246 @@
+//! `generate torrent`: the files of a `.torrent`, fetched over plain HTTP
+//! from its web seeds (BEP 19).
+
+use std::collections::BTreeMap;
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::{
+    layout::{tree_from_paths, InputFile, Pieces, URLFile},
+    Result,
+};
+
+use super::{encode_segment, hex, read_source};
+
+pub fn command() -> Command {
+    Command::new("torrent")
+        .about("Emit a layout of a torrent's files served by its web seeds")
+        .arg(
+            Arg::new("TORRENT")
+                .required(true)
+                .help("URL or path of the .torrent file"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let data = read_source(matches.get_one::<String>("TORRENT").unwrap())?;
+    to_layout(&data)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Bencode::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn string(&self) -> Option<String> {
+        self.bytes()
+            .map(|b| String::from_utf8_lossy(b).into_owned())
+    }
+
+    fn list(&self) -> Option<&[Bencode]> {
+        match self {
+            Bencode::List(l) => Some(l),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes one bencoded value from the front of `data`, returning it and
+/// the remaining input.
+fn decode(data: &[u8]) -> Result<(Bencode, &[u8])> {
+    let invalid = || -> Box<dyn std::error::Error> { "Invalid bencoding".into() };
+    match data.first().ok_or_else(invalid)? {
+        b'i' => {
+            let end = data.iter().position(|&b| b == b'e').ok_or_else(invalid)?;
+            let value = std::str::from_utf8(&data[1..end])?.parse()?;
+            Ok((Bencode::Int(value), &data[end + 1..]))
+        }
+        b'l' => {
+            let mut rest = &data[1..];
+            let mut items = Vec::new();
+            while rest.first() != Some(&b'e') {
+                let (item, next) = decode(rest)?;
+                items.push(item);
+                rest = next;
+            }
+            Ok((Bencode::List(items), &rest[1..]))
+        }
+        b'd' => {
+            let mut rest = &data[1..];
+            let mut dict = BTreeMap::new();
+            while rest.first() != Some(&b'e') {
+                let (key, next) = decode(rest)?;
+                let (value, next) = decode(next)?;
+                dict.insert(key.bytes().ok_or_else(invalid)?.to_vec(), value);
+                rest = next;
+            }
+            Ok((Bencode::Dict(dict), &rest[1..]))
+        }
+        b'0'..=b'9' => {
+            let colon = data.iter().position(|&b| b == b':').ok_or_else(invalid)?;
+            let len: usize = std::str::from_utf8(&data[..colon])?.parse()?;
+            let start = colon + 1;
+            let bytes = data.get(start..start + len).ok_or_else(invalid)?;
+            Ok((Bencode::Bytes(bytes.to_vec()), &data[start + len..]))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn to_layout(data: &[u8]) -> Result<Vec<InputFile>> {
+    let (torrent, _) = decode(data)?;
+    let info = torrent
+        .get("info")
+        .ok_or("The torrent has no info dictionary")?;
+    let name = info
+        .get("name.utf-8")
+        .or_else(|| info.get("name"))
+        .and_then(Bencode::string)
+        .ok_or("The torrent has no name")?;
+    let piece_length = info
+        .get("piece length")
+        .and_then(Bencode::int)
+        .ok_or("The torrent has no piece length")? as u64;
+    let hashes: Vec<String> = info
+        .get("pieces")
+        .and_then(Bencode::bytes)
+        .unwrap_or_default()
+        .chunks(20)
+        .map(hex)
+        .collect();
+    let seeds: Vec<String> = match torrent.get("url-list") {
+        Some(Bencode::List(list)) => list.iter().filter_map(Bencode::string).collect(),
+        Some(seed) => seed.string().into_iter().collect(),
+        None => Vec::new(),
+    };
+    if seeds.is_empty() {
+        return Err("The torrent has no web seeds (url-list)".into());
+    }
+
+    // (path below the torrent's name, length); a single-file torrent is
+    // the one file with an empty path.
+    let files: Vec<(Vec<String>, u64)> = match info.get("files").and_then(Bencode::list) {
+        Some(files) => files
+            .iter()
+            .filter_map(|file| {
+                let length = file.get("length")?.int()? as u64;
+                let path = file.get("path.utf-8").or_else(|| file.get("path"))?;
+                let path = path.list()?.iter().filter_map(Bencode::string).collect();
+                Some((path, length))
+            })
+            .collect(),
+        None => vec![(
+            Vec::new(),
+            info.get("length").and_then(Bencode::int).unwrap_or(0) as u64,
+        )],
+    };
+
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    for (path, length) in files {
+        let urls: Vec<_> = seeds
+            .iter()
+            .map(|seed| seed_url(seed, &name, &path))
+            .collect();
+        let first = (offset / piece_length) as usize;
+        let last = if length == 0 {
+            first
+        } else {
+            ((offset + length - 1) / piece_length + 1) as usize
+        };
+        let mut file = URLFile::new("", urls[0].clone(), length);
+        file.mirrors = urls[1..].to_vec();
+        file.pieces = Some(Pieces {
+            length: piece_length,
+            offset,
+            sha1: hashes[first.min(hashes.len())..last.min(hashes.len())].to_vec(),
+        });
+        let mut full_path = vec![name.clone()];
+        full_path.extend(path);
+        entries.push((full_path.join("/"), InputFile::URLFile(file)));
+        offset += length;
+    }
+    Ok(tree_from_paths(entries))
+}
+
+/// BEP 19: a seed ending in `/` names a directory holding the torrent's
+/// name, otherwise a single-file seed is the file itself.
+fn seed_url(seed: &str, name: &str, path: &[String]) -> String {
+    if path.is_empty() && !seed.ends_with('/') {
+        return seed.to_string();
+    }
+    let mut url = seed.trim_end_matches('/').to_string();
+    for segment in std::iter::once(name).chain(path.iter().map(String::as_str)) {
+        url.push('/');
+        url.push_str(&encode_segment(segment));
+    }
+    url
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::{decode, to_layout, Bencode};
+
+    #[test]
+    fn bencode() {
+        let (value, rest) = decode(b"d3:cow3:moo4:spaml1:a1:bi-3eee!").unwrap();
+        assert_eq!(rest, b"!");
+        assert_eq!(value.get("cow"), Some(&Bencode::Bytes(b"moo".to_vec())));
+        assert_eq!(value.get("spam").unwrap().list().unwrap().len(), 3);
+        assert!(decode(b"5:abc").is_err());
+    }
+
+    #[test]
+    fn multi_file_torrent() {
+        let pieces = [[1u8; 20], [2; 20], [3; 20], [4; 20]].concat();
+        let mut torrent = b"d8:url-listl19:https://a.example/d19:https://b.example/de4:infod5:filesl\
+d6:lengthi20e4:pathl3:one5:a.txteed6:lengthi30e4:pathl5:b.bineee4:name4:data12:piece lengthi16e6:pieces80:"
+            .to_vec();
+        torrent.extend_from_slice(&pieces);
+        torrent.extend_from_slice(b"ee");
+        let files = to_layout(&torrent).unwrap();
+        let [InputFile::Directory(data)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        let [InputFile::Directory(one), InputFile::URLFile(b)] = &data.contents[..] else {
+            panic!("Unexpected layout {:?}", data.contents);
+        };
+        let InputFile::URLFile(a) = &one.contents[0] else {
+            panic!("Unexpected layout {:?}", one.contents);
+        };
+        assert_eq!(a.url, "https://a.example/d/data/one/a.txt");
+        assert_eq!(a.mirrors, ["https://b.example/d/data/one/a.txt"]);
+        let a_pieces = a.pieces.as_ref().unwrap();
+        assert_eq!((a_pieces.offset, a_pieces.sha1.len()), (0, 2));
+        let b_pieces = b.pieces.as_ref().unwrap();
+        assert_eq!((b_pieces.offset, b_pieces.sha1.len()), (20, 3));
+        assert_eq!(b_pieces.sha1[0], "02".repeat(20));
+    }
+}
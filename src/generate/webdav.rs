@@ -0,0 +1,243 @@
+//! `generate webdav`: a layout of a WebDAV share such as a Nextcloud
+//! folder, listed with `PROPFIND`.
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use curl::easy::{Auth as CurlAuth, Easy, List};
+use percent_encoding::percent_decode_str;
+use roxmltree::{Document, Node};
+use url::Url;
+
+use crate::{
+    fetch::{dav, perform},
+    layout::{Auth, Directory, InputFile, URLFile},
+    Result,
+};
+
+pub fn command() -> Command {
+    Command::new("webdav")
+        .about("Emit a layout of a WebDAV share, listing it recursively")
+        .arg(
+            Arg::new("URL")
+                .required(true)
+                .help("davs://, dav:// or http(s):// URL of the folder"),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .help("User to list the share as"),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .env("DAV_PASSWORD")
+                .hide_env_values(true)
+                .help("Password or app token for --user"),
+        )
+        .arg(
+            Arg::new("embed-password")
+                .long("embed-password")
+                .action(ArgAction::SetTrue)
+                .help("Also write the credentials into the layout so the mount can authenticate"),
+        )
+}
+
+struct Credentials<'a> {
+    user: &'a str,
+    password: &'a str,
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let url = matches.get_one::<String>("URL").unwrap();
+    let url = match url.split_once("://") {
+        Some(("dav" | "davs", _)) => dav::http_url(url)?,
+        _ => url.clone(),
+    };
+    let mut url = Url::parse(&url)?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    let credentials = matches.get_one::<String>("user").map(|user| Credentials {
+        user,
+        password: matches
+            .get_one::<String>("password")
+            .map_or("", String::as_str),
+    });
+    let auth = credentials
+        .as_ref()
+        .filter(|_| matches.get_flag("embed-password"))
+        .map(|c| Auth::Basic {
+            username: c.user.to_string(),
+            password: c.password.to_string(),
+        });
+    walk(&url, credentials.as_ref(), auth.as_ref())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct DavEntry {
+    href: String,
+    collection: bool,
+    size: u64,
+    content_type: Option<String>,
+}
+
+fn walk(
+    url: &Url,
+    credentials: Option<&Credentials>,
+    auth: Option<&Auth>,
+) -> Result<Vec<InputFile>> {
+    let listing = propfind(url, credentials)?;
+    let mut files = Vec::new();
+    for entry in parse(&String::from_utf8_lossy(&listing))? {
+        let entry_url = url.join(&entry.href)?;
+        if entry_url.path().trim_end_matches('/') == url.path().trim_end_matches('/') {
+            continue;
+        }
+        let Some(name) = entry_url
+            .path_segments()
+            .and_then(|mut s| s.rfind(|s| !s.is_empty()))
+        else {
+            continue;
+        };
+        let name = percent_decode_str(name).decode_utf8_lossy().into_owned();
+        if entry.collection {
+            let mut dir_url = entry_url.clone();
+            if !dir_url.path().ends_with('/') {
+                let path = format!("{}/", dir_url.path());
+                dir_url.set_path(&path);
+            }
+            let contents = walk(&dir_url, credentials, auth)?;
+            files.push(InputFile::Directory(Directory::new(name, contents)));
+        } else {
+            let scheme = if entry_url.scheme() == "http" {
+                "dav"
+            } else {
+                "davs"
+            };
+            let dav_url = format!(
+                "{}{}",
+                scheme,
+                &entry_url.as_str()[entry_url.scheme().len()..]
+            );
+            let mut file = URLFile::new(name, dav_url, entry.size);
+            file.options.content_type = entry.content_type;
+            file.options.auth = auth.cloned();
+            files.push(InputFile::URLFile(file));
+        }
+    }
+    Ok(files)
+}
+
+const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getcontenttype/></d:prop></d:propfind>"#;
+
+/// Lists the immediate children of `url`. Servers commonly refuse
+/// `Depth: infinity`, so deeper levels get their own requests.
+fn propfind(url: &Url, credentials: Option<&Credentials>) -> Result<Vec<u8>> {
+    let mut curl = Easy::new();
+    curl.url(url.as_str())?;
+    curl.custom_request("PROPFIND")?;
+    curl.follow_location(true)?;
+    curl.useragent(concat!("lhttpfs/", env!("CARGO_PKG_VERSION")))?;
+    curl.post_fields_copy(PROPFIND.as_bytes())?;
+    let mut list = List::new();
+    list.append("Depth: 1")?;
+    list.append("Content-Type: application/xml; charset=utf-8")?;
+    curl.http_headers(list)?;
+    if let Some(credentials) = credentials {
+        let mut auth = CurlAuth::new();
+        auth.basic(true).digest(true);
+        curl.http_auth(&auth)?;
+        curl.username(credentials.user)?;
+        curl.password(credentials.password)?;
+    }
+    perform(curl)
+}
+
+fn parse(xml: &str) -> Result<Vec<DavEntry>> {
+    let document = Document::parse(xml)?;
+    let dav = |node: &Node, name: &str| {
+        node.tag_name().namespace() == Some("DAV:") && node.tag_name().name() == name
+    };
+    let mut entries = Vec::new();
+    for response in document.descendants().filter(|n| dav(n, "response")) {
+        let Some(href) = response
+            .children()
+            .find(|n| dav(n, "href"))
+            .and_then(|n| n.text())
+        else {
+            continue;
+        };
+        // Only the properties the server found are of interest, not the
+        // `404` propstat listing the missing ones.
+        let props = response
+            .children()
+            .filter(|n| dav(n, "propstat"))
+            .filter(|propstat| {
+                propstat
+                    .children()
+                    .find(|n| dav(n, "status"))
+                    .and_then(|n| n.text())
+                    .is_none_or(|status| status.contains(" 200 "))
+            })
+            .flat_map(|propstat| propstat.children().filter(|n| dav(n, "prop")))
+            .flat_map(|prop| prop.children())
+            .collect::<Vec<_>>();
+        let prop = |name| props.iter().find(|n| dav(n, name));
+        entries.push(DavEntry {
+            href: href.trim().to_string(),
+            collection: prop("resourcetype")
+                .is_some_and(|n| n.children().any(|c| dav(&c, "collection"))),
+            size: prop("getcontentlength")
+                .and_then(|n| n.text())
+                .and_then(|size| size.trim().parse().ok())
+                .unwrap_or(0),
+            content_type: prop("getcontenttype")
+                .and_then(|n| n.text())
+                .map(|t| t.trim().to_string()),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, DavEntry};
+
+    #[test]
+    fn multistatus() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/remote.php/dav/files/me/Data/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+    <d:propstat><d:prop><d:getcontentlength/><d:getcontenttype/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/me/Data/a%20b.csv</d:href>
+    <d:propstat><d:prop><d:resourcetype/><d:getcontentlength>12</d:getcontentlength>
+      <d:getcontenttype>text/csv</d:getcontenttype></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(
+            parse(xml).unwrap(),
+            [
+                DavEntry {
+                    href: "/remote.php/dav/files/me/Data/".into(),
+                    collection: true,
+                    size: 0,
+                    content_type: None,
+                },
+                DavEntry {
+                    href: "/remote.php/dav/files/me/Data/a%20b.csv".into(),
+                    collection: false,
+                    size: 12,
+                    content_type: Some("text/csv".into()),
+                }
+            ]
+        );
+    }
+}
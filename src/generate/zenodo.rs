@@ -0,0 +1,205 @@
+//! `generate zenodo`: the files of a Zenodo record, found by record id,
+//! record URL or DOI. DOIs registered elsewhere with DataCite are resolved
+//! through its API.
+
+use clap::{Arg, ArgMatches, Command};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    layout::{InputFile, URLFile},
+    Result,
+};
+
+use super::{feed::url_file_name, get_json, head_size};
+
+pub fn command() -> Command {
+    Command::new("zenodo")
+        .about("Emit a layout of a Zenodo record or DataCite DOI")
+        .arg(
+            Arg::new("RECORD")
+                .required(true)
+                .help("Record id, record URL, or DOI such as 10.5281/zenodo.123456"),
+        )
+        .arg(
+            Arg::new("endpoint")
+                .long("endpoint")
+                .default_value("https://zenodo.org")
+                .help("Zenodo instance, such as https://sandbox.zenodo.org"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .env("ZENODO_TOKEN")
+                .hide_env_values(true)
+                .help("Access token for restricted records"),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    files: Vec<RecordFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordFile {
+    key: String,
+    size: u64,
+    checksum: Option<String>,
+    links: Links,
+}
+
+#[derive(Debug, Deserialize)]
+struct Links {
+    #[serde(rename = "self")]
+    this: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DataCite {
+    data: DataCiteData,
+}
+
+#[derive(Debug, Deserialize)]
+struct DataCiteData {
+    attributes: DataCiteAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DataCiteAttributes {
+    url: Option<String>,
+    #[serde(default)]
+    content_url: Vec<String>,
+}
+
+/// How a record was named on the command line.
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    Zenodo(String),
+    Doi(String),
+}
+
+fn target(record: &str) -> Target {
+    let record = record.trim();
+    if record.chars().all(|c| c.is_ascii_digit()) {
+        return Target::Zenodo(record.into());
+    }
+    let doi = record
+        .trim_start_matches("https://doi.org/")
+        .trim_start_matches("doi:");
+    if let Some(id) = doi.strip_prefix("10.5281/zenodo.") {
+        return Target::Zenodo(id.into());
+    }
+    if let Some(id) = zenodo_id(record) {
+        return Target::Zenodo(id);
+    }
+    Target::Doi(doi.into())
+}
+
+/// The record id of a `https://zenodo.org/records/<id>` style URL.
+fn zenodo_id(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let mut segments = url.path_segments()?;
+    match segments.next()? {
+        "record" | "records" => segments.next().map(String::from),
+        _ => None,
+    }
+}
+
+pub fn run(matches: &ArgMatches) -> Result<Vec<InputFile>> {
+    let endpoint = matches.get_one::<String>("endpoint").unwrap();
+    let headers: Vec<_> = matches
+        .get_one::<String>("token")
+        .map(|token| format!("Authorization: Bearer {}", token))
+        .into_iter()
+        .collect();
+    let id = match target(matches.get_one::<String>("RECORD").unwrap()) {
+        Target::Zenodo(id) => id,
+        Target::Doi(doi) => {
+            let url = format!("https://api.datacite.org/dois/{}", doi);
+            let record: DataCite = get_json(&url, &[])?;
+            let attributes = record.data.attributes;
+            match attributes.url.as_deref().and_then(zenodo_id) {
+                Some(id) => id,
+                None => return Ok(content_urls(attributes.content_url)),
+            }
+        }
+    };
+    let record: Record = get_json(&format!("{}/api/records/{}", endpoint, id), &headers)?;
+    Ok(to_layout(record))
+}
+
+fn to_layout(record: Record) -> Vec<InputFile> {
+    record
+        .files
+        .into_iter()
+        .map(|file| {
+            let mut entry = URLFile::new(file.key, file.links.this, file.size);
+            if let Some((algorithm, digest)) =
+                file.checksum.as_deref().and_then(|c| c.split_once(':'))
+            {
+                match algorithm {
+                    "md5" => entry.md5 = Some(digest.into()),
+                    "sha256" => entry.sha256 = Some(digest.into()),
+                    _ => {}
+                }
+            }
+            InputFile::URLFile(entry)
+        })
+        .collect()
+}
+
+/// DataCite only knows where the files are, so their sizes come from HEAD.
+fn content_urls(urls: Vec<String>) -> Vec<InputFile> {
+    urls.into_iter()
+        .map(|url| {
+            let size = head_size(&url, &[]).unwrap_or_else(|e| {
+                warn!("HEAD {} failed: {}", url, e);
+                None
+            });
+            InputFile::URLFile(URLFile::new(url_file_name(&url), url, size.unwrap_or(0)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::InputFile;
+
+    use super::{target, to_layout, Record, Target};
+
+    #[test]
+    fn targets() {
+        assert_eq!(target("123456"), Target::Zenodo("123456".into()));
+        assert_eq!(target("10.5281/zenodo.42"), Target::Zenodo("42".into()));
+        assert_eq!(
+            target("https://doi.org/10.5281/zenodo.42"),
+            Target::Zenodo("42".into())
+        );
+        assert_eq!(
+            target("https://zenodo.org/records/7"),
+            Target::Zenodo("7".into())
+        );
+        assert_eq!(target("10.1234/abc"), Target::Doi("10.1234/abc".into()));
+    }
+
+    #[test]
+    fn record_files() {
+        let record: Record = serde_json::from_str(
+            r#"{"id": 42, "files": [{"id": "f", "key": "data.csv", "size": 1024,
+                "checksum": "md5:d41d8cd98f00b204e9800998ecf8427e",
+                "links": {"self": "https://zenodo.org/api/records/42/files/data.csv/content"}}]}"#,
+        )
+        .unwrap();
+        let files = to_layout(record);
+        let [InputFile::URLFile(file)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!((file.name.as_str(), file.size), ("data.csv", 1024));
+        assert_eq!(
+            file.md5.as_deref(),
+            Some("d41d8cd98f00b204e9800998ecf8427e")
+        );
+    }
+}
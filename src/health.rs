@@ -0,0 +1,163 @@
+//! `.lhttpfs/health` and `--health-file`: whether the origins answered the
+//! last fetch, and the last error if not, for a liveness probe or a script
+//! to gate a workload on the mount.
+
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use clap::{value_parser, Arg};
+use serde_json::json;
+use tracing::warn;
+
+use crate::fetch::date;
+
+/// What every mount of the process reports.
+pub static HEALTH: Health = Health::new();
+
+/// How often `--health-file` is written.
+const INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn arg() -> Arg {
+    Arg::new("health-file")
+        .long("health-file")
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .help("Keep FILE up to date with what .lhttpfs/health says, removing it on unmount")
+}
+
+pub struct Health {
+    state: Mutex<State>,
+}
+
+struct State {
+    last_success: Option<SystemTime>,
+    last_error: Option<(SystemTime, String)>,
+}
+
+impl Health {
+    const fn new() -> Health {
+        Health {
+            state: Mutex::new(State {
+                last_success: None,
+                last_error: None,
+            }),
+        }
+    }
+
+    /// Counts a fetch from a backend that worked.
+    pub fn succeeded(&self) {
+        self.state.lock().unwrap().last_success = Some(SystemTime::now());
+    }
+
+    /// Counts a fetch from a backend that failed with `error`.
+    pub fn failed(&self, error: impl Display) {
+        self.state.lock().unwrap().last_error = Some((SystemTime::now(), error.to_string()));
+    }
+
+    /// The report, as JSON. The origins are reachable if the last fetch
+    /// worked, and unknown before the first; only a failed last fetch makes
+    /// the mount unhealthy.
+    pub fn report(&self) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        let reachable = match (state.last_success, &state.last_error) {
+            (None, None) => None,
+            (_, None) => Some(true),
+            (success, Some((failure, _))) => Some(success > Some(*failure)),
+        };
+        let report = json!({
+            "healthy": reachable != Some(false),
+            "reachable": reachable,
+            "last_success": state.last_success.map(date::rfc3339),
+            "last_error": state.last_error.as_ref().map(|(_, error)| error),
+            "last_error_time": state.last_error.as_ref().map(|(time, _)| date::rfc3339(*time)),
+        });
+        let mut json = serde_json::to_vec_pretty(&report).unwrap();
+        json.push(b'\n');
+        json
+    }
+}
+
+/// `--health-file`, removed again when dropped.
+pub struct File {
+    path: PathBuf,
+}
+
+impl File {
+    /// Writes the report to `path` once, so a path that can't be written
+    /// fails the mount before it is up.
+    pub fn create(path: &Path) -> io::Result<File> {
+        let file = File {
+            path: std::path::absolute(path)?,
+        };
+        write(&file.path)?;
+        Ok(file)
+    }
+
+    /// Writes the report every few seconds from a thread of its own.
+    pub fn update(&self) {
+        let path = self.path.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(INTERVAL);
+            if let Err(e) = write(&path) {
+                warn!("Writing {} failed: {}", path.display(), e);
+            }
+        });
+    }
+}
+
+/// Replaces the file at `path` with a new report, in one rename so that a
+/// reader never sees half of one.
+fn write(path: &Path) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, HEALTH.report())?;
+    fs::rename(&partial, path)
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{File, Health};
+
+    #[test]
+    fn reported() {
+        let health = Health::new();
+        let report = |health: &Health| -> serde_json::Value {
+            serde_json::from_slice(&health.report()).unwrap()
+        };
+        assert_eq!(report(&health)["healthy"], true);
+        assert!(report(&health)["reachable"].is_null());
+        health.failed("Couldn't connect to server");
+        let failed = report(&health);
+        assert_eq!(failed["healthy"], false);
+        assert_eq!(failed["reachable"], false);
+        assert_eq!(failed["last_error"], "Couldn't connect to server");
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        health.succeeded();
+        let recovered = report(&health);
+        assert_eq!(recovered["healthy"], true);
+        assert_eq!(recovered["last_error"], "Couldn't connect to server");
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-health-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(report.get("healthy").is_some());
+        drop(file);
+        assert!(!path.exists());
+    }
+}
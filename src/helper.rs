@@ -0,0 +1,202 @@
+//! `mount.lhttpfs`: mount(8)'s calling convention, `mount.lhttpfs LAYOUT
+//! DIR [-sfnv] [-o OPTIONS]`, for fstab entries and autofs maps. Run under
+//! that name, through a link, the command line is rewritten into the
+//! `mount` subcommand's.
+
+use std::{error::Error, ffi::OsString, fmt::Display, path::Path};
+
+use clap::Command;
+use fuser::MountOption;
+use tracing::warn;
+
+use crate::Result;
+
+/// The names mount(8) looks for `-t lhttpfs` and `-t fuse.lhttpfs` by,
+/// and macOS's `mount_lhttpfs`.
+const NAMES: [&str; 3] = ["mount.lhttpfs", "mount.fuse.lhttpfs", "mount_lhttpfs"];
+
+/// Options mount(8) and systemd act on themselves, which mean nothing to
+/// the filesystem. Anything starting with `x-` is left alone too.
+const IGNORED: &[&str] = &[
+    "defaults",
+    "rw",
+    "ro",
+    "auto",
+    "noauto",
+    "user",
+    "nouser",
+    "users",
+    "owner",
+    "group",
+    "nofail",
+    "_netdev",
+    "exec",
+    "noexec",
+    "suid",
+    "nosuid",
+    "dev",
+    "nodev",
+    "atime",
+    "noatime",
+    "relatime",
+    "strictatime",
+    "nodiratime",
+    "sync",
+    "async",
+    "comment",
+];
+
+/// `option` as FUSE takes it, for those of the filesystem itself rather
+/// than of mount(8) or lhttpfs: `allow_other`, `default_permissions`,
+/// `fsname=` and `subtype=`.
+pub fn fuse_option(option: &str) -> Option<MountOption> {
+    match option.split_once('=') {
+        Some(("fsname", name)) => Some(MountOption::FSName(name.to_string())),
+        Some(("subtype", name)) => Some(MountOption::Subtype(name.to_string())),
+        Some(_) => None,
+        None => match option {
+            "allow_other" => Some(MountOption::AllowOther),
+            "default_permissions" => Some(MountOption::DefaultPermissions),
+            _ => None,
+        },
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownOption(String);
+
+impl Display for UnknownOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown mount option `{}`", self.0)
+    }
+}
+
+impl Error for UnknownOption {}
+
+/// The command line to parse: `args` itself unless the program was run as
+/// `mount.lhttpfs`, when it becomes the equivalent `lhttpfs mount --daemon`,
+/// since mount(8) waits for the helper to exit. `-o` options are the long
+/// options of `mount`, with `_` for `-` as FUSE spells them, or
+/// `name=value` for those taking a value, and FUSE's own, passed on with
+/// `-o`. `None` means there's nothing to do, for `-f`.
+pub fn args(args: Vec<OsString>, mount: &Command) -> Result<Option<Vec<OsString>>> {
+    let name = args.first().map(Path::new).and_then(Path::file_name);
+    if !name.is_some_and(|name| NAMES.iter().any(|n| name == *n)) {
+        return Ok(Some(args));
+    }
+    let (mut positional, mut options) = (Vec::new(), Vec::new());
+    let (mut sloppy, mut fake, mut verbose) = (false, false, false);
+    let mut rest = args.into_iter().skip(1);
+    while let Some(arg) = rest.next() {
+        let arg = arg.into_string().map_err(|_| "Arguments must be UTF-8")?;
+        let Some(flags) = arg.strip_prefix('-') else {
+            positional.push(arg);
+            continue;
+        };
+        for (i, flag) in flags.char_indices() {
+            match flag {
+                's' => sloppy = true,
+                'f' => fake = true,
+                'v' => verbose = true,
+                'n' => {}
+                'o' | 't' | 'N' => {
+                    let value = match &flags[i + 1..] {
+                        "" => rest.next().and_then(|value| value.into_string().ok()),
+                        value => Some(value.to_string()),
+                    };
+                    let value = value.ok_or_else(|| format!("-{} needs a value", flag))?;
+                    match flag {
+                        'o' => options.extend(value.split(',').map(str::to_string)),
+                        'N' => return Err("Mount namespaces (-N) aren't supported".into()),
+                        _ => {}
+                    }
+                    break;
+                }
+                _ => return Err(format!("Unknown flag -{}", flag).into()),
+            }
+        }
+    }
+    let [layouts, dir] = <[String; 2]>::try_from(positional)
+        .map_err(|_| "Expected mount.lhttpfs LAYOUT[,LAYOUT...] DIR [-o OPTIONS]")?;
+    if fake {
+        return Ok(None);
+    }
+    let mut command = vec!["lhttpfs".to_string(), "mount".into(), "--daemon".into()];
+    if verbose {
+        command.push("-v".into());
+    }
+    for option in options {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option.as_str(), None),
+        };
+        if name.is_empty() || IGNORED.contains(&name) || name.starts_with("x-") {
+            continue;
+        }
+        let long = [name.to_string(), name.replace('_', "-")]
+            .into_iter()
+            .find(|long| (mount.get_arguments()).any(|arg| arg.get_long() == Some(long)));
+        match (long, value) {
+            (Some(long), Some(value)) => command.push(format!("--{}={}", long, value)),
+            (Some(long), None) => command.push(format!("--{}", long)),
+            (None, _) if fuse_option(&option).is_some() => {
+                command.extend(["-o".to_string(), option]);
+            }
+            (None, _) if sloppy => warn!("Ignoring unknown mount option {}", option),
+            (None, _) => return Err(Box::new(UnknownOption(option))),
+        }
+    }
+    command.push(dir);
+    command.extend(layouts.split(',').map(str::to_string));
+    Ok(Some(command.into_iter().map(OsString::from).collect()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+
+    use clap::{Arg, ArgAction, Command};
+
+    use super::args;
+
+    fn mount() -> Command {
+        Command::new("mount")
+            .arg(
+                Arg::new("allow-root")
+                    .long("allow-root")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(Arg::new("cache-dir").long("cache-dir"))
+    }
+
+    fn rewrite(line: &str) -> Option<String> {
+        let line = line.split(' ').map(OsString::from).collect();
+        let args = args(line, &mount()).unwrap()?;
+        Some(args.join(" ".as_ref()).into_string().unwrap())
+    }
+
+    #[test]
+    fn helper() {
+        assert_eq!(
+            rewrite("/sbin/mount.lhttpfs a.json,b.json /mnt -o rw,allow_root,cache_dir=/c,x-systemd.automount"),
+            Some("lhttpfs mount --daemon --allow-root --cache-dir=/c /mnt a.json b.json".into())
+        );
+        assert_eq!(
+            rewrite("mount.lhttpfs a.json /mnt -o allow_other,fsname=assets,noatime"),
+            Some("lhttpfs mount --daemon -o allow_other -o fsname=assets /mnt a.json".into())
+        );
+        assert_eq!(
+            rewrite("mount.fuse.lhttpfs a.json /mnt -v -oallow-root"),
+            Some("lhttpfs mount --daemon -v --allow-root /mnt a.json".into())
+        );
+        assert_eq!(rewrite("mount.lhttpfs a.json /mnt -f"), None);
+        assert_eq!(
+            rewrite("lhttpfs mount /mnt a.json"),
+            Some("lhttpfs mount /mnt a.json".into())
+        );
+        let helper = |line: &str| args(line.split(' ').map(OsString::from).collect(), &mount());
+        assert!(helper("mount.lhttpfs a.json /mnt -o bogus").is_err());
+        assert!(helper("mount.lhttpfs a.json /mnt -s -o bogus").is_ok());
+        assert!(helper("mount.lhttpfs a.json").is_err());
+    }
+}
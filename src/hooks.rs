@@ -0,0 +1,206 @@
+//! `--on-event`: a command run by `sh` for notable events, with what
+//! happened in `LHTTPFS_*` environment variables, to send notifications or
+//! prefetch more without changing the crate. Commands run one at a time
+//! from a thread of their own, so a slow one holds up later events but
+//! never a read.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        OnceLock,
+    },
+};
+
+use clap::Arg;
+use tracing::warn;
+
+/// How many events may wait for the command before more are dropped.
+const MAX_QUEUED: usize = 1024;
+
+static QUEUE: OnceLock<SyncSender<Vec<(&'static str, String)>>> = OnceLock::new();
+
+pub fn arg() -> Arg {
+    Arg::new("on-event")
+        .long("on-event")
+        .value_name("COMMAND")
+        .help("Run COMMAND with sh for first accesses, failed fetches, evictions and reloads")
+}
+
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A file of a mount was opened for the first time.
+    Access {
+        mount: &'a str,
+        path: &'a str,
+        inode: u64,
+    },
+    FetchFailed {
+        url: &'a str,
+        error: String,
+    },
+    /// Cached bytes were dropped, `reason` being `expired` or `flushed`.
+    Evicted {
+        key: &'a str,
+        reason: &'static str,
+    },
+    Reloaded {
+        mount: &'a str,
+        inodes: usize,
+    },
+}
+
+impl Event<'_> {
+    /// The environment the command gets for the event.
+    fn vars(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Event::Access { mount, path, inode } => vec![
+                ("LHTTPFS_EVENT", "access".into()),
+                ("LHTTPFS_MOUNT", mount.to_string()),
+                ("LHTTPFS_PATH", path.to_string()),
+                ("LHTTPFS_INODE", inode.to_string()),
+            ],
+            Event::FetchFailed { url, error } => vec![
+                ("LHTTPFS_EVENT", "fetch-failed".into()),
+                ("LHTTPFS_URL", url.to_string()),
+                ("LHTTPFS_ERROR", error.clone()),
+            ],
+            Event::Evicted { key, reason } => vec![
+                ("LHTTPFS_EVENT", "evicted".into()),
+                ("LHTTPFS_KEY", key.to_string()),
+                ("LHTTPFS_REASON", reason.to_string()),
+            ],
+            Event::Reloaded { mount, inodes } => vec![
+                ("LHTTPFS_EVENT", "reloaded".into()),
+                ("LHTTPFS_MOUNT", mount.to_string()),
+                ("LHTTPFS_INODES", inodes.to_string()),
+            ],
+        }
+    }
+}
+
+/// Runs `command` for every event from now on.
+pub fn init(command: String) {
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED);
+    if QUEUE.set(sender).is_ok() {
+        std::thread::spawn(move || {
+            for vars in receiver {
+                match run(&command, &vars) {
+                    Ok(status) if !status.success() => {
+                        warn!("`{}` failed for {}: {}", command, vars[0].1, status)
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Running `{}` failed: {}", command, e),
+                }
+            }
+        });
+    }
+}
+
+/// Has the command run for `event`, unless there isn't one.
+pub fn fire(event: Event) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    if let Err(TrySendError::Full(vars)) = queue.try_send(event.vars()) {
+        warn!("Too many events queued, dropping {}", vars[0].1);
+    }
+}
+
+fn run(command: &str, vars: &[(&'static str, String)]) -> io::Result<ExitStatus> {
+    let mut sh = Command::new("sh");
+    sh.args(["-c", command])
+        .envs(vars.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null());
+    // The signals the mount waits for are blocked in its threads, and would
+    // stay blocked in the command.
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        sh.pre_exec(|| {
+            let mut signals = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &signals, std::ptr::null_mut());
+            Ok(())
+        })
+    };
+    sh.status()
+}
+
+/// The files of one mount, by inode, and which were opened, for `access`
+/// and `reloaded` events.
+pub struct Mount {
+    pub root: PathBuf,
+    pub paths: HashMap<u64, String>,
+    opened: HashSet<u64>,
+}
+
+impl Mount {
+    pub fn new(root: PathBuf, paths: HashMap<u64, String>) -> Mount {
+        Mount {
+            root,
+            paths,
+            opened: HashSet::new(),
+        }
+    }
+
+    /// Fires `access` if the file `inode` wasn't opened before.
+    pub fn opened(&mut self, inode: u64) {
+        if !self.opened.insert(inode) {
+            return;
+        }
+        if let Some(path) = self.paths.get(&inode) {
+            fire(Event::Access {
+                mount: &self.root.to_string_lossy(),
+                path,
+                inode,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run, Event};
+
+    #[test]
+    fn ran() {
+        let event = Event::FetchFailed {
+            url: "https://example.com/a",
+            error: "timed out".into(),
+        };
+        let path = std::env::temp_dir().join(format!("lhttpfs-hook-{}", std::process::id()));
+        let command = format!(
+            "echo \"$LHTTPFS_EVENT $LHTTPFS_URL $LHTTPFS_ERROR\" > {}",
+            path.display()
+        );
+        assert!(run(&command, &event.vars()).unwrap().success());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "fetch-failed https://example.com/a timed out\n"
+        );
+        std::fs::remove_file(path).unwrap();
+        assert!(!run("exit 3", &event.vars()).unwrap().success());
+    }
+
+    #[test]
+    fn vars() {
+        let event = Event::Access {
+            mount: "/mnt",
+            path: "/mnt/d/a",
+            inode: 5,
+        };
+        assert_eq!(
+            event.vars(),
+            [
+                ("LHTTPFS_EVENT", "access".to_string()),
+                ("LHTTPFS_MOUNT", "/mnt".into()),
+                ("LHTTPFS_PATH", "/mnt/d/a".into()),
+                ("LHTTPFS_INODE", "5".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,527 @@
+//! `validate`, `tree`, `du` and `compile`: work with the resolved layout
+//! without mounting it.
+
+use std::{io::Write, time::Duration};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use lhttpfs::fs::FileType;
+
+use lhttpfs::fetch::{HostPolicy, Retry};
+
+use crate::{
+    filter,
+    fs::LazyHTTPFS,
+    layout::{Defaults, Limits},
+    signature, Result,
+};
+
+fn layout_arg() -> Arg {
+    Arg::new("LAYOUT")
+        .required(true)
+        .num_args(1..)
+        .help("Files or http(s) URLs of the layout of the filesystem, merged in order")
+}
+
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_parser(["json", "toml", "yaml", "csv", "tsv"])
+        .help("What the layouts are written in, if not what their extensions say (JSON otherwise)")
+}
+
+fn on_conflict_arg() -> Arg {
+    Arg::new("on-conflict")
+        .long("on-conflict")
+        .value_parser(["error", "first", "last"])
+        .default_value("error")
+        .help("Which entry to keep when merged layouts have one at the same path")
+}
+
+fn profile_arg() -> Arg {
+    Arg::new("profile").long("profile").help(
+        "Apply the overrides of the named profile in the layout; \"streaming\" also \
+             tunes the mount for playing media from it",
+    )
+}
+
+fn checksum_files_arg() -> Arg {
+    Arg::new("checksum-files")
+        .long("checksum-files")
+        .action(ArgAction::SetTrue)
+        .help("Add a <name>.sha256 file next to each file with a sha256, for sha256sum -c")
+}
+
+fn auto_decompress_arg() -> Arg {
+    Arg::new("auto-decompress")
+        .long("auto-decompress")
+        .action(ArgAction::SetTrue)
+        .help("Serve .gz, .zst and .xz files, or files of those types, decompressed")
+}
+
+fn source_files_arg() -> Arg {
+    Arg::new("source-files")
+        .long("source-files")
+        .action(ArgAction::SetTrue)
+        .help("Add a hidden .<name>.url file next to each file, listing the URLs it is read from")
+}
+
+fn no_probe_arg() -> Arg {
+    Arg::new("no-probe")
+        .long("no-probe")
+        .action(ArgAction::SetTrue)
+        .help("Don't ask servers for the sizes the layout leaves out until each file is opened")
+}
+
+pub fn owner_args() -> [Arg; 4] {
+    [
+        Arg::new("uid")
+            .long("uid")
+            .value_parser(value_parser!(u32))
+            .help("Owner of the entries the layout gives none [default: the user running lhttpfs]"),
+        Arg::new("gid")
+            .long("gid")
+            .value_parser(value_parser!(u32))
+            .help("Group of the entries the layout gives none [default: the user's group]"),
+        Arg::new("file-mode")
+            .long("file-mode")
+            .value_name("MODE")
+            .value_parser(parse_mode)
+            .help("Permissions of the files the layout gives none, in octal [default: 0444]"),
+        Arg::new("dir-mode")
+            .long("dir-mode")
+            .value_name("MODE")
+            .value_parser(parse_mode)
+            .help("Permissions of the directories the layout gives none, in octal [default: 0555]"),
+    ]
+}
+
+fn parse_mode(mode: &str) -> std::result::Result<u16, String> {
+    match u16::from_str_radix(mode.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "Expected permissions in octal, such as 0644, got {:?}",
+            mode
+        )),
+    }
+}
+
+/// `defaults`, with the owner and permissions [`owner_args`] give in place
+/// of their own.
+pub fn owned(matches: &ArgMatches, defaults: &Defaults) -> Defaults {
+    Defaults {
+        uid: matches.get_one("uid").copied().or(defaults.uid),
+        gid: matches.get_one("gid").copied().or(defaults.gid),
+        mode: matches.get_one("file-mode").copied().or(defaults.mode),
+        dir_mode: matches.get_one("dir-mode").copied().or(defaults.dir_mode),
+        ..defaults.clone()
+    }
+}
+
+pub fn limit_args() -> [Arg; 5] {
+    [
+        Arg::new("max-depth")
+            .long("max-depth")
+            .value_parser(value_parser!(usize))
+            .help("Refuse layouts with directories nested deeper than this [default: 64]"),
+        Arg::new("max-entries")
+            .long("max-entries")
+            .value_parser(value_parser!(u64))
+            .help("Refuse layouts with more entries than this [default: 10000000]"),
+        Arg::new("max-name-length")
+            .long("max-name-length")
+            .value_parser(value_parser!(usize))
+            .help("Refuse layouts with longer entry names than this, in bytes [default: 255]"),
+        Arg::new("allow-filters")
+            .long("allow-filters")
+            .action(ArgAction::SetTrue)
+            .help("Run the \"filter\" commands of layouts, which can run anything"),
+        Arg::new("require-https")
+            .long("require-https")
+            .action(ArgAction::SetTrue)
+            .help("Refuse layouts with URLs read unencrypted, but for entries with \"allow_http\""),
+    ]
+}
+
+/// `--allow-control-writes`, without which the servers refuse writes to
+/// the control files, their clients being anyone who can connect.
+pub fn control_writes_arg() -> Arg {
+    Arg::new("allow-control-writes")
+        .long("allow-control-writes")
+        .action(ArgAction::SetTrue)
+        .help(
+            "Let clients write to the control files, to flush the cache or reload and add layouts",
+        )
+}
+
+/// The limits given by [`limit_args`], defaulting to [`Limits::default`].
+pub fn limits(matches: &ArgMatches) -> Limits {
+    let defaults = Limits::default();
+    Limits {
+        max_depth: matches
+            .get_one("max-depth")
+            .copied()
+            .unwrap_or(defaults.max_depth),
+        max_entries: matches
+            .get_one("max-entries")
+            .copied()
+            .unwrap_or(defaults.max_entries),
+        max_name_length: matches
+            .get_one("max-name-length")
+            .copied()
+            .unwrap_or(defaults.max_name_length),
+        filters: matches.get_flag("allow-filters"),
+        require_https: matches.get_flag("require-https"),
+    }
+}
+
+pub fn host_args() -> [Arg; 2] {
+    [
+        Arg::new("allow-host")
+            .long("allow-host")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Only fetch from hosts matching this glob or network, e.g. *.example.com or 10.0.0.0/8"),
+        Arg::new("deny-host")
+            .long("deny-host")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Never fetch from hosts matching this glob or network, even if allowed"),
+    ]
+}
+
+pub fn retry_args() -> [Arg; 2] {
+    [
+        Arg::new("retries")
+            .long("retries")
+            .value_name("N")
+            .value_parser(value_parser!(u32))
+            .default_value("2")
+            .help(
+                "Try fetches failing with a 5xx, a timeout or a lost connection up to N more times",
+            ),
+        Arg::new("retry-delay")
+            .long("retry-delay")
+            .value_name("MS")
+            .value_parser(value_parser!(u64))
+            .default_value("250")
+            .help("Wait MS milliseconds before the first retry, twice as long before each after"),
+    ]
+}
+
+/// How [`retry_args`] have failed fetches tried again.
+pub fn retry(matches: &ArgMatches) -> Retry {
+    Retry {
+        attempts: *matches.get_one("retries").unwrap(),
+        delay: Duration::from_millis(*matches.get_one("retry-delay").unwrap()),
+    }
+}
+
+/// The hosts [`host_args`] let the mount fetch from.
+pub fn hosts(matches: &ArgMatches) -> Result<HostPolicy> {
+    let patterns = |id| {
+        let patterns = matches.get_many::<String>(id).into_iter().flatten();
+        patterns.cloned().collect::<Vec<_>>()
+    };
+    Ok(HostPolicy::new(
+        &patterns("allow-host"),
+        &patterns("deny-host"),
+    )?)
+}
+
+/// Options that decide what is mounted, shared by mounting, `tree` and `du`.
+pub fn load_args() -> Vec<Arg> {
+    let mut args = vec![
+        layout_arg(),
+        format_arg(),
+        on_conflict_arg(),
+        profile_arg(),
+        checksum_files_arg(),
+        auto_decompress_arg(),
+        source_files_arg(),
+        no_probe_arg(),
+        signature::arg(),
+    ];
+    args.extend(filter::args());
+    args.extend(owner_args());
+    args.extend(limit_args());
+    args.extend(host_args());
+    args.extend(retry_args());
+    args
+}
+
+pub fn validate_command() -> Command {
+    Command::new("validate")
+        .about("Check that layouts can be mounted, without fetching anything")
+        .args(load_args())
+        // Sizes left out are left unknown.
+        .mut_arg("no-probe", |arg| arg.default_value("true").hide(true))
+}
+
+/// Loading the layout did the checking, so this only sums it up.
+pub fn run_validate(fs: &LazyHTTPFS, out: &mut impl Write) -> Result<()> {
+    let entries = fs.walk();
+    let dirs = entries.iter().filter(|e| e.is_dir()).count();
+    let files = entries.iter().filter(|e| !e.is_dir());
+    let total = files.clone().map(|e| e.attr.size).sum();
+    writeln!(
+        out,
+        "OK: {} directories, {} files, {}",
+        dirs - 1,
+        files.count(),
+        human(total)
+    )?;
+    Ok(())
+}
+
+pub fn tree_command() -> Command {
+    Command::new("tree")
+        .about("Print the tree a layout would mount, with sizes")
+        .args(load_args())
+        .arg(
+            Arg::new("urls")
+                .long("urls")
+                .action(ArgAction::SetTrue)
+                .help("Show where each file is fetched from"),
+        )
+        .arg(
+            Arg::new("bytes")
+                .long("bytes")
+                .action(ArgAction::SetTrue)
+                .help("Print exact sizes in bytes"),
+        )
+}
+
+pub fn du_command() -> Command {
+    Command::new("du")
+        .about("Print the total size of each directory a layout would mount")
+        .args(load_args())
+        .arg(
+            Arg::new("all")
+                .long("all")
+                .short('a')
+                .action(ArgAction::SetTrue)
+                .help("List files as well as directories"),
+        )
+        .arg(
+            Arg::new("summarize")
+                .long("summarize")
+                .short('s')
+                .action(ArgAction::SetTrue)
+                .help("Only print the total"),
+        )
+        .arg(
+            Arg::new("human-readable")
+                .long("human-readable")
+                .action(ArgAction::SetTrue)
+                .help("Print sizes in KiB, MiB, ..."),
+        )
+}
+
+pub fn compile_command() -> Command {
+    Command::new("compile")
+        .about("Write a layout in the compact binary format, which loads much faster")
+        .args(load_args())
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .required(true)
+                .help("File to write the compiled layout to"),
+        )
+}
+
+pub fn run_tree(fs: &LazyHTTPFS, matches: &ArgMatches, out: &mut impl Write) -> Result<()> {
+    let (urls, bytes) = (matches.get_flag("urls"), matches.get_flag("bytes"));
+    print_tree(fs, "/", urls, bytes, out)
+}
+
+/// Prints the tree under a line saying `root`, with sources if `urls` and
+/// exact sizes if `bytes`, then how many directories and files there are
+/// and their total size.
+pub fn print_tree(
+    fs: &LazyHTTPFS,
+    root: &str,
+    urls: bool,
+    bytes: bool,
+    out: &mut impl Write,
+) -> Result<()> {
+    let entries = fs.walk();
+    let size = |n: u64| {
+        if bytes {
+            n.to_string()
+        } else {
+            human(n)
+        }
+    };
+    let (mut dirs, mut files, mut total) = (0, 0, 0);
+    // Whether the ancestor at each depth still has siblings coming, which
+    // decides between "│   " and "    " in the indentation.
+    let mut open: Vec<bool> = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.depth == 0 {
+            writeln!(out, "{}", root)?;
+            continue;
+        }
+        let last = !entries[i + 1..]
+            .iter()
+            .take_while(|e| e.depth >= entry.depth)
+            .any(|e| e.depth == entry.depth);
+        open.truncate(entry.depth - 1);
+        for &more in &open {
+            write!(out, "{}", if more { "│   " } else { "    " })?;
+        }
+        write!(out, "{}", if last { "└── " } else { "├── " })?;
+        open.push(!last);
+        let name = entry.name.to_string_lossy();
+        if entry.attr.kind == FileType::Directory {
+            dirs += 1;
+            writeln!(out, "{}/", name)?;
+            continue;
+        }
+        if entry.attr.kind == FileType::Symlink {
+            writeln!(out, "{} -> {}", name, entry.source.as_deref().unwrap_or(""))?;
+            continue;
+        }
+        files += 1;
+        total += entry.attr.size;
+        write!(out, "{} ({})", name, size(entry.attr.size))?;
+        match &entry.source {
+            Some(source) if urls => writeln!(out, " <- {}", source)?,
+            _ => writeln!(out)?,
+        }
+    }
+    writeln!(
+        out,
+        "\n{} directories, {} files, {}",
+        dirs,
+        files,
+        size(total)
+    )?;
+    Ok(())
+}
+
+pub fn run_du(fs: &LazyHTTPFS, matches: &ArgMatches, out: &mut impl Write) -> Result<()> {
+    let all = matches.get_flag("all");
+    let summarize = matches.get_flag("summarize");
+    let size = |bytes: u64| {
+        if matches.get_flag("human-readable") {
+            human(bytes)
+        } else {
+            bytes.to_string()
+        }
+    };
+    // Directories whose contents are still being listed, as (path, total).
+    let mut stack: Vec<(String, u64)> = Vec::new();
+    let finish = |stack: &mut Vec<(String, u64)>, out: &mut dyn Write| -> Result<()> {
+        let (path, total) = stack.pop().unwrap();
+        if let Some(parent) = stack.last_mut() {
+            parent.1 += total;
+        }
+        if !summarize || stack.is_empty() {
+            writeln!(out, "{}\t{}", size(total), path)?;
+        }
+        Ok(())
+    };
+    for entry in fs.walk() {
+        while stack.len() > entry.depth {
+            finish(&mut stack, out)?;
+        }
+        let path = match stack.last() {
+            None => ".".to_owned(),
+            Some((parent, _)) => format!("{}/{}", parent, entry.name.to_string_lossy()),
+        };
+        if entry.attr.kind == FileType::Directory {
+            stack.push((path, 0));
+            continue;
+        }
+        if let Some(parent) = stack.last_mut() {
+            parent.1 += entry.attr.size;
+        }
+        if all && !summarize {
+            writeln!(out, "{}\t{}", size(entry.attr.size), path)?;
+        }
+    }
+    while !stack.is_empty() {
+        finish(&mut stack, out)?;
+    }
+    Ok(())
+}
+
+/// Formats a size with binary units, e.g. `1.5 MiB`.
+pub fn human(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fs::LazyHTTPFS, layout};
+
+    use super::{du_command, human, run_du, run_tree, run_validate, tree_command};
+
+    const LAYOUT: &str = r#"[
+        {"name": "b.bin", "size": 2048, "url": "https://example.com/b"},
+        {"name": "data", "contents": [
+            {"name": "x", "size": 10, "url": "https://example.com/x"},
+            {"name": "sub", "contents": [
+                {"name": "y", "size": 5, "url": "https://example.com/y"}
+            ]}
+        ]},
+        {"name": "a.txt", "content": "hi"}
+    ]"#;
+
+    fn fs() -> LazyHTTPFS {
+        LazyHTTPFS::new(layout::parse(LAYOUT.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn tree() {
+        let matches = tree_command().get_matches_from(["tree", "layout.json", "--urls"]);
+        let mut out = Vec::new();
+        run_tree(&fs(), &matches, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "/
+├── a.txt (2 B) <- <inline, 2 bytes>
+├── b.bin (2.0 KiB) <- https://example.com/b
+└── data/
+    ├── sub/
+    │   └── y (5 B) <- https://example.com/y
+    └── x (10 B) <- https://example.com/x
+
+2 directories, 4 files, 2.0 KiB
+"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let mut out = Vec::new();
+        run_validate(&fs(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "OK: 2 directories, 4 files, 2.0 KiB\n"
+        );
+    }
+
+    #[test]
+    fn du() {
+        let matches = du_command().get_matches_from(["du", "layout.json", "-a"]);
+        let mut out = Vec::new();
+        run_du(&fs(), &matches, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2\t./a.txt\n2048\t./b.bin\n5\t./data/sub/y\n5\t./data/sub\n10\t./data/x\n15\t./data\n2065\t.\n"
+        );
+        assert_eq!(human(1536 * 1024), "1.5 MiB");
+    }
+}
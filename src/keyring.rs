@@ -0,0 +1,282 @@
+//! `keyring:` references: credentials of a layout's `auth` written as
+//! `keyring:<service>/<account>` and looked up in the system's secret
+//! store when first fetched with, so the secret itself is never in the
+//! layout, the environment or the shell's history. On Linux that is the
+//! Secret Service, through libsecret's `secret-tool`, under the `service`
+//! and `username` attributes `secret-tool store` or Python's `keyring`
+//! give it; on macOS, the login keychain.
+//!
+//! Secrets are kept in memory once looked up, and never written to the
+//! cache or a compiled layout, which hold the reference instead.
+//!
+//! Layouts can come from anywhere, an origin they are refreshed from or
+//! `.lhttpfs/add`, so a reference is only looked up for the hosts it was
+//! granted for with `--keyring <service>/<account>=<hosts>`, and fails the
+//! fetch of any other.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use crate::{fetch::HostPolicy, layout::Auth};
+
+pub const PREFIX: &str = "keyring:";
+
+/// Secrets looked up so far, by reference.
+static SECRETS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// A `keyring:` reference that couldn't be resolved.
+#[derive(Debug)]
+pub struct KeyringError {
+    pub reference: String,
+    reason: String,
+}
+
+impl Display for KeyringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.reference, self.reason)
+    }
+}
+
+impl Error for KeyringError {}
+
+/// The references that may be looked up, each with the hosts it may be
+/// sent to.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    granted: HashMap<String, HostPolicy>,
+}
+
+impl Keyring {
+    /// Lets `reference` be looked up for the hosts `hosts` allows.
+    pub fn grant(&mut self, reference: &str, hosts: HostPolicy) {
+        let reference = match reference.starts_with(PREFIX) {
+            true => reference.to_string(),
+            false => format!("{}{}", PREFIX, reference),
+        };
+        self.granted.insert(reference, hosts);
+    }
+
+    /// `auth`, to be sent to `url`, with any `keyring:` references in it
+    /// replaced by their secrets, or as it is without any.
+    pub fn resolved<'a>(&self, auth: &'a Auth, url: &str) -> Result<Cow<'a, Auth>, KeyringError> {
+        let secret = |value| self.secret(value, url);
+        let references = match auth {
+            Auth::Bearer(token) => is_reference(token),
+            Auth::Basic { username, password } => is_reference(username) || is_reference(password),
+            Auth::Ssh { passphrase, .. } => passphrase.as_deref().is_some_and(is_reference),
+        };
+        if !references {
+            return Ok(Cow::Borrowed(auth));
+        }
+        Ok(Cow::Owned(match auth {
+            Auth::Bearer(token) => Auth::Bearer(secret(token)?.into_owned()),
+            Auth::Basic { username, password } => Auth::Basic {
+                username: secret(username)?.into_owned(),
+                password: secret(password)?.into_owned(),
+            },
+            Auth::Ssh {
+                key,
+                passphrase,
+                known_hosts,
+            } => Auth::Ssh {
+                key: key.clone(),
+                passphrase: passphrase
+                    .as_deref()
+                    .map(|passphrase| secret(passphrase).map(Cow::into_owned))
+                    .transpose()?,
+                known_hosts: known_hosts.clone(),
+            },
+        }))
+    }
+
+    /// What `value` stands for, to be sent to `url`: the secret it refers
+    /// to if it is a `keyring:` reference granted for `url`'s host, itself
+    /// otherwise.
+    fn secret<'a>(&self, value: &'a str, url: &str) -> Result<Cow<'a, str>, KeyringError> {
+        if !is_reference(value) {
+            return Ok(Cow::Borrowed(value));
+        }
+        match self.granted.get(value) {
+            Some(hosts) if hosts.check(url).is_ok() => secret(value),
+            _ => Err(KeyringError {
+                reference: value.to_string(),
+                reason: format!(
+                    "not granted for {}; grant it with --keyring {}=<host>",
+                    url,
+                    &value[PREFIX.len()..]
+                ),
+            }),
+        }
+    }
+}
+
+/// Parses a `--keyring` grant, `<service>/<account>=<host>[,<host>...]`.
+pub fn parse_grant(grant: &str) -> Result<(String, HostPolicy), String> {
+    let Some((reference, hosts)) = grant.rsplit_once('=') else {
+        return Err(format!(
+            "Expected <service>/<account>=<hosts>, got {:?}",
+            grant
+        ));
+    };
+    let hosts = hosts
+        .split(',')
+        .filter(|host| !host.is_empty())
+        .collect::<Vec<_>>();
+    if !reference.contains('/') || hosts.is_empty() {
+        return Err(format!(
+            "Expected <service>/<account>=<hosts>, got {:?}",
+            grant
+        ));
+    }
+    let hosts = HostPolicy::new(&hosts, &[]).map_err(|e| e.to_string())?;
+    Ok((reference.to_string(), hosts))
+}
+
+/// Whether the secrets of `auth` are all `keyring:` references, so that
+/// it can be written down without them.
+pub fn referenced(auth: &Auth) -> bool {
+    match auth {
+        Auth::Bearer(token) => is_reference(token),
+        Auth::Basic { password, .. } => is_reference(password),
+        Auth::Ssh { passphrase, .. } => passphrase.as_deref().is_none_or(is_reference),
+    }
+}
+
+fn is_reference(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// What `value` stands for: the secret it refers to if it is a `keyring:`
+/// reference, itself otherwise.
+fn secret(value: &str) -> Result<Cow<'_, str>, KeyringError> {
+    let Some(name) = value.strip_prefix(PREFIX) else {
+        return Ok(Cow::Borrowed(value));
+    };
+    let secrets = SECRETS.get_or_init(Mutex::default);
+    if let Some(secret) = secrets
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(value)
+    {
+        return Ok(Cow::Owned(secret.clone()));
+    }
+    let failed = |reason: String| KeyringError {
+        reference: value.to_string(),
+        reason,
+    };
+    let Some((service, account)) = name.rsplit_once('/') else {
+        return Err(failed("references are keyring:<service>/<account>".into()));
+    };
+    let secret = lookup(command(service, account)).map_err(failed)?;
+    let mut secrets = secrets.lock().unwrap_or_else(PoisonError::into_inner);
+    secrets.insert(value.to_string(), secret.clone());
+    Ok(Cow::Owned(secret))
+}
+
+/// The command printing the secret of `account` at `service`.
+#[cfg(target_os = "macos")]
+fn command(service: &str, account: &str) -> Command {
+    let mut command = Command::new("security");
+    command.args(["find-generic-password", "-w", "-s", service, "-a", account]);
+    command
+}
+
+#[cfg(not(target_os = "macos"))]
+fn command(service: &str, account: &str) -> Command {
+    let mut command = Command::new("secret-tool");
+    command.args(["lookup", "service", service, "username", account]);
+    command
+}
+
+/// The secret `command` prints, without the newline it ends with.
+fn lookup(mut command: Command) -> Result<String, String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("couldn't run {}: {}", program, e))?;
+    let mut secret = String::from_utf8(output.stdout)
+        .map_err(|_| format!("{} printed a secret that isn't UTF-8", program))?;
+    if !output.status.success() || secret.is_empty() {
+        return Err(format!("{} found no such secret", program));
+    }
+    if secret.ends_with('\n') {
+        secret.pop();
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{borrow::Cow, process::Command};
+
+    use super::{lookup, parse_grant, secret, Keyring, SECRETS};
+    use crate::layout::Auth;
+
+    #[test]
+    fn looked_up() {
+        let mut found = Command::new("printf");
+        found.arg("s3cret\n");
+        assert_eq!(lookup(found).unwrap(), "s3cret");
+        let missing = lookup(Command::new("false")).unwrap_err();
+        assert_eq!(missing, "false found no such secret");
+        assert!(lookup(Command::new("/nonexistent/secret-tool"))
+            .unwrap_err()
+            .starts_with("couldn't run /nonexistent/secret-tool"));
+    }
+
+    #[test]
+    fn references() {
+        let secrets = SECRETS.get_or_init(Default::default);
+        let reference = "keyring:example.com/ci";
+        secrets
+            .lock()
+            .unwrap()
+            .insert(reference.into(), "hunter2".into());
+        let auth = Auth::Basic {
+            username: "ci".into(),
+            password: reference.into(),
+        };
+        let mut keyring = Keyring::default();
+        let (granted, hosts) = parse_grant("example.com/ci=example.com,*.example.com").unwrap();
+        keyring.grant(&granted, hosts);
+        assert_eq!(
+            keyring
+                .resolved(&auth, "https://example.com/a")
+                .unwrap()
+                .into_owned(),
+            Auth::Basic {
+                username: "ci".into(),
+                password: "hunter2".into(),
+            }
+        );
+        // Only sent to the hosts it was granted for.
+        let error = keyring.resolved(&auth, "https://evil.test/a").unwrap_err();
+        assert_eq!(error.reference, reference);
+        assert!(Keyring::default()
+            .resolved(&auth, "https://example.com/a")
+            .is_err());
+        let plain = Auth::Bearer("token".into());
+        assert!(matches!(
+            Keyring::default()
+                .resolved(&plain, "https://evil.test/a")
+                .unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert!(parse_grant("example.com/ci").is_err());
+        assert!(parse_grant("ci=example.com").is_err());
+        assert!(parse_grant("example.com/ci=").is_err());
+        let error = secret("keyring:no-account").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "keyring:no-account: references are keyring:<service>/<account>"
+        );
+    }
+}
@@ -0,0 +1,2044 @@
+//! Layouts, the JSON describing what is mounted: each entry's name, size
+//! and where its bytes come from.
+//!
+//! A tree generated in code doesn't need to go through JSON:
+//!
+//! ```
+//! use lhttpfs::layout::{Directory, InputFile, URLFile};
+//!
+//! let models = Directory::new("models", Vec::new())
+//!     .base_url("https://example.com/models/")
+//!     .add_file(URLFile::new("a.bin", "a.bin", 1024).sha256("ab12"));
+//! let files: Vec<InputFile> = vec![models.into()];
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+use serde::{
+    de::{self, IgnoredAny},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::Value;
+
+use crate::{
+    archive::Archive,
+    transform::{Compression, Encryption},
+    LhttpfsError,
+};
+
+/// The newest layout format this build understands. Bump it whenever a layout
+/// using a new entry type or field would be misread by an older release.
+pub const LAYOUT_VERSION: u64 = 14;
+
+#[derive(Debug)]
+pub struct UnsupportedVersion(u64);
+
+impl Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "This layout needs a newer lhttpfs (layout version {}, this build supports up to {})",
+            self.0, LAYOUT_VERSION
+        )
+    }
+}
+
+impl Error for UnsupportedVersion {}
+
+#[derive(Debug)]
+pub struct NotALayout();
+
+impl Display for NotALayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "A layout must be an array of entries or an object with a version"
+        )
+    }
+}
+
+impl Error for NotALayout {}
+
+/// `bytes` in lowercase hex, as layouts give digests.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads a layout document. The version is checked before the entries are
+/// looked at, so a layout from a newer release is reported as such instead of
+/// failing on whatever new entry type it happens to use.
+pub fn parse(reader: impl Read) -> Result<Vec<InputFile>, LhttpfsError> {
+    entries(reader).collect()
+}
+
+/// The top-level entries of a layout document, read from `reader` one at a
+/// time as the iterator is advanced, so a catalog with millions of them
+/// never has to be held whole. Each is checked as [`parse`] would, and the
+/// first error ends the iteration.
+pub fn entries<R: Read>(reader: R) -> Entries<R> {
+    Entries {
+        reader: BufReader::new(reader),
+        state: State::Start,
+        read: 0,
+    }
+}
+
+/// See [`entries`].
+pub struct Entries<R> {
+    reader: BufReader<R>,
+    state: State,
+    /// How many entries were read, to say which one is malformed.
+    read: u64,
+}
+
+enum State {
+    Start,
+    /// In the array of entries, of the object form if `nested`.
+    Array {
+        first: bool,
+        nested: bool,
+    },
+    /// `contents` that came before `version`, held until the version said
+    /// they can be read.
+    Early(std::vec::IntoIter<Value>),
+    Done,
+}
+
+#[derive(Debug)]
+pub struct MalformedLayout(String);
+
+impl Display for MalformedLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for MalformedLayout {}
+
+fn malformed(message: impl Into<String>) -> Box<dyn Error> {
+    Box::new(MalformedLayout(message.into()))
+}
+
+/// The next byte that isn't whitespace, left unread.
+fn peek(reader: &mut impl BufRead) -> io::Result<Option<u8>> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let skip = buf.iter().position(|b| !b.is_ascii_whitespace());
+        if let Some(skip) = skip {
+            let next = buf[skip];
+            reader.consume(skip);
+            return Ok(Some(next));
+        }
+        let len = buf.len();
+        reader.consume(len);
+    }
+}
+
+/// Reads `expected`, after any whitespace.
+fn expect(reader: &mut impl BufRead, expected: u8) -> Result<(), Box<dyn Error>> {
+    match peek(reader)? {
+        Some(next) if next == expected => {
+            reader.consume(1);
+            Ok(())
+        }
+        Some(next) => Err(malformed(format!(
+            "Expected `{}`, found `{}`",
+            expected as char, next as char
+        ))),
+        None => Err(malformed(format!(
+            "Expected `{}`, found the end",
+            expected as char
+        ))),
+    }
+}
+
+/// Reads one JSON value of type `T`. serde_json reads exactly up to the
+/// end of a string, object, array or literal, but looks one byte past a
+/// number, so those are read here.
+fn value<T: de::DeserializeOwned>(reader: &mut impl BufRead) -> Result<T, Box<dyn Error>> {
+    if let Some(b'-' | b'0'..=b'9') = peek(reader)? {
+        let mut number = Vec::new();
+        while let Some(&next) = reader.fill_buf()?.first() {
+            if !matches!(next, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+                break;
+            }
+            number.push(next);
+            reader.consume(1);
+        }
+        return Ok(serde_json::from_slice(&number)?);
+    }
+    Ok(T::deserialize(&mut serde_json::Deserializer::from_reader(
+        reader,
+    ))?)
+}
+
+impl<R: Read> Entries<R> {
+    fn advance(&mut self) -> Result<Option<InputFile>, Box<dyn Error>> {
+        loop {
+            match &mut self.state {
+                State::Done => return Ok(None),
+                State::Start => self.start()?,
+                State::Early(values) => match values.next() {
+                    Some(value) => return Ok(Some(InputFile::deserialize(value)?)),
+                    None => self.state = State::Done,
+                },
+                State::Array { first, nested } => {
+                    let nested = *nested;
+                    if peek(&mut self.reader)? == Some(b']') {
+                        self.reader.consume(1);
+                        if nested {
+                            self.rest_of_object()?;
+                        }
+                        self.end()?;
+                        continue;
+                    }
+                    if !std::mem::take(first) {
+                        expect(&mut self.reader, b',')?;
+                    }
+                    return Ok(Some(value(&mut self.reader)?));
+                }
+            }
+        }
+    }
+
+    /// Reads up to the first entry, checking the version on the way.
+    fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.reader.fill_buf()?.starts_with(COMPILED_MAGIC) {
+            return Err(Box::new(CompiledLayout()));
+        }
+        match peek(&mut self.reader)? {
+            Some(b'[') => {
+                self.reader.consume(1);
+                self.state = State::Array {
+                    first: true,
+                    nested: false,
+                };
+                return Ok(());
+            }
+            Some(b'{') => self.reader.consume(1),
+            _ => return Err(Box::new(NotALayout())),
+        }
+        let mut version = None;
+        let mut early = None;
+        let mut first = true;
+        while peek(&mut self.reader)? != Some(b'}') {
+            if !std::mem::take(&mut first) {
+                expect(&mut self.reader, b',')?;
+            }
+            let key: String = value(&mut self.reader)?;
+            expect(&mut self.reader, b':')?;
+            match key.as_str() {
+                "version" => {
+                    let v: u64 = value(&mut self.reader)?;
+                    if v > LAYOUT_VERSION {
+                        self.state = State::Done;
+                        return Err(Box::new(UnsupportedVersion(v)));
+                    }
+                    version = Some(v);
+                }
+                "contents" if version.is_some() => {
+                    expect(&mut self.reader, b'[')?;
+                    self.state = State::Array {
+                        first: true,
+                        nested: true,
+                    };
+                    return Ok(());
+                }
+                "contents" => early = Some(value::<Vec<Value>>(&mut self.reader)?),
+                _ => {
+                    value::<IgnoredAny>(&mut self.reader)?;
+                }
+            }
+        }
+        self.reader.consume(1);
+        self.end()?;
+        match (version, early) {
+            (None, _) => Err(malformed("A layout object needs a `version`")),
+            (Some(_), None) => Err(malformed("A layout object needs `contents`")),
+            (Some(_), Some(early)) => {
+                self.state = State::Early(early.into_iter());
+                Ok(())
+            }
+        }
+    }
+
+    /// Skips the keys of the layout object after its `contents`.
+    fn rest_of_object(&mut self) -> Result<(), Box<dyn Error>> {
+        while peek(&mut self.reader)? != Some(b'}') {
+            expect(&mut self.reader, b',')?;
+            let key: String = value(&mut self.reader)?;
+            expect(&mut self.reader, b':')?;
+            if key == "contents" || key == "version" {
+                return Err(malformed(format!("The layout has a second `{}`", key)));
+            }
+            value::<IgnoredAny>(&mut self.reader)?;
+        }
+        self.reader.consume(1);
+        Ok(())
+    }
+
+    /// Checks nothing but whitespace follows the document.
+    fn end(&mut self) -> Result<(), Box<dyn Error>> {
+        self.state = State::Done;
+        match peek(&mut self.reader)? {
+            None => Ok(()),
+            Some(_) => Err(malformed("Trailing characters after the layout")),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Entries<R> {
+    type Item = Result<InputFile, LhttpfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.advance();
+        if result.is_err() {
+            self.state = State::Done;
+        }
+        match result {
+            Ok(Some(file)) => {
+                self.read += 1;
+                Some(Ok(file))
+            }
+            Ok(None) => None,
+            Err(e) if e.is::<serde_json::Error>() => {
+                let entry = self.read + 1;
+                let e = malformed(format!("Entry {} of the layout: {}", entry, e));
+                Some(Err(LhttpfsError::layout(e)))
+            }
+            Err(e) => Some(Err(LhttpfsError::layout(e))),
+        }
+    }
+}
+
+/// Marks a compiled layout, written by `LazyHTTPFS::compile`. JSON can't
+/// start with a NUL byte, so this never matches a JSON layout.
+pub const COMPILED_MAGIC: &[u8] = b"\0lhttpfs-compiled\n";
+
+#[derive(Debug)]
+pub struct CompiledLayout();
+
+impl Display for CompiledLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Compiled layouts can only be mounted on their own; merge, filter and apply profiles when compiling"
+        )
+    }
+}
+
+impl Error for CompiledLayout {}
+
+/// Writes `files` as a versioned layout document.
+pub fn write(mut writer: impl Write, files: &[InputFile]) -> Result<(), LhttpfsError> {
+    #[derive(Serialize)]
+    struct Layout<'a> {
+        version: u64,
+        contents: &'a [InputFile],
+    }
+    serde_json::to_writer_pretty(
+        &mut writer,
+        &Layout {
+            version: LAYOUT_VERSION,
+            contents: files,
+        },
+    )
+    .map_err(io::Error::from)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct BadRow {
+    line: u64,
+    message: String,
+}
+
+impl Display for BadRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for BadRow {}
+
+/// Reads a layout written in TOML. A TOML document is a table, so it has to
+/// be of the object form, with `version` and `[[contents]]`.
+pub fn parse_toml(text: &str) -> Result<Vec<InputFile>, LhttpfsError> {
+    let value: Value = toml::from_str(text).map_err(|e| LhttpfsError::layout(Box::new(e)))?;
+    parse(&serde_json::to_vec(&value).unwrap()[..])
+}
+
+/// Reads a layout written in YAML, of either form.
+pub fn parse_yaml(reader: impl Read) -> Result<Vec<InputFile>, LhttpfsError> {
+    let value: Value =
+        serde_yaml::from_reader(reader).map_err(|e| LhttpfsError::layout(Box::new(e)))?;
+    parse(&serde_json::to_vec(&value).unwrap()[..])
+}
+
+/// Reads a table of `path,url,size[,sha256]` rows separated by `delimiter`,
+/// the shape many dataset indexes are published in. A first row naming its
+/// columns is optional; with one, the columns may come in any order.
+pub fn parse_table(reader: impl Read, delimiter: u8) -> Result<Vec<InputFile>, LhttpfsError> {
+    parse_rows(reader, delimiter).map_err(LhttpfsError::layout)
+}
+
+fn parse_rows(reader: impl Read, delimiter: u8) -> Result<Vec<InputFile>, Box<dyn Error>> {
+    const COLUMNS: [&str; 4] = ["path", "url", "size", "sha256"];
+    let mut rows = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .into_records()
+        .peekable();
+    // Which field of a row holds each of `COLUMNS`.
+    let mut fields = [Some(0), Some(1), Some(2), Some(3)];
+    if let Some(Ok(first)) = rows.peek() {
+        if first
+            .get(2)
+            .is_some_and(|size| size.parse::<u64>().is_err())
+        {
+            fields = COLUMNS.map(|column| first.iter().position(|name| name == column));
+            if let Some(missing) = COLUMNS[..3].iter().zip(fields).find(|(_, f)| f.is_none()) {
+                return Err(Box::new(BadRow {
+                    line: 1,
+                    message: format!("Header has no {} column", missing.0),
+                }));
+            }
+            rows.next();
+        }
+    }
+    let mut entries = Vec::new();
+    for row in rows {
+        let row = row?;
+        let line = row.position().map_or(0, |p| p.line());
+        let field = |i: usize| fields[i].and_then(|f| row.get(f)).filter(|v| !v.is_empty());
+        let bad = |message: String| -> Box<dyn Error> { Box::new(BadRow { line, message }) };
+        if row.iter().all(str::is_empty) {
+            continue;
+        }
+        let (Some(path), Some(url), Some(size)) = (field(0), field(1), field(2)) else {
+            return Err(bad("Rows need a path, url and size".into()));
+        };
+        let size = size
+            .parse()
+            .map_err(|_| bad(format!("{} is not a size", size)))?;
+        let mut file = URLFile::new("", url, size);
+        file.sha256 = field(3).map(str::to_lowercase);
+        entries.push((path.to_string(), InputFile::URLFile(file)));
+    }
+    Ok(tree_from_paths(entries))
+}
+
+/// An entry of a layout, as parsed: a file of one kind or another, or a
+/// directory of more.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged, try_from = "Entry")]
+pub enum InputFile {
+    ChunkedFile(ChunkedFile),
+    URLFile(URLFile),
+    Directory(Directory),
+    InlineFile(InlineFile),
+    ConcatFile(ConcatFile),
+    SliceFile(SliceFile),
+    Link(Link),
+}
+
+/// Every field any kind of entry can have. Entries are told apart by which
+/// of them are present, in the order an untagged `InputFile` would try its
+/// variants, but without buffering a whole directory to try each variant
+/// on it in turn.
+#[derive(Deserialize)]
+struct Entry {
+    name: String,
+    url: Option<String>,
+    size: Option<u64>,
+    chunk_size: Option<u64>,
+    sha256: Option<String>,
+    md5: Option<String>,
+    #[serde(default)]
+    mirrors: Vec<String>,
+    pieces: Option<Pieces>,
+    decompress: Option<Compression>,
+    compressed_size: Option<u64>,
+    archive: Option<Archive>,
+    decrypt: Option<Encryption>,
+    filter: Option<String>,
+    contents: Option<Vec<InputFile>>,
+    #[serde(default)]
+    defaults: Defaults,
+    base_url: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Overlay>,
+    content: Option<String>,
+    #[serde(default)]
+    encoding: Encoding,
+    segments: Option<Vec<Segment>>,
+    slice_of: Option<String>,
+    offset: Option<u64>,
+    target: Option<String>,
+    #[serde(flatten)]
+    options: Defaults,
+}
+
+impl TryFrom<Entry> for InputFile {
+    type Error = String;
+
+    fn try_from(entry: Entry) -> Result<InputFile, String> {
+        let Entry { name, options, .. } = entry;
+        // A file without a size is given 0, for the mount to ask for it.
+        let file = entry.size.is_some()
+            || entry.contents.is_none() && entry.content.is_none() && entry.segments.is_none();
+        Ok(match (entry.url, entry.size) {
+            (Some(url), size) if file => match entry.chunk_size {
+                Some(chunk_size) => InputFile::ChunkedFile(ChunkedFile {
+                    size: size.ok_or_else(|| format!("Chunked file {} needs a size", name))?,
+                    name,
+                    url,
+                    chunk_size,
+                    options,
+                }),
+                None => InputFile::URLFile(URLFile {
+                    name,
+                    url,
+                    size: size.unwrap_or(0),
+                    sha256: entry.sha256,
+                    md5: entry.md5,
+                    mirrors: entry.mirrors,
+                    pieces: entry.pieces,
+                    decompress: entry.decompress,
+                    compressed_size: entry.compressed_size,
+                    archive: entry.archive,
+                    decrypt: entry.decrypt,
+                    filter: entry.filter,
+                    options,
+                }),
+            },
+            (None, _) if entry.target.is_some() => InputFile::Link(Link {
+                name,
+                target: entry.target.unwrap(),
+                options,
+            }),
+            (None, size) if entry.slice_of.is_some() => {
+                let Some(size) = size else {
+                    return Err(format!("Slice {} needs a size", name));
+                };
+                InputFile::SliceFile(SliceFile {
+                    name,
+                    slice_of: entry.slice_of.unwrap(),
+                    offset: entry.offset.unwrap_or(0),
+                    size,
+                    options,
+                })
+            }
+            _ => match (entry.contents, entry.content, entry.segments) {
+                (Some(contents), _, _) => InputFile::Directory(Directory {
+                    name,
+                    contents,
+                    defaults: entry.defaults,
+                    base_url: entry.base_url,
+                    profiles: entry.profiles,
+                }),
+                (None, Some(content), _) => InputFile::InlineFile(InlineFile {
+                    name,
+                    content,
+                    encoding: entry.encoding,
+                    options,
+                }),
+                (None, None, Some(segments)) => InputFile::ConcatFile(ConcatFile {
+                    name,
+                    segments,
+                    archive: entry.archive,
+                    options,
+                }),
+                (None, None, None) => {
+                    return Err(format!(
+                        "Entry {} needs a url, content, segments, slice_of, target or contents",
+                        name
+                    ))
+                }
+            },
+        })
+    }
+}
+
+macro_rules! input_file_from {
+    ($($kind:ident),*) => {$(
+        impl From<$kind> for InputFile {
+            fn from(file: $kind) -> InputFile {
+                InputFile::$kind(file)
+            }
+        }
+    )*};
+}
+
+input_file_from!(
+    ChunkedFile,
+    URLFile,
+    Directory,
+    InlineFile,
+    ConcatFile,
+    SliceFile,
+    Link
+);
+
+impl InputFile {
+    pub fn name(&self) -> &str {
+        match self {
+            InputFile::ChunkedFile(chunked) => &chunked.name,
+            InputFile::URLFile(urlfile) => &urlfile.name,
+            InputFile::Directory(directory) => &directory.name,
+            InputFile::InlineFile(inline) => &inline.name,
+            InputFile::ConcatFile(concat) => &concat.name,
+            InputFile::SliceFile(slice) => &slice.name,
+            InputFile::Link(link) => &link.name,
+        }
+    }
+}
+
+impl InputFile {
+    pub fn set_name(&mut self, name: String) {
+        match self {
+            InputFile::ChunkedFile(chunked) => chunked.name = name,
+            InputFile::URLFile(urlfile) => urlfile.name = name,
+            InputFile::Directory(directory) => directory.name = name,
+            InputFile::InlineFile(inline) => inline.name = name,
+            InputFile::ConcatFile(concat) => concat.name = name,
+            InputFile::SliceFile(slice) => slice.name = name,
+            InputFile::Link(link) => link.name = name,
+        }
+    }
+}
+
+/// Bounds on the shape of a layout, checked before mounting so a runaway
+/// generator or a hostile catalog gets a clear error instead of an
+/// exhausted stack or an unusable mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How many directories deep entries may be nested.
+    pub max_depth: usize,
+    /// Total number of entries, counting every chunk of a chunked file.
+    pub max_entries: u64,
+    /// Longest entry name in bytes, `NAME_MAX` on Linux.
+    pub max_name_length: usize,
+    /// Whether entries may have a `filter`, a shell command their bytes are
+    /// piped through. Only for layouts whose authors may run anything.
+    pub filters: bool,
+    /// Whether files must be fetched over HTTPS, refusing plain `http://`
+    /// URLs unless the entry sets `allow_http`. Checked once the layout is
+    /// resolved, when relative URLs are known.
+    pub require_https: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_depth: 64,
+            max_entries: 10_000_000,
+            max_name_length: 255,
+            filters: false,
+            require_https: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded(pub(crate) String);
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// What [`validate`] found wrong with a layout, each problem with where it
+/// is.
+#[derive(Debug)]
+pub struct InvalidLayout(Vec<String>);
+
+impl Display for InvalidLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let [problem] = &self.0[..] {
+            return write!(f, "{}", problem);
+        }
+        write!(f, "The layout has {} problems:", self.0.len())?;
+        for problem in &self.0 {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for InvalidLayout {}
+
+/// Checks what reading a layout doesn't: that names are allowed, as not
+/// empty, `.`, `..` or with a `/`, and used once in their directory, and that URLs parse,
+/// against the `base_url` of the directories they are in. All of the
+/// problems are reported, each at its place in the document, such as
+/// `[2].contents[0]`, and its path in the tree.
+pub fn validate(files: &[InputFile]) -> Result<(), LhttpfsError> {
+    let mut problems = Vec::new();
+    let mut stack: Vec<(&[InputFile], String, String, Option<url::Url>)> =
+        vec![(files, String::new(), String::new(), None)];
+    while let Some((files, at, parent, base)) = stack.pop() {
+        let mut seen: HashMap<&str, String> = HashMap::new();
+        for (i, file) in files.iter().enumerate() {
+            let at = match at.as_str() {
+                "" => format!("[{}]", i),
+                at => format!("{}.contents[{}]", at, i),
+            };
+            let name = file.name();
+            let path = format!("{}/{}", parent, name);
+            let mut problem =
+                |message: String| problems.push(format!("{} ({}): {}", at, path, message));
+            if let Err(e) = crate::fs::check_name(name) {
+                problem(e.to_string());
+            }
+            match seen.get(name) {
+                Some(first) => problem(format!("{:?} is also the name of {}", name, first)),
+                None => {
+                    seen.insert(name, at.clone());
+                }
+            }
+            let resolve = |url: &str| match &base {
+                Some(base) => base.join(url),
+                None => url::Url::parse(url),
+            };
+            let urls: Vec<&String> = match file {
+                InputFile::URLFile(file) => {
+                    std::iter::once(&file.url).chain(&file.mirrors).collect()
+                }
+                InputFile::ChunkedFile(file) => vec![&file.url],
+                InputFile::ConcatFile(file) => file.segments.iter().map(|s| &s.url).collect(),
+                InputFile::Directory(dir) => dir.base_url.iter().collect(),
+                _ => Vec::new(),
+            };
+            for url in urls {
+                if let Err(e) = resolve(url) {
+                    problem(format!("{:?} isn't a valid URL: {}", url, e));
+                }
+            }
+            if let InputFile::Directory(dir) = file {
+                let base = match &dir.base_url {
+                    Some(url) => resolve(url).ok().or(base.clone()),
+                    None => base.clone(),
+                };
+                stack.push((&dir.contents, at, path, base));
+            }
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(LhttpfsError::Layout(Box::new(InvalidLayout(problems)))),
+    }
+}
+
+impl Limits {
+    /// Walks `files` without recursion, failing on the first limit exceeded.
+    pub fn check(&self, files: &[InputFile]) -> Result<(), LhttpfsError> {
+        let exceeded = |message: String| -> Result<(), LhttpfsError> {
+            Err(LhttpfsError::Layout(Box::new(LimitExceeded(message))))
+        };
+        let mut entries: u64 = 0;
+        let mut stack: Vec<(&[InputFile], String, usize)> = vec![(files, String::new(), 1)];
+        while let Some((files, parent, depth)) = stack.pop() {
+            for file in files {
+                let path = format!("{}/{}", parent, file.name());
+                if file.name().len() > self.max_name_length {
+                    return exceeded(format!(
+                        "The name of {} is {} bytes long, more than the limit of {}",
+                        path,
+                        file.name().len(),
+                        self.max_name_length
+                    ));
+                }
+                entries += match file {
+                    InputFile::ChunkedFile(chunked) if chunked.chunk_size > 0 => {
+                        1 + chunked.size.div_ceil(chunked.chunk_size)
+                    }
+                    _ => 1,
+                };
+                if entries > self.max_entries {
+                    return exceeded(format!(
+                        "The layout has more than {} entries (reached at {})",
+                        self.max_entries, path
+                    ));
+                }
+                if let InputFile::URLFile(URLFile {
+                    filter: Some(_), ..
+                }) = file
+                {
+                    if !self.filters {
+                        return exceeded(format!(
+                            "{} has a filter command, and filters aren't allowed",
+                            path
+                        ));
+                    }
+                }
+                if let InputFile::Directory(dir) = file {
+                    if depth > self.max_depth {
+                        return exceeded(format!(
+                            "{} is nested more than {} directories deep",
+                            path, self.max_depth
+                        ));
+                    }
+                    stack.push((&dir.contents, path, depth + 1));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What [`merge`] does when two layouts have an entry at the same path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    Error,
+    /// Keep the entry of the layout given first.
+    First,
+    /// Keep the entry of the layout given last.
+    Last,
+}
+
+#[derive(Debug)]
+pub struct MergeConflict(String);
+
+impl Display for MergeConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "More than one layout has an entry at {}", self.0)
+    }
+}
+
+impl Error for MergeConflict {}
+
+/// Merges layouts into a single tree, in order. Directories at the same path
+/// are merged when they have the same `defaults`, `base_url` and `profiles`,
+/// since those apply to everything inside; any other pair of entries with
+/// the same path is a conflict.
+pub fn merge(
+    layouts: impl IntoIterator<Item = Vec<InputFile>>,
+    on_conflict: OnConflict,
+) -> Result<Vec<InputFile>, Box<dyn Error>> {
+    fn merge_into(
+        into: &mut Vec<InputFile>,
+        files: Vec<InputFile>,
+        parent: &str,
+        on_conflict: OnConflict,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut index: HashMap<String, usize> = into
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.name().to_owned(), i))
+            .collect();
+        for file in files {
+            let path = format!("{}/{}", parent, file.name());
+            let Some(&i) = index.get(file.name()) else {
+                index.insert(file.name().to_owned(), into.len());
+                into.push(file);
+                continue;
+            };
+            match (&mut into[i], file) {
+                (InputFile::Directory(a), InputFile::Directory(b))
+                    if a.defaults == b.defaults
+                        && a.base_url == b.base_url
+                        && a.profiles == b.profiles =>
+                {
+                    merge_into(&mut a.contents, b.contents, &path, on_conflict)?
+                }
+                (_, file) => match on_conflict {
+                    OnConflict::Error => return Err(Box::new(MergeConflict(path))),
+                    OnConflict::First => {}
+                    OnConflict::Last => into[i] = file,
+                },
+            }
+        }
+        Ok(())
+    }
+    let mut merged = Vec::new();
+    for files in layouts {
+        merge_into(&mut merged, files, "", on_conflict)?;
+    }
+    Ok(merged)
+}
+
+/// Builds a tree out of entries keyed by `/` separated paths, creating
+/// directories as needed. Entries keep the order they were given in, and each
+/// is renamed to the last component of its path.
+pub fn tree_from_paths(entries: impl IntoIterator<Item = (String, InputFile)>) -> Vec<InputFile> {
+    #[derive(Default)]
+    struct Tree {
+        entries: Vec<Entry>,
+        dirs: HashMap<String, usize>,
+    }
+    enum Entry {
+        Dir(String, Tree),
+        File(Box<InputFile>),
+    }
+    impl Tree {
+        fn insert(&mut self, path: &[&str], mut file: InputFile) {
+            match path {
+                [] => {}
+                [name] => {
+                    file.set_name(name.to_string());
+                    self.entries.push(Entry::File(Box::new(file)));
+                }
+                [dir, rest @ ..] => {
+                    let index = *self.dirs.entry(dir.to_string()).or_insert_with(|| {
+                        self.entries
+                            .push(Entry::Dir(dir.to_string(), Tree::default()));
+                        self.entries.len() - 1
+                    });
+                    if let Entry::Dir(_, tree) = &mut self.entries[index] {
+                        tree.insert(rest, file);
+                    }
+                }
+            }
+        }
+        fn into_files(self) -> Vec<InputFile> {
+            self.entries
+                .into_iter()
+                .map(|entry| match entry {
+                    Entry::Dir(name, tree) => {
+                        InputFile::Directory(Directory::new(name, tree.into_files()))
+                    }
+                    Entry::File(file) => *file,
+                })
+                .collect()
+        }
+    }
+    let mut tree = Tree::default();
+    for (path, file) in entries {
+        let components: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        tree.insert(&components, file);
+    }
+    tree.into_files()
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct URLFile {
+    pub name: String,
+    pub url: String,
+    /// 0 if the layout gives none, for the mount to ask the server.
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file's contents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Hex encoded MD5, for catalogs that only publish that.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    /// Other URLs serving the same bytes, tried in order if `url` fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    /// BitTorrent piece hashes covering this file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<Pieces>,
+    /// Serve the file decompressed. `size` is then the decompressed size,
+    /// or 0 to find it out on the first read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decompress: Option<Compression>,
+    /// The size of the compressed file on the server. With it, seekable
+    /// zstd files are read a frame at a time instead of as a whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compressed_size: Option<u64>,
+    /// Mount the archive's members as a directory named `name`, rather
+    /// than the archive itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<Archive>,
+    /// Serve the file decrypted; `size` is the decrypted size. Decryption
+    /// comes before `decompress`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decrypt: Option<Encryption>,
+    /// A shell command the file is piped through after decryption and
+    /// decompression, serving its output. `size` is then the output's
+    /// size, or 0 to find it out on the first read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(flatten)]
+    pub options: Defaults,
+}
+
+/// The pieces of a torrent that overlap one of its files. Pieces are cut
+/// from the concatenation of all files in the torrent, so `offset` is where
+/// this file starts in that stream, and the first and last piece may also
+/// cover bytes of neighbouring files.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Pieces {
+    pub length: u64,
+    pub offset: u64,
+    /// Hex encoded SHA-1 of each piece, starting with the one containing
+    /// `offset`.
+    pub sha1: Vec<String>,
+}
+
+impl URLFile {
+    pub fn new(name: impl Into<String>, url: impl Into<String>, size: u64) -> URLFile {
+        URLFile {
+            name: name.into(),
+            url: url.into(),
+            size,
+            sha256: None,
+            md5: None,
+            mirrors: Vec::new(),
+            pieces: None,
+            decompress: None,
+            compressed_size: None,
+            archive: None,
+            decrypt: None,
+            filter: None,
+            options: Defaults::default(),
+        }
+    }
+
+    /// Checks the file's bytes against `sha256`, in hex.
+    pub fn sha256(mut self, sha256: impl Into<String>) -> URLFile {
+        self.sha256 = Some(sha256.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Falls back to `url` if those before it fail.
+    pub fn mirror(mut self, url: impl Into<String>) -> URLFile {
+        self.mirrors.push(url.into());
+        self
+    }
+
+    /// Serves the file decompressed, `size` being the decompressed size.
+    pub fn decompress(mut self, compression: Compression) -> URLFile {
+        self.decompress = Some(compression);
+        self
+    }
+
+    /// Mounts the archive's members instead of the archive.
+    pub fn archive(mut self, archive: Archive) -> URLFile {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Sets the file's own options, over those it inherits.
+    pub fn options(mut self, options: Defaults) -> URLFile {
+        self.options = options;
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Directory {
+    pub name: String,
+    pub contents: Vec<InputFile>,
+    #[serde(default, skip_serializing_if = "Defaults::is_empty")]
+    pub defaults: Defaults,
+    /// Relative `url`s below this directory are resolved against this. It may
+    /// itself be relative to the base URL of an enclosing directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// Overrides for this directory that only apply when mounting with the
+    /// named `--profile`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Overlay>,
+}
+
+impl Directory {
+    pub fn new(name: impl Into<String>, contents: Vec<InputFile>) -> Directory {
+        Directory {
+            name: name.into(),
+            contents,
+            defaults: Defaults::default(),
+            base_url: None,
+            profiles: BTreeMap::new(),
+        }
+    }
+
+    /// A directory named `name` of `entries`, keyed by `/` separated paths
+    /// below it, as [`tree_from_paths`] would build them.
+    pub fn from_paths(
+        name: impl Into<String>,
+        entries: impl IntoIterator<Item = (String, InputFile)>,
+    ) -> Directory {
+        Directory::new(name, tree_from_paths(entries))
+    }
+
+    /// Adds `file` after the entries already in the directory.
+    pub fn add_file(mut self, file: impl Into<InputFile>) -> Directory {
+        self.contents.push(file.into());
+        self
+    }
+
+    /// Adds `dir`, with everything in it, after the entries already in the
+    /// directory.
+    pub fn add_dir(self, dir: Directory) -> Directory {
+        self.add_file(dir)
+    }
+
+    /// What everything in the directory inherits.
+    pub fn defaults(mut self, defaults: Defaults) -> Directory {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Resolves relative `url`s below the directory against `url`.
+    pub fn base_url(mut self, url: impl Into<String>) -> Directory {
+        self.base_url = Some(url.into());
+        self
+    }
+}
+
+impl<F: Into<InputFile>> Extend<F> for Directory {
+    fn extend<I: IntoIterator<Item = F>>(&mut self, files: I) {
+        self.contents.extend(files.into_iter().map(Into::into));
+    }
+}
+
+/// Collects entries into a directory without a name yet, for its `name`
+/// to be set: `Directory { name: "data".into(), ..files.collect() }`.
+impl<F: Into<InputFile>> FromIterator<F> for Directory {
+    fn from_iter<I: IntoIterator<Item = F>>(files: I) -> Directory {
+        Directory::new("", files.into_iter().map(Into::into).collect())
+    }
+}
+
+/// What a profile changes about a directory: its `base_url`, and
+/// `defaults` layered over the directory's own.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Overlay {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Defaults::is_empty")]
+    pub defaults: Defaults,
+}
+
+#[derive(Debug)]
+pub struct UnknownProfile(String);
+
+impl Display for UnknownProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No directory in the layout has a profile named {}",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownProfile {}
+
+/// Applies the overlays of `profile` to every directory that has one. It's an
+/// error if none do, since that is almost certainly a typo.
+pub fn apply_profile(files: &mut [InputFile], profile: &str) -> Result<(), LhttpfsError> {
+    fn apply(files: &mut [InputFile], profile: &str) -> bool {
+        let mut found = false;
+        for file in files {
+            if let InputFile::Directory(dir) = file {
+                if let Some(overlay) = dir.profiles.remove(profile) {
+                    found = true;
+                    dir.base_url = overlay.base_url.or(dir.base_url.take());
+                    dir.defaults = overlay.defaults.inherit(&dir.defaults);
+                }
+                found |= apply(&mut dir.contents, profile);
+            }
+        }
+        found
+    }
+    if apply(files, profile) {
+        Ok(())
+    } else {
+        Err(LhttpfsError::Layout(Box::new(UnknownProfile(
+            profile.to_owned(),
+        ))))
+    }
+}
+
+/// Adds a `<name>.sha256` file next to every file with a `sha256`, in the
+/// format `sha256sum -c` reads. Names that are already taken are left alone.
+pub fn add_checksum_files(files: &mut Vec<InputFile>) {
+    let mut sums = Vec::new();
+    for file in files.iter_mut() {
+        match file {
+            InputFile::Directory(dir) => add_checksum_files(&mut dir.contents),
+            InputFile::URLFile(URLFile {
+                name,
+                sha256: Some(sha256),
+                archive: None,
+                ..
+            }) => sums.push(InlineFile::new(
+                format!("{}.sha256", name),
+                format!("{}  {}\n", sha256, name),
+            )),
+            _ => {}
+        }
+    }
+    for sum in sums {
+        if !files.iter().any(|file| file.name() == sum.name) {
+            files.push(InputFile::InlineFile(sum));
+        }
+    }
+}
+
+/// Serves every file that looks compressed, by its extension or its
+/// `content_type`, decompressed under the name without the extension.
+/// Files that already say how to read them are left alone, and so are the
+/// names of files whose decompressed name is already taken.
+pub fn auto_decompress(files: &mut [InputFile]) {
+    let mut names = files
+        .iter()
+        .map(|file| file.name().to_string())
+        .collect::<HashSet<_>>();
+    for file in files.iter_mut() {
+        let file = match file {
+            InputFile::Directory(dir) => {
+                auto_decompress(&mut dir.contents);
+                continue;
+            }
+            InputFile::URLFile(file)
+                if file.decompress.is_none()
+                    && file.archive.is_none()
+                    && file.decrypt.is_none()
+                    && file.filter.is_none()
+                    && file.pieces.is_none() =>
+            {
+                file
+            }
+            _ => continue,
+        };
+        let by_type =
+            (file.options.content_type.as_deref()).and_then(Compression::from_content_type);
+        let (compression, name) = match (Compression::from_name(&file.name), by_type) {
+            (Some((compression, name)), _) => (compression, Some(name)),
+            (None, Some(compression)) => (compression, None),
+            (None, None) => continue,
+        };
+        file.decompress = Some(compression);
+        file.compressed_size = Some(file.size);
+        // Found out on the first read.
+        file.size = 0;
+        if by_type.is_some() {
+            file.options.content_type = None;
+        }
+        if let Some(name) = name.filter(|name| !names.contains(name)) {
+            names.insert(name.clone());
+            file.name = name;
+        }
+    }
+}
+
+/// A small file whose bytes are embedded in the layout itself.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InlineFile {
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(flatten)]
+    pub options: Defaults,
+}
+
+impl InlineFile {
+    pub fn new(name: impl Into<String>, content: impl Into<String>) -> InlineFile {
+        InlineFile {
+            name: name.into(),
+            content: content.into(),
+            encoding: Encoding::Utf8,
+            options: Defaults::default(),
+        }
+    }
+}
+
+/// A symbolic link to `target`, which like any link's is usually a path
+/// relative to the directory the link is in, such as `v2/model.bin`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Link {
+    pub name: String,
+    pub target: String,
+    #[serde(flatten)]
+    pub options: Defaults,
+}
+
+impl Link {
+    pub fn new(name: impl Into<String>, target: impl Into<String>) -> Link {
+        Link {
+            name: name.into(),
+            target: target.into(),
+            options: Defaults::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+/// One large remote object exposed as a directory of `chunk_size` sized
+/// files named `<name>.000`, `<name>.001`, ..., each read with range requests.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChunkedFile {
+    pub name: String,
+    pub url: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    #[serde(flatten)]
+    pub options: Defaults,
+}
+
+/// A file published as several parts (`file.bin.000`, `file.bin.001`, ...)
+/// that is presented as their concatenation, in order.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConcatFile {
+    pub name: String,
+    pub segments: Vec<Segment>,
+    /// Mount the archive split into `segments` as a directory, rather than
+    /// the concatenation itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<Archive>,
+    #[serde(flatten)]
+    pub options: Defaults,
+}
+
+/// `size` bytes from `offset` of another file in the layout, named by its
+/// path from the root, shown as a file of their own.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SliceFile {
+    pub name: String,
+    pub slice_of: String,
+    #[serde(default)]
+    pub offset: u64,
+    pub size: u64,
+    #[serde(flatten)]
+    pub options: Defaults,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Segment {
+    pub url: String,
+    pub size: u64,
+}
+
+/// Settings that a directory hands down to everything below it. Every field
+/// is optional, so a nested directory or a file only overrides what it sets.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Defaults {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Auth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CachePolicy>,
+    /// Seconds cached bytes stay valid before they are fetched again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// Keep cached bytes for as long as the cache itself lives, ignoring `ttl`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_mode",
+        serialize_with = "serialize_mode",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mode: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// The permissions of directories, where `mode` is those of files.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_mode",
+        serialize_with = "serialize_mode",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dir_mode: Option<u16>,
+    /// When the entry was last modified, in seconds since the epoch.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_mtime",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mtime: Option<u64>,
+    /// MIME type reported through the `user.mime_type` xattr, and by
+    /// [`auto_decompress`] to tell a compressed file from a plain one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Fetch from plain `http://` URLs even under
+    /// [`Limits::require_https`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_http: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+}
+
+impl Defaults {
+    fn is_empty(&self) -> bool {
+        *self == Defaults::default()
+    }
+
+    /// Layers `self` on top of `parent`. Headers are merged key by key.
+    pub fn inherit(&self, parent: &Defaults) -> Defaults {
+        let mut headers = parent.headers.clone();
+        headers.extend(self.headers.clone());
+        Defaults {
+            headers,
+            auth: self.auth.clone().or_else(|| parent.auth.clone()),
+            cache: self.cache.or(parent.cache),
+            ttl: self.ttl.or(parent.ttl),
+            pin: self.pin.or(parent.pin),
+            mode: self.mode.or(parent.mode),
+            uid: self.uid.or(parent.uid),
+            gid: self.gid.or(parent.gid),
+            dir_mode: self.dir_mode.or(parent.dir_mode),
+            mtime: self.mtime.or(parent.mtime),
+            content_type: self
+                .content_type
+                .clone()
+                .or_else(|| parent.content_type.clone()),
+            allow_http: self.allow_http.or(parent.allow_http),
+            priority: self.priority.or(parent.priority),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    Bearer(String),
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// Public key authentication for sftp:// URLs. Without a `key`, the
+    /// SSH agent and the usual `~/.ssh/id_*` keys are tried.
+    Ssh {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        passphrase: Option<String>,
+        /// Defaults to `~/.ssh/known_hosts`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        known_hosts: Option<String>,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    None,
+    #[default]
+    Memory,
+    /// Kept in the cache directory, so it survives remounts.
+    Disk,
+}
+
+/// How eagerly a file is fetched when bandwidth is short.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Fetched only when read, never prefetched, and only once the fetches
+    /// of other files waiting for a thread have one.
+    Low,
+    #[default]
+    Normal,
+    /// Prefetched before anything else, and fetched ahead of other files.
+    High,
+}
+
+/// Modes are usually written in octal, which JSON has no literal for, so
+/// accept either a plain number or a string such as `"0644"`.
+fn deserialize_mode<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u16),
+        Octal(String),
+    }
+    match Option::<Mode>::deserialize(d)? {
+        None => Ok(None),
+        Some(Mode::Number(n)) => Ok(Some(n)),
+        Some(Mode::Octal(s)) => u16::from_str_radix(s.trim_start_matches("0o"), 8)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Timestamps are as likely to come as a date as a number, so accept either
+/// seconds since the epoch or an RFC 3339 timestamp such as
+/// `"2024-05-01T12:00:00Z"`.
+fn deserialize_mtime<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mtime {
+        Seconds(u64),
+        Date(String),
+    }
+    match Option::<Mtime>::deserialize(d)? {
+        None => Ok(None),
+        Some(Mtime::Seconds(secs)) => Ok(Some(secs)),
+        Some(Mtime::Date(date)) => crate::fetch::date::parse_rfc3339(&date)
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since| Some(since.as_secs()))
+            .ok_or_else(|| de::Error::custom(format!("{:?} isn't an RFC 3339 timestamp", date))),
+    }
+}
+
+fn serialize_mode<S: Serializer>(mode: &Option<u16>, s: S) -> Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => s.serialize_str(&format!("{:04o}", mode)),
+        None => s.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{error::Error, io::Read};
+
+    use crate::{transform::Compression, LhttpfsError};
+
+    use super::{
+        add_checksum_files, apply_profile, auto_decompress, entries, merge, parse, parse_table,
+        parse_toml, parse_yaml, tree_from_paths, validate, write, BadRow, Defaults, Directory,
+        InlineFile, InputFile, LimitExceeded, Limits, MalformedLayout, MergeConflict, OnConflict,
+        URLFile, UnknownProfile, UnsupportedVersion, LAYOUT_VERSION,
+    };
+
+    #[test]
+    fn versions() {
+        let bare = parse(r#"[{"name": "a", "content": "a"}]"#.as_bytes()).unwrap();
+        let versioned =
+            parse(r#"{"version": 1, "contents": [{"name": "a", "content": "a"}]}"#.as_bytes());
+        assert_eq!(bare, versioned.unwrap());
+
+        let newer = format!(
+            r#"{{"version": {}, "contents": [{{"name": "a", "kind": "from-the-future"}}]}}"#,
+            LAYOUT_VERSION + 1
+        );
+        let result = parse(newer.as_bytes());
+        assert!(result.is_err_and(|e| e.source().unwrap().is::<UnsupportedVersion>()));
+        assert!(parse("3".as_bytes()).is_err());
+
+        let late =
+            parse(r#"{"contents": [{"name": "a", "content": "a"}], "version": 1}"#.as_bytes());
+        assert_eq!(bare, late.unwrap());
+        let err = parse(r#"[{"name": "d", "contents": [{"name": "x"}]}]"#.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("Entry x needs"), "{}", err);
+    }
+
+    /// A layout of `n` files, made as it is read.
+    struct Synthetic {
+        n: u64,
+        next: u64,
+        pending: Vec<u8>,
+    }
+
+    impl Read for Synthetic {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() && self.next <= self.n {
+                self.pending = match self.next {
+                    0 => br#"{"version": 1, "contents": ["#.to_vec(),
+                    i if i == self.n => b"]}".to_vec(),
+                    i => format!(
+                        r#"{}{{"name": "f{}", "url": "https://example.com/{}", "size": {}}}"#,
+                        if i == 1 { "" } else { ",\n" },
+                        i,
+                        i,
+                        i
+                    )
+                    .into_bytes(),
+                };
+                self.next += 1;
+            }
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn streamed() {
+        let n = 200_000;
+        let mut count = 0;
+        for (i, file) in entries(Synthetic {
+            n,
+            next: 0,
+            pending: Vec::new(),
+        })
+        .enumerate()
+        {
+            let InputFile::URLFile(file) = file.unwrap() else {
+                panic!("Expected a URL file");
+            };
+            assert_eq!(file.size, i as u64 + 1);
+            count += 1;
+        }
+        assert_eq!(count, n - 1);
+
+        // Entries come out as they are read, before anything wrong later on.
+        let json = r#"[{"name": "a", "content": "a"}, {"name": "b", "content": "b"}, oops"#;
+        let mut files = entries(json.as_bytes());
+        assert_eq!(files.next().unwrap().unwrap().name(), "a");
+        assert_eq!(files.next().unwrap().unwrap().name(), "b");
+        let error = files.next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("Entry 3"), "{}", error);
+        assert!(files.next().is_none());
+
+        let trailing = parse(r#"{"version": 1, "contents": [], "x": 2} 3"#.as_bytes());
+        assert!(trailing
+            .unwrap_err()
+            .source()
+            .unwrap()
+            .is::<MalformedLayout>());
+        assert!(parse(r#"{"version": 1}"#.as_bytes()).is_err());
+        assert!(parse(r#"{"version": 1, "contents": [], "version": 2}"#.as_bytes()).is_err());
+        assert_eq!(parse(" [ ] ".as_bytes()).unwrap(), []);
+    }
+
+    #[test]
+    fn sizeless() {
+        let json = r#"[{"name": "a", "url": "https://e.com/a"}, {"name": "b", "url": "https://e.com/b", "chunk_size": 4}]"#;
+        let mut files = entries(json.as_bytes());
+        let Some(Ok(InputFile::URLFile(a))) = files.next() else {
+            panic!("Expected a URL file");
+        };
+        assert_eq!(a.size, 0);
+        let error = files.next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("needs a size"), "{}", error);
+    }
+
+    #[test]
+    fn round_trip() {
+        let json = include_str!("example.json");
+        let files = parse(json.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write(&mut out, &files).unwrap();
+        assert_eq!(parse(out.as_slice()).unwrap(), files);
+
+        let json = r#"[{"name": "d", "defaults": {"mode": "0640", "auth": {"bearer": "t"}},
+            "contents": [{"name": "c", "content": "AA==", "encoding": "base64",
+                          "dir_mode": "0750", "mtime": "2024-05-01T12:00:00Z"}]}]"#;
+        let files = parse(json.as_bytes()).unwrap();
+        let InputFile::Directory(d) = &files[0] else {
+            panic!("Expected a directory, got {:?}", files);
+        };
+        let InputFile::InlineFile(c) = &d.contents[0] else {
+            panic!("Expected an inline file, got {:?}", d.contents);
+        };
+        assert_eq!(
+            (c.options.dir_mode, c.options.mtime),
+            (Some(0o750), Some(1714564800))
+        );
+        let mut out = Vec::new();
+        write(&mut out, &files).unwrap();
+        assert!(String::from_utf8_lossy(&out).contains(r#""mode": "0640""#));
+        assert!(String::from_utf8_lossy(&out).contains(r#""mtime": 1714564800"#));
+        assert_eq!(parse(out.as_slice()).unwrap(), files);
+        let bad = r#"[{"name": "a", "content": "", "mtime": "last week"}]"#;
+        assert!(parse(bad.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn tables() {
+        let csv = "data/a.bin, https://example.com/a, 3\n\nb.bin,https://example.com/b,4,ABCD\n";
+        let files = parse_table(csv.as_bytes(), b',').unwrap();
+        let [InputFile::Directory(data), InputFile::URLFile(b)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!(data.contents[0].name(), "a.bin");
+        assert_eq!((b.size, b.sha256.as_deref()), (4, Some("abcd")));
+
+        let tsv = "size\tsha256\tpath\turl\n4\t\tb.bin\thttps://example.com/b\n";
+        let [InputFile::URLFile(b)] = &parse_table(tsv.as_bytes(), b'\t').unwrap()[..] else {
+            panic!("Expected a single file");
+        };
+        assert_eq!(
+            (b.name.as_str(), b.size, b.sha256.as_ref()),
+            ("b.bin", 4, None)
+        );
+
+        let bad = parse_table(
+            "a,https://example.com/a,3\nb,https://example.com/b,x\n".as_bytes(),
+            b',',
+        );
+        let Err(LhttpfsError::Layout(bad)) = bad else {
+            panic!("Unexpected {:?}", bad);
+        };
+        assert!(bad.is::<BadRow>() && bad.to_string().starts_with("Line 2"));
+        assert!(parse_table("name,url,size\n".as_bytes(), b',').is_err());
+    }
+
+    #[test]
+    fn paths_to_tree() {
+        let file = |path: &str| {
+            let url = format!("https://example.com/{}", path);
+            (
+                path.to_string(),
+                InputFile::URLFile(URLFile::new("", url, 1)),
+            )
+        };
+        let files = tree_from_paths([file("a/b/c.txt"), file("d.txt"), file("a/e.txt")]);
+        let [InputFile::Directory(a), InputFile::URLFile(d)] = &files[..] else {
+            panic!("Unexpected layout {:?}", files);
+        };
+        assert_eq!((a.name.as_str(), d.name.as_str()), ("a", "d.txt"));
+        let [InputFile::Directory(b), InputFile::URLFile(e)] = &a.contents[..] else {
+            panic!("Unexpected layout {:?}", a.contents);
+        };
+        assert_eq!((b.name.as_str(), e.name.as_str()), ("b", "e.txt"));
+        assert_eq!(b.contents[0].name(), "c.txt");
+    }
+
+    #[test]
+    fn profiles() {
+        let json = r#"[{
+            "name": "models",
+            "base_url": "https://us.example.com/",
+            "defaults": {"headers": {"X-Env": "prod", "Accept": "*/*"}},
+            "profiles": {
+                "eu-mirror": {"base_url": "https://eu.example.com/"},
+                "dev": {"defaults": {"headers": {"X-Env": "dev"}, "auth": {"bearer": "t"}}}
+            },
+            "contents": [{"name": "a", "size": 1, "url": "a"}]
+        }]"#;
+        let mut eu = parse(json.as_bytes()).unwrap();
+        apply_profile(&mut eu, "eu-mirror").unwrap();
+        let InputFile::Directory(eu) = &eu[0] else {
+            panic!("Expected a directory");
+        };
+        assert_eq!(eu.base_url.as_deref(), Some("https://eu.example.com/"));
+        assert_eq!(eu.defaults.headers["X-Env"], "prod");
+
+        let mut dev = parse(json.as_bytes()).unwrap();
+        apply_profile(&mut dev, "dev").unwrap();
+        let InputFile::Directory(dev) = &dev[0] else {
+            panic!("Expected a directory");
+        };
+        assert_eq!(dev.base_url.as_deref(), Some("https://us.example.com/"));
+        assert_eq!(dev.defaults.headers["X-Env"], "dev");
+        assert_eq!(dev.defaults.headers["Accept"], "*/*");
+        assert!(dev.defaults.auth.is_some());
+
+        let mut files = parse(json.as_bytes()).unwrap();
+        let err = apply_profile(&mut files, "staging").unwrap_err();
+        assert!(err.source().unwrap().is::<UnknownProfile>());
+    }
+
+    #[test]
+    fn constructed() {
+        let files = [
+            URLFile::new("a.bin", "a.bin", 3).sha256("ABCD"),
+            URLFile::new("b.bin.gz", "b.gz", 0).decompress(Compression::Gzip),
+        ];
+        let data = Directory {
+            name: "data".into(),
+            ..files.into_iter().collect()
+        }
+        .base_url("https://example.com/data/")
+        .add_dir(Directory::new("empty", Vec::new()));
+        let mut root = Directory::from_paths(
+            "/",
+            [("docs/README".to_string(), InlineFile::new("", "hi").into())],
+        );
+        root.extend([data]);
+        let json = r#"[
+            {"name": "docs", "contents": [{"name": "README", "content": "hi"}]},
+            {"name": "data", "base_url": "https://example.com/data/", "contents": [
+                {"name": "a.bin", "url": "a.bin", "size": 3, "sha256": "abcd"},
+                {"name": "b.bin.gz", "url": "b.gz", "size": 0, "decompress": "gzip"},
+                {"name": "empty", "contents": []}
+            ]}
+        ]"#;
+        assert_eq!(root.contents, parse(json.as_bytes()).unwrap());
+
+        let mut out = Vec::new();
+        let file = URLFile::new("c", "https://example.com/c", 1)
+            .mirror("https://mirror.example.com/c")
+            .options(Defaults {
+                uid: Some(7),
+                ..Defaults::default()
+            });
+        write(&mut out, &[file.into()]).unwrap();
+        let [InputFile::URLFile(c)] = &parse(out.as_slice()).unwrap()[..] else {
+            panic!("Expected a single file");
+        };
+        assert_eq!((c.mirrors.len(), c.options.uid), (1, Some(7)));
+    }
+
+    #[test]
+    fn merging() {
+        const MODELS: &str = r#"[{"name": "models", "contents": [{"name": "a", "content": "1"}]},
+                         {"name": "README", "content": "models"}]"#;
+        const DATASETS: &str = r#"[{"name": "models", "contents": [{"name": "b", "content": "2"}]},
+                           {"name": "datasets", "contents": []},
+                           {"name": "README", "content": "datasets"}]"#;
+        let layouts = || [MODELS, DATASETS].map(|l| parse(l.as_bytes()).unwrap());
+
+        let err = merge(layouts(), OnConflict::Error).unwrap_err();
+        assert_eq!(err.downcast_ref::<MergeConflict>().unwrap().0, "/README");
+
+        let last = merge(layouts(), OnConflict::Last).unwrap();
+        let [InputFile::Directory(models), InputFile::InlineFile(readme), InputFile::Directory(_)] =
+            &last[..]
+        else {
+            panic!("Unexpected merge {:?}", last);
+        };
+        let names: Vec<_> = models.contents.iter().map(InputFile::name).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(readme.content, "datasets");
+
+        let first = merge(layouts(), OnConflict::First).unwrap();
+        let InputFile::InlineFile(readme) = &first[1] else {
+            panic!("Unexpected merge {:?}", first);
+        };
+        assert_eq!(readme.content, "models");
+
+        // Different defaults would change what the other layout's files get.
+        let private = r#"[{"name": "models", "defaults": {"uid": 0}, "contents": []}]"#;
+        let layouts = [MODELS, private].map(|l| parse(l.as_bytes()).unwrap());
+        assert!(merge(layouts, OnConflict::Error).is_err());
+    }
+
+    #[test]
+    fn formats() {
+        let json = parse(
+            r#"{"version": 1, "contents": [
+                {"name": "a", "url": "https://e.com/a", "size": 3, "mode": "600"},
+                {"name": "d", "contents": [{"name": "b", "content": "b"}]}
+            ]}"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let toml = r#"
+            version = 1
+
+            [[contents]]
+            name = "a"
+            url = "https://e.com/a"
+            size = 3
+            mode = "600"
+
+            [[contents]]
+            name = "d"
+            contents = [{ name = "b", content = "b" }]
+        "#;
+        assert_eq!(parse_toml(toml).unwrap(), json);
+        let yaml = "
+- name: a
+  url: https://e.com/a
+  size: 3
+  mode: '600'
+- name: d
+  contents:
+    - {name: b, content: b}
+";
+        assert_eq!(parse_yaml(yaml.as_bytes()).unwrap(), json);
+        let newer = format!("version = {}\ncontents = []\n", LAYOUT_VERSION + 1);
+        assert!(parse_toml(&newer)
+            .unwrap_err()
+            .to_string()
+            .contains("newer lhttpfs"));
+        assert!(parse_toml("name = ").is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let files = parse(
+            r#"[
+                {"name": "a", "url": "https://e.com/a", "size": 1},
+                {"name": "d", "base_url": "https://e.com/d/", "contents": [
+                    {"name": "b", "url": "b", "size": 1, "mirrors": ["https://[::1"]},
+                    {"name": "", "content": ""},
+                    {"name": "b", "content": ""}
+                ]},
+                {"name": "a", "url": "not a url", "size": 1},
+                {"name": "x/y", "content": ""}
+            ]"#
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            validate(&files).unwrap_err().to_string(),
+            "Invalid layout: The layout has 6 problems:
+  [2] (/a): \"a\" is also the name of [0]
+  [2] (/a): \"not a url\" isn't a valid URL: relative URL without a base
+  [3] (/x/y): \"x/y\" isn't allowed as a name: names can't contain a /
+  [1].contents[0] (/d/b): \"https://[::1\" isn't a valid URL: invalid IPv6 address
+  [1].contents[1] (/d/): Empty filenames are not allowed
+  [1].contents[2] (/d/b): \"b\" is also the name of [1].contents[0]"
+        );
+        assert!(validate(&files[..2][..1]).is_ok());
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_depth: 3,
+            max_entries: 10,
+            max_name_length: 8,
+            filters: false,
+            require_https: false,
+        };
+        let nested = |depth: usize| {
+            let mut files = vec![InputFile::URLFile(URLFile::new("f", "https://e.com/f", 1))];
+            for _ in 0..depth {
+                files = vec![InputFile::Directory(Directory::new("d", files))];
+            }
+            files
+        };
+        assert!(limits.check(&nested(3)).is_ok());
+        let err = limits.check(&nested(4)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid layout: /d/d/d/d is nested more than 3 directories deep"
+        );
+
+        let long = [InputFile::URLFile(URLFile::new(
+            "a-long-name",
+            "https://e.com/f",
+            1,
+        ))];
+        assert!(limits
+            .check(&long)
+            .is_err_and(|e| e.source().unwrap().is::<LimitExceeded>()));
+
+        let chunked = parse(
+            r#"[{"name": "c", "url": "https://e.com/c", "size": 100, "chunk_size": 10}]"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(limits.check(&chunked).is_err());
+
+        let filtered = parse(
+            r#"[{"name": "f", "url": "https://e.com/f", "size": 0, "filter": "cat"}]"#.as_bytes(),
+        )
+        .unwrap();
+        let err = limits.check(&filtered).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid layout: /f has a filter command, and filters aren't allowed"
+        );
+        let trusted = Limits {
+            filters: true,
+            ..limits
+        };
+        assert!(trusted.check(&filtered).is_ok());
+    }
+
+    #[test]
+    fn auto_decompressed() {
+        let json = r#"[
+            {"name": "a.csv.gz", "url": "https://example.com/a", "size": 10},
+            {"name": "b", "url": "https://example.com/b", "size": 20,
+             "content_type": "application/zstd"},
+            {"name": "c.xz", "url": "https://example.com/c", "size": 30, "decompress": "gzip"},
+            {"name": "d", "contents": [
+                {"name": "e.txt.xz", "url": "https://example.com/e", "size": 40},
+                {"name": "e.txt", "content": "taken"}
+            ]}
+        ]"#;
+        let mut files = parse(json.as_bytes()).unwrap();
+        auto_decompress(&mut files);
+        let urlfile = |file: &InputFile| match file {
+            InputFile::URLFile(file) => (file.name.clone(), file.decompress, file.compressed_size),
+            file => panic!("Expected a URL file, got {:?}", file),
+        };
+        assert_eq!(
+            urlfile(&files[0]),
+            ("a.csv".into(), Some(Compression::Gzip), Some(10))
+        );
+        assert_eq!(
+            urlfile(&files[1]),
+            ("b".into(), Some(Compression::Zstd), Some(20))
+        );
+        assert_eq!(
+            urlfile(&files[2]),
+            ("c.xz".into(), Some(Compression::Gzip), None)
+        );
+        let InputFile::Directory(d) = &files[3] else {
+            panic!("Expected a directory, got {:?}", files[3]);
+        };
+        assert_eq!(
+            urlfile(&d.contents[0]),
+            ("e.txt.xz".into(), Some(Compression::Xz), Some(40))
+        );
+    }
+
+    #[test]
+    fn content_types() {
+        let json = r#"[{"name": "d", "defaults": {"content_type": "text/csv"}, "contents": [
+            {"name": "a", "url": "https://example.com/a", "size": 1},
+            {"name": "b", "url": "https://example.com/b", "size": 1,
+             "content_type": "application/gzip; charset=binary"}
+        ]}]"#;
+        let mut files = parse(json.as_bytes()).unwrap();
+        let InputFile::Directory(d) = &files[0] else {
+            panic!("Expected a directory, got {:?}", files[0]);
+        };
+        let [InputFile::URLFile(a), InputFile::URLFile(b)] = &d.contents[..] else {
+            panic!("Unexpected contents {:?}", d.contents);
+        };
+        let inherited = |file: &URLFile| file.options.inherit(&d.defaults).content_type;
+        assert_eq!(inherited(a).as_deref(), Some("text/csv"));
+        assert_eq!(
+            inherited(b).as_deref(),
+            Some("application/gzip; charset=binary")
+        );
+
+        auto_decompress(&mut files);
+        let InputFile::Directory(d) = &files[0] else {
+            unreachable!();
+        };
+        let [InputFile::URLFile(a), InputFile::URLFile(b)] = &d.contents[..] else {
+            panic!("Unexpected contents {:?}", d.contents);
+        };
+        // A text type leaves the file alone; a compressed one is dropped,
+        // as it no longer describes what is served.
+        assert_eq!(
+            (a.decompress, a.options.content_type.as_deref()),
+            (None, None)
+        );
+        assert_eq!(
+            (b.decompress, b.options.content_type.as_deref()),
+            (Some(Compression::Gzip), None)
+        );
+    }
+
+    #[test]
+    fn checksum_files() {
+        let json = r#"[
+            {"name": "a.bin", "url": "https://example.com/a", "size": 1, "sha256": "ab12"},
+            {"name": "b.bin", "url": "https://example.com/b", "size": 1, "sha256": "cd34"},
+            {"name": "b.bin.sha256", "content": "mine"},
+            {"name": "d", "contents": [
+                {"name": "c.bin", "url": "https://example.com/c", "size": 1}
+            ]}
+        ]"#;
+        let mut files = parse(json.as_bytes()).unwrap();
+        add_checksum_files(&mut files);
+        let names = files.iter().map(InputFile::name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["a.bin", "b.bin", "b.bin.sha256", "d", "a.bin.sha256"]
+        );
+        let InputFile::InlineFile(sum) = &files[4] else {
+            panic!("Expected an inline file, got {:?}", files[4]);
+        };
+        assert_eq!(sum.content, "ab12  a.bin\n");
+        let InputFile::Directory(d) = &files[3] else {
+            panic!("Expected a directory, got {:?}", files[3]);
+        };
+        assert_eq!(d.contents.len(), 1);
+    }
+}
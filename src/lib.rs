@@ -0,0 +1,62 @@
+//! A read-only filesystem of remote files, fetched when read.
+//!
+//! A layout ([`layout`]) describes the tree: each file's name, size and
+//! where its bytes are, by URL. [`fs::LazyHTTPFS`] resolves it into inodes
+//! and, as a `fuser::Filesystem` with the `fuse` feature, serves it,
+//! fetching ranges through the [`fetch::Fetchers`] registered for each URL
+//! scheme and keeping them in a [`cache::Cache`]. The `lhttpfs` binary is a command line around these;
+//! another program can mount a tree of its own the same way, with
+//! [`fs::MountBuilder`] serving it as the binary does:
+//!
+//! ```no_run
+//! use lhttpfs::{layout, LazyHTTPFS, MountBuilder};
+//!
+//! # fn main() -> lhttpfs::Result<()> {
+//! let files = layout::parse(
+//!     r#"[{"name": "a.txt", "url": "https://example.com/a.txt", "size": 5}]"#
+//!         .as_bytes(),
+//! )?;
+//! let fs = LazyHTTPFS::builder()
+//!     .cache_dir(Some("/var/cache/assets".into()))
+//!     .uid(1000)
+//!     .gid(1000)
+//!     .build(files)?;
+//! MountBuilder::new(fs).fuse_threads(4).mount("/mnt/a")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! or fetch through the same backends without a filesystem at all, with
+//! [`fetch::Fetchers::fetch_range`]. In tests, a [`fetch::MemoryFetcher`]
+//! registered with [`fs::Builder::fetcher`] stands in for the origin.
+
+pub mod access;
+pub mod archive;
+pub mod cache;
+mod error;
+pub mod fetch;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fs;
+pub mod health;
+pub mod hooks;
+pub mod keyring;
+pub mod layout;
+pub mod metrics;
+pub mod observer;
+pub mod otlp;
+#[cfg(feature = "http")]
+pub mod testing;
+pub mod transform;
+
+pub use error::{LhttpfsError, NotBuilt};
+pub use fs::LazyHTTPFS;
+#[cfg(feature = "fuse")]
+pub use fs::MountBuilder;
+
+#[cfg(all(feature = "fuse", not(unix)))]
+compile_error!("The fuse feature mounts through FUSE, which needs a Unix; build with --no-default-features --features http");
+
+/// What the crate's own fallible functions return; the public entry points
+/// return a [`LhttpfsError`] instead.
+pub type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
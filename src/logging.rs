@@ -0,0 +1,150 @@
+//! Where log records go: stderr, as text, or with `--log-format json` as a
+//! line of JSON each, its fields as keys, for journald or an ELK stack to
+//! index and query.
+//!
+//! Either way, a record logged while spans are open on its thread (see
+//! [`crate::otlp`]) names them, outermost first, with their fields, so that
+//! a failed fetch can be followed back to the read and lookup it was for.
+
+use std::io::IsTerminal;
+
+use clap::Arg;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    fmt::{self, MakeWriter},
+    registry::LookupSpan,
+    Layer,
+};
+
+pub fn format_arg() -> Arg {
+    Arg::new("log-format")
+        .long("log-format")
+        .global(true)
+        .value_name("FORMAT")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .help("Log as text, or as a JSON object per line")
+}
+
+/// The layer writing records to stderr, as JSON if `json`, with the open
+/// spans as `read{lhttpfs.inode=5}:fetch{url.full="…"}: ` before the
+/// message of a text one and as a `spans` array in a JSON one.
+pub fn layer<S>(json: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    layer_to(json, std::io::stderr().is_terminal(), std::io::stderr)
+}
+
+fn layer_to<S, W>(json: bool, ansi: bool, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer);
+    match json {
+        true => (layer.json())
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+        false => layer.with_ansi(ansi).boxed(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::json;
+    use tracing::{debug, info_span, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::layer_to;
+
+    /// What was logged, to be read back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The lines `log` writes, as JSON if `json`.
+    fn logged(json: bool, log: impl FnOnce()) -> Vec<String> {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let layer = layer_to(json, false, move || writer.clone());
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), log);
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        text.lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn structured() {
+        let lines = logged(true, || {
+            let error = std::io::Error::other("gone");
+            debug!(
+                target: "lhttpfs::fs",
+                op = "read", inode = 5u64, latency_ms = 1.5, error = %error,
+                "Read {} bytes", 3
+            );
+        });
+        let mut record: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert!(record["timestamp"].is_string());
+        record.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            record,
+            json!({
+                "level": "DEBUG",
+                "target": "lhttpfs::fs",
+                "message": "Read 3 bytes",
+                "op": "read",
+                "inode": 5,
+                "latency_ms": 1.5,
+                "error": "gone",
+            })
+        );
+        let lines = logged(false, || debug!(op = "read", inode = 5u64, "Read"));
+        assert!(
+            lines[0].ends_with("Read op=\"read\" inode=5"),
+            "{}",
+            lines[0]
+        );
+    }
+
+    #[test]
+    fn spanned() {
+        let log = || {
+            warn!("Before");
+            let _read = info_span!("read", lhttpfs.inode = 5, lhttpfs.offset = 4096).entered();
+            let _fetch = info_span!("fetch", url.full = "https://example.com/a").entered();
+            warn!("Fetching failed");
+        };
+        let lines = logged(false, log);
+        assert!(!lines[0].contains('{'), "{}", lines[0]);
+        let spans = r#"read{lhttpfs.inode=5 lhttpfs.offset=4096}:fetch{url.full="https://example.com/a"}: "#;
+        assert!(lines[1].contains(spans), "{}", lines[1]);
+        let lines = logged(true, log);
+        let before: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert!(before.get("spans").is_none());
+        let record: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(
+            record["spans"],
+            json!([
+                {"name": "read", "lhttpfs.inode": 5, "lhttpfs.offset": 4096},
+                {"name": "fetch", "url.full": "https://example.com/a"},
+            ])
+        );
+    }
+}
@@ -1,22 +1,139 @@
-use std::{error::Error, fs::File};
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Cursor, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::{Arg, ArgAction, Command};
-use fs::LazyHTTPFS;
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use fuser::MountOption;
+use lhttpfs::{
+    access, cache, fetch, fs,
+    fs::{ControlFile, LazyHTTPFS},
+    health, hooks, keyring, layout,
+    layout::Defaults,
+    metrics, otlp, transform, LhttpfsError, Result,
+};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
-mod fs;
+#[cfg(feature = "http")]
+mod bench;
+mod check;
+mod config;
+mod credentials;
+mod ctl;
+mod daemon;
+mod dav;
+mod dbus;
+mod docker;
+mod encrypt;
+mod filter;
+mod gateway;
+#[cfg(feature = "http")]
+mod generate;
+mod helper;
+mod inspect;
+mod logging;
+mod nfs;
+mod ninep;
+mod prefetch;
+mod preflight;
+mod sandbox;
+mod server;
+mod signature;
+mod systemd;
 
-type Result<T> = core::result::Result<T, Box<dyn Error>>;
+fn command() -> Command {
+    let command = Command::new("lhttpfs")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Mount remote files as a read-only filesystem, fetching them when read")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("proxy")
+                .long("proxy")
+                .global(true)
+                .value_name("URL")
+                .help(
+                    "Send requests through this proxy, as curl's http_proxy and https_proxy would",
+                ),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .global(true)
+                .value_name("FILTER")
+                .help("What to log, as RUST_LOG takes it, such as info or lhttpfs=debug"),
+        )
+        .arg(logging::format_arg())
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .global(true)
+                .action(ArgAction::Count)
+                .help("Log more: -v for info, -vv for debug and -vvv for trace"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .conflicts_with("verbose")
+                .help("Log nothing, not even errors"),
+        )
+        .arg(config::arg())
+        .args(credentials::args())
+        .subcommand(mount_command())
+        .subcommand(inspect::validate_command())
+        .subcommand(prefetch::command())
+        .subcommand(prefetch::cache_command())
+        .subcommand(inspect::tree_command())
+        .subcommand(inspect::du_command())
+        .subcommand(inspect::compile_command())
+        .subcommand(check::command())
+        .subcommand(encrypt::command())
+        .subcommand(ctl::command())
+        .subcommand(nfs::command())
+        .subcommand(dav::command())
+        .subcommand(gateway::command())
+        .subcommand(ninep::command())
+        .subcommand(docker::command())
+        .subcommand(
+            Command::new("completions")
+                .about("Print a completion script for a shell, to be sourced by it")
+                .arg(
+                    Arg::new("SHELL")
+                        .required(true)
+                        .value_parser(clap::value_parser!(clap_complete::Shell)),
+                ),
+        );
+    #[cfg(feature = "http")]
+    let command = (command.subcommand(generate::command())).subcommand(bench::command());
+    config::with_env(command)
+}
 
-fn main() {
-    let matches = Command::new("hello")
-        .version("0.0.1")
-        .author("Christopher Berner")
+fn mount_command() -> Command {
+    Command::new("mount")
+        .about("Mount layouts at a directory, serving them until unmounted")
         .arg(
             Arg::new("MOUNT_POINT")
-                .required(true)
-                .index(1)
-                .help("Act as a client, and mount FUSE at given path"),
+                .required_unless_present("mount")
+                .requires("LAYOUT")
+                .help("Directory to mount the filesystem at"),
+        )
+        .arg(
+            Arg::new("mount")
+                .long("mount")
+                .value_name("DIR=LAYOUT[,LAYOUT...]")
+                .action(ArgAction::Append)
+                .value_parser(parse_mount)
+                .help("Also mount these layouts at DIR, sharing the cache with the other mounts"),
         )
         .arg(
             Arg::new("auto_unmount")
@@ -24,6 +141,49 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .help("Automatically unmount on process exit"),
         )
+        .arg(
+            Arg::new("fetch-threads")
+                .long("fetch-threads")
+                .aliases(["read-threads", "max-connections"])
+                .value_name("N")
+                .default_value("8")
+                .value_parser(clap::value_parser!(usize))
+                .help("Fetch for up to N reads at once, or for each in turn with 0"),
+        )
+        .arg(
+            Arg::new("fuse-threads")
+                .long("fuse-threads")
+                .value_name("N")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .help("Answer the reads the kernel sends on N threads"),
+        )
+        .arg(
+            Arg::new("readahead")
+                .long("readahead")
+                .value_name("SIZE")
+                .default_value("8M")
+                .value_parser(prefetch::size)
+                .help("Fetch up to SIZE ahead of reads going through a file in order, or nothing with 0"),
+        )
+        .arg(
+            Arg::new("epoch-order")
+                .long("epoch-order")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help(
+                    "Fetch the files FILE lists, a path a line, just ahead of reads in that order",
+                ),
+        )
+        .arg(
+            Arg::new("epoch-ahead")
+                .long("epoch-ahead")
+                .value_name("N")
+                .default_value("16")
+                .requires("epoch-order")
+                .value_parser(clap::value_parser!(usize))
+                .help("Fetch N files of --epoch-order ahead of the one being read"),
+        )
         .arg(
             Arg::new("allow-root")
                 .long("allow-root")
@@ -31,34 +191,997 @@ fn main() {
                 .help("Allow root user to access filesystem"),
         )
         .arg(
-            Arg::new("LAYOUT")
-                .required(true)
-                .index(2)
-                .help("JSON file that contains the layout of the filesystem"),
+            Arg::new("option")
+                .short('o')
+                .value_name("OPTION[,OPTION...]")
+                .action(ArgAction::Append)
+                .value_parser(parse_options)
+                .help("Pass FUSE mount options on, e.g. allow_other,default_permissions,fsname=assets"),
         )
-        .get_matches();
-    env_logger::init();
-    let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
-    let mut options = vec![MountOption::RO, MountOption::FSName("lhttp".to_string())];
-    if matches.get_flag("auto_unmount") {
+        .arg(
+            Arg::new("daemon")
+                .long("daemon")
+                .visible_alias("daemonize")
+                .action(ArgAction::SetTrue)
+                .help("Go to the background once mounted, reporting failures before that"),
+        )
+        .arg(
+            Arg::new("foreground")
+                .short('f')
+                .long("foreground")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("daemon")
+                .help("Stay in the foreground until unmounted, as without --daemon"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print the tree that would be mounted and its size, then exit"),
+        )
+        .arg(
+            Arg::new("access-log")
+                .long("access-log")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Append a line of JSON to FILE for every read"),
+        )
+        .arg(
+            Arg::new("metrics-listen")
+                .long("metrics-listen")
+                .value_name("ADDR")
+                .value_parser(clap::value_parser!(SocketAddr))
+                .help("Serve Prometheus metrics at http://ADDR/metrics, e.g. 127.0.0.1:9123"),
+        )
+        .arg(
+            Arg::new("pidfile")
+                .long("pidfile")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the process id to FILE while mounted"),
+        )
+        .arg(
+            Arg::new("refresh")
+                .long("refresh")
+                .value_name("INTERVAL")
+                .value_parser(parse_interval)
+                .help("Reload the layouts every INTERVAL, e.g. 300s, 5m or 1h"),
+        )
+        .arg(health::arg())
+        .arg(hooks::arg())
+        .arg(ctl::arg())
+        .arg(dbus::arg())
+        .arg(otlp::arg())
+        .arg(prefetch::cache_dir_arg())
+        .arg(prefetch::cache_size_arg())
+        .arg(fetch::plugin::arg())
+        .args(sandbox::args())
+        .args(inspect::load_args())
+        .mut_arg("LAYOUT", |arg| {
+            arg.required(false).required_unless_present("mount")
+        })
+}
+
+/// Splits a `--mount` value into the mount point and its layouts.
+fn parse_mount(spec: &str) -> core::result::Result<(PathBuf, Vec<String>), String> {
+    match spec.split_once('=') {
+        Some((dir, layouts)) if !dir.is_empty() && !layouts.is_empty() => {
+            Ok((dir.into(), layouts.split(',').map(str::to_string).collect()))
+        }
+        _ => Err(format!("expected DIR=LAYOUT[,LAYOUT...], got {:?}", spec)),
+    }
+}
+
+/// Mount options for writing, which the read-only mount refuses rather
+/// than pass on next to its `ro`.
+const WRITE_OPTIONS: &[&str] = &["rw", "big_writes", "atomic_o_trunc", "writeback_cache"];
+
+/// Parses a `-o` value into mount options: FUSE's own as
+/// [`helper::fuse_option`] knows them, and anything else but
+/// [`WRITE_OPTIONS`] as it is, for the kernel or fusermount to make sense
+/// of.
+fn parse_options(options: &str) -> core::result::Result<Vec<MountOption>, String> {
+    let options = options.split(',').filter(|option| !option.is_empty());
+    let options = options.map(|option| {
+        if WRITE_OPTIONS.contains(&option) {
+            return Err(format!(
+                "lhttpfs mounts are read-only, so -o {} can't be given",
+                option
+            ));
+        }
+        Ok(helper::fuse_option(option).unwrap_or_else(|| MountOption::CUSTOM(option.to_string())))
+    });
+    options.collect()
+}
+
+/// Parses a `--refresh` interval: a number of seconds, or of minutes or
+/// hours with an `m` or `h`.
+fn parse_interval(interval: &str) -> core::result::Result<Duration, String> {
+    let (number, unit) = match interval.strip_suffix(['s', 'm', 'h']) {
+        Some(number) => (number, &interval[number.len()..]),
+        None => (interval, "s"),
+    };
+    let seconds = match (number.parse::<u64>(), unit) {
+        (Ok(n), "s") => Some(n),
+        (Ok(n), "m") => n.checked_mul(60),
+        (Ok(n), _) => n.checked_mul(3600),
+        (Err(_), _) => return Err(format!("expected e.g. 300s, 5m or 1h, got {:?}", interval)),
+    };
+    let seconds = seconds.ok_or_else(|| format!("{} is too long an interval", interval))?;
+    if seconds == 0 {
+        return Err("the interval can't be 0".into());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// What `-v`, `-q` and `--log-level` ask to log, on top of `RUST_LOG`.
+fn log_filter(matches: &ArgMatches) -> EnvFilter {
+    // Levels without a module only apply to what RUST_LOG doesn't name.
+    let level = match (matches.get_flag("quiet"), matches.get_count("verbose")) {
+        (true, _) => Some(LevelFilter::OFF),
+        (_, 0) => None,
+        (_, 1) => Some(LevelFilter::INFO),
+        (_, 2) => Some(LevelFilter::DEBUG),
+        _ => Some(LevelFilter::TRACE),
+    };
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(level.unwrap_or(LevelFilter::ERROR).into())
+        .from_env_lossy();
+    if let Some(level) = level {
+        filter = filter.add_directive(level.into());
+    }
+    let directives = matches.get_one::<String>("log-level").into_iter();
+    for directive in directives.flat_map(|filter| filter.split(',')) {
+        match directive.parse() {
+            Ok(directive) => filter = filter.add_directive(directive),
+            Err(e) => eprintln!("Ignoring the log filter {:?}: {}", directive, e),
+        }
+    }
+    filter
+}
+
+fn main() {
+    let (matches, config) = match matches() {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let json = matches
+        .get_one::<String>("log-format")
+        .is_some_and(|format| format == "json");
+    tracing_subscriber::registry()
+        .with(logging::layer(json).with_filter(log_filter(&matches)))
+        .with(otlp::layer())
+        .init();
+    if let Some(proxy) = matches.get_one::<String>("proxy") {
+        // Set before any threads are started, for every curl handle to see.
+        for var in ["http_proxy", "https_proxy", "all_proxy"] {
+            std::env::set_var(var, proxy);
+        }
+    }
+    let defaults = match credentials::defaults(&matches, config.defaults) {
+        Ok(defaults) => defaults,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let defaults = &defaults;
+    let (name, matches) = matches.subcommand().unwrap();
+    let result = match name {
+        "mount" => mount(matches, defaults),
+        #[cfg(feature = "http")]
+        "generate" => generate::run(matches),
+        #[cfg(feature = "http")]
+        "bench" => bench::run(matches, &mut std::io::stdout()),
+        "encrypt" => encrypt::run(matches),
+        "cache" => prefetch::run_cache(matches, &mut std::io::stdout()),
+        "ctl" => ctl::run(matches, &mut std::io::stdout()),
+        "docker-plugin" => docker::run(matches),
+        "completions" => {
+            let shell = *matches.get_one::<clap_complete::Shell>("SHELL").unwrap();
+            clap_complete::generate(shell, &mut command(), "lhttpfs", &mut std::io::stdout());
+            Ok(())
+        }
+        "check" => {
+            match load(matches, defaults)
+                .and_then(|fs| check::run(&fs, matches, &mut std::io::stdout()))
+            {
+                Ok(false) => Ok(()),
+                Ok(true) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        "compile" => {
+            let output = matches.get_one::<String>("output").unwrap();
+            load(matches, defaults)
+                .and_then(|fs| Ok(fs.compile(BufWriter::new(File::create(output)?))?))
+        }
+        "serve-nfs" => load_served(matches, defaults).and_then(|fs| nfs::run(fs, matches)),
+        "serve-dav" => load_served(matches, defaults).and_then(|fs| dav::run(fs, matches)),
+        "serve-http" => load_served(matches, defaults).and_then(|fs| gateway::run(fs, matches)),
+        "serve-9p" => load_served(matches, defaults).and_then(|fs| ninep::run(fs, matches)),
+        "prefetch" => load(matches, defaults)
+            .map(|fs| with_fetch_args(fs, matches))
+            .and_then(|mut fs| prefetch::run(&mut fs, &mut std::io::stdout())),
+        _ => load(matches, defaults).and_then(|fs| {
+            let mut out = std::io::stdout().lock();
+            match name {
+                "validate" => inspect::run_validate(&fs, &mut out),
+                "tree" => inspect::run_tree(&fs, matches, &mut out),
+                _ => inspect::run_du(&fs, matches, &mut out),
+            }
+        }),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        if let Some(hint) = e.downcast_ref().and_then(hint) {
+            eprintln!("  {}", hint);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// What to do about `error`, where that isn't already part of it.
+fn hint(error: &LhttpfsError) -> Option<&'static str> {
+    match error {
+        LhttpfsError::Fetch {
+            status: Some(401 | 403),
+            ..
+        } => Some("The origin wants credentials: give the entry, or its directory, an `auth`."),
+        LhttpfsError::Fetch {
+            status: Some(404 | 410),
+            ..
+        } => Some("The origin no longer has it: regenerate the layout, or fix its URL."),
+        LhttpfsError::Cache { .. } => {
+            Some("Point --cache-dir somewhere writable, or clear it with `lhttpfs cache clear`.")
+        }
+        _ => None,
+    }
+}
+
+/// Applies `--cache-dir`, `--cache-size` and `--backend-plugin`, for the
+/// commands that read files.
+fn with_fetch_args(mut fs: LazyHTTPFS, matches: &ArgMatches) -> LazyHTTPFS {
+    if let Some(dir) = matches.get_one::<PathBuf>("cache-dir") {
+        fs.set_cache_dir(dir.clone());
+    }
+    if let Some(bytes) = matches.get_one::<u64>("cache-size") {
+        fs.set_cache_limit(Some(*bytes));
+    }
+    let plugins = matches.get_many::<(String, PathBuf)>("backend-plugin");
+    for (scheme, program) in plugins.into_iter().flatten() {
+        let plugin = fetch::plugin::Plugin::new(program);
+        fs.fetchers_mut().register(scheme, Arc::new(plugin));
+    }
+    fs
+}
+
+/// Reloads `fs` from `layouts` and what was written to `.lhttpfs/add`, which
+/// is refused when layouts have to be signed, since those can't be.
+fn set_reloader(
+    fs: &mut LazyHTTPFS,
+    layouts: Vec<String>,
+    matches: ArgMatches,
+    defaults: Defaults,
+) {
+    if matches.get_one::<String>("require-signed-layout").is_some() {
+        fs.deny_writes(ControlFile::Add);
+    }
+    fs.set_reloader(Box::new(move |added: &[Vec<u8>]| {
+        load_layouts(&layouts, added, &matches, &defaults)
+    }));
+}
+
+/// Loads the layouts to serve as a mount would, with the manifest and the
+/// control files, for the servers that stand in for one.
+fn load_served(matches: &ArgMatches, defaults: &Defaults) -> Result<LazyHTTPFS> {
+    let layouts: Vec<String> = matches
+        .get_many::<String>("LAYOUT")
+        .unwrap()
+        .cloned()
+        .collect();
+    let mut fs = with_fetch_args(load_layouts(&layouts, &[], matches, defaults)?, matches);
+    fs.add_manifest();
+    fs.add_control();
+    set_reloader(&mut fs, layouts, matches.clone(), defaults.clone());
+    Ok(fs)
+}
+
+/// Loads the layouts and serves them at their mount points until they are
+/// all unmounted, each one from a thread of its own. With `--daemon` it is
+/// done in a child process, which the terminal is handed back from once
+/// everything is mounted.
+fn mount(matches: &ArgMatches, defaults: &Defaults) -> Result<()> {
+    // Not `RO`, which would keep the control files from being written to;
+    // everything else refuses writes itself.
+    let mut options = vec![MountOption::FSName("lhttp".to_string())];
+    // macFUSE and fuse-t unmount by themselves once the process is gone,
+    // and refuse the option.
+    if matches.get_flag("auto_unmount") && !cfg!(target_os = "macos") {
         options.push(MountOption::AutoUnmount);
     }
     if matches.get_flag("allow-root") {
         options.push(MountOption::AllowRoot);
     }
+    let passed = matches.get_many::<Vec<MountOption>>("option");
+    for option in passed.into_iter().flatten().flatten() {
+        // A name of its own replaces lhttp's.
+        if let MountOption::FSName(_) = option {
+            options.retain(|o| !matches!(o, MountOption::FSName(_)));
+        }
+        options.push(option.clone());
+    }
+    let dry_run = matches.get_flag("dry-run");
+    let ready = (matches.get_flag("daemon") && !dry_run)
+        .then(daemon::daemonize)
+        .transpose()?;
+    let mut filesystems = load_mounts(matches, defaults)?;
+    if dry_run {
+        return print_mounts(&filesystems, &mut std::io::stdout());
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("access-log") {
+        let file =
+            access::open(path).map_err(|e| format!("Couldn't open {}: {}", path.display(), e))?;
+        for (mountpoint, fs) in &mut filesystems {
+            fs.set_access_log(file.clone(), mountpoint);
+        }
+    }
+    let on_event = matches.get_one::<String>("on-event");
+    if on_event.is_some() {
+        for (mountpoint, fs) in &mut filesystems {
+            fs.set_hooks(mountpoint);
+        }
+    }
+    let metrics = (matches.get_one::<SocketAddr>("metrics-listen"))
+        .map(|addr| metrics::bind(*addr).map_err(|e| format!("Couldn't listen on {}: {}", addr, e)))
+        .transpose()?;
+    let socket = (matches.get_one::<PathBuf>("control-socket"))
+        .map(|path| {
+            ctl::Socket::bind(path)
+                .map_err(|e| format!("Couldn't listen on {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let bus = (matches.get_one::<String>("dbus"))
+        .map(|bus| dbus::Bus::connect(bus == "system"))
+        .transpose()?;
+    let health = (matches.get_one::<PathBuf>("health-file"))
+        .map(|path| {
+            health::File::create(path)
+                .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let mountpoints = filesystems.iter().map(|(mountpoint, _)| mountpoint.clone());
+    let mountpoints: Vec<_> = mountpoints.collect();
+    let mut sessions = Vec::new();
+    for (i, (mountpoint, fs)) in filesystems.into_iter().enumerate() {
+        let epoch = match i {
+            0 => epoch(matches, &fs)?,
+            _ => None,
+        };
+        let notifier = fs.notifier();
+        let mut options = options.clone();
+        if cfg!(target_os = "macos") {
+            options.extend(macos_options(&mountpoint));
+        }
+        let threads = *matches.get_one::<usize>("fetch-threads").unwrap();
+        let dispatchers = *matches.get_one::<u16>("fuse-threads").unwrap();
+        let readahead = *matches.get_one::<u64>("readahead").unwrap();
+        let mut fs = (fs::Dispatched::new(fs, threads).dispatchers(dispatchers as usize))
+            .readahead(readahead);
+        if let Some(epoch) = epoch {
+            fs = fs.epoch(epoch);
+        }
+        if (matches.get_one::<String>("profile")).is_some_and(|p| p == fs::Streaming::PROFILE) {
+            fs = fs.streaming(fs::Streaming::default());
+        }
+        let session = fuser::Session::new(fs, &mountpoint, &options)
+            .map_err(|e| preflight::explain(e, &mountpoint, matches.get_flag("allow-root")))?;
+        let _ = notifier.set(session.notifier());
+        sessions.push(session);
+    }
+    let unmounters = sessions.iter_mut().map(|s| s.unmount_callable()).collect();
+    let mut unmount: Box<dyn FnMut() + Send> = Box::new(daemon::unmounting(unmounters));
+    if matches.get_flag("sandbox") {
+        let mut unmounter = sandbox::Unmounter::fork(unmount)?;
+        sandbox::restrict(matches)?;
+        unmount = Box::new(move || unmounter.unmount());
+    }
+    daemon::unmount_on_signals(unmount)?;
+    if let Some(listener) = metrics {
+        metrics::serve(listener);
+    }
+    if let Some(endpoint) = matches.get_one::<String>("otlp-endpoint") {
+        otlp::init(endpoint);
+    }
+    if let Some(health) = &health {
+        health.update();
+    }
+    if let Some(command) = on_event {
+        hooks::init(command.clone());
+    }
+    if let Some(bus) = bus {
+        bus.serve(mountpoints.clone());
+    }
+    if let Some(interval) = matches.get_one::<Duration>("refresh") {
+        refresh(mountpoints.clone(), *interval);
+    }
+    if let Some(socket) = &socket {
+        socket.serve(mountpoints)?;
+    }
+    let _pidfile = (matches.get_one::<PathBuf>("pidfile"))
+        .map(|path| daemon::Pidfile::create(path))
+        .transpose()?;
+    if let Some(ready) = ready {
+        ready.notify()?;
+    }
+    systemd::notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+    systemd::watchdog();
+    let result = std::thread::scope(|scope| {
+        let running: Vec<_> = (sessions.iter_mut())
+            .map(|session| scope.spawn(|| session.run()))
+            .collect();
+        running
+            .into_iter()
+            .try_for_each(|session| session.join().unwrap())
+    });
+    systemd::notify("STOPPING=1");
+    otlp::flush();
+    result?;
+    Ok(())
+}
+
+/// `--refresh`: reloads every mount each `interval` through its
+/// `.lhttpfs/reload`, as `ctl reload` would. A failed reload leaves the
+/// tree as it was until the next one.
+fn refresh(mountpoints: Vec<PathBuf>, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        for mountpoint in &mountpoints {
+            if let Err(e) = ctl::handle(Some(mountpoint), ctl::Request::Reload, &mountpoints) {
+                tracing::warn!("Refreshing {} failed: {}", mountpoint.display(), e);
+            }
+        }
+    });
+}
+
+/// The `--epoch-order` of the first mount, `fs`, if there is one.
+fn epoch(matches: &ArgMatches, fs: &LazyHTTPFS) -> Result<Option<fs::Epoch>> {
+    let Some(path) = matches.get_one::<PathBuf>("epoch-order") else {
+        return Ok(None);
+    };
+    let order = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let paths = order.lines().map(str::trim).filter(|line| !line.is_empty());
+    let ahead = *matches.get_one::<usize>("epoch-ahead").unwrap();
+    Ok(Some(fs::Epoch::new(fs, paths, ahead)?))
+}
+
+/// What macFUSE and fuse-t need besides: a name for Finder to show the
+/// volume by, the mount point's, and no `._` files, which Finder would
+/// otherwise try to write next to every file it looks at.
+fn macos_options(mountpoint: &Path) -> Vec<MountOption> {
+    let name = mountpoint.file_name().unwrap_or(OsStr::new("lhttpfs"));
+    // A comma would end the option.
+    let name = name.to_string_lossy().replace(',', "_");
+    vec![
+        MountOption::CUSTOM(format!("volname={}", name)),
+        MountOption::CUSTOM("noappledouble".to_string()),
+    ]
+}
 
-    let a: Result<_> = File::open(matches.get_one::<String>("LAYOUT").unwrap())
-        .map_err(From::from)
-        .and_then(|f| serde_json::from_reader(f).map_err(From::from))
-        .and_then(LazyHTTPFS::new);
+/// Loads the layouts of each mount point, the first setting up the cache
+/// and backends and the others sharing them.
+fn load_mounts(matches: &ArgMatches, defaults: &Defaults) -> Result<Vec<(PathBuf, LazyHTTPFS)>> {
+    let mut mounts = Vec::new();
+    if let Some(mountpoint) = matches.get_one::<String>("MOUNT_POINT") {
+        let layouts = matches.get_many::<String>("LAYOUT").unwrap().cloned();
+        mounts.push((PathBuf::from(mountpoint), layouts.collect()));
+    }
+    let more = matches.get_many::<(PathBuf, Vec<String>)>("mount");
+    mounts.extend(more.into_iter().flatten().cloned());
 
-    match a {
-        Ok(data) => {
-            fuser::mount2(data, mountpoint, &options).unwrap();
+    let mut filesystems: Vec<(PathBuf, LazyHTTPFS)> = Vec::new();
+    for (mountpoint, layouts) in mounts {
+        let mut fs = load_layouts(&layouts, &[], matches, defaults)?;
+        match filesystems.first() {
+            Some((_, first)) => fs.share(first),
+            None => fs = with_fetch_args(fs, matches),
         }
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(1);
+        fs.add_manifest();
+        fs.add_control();
+        set_reloader(&mut fs, layouts, matches.clone(), defaults.clone());
+        // The daemon leaves the working directory, and unmounting needs
+        // the path to still resolve then.
+        filesystems.push((std::path::absolute(mountpoint)?, fs));
+    }
+    Ok(filesystems)
+}
+
+/// `--dry-run`: the tree each mount point would show.
+fn print_mounts(filesystems: &[(PathBuf, LazyHTTPFS)], out: &mut impl Write) -> Result<()> {
+    for (i, (mountpoint, fs)) in filesystems.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        let root = mountpoint.display().to_string();
+        inspect::print_tree(fs, &root, false, false, out)?;
+    }
+    Ok(())
+}
+
+/// Parses the command line, taking what it leaves out from the
+/// configuration file.
+fn matches() -> Result<(ArgMatches, config::Config)> {
+    let Some(args) = helper::args(std::env::args_os().collect(), &mount_command())? else {
+        std::process::exit(0);
+    };
+    // What's required may be in the file, so `--config` is looked for
+    // before anything is checked.
+    let lenient = command().ignore_errors(true).get_matches_from(&args);
+    let config = config::load(&lenient)?;
+    Ok((config.apply(command())?.get_matches_from(args), config))
+}
+
+/// Parses a layout in `format`, or else the one its extension says:
+/// `.toml`, `.yaml` or `.yml`, and `.csv` and `.tsv` files as tables of
+/// `path,url,size[,sha256]` rows. Anything else is JSON.
+fn parse(
+    path: &str,
+    format: Option<&str>,
+    mut reader: impl Read,
+) -> Result<Vec<layout::InputFile>> {
+    let name = match remote(path) {
+        true => path.split(['?', '#']).next().unwrap_or(path),
+        false => path,
+    };
+    let extension = Path::new(name).extension().and_then(|e| e.to_str());
+    let files = match format.or(extension) {
+        Some("csv") => layout::parse_table(reader, b',')?,
+        Some("tsv") => layout::parse_table(reader, b'\t')?,
+        Some("toml") => {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            layout::parse_toml(&text)?
+        }
+        Some("yaml" | "yml") => layout::parse_yaml(reader)?,
+        _ => layout::parse(reader)?,
+    };
+    Ok(files)
+}
+
+/// Whether the layout at `path` is to be downloaded rather than opened.
+fn remote(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// How remote layouts are downloaded: as the files they describe are
+/// fetched, from the hosts `--allow-host` and `--deny-host` allow, only
+/// over HTTPS with `--require-https`, and with the headers and credentials
+/// given when mounting.
+#[derive(Default)]
+struct Downloads {
+    fetchers: fetch::Fetchers,
+    defaults: Defaults,
+    require_https: bool,
+}
+
+impl Downloads {
+    fn new(matches: &ArgMatches, defaults: &Defaults) -> Result<Downloads> {
+        let mut fetchers = fetch::Fetchers::default();
+        fetchers.restrict(inspect::hosts(matches)?);
+        fetchers.retry(inspect::retry(matches));
+        fetchers.use_keyring(credentials::keyring(matches));
+        if let Some(netrc) = credentials::netrc(matches)? {
+            fetchers.use_netrc(netrc);
+        }
+        let require_https = inspect::limits(matches).require_https;
+        if require_https {
+            fetchers.require_https();
+        }
+        Ok(Downloads {
+            fetchers,
+            defaults: defaults.clone(),
+            require_https,
+        })
+    }
+
+    /// Reads the layout at `path`, downloading it if it's an http(s) URL.
+    fn read_layout(&self, path: &str) -> Result<Vec<u8>> {
+        if !remote(path) {
+            return Ok(std::fs::read(path)?);
         }
+        if self.require_https && self.fetchers.plaintext(path) {
+            return Err(format!("{} is plain HTTP, which --require-https refuses", path).into());
+        }
+        let request = fetch::Request {
+            url: path,
+            headers: &self.defaults.headers,
+            auth: self.defaults.auth.as_ref(),
+            size: 0,
+        };
+        Ok(self.fetchers.fetch_range(&request, None)?)
+    }
+}
+
+/// Reads and merges the `LAYOUT` files, applies `--profile`,
+/// `--include`/`--exclude` and `--checksum-files` and resolves them, within
+/// the limits, into the tree that gets mounted. A single compiled layout
+/// is loaded as is.
+/// With `--require-signed-layout`, every file's signature is checked first.
+fn load(matches: &ArgMatches, defaults: &Defaults) -> Result<LazyHTTPFS> {
+    let paths: Vec<String> = matches.get_many("LAYOUT").unwrap().cloned().collect();
+    load_layouts(&paths, &[], matches, defaults)
+}
+
+/// [`load`] for the layouts at `paths` rather than those of `LAYOUT`, and
+/// those `added` to a running mount through `.lhttpfs/add`.
+fn load_layouts(
+    paths: &[String],
+    added: &[Vec<u8>],
+    matches: &ArgMatches,
+    defaults: &Defaults,
+) -> Result<LazyHTTPFS> {
+    let defaults = &inspect::owned(matches, defaults);
+    let key = matches
+        .get_one::<String>("require-signed-layout")
+        .map(|key| signature::public_key(key))
+        .transpose()?;
+    let downloads = Downloads::new(matches, defaults)?;
+    let open = |path: &str| -> Result<Box<dyn BufRead>> {
+        Ok(match &key {
+            Some(key) => Box::new(Cursor::new(signature::read_verified(
+                path, key, &downloads,
+            )?)),
+            None if remote(path) => Box::new(Cursor::new(downloads.read_layout(path)?)),
+            None => Box::new(BufReader::new(File::open(path)?)),
+        })
+    };
+    let filter = filter::Filter::from_matches(matches)?;
+    let hosts = inspect::hosts(matches)?;
+    let mut layouts = Vec::new();
+    for path in paths {
+        // Each layout is opened once, so they can be pipes too.
+        let mut reader = open(path)?;
+        if paths.len() == 1 && added.is_empty() {
+            if let Some(mut fs) = LazyHTTPFS::read_compiled(&mut reader, inspect::limits(matches))?
+            {
+                if filter.is_some()
+                    || (matches.get_one::<String>("profile"))
+                        .is_some_and(|profile| profile != fs::Streaming::PROFILE)
+                    || matches.get_flag("checksum-files")
+                    || matches.get_flag("auto-decompress")
+                    || *defaults != Defaults::default()
+                {
+                    return Err(Box::new(layout::CompiledLayout()));
+                }
+                fs.fetchers_mut().restrict(hosts);
+                fs.fetchers_mut().retry(inspect::retry(matches));
+                if let Some(netrc) = credentials::netrc(matches)? {
+                    fs.fetchers_mut().use_netrc(netrc);
+                }
+                fs.fetchers_mut().use_keyring(credentials::keyring(matches));
+                return Ok(with_source_files(fs, matches));
+            }
+        }
+        let format = matches.get_one::<String>("format").map(String::as_str);
+        layouts.push(parse(path, format, reader)?);
+    }
+    for added in added {
+        layouts.push(layout::parse(&added[..])?);
+    }
+    let on_conflict = match matches.get_one::<String>("on-conflict").map(String::as_str) {
+        Some("first") => layout::OnConflict::First,
+        Some("last") => layout::OnConflict::Last,
+        _ => layout::OnConflict::Error,
+    };
+    let mut files = layout::merge(layouts, on_conflict)?;
+    if let Some(profile) = matches.get_one::<String>("profile") {
+        match layout::apply_profile(&mut files, profile) {
+            // Built in, it needs no overrides in the layout.
+            Err(_) if profile == fs::Streaming::PROFILE => {}
+            applied => applied?,
+        }
+    }
+    if let Some(filter) = filter {
+        files = filter.apply(files);
+    }
+    if matches.get_flag("auto-decompress") {
+        layout::auto_decompress(&mut files);
+    }
+    if matches.get_flag("checksum-files") {
+        layout::add_checksum_files(&mut files);
+    }
+    let mut builder = LazyHTTPFS::builder()
+        .defaults(defaults.clone())
+        .limits(inspect::limits(matches))
+        .hosts(hosts)
+        .retry(inspect::retry(matches))
+        .keyring(credentials::keyring(matches))
+        .probe(!matches.get_flag("no-probe"));
+    if let Some(netrc) = credentials::netrc(matches)? {
+        builder = builder.netrc(netrc);
+    }
+    let fs = builder.build(files)?;
+    Ok(with_source_files(fs, matches))
+}
+
+/// Applies `--source-files`, which compiled layouts can take too: the
+/// files are made from the resolved tree.
+fn with_source_files(mut fs: LazyHTTPFS, matches: &ArgMatches) -> LazyHTTPFS {
+    if matches.get_flag("source-files") {
+        fs.add_source_files();
+    }
+    fs
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use fuser::MountOption;
+    use lhttpfs::fetch::{HostRefused, HttpStatus};
+
+    use tracing::level_filters::LevelFilter;
+
+    use super::{
+        command, hint, inspect, load_mounts, log_filter, macos_options, parse_interval,
+        parse_options, print_mounts, Defaults, Downloads, LhttpfsError,
+    };
+
+    #[test]
+    fn macos() {
+        assert_eq!(
+            macos_options(Path::new("/Volumes/data,sets")),
+            [
+                MountOption::CUSTOM("volname=data_sets".into()),
+                MountOption::CUSTOM("noappledouble".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn arguments() {
+        command().debug_assert();
+        let matches = command().get_matches_from(["lhttpfs", "mount", "/mnt", "a.json", "b.json"]);
+        let (_, matches) = matches.subcommand().unwrap();
+        let layouts: Vec<_> = matches.get_many::<String>("LAYOUT").unwrap().collect();
+        assert_eq!(layouts, ["a.json", "b.json"]);
+        let matches = command().get_matches_from(["lhttpfs", "tree", "-vv", "a.json"]);
+        let (_, matches) = matches.subcommand().unwrap();
+        assert_eq!(matches.get_count("verbose"), 2);
+        assert!(command()
+            .try_get_matches_from(["lhttpfs", "tree", "-v", "-q", "a.json"])
+            .is_err());
+        let args = [
+            "lhttpfs",
+            "mount",
+            "--uid",
+            "0",
+            "--dir-mode",
+            "0750",
+            "/mnt",
+            "a.json",
+        ];
+        let matches = command().get_matches_from(args);
+        let (_, matches) = matches.subcommand().unwrap();
+        let config = Defaults {
+            uid: Some(1000),
+            gid: Some(100),
+            ..Defaults::default()
+        };
+        let owned = inspect::owned(matches, &config);
+        assert_eq!(
+            (owned.uid, owned.gid, owned.mode),
+            (Some(0), Some(100), None)
+        );
+        assert_eq!(owned.dir_mode, Some(0o750));
+        assert!(command()
+            .try_get_matches_from(["lhttpfs", "tree", "--file-mode", "0999", "a.json"])
+            .is_err());
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(
+            parse_options("allow_other,,fsname=assets,max_read=131072"),
+            Ok(vec![
+                MountOption::AllowOther,
+                MountOption::FSName("assets".into()),
+                MountOption::CUSTOM("max_read=131072".into()),
+            ])
+        );
+        assert!(parse_options("allow_other,rw").is_err());
+        assert!(parse_options("writeback_cache").is_err());
+        let args = ["lhttpfs", "mount", "-f", "--daemonize", "/mnt", "a.json"];
+        assert!(command().try_get_matches_from(args).is_err());
+        let matches = command().get_matches_from(["lhttpfs", "verify", "a.json"]);
+        assert_eq!(matches.subcommand_name(), Some("check"));
+    }
+
+    #[test]
+    fn downloads() {
+        let downloads = |args: &[&str]| {
+            let args = [&["lhttpfs", "mount"], args, &["/mnt", "a.json"]].concat();
+            let matches = command().get_matches_from(args);
+            let (_, matches) = matches.subcommand().unwrap();
+            Downloads::new(matches, &Defaults::default()).unwrap()
+        };
+        // Refused before anything is asked of the host.
+        let error = downloads(&["--require-https"])
+            .read_layout("http://127.0.0.1:9/a.json")
+            .unwrap_err();
+        assert!(error.to_string().contains("--require-https refuses"));
+        let error = downloads(&["--deny-host", "127.0.0.1"])
+            .read_layout("https://127.0.0.1:9/a.json")
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LhttpfsError>(),
+            Some(LhttpfsError::Fetch { source, .. }) if source.is::<HostRefused>()
+        ));
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("300"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("300s"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("5d").is_err());
+        assert!(parse_interval("m").is_err());
+        assert!(parse_interval(&format!("{}h", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn verbosity() {
+        if std::env::var_os("RUST_LOG").is_some() {
+            return;
+        }
+        let level = |flags: &[&str]| {
+            let args = ["lhttpfs", "tree"].iter().chain(flags).chain(&["a.json"]);
+            log_filter(&command().get_matches_from(args)).max_level_hint()
+        };
+        assert_eq!(level(&[]), Some(LevelFilter::ERROR));
+        assert_eq!(level(&["-v"]), Some(LevelFilter::INFO));
+        assert_eq!(level(&["-vv"]), Some(LevelFilter::DEBUG));
+        assert_eq!(level(&["-vvvv"]), Some(LevelFilter::TRACE));
+        assert_eq!(level(&["-q"]), Some(LevelFilter::OFF));
+        // --log-level names what -v doesn't.
+        let named = ["-v", "--log-level", "lhttpfs=trace"];
+        assert_eq!(level(&named), Some(LevelFilter::TRACE));
+    }
+
+    #[test]
+    fn completions() {
+        let mut script = Vec::new();
+        clap_complete::generate(
+            clap_complete::Shell::Bash,
+            &mut command(),
+            "lhttpfs",
+            &mut script,
+        );
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("lhttpfs__subcmd__mount") && script.contains("--auto-decompress"));
+    }
+
+    #[test]
+    fn mounts() {
+        let args = [
+            "lhttpfs",
+            "mount",
+            "--mount",
+            "/a=a.json",
+            "--mount",
+            "/b=b.json,c.json",
+        ];
+        let matches = command().get_matches_from(args);
+        let (_, matches) = matches.subcommand().unwrap();
+        let mounts: Vec<_> = (matches.get_many::<(PathBuf, Vec<String>)>("mount").unwrap())
+            .cloned()
+            .collect();
+        assert_eq!(
+            mounts,
+            [
+                ("/a".into(), vec!["a.json".to_string()]),
+                (
+                    "/b".into(),
+                    vec!["b.json".to_string(), "c.json".to_string()]
+                )
+            ]
+        );
+        assert!(command()
+            .try_get_matches_from(["lhttpfs", "mount", "--mount", "/a"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(["lhttpfs", "mount", "/mnt"])
+            .is_err());
+    }
+
+    #[test]
+    fn threads() {
+        let args = ["lhttpfs", "mount", "/mnt", "a.json", "--read-threads", "3"];
+        let matches = command().get_matches_from(args);
+        let (_, matches) = matches.subcommand().unwrap();
+        assert_eq!(matches.get_one::<usize>("fetch-threads"), Some(&3));
+        assert_eq!(matches.get_one::<u16>("fuse-threads"), Some(&1));
+        let args = [
+            "lhttpfs",
+            "mount",
+            "/mnt",
+            "a.json",
+            "--max-connections",
+            "16",
+        ];
+        let matches = command().get_matches_from(args);
+        let (_, matches) = matches.subcommand().unwrap();
+        assert_eq!(matches.get_one::<usize>("fetch-threads"), Some(&16));
+        let args = ["lhttpfs", "mount", "/mnt", "a.json", "--fuse-threads", "0"];
+        assert!(command().try_get_matches_from(args).is_err());
+    }
+
+    #[test]
+    fn dry_run() {
+        let path =
+            std::env::temp_dir().join(format!("lhttpfs-dry-run-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{"name": "a.txt", "content": "hi"}]"#).unwrap();
+        let matches = command().get_matches_from([
+            "lhttpfs",
+            "mount",
+            "--dry-run",
+            "/mnt/a",
+            path.to_str().unwrap(),
+            "--mount",
+            &format!("/mnt/b={}", path.display()),
+        ]);
+        let (_, matches) = matches.subcommand().unwrap();
+        let filesystems = load_mounts(matches, &Default::default()).unwrap();
+        let mut out = Vec::new();
+        print_mounts(&filesystems, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let trees: Vec<_> = out.split("\n\n/mnt/").collect();
+        assert_eq!(trees.len(), 2);
+        assert!(trees[0].starts_with("/mnt/a\n") && trees[1].starts_with("b\n"));
+        assert!(
+            trees[1].contains("├── .lhttpfs-manifest.json") && trees[1].contains("└── a.txt (2 B)")
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hinted() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-hinted-{}", std::process::id()));
+        std::fs::write(&path, "{").unwrap();
+        let args = [
+            "lhttpfs",
+            "mount",
+            "--dry-run",
+            "/mnt/a",
+            path.to_str().unwrap(),
+        ];
+        let matches = command().get_matches_from(args);
+        let (_, matches) = matches.subcommand().unwrap();
+        let Err(e) = load_mounts(matches, &Default::default()).map(|_| ()) else {
+            panic!("Expected an error");
+        };
+        let e: &LhttpfsError = e.downcast_ref().unwrap();
+        assert!(matches!(e, LhttpfsError::Layout(_)), "{:?}", e);
+        assert_eq!(hint(e), None);
+        std::fs::remove_file(path).unwrap();
+
+        let status = HttpStatus {
+            url: "https://h/a".into(),
+            status: 403,
+        };
+        let error = LhttpfsError::fetch("https://h/a", Box::new(status));
+        assert!(hint(&error).unwrap().contains("`auth`"));
     }
 }
@@ -0,0 +1,313 @@
+//! `--metrics-listen`: counters and latency histograms of reads and
+//! fetches, served over HTTP in the Prometheus text format.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::Duration,
+};
+
+use tracing::warn;
+
+/// What every mount of the process adds to.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0, 30.0];
+
+struct Histogram {
+    /// Observations at or below each bound, not yet cumulative.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i].fetch_add(1, Relaxed);
+        }
+        self.count.fetch_add(1, Relaxed);
+        self.sum_micros
+            .fetch_add(latency.as_micros() as u64, Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut total = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            total += bucket.load(Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, total);
+        }
+        let count = self.count.load(Relaxed);
+        let sum = self.sum_micros.load(Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, count);
+    }
+}
+
+pub struct Metrics {
+    reads: AtomicU64,
+    read_errors: AtomicU64,
+    /// Bytes returned by reads served from the cache and by those that
+    /// had to fetch.
+    hit_bytes: AtomicU64,
+    miss_bytes: AtomicU64,
+    read_latency: Histogram,
+    fetches: AtomicU64,
+    fetch_errors: AtomicU64,
+    origin_bytes: AtomicU64,
+    fetch_latency: Histogram,
+    /// Fetches started and not yet counted.
+    in_flight: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            reads: AtomicU64::new(0),
+            read_errors: AtomicU64::new(0),
+            hit_bytes: AtomicU64::new(0),
+            miss_bytes: AtomicU64::new(0),
+            read_latency: Histogram::new(),
+            fetches: AtomicU64::new(0),
+            fetch_errors: AtomicU64::new(0),
+            origin_bytes: AtomicU64::new(0),
+            fetch_latency: Histogram::new(),
+            in_flight: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a read through the mount, `None` if it failed.
+    pub fn read(&self, bytes: Option<usize>, fetched: bool, latency: Duration) {
+        self.reads.fetch_add(1, Relaxed);
+        match (bytes, fetched) {
+            (None, _) => self.read_errors.fetch_add(1, Relaxed),
+            (Some(bytes), false) => self.hit_bytes.fetch_add(bytes as u64, Relaxed),
+            (Some(bytes), true) => self.miss_bytes.fetch_add(bytes as u64, Relaxed),
+        };
+        self.read_latency.observe(latency);
+    }
+
+    /// Counts a fetch from a backend as under way, until [`Metrics::fetch`]
+    /// counts how it went.
+    pub fn fetch_started(&self) {
+        self.in_flight.fetch_add(1, Relaxed);
+    }
+
+    /// Counts a fetch from a backend, `None` if it failed.
+    pub fn fetch(&self, bytes: Option<usize>, latency: Duration) {
+        let _ = (self.in_flight).fetch_update(Relaxed, Relaxed, |n| n.checked_sub(1));
+        self.fetches.fetch_add(1, Relaxed);
+        match bytes {
+            Some(bytes) => self.origin_bytes.fetch_add(bytes as u64, Relaxed),
+            None => self.fetch_errors.fetch_add(1, Relaxed),
+        };
+        self.fetch_latency.observe(latency);
+    }
+
+    /// The counters, for `.lhttpfs/stats`. The hit rate is of bytes read,
+    /// and `null` before anything was.
+    pub fn counters(&self) -> serde_json::Value {
+        let load = |value: &AtomicU64| value.load(Relaxed);
+        let (hit, miss) = (load(&self.hit_bytes), load(&self.miss_bytes));
+        let hit_rate = (hit + miss > 0).then(|| hit as f64 / (hit + miss) as f64);
+        serde_json::json!({
+            "reads": load(&self.reads),
+            "read_errors": load(&self.read_errors),
+            "read_bytes_hit": load(&self.hit_bytes),
+            "read_bytes_miss": load(&self.miss_bytes),
+            "fetches": load(&self.fetches),
+            "fetch_errors": load(&self.fetch_errors),
+            "origin_bytes": load(&self.origin_bytes),
+            "fetches_in_flight": load(&self.in_flight),
+            "cache_hit_rate": hit_rate,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "lhttpfs_reads_total",
+            "Reads through the mount.",
+            &[("", &self.reads)],
+        );
+        counter(
+            &mut out,
+            "lhttpfs_read_errors_total",
+            "Reads that failed.",
+            &[("", &self.read_errors)],
+        );
+        counter(
+            &mut out,
+            "lhttpfs_read_bytes_total",
+            "Bytes returned by reads, by whether they were served from the cache.",
+            &[
+                ("{cache=\"hit\"}", &self.hit_bytes),
+                ("{cache=\"miss\"}", &self.miss_bytes),
+            ],
+        );
+        self.read_latency.render(
+            &mut out,
+            "lhttpfs_read_duration_seconds",
+            "How long reads took.",
+        );
+        counter(
+            &mut out,
+            "lhttpfs_fetches_total",
+            "Fetches from backends.",
+            &[("", &self.fetches)],
+        );
+        counter(
+            &mut out,
+            "lhttpfs_fetch_errors_total",
+            "Fetches that failed.",
+            &[("", &self.fetch_errors)],
+        );
+        counter(
+            &mut out,
+            "lhttpfs_origin_bytes_total",
+            "Bytes fetched from backends.",
+            &[("", &self.origin_bytes)],
+        );
+        self.fetch_latency.render(
+            &mut out,
+            "lhttpfs_fetch_duration_seconds",
+            "How long fetches took.",
+        );
+        let _ = writeln!(
+            out,
+            "# HELP lhttpfs_fetches_in_flight Fetches under way.\n\
+             # TYPE lhttpfs_fetches_in_flight gauge\n\
+             lhttpfs_fetches_in_flight {}",
+            self.in_flight.load(Relaxed)
+        );
+        out
+    }
+}
+
+/// Writes a counter with a value for each set of labels.
+fn counter(out: &mut String, name: &str, help: &str, values: &[(&str, &AtomicU64)]) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for (labels, value) in values {
+        let _ = writeln!(out, "{}{} {}", name, labels, value.load(Relaxed));
+    }
+}
+
+/// Binds `addr` up front, so a taken port fails the mount before it is up.
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+/// Answers `GET /metrics` on `listener` from a thread of its own, one
+/// connection at a time.
+pub fn serve(listener: TcpListener) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(respond) {
+                warn!("Serving metrics failed: {}", e);
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = String::new();
+    let mut reader = BufReader::new(&stream);
+    reader.read_line(&mut request)?;
+    // The headers are read, up to the blank line, and ignored.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request.split(' ').nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" | "/" => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    use super::{bind, serve, Metrics};
+
+    #[test]
+    fn rendered() {
+        let metrics = Metrics::new();
+        metrics.read(Some(100), false, Duration::from_millis(2));
+        metrics.read(Some(50), true, Duration::from_millis(200));
+        metrics.read(None, false, Duration::from_millis(2));
+        metrics.fetch_started();
+        metrics.fetch_started();
+        metrics.fetch(Some(4096), Duration::from_millis(150));
+        let text = metrics.render();
+        for line in [
+            "lhttpfs_reads_total 3",
+            "lhttpfs_read_errors_total 1",
+            "lhttpfs_read_bytes_total{cache=\"hit\"} 100",
+            "lhttpfs_read_bytes_total{cache=\"miss\"} 50",
+            "lhttpfs_read_duration_seconds_bucket{le=\"0.001\"} 0",
+            "lhttpfs_read_duration_seconds_bucket{le=\"0.005\"} 2",
+            "lhttpfs_read_duration_seconds_bucket{le=\"0.25\"} 3",
+            "lhttpfs_read_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "lhttpfs_read_duration_seconds_sum 0.204",
+            "lhttpfs_origin_bytes_total 4096",
+            "lhttpfs_fetch_duration_seconds_count 1",
+            "lhttpfs_fetches_in_flight 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                text
+            );
+        }
+        let counters = metrics.counters();
+        assert_eq!(counters["fetches_in_flight"], 1);
+        assert_eq!(counters["cache_hit_rate"], 100.0 / 150.0);
+    }
+
+    #[test]
+    fn served() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(listener);
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE lhttpfs_reads_total counter"));
+        assert!(get("/other").starts_with("HTTP/1.1 404"));
+    }
+}
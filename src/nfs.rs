@@ -0,0 +1,829 @@
+//! `serve-nfs`: the tree a mount would show, served as an NFSv3 server
+//! instead (version 3 only, v4 clients have to be told `vers=3`), for where there is no `/dev/fuse` to mount with, as in some
+//! containers and on locked-down HPC nodes. The kernel's own NFS client
+//! mounts it, so nothing but the process needs to be allowed.
+//!
+//! There is no portmapper: MOUNT and NFS are answered on the same TCP
+//! port, which the client is told with `port=` and `mountport=`. Every
+//! caller is taken for who it says it is, so the server should only ever
+//! listen on localhost. The export is read-only: everything written to is
+//! answered with `NFS3ERR_ROFS`, the control files too unless
+//! `--allow-control-writes` is given.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::ffi::OsStrExt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use lhttpfs::fs::{FileAttr, FileType};
+use tracing::{debug, warn};
+
+use crate::{
+    fetch,
+    fs::{LazyHTTPFS, OpError},
+    inspect, prefetch,
+};
+
+const MOUNT_PROGRAM: u32 = 100005;
+const NFS_PROGRAM: u32 = 100003;
+
+// Procedures of MOUNT v3 and NFS v3, from RFC 1813.
+const MOUNTPROC3_MNT: u32 = 1;
+const MOUNTPROC3_DUMP: u32 = 2;
+const MOUNTPROC3_EXPORT: u32 = 5;
+const NFSPROC3_GETATTR: u32 = 1;
+const NFSPROC3_SETATTR: u32 = 2;
+const NFSPROC3_LOOKUP: u32 = 3;
+const NFSPROC3_ACCESS: u32 = 4;
+const NFSPROC3_READLINK: u32 = 5;
+const NFSPROC3_READ: u32 = 6;
+const NFSPROC3_WRITE: u32 = 7;
+const NFSPROC3_RENAME: u32 = 14;
+const NFSPROC3_LINK: u32 = 15;
+const NFSPROC3_READDIR: u32 = 16;
+const NFSPROC3_READDIRPLUS: u32 = 17;
+const NFSPROC3_FSSTAT: u32 = 18;
+const NFSPROC3_FSINFO: u32 = 19;
+const NFSPROC3_PATHCONF: u32 = 20;
+const NFSPROC3_COMMIT: u32 = 21;
+
+// Whether a call was accepted, before any results.
+const SUCCESS: u32 = 0;
+const PROG_UNAVAIL: u32 = 1;
+const PROG_MISMATCH: u32 = 2;
+const PROC_UNAVAIL: u32 = 3;
+const GARBAGE_ARGS: u32 = 4;
+
+const NFS3_OK: u32 = 0;
+const NFS3ERR_PERM: u32 = 1;
+const NFS3ERR_NOENT: u32 = 2;
+const NFS3ERR_IO: u32 = 5;
+const NFS3ERR_ACCES: u32 = 13;
+const NFS3ERR_NOTDIR: u32 = 20;
+const NFS3ERR_ISDIR: u32 = 21;
+const NFS3ERR_INVAL: u32 = 22;
+const NFS3ERR_ROFS: u32 = 30;
+const NFS3ERR_STALE: u32 = 70;
+const NFS3ERR_NOTSUPP: u32 = 10004;
+const NFS3ERR_TOOSMALL: u32 = 10005;
+const NFS3ERR_JUKEBOX: u32 = 10008;
+const MNT3ERR_NOENT: u32 = 2;
+
+const ACCESS_READ: u32 = 0x01;
+const ACCESS_LOOKUP: u32 = 0x02;
+const ACCESS_MODIFY: u32 = 0x04;
+const ACCESS_EXTEND: u32 = 0x08;
+const ACCESS_EXECUTE: u32 = 0x20;
+
+/// The most bytes a READ gets, and the longest call taken.
+const MAX_READ: u32 = 1 << 20;
+const MAX_RECORD: usize = 2 << 20;
+
+pub fn command() -> Command {
+    Command::new("serve-nfs")
+        .about(
+            "Serve layouts as a read-only, localhost NFSv3 server, for where FUSE isn't available",
+        )
+        .args(inspect::load_args())
+        .arg(prefetch::cache_dir_arg())
+        .arg(prefetch::cache_size_arg())
+        .arg(fetch::plugin::arg())
+        .arg(inspect::control_writes_arg())
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .value_parser(value_parser!(SocketAddr))
+                .default_value("127.0.0.1:2049")
+                .help("Address to serve NFS and MOUNT at"),
+        )
+}
+
+/// Serves `fs` until the process is stopped.
+pub fn run(fs: LazyHTTPFS, matches: &ArgMatches) -> crate::Result<()> {
+    let addr = matches.get_one::<SocketAddr>("listen").unwrap();
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("Couldn't listen on {}: {}", addr, e))?;
+    eprintln!(
+        "Serving NFSv3 at {}; mount with\n  mount -t nfs -o vers=3,proto=tcp,port={2},mountport={2},mountproto=tcp,nolock {1}:/ DIR",
+        addr,
+        addr.ip(),
+        addr.port()
+    );
+    serve(listener, fs, matches.get_flag("allow-control-writes"));
+    Ok(())
+}
+
+/// Answers every connection to `listener` from a thread of its own, the
+/// filesystem taking one call at a time. Only with `writable` can the
+/// control files be written to.
+fn serve(listener: TcpListener, fs: LazyHTTPFS, writable: bool) {
+    let server = Arc::new(Mutex::new(Server {
+        fs,
+        parents: HashMap::new(),
+        writable,
+    }));
+    for stream in listener.incoming() {
+        let server = server.clone();
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = connection(stream, &server) {
+                        warn!("Serving NFS failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Accepting an NFS connection failed: {}", e),
+        }
+    }
+}
+
+fn connection(stream: TcpStream, server: &Mutex<Server>) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    while let Some(record) = read_record(&mut reader)? {
+        let Some(reply) = call(server, &record) else {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not an RPC call"));
+        };
+        let last = 0x8000_0000 | reply.len() as u32;
+        writer.write_all(&last.to_be_bytes())?;
+        writer.write_all(&reply)?;
+    }
+    Ok(())
+}
+
+/// One call, put together from its fragments, or `None` once the client
+/// hung up.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut record = Vec::new();
+    loop {
+        let mut mark = [0; 4];
+        match reader.read_exact(&mut mark) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && record.is_empty() => return Ok(None),
+            result => result?,
+        }
+        let mark = u32::from_be_bytes(mark);
+        let length = (mark & 0x7fff_ffff) as usize;
+        if record.len() + length > MAX_RECORD {
+            return Err(io::Error::new(ErrorKind::InvalidData, "call too long"));
+        }
+        let start = record.len();
+        record.resize(start + length, 0);
+        reader.read_exact(&mut record[start..])?;
+        if mark & 0x8000_0000 != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+/// The reply to the RPC call `record`, or `None` if it isn't one.
+fn call(server: &Mutex<Server>, record: &[u8]) -> Option<Vec<u8>> {
+    let mut args = Args(record);
+    let xid = args.u32().ok()?;
+    if args.u32().ok()? != 0 {
+        return None;
+    }
+    let (rpcvers, program, version, procedure) = (
+        args.u32().ok()?,
+        args.u32().ok()?,
+        args.u32().ok()?,
+        args.u32().ok()?,
+    );
+    // The credentials and verifier, which are not checked.
+    for _ in 0..2 {
+        args.u32().ok()?;
+        args.opaque().ok()?;
+    }
+    let mut reply = Xdr::default();
+    reply.u32(xid);
+    reply.u32(1);
+    if rpcvers != 2 {
+        // MSG_DENIED with RPC_MISMATCH, taking only version 2.
+        for n in [1, 0, 2, 2] {
+            reply.u32(n);
+        }
+        return Some(reply.0);
+    }
+    reply.u32(0);
+    reply.u32(0);
+    reply.u32(0);
+    debug!("NFS call {} of program {} v{}", procedure, program, version);
+    let results = match (program, version) {
+        (NFS_PROGRAM, 3) => server.lock().unwrap().nfs(procedure, &mut args),
+        (MOUNT_PROGRAM, 3) => mount(procedure, &mut args),
+        (NFS_PROGRAM | MOUNT_PROGRAM, _) => {
+            for n in [PROG_MISMATCH, 3, 3] {
+                reply.u32(n);
+            }
+            return Some(reply.0);
+        }
+        _ => Err(PROG_UNAVAIL),
+    };
+    match results {
+        Ok(results) => {
+            reply.u32(SUCCESS);
+            reply.0.extend(results.0);
+        }
+        Err(stat) => reply.u32(stat),
+    }
+    Some(reply.0)
+}
+
+fn mount(procedure: u32, args: &mut Args) -> Result<Xdr, u32> {
+    let mut out = Xdr::default();
+    match procedure {
+        MOUNTPROC3_MNT => match args.opaque()? {
+            b"/" | b"" => {
+                out.u32(0);
+                out.handle(1);
+                // AUTH_UNIX, though nothing is checked.
+                out.u32(1);
+                out.u32(1);
+            }
+            _ => out.u32(MNT3ERR_NOENT),
+        },
+        MOUNTPROC3_DUMP => out.bool(false),
+        MOUNTPROC3_EXPORT => {
+            out.bool(true);
+            out.opaque(b"/");
+            out.bool(false);
+            out.bool(false);
+        }
+        // NULL, UMNT and UMNTALL, which have nothing to do.
+        0 | 3 | 4 => {}
+        _ => return Err(PROC_UNAVAIL),
+    }
+    Ok(out)
+}
+
+struct Server {
+    fs: LazyHTTPFS,
+    /// The directories that directories were found in, for `..`.
+    parents: HashMap<u64, u64>,
+    /// Whether the control files can be written to.
+    writable: bool,
+}
+
+fn status(error: OpError) -> u32 {
+    match error {
+        OpError::NotFound => NFS3ERR_NOENT,
+        OpError::NoAttribute => NFS3ERR_NOTSUPP,
+        OpError::Denied => NFS3ERR_ACCES,
+        OpError::ReadOnly => NFS3ERR_ROFS,
+        OpError::NotPermitted => NFS3ERR_PERM,
+        OpError::Failed => NFS3ERR_IO,
+        OpError::Refused => NFS3ERR_ACCES,
+        // Try again later.
+        OpError::TimedOut => NFS3ERR_JUKEBOX,
+        OpError::NotLink => NFS3ERR_INVAL,
+    }
+}
+
+impl Server {
+    fn attr(&self, ino: u64) -> Result<FileAttr, u32> {
+        let (attr, _) = self.fs.attributes(ino).map_err(|_| NFS3ERR_STALE)?;
+        Ok(attr)
+    }
+
+    /// The attributes of `ino` for a client about to read it, generated
+    /// files being generated anew as a mount does on open: a client asks
+    /// for them first, and reads no further than the size they give.
+    fn fresh(&mut self, ino: u64) -> Result<FileAttr, u32> {
+        self.fs.regenerate(ino);
+        self.attr(ino)
+    }
+
+    fn dir(&self, ino: u64) -> Result<FileAttr, u32> {
+        match self.attr(ino)? {
+            attr if attr.kind == FileType::Directory => Ok(attr),
+            _ => Err(NFS3ERR_NOTDIR),
+        }
+    }
+
+    fn lookup(&mut self, dir: u64, name: &[u8]) -> Result<FileAttr, u32> {
+        self.dir(dir)?;
+        let ino = match name {
+            b"." => dir,
+            b".." => *self.parents.get(&dir).unwrap_or(&dir),
+            _ => {
+                let (attr, _) = (self.fs.find(dir, OsStr::from_bytes(name))).map_err(status)?;
+                attr.ino
+            }
+        };
+        let attr = self.fresh(ino)?;
+        if attr.kind == FileType::Directory && ino != dir {
+            self.parents.entry(ino).or_insert(dir);
+        }
+        Ok(attr)
+    }
+
+    /// The entries of `dir` after `cookie`, with their attributes if
+    /// `plus`, as many as fit in `count` bytes.
+    fn readdir(&mut self, out: &mut Xdr, dir: u64, cookie: u64, count: u32, plus: bool) {
+        let attr = match self.dir(dir) {
+            Ok(attr) => attr,
+            Err(status) => {
+                out.u32(status);
+                return out.post_op(None);
+            }
+        };
+        let parent = *self.parents.get(&dir).unwrap_or(&dir);
+        let entries = match self.fs.list(dir) {
+            Ok(entries) => entries.into_iter().map(|(ino, name, _)| {
+                let ino = if name == ".." { parent } else { ino };
+                (ino, name.as_bytes().to_vec())
+            }),
+            Err(e) => {
+                out.u32(status(e));
+                return out.post_op(Some(&attr));
+            }
+        };
+        let entries: Vec<_> = entries.collect();
+        let mut list = Xdr::default();
+        let mut eof = true;
+        // What the rest of the reply takes, with room to spare.
+        let budget = (count as usize).saturating_sub(128);
+        for (i, (ino, name)) in entries.iter().enumerate().skip(cookie as usize) {
+            let mut entry = Xdr::default();
+            entry.bool(true);
+            entry.u64(*ino);
+            entry.opaque(name);
+            entry.u64(i as u64 + 1);
+            if plus {
+                let attr = self.attr(*ino).ok();
+                entry.post_op(attr.as_ref());
+                entry.bool(true);
+                entry.handle(*ino);
+                if attr.is_some_and(|a| a.kind == FileType::Directory) && name[0] != b'.' {
+                    self.parents.entry(*ino).or_insert(dir);
+                }
+            }
+            if list.0.len() + entry.0.len() > budget {
+                eof = false;
+                break;
+            }
+            list.0.extend(entry.0);
+        }
+        if list.0.is_empty() && !eof {
+            out.u32(NFS3ERR_TOOSMALL);
+            return out.post_op(Some(&attr));
+        }
+        out.u32(NFS3_OK);
+        out.post_op(Some(&attr));
+        out.0.extend([0; 8]);
+        out.0.extend(list.0);
+        out.bool(false);
+        out.bool(eof);
+    }
+
+    fn nfs(&mut self, procedure: u32, args: &mut Args) -> Result<Xdr, u32> {
+        let mut out = Xdr::default();
+        match procedure {
+            0 => {}
+            NFSPROC3_GETATTR => match self.fresh(args.handle()?) {
+                Ok(attr) => {
+                    out.u32(NFS3_OK);
+                    out.fattr(&attr);
+                }
+                Err(status) => out.u32(status),
+            },
+            NFSPROC3_SETATTR if !self.writable => {
+                out.u32(NFS3ERR_ROFS);
+                out.wcc(None);
+            }
+            NFSPROC3_SETATTR => {
+                // Only truncating a control file gets this far, on the way
+                // to writing to it, and changes nothing.
+                match self.fs.truncate(args.handle()?) {
+                    Ok((attr, _)) => {
+                        out.u32(NFS3_OK);
+                        out.wcc(Some(&attr));
+                    }
+                    Err(e) => {
+                        out.u32(status(e));
+                        out.wcc(None);
+                    }
+                }
+            }
+            NFSPROC3_LOOKUP => {
+                let dir = args.handle()?;
+                let name = args.opaque()?;
+                match self.lookup(dir, name) {
+                    Ok(attr) => {
+                        out.u32(NFS3_OK);
+                        out.handle(attr.ino);
+                        out.post_op(Some(&attr));
+                    }
+                    Err(status) => out.u32(status),
+                }
+                out.post_op(self.attr(dir).ok().as_ref());
+            }
+            NFSPROC3_ACCESS => {
+                let ino = args.handle()?;
+                let asked = args.u32()?;
+                match self.attr(ino) {
+                    Ok(attr) => {
+                        let mut allowed = ACCESS_READ | ACCESS_LOOKUP;
+                        if attr.kind == FileType::Directory || attr.perm & 0o111 != 0 {
+                            allowed |= ACCESS_EXECUTE;
+                        }
+                        if self.writable && self.fs.truncate(ino).is_ok() {
+                            allowed |= ACCESS_MODIFY | ACCESS_EXTEND;
+                        }
+                        out.u32(NFS3_OK);
+                        out.post_op(Some(&attr));
+                        out.u32(asked & allowed);
+                    }
+                    Err(status) => {
+                        out.u32(status);
+                        out.post_op(None);
+                    }
+                }
+            }
+            NFSPROC3_READLINK => {
+                let ino = args.handle()?;
+                let attr = self.attr(ino).ok();
+                match self.fs.link_target(ino) {
+                    Ok(target) => {
+                        out.u32(NFS3_OK);
+                        out.post_op(attr.as_ref());
+                        out.opaque(target);
+                    }
+                    Err(e) => {
+                        out.u32(status(e));
+                        out.post_op(attr.as_ref());
+                    }
+                }
+            }
+            NFSPROC3_READ => {
+                let ino = args.handle()?;
+                let offset = args.u64()?;
+                let count = args.u32()?.min(MAX_READ);
+                let attr = self.attr(ino);
+                let read = match &attr {
+                    Ok(attr) if attr.kind == FileType::Directory => Err(NFS3ERR_ISDIR),
+                    Ok(_) => (self.fs.read_file(ino, offset as i64, count)).map_err(status),
+                    Err(status) => Err(*status),
+                };
+                let attr = attr.ok();
+                match read {
+                    Ok(data) => {
+                        let size = attr.map_or(0, |attr| attr.size);
+                        out.u32(NFS3_OK);
+                        out.post_op(attr.as_ref());
+                        out.u32(data.len() as u32);
+                        out.bool(offset + data.len() as u64 >= size);
+                        out.opaque(&data);
+                    }
+                    Err(status) => {
+                        out.u32(status);
+                        out.post_op(attr.as_ref());
+                    }
+                }
+            }
+            NFSPROC3_WRITE if !self.writable => {
+                out.u32(NFS3ERR_ROFS);
+                out.wcc(None);
+            }
+            NFSPROC3_WRITE => {
+                let ino = args.handle()?;
+                let (_offset, _count, _stable) = (args.u64()?, args.u32()?, args.u32()?);
+                let data = args.opaque()?;
+                match self.fs.write_file(ino, data) {
+                    Ok(()) => {
+                        out.u32(NFS3_OK);
+                        out.wcc(self.attr(ino).ok().as_ref());
+                        out.u32(data.len() as u32);
+                        // FILE_SYNC, there being nothing to commit later.
+                        out.u32(2);
+                        out.0.extend([0; 8]);
+                    }
+                    Err(e) => {
+                        out.u32(status(e));
+                        out.wcc(None);
+                    }
+                }
+            }
+            // CREATE, MKDIR, SYMLINK, MKNOD, REMOVE and RMDIR, which all
+            // fail with the directory's wcc_data, and RENAME and LINK with
+            // two of those or an attribute and one.
+            8..=13 => {
+                out.u32(NFS3ERR_ROFS);
+                out.wcc(None);
+            }
+            NFSPROC3_RENAME => {
+                out.u32(NFS3ERR_ROFS);
+                out.wcc(None);
+                out.wcc(None);
+            }
+            NFSPROC3_LINK => {
+                out.u32(NFS3ERR_ROFS);
+                out.post_op(None);
+                out.wcc(None);
+            }
+            NFSPROC3_READDIR => {
+                let dir = args.handle()?;
+                let cookie = args.u64()?;
+                args.fixed(8)?;
+                let count = args.u32()?;
+                self.readdir(&mut out, dir, cookie, count, false);
+            }
+            NFSPROC3_READDIRPLUS => {
+                let dir = args.handle()?;
+                let cookie = args.u64()?;
+                args.fixed(8)?;
+                let (_dircount, count) = (args.u32()?, args.u32()?);
+                self.readdir(&mut out, dir, cookie, count, true);
+            }
+            NFSPROC3_FSSTAT | NFSPROC3_FSINFO | NFSPROC3_PATHCONF | NFSPROC3_COMMIT => {
+                let attr = match self.attr(args.handle()?) {
+                    Ok(attr) => attr,
+                    Err(status) => {
+                        out.u32(status);
+                        out.post_op(None);
+                        return Ok(out);
+                    }
+                };
+                out.u32(NFS3_OK);
+                match procedure {
+                    // Nothing is free, as a mount says too.
+                    NFSPROC3_FSSTAT => {
+                        out.post_op(Some(&attr));
+                        for n in [0; 6] {
+                            out.u64(n);
+                        }
+                        out.u32(0);
+                    }
+                    NFSPROC3_FSINFO => {
+                        out.post_op(Some(&attr));
+                        for n in [MAX_READ, MAX_READ, 4096, 65536, 65536, 4096, 65536] {
+                            out.u32(n);
+                        }
+                        out.u64(u64::MAX);
+                        out.u32(0);
+                        out.u32(1);
+                        // FSF_HOMOGENEOUS
+                        out.u32(0x08);
+                    }
+                    NFSPROC3_PATHCONF => {
+                        out.post_op(Some(&attr));
+                        out.u32(1);
+                        out.u32(255);
+                        for b in [true, true, false, true] {
+                            out.bool(b);
+                        }
+                    }
+                    _ => {
+                        out.wcc(Some(&attr));
+                        out.0.extend([0; 8]);
+                    }
+                }
+            }
+            _ => return Err(PROC_UNAVAIL),
+        }
+        Ok(out)
+    }
+}
+
+/// What is left of a call's arguments.
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn fixed(&mut self, length: usize) -> Result<&'a [u8], u32> {
+        if self.0.len() < length {
+            return Err(GARBAGE_ARGS);
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_be_bytes(self.fixed(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_be_bytes(self.fixed(8)?.try_into().unwrap()))
+    }
+
+    fn opaque(&mut self) -> Result<&'a [u8], u32> {
+        let length = self.u32()? as usize;
+        let bytes = self.fixed(length)?;
+        self.fixed(pad(length))?;
+        Ok(bytes)
+    }
+
+    /// The inode a file handle stands for, 0 being none.
+    fn handle(&mut self) -> Result<u64, u32> {
+        let handle = self.opaque()?;
+        Ok(handle.try_into().map_or(0, u64::from_be_bytes))
+    }
+}
+
+fn pad(length: usize) -> usize {
+    (4 - length % 4) % 4
+}
+
+/// An XDR encoded reply.
+#[derive(Default)]
+struct Xdr(Vec<u8>);
+
+impl Xdr {
+    fn u32(&mut self, n: u32) {
+        self.0.extend(n.to_be_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.0.extend(n.to_be_bytes());
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.u32(b as u32);
+    }
+
+    fn opaque(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend(bytes);
+        self.0.extend(&[0; 3][..pad(bytes.len())]);
+    }
+
+    fn handle(&mut self, ino: u64) {
+        self.opaque(&ino.to_be_bytes());
+    }
+
+    fn time(&mut self, time: SystemTime) {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u32(since.as_secs() as u32);
+        self.u32(since.subsec_nanos());
+    }
+
+    fn fattr(&mut self, attr: &FileAttr) {
+        self.u32(match attr.kind {
+            FileType::Directory => 2,
+            FileType::Symlink => 5,
+            _ => 1,
+        });
+        self.u32(attr.perm as u32);
+        self.u32(attr.nlink);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.u64(attr.size);
+        self.u64(attr.blocks * 512);
+        self.u64(0);
+        self.u64(0);
+        self.u64(attr.ino);
+        self.time(attr.atime);
+        self.time(attr.mtime);
+        self.time(attr.ctime);
+    }
+
+    fn post_op(&mut self, attr: Option<&FileAttr>) {
+        self.bool(attr.is_some());
+        if let Some(attr) = attr {
+            self.fattr(attr);
+        }
+    }
+
+    /// A wcc_data with the attributes after a change, and none from
+    /// before.
+    fn wcc(&mut self, after: Option<&FileAttr>) {
+        self.bool(false);
+        self.post_op(after);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{read_record, serve, Args, Xdr, MOUNT_PROGRAM, NFS3ERR_NOENT, NFS3ERR_ROFS};
+    use crate::{fs::LazyHTTPFS, layout};
+
+    /// Calls `procedure` of `program` v3, returning the results.
+    fn call(stream: &mut TcpStream, program: u32, procedure: u32, args: Xdr) -> Vec<u8> {
+        let mut call = Xdr::default();
+        for n in [7, 0, 2, program, 3, procedure, 0, 0, 0, 0] {
+            call.u32(n);
+        }
+        call.0.extend(args.0);
+        let mut record = (0x8000_0000 | call.0.len() as u32).to_be_bytes().to_vec();
+        record.extend(call.0);
+        stream.write_all(&record).unwrap();
+        let reply = read_record(stream).unwrap().unwrap();
+        let mut reply = Args(&reply);
+        // xid, REPLY, MSG_ACCEPTED, the verifier and SUCCESS.
+        for expected in [7, 1, 0, 0, 0, 0] {
+            assert_eq!(reply.u32().unwrap(), expected);
+        }
+        reply.0.to_vec()
+    }
+
+    fn with(f: impl FnOnce(&mut Xdr)) -> Xdr {
+        let mut xdr = Xdr::default();
+        f(&mut xdr);
+        xdr
+    }
+
+    #[test]
+    fn served() {
+        let layout = r#"[{"name": "d", "contents": [{"name": "a", "content": "abc"}]}]"#;
+        let mut fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        fs.add_control();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        std::thread::spawn(move || serve(listener, fs, false));
+
+        let mnt = call(&mut stream, MOUNT_PROGRAM, 1, with(|x| x.opaque(b"/")));
+        let mut mnt = Args(&mnt);
+        assert_eq!(mnt.u32(), Ok(0));
+        let root = mnt.handle().unwrap();
+
+        let lookup = |stream: &mut TcpStream, dir: u64, name: &[u8]| {
+            let results = call(
+                stream,
+                100003,
+                3,
+                with(|x| {
+                    x.handle(dir);
+                    x.opaque(name);
+                }),
+            );
+            let mut results = Args(&results);
+            match results.u32().unwrap() {
+                0 => Ok(results.handle().unwrap()),
+                status => Err(status),
+            }
+        };
+        let d = lookup(&mut stream, root, b"d").unwrap();
+        let a = lookup(&mut stream, d, b"a").unwrap();
+        assert_eq!(lookup(&mut stream, d, b".."), Ok(root));
+        assert_eq!(lookup(&mut stream, d, b"b"), Err(NFS3ERR_NOENT));
+
+        let args = with(|x| {
+            x.handle(a);
+            x.u64(1);
+            x.u32(10);
+        });
+        let read = call(&mut stream, 100003, 6, args);
+        let mut read = Args(&read);
+        assert_eq!(read.u32(), Ok(0));
+        assert_eq!(read.u32(), Ok(1));
+        read.fixed(84).unwrap();
+        assert_eq!(read.u32(), Ok(2));
+        assert_eq!(read.u32(), Ok(1));
+        assert_eq!(read.opaque(), Ok(&b"bc"[..]));
+
+        let args = with(|x| {
+            x.handle(d);
+            x.u64(0);
+            x.0.extend([0; 8]);
+            x.u32(4096);
+        });
+        let listing = call(&mut stream, 100003, 16, args);
+        let mut listing = Args(&listing);
+        assert_eq!(listing.u32(), Ok(0));
+        listing.fixed(4 + 84 + 8).unwrap();
+        let mut names = Vec::new();
+        while listing.u32() == Ok(1) {
+            listing.u64().unwrap();
+            names.push(listing.opaque().unwrap().to_vec());
+            listing.u64().unwrap();
+        }
+        assert_eq!(names, [&b"."[..], b"..", b"a"]);
+        assert_eq!(listing.u32(), Ok(1));
+
+        let args = with(|x| {
+            x.handle(a);
+            x.u64(0);
+            x.u32(1);
+            x.u32(2);
+            x.opaque(b"x");
+        });
+        let write = call(&mut stream, 100003, 7, args);
+        assert_eq!(Args(&write).u32(), Ok(NFS3ERR_ROFS));
+        // Nor can the control files be, without --allow-control-writes.
+        let control = lookup(&mut stream, root, b".lhttpfs").unwrap();
+        let flush = lookup(&mut stream, control, b"flush").unwrap();
+        let args = with(|x| {
+            x.handle(flush);
+            x.u64(0);
+            x.u32(1);
+            x.u32(2);
+            x.opaque(b"1");
+        });
+        let write = call(&mut stream, 100003, 7, args);
+        assert_eq!(Args(&write).u32(), Ok(NFS3ERR_ROFS));
+        let mut rest = [0; 1];
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(stream.read(&mut rest).unwrap(), 0);
+    }
+}
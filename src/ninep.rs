@@ -0,0 +1,650 @@
+//! `serve-9p`: the tree a mount would show, served with 9P2000.L, the
+//! protocol Linux mounts with `-t 9p`, over TCP or a Unix socket. A VM or
+//! WSL guest mounts it over the network, leaving the downloads and the
+//! cache on the host:
+//! `mount -t 9p -o trans=tcp,port=5640,version=9p2000.L 10.0.2.2 /mnt`.
+//!
+//! Every client is taken for who it says it is, so listen only where the
+//! guests can reach it. Nothing can be written to: the control files
+//! answer writes with `EPERM` unless `--allow-control-writes` is given,
+//! and everything else with `EROFS`.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::unix::{ffi::OsStrExt, net::UnixListener},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Arg, ArgMatches, Command};
+use lhttpfs::fs::{FileAttr, FileType};
+use tracing::{debug, warn};
+
+use crate::{
+    fetch,
+    fs::{LazyHTTPFS, OpError},
+    inspect, prefetch,
+};
+
+const VERSION: &str = "9P2000.L";
+/// The largest message taken or sent.
+const MAX_SIZE: u32 = 1 << 20;
+
+// Message types, each reply being one more than its request.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+/// Tlcreate, Tsymlink, Tmknod, Trename, Txattrcreate, Tlink, Tmkdir,
+/// Trenameat and Tunlinkat, which would all change the tree.
+const CHANGES: [u8; 9] = [14, 16, 18, 20, 32, 70, 72, 74, 76];
+
+// Linux's errno values, which 9P2000.L uses wherever the server runs.
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const ENODATA: u32 = 61;
+const EOPNOTSUPP: u32 = 95;
+const ETIMEDOUT: u32 = 110;
+
+pub fn command() -> Command {
+    Command::new("serve-9p")
+        .about("Serve layouts with 9P2000.L, for VMs and WSL to mount with -t 9p")
+        .args(inspect::load_args())
+        .arg(prefetch::cache_dir_arg())
+        .arg(prefetch::cache_size_arg())
+        .arg(fetch::plugin::arg())
+        .arg(inspect::control_writes_arg())
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDR|unix:PATH")
+                .default_value("127.0.0.1:5640")
+                .help("TCP address or Unix socket to serve 9P at"),
+        )
+}
+
+/// Serves `fs` until the process is stopped.
+pub fn run(mut fs: LazyHTTPFS, matches: &ArgMatches) -> crate::Result<()> {
+    let listen = matches.get_one::<String>("listen").unwrap();
+    if !matches.get_flag("allow-control-writes") {
+        fs.deny_control_writes();
+    }
+    let fs = Arc::new(Mutex::new(fs));
+    let error = |e| format!("Couldn't listen on {}: {}", listen, e);
+    match listen.strip_prefix("unix:") {
+        Some(path) => {
+            let listener = UnixListener::bind(path).map_err(error)?;
+            eprintln!("Serving 9P2000.L at {}", path);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => spawn(stream, &fs),
+                    Err(e) => warn!("Accepting a 9P connection failed: {}", e),
+                }
+            }
+        }
+        None => {
+            let listener = TcpListener::bind(listen).map_err(error)?;
+            eprintln!("Serving 9P2000.L at {}", listen);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => spawn(stream, &fs),
+                    Err(e) => warn!("Accepting a 9P connection failed: {}", e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answers `stream` from a thread of its own, the filesystem taking one
+/// message at a time.
+fn spawn(stream: impl Read + Write + Send + 'static, fs: &Arc<Mutex<LazyHTTPFS>>) {
+    let fs = fs.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = connection(stream, &fs) {
+            warn!("Serving 9P failed: {}", e);
+        }
+    });
+}
+
+fn connection(mut stream: impl Read + Write, fs: &Mutex<LazyHTTPFS>) -> io::Result<()> {
+    let mut session = Session {
+        msize: MAX_SIZE,
+        fids: HashMap::new(),
+    };
+    loop {
+        let mut size = [0; 4];
+        match stream.read_exact(&mut size) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let size = u32::from_le_bytes(size);
+        if !(7..=MAX_SIZE).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad message size",
+            ));
+        }
+        let mut message = vec![0; size as usize - 4];
+        stream.read_exact(&mut message)?;
+        let (kind, tag) = (message[0], [message[1], message[2]]);
+        let mut args = Args(&message[3..]);
+        let mut out = Msg::default();
+        let reply = match session.handle(&mut fs.lock().unwrap(), kind, &mut args, &mut out) {
+            Ok(()) => kind + 1,
+            Err(errno) => {
+                debug!("9P message {} failed with errno {}", kind, errno);
+                out.0.clear();
+                out.u32(errno);
+                RLERROR
+            }
+        };
+        let size = out.0.len() as u32 + 7;
+        let mut header = size.to_le_bytes().to_vec();
+        header.push(reply);
+        header.extend(tag);
+        stream.write_all(&header)?;
+        stream.write_all(&out.0)?;
+    }
+}
+
+/// What a fid stands for: the way from the root to a node, for `..`, or
+/// the value of an extended attribute to be read.
+#[derive(Clone)]
+struct Fid {
+    path: Vec<u64>,
+    xattr: Option<Vec<u8>>,
+}
+
+impl Fid {
+    fn ino(&self) -> u64 {
+        *self.path.last().unwrap()
+    }
+}
+
+struct Session {
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+fn errno(error: OpError) -> u32 {
+    match error {
+        OpError::NotFound => ENOENT,
+        OpError::NoAttribute => ENODATA,
+        OpError::Denied => EACCES,
+        OpError::ReadOnly => EROFS,
+        OpError::NotPermitted => EPERM,
+        OpError::Failed => EIO,
+        OpError::Refused => EACCES,
+        OpError::TimedOut => ETIMEDOUT,
+        OpError::NotLink => EINVAL,
+    }
+}
+
+impl Session {
+    fn fid(&self, args: &mut Args) -> Result<&Fid, u32> {
+        self.fids.get(&args.u32()?).ok_or(EBADF)
+    }
+
+    /// Answers the message `kind`, writing the reply's body to `out`, or
+    /// fails with an errno.
+    fn handle(
+        &mut self,
+        fs: &mut LazyHTTPFS,
+        kind: u8,
+        args: &mut Args,
+        out: &mut Msg,
+    ) -> Result<(), u32> {
+        match kind {
+            TVERSION => {
+                self.msize = args.u32()?.min(MAX_SIZE);
+                let version = args.string()?;
+                self.fids.clear();
+                out.u32(self.msize);
+                out.string(match version.starts_with(VERSION.as_bytes()) {
+                    true => VERSION.as_bytes(),
+                    false => b"unknown",
+                });
+            }
+            TATTACH => {
+                let fid = args.u32()?;
+                let root = attr(fs, lhttpfs::fs::ROOT_INO)?;
+                let path = vec![root.ino];
+                self.fids.insert(fid, Fid { path, xattr: None });
+                out.qid(&root);
+            }
+            TWALK => {
+                let mut path = self.fid(args)?.path.clone();
+                let newfid = args.u32()?;
+                let names = args.u16()?;
+                let mut qids = Vec::new();
+                for _ in 0..names {
+                    let name = args.string()?;
+                    let found = match name {
+                        b".." => {
+                            if path.len() > 1 {
+                                path.pop();
+                            }
+                            attr(fs, *path.last().unwrap())
+                        }
+                        _ => (fs.find(*path.last().unwrap(), OsStr::from_bytes(name)))
+                            .map(|(attr, _)| attr)
+                            .map_err(errno),
+                    };
+                    match found {
+                        Ok(attr) => {
+                            if name != b".." {
+                                path.push(attr.ino);
+                            }
+                            qids.push(attr);
+                        }
+                        // Only a walk that went nowhere fails; one that
+                        // stopped short says how far it got.
+                        Err(errno) if qids.is_empty() => return Err(errno),
+                        Err(_) => break,
+                    }
+                }
+                out.u16(qids.len() as u16);
+                for attr in &qids {
+                    out.qid(attr);
+                }
+                if qids.len() == names as usize {
+                    self.fids.insert(newfid, Fid { path, xattr: None });
+                }
+            }
+            TLOPEN => {
+                let ino = self.fid(args)?.ino();
+                let flags = args.u32()?;
+                let write = flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32;
+                fs.open_file(ino, write).map_err(errno)?;
+                out.qid(&attr(fs, ino)?);
+                out.u32(self.msize - 24);
+            }
+            TGETATTR => {
+                let ino = self.fid(args)?.ino();
+                // Generated files are generated anew, for their size.
+                fs.regenerate(ino);
+                let attr = attr(fs, ino)?;
+                let kind = match attr.kind {
+                    // S_IFDIR, S_IFLNK and S_IFREG, as Linux has them.
+                    FileType::Directory => 0o040000,
+                    FileType::Symlink => 0o120000,
+                    _ => 0o100000,
+                };
+                // P9_GETATTR_BASIC: everything up to the number of blocks.
+                out.u64(0x7ff);
+                out.qid(&attr);
+                out.u32(kind | attr.perm as u32);
+                out.u32(attr.uid);
+                out.u32(attr.gid);
+                for n in [
+                    attr.nlink as u64,
+                    0,
+                    attr.size,
+                    attr.blksize as u64,
+                    attr.blocks,
+                ] {
+                    out.u64(n);
+                }
+                for time in [attr.atime, attr.mtime, attr.ctime, attr.crtime] {
+                    out.time(time);
+                }
+                out.u64(0);
+                out.u64(0);
+            }
+            TSETATTR => {
+                // Only truncating a control file gets this far, on the way
+                // to writing to it, and changes nothing.
+                let ino = self.fid(args)?.ino();
+                fs.truncate(ino).map_err(errno)?;
+            }
+            TSTATFS => {
+                self.fid(args)?;
+                // V9FS_MAGIC, and nothing free, as a mount says too.
+                out.u32(0x01021997);
+                out.u32(4096);
+                for _ in 0..6 {
+                    out.u64(0);
+                }
+                out.u32(255);
+            }
+            TXATTRWALK => {
+                let fid = self.fid(args)?.clone();
+                let newfid = args.u32()?;
+                let name = args.string()?;
+                let value = match name {
+                    b"" => {
+                        let names = fs.xattr_names(fid.ino()).map_err(errno)?;
+                        let names = names.iter().map(|name| format!("{}\0", name));
+                        names.collect::<String>().into_bytes()
+                    }
+                    _ => (fs.xattr(fid.ino(), OsStr::from_bytes(name)))
+                        .map_err(errno)?
+                        .to_vec(),
+                };
+                out.u64(value.len() as u64);
+                let xattr = Some(value);
+                self.fids.insert(newfid, Fid { xattr, ..fid });
+            }
+            TREADDIR => {
+                let fid = self.fid(args)?;
+                let (ino, parent) = (fid.ino(), fid.path.iter().rev().nth(1).copied());
+                let offset = args.u64()?;
+                let count = args.u32()?.min(self.msize - 11) as usize;
+                let entries = fs.list(ino).map_err(errno)?;
+                let mut data = Msg::default();
+                for (i, (mut ino, name, kind)) in entries.into_iter().enumerate() {
+                    if (i as u64) < offset {
+                        continue;
+                    }
+                    if name == ".." {
+                        ino = parent.unwrap_or(ino);
+                    }
+                    let mut entry = Msg::default();
+                    entry.qid(&FileAttr {
+                        ino,
+                        kind,
+                        ..attr(fs, ino)?
+                    });
+                    entry.u64(i as u64 + 1);
+                    entry.0.push(match kind {
+                        FileType::Directory => libc::DT_DIR,
+                        FileType::Symlink => libc::DT_LNK,
+                        _ => libc::DT_REG,
+                    });
+                    entry.string(name.as_bytes());
+                    if data.0.len() + entry.0.len() > count {
+                        break;
+                    }
+                    data.0.extend(entry.0);
+                }
+                out.data(&data.0);
+            }
+            TREAD => {
+                let fid = self.fid(args)?.clone();
+                let offset = args.u64()?;
+                let count = args.u32()?.min(self.msize - 11);
+                match &fid.xattr {
+                    Some(value) => {
+                        let start = (offset as usize).min(value.len());
+                        let end = (start + count as usize).min(value.len());
+                        out.data(&value[start..end]);
+                    }
+                    None => {
+                        let data = fs.read_file(fid.ino(), offset as i64, count);
+                        out.data(&data.map_err(errno)?);
+                    }
+                }
+            }
+            TWRITE => {
+                let ino = self.fid(args)?.ino();
+                let _offset = args.u64()?;
+                let data = args.data()?;
+                fs.write_file(ino, data).map_err(errno)?;
+                out.u32(data.len() as u32);
+            }
+            TCLUNK => {
+                self.fids.remove(&args.u32()?).ok_or(EBADF)?;
+            }
+            TREMOVE => {
+                self.fids.remove(&args.u32()?).ok_or(EBADF)?;
+                return Err(EROFS);
+            }
+            // Every message is answered before the next is read, so there
+            // is never anything left to flush.
+            TFLUSH | TFSYNC => {}
+            TREADLINK => {
+                let ino = self.fid(args)?.ino();
+                out.string(fs.link_target(ino).map_err(errno)?);
+            }
+            kind if CHANGES.contains(&kind) => return Err(EROFS),
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(())
+    }
+}
+
+fn attr(fs: &LazyHTTPFS, ino: u64) -> Result<FileAttr, u32> {
+    let (attr, _) = fs.attributes(ino).map_err(errno)?;
+    Ok(attr)
+}
+
+/// What is left of a message.
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn fixed(&mut self, length: usize) -> Result<&'a [u8], u32> {
+        if self.0.len() < length {
+            return Err(EINVAL);
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.fixed(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.fixed(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.fixed(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], u32> {
+        let length = self.u16()? as usize;
+        self.fixed(length)
+    }
+
+    fn data(&mut self) -> Result<&'a [u8], u32> {
+        let length = self.u32()? as usize;
+        self.fixed(length)
+    }
+}
+
+/// The body of a reply.
+#[derive(Default)]
+struct Msg(Vec<u8>);
+
+impl Msg {
+    fn u16(&mut self, n: u16) {
+        self.0.extend(n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend(n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.0.extend(n.to_le_bytes());
+    }
+
+    fn string(&mut self, bytes: &[u8]) {
+        self.u16(bytes.len() as u16);
+        self.0.extend(bytes);
+    }
+
+    fn data(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend(bytes);
+    }
+
+    fn time(&mut self, time: SystemTime) {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u64(since.as_secs());
+        self.u64(since.subsec_nanos() as u64);
+    }
+
+    /// The qid of `attr`: its type, a version that never changes, and its
+    /// inode as the path.
+    fn qid(&mut self, attr: &FileAttr) {
+        self.0.push(match attr.kind {
+            FileType::Directory => 0x80,
+            FileType::Symlink => 0x02,
+            _ => 0,
+        });
+        self.u32(0);
+        self.u64(attr.ino);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        sync::Mutex,
+    };
+
+    use super::{connection, Args, Msg, EPERM, EROFS, RLERROR};
+    use crate::{fs::LazyHTTPFS, layout};
+
+    /// Sends the message `kind`, returning the reply's type and body.
+    fn send(stream: &mut UnixStream, kind: u8, body: Msg) -> (u8, Vec<u8>) {
+        let mut message = (body.0.len() as u32 + 7).to_le_bytes().to_vec();
+        message.push(kind);
+        message.extend([1, 0]);
+        message.extend(body.0);
+        stream.write_all(&message).unwrap();
+        let mut header = [0; 7];
+        stream.read_exact(&mut header).unwrap();
+        let mut reply = vec![0; u32::from_le_bytes(header[..4].try_into().unwrap()) as usize - 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(header[5..], [1, 0]);
+        (header[4], reply)
+    }
+
+    fn with(f: impl FnOnce(&mut Msg)) -> Msg {
+        let mut msg = Msg::default();
+        f(&mut msg);
+        msg
+    }
+
+    #[test]
+    fn served() {
+        let layout = r#"[{"name": "d", "contents": [
+            {"name": "a", "content": "abc", "content_type": "text/plain"}
+        ]}]"#;
+        let mut fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        fs.add_control();
+        fs.deny_control_writes();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || connection(server, &Mutex::new(fs)));
+
+        let (kind, reply) = send(
+            &mut client,
+            100,
+            with(|m| {
+                m.u32(8192);
+                m.string(b"9P2000.L");
+            }),
+        );
+        assert_eq!(kind, 101);
+        assert_eq!(Args(&reply[4..]).string(), Ok(&b"9P2000.L"[..]));
+        let attach = with(|m| {
+            m.u32(0);
+            m.u32(!0);
+            m.string(b"user");
+            m.string(b"");
+            m.u32(1000);
+        });
+        assert_eq!(send(&mut client, 104, attach).0, 105);
+
+        let walk = |names: &[&[u8]], newfid: u32| {
+            with(|m| {
+                m.u32(0);
+                m.u32(newfid);
+                m.u16(names.len() as u16);
+                for name in names {
+                    m.string(name);
+                }
+            })
+        };
+        let (kind, reply) = send(&mut client, 110, walk(&[b"d", b"a"], 1));
+        assert_eq!((kind, Args(&reply).u16()), (111, Ok(2)));
+        let (kind, reply) = send(&mut client, 110, walk(&[b"d", b"b"], 2));
+        assert_eq!((kind, Args(&reply).u16()), (111, Ok(1)));
+        assert_eq!(send(&mut client, 110, walk(&[b"e"], 2)).0, RLERROR);
+
+        let open = |fid: u32, flags: u32| {
+            with(|m| {
+                m.u32(fid);
+                m.u32(flags);
+            })
+        };
+        assert_eq!(send(&mut client, 12, open(1, 0)).0, 13);
+        let read = with(|m| {
+            m.u32(1);
+            m.u64(1);
+            m.u32(100);
+        });
+        let (kind, reply) = send(&mut client, 116, read);
+        assert_eq!((kind, Args(&reply).data()), (117, Ok(&b"bc"[..])));
+        let (kind, reply) = send(&mut client, 12, open(1, 1));
+        assert_eq!((kind, reply), (RLERROR, EROFS.to_le_bytes().to_vec()));
+        assert_eq!(
+            send(&mut client, 110, walk(&[b".lhttpfs", b"flush"], 3)).0,
+            111
+        );
+        let (kind, reply) = send(&mut client, 12, open(3, 1));
+        assert_eq!((kind, reply), (RLERROR, EPERM.to_le_bytes().to_vec()));
+        let write = with(|m| {
+            m.u32(3);
+            m.u64(0);
+            m.data(b"1");
+        });
+        let (kind, reply) = send(&mut client, 118, write);
+        assert_eq!((kind, reply), (RLERROR, EPERM.to_le_bytes().to_vec()));
+
+        let xattr = with(|m| {
+            m.u32(1);
+            m.u32(3);
+            m.string(b"user.mime_type");
+        });
+        let (kind, reply) = send(&mut client, 30, xattr);
+        assert_eq!((kind, Args(&reply).u64()), (31, Ok(10)));
+
+        assert_eq!(send(&mut client, 110, walk(&[b"d"], 4)).0, 111);
+        let readdir = with(|m| {
+            m.u32(4);
+            m.u64(0);
+            m.u32(4096);
+        });
+        let (kind, reply) = send(&mut client, 40, readdir);
+        assert_eq!(kind, 41);
+        let mut entries = Args(&reply);
+        let mut rest = Args(entries.data().unwrap());
+        let mut names = Vec::new();
+        while !rest.0.is_empty() {
+            rest.fixed(13 + 8 + 1).unwrap();
+            names.push(rest.string().unwrap());
+        }
+        assert_eq!(names, [&b"."[..], b"..", b"a"]);
+        assert_eq!(send(&mut client, 120, with(|m| m.u32(4))).0, 121);
+        assert_eq!(send(&mut client, 120, with(|m| m.u32(4))).0, RLERROR);
+    }
+}
@@ -0,0 +1,141 @@
+//! [`FsObserver`]: callbacks a program registers with
+//! [`Builder::observer`] to watch a mount at work, for metrics, prefetching
+//! or auditing of its own without changing the crate.
+//!
+//! [`Builder::observer`]: crate::fs::Builder::observer
+
+use std::{ffi::OsStr, sync::Arc, time::Duration};
+
+use crate::{access::Access, fetch::Request, LhttpfsError};
+
+/// What a mount tells its observers about. Every method does nothing unless
+/// overridden. They are called on the thread serving the request, some
+/// with the cache locked, so they should return quickly and hand anything
+/// slow to a thread of their own.
+pub trait FsObserver: Send + Sync {
+    /// `name` was looked up in directory `parent`, and found as `ino` if so.
+    fn on_lookup(&self, _parent: u64, _name: &OsStr, _ino: Option<u64>) {}
+
+    /// A read was answered, as it is written to the access log.
+    fn on_read(&self, _read: &Access) {}
+
+    /// `range` of `request.url`, or all of it, is about to be fetched.
+    fn on_fetch_start(&self, _request: &Request, _range: Option<(u64, u64)>) {}
+
+    /// The fetch [`FsObserver::on_fetch_start`] announced took `latency`,
+    /// and returned this many bytes or failed.
+    fn on_fetch_finish(
+        &self,
+        _request: &Request,
+        _range: Option<(u64, u64)>,
+        _result: Result<usize, &LhttpfsError>,
+        _latency: Duration,
+    ) {
+    }
+
+    /// Cached bytes were dropped, `reason` being `expired` or `flushed`.
+    fn on_cache_evict(&self, _key: &str, _reason: &str) {}
+}
+
+/// The observers of a mount, or of the fetchers or cache it is shared
+/// through, called in the order they were added.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn FsObserver>>);
+
+impl Observers {
+    pub fn add(&mut self, observer: Arc<dyn FsObserver>) {
+        self.0.push(observer);
+    }
+
+    /// Calls `f` with each observer.
+    pub fn each(&self, f: impl Fn(&dyn FsObserver)) {
+        self.0.iter().for_each(|observer| f(observer.as_ref()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ffi::OsStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::FsObserver;
+    use crate::{
+        access::Access, fetch::MemoryFetcher, fetch::Request, fs::LazyHTTPFS, layout, LhttpfsError,
+    };
+
+    /// What it was told, in order.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl FsObserver for Recorder {
+        fn on_lookup(&self, parent: u64, name: &OsStr, ino: Option<u64>) {
+            let event = format!("lookup {} {:?} {:?}", parent, name, ino);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_read(&self, read: &Access) {
+            let event = format!("read {} {} {}", read.inode, read.bytes, read.cache);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_fetch_start(&self, request: &Request, range: Option<(u64, u64)>) {
+            let event = format!("fetch {} {:?}", request.url, range);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_fetch_finish(
+            &self,
+            _request: &Request,
+            _range: Option<(u64, u64)>,
+            result: Result<usize, &LhttpfsError>,
+            _latency: Duration,
+        ) {
+            let event = format!("fetched {:?}", result.map_err(|_| ()));
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_cache_evict(&self, key: &str, reason: &str) {
+            let event = format!("evicted {} {}", key, reason);
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn observed() {
+        let recorder = Arc::new(Recorder::default());
+        let layout = r#"[{"name": "a", "url": "mem://a", "size": 4}]"#;
+        let mut fs = LazyHTTPFS::builder()
+            .cache_dir(None)
+            .fetcher(
+                "mem",
+                Arc::new(MemoryFetcher::new().with("mem://a", "abcd")),
+            )
+            .ttl(Duration::ZERO)
+            .observer(recorder.clone())
+            .build(layout::parse(layout.as_bytes()).unwrap())
+            .unwrap();
+        assert!(fs.find(1, "b".as_ref()).is_err());
+        let (a, _) = fs.find(1, "a".as_ref()).unwrap();
+        fs.open_file(a.ino, false).unwrap();
+        fs.read_file(a.ino, 0, 4).unwrap();
+        // Nothing stays fresh, so the second read fetches again.
+        fs.read_file(a.ino, 0, 4).unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "lookup 1 \"b\" None".to_string(),
+                format!("lookup 1 \"a\" Some({})", a.ino),
+                "fetch mem://a Some((0, 4))".into(),
+                "fetched Ok(4)".into(),
+                format!("read {} 4 miss", a.ino),
+                "evicted mem://a bytes=0-4 expired".into(),
+                "fetch mem://a Some((0, 4))".into(),
+                "fetched Ok(4)".into(),
+                format!("read {} 4 miss", a.ino),
+            ]
+        );
+    }
+}
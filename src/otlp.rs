@@ -0,0 +1,408 @@
+//! `--otlp-endpoint`: the `tracing` spans of FUSE operations and of the
+//! fetches they lead to, sent to an OpenTelemetry collector as OTLP/HTTP
+//! JSON, so a slow read can be told apart from a slow origin in a tracing
+//! backend. A span's `otel.kind` field is its OTLP kind, `server` for the
+//! requests the filesystem answers, `client` for those to an origin and
+//! `internal` otherwise, and its `otel.status_message`, once recorded,
+//! marks it failed.
+
+use std::{
+    sync::{Condvar, Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Arg;
+#[cfg(feature = "http")]
+use curl::easy::{Easy, List};
+use serde_json::{json, Value};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{filter_fn, Filtered},
+    layer::{self, Context},
+    registry::LookupSpan,
+    Layer as _,
+};
+
+/// How often spans are sent, and how many are kept waiting at most.
+const INTERVAL: Duration = Duration::from_secs(5);
+const MAX_QUEUED: usize = 10_000;
+
+pub fn arg() -> Arg {
+    Arg::new("otlp-endpoint")
+        .long("otlp-endpoint")
+        .value_name("URL")
+        .env("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .help("Send traces to the OpenTelemetry collector at URL, e.g. http://localhost:4318")
+}
+
+/// Where spans are sent, once [`init`] is called.
+static URL: OnceLock<String> = OnceLock::new();
+static QUEUE: Queue = Queue::new();
+
+/// Closed spans waiting to be sent.
+struct Queue {
+    spans: Mutex<Vec<Value>>,
+    wake: Condvar,
+}
+
+impl Queue {
+    const fn new() -> Queue {
+        Queue {
+            spans: Mutex::new(Vec::new()),
+            wake: Condvar::new(),
+        }
+    }
+
+    fn push(&self, span: Value) {
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() < MAX_QUEUED {
+            spans.push(span);
+        }
+        if spans.len() >= MAX_QUEUED / 2 {
+            self.wake.notify_one();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// What is known of an open span, kept in its extensions.
+#[derive(Debug)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    kind: Kind,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+    error: Option<String>,
+}
+
+/// Sends spans to the collector at `endpoint`, from a thread of its own.
+/// An endpoint without a path gets the usual `/v1/traces`. Until this is
+/// called, [`layer()`] records nothing.
+pub fn init(endpoint: &str) {
+    let url = match endpoint.trim_end_matches('/') {
+        url if url.ends_with("/v1/traces") => url.to_string(),
+        url => format!("{}/v1/traces", url),
+    };
+    if URL.set(url).is_ok() {
+        std::thread::spawn(|| loop {
+            let queue = QUEUE.spans.lock().unwrap();
+            let (mut queue, _) = QUEUE.wake.wait_timeout(queue, INTERVAL).unwrap();
+            let spans = std::mem::take(&mut *queue);
+            drop(queue);
+            send(spans);
+        });
+    }
+}
+
+/// Sends what hasn't been sent yet, before the process exits.
+pub fn flush() {
+    if URL.get().is_some() {
+        send(std::mem::take(&mut *QUEUE.spans.lock().unwrap()));
+    }
+}
+
+/// The layer queueing closed spans for [`init`]'s thread to send. Spans
+/// are only enabled for it once there is somewhere to send them.
+pub fn layer<S>() -> Filtered<Exporter, impl layer::Filter<S>, S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    Exporter(&QUEUE).with_filter(filter_fn(|metadata| {
+        metadata.is_span() && URL.get().is_some()
+    }))
+}
+
+/// See [`layer()`].
+pub struct Exporter(&'static Queue);
+
+impl<S> tracing_subscriber::Layer<S> for Exporter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some((data.trace_id, data.span_id))
+        });
+        let mut data = SpanData {
+            trace_id: parent.map_or_else(random, |(trace_id, _)| trace_id),
+            span_id: random(),
+            parent: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            kind: Kind::Internal,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(data);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let data = span.extensions_mut().remove::<SpanData>();
+        if let Some(data) = data {
+            self.0.push(end(data));
+        }
+    }
+}
+
+impl SpanData {
+    fn set(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            ("otel.kind", Value::String(kind)) => {
+                self.kind = match kind.as_str() {
+                    "server" => Kind::Server,
+                    "client" => Kind::Client,
+                    _ => Kind::Internal,
+                }
+            }
+            ("otel.status_message", Value::String(error)) => self.error = Some(error),
+            (key, value) => self.attributes.push((key.to_string(), value)),
+        }
+    }
+}
+
+impl Visit for SpanData {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field, format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, value.into());
+    }
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    // Never fails for so few bytes once the pool is initialized.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::getrandom(bytes.as_mut_ptr().cast(), N, 0)
+    };
+    #[cfg(not(target_os = "linux"))]
+    unsafe {
+        libc::getentropy(bytes.as_mut_ptr().cast(), N)
+    };
+    bytes
+}
+
+/// `span`, closed now, as OTLP JSON.
+fn end(span: SpanData) -> Value {
+    let nanos = |time: SystemTime| {
+        let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        nanos.as_nanos().to_string()
+    };
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+    let attributes = span.attributes.into_iter().map(|(key, value)| {
+        let value = match value {
+            Value::Bool(b) => json!({"boolValue": b}),
+            Value::Number(n) if n.is_i64() || n.is_u64() => json!({"intValue": n.to_string()}),
+            Value::Number(n) => json!({"doubleValue": n}),
+            Value::String(s) => json!({"stringValue": s}),
+            value => json!({"stringValue": value.to_string()}),
+        };
+        json!({"key": key, "value": value})
+    });
+    let status = match &span.error {
+        Some(error) => json!({"code": 2, "message": error}),
+        None => json!({"code": 1}),
+    };
+    let mut json = json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(SystemTime::now()),
+        "attributes": attributes.collect::<Vec<_>>(),
+        "status": status,
+    });
+    if let Some(parent) = span.parent {
+        json["parentSpanId"] = hex(&parent).into();
+    }
+    json
+}
+
+/// The request body for `spans`.
+fn payload(spans: Vec<Value>) -> Value {
+    json!({"resourceSpans": [{
+        "resource": {"attributes": [
+            {"key": "service.name", "value": {"stringValue": "lhttpfs"}},
+        ]},
+        "scopeSpans": [{
+            "scope": {"name": "lhttpfs", "version": env!("CARGO_PKG_VERSION")},
+            "spans": spans,
+        }],
+    }]})
+}
+
+fn send(spans: Vec<Value>) {
+    let Some(url) = URL.get() else {
+        return;
+    };
+    if spans.is_empty() {
+        return;
+    }
+    let count = spans.len();
+    if let Err(e) = post(url, &payload(spans).to_string()) {
+        warn!("Sending {} spans to {} failed: {}", count, url, e);
+    }
+}
+
+#[cfg(feature = "http")]
+fn post(url: &str, body: &str) -> crate::Result<()> {
+    let mut curl = Easy::new();
+    curl.url(url)?;
+    curl.post(true)?;
+    curl.post_fields_copy(body.as_bytes())?;
+    let mut headers = List::new();
+    headers.append("Content-Type: application/json")?;
+    curl.http_headers(headers)?;
+    curl.timeout(Duration::from_secs(10))?;
+    crate::fetch::perform(curl)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+fn post(_url: &str, _body: &str) -> crate::Result<()> {
+    Err(Box::new(crate::NotBuilt("http")))
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "http")]
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    use tracing::{field::Empty, info_span, Span};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[cfg(feature = "http")]
+    use super::post;
+    use super::{payload, Exporter, Queue};
+
+    #[test]
+    fn nested() {
+        let queue = Box::leak(Box::new(Queue::new()));
+        let subscriber = tracing_subscriber::registry().with(Exporter(queue));
+        tracing::subscriber::with_default(subscriber, || {
+            let read = info_span!(
+                "read",
+                otel.kind = "server",
+                lhttpfs.inode = 5,
+                lhttpfs.retries = Empty
+            )
+            .entered();
+            let fetch = info_span!(
+                "fetch",
+                otel.kind = "client",
+                url.full = "https://example.com/a",
+                otel.status_message = Empty
+            )
+            .entered();
+            Span::current().record("otel.status_message", "timed out");
+            drop(fetch);
+            read.record("lhttpfs.retries", 1);
+        });
+        let spans = std::mem::take(&mut *queue.spans.lock().unwrap());
+        let [fetch, read] = &spans[..] else {
+            panic!("Expected two spans, got {:?}", spans);
+        };
+        assert_eq!(fetch["name"], "fetch");
+        assert_eq!(fetch["traceId"], read["traceId"]);
+        assert_eq!(fetch["parentSpanId"], read["spanId"]);
+        assert!(read.get("parentSpanId").is_none());
+        assert_eq!(fetch["kind"], 3);
+        assert_eq!(fetch["status"]["code"], 2);
+        assert_eq!(fetch["status"]["message"], "timed out");
+        assert_eq!(read["attributes"][0]["value"]["intValue"], "5");
+        assert_eq!(read["attributes"][1]["key"], "lhttpfs.retries");
+        let payload = payload(vec![read.clone()]);
+        let spans = &payload["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["name"], "read");
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn posted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let (mut line, mut length) = (String::new(), 0);
+            reader.read_line(&mut line).unwrap();
+            let request = line.clone();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+                .unwrap();
+            (request, String::from_utf8(body).unwrap())
+        });
+        post(&url, r#"{"resourceSpans": []}"#).unwrap();
+        let (request, body) = collector.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces "));
+        assert_eq!(body, r#"{"resourceSpans": []}"#);
+    }
+}
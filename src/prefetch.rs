@@ -0,0 +1,198 @@
+//! `prefetch` and `cache`: fill the cache directory ahead of mounting, and
+//! look after what's in it.
+
+use std::{cmp::Reverse, io::Write, path::PathBuf};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+
+use crate::{
+    cache::Cache,
+    fetch,
+    fs::LazyHTTPFS,
+    inspect::{self, human},
+    layout::Priority,
+    Result,
+};
+
+pub fn cache_dir_arg() -> Arg {
+    Arg::new("cache-dir")
+        .long("cache-dir")
+        .value_name("DIR")
+        .value_parser(value_parser!(PathBuf))
+        .help("Keep \"disk\" cached files in DIR [default: ~/.cache/lhttpfs]")
+}
+
+pub fn cache_size_arg() -> Arg {
+    Arg::new("cache-size")
+        .long("cache-size")
+        .value_name("SIZE")
+        .value_parser(size)
+        .help("Evict the least recently used cached files once the cache directory, or memory, holds SIZE, such as 10G or 512M")
+}
+
+/// A size in bytes, or in KiB, MiB, GiB or TiB with a `K`, `M`, `G` or `T`
+/// after it.
+pub fn size(value: &str) -> std::result::Result<u64, String> {
+    let value = value
+        .trim()
+        .trim_end_matches(['B', 'b'])
+        .trim_end_matches(['i']);
+    let (digits, shift) = match value.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&value[..at], 10),
+        Some((at, 'M' | 'm')) => (&value[..at], 20),
+        Some((at, 'G' | 'g')) => (&value[..at], 30),
+        Some((at, 'T' | 't')) => (&value[..at], 40),
+        _ => (value, 0),
+    };
+    let bytes = (digits.trim().parse::<u64>()).map_err(|e| format!("Not a size: {}", e))?;
+    bytes
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "Too big a size".to_string())
+}
+
+/// The directory given with `--cache-dir`, or [`Cache::default_dir`].
+fn cache_dir(matches: &ArgMatches) -> Option<PathBuf> {
+    (matches.get_one::<PathBuf>("cache-dir").cloned()).or_else(Cache::default_dir)
+}
+
+pub fn command() -> Command {
+    Command::new("prefetch")
+        .about("Download every \"disk\" cached file of a layout into the cache directory")
+        .args(inspect::load_args())
+        .arg(cache_dir_arg())
+        .arg(cache_size_arg())
+        .arg(fetch::plugin::arg())
+}
+
+/// Prefetches each file in turn, those of a higher priority first,
+/// printing them as they are done. Files only cached in memory are counted
+/// but left alone, the process being about to exit, as are those of a
+/// `low` priority, which are only fetched when read.
+pub fn run(fs: &mut LazyHTTPFS, out: &mut impl Write) -> Result<()> {
+    let (mut files, mut total, mut skipped, mut low) = (0, 0, 0, 0);
+    let mut entries = fs.walk();
+    entries.retain(|entry| !entry.is_dir());
+    entries.sort_by_key(|entry| Reverse(fs.priority(entry.attr.ino)));
+    for entry in entries {
+        if fs.priority(entry.attr.ino) == Some(Priority::Low) {
+            low += 1;
+            continue;
+        }
+        let path = entry
+            .path
+            .strip_prefix("/")
+            .unwrap_or(&entry.path)
+            .display();
+        let prefetched = fs.prefetch(entry.attr.ino);
+        match prefetched.map_err(|e| format!("Prefetching {} failed: {}", path, e))? {
+            Some(size) => {
+                writeln!(out, "{}\t{}", human(size), path)?;
+                files += 1;
+                total += size;
+            }
+            None => skipped += 1,
+        }
+    }
+    writeln!(out, "\n{} files, {}", files, human(total))?;
+    if skipped > 0 {
+        writeln!(
+            out,
+            "{} files skipped, not having \"cache\": \"disk\"",
+            skipped
+        )?;
+    }
+    if low > 0 {
+        writeln!(out, "{} files skipped, having \"priority\": \"low\"", low)?;
+    }
+    Ok(())
+}
+
+pub fn cache_command() -> Command {
+    Command::new("cache")
+        .about("Look after the cache directory that \"disk\" cached files are kept in")
+        .subcommand_required(true)
+        .arg(cache_dir_arg())
+        .subcommand(Command::new("path").about("Print where the cache directory is"))
+        .subcommand(Command::new("size").about("Print how much is cached"))
+        .subcommand(Command::new("clear").about("Remove everything from the cache"))
+}
+
+pub fn run_cache(matches: &ArgMatches, out: &mut impl Write) -> Result<()> {
+    let dir = cache_dir(matches).ok_or("There is no cache directory without $HOME")?;
+    let mut cache = Cache::new(Some(dir.clone()));
+    match matches.subcommand_name() {
+        Some("path") => writeln!(out, "{}", dir.display())?,
+        Some("size") => {
+            let (entries, bytes) = cache.usage()?;
+            writeln!(out, "{} entries, {}", entries, human(bytes))?;
+        }
+        _ => writeln!(out, "Freed {}", human(cache.clear()?))?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{fetch::MemoryFetcher, fs::LazyHTTPFS, layout};
+
+    use super::{cache_command, run, run_cache, size};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(size("512"), Ok(512));
+        assert_eq!(size("4K"), Ok(4 << 10));
+        assert_eq!(size("10G"), Ok(10 << 30));
+        assert!(size("1.5G").is_err());
+        assert_eq!(size("2MiB"), Ok(2 << 20));
+        assert!(size("big").is_err());
+        assert!(size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn prefetched() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-prefetch-{}", std::process::id()));
+        let json = r#"[
+            {"name": "d", "contents": [
+                {"name": "a.txt", "url": "mem://a.txt", "size": 3, "cache": "disk"},
+                {"name": "b.txt", "content": "b"}
+            ]},
+            {"name": "e", "defaults": {"cache": "disk"}, "contents": [
+                {"name": "config.json", "url": "mem://c", "size": 2, "priority": "high"},
+                {"name": "blob.bin", "url": "mem://blob", "size": 1, "priority": "low"}
+            ]}
+        ]"#;
+        let mut fs = LazyHTTPFS::builder()
+            .cache_dir(Some(dir.clone()))
+            .fetcher(
+                "mem",
+                Arc::new(
+                    MemoryFetcher::new()
+                        .with("mem://a.txt", b"abc")
+                        .with("mem://c", b"{}"),
+                ),
+            )
+            .build(layout::parse(json.as_bytes()).unwrap())
+            .unwrap();
+        let mut out = Vec::new();
+        run(&mut fs, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2 B\te/config.json\n3 B\td/a.txt\n\n2 files, 5 B\n\
+             1 files skipped, not having \"cache\": \"disk\"\n\
+             1 files skipped, having \"priority\": \"low\"\n"
+        );
+
+        let cache = |command: &str| {
+            let dir = dir.to_str().unwrap();
+            let matches = cache_command().get_matches_from(["cache", "--cache-dir", dir, command]);
+            let mut out = Vec::new();
+            run_cache(&matches, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(cache("size"), "2 entries, 5 B\n");
+        assert_eq!(cache("clear"), "Freed 5 B\n");
+        assert_eq!(cache("size"), "0 entries, 0 B\n");
+    }
+}
@@ -0,0 +1,224 @@
+//! Why a mount failed, in terms of what to do about it. libfuse mounts
+//! with mount(2) where the process may, as root or as the root of a user
+//! namespace, and through the setuid `fusermount3` otherwise, so a
+//! failure mostly comes down to the device, that helper or the mount
+//! point, which rootless containers and shared login nodes each get
+//! wrong in their own way.
+
+use std::{
+    ffi::CString,
+    fs,
+    io::{self, ErrorKind},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use lhttpfs::LhttpfsError;
+
+/// What the system allows the process, as far as mounting goes.
+#[derive(Debug)]
+struct Environment {
+    root: bool,
+    /// Why `/dev/fuse` couldn't be opened, if it couldn't.
+    dev_fuse: Option<ErrorKind>,
+    /// The `fusermount3` (or `fusermount`) on `$PATH`, and whether it is
+    /// setuid root.
+    fusermount: Option<(PathBuf, bool)>,
+    /// Whether the process is in a user namespace other than the first.
+    user_namespace: bool,
+    /// Whether `/etc/fuse.conf` lets users other than root use
+    /// `allow_other` and `allow_root`.
+    user_allow_other: bool,
+    /// Why the mount point can't be mounted on, if anything is wrong with
+    /// it.
+    mountpoint: Option<String>,
+}
+
+impl Environment {
+    fn probe(mountpoint: &Path) -> Environment {
+        let root = unsafe { libc::geteuid() } == 0;
+        let dev_fuse = (fs::OpenOptions::new().read(true).write(true))
+            .open("/dev/fuse")
+            .err()
+            .map(|e| e.kind());
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let fusermount = ["fusermount3", "fusermount"].into_iter().find_map(|name| {
+            std::env::split_paths(&path).find_map(|dir| {
+                let path = dir.join(name);
+                let metadata = fs::metadata(&path).ok()?;
+                let setuid = metadata.uid() == 0 && metadata.mode() & 0o4000 != 0;
+                Some((path, setuid))
+            })
+        });
+        // The first namespace maps every id to itself.
+        let uid_map = fs::read_to_string("/proc/self/uid_map").unwrap_or_default();
+        let identity = ["0", "0", "4294967295"];
+        let user_namespace = !uid_map.trim().is_empty() && uid_map.split_whitespace().ne(identity);
+        let fuse_conf = fs::read_to_string("/etc/fuse.conf").unwrap_or_default();
+        let user_allow_other = fuse_conf.lines().any(|l| l.trim() == "user_allow_other");
+        let mountpoint = match fs::metadata(mountpoint) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Some("doesn't exist".into()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => Some(
+                "is still mounted by an lhttpfs that is gone; unmount it with `fusermount3 -u` first"
+                    .into(),
+            ),
+            Err(e) => Some(e.to_string()),
+            Ok(metadata) if !metadata.is_dir() => Some("isn't a directory".into()),
+            Ok(_) if !root && !writable(mountpoint) => Some(
+                "can't be written to, which fusermount3 wants of the directories it mounts on"
+                    .into(),
+            ),
+            Ok(_) => None,
+        };
+        Environment {
+            root,
+            dev_fuse,
+            fusermount,
+            user_namespace,
+            user_allow_other,
+            mountpoint,
+        }
+    }
+
+    /// What to do about mounting at `mountpoint` failing, most likely
+    /// first.
+    fn hints(&self, mountpoint: &Path, allow_root: bool) -> Vec<String> {
+        let mut hints = Vec::new();
+        let alternatives =
+            "`lhttpfs serve-nfs`, `serve-9p` or `serve-http` serve the tree without FUSE";
+        match self.dev_fuse {
+            Some(ErrorKind::NotFound) => hints.push(format!(
+                "/dev/fuse doesn't exist: load the fuse module, or give the container the \
+                 device (`docker run --device /dev/fuse`). Otherwise {}.",
+                alternatives
+            )),
+            Some(ErrorKind::PermissionDenied) => hints.push(format!(
+                "/dev/fuse can't be opened: join the group that owns it, or allow the device \
+                 in the container's device cgroup. Otherwise {}.",
+                alternatives
+            )),
+            _ => {}
+        }
+        if let Some(problem) = &self.mountpoint {
+            hints.push(format!("{} {}.", mountpoint.display(), problem));
+        }
+        if !self.root {
+            match &self.fusermount {
+                None if !self.user_namespace => hints.push(
+                    "Only root can mount without fusermount3, which isn't on $PATH: \
+                     install fuse3."
+                        .into(),
+                ),
+                Some((path, false)) if !self.user_namespace => hints.push(format!(
+                    "{} isn't setuid root, so it can't mount for anyone else: `chmod u+s` \
+                     it, or mount as the root of a user namespace (`unshare -rm`).",
+                    path.display()
+                )),
+                _ if self.user_namespace => hints.push(
+                    "In a user namespace, mounting takes its root: run as uid 0 in it \
+                     (`unshare -rm`, `podman unshare`)."
+                        .into(),
+                ),
+                _ => {}
+            }
+            if allow_root && !self.user_allow_other {
+                hints.push(
+                    "--allow-root takes `user_allow_other` in /etc/fuse.conf when not run \
+                     as root."
+                        .into(),
+                );
+            }
+        } else if self.user_namespace {
+            hints.push(
+                "FUSE in a user namespace needs Linux 4.18 or later, and a mount namespace \
+                 the user namespace owns (`unshare -rm`, or the runtime's own user namespace)."
+                    .into(),
+            );
+        }
+        hints
+    }
+}
+
+fn writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// `error`, from mounting at `mountpoint`, with what to do about it.
+pub fn explain(error: io::Error, mountpoint: &Path, allow_root: bool) -> LhttpfsError {
+    let hints = match cfg!(target_os = "linux") {
+        true => Environment::probe(mountpoint).hints(mountpoint, allow_root),
+        false => Vec::new(),
+    };
+    LhttpfsError::Mount {
+        mountpoint: mountpoint.to_path_buf(),
+        source: error,
+        hints,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::ErrorKind, path::Path};
+
+    use super::Environment;
+
+    fn rootless() -> Environment {
+        Environment {
+            root: false,
+            dev_fuse: None,
+            fusermount: None,
+            user_namespace: false,
+            user_allow_other: false,
+            mountpoint: None,
+        }
+    }
+
+    #[test]
+    fn hinted() {
+        let mnt = Path::new("/mnt/a");
+        let hints = rootless().hints(mnt, false);
+        assert_eq!(hints.len(), 1);
+        assert!(hints[0].contains("install fuse3"));
+
+        let setuid = Environment {
+            fusermount: Some(("/usr/bin/fusermount3".into(), true)),
+            ..rootless()
+        };
+        assert!(setuid.hints(mnt, false).is_empty());
+        assert!(setuid.hints(mnt, true)[0].contains("user_allow_other"));
+        let plain = Environment {
+            fusermount: Some(("/usr/bin/fusermount3".into(), false)),
+            ..rootless()
+        };
+        assert!(plain.hints(mnt, false)[0].starts_with("/usr/bin/fusermount3 isn't setuid"));
+
+        let container = Environment {
+            root: true,
+            dev_fuse: Some(ErrorKind::NotFound),
+            mountpoint: Some("doesn't exist".into()),
+            ..rootless()
+        };
+        let hints = container.hints(mnt, true);
+        assert!(hints[0].contains("--device /dev/fuse"));
+        assert_eq!(hints[1], "/mnt/a doesn't exist.");
+        assert_eq!(hints.len(), 2);
+
+        let namespaced = Environment {
+            user_namespace: true,
+            ..rootless()
+        };
+        assert!(namespaced.hints(mnt, false)[0].contains("unshare -rm"));
+    }
+
+    #[test]
+    fn probed() {
+        let dir = std::env::temp_dir();
+        let environment = Environment::probe(&dir);
+        assert_eq!(environment.mountpoint, None);
+        let missing = Environment::probe(&dir.join("lhttpfs-no-such-dir"));
+        assert_eq!(missing.mountpoint.as_deref(), Some("doesn't exist"));
+    }
+}
@@ -0,0 +1,576 @@
+//! `--sandbox`: once mounted, the process gives up what serving the mount
+//! doesn't need, so that a layout from someone else, or a server that
+//! answers one of its requests with something malicious, can get at
+//! less. Landlock limits the files it can open to the system libraries
+//! and configuration that fetching takes, the layouts, the cache and
+//! whatever `--sandbox-allow` adds, and a seccomp filter refuses the
+//! system calls that administer the machine rather than serve a file.
+//! [`Unmounter`] keeps unmounting working, which the sandbox rules out
+//! in it.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code, unused_imports))]
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches};
+use tracing::{info, warn};
+
+use crate::Result;
+
+pub fn args() -> Vec<Arg> {
+    vec![
+        Arg::new("sandbox")
+            .long("sandbox")
+            .action(ArgAction::SetTrue)
+            .help("Once mounted, restrict the files and system calls the process can get at"),
+        Arg::new("sandbox-allow")
+            .long("sandbox-allow")
+            .value_name("PATH")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(PathBuf))
+            .help("Let the sandbox read PATH too, e.g. the directory file:// URLs point into"),
+    ]
+}
+
+/// What may be done beneath a path, as Landlock's `LANDLOCK_ACCESS_FS_*`.
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const REFER: u64 = 1 << 13;
+const TRUNCATE: u64 = 1 << 14;
+/// Everything the first version of Landlock knows of.
+const ALL_V1: u64 = (1 << 13) - 1;
+/// What applies to a file rather than a directory.
+const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+const READ: u64 = READ_FILE | READ_DIR;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Where fetching reads from: the libraries curl and the resolver load and
+/// the configuration they read.
+const SYSTEM: &[&str] = &[
+    "/etc/ca-certificates",
+    "/etc/crypto-policies",
+    "/etc/gai.conf",
+    "/etc/group",
+    "/etc/host.conf",
+    "/etc/hosts",
+    "/etc/ld.so.cache",
+    "/etc/localtime",
+    "/etc/nsswitch.conf",
+    "/etc/passwd",
+    "/etc/pki",
+    "/etc/protocols",
+    "/etc/resolv.conf",
+    "/etc/services",
+    "/etc/ssl",
+    "/dev/random",
+    "/dev/urandom",
+    "/proc/self",
+    "/sys/devices/system/cpu",
+    "/sys/fs/cgroup",
+];
+
+/// Where the programs `--on-event` and `--backend-plugin` run come from.
+const PROGRAMS: &[&str] = &[
+    "/bin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/nix/store",
+    "/sbin",
+    "/usr",
+];
+
+/// The paths the mount described by `matches` still needs once it is up,
+/// with what it needs to do beneath each.
+fn rules(matches: &ArgMatches) -> Vec<(PathBuf, u64)> {
+    let mut rules: Vec<(PathBuf, u64)> = Vec::new();
+    rules.extend(SYSTEM.iter().map(|path| (path.into(), READ)));
+    rules.extend(PROGRAMS.iter().map(|path| (path.into(), READ | EXECUTE)));
+    rules.push(("/dev/null".into(), READ_FILE | WRITE_FILE));
+    // Read again on reload.
+    let layouts = matches.get_many::<String>("LAYOUT").into_iter().flatten();
+    let mounts = matches.get_many::<(PathBuf, Vec<String>)>("mount");
+    let layouts = layouts.chain(
+        mounts
+            .into_iter()
+            .flatten()
+            .flat_map(|(_, layouts)| layouts),
+    );
+    rules.extend(layouts.map(|layout| (layout.into(), READ)));
+    let allowed = matches.get_many::<PathBuf>("sandbox-allow");
+    rules.extend(
+        allowed
+            .into_iter()
+            .flatten()
+            .map(|path| (path.clone(), READ)),
+    );
+    let plugins = matches.get_many::<(String, PathBuf)>("backend-plugin");
+    for (_, program) in plugins.into_iter().flatten() {
+        rules.push((program.clone(), READ | EXECUTE));
+    }
+    if let Some(dir) = matches.get_one::<PathBuf>("cache-dir") {
+        rules.push((dir.clone(), ALL_V1 | REFER | TRUNCATE));
+    }
+    // Written, replaced and removed beside themselves.
+    for file in ["pidfile", "health-file", "control-socket"] {
+        let parent = (matches.get_one::<PathBuf>(file))
+            .and_then(|path| std::path::absolute(path).ok())
+            .and_then(|path| path.parent().map(Path::to_path_buf));
+        if let Some(parent) = parent {
+            rules.push((parent, ALL_V1 | REFER | TRUNCATE));
+        }
+    }
+    rules
+}
+
+/// Restricts every thread of the process, which is why it is called before
+/// any are started, to what [`rules`] allows, where the kernel has
+/// Landlock, and to the system calls [`DENIED`] leaves.
+#[cfg(target_os = "linux")]
+pub fn restrict(matches: &ArgMatches) -> Result<()> {
+    // SAFETY: no pointers are passed.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!("Couldn't sandbox: {}", io::Error::last_os_error()).into());
+    }
+    match landlock(&rules(matches)) {
+        Ok(abi) => info!("Restricted files with Landlock version {}", abi),
+        Err(e) => warn!("Files aren't restricted, as Landlock isn't there: {}", e),
+    }
+    seccomp().map_err(|e| format!("Couldn't filter system calls: {}", e))?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict(_: &ArgMatches) -> Result<()> {
+    Err("--sandbox takes Linux's Landlock and seccomp".into())
+}
+
+/// Confines the calling thread, and the ones it starts, to `rules`,
+/// giving the Landlock version used.
+#[cfg(target_os = "linux")]
+fn landlock(rules: &[(PathBuf, u64)]) -> io::Result<i64> {
+    // SAFETY: asking for the version takes no attributes.
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(io::Error::last_os_error());
+    }
+    // What newer versions handle as well is left alone; truncating and
+    // moving files across directories were free before they could be
+    // restricted.
+    let mut handled = ALL_V1;
+    if abi >= 2 {
+        handled |= REFER;
+    }
+    if abi >= 3 {
+        handled |= TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` is the size passed.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the kernel just opened it for us alone.
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+    for (path, access) in rules {
+        let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+            continue;
+        };
+        // SAFETY: `c_path` is a C string.
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            // Missing paths are missing from the sandbox too.
+            continue;
+        }
+        // SAFETY: `open` just opened it.
+        let parent = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut access = access & handled;
+        if !path.is_dir() {
+            access &= FILE;
+        }
+        let beneath = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: parent.as_raw_fd(),
+        };
+        // SAFETY: `beneath` is a path beneath rule.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &beneath,
+                0,
+            )
+        };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ));
+        }
+    }
+    // SAFETY: no pointers are passed.
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(abi)
+}
+
+/// The system calls refused, with `EPERM`: mounting and namespaces,
+/// which the sandbox would otherwise be left by, looking into other
+/// processes, loading code into the kernel and administering the machine.
+/// `io_uring` is among them because seccomp doesn't see what goes through
+/// it.
+#[cfg(target_os = "linux")]
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fsopen,
+    libc::SYS_fspick,
+    libc::SYS_init_module,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_io_uring_setup,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_lookup_dcookie,
+    libc::SYS_mount,
+    libc::SYS_mount_setattr,
+    libc::SYS_move_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_open_tree,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setdomainname,
+    libc::SYS_sethostname,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// The filter refusing [`DENIED`], killing the process on system calls
+/// made for another architecture, whose numbers mean something else.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn filter() -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let statement = |code, k| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |condition, k, jt, jf| libc::sock_filter {
+        code: (BPF_JMP | condition | BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    // Where `seccomp_data` keeps them.
+    let (nr, arch) = (0, 4);
+    let mut program = vec![
+        statement(BPF_LD | BPF_W | BPF_ABS, arch),
+        jump(BPF_JEQ, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, nr),
+    ];
+    // x32's system calls are x86_64's with this bit set.
+    let denied = DENIED.len() as u8;
+    if cfg!(target_arch = "x86_64") {
+        program.push(jump(BPF_JGE, 0x4000_0000, denied + 1, 0));
+    }
+    for (i, &call) in DENIED.iter().enumerate() {
+        program.push(jump(BPF_JEQ, call as u32, denied - i as u8, 0));
+    }
+    program.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    let refuse = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    program.push(statement(BPF_RET | BPF_K, refuse));
+    program
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn seccomp() -> io::Result<()> {
+    let mut filter = filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: `program` points to `filter`, which outlives the call.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        // The id of a thread that couldn't be synchronized.
+        1.. => Err(io::Error::other(format!(
+            "thread {} has a filter of its own",
+            result
+        ))),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn seccomp() -> io::Result<()> {
+    warn!("System calls aren't filtered on this architecture");
+    Ok(())
+}
+
+/// A process forked off before sandboxing, to unmount with the rights the
+/// mount had: the sandbox refuses `umount2` and doesn't let
+/// `fusermount3` be setuid. It unmounts when told to, or when the mount
+/// has gone away without a word.
+pub struct Unmounter(File);
+
+impl Unmounter {
+    /// Forks off the process, which calls `unmount` and exits. Call this
+    /// before starting any threads.
+    pub fn fork(mut unmount: impl FnMut()) -> Result<Unmounter> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors.
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: `pipe` just opened them and nothing else owns them.
+        let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        // Kept from the commands run later, which would otherwise keep the
+        // pipe open past the mount.
+        // SAFETY: `write` is open.
+        if unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: the process is still single threaded.
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error().into()),
+            0 => {
+                drop(write);
+                // Ctrl-C goes to the whole process group, and the mount is
+                // the one to tell it to unmount.
+                // SAFETY: `sigfillset` initializes the set before it's used.
+                unsafe {
+                    let mut signals = std::mem::zeroed();
+                    libc::sigfillset(&mut signals);
+                    libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+                }
+                let _ = read.read(&mut [0]);
+                unmount();
+                // SAFETY: nothing of the mount's is left to clean up here.
+                unsafe { libc::_exit(0) }
+            }
+            _ => Ok(Unmounter(write)),
+        }
+    }
+
+    pub fn unmount(&mut self) {
+        if let Err(e) = self.0.write_all(b"u") {
+            warn!("Unmounting failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{rules, Unmounter, ALL_V1, EXECUTE, READ, REFER, TRUNCATE};
+
+    #[test]
+    fn ruled() {
+        let matches = crate::command().get_matches_from([
+            "lhttpfs",
+            "mount",
+            "--cache-dir",
+            "/var/cache/lhttpfs",
+            "--pidfile",
+            "/run/lhttpfs/a.pid",
+            "--sandbox",
+            "--sandbox-allow",
+            "/srv/assets",
+            "--backend-plugin",
+            "s3=/opt/lhttpfs-s3",
+            "/mnt/a",
+            "a.json",
+        ]);
+        let (_, matches) = matches.subcommand().unwrap();
+        let rules = rules(matches);
+        let rule = |path: &str| {
+            let path = PathBuf::from(path);
+            rules
+                .iter()
+                .find(|(p, _)| *p == path)
+                .map(|(_, access)| *access)
+        };
+        assert_eq!(rule("a.json"), Some(READ));
+        assert_eq!(rule("/srv/assets"), Some(READ));
+        assert_eq!(rule("/opt/lhttpfs-s3"), Some(READ | EXECUTE));
+        assert_eq!(rule("/usr"), Some(READ | EXECUTE));
+        let writable = ALL_V1 | REFER | TRUNCATE;
+        assert_eq!(rule("/var/cache/lhttpfs"), Some(writable));
+        assert_eq!(rule("/run/lhttpfs"), Some(writable));
+        assert_eq!(rule("/etc/resolv.conf"), Some(READ));
+        assert_eq!(rule("/etc"), None);
+        assert_eq!(rule("/home"), None);
+    }
+
+    #[test]
+    fn unmounted() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-unmounter-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let marker = dir.clone();
+        let mut unmounter = Unmounter::fork(move || {
+            std::fs::write(&marker, "unmounted").unwrap();
+        })
+        .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!dir.exists());
+        unmounter.unmount();
+        for _ in 0..100 {
+            if dir.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(std::fs::read_to_string(&dir).unwrap(), "unmounted");
+        std::fs::remove_file(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn restricted() {
+        let matches = crate::command().get_matches_from([
+            "lhttpfs",
+            "mount",
+            "--sandbox",
+            "--sandbox-allow",
+            "/etc/hostname",
+            "/mnt/a",
+            "a.json",
+        ]);
+        let (_, matches) = matches.subcommand().unwrap();
+        // The sandbox can't be left, so it goes to a process of its own, and
+        // what happens there to its exit status.
+        // SAFETY: the child only makes system calls before exiting.
+        match unsafe { libc::fork() } {
+            0 => {
+                let code = if super::restrict(matches).is_err() {
+                    1
+                } else if std::fs::read("/etc/hostname").is_err() {
+                    2
+                } else if unsafe { libc::unshare(libc::CLONE_NEWUSER) } == 0 {
+                    3
+                } else if std::fs::read_dir("/").is_ok() {
+                    4
+                } else {
+                    0
+                };
+                // SAFETY: no preconditions.
+                unsafe { libc::_exit(code) }
+            }
+            child => {
+                let mut status = 0;
+                // SAFETY: `status` is a live value.
+                unsafe { libc::waitpid(child, &mut status, 0) };
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn filtered() {
+        let filter = super::filter();
+        let last = filter.len() - 1;
+        // Every refusal jumps to the last instruction, past the one allowing.
+        for (i, instruction) in filter.iter().enumerate().skip(4) {
+            if i < last - 1 {
+                assert_eq!(i + 1 + instruction.jt as usize, last);
+            }
+        }
+        assert_eq!(filter[last].k, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        assert_eq!(filter[last - 1].k, libc::SECCOMP_RET_ALLOW);
+    }
+}
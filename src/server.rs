@@ -0,0 +1,494 @@
+//! The HTTP/1.1 server `serve-dav` and `serve-http` answer with: requests
+//! are read off each connection one after another, from a thread per
+//! connection, and files are sent in pieces, with the range asked for, so
+//! that a large one is never held in memory whole.
+
+use std::{
+    ffi::OsStr,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use lhttpfs::fs::{FileAttr, FileType};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tracing::{debug, warn};
+
+use crate::{
+    fetch::date::http_date,
+    fs::{LazyHTTPFS, OpError},
+    inspect::human,
+};
+
+/// How much of a file is read at a time.
+const CHUNK: u64 = 1 << 20;
+/// The largest request body taken, which only the control files get.
+const MAX_BODY: usize = 1 << 20;
+/// The longest request or header line taken, and the most headers.
+const MAX_LINE: usize = 8 << 10;
+const MAX_HEADERS: usize = 100;
+
+/// Everything but the unreserved characters and `/`.
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// A request refused before it was read in full, with the status it is
+/// answered with. The connection is closed after it.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct Refused(pub &'static str);
+
+impl From<Refused> for io::Error {
+    fn from(refused: Refused) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, refused)
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&mut LazyHTTPFS, &Request) -> Response + Send + Sync>;
+
+pub struct Request {
+    pub method: String,
+    /// The path, decoded and without the query.
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the header `name`, given in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let mut headers = self.headers.iter();
+        headers
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// `length` bytes of the file `ino` from `offset`, or all of them up to
+    /// its end if its size isn't known.
+    File {
+        ino: u64,
+        offset: u64,
+        length: Option<u64>,
+    },
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &str, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: Body::Bytes(body.into()),
+        }
+    }
+
+    /// A plain text response saying what `status` means.
+    pub fn status(status: &'static str) -> Response {
+        Response::new(status, "text/plain", format!("{}\n", status))
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Response {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+/// The status a failed operation is answered with.
+pub fn status(error: OpError) -> &'static str {
+    match error {
+        OpError::NotFound => "404 Not Found",
+        OpError::Denied | OpError::ReadOnly | OpError::NotPermitted => "403 Forbidden",
+        OpError::NotLink => "400 Bad Request",
+        OpError::Refused => "502 Bad Gateway",
+        OpError::TimedOut => "504 Gateway Timeout",
+        OpError::NoAttribute | OpError::Failed => "500 Internal Server Error",
+    }
+}
+
+/// Answers every connection to `listener` with `handler`, the filesystem
+/// taking one request at a time.
+pub fn serve(
+    listener: TcpListener,
+    fs: LazyHTTPFS,
+    handler: impl Fn(&mut LazyHTTPFS, &Request) -> Response + Send + Sync + 'static,
+) {
+    let fs = Arc::new(Mutex::new(fs));
+    let handler: Handler = Arc::new(handler);
+    for stream in listener.incoming() {
+        let fs = fs.clone();
+        let handler = handler.clone();
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = connection(stream, &fs, &handler) {
+                        debug!("Serving HTTP failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Accepting an HTTP connection failed: {}", e),
+        }
+    }
+}
+
+fn connection(stream: TcpStream, fs: &Mutex<LazyHTTPFS>, handler: &Handler) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    loop {
+        let (request, keep_alive) = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                let refused = e.get_ref().and_then(|e| e.downcast_ref::<Refused>());
+                if let Some(Refused(status)) = refused {
+                    respond(&mut writer, fs, Response::status(status), false, false)?;
+                }
+                return Err(e);
+            }
+        };
+        let mut locked = fs.lock().unwrap_or_else(PoisonError::into_inner);
+        let response = handler(&mut locked, &request);
+        drop(locked);
+        debug!("{} {} {}", request.method, request.path, response.status);
+        let head = request.method == "HEAD";
+        if !respond(&mut writer, fs, response, head, keep_alive)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The next request, and whether the connection may be kept open after
+/// it, or `None` once the client hung up. Requests with lines, headers or
+/// a body past the limits fail with [`Refused`].
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<Option<(Request, bool)>> {
+    let mut line = String::new();
+    if read_line(reader, &mut line, "414 URI Too Long")? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), version) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad request line",
+        ));
+    };
+    let target = target.split('?').next().unwrap_or("");
+    let mut request = Request {
+        method: method.to_string(),
+        path: percent_decode_str(target).decode_utf8_lossy().into_owned(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+    let mut keep_alive = version == Some("HTTP/1.1");
+    loop {
+        line.clear();
+        read_line(reader, &mut line, "431 Request Header Fields Too Large")?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if request.headers.len() == MAX_HEADERS {
+            return Err(Refused("431 Request Header Fields Too Large").into());
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let header = (name.trim().to_lowercase(), value.trim().to_string());
+            request.headers.push(header);
+        }
+    }
+    match request
+        .header("connection")
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("close") => keep_alive = false,
+        Some("keep-alive") => keep_alive = true,
+        _ => {}
+    }
+    let length = request
+        .header("content-length")
+        .and_then(|n| n.parse().ok());
+    match length.unwrap_or(0) {
+        length if length > MAX_BODY => return Err(Refused("413 Content Too Large").into()),
+        length => {
+            request.body.resize(length, 0);
+            reader.read_exact(&mut request.body)?;
+        }
+    }
+    Ok(Some((request, keep_alive)))
+}
+
+/// Reads a line into `line` like [`BufRead::read_line`], failing with
+/// `status` if it is longer than [`MAX_LINE`].
+fn read_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    status: &'static str,
+) -> io::Result<usize> {
+    let read = reader.take(MAX_LINE as u64 + 1).read_line(line)?;
+    if line.len() > MAX_LINE {
+        return Err(Refused(status).into());
+    }
+    Ok(read)
+}
+
+/// Writes `response`, returning whether the connection may be kept open.
+fn respond(
+    out: &mut impl Write,
+    fs: &Mutex<LazyHTTPFS>,
+    response: Response,
+    head: bool,
+    mut keep_alive: bool,
+) -> io::Result<bool> {
+    let length = match &response.body {
+        Body::Bytes(bytes) => Some(bytes.len() as u64),
+        Body::File { length, .. } => *length,
+    };
+    // Without a length the end of the body is where the connection ends.
+    keep_alive &= length.is_some();
+    let mut header = format!("HTTP/1.1 {}\r\n", response.status);
+    for (name, value) in &response.headers {
+        header.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(length) = length {
+        header.push_str(&format!("Content-Length: {}\r\n", length));
+    }
+    if !keep_alive {
+        header.push_str("Connection: close\r\n");
+    }
+    header.push_str("\r\n");
+    out.write_all(header.as_bytes())?;
+    if head {
+        return Ok(keep_alive);
+    }
+    match response.body {
+        Body::Bytes(bytes) => out.write_all(&bytes)?,
+        Body::File {
+            ino,
+            mut offset,
+            length,
+        } => {
+            let end = length.map_or(u64::MAX, |length| offset + length);
+            while offset < end {
+                let size = CHUNK.min(end - offset) as u32;
+                let mut locked = fs.lock().unwrap_or_else(PoisonError::into_inner);
+                let data = locked.read_file(ino, offset as i64, size);
+                drop(locked);
+                let data = data.map_err(|e| io::Error::other(format!("{:?}", e)))?;
+                if data.is_empty() {
+                    break;
+                }
+                out.write_all(&data)?;
+                offset += data.len() as u64;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(keep_alive)
+}
+
+/// The attributes of what `path` names, from the root, following links.
+pub fn resolve(fs: &LazyHTTPFS, path: &str) -> Result<FileAttr, OpError> {
+    fs.resolve(path)
+}
+
+/// `path` as it goes in a URL, with a `/` at the end for a directory.
+pub fn href(path: &str, attr: &FileAttr) -> String {
+    let mut href = utf8_percent_encode(path, PATH).to_string();
+    if attr.kind == FileType::Directory && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+/// The range `header` asks for in a file of `size` bytes, as its first
+/// byte and length, or `Err` if none of it is there. Only single ranges
+/// are ever answered; anything else gets the whole file.
+fn range(header: Option<&str>, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.and_then(|h| h.strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(size.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end - start + 1)))
+}
+
+/// Answers `GET` or `HEAD` for the file `attr`, with the range asked for.
+pub fn get(fs: &mut LazyHTTPFS, request: &Request, attr: &FileAttr) -> Response {
+    let opened = match fs.open_file(attr.ino, false) {
+        Ok(opened) => opened,
+        Err(e) => return Response::status(status(e)),
+    };
+    // Opening generated files learns their size.
+    let attr = fs.attributes(attr.ino).map_or(*attr, |(attr, _)| attr);
+    let content_type = fs.xattr(attr.ino, OsStr::new("user.mime_type"));
+    let content_type = content_type.map_or("application/octet-stream".into(), |mime| {
+        String::from_utf8_lossy(mime).into_owned()
+    });
+    let mut response = Response::new("200 OK", &content_type, Vec::new())
+        .header("Last-Modified", http_date(attr.mtime));
+    if opened.direct {
+        response.body = Body::File {
+            ino: attr.ino,
+            offset: 0,
+            length: None,
+        };
+        return response;
+    }
+    response = response.header("Accept-Ranges", "bytes");
+    let (offset, length) = match range(request.header("range"), attr.size) {
+        Ok(Some((offset, length))) => {
+            response.status = "206 Partial Content";
+            let last = offset + length - 1;
+            let range = format!("bytes {}-{}/{}", offset, last, attr.size);
+            response = response.header("Content-Range", range);
+            (offset, length)
+        }
+        Ok(None) => (0, attr.size),
+        Err(()) => {
+            let range = format!("bytes */{}", attr.size);
+            return Response::status("416 Range Not Satisfiable").header("Content-Range", range);
+        }
+    };
+    response.body = Body::File {
+        ino: attr.ino,
+        offset,
+        length: Some(length),
+    };
+    response
+}
+
+/// Answers `GET` or `HEAD` for the directory `attr` with a page listing
+/// what it holds, sending a path without a `/` at the end there first, for
+/// the links on the page to be relative to the directory.
+pub fn listing(fs: &LazyHTTPFS, request: &Request, attr: &FileAttr) -> Response {
+    if !request.path.ends_with('/') {
+        let location = href(&request.path, attr);
+        return Response::status("301 Moved Permanently").header("Location", location);
+    }
+    let mut entries = match fs.list(attr.ino) {
+        Ok(entries) => entries,
+        Err(e) => return Response::status(status(e)),
+    };
+    entries.retain(|(_, name, _)| *name != "." && *name != "..");
+    entries.sort_by_key(|(_, name, _)| *name);
+    let title = escape(&request.path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+         <body><h1>Index of {0}</h1>\n<table>\n",
+        title
+    );
+    if request.path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (ino, name, _) in entries {
+        let Ok((attr, _)) = fs.attributes(ino) else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+        let (link, size) = match attr.kind {
+            FileType::Directory => (format!("{}/", name), String::new()),
+            _ => (name.to_string(), human(attr.size)),
+        };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape(&href(&name, &attr)),
+            escape(&link),
+            size,
+            http_date(attr.mtime)
+        ));
+    }
+    html.push_str("</table></body></html>\n");
+    Response::new("200 OK", "text/html; charset=utf-8", html)
+}
+
+/// `text` with what HTML or XML would take for markup escaped.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{href, range, read_request, resolve, Refused};
+    use crate::{fs::LazyHTTPFS, layout};
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range(None, 10), Ok(None));
+        assert_eq!(range(Some("bytes=2-4"), 10), Ok(Some((2, 3))));
+        assert_eq!(range(Some("bytes=2-"), 10), Ok(Some((2, 8))));
+        assert_eq!(range(Some("bytes=-3"), 10), Ok(Some((7, 3))));
+        assert_eq!(range(Some("bytes=5-100"), 10), Ok(Some((5, 5))));
+        assert_eq!(range(Some("bytes=10-"), 10), Err(()));
+        assert_eq!(range(Some("bytes=0-1,4-5"), 10), Ok(None));
+        assert_eq!(range(Some("lines=1-2"), 10), Ok(None));
+    }
+
+    #[test]
+    fn requests() {
+        let text =
+            "PUT /d/a%20b?x=1 HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabcGET";
+        let (request, keep_alive) = read_request(&mut Cursor::new(text)).unwrap().unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("PUT", "/d/a b")
+        );
+        assert_eq!(request.header("content-length"), Some("3"));
+        assert_eq!(request.body, b"abc");
+        assert!(!keep_alive);
+        assert!(read_request(&mut Cursor::new("")).unwrap().is_none());
+        let refused = |text: String| {
+            let error = read_request(&mut Cursor::new(text)).err().unwrap();
+            error.into_inner().unwrap().downcast::<Refused>().unwrap().0
+        };
+        let long = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(9000));
+        assert_eq!(refused(long), "431 Request Header Fields Too Large");
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X: a\r\n".repeat(101));
+        assert_eq!(refused(many), "431 Request Header Fields Too Large");
+        let uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000));
+        assert_eq!(refused(uri), "414 URI Too Long");
+        let body = "PUT / HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n".to_string();
+        assert_eq!(refused(body), "413 Content Too Large");
+
+        let layout = r#"[{"name": "d", "contents": [{"name": "a b", "content": "abc"}]}]"#;
+        let fs = LazyHTTPFS::new(layout::parse(layout.as_bytes()).unwrap()).unwrap();
+        let file = resolve(&fs, &request.path).unwrap();
+        assert_eq!(file.size, 3);
+        assert_eq!(href("/d/a b", &file), "/d/a%20b");
+        assert_eq!(href("/d", &resolve(&fs, "/d/").unwrap()), "/d/");
+        assert!(resolve(&fs, "/d/c").is_err());
+    }
+}
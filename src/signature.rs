@@ -0,0 +1,96 @@
+//! Checking layouts against detached minisign signatures, so a published
+//! catalog can't be tampered with on its way to the machines mounting it.
+
+use std::{error::Error, fmt::Display, path::Path};
+
+use clap::Arg;
+use minisign_verify::{PublicKey, Signature};
+
+use crate::{Downloads, Result};
+
+pub fn arg() -> Arg {
+    Arg::new("require-signed-layout")
+        .long("require-signed-layout")
+        .value_name("PUBKEY")
+        .help(
+            "Refuse layouts without a valid <layout>.minisig signature by this minisign \
+             public key, given as a key file or the base64 key itself",
+        )
+}
+
+#[derive(Debug)]
+pub struct BadSignature {
+    path: String,
+    error: minisign_verify::Error,
+}
+
+impl Display for BadSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Signature check of {} failed: {}", self.path, self.error)
+    }
+}
+
+impl Error for BadSignature {}
+
+/// Reads a public key from a `minisign.pub` style file, or from `key` itself.
+pub fn public_key(key: &str) -> Result<PublicKey> {
+    let key = if Path::new(key).is_file() {
+        PublicKey::from_file(key)?
+    } else {
+        PublicKey::from_base64(key.trim())?
+    };
+    Ok(key)
+}
+
+/// Reads the layout at `path`, a file or URL, failing unless `path.minisig` holds a
+/// signature of its exact bytes by `key`.
+pub fn read_verified(path: &str, key: &PublicKey, downloads: &Downloads) -> Result<Vec<u8>> {
+    let data = downloads.read_layout(path)?;
+    let signature_path = format!("{}.minisig", path);
+    let bad = |error| BadSignature {
+        path: path.to_owned(),
+        error,
+    };
+    let signature = if crate::remote(path) {
+        let text = downloads.read_layout(&signature_path)?;
+        Signature::decode(&String::from_utf8_lossy(&text)).map_err(bad)?
+    } else {
+        Signature::from_file(&signature_path).map_err(bad)?
+    };
+    key.verify(&data, &signature, false).map_err(bad)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{public_key, read_verified, BadSignature};
+    use crate::Downloads;
+
+    const KEY: &str = "RWSp0maILOCyfA/VcWmWRI858baifgeH1Zqt6FL23ZZztNdCt0NF1CEv";
+
+    #[test]
+    fn verification() {
+        let key = public_key(KEY).unwrap();
+        let signed = concat!(env!("CARGO_MANIFEST_DIR"), "/src/signed.json");
+        assert!(read_verified(signed, &key, &Downloads::default()).is_ok());
+
+        let dir = std::env::temp_dir().join(format!("lhttpfs-signature-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tampered = dir.join("signed.json");
+        let mut data = std::fs::read(signed).unwrap();
+        data[0] = b' ';
+        std::fs::write(&tampered, data).unwrap();
+        std::fs::copy(
+            format!("{}.minisig", signed),
+            dir.join("signed.json.minisig"),
+        )
+        .unwrap();
+        let err =
+            read_verified(tampered.to_str().unwrap(), &key, &Downloads::default()).unwrap_err();
+        assert!(err.is::<BadSignature>());
+
+        let unsigned = concat!(env!("CARGO_MANIFEST_DIR"), "/src/example.json");
+        assert!(read_verified(unsigned, &key, &Downloads::default()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
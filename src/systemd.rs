@@ -0,0 +1,97 @@
+//! The sd_notify protocol, for running as a `Type=notify` systemd service:
+//! `READY=1` once mounted, `STOPPING=1` on the way out and watchdog pings,
+//! each a datagram sent to `$NOTIFY_SOCKET`. Outside systemd nothing is
+//! sent.
+
+#[cfg(target_os = "linux")]
+use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+use std::{
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    time::Duration,
+};
+
+use tracing::warn;
+
+/// Tells the service manager about `state`, such as `READY=1`, if it is
+/// listening.
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("Couldn't notify systemd of {}: {}", state, e);
+    }
+}
+
+/// Sends `state` to the socket at `path`, which is in the abstract
+/// namespace when it starts with `@`.
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+        None => socket.send_to(state.as_bytes(), path)?,
+    };
+    Ok(())
+}
+
+/// How often the service manager wants `WATCHDOG=1`, if it is watching
+/// this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match std::env::var("WATCHDOG_PID") {
+        Ok(pid) if pid.parse() != Ok(std::process::id()) => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Pings the watchdog at half the interval systemd asks for, from a thread
+/// of its own, for as long as the process lives.
+pub fn watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    std::thread::spawn(move || loop {
+        notify("WATCHDOG=1");
+        std::thread::sleep(interval / 2);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::send;
+
+    #[test]
+    fn notified() {
+        let path = std::env::temp_dir().join(format!("lhttpfs-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_socket() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("@lhttpfs-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name[1..]).unwrap();
+        let listener = UnixDatagram::bind_addr(&addr).unwrap();
+        send(name.as_ref(), "STOPPING=1").unwrap();
+        let mut buf = [0; 16];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+}
@@ -0,0 +1,392 @@
+//! [`Origin`]: a local HTTP server for tests, and `lhttpfs bench`, to read
+//! real layouts from, through curl, the cache and the read path of a mount,
+//! with the latency and failures of a real origin when they need them.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// A request [`Origin`] answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Served {
+    pub path: String,
+    /// The `Range` header, such as `bytes=0-1023`.
+    pub range: Option<String>,
+    pub status: u32,
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<String, Vec<u8>>,
+    /// Answered with instead of the file, by path.
+    failures: HashMap<String, u32>,
+    /// Where requests are sent on to, by path.
+    redirects: HashMap<String, String>,
+    latency: Duration,
+    /// Whether `Range`s are ignored, answering with the whole file.
+    whole: bool,
+    served: Vec<Served>,
+}
+
+/// Serves files by path over HTTP/1.1, with single `Range`s, an `ETag` to
+/// ask `If-None-Match` with and a [`LAST_MODIFIED`], on a port of its own
+/// for as long as the test runs.
+pub struct Origin {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Origin {
+    pub fn start() -> Origin {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = Origin {
+            addr: listener.local_addr().unwrap(),
+            state: Arc::default(),
+        };
+        let state = origin.state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = state.clone();
+                thread::spawn(move || answer(stream, &state));
+            }
+        });
+        origin
+    }
+
+    /// Serves `data` as `path`.
+    pub fn with(self, path: &str, data: impl Into<Vec<u8>>) -> Origin {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(path.to_string(), data.into());
+        self
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Waits `latency` before answering each request from now on.
+    pub fn latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Answers requests for `path` with `status` from now on, or as usual
+    /// again with `None`.
+    pub fn fail(&self, path: &str, status: Option<u32>) {
+        let failures = &mut self.state.lock().unwrap().failures;
+        match status {
+            Some(status) => failures.insert(path.to_string(), status),
+            None => failures.remove(path),
+        };
+    }
+
+    /// Redirects requests for `path` to `to`, with a `302 Found`, as CDNs
+    /// and release hosts do.
+    pub fn redirect(self, path: &str, to: &str) -> Origin {
+        self.state
+            .lock()
+            .unwrap()
+            .redirects
+            .insert(path.to_string(), to.to_string());
+        self
+    }
+
+    /// Ignores `Range`s from now on, as some servers do, answering with the
+    /// whole file.
+    pub fn ignore_ranges(&self) {
+        self.state.lock().unwrap().whole = true;
+    }
+
+    /// What was asked for so far, in order.
+    pub fn served(&self) -> Vec<Served> {
+        self.state.lock().unwrap().served.clone()
+    }
+}
+
+/// When every file [`Origin`] serves was last modified.
+pub const LAST_MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+/// Answers the request on `stream`, then closes it.
+fn answer(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let mut words = line.split_whitespace();
+    let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let (mut range, mut if_none_match) = (None, None);
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).unwrap_or(0) == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("if-none-match") {
+                if_none_match = Some(value.trim().to_string());
+            }
+        }
+    }
+    let latency = state.lock().unwrap().latency;
+    thread::sleep(latency);
+    let (status, headers, body) = {
+        let state = state.lock().unwrap();
+        match (state.failures.get(path), state.files.get(path)) {
+            (Some(&status), _) => (status, String::new(), Vec::new()),
+            (None, _) if state.redirects.contains_key(path) => {
+                let headers = format!("Location: {}\r\n", state.redirects[path]);
+                (302, headers, Vec::new())
+            }
+            (None, None) => (404, String::new(), Vec::new()),
+            (None, Some(data)) if if_none_match == Some(etag(data)) => {
+                (304, String::new(), Vec::new())
+            }
+            (None, Some(data)) => {
+                let (status, headers, body) = match state.whole {
+                    true => respond(data, None),
+                    false => respond(data, range.as_deref()),
+                };
+                let headers = format!(
+                    "{}ETag: {}\r\nLast-Modified: {}\r\n",
+                    headers,
+                    etag(data),
+                    LAST_MODIFIED
+                );
+                (status, headers, body)
+            }
+        }
+    };
+    state.lock().unwrap().served.push(Served {
+        path: path.to_string(),
+        range,
+        status,
+    });
+    let mut stream = &stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} Origin\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n",
+        status,
+        body.len(),
+        headers
+    );
+    if method != "HEAD" {
+        let _ = stream.write_all(&body);
+    }
+}
+
+/// What identifies this version of `data`.
+fn etag(data: &[u8]) -> String {
+    let mut hasher = std::hash::DefaultHasher::new();
+    std::hash::Hash::hash(data, &mut hasher);
+    format!("\"{:016x}\"", std::hash::Hasher::finish(&hasher))
+}
+
+/// The status, extra headers and body answering `range` of `data`.
+fn respond(data: &[u8], range: Option<&str>) -> (u32, String, Vec<u8>) {
+    let Some(range) = range.and_then(|range| range.strip_prefix("bytes=")) else {
+        return (200, String::new(), data.to_vec());
+    };
+    let (start, end) = range.split_once('-').unwrap_or((range, ""));
+    let start = start.parse::<usize>().unwrap_or(0);
+    let end = end
+        .parse::<usize>()
+        .map_or(data.len(), |end| end + 1)
+        .min(data.len());
+    if start >= end {
+        let headers = format!("Content-Range: bytes */{}\r\n", data.len());
+        return (416, headers, Vec::new());
+    }
+    let headers = format!(
+        "Content-Range: bytes {}-{}/{}\r\n",
+        start,
+        end - 1,
+        data.len()
+    );
+    (206, headers, data[start..end].to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{Origin, Served, LAST_MODIFIED};
+    use crate::{
+        fetch::date::http_date,
+        fs::{LazyHTTPFS, RemoteTree},
+        layout, LhttpfsError,
+    };
+
+    fn tree(layout: &str) -> RemoteTree {
+        let files = layout::parse(layout.as_bytes()).unwrap();
+        RemoteTree::new(LazyHTTPFS::builder().cache_dir(None).build(files).unwrap())
+    }
+
+    #[test]
+    fn ranges_and_cache() {
+        let data = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let origin = Origin::start().with("/big", data.clone());
+        let tree = tree(&format!(
+            r#"[{{"name": "big", "url": "{}", "size": 3000, "chunk_size": 1024}}]"#,
+            origin.url("/big")
+        ));
+        let chunks = tree.list("/big").unwrap();
+        let chunks = chunks.iter().map(|chunk| chunk.path.to_str().unwrap());
+        let [first, _, last] = chunks.collect::<Vec<_>>().try_into().unwrap();
+        let (first, last) = (tree.open(first).unwrap(), tree.open(last).unwrap());
+        assert_eq!(first.read_at(1000, 100).unwrap(), &data[1000..1024]);
+        assert_eq!(last.read_at(900, 500).unwrap(), &data[2948..]);
+        // Cached now.
+        assert_eq!(first.read_at(20, 10).unwrap(), &data[20..30]);
+        let served = |range: &str| Served {
+            path: "/big".into(),
+            range: Some(range.into()),
+            status: 206,
+        };
+        assert_eq!(
+            origin.served(),
+            [served("bytes=0-1023"), served("bytes=2048-2999")]
+        );
+    }
+
+    #[test]
+    fn ranges_ignored() {
+        let data = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let origin = Origin::start().with("/big", data.clone());
+        origin.ignore_ranges();
+        let tree = tree(&format!(
+            r#"[{{"name": "big", "url": "{}", "size": 300000, "chunk_size": 1024}}]"#,
+            origin.url("/big")
+        ));
+        let chunks = tree.list("/big").unwrap();
+        let chunk = tree.open(chunks[1].path.to_str().unwrap()).unwrap();
+        // Only the chunk is kept of the whole body.
+        assert_eq!(chunk.read_at(0, 2000).unwrap(), &data[1024..2048]);
+        let served = origin.served();
+        assert_eq!(served.len(), 1);
+        assert_eq!(served[0].status, 200);
+    }
+
+    #[test]
+    fn revalidated() {
+        let dir = std::env::temp_dir().join(format!("lhttpfs-revalidated-{}", std::process::id()));
+        let origin = Origin::start().with("/a", "old");
+        let layout = format!(
+            r#"[{{"name": "a", "url": "{}", "size": 3, "cache": "disk", "ttl": 60}}]"#,
+            origin.url("/a")
+        );
+        let tree = || {
+            let files = layout::parse(layout.as_bytes()).unwrap();
+            let fs = LazyHTTPFS::builder().cache_dir(Some(dir.clone()));
+            RemoteTree::new(fs.build(files).unwrap())
+        };
+        // Whatever is cached goes stale, as after a remount long after.
+        let stale = || {
+            let past = SystemTime::now() - Duration::from_secs(120);
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let file = std::fs::File::open(entry.unwrap().path()).unwrap();
+                file.set_modified(past).unwrap();
+            }
+        };
+        assert_eq!(tree().open("/a").unwrap().read_at(0, 3).unwrap(), b"old");
+        stale();
+        // Still current, and fresh again.
+        assert_eq!(tree().open("/a").unwrap().read_at(0, 3).unwrap(), b"old");
+        assert_eq!(tree().open("/a").unwrap().read_at(0, 3).unwrap(), b"old");
+        let origin = origin.with("/a", "new");
+        stale();
+        assert_eq!(tree().open("/a").unwrap().read_at(0, 3).unwrap(), b"new");
+        // Offline, what was cached is served.
+        origin.fail("/a", Some(503));
+        stale();
+        assert_eq!(tree().open("/a").unwrap().read_at(0, 3).unwrap(), b"new");
+        let statuses = origin.served().into_iter().map(|s| s.status);
+        assert_eq!(statuses.collect::<Vec<_>>(), [206, 304, 206, 503, 503]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let origin = Origin::start().with("/a", "mirrored");
+        let tree = tree(&format!(
+            r#"[
+                {{"name": "gone", "url": "{}", "size": 3}},
+                {{"name": "a", "url": "{}", "size": 8, "mirrors": ["{}"]}}
+            ]"#,
+            origin.url("/gone"),
+            origin.url("/down"),
+            origin.url("/a"),
+        ));
+        let gone = tree.open("/gone").unwrap().read_at(0, 3);
+        let Err(LhttpfsError::Fetch { status, .. }) = gone else {
+            panic!("Expected a failed fetch, got {:?}", gone);
+        };
+        assert_eq!(status, Some(404));
+        origin.fail("/down", Some(503));
+        assert_eq!(tree.open("/a").unwrap().read_at(0, 8).unwrap(), b"mirrored");
+        let statuses = origin.served().into_iter().map(|s| s.status);
+        assert_eq!(statuses.collect::<Vec<_>>(), [404, 503, 206]);
+    }
+
+    #[test]
+    fn slow_origin() {
+        let origin = Origin::start().with("/a", "aaaa").with("/b", "bbbb");
+        let layout = format!(
+            r#"[
+                {{"name": "a", "url": "{}", "size": 4}},
+                {{"name": "b", "url": "{}", "size": 4}},
+                {{"name": "c", "content": "cccc"}}
+            ]"#,
+            origin.url("/a"),
+            origin.url("/b")
+        );
+        let tree = tree(&layout);
+        origin.latency(Duration::from_millis(300));
+        let start = Instant::now();
+        let reads = ["/a", "/b"].map(|path| {
+            let file = tree.open(path).unwrap();
+            thread::spawn(move || file.read_at(0, 4).unwrap())
+        });
+        thread::sleep(Duration::from_millis(50));
+        // Neither fetch holds up what needs none.
+        assert_eq!(tree.open("/c").unwrap().read_at(0, 4).unwrap(), b"cccc");
+        assert!(start.elapsed() < Duration::from_millis(250));
+        let [a, b] = reads.map(|read| read.join().unwrap());
+        assert_eq!((&a[..], &b[..]), (&b"aaaa"[..], &b"bbbb"[..]));
+        // Fetched side by side.
+        assert!(start.elapsed() < Duration::from_millis(550));
+    }
+
+    #[test]
+    fn last_modified() {
+        let origin = Origin::start().with("/a", "aaaa");
+        let tree = tree(&format!(
+            r#"[
+                {{"name": "a", "url": "{0}"}},
+                {{"name": "b", "url": "{0}", "mtime": "2024-05-01T12:00:00Z"}}
+            ]"#,
+            origin.url("/a")
+        ));
+        let entries = tree.list("/").unwrap();
+        let mtime = |name: &str| {
+            let entry = entries.iter().find(|entry| entry.name == name).unwrap();
+            entry.attr.mtime
+        };
+        // Dated by the server while its size was asked for, unless the
+        // layout gives a date.
+        assert_eq!(http_date(mtime("a")), LAST_MODIFIED);
+        let given = UNIX_EPOCH + Duration::from_secs(1714564800);
+        assert_eq!(mtime("b"), given);
+    }
+}
@@ -0,0 +1,641 @@
+//! Changes made to a file's bytes between fetching and serving them.
+
+use std::{
+    error::Error,
+    fmt::Display,
+    io::Write,
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+};
+
+use aes_gcm::{
+    aead::{Aead, Generate, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+#[cfg(feature = "compression")]
+use std::io::Read;
+
+#[cfg(feature = "compression")]
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+#[cfg(feature = "compression")]
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "compression")]
+use xz2::read::XzDecoder;
+
+use crate::{archive::ReadAt, Result};
+
+/// How a remote file is compressed, to serve it decompressed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    /// A raw deflate stream, as in deflated zip members.
+    Deflate,
+    /// A zlib stream, as in SquashFS images.
+    Zlib,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Deflate => write!(f, "deflate"),
+            Compression::Zlib => write!(f, "zlib"),
+        }
+    }
+}
+
+impl Compression {
+    /// The compression a file name's extension stands for, and the name
+    /// of the decompressed file.
+    pub fn from_name(name: &str) -> Option<(Compression, String)> {
+        let (stem, extension) = name.rsplit_once('.')?;
+        let compression = match extension {
+            "gz" | "tgz" => Compression::Gzip,
+            "zst" | "tzst" => Compression::Zstd,
+            "xz" | "txz" => Compression::Xz,
+            _ => return None,
+        };
+        match extension.starts_with('t') {
+            true => Some((compression, format!("{}.tar", stem))),
+            false if stem.is_empty() => None,
+            false => Some((compression, stem.to_string())),
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Compression> {
+        match content_type.split(';').next()?.trim() {
+            "application/gzip" | "application/x-gzip" => Some(Compression::Gzip),
+            "application/zstd" => Some(Compression::Zstd),
+            "application/x-xz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() * 4);
+        match self {
+            // Concatenated members, as `bgzip` and `pigz` write, are one
+            // stream.
+            Compression::Gzip => MultiGzDecoder::new(data).read_to_end(&mut out)?,
+            Compression::Zstd => zstd::Decoder::new(data)?.read_to_end(&mut out)?,
+            Compression::Xz => XzDecoder::new_multi_decoder(data).read_to_end(&mut out)?,
+            Compression::Deflate => DeflateDecoder::new(data).read_to_end(&mut out)?,
+            Compression::Zlib => ZlibDecoder::new(data).read_to_end(&mut out)?,
+        };
+        Ok(out)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn decompress(self, _data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let error = crate::NotBuilt("compression");
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, error))
+    }
+}
+
+/// How a remote file is encrypted, to serve it decrypted. The file is a
+/// random nonce followed by the ciphertext and its tag.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Encryption {
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// Holds the key, as raw bytes or hex digits. Only the path is kept in
+    /// compiled layouts.
+    pub key_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cipher {
+    #[default]
+    Aes256Gcm,
+}
+
+const NONCE_LEN: usize = 12;
+/// How much longer encryption makes a file: the nonce and the tag.
+pub const ENCRYPTION_OVERHEAD: u64 = NONCE_LEN as u64 + 16;
+
+#[derive(Debug)]
+pub struct BadKey(PathBuf);
+
+impl Display for BadKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} doesn't hold a 256 bit key, as 32 bytes or 64 hex digits",
+            self.0.display()
+        )
+    }
+}
+
+impl Error for BadKey {}
+
+#[derive(Debug)]
+pub struct DecryptionFailed;
+
+impl Display for DecryptionFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Decryption failed: wrong key, or the file was modified")
+    }
+}
+
+impl Error for DecryptionFailed {}
+
+impl Encryption {
+    fn cipher(&self) -> Result<Aes256Gcm> {
+        let key = std::fs::read(&self.key_file)?;
+        let hex = std::str::from_utf8(&key).ok().map(str::trim);
+        let key = match hex.filter(|hex| hex.len() == 64) {
+            Some(hex) => (0..32)
+                .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|_| BadKey(self.key_file.clone()))?,
+            None => key,
+        };
+        let key =
+            Key::<Aes256Gcm>::try_from(&key[..]).map_err(|_| BadKey(self.key_file.clone()))?;
+        Ok(Aes256Gcm::new(&key))
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.cipher()?;
+        if data.len() < ENCRYPTION_OVERHEAD as usize {
+            return Err(Box::new(DecryptionFailed));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_from(nonce).unwrap();
+        Ok(cipher
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| DecryptionFailed)?)
+    }
+
+    /// Writes a new random key to `key_file`, which mustn't exist yet.
+    pub fn generate_key(&self) -> Result<()> {
+        let key = Key::<Aes256Gcm>::generate();
+        let hex = key.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let mut file = std::fs::File::create_new(&self.key_file)?;
+        std::io::Write::write_all(&mut file, format!("{}\n", hex).as_bytes())?;
+        Ok(())
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.cipher()?;
+        let nonce = Nonce::generate();
+        let ciphertext = cipher.encrypt(&nonce, data).map_err(|e| e.to_string())?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterFailed {
+    command: String,
+    status: ExitStatus,
+}
+
+impl Display for FilterFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Filter `{}` failed: {}", self.command, self.status)
+    }
+}
+
+impl Error for FilterFailed {}
+
+/// Pipes `data` through `command`, run by `sh`, and returns what it wrote.
+pub fn filter(command: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Written from another thread, so a command that writes before it has
+    // read everything can't fill its output pipe and stall both sides.
+    let output = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || {
+            // A command may well stop reading early, as `head` does.
+            let _ = stdin.write_all(data);
+        });
+        let output = child.wait_with_output();
+        writer.join().unwrap();
+        output
+    })?;
+    if !output.status.success() {
+        return Err(Box::new(FilterFailed {
+            command: command.to_string(),
+            status: output.status,
+        }));
+    }
+    Ok(output.stdout)
+}
+
+/// The footer closing a seekable zstd file's seek table: the number of
+/// frames, a descriptor byte and the magic number.
+pub const SEEK_FOOTER_LEN: u64 = 9;
+
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+
+/// One independently decompressible frame of a seekable zstd file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub compressed_offset: u64,
+    pub compressed_size: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Where each frame of a seekable zstd file lies, so a read only needs
+/// the frames it overlaps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekTable {
+    frames: Vec<Frame>,
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+impl SeekTable {
+    /// The length of the whole seek table, given its footer, or `None` if
+    /// the file isn't seekable.
+    pub fn len_from_footer(footer: &[u8]) -> Option<u64> {
+        if footer.len() != SEEK_FOOTER_LEN as usize || u32_at(footer, 5) != SEEKABLE_MAGIC {
+            return None;
+        }
+        let entry = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+        Some(8 + u32_at(footer, 0) as u64 * entry + SEEK_FOOTER_LEN)
+    }
+
+    /// Parses a seek table, from its skippable frame header to its footer.
+    pub fn parse(table: &[u8]) -> Option<SeekTable> {
+        let footer = &table[table.len().checked_sub(SEEK_FOOTER_LEN as usize)?..];
+        if Self::len_from_footer(footer)? != table.len() as u64
+            || u32_at(table, 0) != SKIPPABLE_MAGIC
+        {
+            return None;
+        }
+        let entry = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+        let mut frames = Vec::new();
+        let (mut compressed_offset, mut offset) = (0, 0);
+        for entry in table[8..table.len() - SEEK_FOOTER_LEN as usize].chunks(entry) {
+            let frame = Frame {
+                compressed_offset,
+                compressed_size: u32_at(entry, 0) as u64,
+                offset,
+                size: u32_at(entry, 4) as u64,
+            };
+            compressed_offset += frame.compressed_size;
+            offset += frame.size;
+            frames.push(frame);
+        }
+        Some(SeekTable { frames })
+    }
+
+    /// The decompressed size of the whole file.
+    pub fn size(&self) -> u64 {
+        self.frames
+            .last()
+            .map_or(0, |frame| frame.offset + frame.size)
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+#[derive(Debug)]
+pub struct BadGzip(String);
+
+impl Display for BadGzip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bad gzip stream: {}", self.0)
+    }
+}
+
+impl Error for BadGzip {}
+
+/// How much of a gzip stream is decompressed at a time.
+const GZIP_CHUNK: u64 = 64 * 1024;
+
+/// A place in a gzip stream decompression can resume from.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+struct Point {
+    compressed: u64,
+    offset: u64,
+    /// The inflater, with the window that later data refers back to, or
+    /// `None` between gzip members.
+    #[cfg(feature = "compression")]
+    state: Option<Box<InflateState>>,
+}
+
+/// Access points into a gzip stream, one every `spacing` decompressed
+/// bytes, so a read only decompresses from the point before it. Points are
+/// added as the stream is read, so the first read past the furthest point
+/// decompresses everything up to it.
+pub struct GzipIndex {
+    spacing: u64,
+    points: Vec<Point>,
+}
+
+impl Default for GzipIndex {
+    fn default() -> Self {
+        GzipIndex::new(4 * 1024 * 1024)
+    }
+}
+
+impl GzipIndex {
+    /// Each point holds a 32 KiB window and the inflater's tables, so a
+    /// smaller `spacing` trades memory for shorter reads.
+    pub fn new(spacing: u64) -> GzipIndex {
+        GzipIndex {
+            spacing,
+            points: vec![Point {
+                compressed: 0,
+                offset: 0,
+                #[cfg(feature = "compression")]
+                state: None,
+            }],
+        }
+    }
+
+    /// Decompresses `len` bytes at `offset`; fewer at the end of the stream.
+    pub fn read(&mut self, reader: &mut dyn ReadAt, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let start = self.points.partition_point(|point| point.offset <= offset) - 1;
+        let mut point = self.points[start].clone();
+        let end = offset.saturating_add(len);
+        let mut out = Vec::with_capacity(len.min(GZIP_CHUNK * 16) as usize);
+        while point.offset < end {
+            let last = self.points.last().unwrap().offset;
+            if point.offset >= last + self.spacing {
+                self.points.push(point.clone());
+            }
+            let data = step(reader, &mut point)?;
+            if data.is_empty() {
+                break;
+            }
+            let data_start = point.offset - data.len() as u64;
+            let from = offset.saturating_sub(data_start) as usize;
+            let to = (end - data_start).min(data.len() as u64) as usize;
+            if from < to {
+                out.extend_from_slice(&data[from..to]);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// The length of the gzip member header at the start of `data`.
+#[cfg(feature = "compression")]
+fn gzip_header_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 10 || data[..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = data[3];
+    let mut len = 10;
+    if flags & 4 != 0 {
+        let extra = u16::from_le_bytes([*data.get(len)?, *data.get(len + 1)?]) as usize;
+        len += 2 + extra;
+    }
+    for flag in [8, 16] {
+        if flags & flag != 0 {
+            len += data.get(len..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & 2 != 0 {
+        len += 2;
+    }
+    (len <= data.len()).then_some(len)
+}
+
+/// Decompresses the next chunk after `point`, moving it along. Returns
+/// nothing at the end of the stream.
+#[cfg(feature = "compression")]
+fn step(reader: &mut dyn ReadAt, point: &mut Point) -> Result<Vec<u8>> {
+    let state = match &mut point.state {
+        Some(state) => state,
+        None => {
+            let header = reader.read_at(point.compressed, GZIP_CHUNK)?;
+            // Some writers pad the last member with zeros.
+            if header.iter().all(|&b| b == 0) {
+                return Ok(Vec::new());
+            }
+            let len = gzip_header_len(&header)
+                .ok_or_else(|| BadGzip(format!("no gzip header at byte {}", point.compressed)))?;
+            point.compressed += len as u64;
+            point.state.insert(InflateState::new_boxed(DataFormat::Raw))
+        }
+    };
+    let input = reader.read_at(point.compressed, GZIP_CHUNK)?;
+    let mut out = vec![0; GZIP_CHUNK as usize * 4];
+    let result = inflate(state, &input, &mut out, MZFlush::None);
+    point.compressed += result.bytes_consumed as u64;
+    point.offset += result.bytes_written as u64;
+    out.truncate(result.bytes_written);
+    match result.status {
+        // The member's CRC and size follow.
+        Ok(MZStatus::StreamEnd) => {
+            point.compressed += 8;
+            point.state = None;
+        }
+        Ok(_) => {}
+        Err(MZError::Buf) if input.is_empty() => {
+            return Err(Box::new(BadGzip(format!(
+                "truncated at byte {}",
+                point.compressed
+            ))))
+        }
+        Err(MZError::Buf) => {}
+        Err(e) => {
+            return Err(Box::new(BadGzip(format!(
+                "{:?} at byte {}",
+                e, point.compressed
+            ))))
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "compression"))]
+fn step(_reader: &mut dyn ReadAt, _point: &mut Point) -> Result<Vec<u8>> {
+    Err(Box::new(crate::NotBuilt("compression")))
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "compression")]
+    use std::io::Write;
+
+    #[cfg(feature = "compression")]
+    use flate2::{write::GzEncoder, Compression as Level};
+
+    use super::{filter, Cipher, Compression, Encryption, ENCRYPTION_OVERHEAD};
+    #[cfg(feature = "compression")]
+    use super::{Frame, GzipIndex, SeekTable, SEEK_FOOTER_LEN};
+
+    #[cfg(feature = "compression")]
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn gzip_members() {
+        let data = [gzip(b"a,b\n"), gzip(b"1,2\n")].concat();
+        assert_eq!(Compression::Gzip.decompress(&data).unwrap(), b"a,b\n1,2\n");
+        assert!(Compression::Gzip.decompress(b"a,b\n").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "compression"))]
+    fn not_built() {
+        let e = Compression::Gzip.decompress(b"").unwrap_err();
+        assert!(e.get_ref().unwrap().is::<crate::NotBuilt>(), "{}", e);
+    }
+
+    #[test]
+    fn detection() {
+        assert_eq!(
+            Compression::from_name("a.csv.gz"),
+            Some((Compression::Gzip, "a.csv".into()))
+        );
+        assert_eq!(
+            Compression::from_name("src.tzst"),
+            Some((Compression::Zstd, "src.tar".into()))
+        );
+        assert_eq!(Compression::from_name(".gz"), None);
+        assert_eq!(Compression::from_name("a.zip"), None);
+        assert_eq!(
+            Compression::from_content_type("application/x-xz; charset=binary"),
+            Some(Compression::Xz)
+        );
+        assert_eq!(Compression::from_content_type("text/csv"), None);
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(filter("tr a-z A-Z", b"a,b\n").unwrap(), b"A,B\n");
+        let data = vec![b'x'; 1 << 20];
+        assert_eq!(filter("head -c 3", &data).unwrap(), b"xxx");
+        assert_eq!(filter("cat", &data).unwrap(), data);
+        assert!(filter("exit 3", b"a,b\n").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn zstd_and_xz() {
+        let data = [
+            zstd::encode_all(&b"a,b\n"[..], 3).unwrap(),
+            zstd::encode_all(&b"1,2\n"[..], 3).unwrap(),
+        ]
+        .concat();
+        assert_eq!(Compression::Zstd.decompress(&data).unwrap(), b"a,b\n1,2\n");
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(b"a,b\n").unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(Compression::Xz.decompress(&data).unwrap(), b"a,b\n");
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn seek_tables() {
+        let frames = [
+            zstd::encode_all(&b"hello "[..], 3).unwrap(),
+            zstd::encode_all(&b"world"[..], 3).unwrap(),
+        ];
+        // A seek table without checksums after the two frames.
+        let mut table = Vec::new();
+        table.extend(0x184D_2A5Eu32.to_le_bytes());
+        table.extend((2 * 8 + SEEK_FOOTER_LEN as u32).to_le_bytes());
+        for (frame, size) in frames.iter().zip([6u32, 5]) {
+            table.extend((frame.len() as u32).to_le_bytes());
+            table.extend(size.to_le_bytes());
+        }
+        table.extend(2u32.to_le_bytes());
+        table.push(0);
+        table.extend(0x8F92_EAB1u32.to_le_bytes());
+        let footer = &table[table.len() - SEEK_FOOTER_LEN as usize..];
+        assert_eq!(SeekTable::len_from_footer(footer), Some(table.len() as u64));
+
+        let seek_table = SeekTable::parse(&table).unwrap();
+        assert_eq!(seek_table.size(), 11);
+        assert_eq!(
+            seek_table.frames()[1],
+            Frame {
+                compressed_offset: frames[0].len() as u64,
+                compressed_size: frames[1].len() as u64,
+                offset: 6,
+                size: 5
+            }
+        );
+        // The whole file still decompresses as one stream.
+        let file = [frames.concat(), table].concat();
+        assert_eq!(Compression::Zstd.decompress(&file).unwrap(), b"hello world");
+        assert_eq!(SeekTable::len_from_footer(&[0; 9]), None);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn gzip_index() {
+        // Incompressible, so each chunk covers little of the output.
+        let mut state = 1u32;
+        let data = (0..600_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 24) as u8
+            })
+            .collect::<Vec<_>>();
+        let file = [gzip(&data[..250_000]), gzip(&data[250_000..])].concat();
+        let mut index = GzipIndex::new(100_000);
+        let mut reader = &file[..];
+        assert_eq!(
+            index.read(&mut reader, 500_000, 10).unwrap(),
+            &data[500_000..500_010]
+        );
+        assert!(index.points.len() > 3);
+        for (offset, len) in [(0, 5), (249_990, 20), (120_000, 300_000), (599_990, 100)] {
+            let end = (offset + len).min(data.len());
+            let read = index.read(&mut reader, offset as u64, len as u64).unwrap();
+            assert_eq!(read, &data[offset..end]);
+        }
+        let mut truncated = &file[..file.len() / 2];
+        assert!(GzipIndex::default()
+            .read(&mut truncated, 590_000, 10)
+            .is_err());
+    }
+
+    #[test]
+    fn aes_gcm() {
+        let key_file = std::env::temp_dir().join(format!("lhttpfs-key-{}", std::process::id()));
+        std::fs::write(&key_file, format!("{}\n", "0f".repeat(32))).unwrap();
+        let encryption = Encryption {
+            cipher: Cipher::Aes256Gcm,
+            key_file: key_file.clone(),
+        };
+        assert!(encryption.generate_key().is_err());
+        let encrypted = encryption.encrypt(b"secret").unwrap();
+        assert_eq!(encrypted.len() as u64, 6 + ENCRYPTION_OVERHEAD);
+        assert_eq!(encryption.decrypt(&encrypted).unwrap(), b"secret");
+
+        // The same key as raw bytes.
+        std::fs::write(&key_file, [0x0f; 32]).unwrap();
+        assert_eq!(encryption.decrypt(&encrypted).unwrap(), b"secret");
+        let mut tampered = encrypted.clone();
+        tampered[20] ^= 1;
+        assert!(encryption.decrypt(&tampered).is_err());
+        std::fs::write(&key_file, "short").unwrap();
+        assert!(encryption.decrypt(&encrypted).is_err());
+        std::fs::remove_file(key_file).unwrap();
+    }
+}